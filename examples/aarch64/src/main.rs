@@ -17,7 +17,6 @@ use uart8250 as uart;
 
 use buddy_system_allocator::LockedHeap;
 use core::{
-    mem::size_of,
     panic::PanicInfo,
     ptr::{self, NonNull},
 };
@@ -100,13 +99,11 @@ extern "C" fn main(x0: u64, x1: u64, x2: u64, x3: u64) {
         if let (Some(compatible), Some(region)) =
             (node.compatible(), node.reg().and_then(|mut reg| reg.next()))
         {
-            if compatible.all().any(|s| s == "virtio,mmio")
-                && region.size.unwrap_or(0) > size_of::<VirtIOHeader>()
-            {
+            if compatible.all().any(|s| s == "virtio,mmio") {
                 debug!("Found VirtIO MMIO device at {:?}", region);
 
                 let header = NonNull::new(region.starting_address as *mut VirtIOHeader).unwrap();
-                match unsafe { MmioTransport::new(header) } {
+                match unsafe { MmioTransport::new(header, region.size.unwrap_or(0)) } {
                     Err(e) => warn!("Error creating VirtIO MMIO transport: {}", e),
                     Ok(transport) => {
                         info!(
@@ -210,7 +207,7 @@ fn virtio_net<T: Transport>(transport: T) {
 fn virtio_console<T: Transport>(transport: T) {
     let mut console =
         VirtIOConsole::<HalImpl, T>::new(transport).expect("Failed to create console driver");
-    let info = console.info();
+    let info = console.info().expect("Failed to read console info");
     info!("VirtIO console {}x{}", info.rows, info.columns);
     for &c in b"Hello world on console!\n" {
         console.send(c).expect("Failed to send character");
@@ -423,14 +420,14 @@ fn allocate_bars(
             match address_type {
                 MemoryBarType::Width32 => {
                     if size > 0 {
-                        let address = allocator.allocate_memory_32(size);
+                        let address = allocator.allocate_memory_32(size.try_into().unwrap());
                         debug!("Allocated address {:#010x}", address);
                         root.set_bar_32(device_function, bar_index, address);
                     }
                 }
                 MemoryBarType::Width64 => {
                     if size > 0 {
-                        let address = allocator.allocate_memory_32(size);
+                        let address = allocator.allocate_memory_32(size.try_into().unwrap());
                         debug!("Allocated address {:#010x}", address);
                         root.set_bar_64(device_function, bar_index, address.into());
                     }
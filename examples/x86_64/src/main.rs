@@ -11,6 +11,7 @@ mod boot;
 mod hal;
 mod heap;
 mod logger;
+mod msix;
 mod trap;
 
 #[cfg(feature = "tcp")]
@@ -18,7 +19,10 @@ mod tcp;
 
 use self::hal::HalImpl;
 use virtio_drivers::{
-    device::{blk::VirtIOBlk, gpu::VirtIOGpu},
+    device::{
+        blk::{BlkReq, BlkResp, VirtIOBlk},
+        gpu::VirtIOGpu,
+    },
     transport::{
         pci::{
             bus::{BarInfo, Cam, Command, DeviceFunction, PciRoot},
@@ -53,6 +57,7 @@ extern "C" fn main(_mbi: *const u8) -> ! {
     info!("virtio-drivers example started.");
 
     trap::init();
+    msix::init();
     heap::init_heap();
 
     enumerate_pci(MMCONFIG_BASE as _);
@@ -61,16 +66,17 @@ extern "C" fn main(_mbi: *const u8) -> ! {
     system_off();
 }
 
-fn virtio_device(transport: impl Transport) {
+/// Tests the given device, which raises interrupts on the given CPU vector if any.
+fn virtio_device(transport: impl Transport, vector: Option<u8>) {
     match transport.device_type() {
-        DeviceType::Block => virtio_blk(transport),
+        DeviceType::Block => virtio_blk(transport, vector),
         DeviceType::GPU => virtio_gpu(transport),
-        DeviceType::Network => virtio_net(transport),
+        DeviceType::Network => virtio_net(transport, vector),
         t => warn!("Unrecognized virtio device: {:?}", t),
     }
 }
 
-fn virtio_blk<T: Transport>(transport: T) {
+fn virtio_blk<T: Transport>(transport: T, vector: Option<u8>) {
     let mut blk = VirtIOBlk::<HalImpl, T>::new(transport).expect("failed to create blk driver");
     assert!(!blk.readonly());
     let mut input = [0xffu8; 512];
@@ -80,10 +86,38 @@ fn virtio_blk<T: Transport>(transport: T) {
             *x = i as u8;
         }
         blk.write_blocks(i, &input).expect("failed to write");
-        blk.read_blocks(i, &mut output).expect("failed to read");
+        match vector {
+            Some(vector) => read_blocks_irq(&mut blk, vector, i, &mut output),
+            None => blk.read_blocks(i, &mut output).expect("failed to read"),
+        }
         assert_eq!(input, output);
     }
-    info!("virtio-blk test finished");
+    info!(
+        "virtio-blk test finished, interrupts {:?}",
+        blk.interrupt_stats()
+    );
+}
+
+/// Reads a block, sleeping until the device signals completion with an interrupt.
+fn read_blocks_irq<T: Transport>(
+    blk: &mut VirtIOBlk<HalImpl, T>,
+    vector: u8,
+    block_id: usize,
+    buf: &mut [u8],
+) {
+    let mut req = BlkReq::default();
+    let mut resp = BlkResp::default();
+    unsafe {
+        let token = blk
+            .read_blocks_nb(block_id, &mut req, buf, &mut resp)
+            .expect("failed to start read");
+        while blk.peek_used() != Some(token) {
+            msix::wait_for_interrupt(vector);
+            blk.ack_interrupt();
+        }
+        blk.complete_read_blocks(token, &req, buf, &mut resp)
+            .expect("failed to read");
+    }
 }
 
 fn virtio_gpu<T: Transport>(transport: T) {
@@ -115,7 +149,7 @@ fn virtio_gpu<T: Transport>(transport: T) {
     info!("virtio-gpu test finished");
 }
 
-fn virtio_net<T: Transport>(transport: T) {
+fn virtio_net<T: Transport>(transport: T, vector: Option<u8>) {
     #[cfg(not(feature = "tcp"))]
     {
         let mut net =
@@ -124,7 +158,18 @@ fn virtio_net<T: Transport>(transport: T) {
         info!("MAC address: {:02x?}", net.mac_address());
 
         let mut buf = [0u8; 2048];
-        let (hdr_len, pkt_len) = net.receive_wait(&mut buf).expect("failed to recv");
+        let (hdr_len, pkt_len) = match vector {
+            Some(vector) => unsafe {
+                let token = net.receive_begin(&mut buf).expect("failed to start recv");
+                while net.poll_receive() != Some(token) {
+                    msix::wait_for_interrupt(vector);
+                    net.ack_interrupt();
+                }
+                net.receive_complete(token, &mut buf)
+                    .expect("failed to recv")
+            },
+            None => net.receive_wait(&mut buf).expect("failed to recv"),
+        };
         info!(
             "recv {} bytes: {:02x?}",
             pkt_len,
@@ -143,7 +188,7 @@ fn virtio_net<T: Transport>(transport: T) {
         )
         .expect("failed to create net driver");
         info!("MAC address: {:02x?}", net.mac_address());
-        tcp::test_echo_server(net);
+        tcp::test_echo_server(net, vector);
     }
 }
 
@@ -174,7 +219,8 @@ fn enumerate_pci(mmconfig_base: *mut u8) {
                 transport.device_type(),
                 transport.read_device_features(),
             );
-            virtio_device(transport);
+            let vector = msix::setup(&mut pci_root, device_function, &mut transport);
+            virtio_device(transport, vector);
         }
     }
 }
@@ -0,0 +1,130 @@
+//! MSI-X setup and a minimal interrupt registry for VirtIO PCI devices.
+//!
+//! Each device gets a single MSI-X table entry, shared by all of its queues, which is routed to its
+//! own CPU interrupt vector. The interrupt handler just records that the vector fired, and drivers
+//! wait for that with [`wait_for_interrupt`] before acknowledging the interrupt on the device and
+//! processing their used rings.
+
+use core::sync::atomic::{AtomicBool, AtomicU8, Ordering};
+use virtio_drivers::transport::pci::{
+    bus::{BarInfo, DeviceFunction, PciRoot},
+    PciTransport, NO_VECTOR,
+};
+use x86_64::instructions::{interrupts, port::Port};
+
+/// The first CPU interrupt vector used for MSI-X, well clear of the CPU exceptions.
+pub const FIRST_VECTOR: u8 = 0x30;
+
+/// The maximum number of devices which can be given an interrupt vector.
+const MAX_VECTORS: usize = 8;
+
+/// The physical address of the local APIC registers.
+const LAPIC_BASE: usize = 0xFEE0_0000;
+/// The offset of the spurious interrupt vector register within the local APIC.
+const LAPIC_SVR: usize = 0xF0;
+/// The offset of the end of interrupt register within the local APIC.
+const LAPIC_EOI: usize = 0xB0;
+/// The APIC software enable bit in the spurious interrupt vector register.
+const LAPIC_SVR_ENABLE: u32 = 1 << 8;
+
+/// The size of an entry in the MSI-X table.
+const MSIX_ENTRY_SIZE: usize = 16;
+/// The vector control bit which masks an MSI-X table entry.
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// Whether each vector has fired since it was last waited for.
+static PENDING: [AtomicBool; MAX_VECTORS] = [const { AtomicBool::new(false) }; MAX_VECTORS];
+/// The number of vectors allocated so far.
+static ALLOCATED: AtomicU8 = AtomicU8::new(0);
+
+/// Enables the local APIC and masks the legacy PIC, so that only MSIs are delivered.
+pub fn init() {
+    unsafe {
+        Port::<u8>::new(0x21).write(0xff);
+        Port::<u8>::new(0xa1).write(0xff);
+        lapic_write(LAPIC_SVR, LAPIC_SVR_ENABLE | 0xff);
+    }
+    interrupts::enable();
+}
+
+/// Routes the MSI-X interrupts of the given device function to a newly allocated CPU vector, and
+/// configures the transport to use them for all queues.
+///
+/// Returns the CPU vector, or `None` if the device doesn't support MSI-X or we have run out of
+/// vectors, in which case the driver must poll.
+pub fn setup(
+    pci_root: &mut PciRoot,
+    device_function: DeviceFunction,
+    transport: &mut PciTransport,
+) -> Option<u8> {
+    let msix = pci_root.msix_info(device_function)?;
+    let BarInfo::Memory { address, .. } =
+        pci_root.bar_info(device_function, msix.table_bar).ok()?
+    else {
+        warn!("MSI-X table of {} isn't in a memory BAR", device_function);
+        return None;
+    };
+    let index = ALLOCATED.fetch_add(1, Ordering::Relaxed);
+    if usize::from(index) >= MAX_VECTORS {
+        warn!("Out of MSI-X vectors for {}", device_function);
+        return None;
+    }
+    let vector = FIRST_VECTOR + index;
+
+    // Point table entry 0 at the local APIC of the boot CPU, in fixed delivery mode.
+    let entry = (address as usize + msix.table_offset as usize) as *mut u32;
+    unsafe {
+        entry.add(0).write_volatile(LAPIC_BASE as u32);
+        entry.add(1).write_volatile(0);
+        entry.add(2).write_volatile(vector.into());
+        entry.add(3).write_volatile(0);
+        // Mask the remaining entries, as we don't use them.
+        for i in 1..usize::from(msix.table_size) {
+            entry
+                .byte_add(i * MSIX_ENTRY_SIZE)
+                .add(3)
+                .write_volatile(MSIX_ENTRY_MASKED);
+        }
+    }
+    pci_root.set_msix_enabled(device_function, &msix, true);
+
+    transport.set_queue_msix_vector(0);
+    if let Err(e) = transport.set_config_msix_vector(NO_VECTOR) {
+        warn!("Failed to clear config change vector: {}", e);
+    }
+    info!(
+        "  MSI-X: {} table entries, entry 0 routed to vector {:#x}",
+        msix.table_size, vector
+    );
+    Some(vector)
+}
+
+/// Blocks until the given vector has fired at least once since the last call.
+pub fn wait_for_interrupt(vector: u8) {
+    let pending = &PENDING[usize::from(vector - FIRST_VECTOR)];
+    loop {
+        // Disable interrupts while checking, so that one can't arrive between the check and the
+        // `hlt`.
+        interrupts::disable();
+        if pending.swap(false, Ordering::Acquire) {
+            interrupts::enable();
+            return;
+        }
+        interrupts::enable_and_hlt();
+    }
+}
+
+/// Handles an interrupt on one of the vectors allocated by [`setup`]. Called from the trap handler.
+pub fn handle_interrupt(vector: u8) {
+    match PENDING.get(usize::from(vector - FIRST_VECTOR)) {
+        Some(pending) => pending.store(true, Ordering::Release),
+        None => warn!("Unexpected interrupt on vector {:#x}", vector),
+    }
+    unsafe {
+        lapic_write(LAPIC_EOI, 0);
+    }
+}
+
+unsafe fn lapic_write(offset: usize, value: u32) {
+    ((LAPIC_BASE + offset) as *mut u32).write_volatile(value);
+}
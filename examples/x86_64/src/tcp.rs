@@ -12,7 +12,7 @@ use smoltcp::{socket::tcp, time::Instant};
 use virtio_drivers::device::net::{RxBuffer, VirtIONet};
 use virtio_drivers::{transport::Transport, Error};
 
-use super::{HalImpl, NET_QUEUE_SIZE};
+use super::{msix, HalImpl, NET_QUEUE_SIZE};
 
 type DeviceImpl<T> = VirtIONet<HalImpl, T, NET_QUEUE_SIZE>;
 
@@ -37,8 +37,14 @@ impl<T: Transport> DeviceWrapper<T> {
 }
 
 impl<T: Transport> Device for DeviceWrapper<T> {
-    type RxToken<'a> = VirtioRxToken<T> where Self: 'a;
-    type TxToken<'a> = VirtioTxToken<T> where Self: 'a;
+    type RxToken<'a>
+        = VirtioRxToken<T>
+    where
+        Self: 'a;
+    type TxToken<'a>
+        = VirtioTxToken<T>
+    where
+        Self: 'a;
 
     fn receive(&mut self, _timestamp: Instant) -> Option<(Self::RxToken<'_>, Self::TxToken<'_>)> {
         match self.inner.borrow_mut().receive() {
@@ -98,7 +104,11 @@ impl<T: Transport> TxToken for VirtioTxToken<T> {
     }
 }
 
-pub fn test_echo_server<T: Transport>(dev: DeviceImpl<T>) {
+/// Runs the echo server until the first connection closes.
+///
+/// If `vector` is given, sleeps until the device raises an interrupt on it whenever there is
+/// nothing to do, rather than busy polling.
+pub fn test_echo_server<T: Transport>(dev: DeviceImpl<T>, vector: Option<u8>) {
     let mut device = DeviceWrapper::new(dev);
 
     // Create interface
@@ -133,7 +143,14 @@ pub fn test_echo_server<T: Transport>(dev: DeviceImpl<T>) {
     loop {
         let timestamp =
             unsafe { Instant::from_micros_const(core::arch::x86_64::_rdtsc() as i64 / 2_500) };
-        iface.poll(timestamp, &mut device, &mut sockets);
+        let activity = iface.poll(timestamp, &mut device, &mut sockets);
+        if let Some(vector) = vector {
+            if !activity && !device.inner.borrow().can_recv() {
+                msix::wait_for_interrupt(vector);
+                device.inner.borrow_mut().ack_interrupt();
+                continue;
+            }
+        }
 
         // tcp:PORT: echo with reverse
         let socket = sockets.get_mut::<tcp::Socket>(tcp_handle);
@@ -12,6 +12,7 @@ fn trap_handler(isf: InterruptStackFrame, index: u8, error_code: Option<u64>) {
                 isf.instruction_pointer, cr2, error_code
             );
         }
+        x if x >= crate::msix::FIRST_VECTOR => crate::msix::handle_interrupt(x),
         _ => {
             panic!(
                 "Unhandled exception {} (error_code = {:#x?}) at {:#x}:\n{:#x?}",
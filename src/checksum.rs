@@ -0,0 +1,135 @@
+//! The Internet checksum (RFC 1071), with an extension point for optimised implementations.
+//!
+//! Drivers which need to compute or check checksums in software, such as the network driver when
+//! the device hands over packets with a partial checksum, are generic over a [`Checksum`]
+//! implementation. [`PortableChecksum`] works everywhere, but on targets where checksumming
+//! dominates CPU time the embedder can provide its own, e.g. using vector instructions:
+//!
+//! ```
+//! use virtio_drivers::checksum::{Checksum, PortableChecksum};
+//!
+//! struct MyChecksum;
+//!
+//! impl Checksum for MyChecksum {
+//!     fn ones_complement_sum(data: &[u8]) -> u16 {
+//!         // Call an optimised routine here.
+//!         PortableChecksum::ones_complement_sum(data)
+//!     }
+//! }
+//! ```
+
+/// An implementation of the ones' complement sum used by the Internet checksum.
+pub trait Checksum {
+    /// Returns the 16-bit ones' complement sum of `data`, taken as big-endian 16-bit words.
+    ///
+    /// If `data` has an odd length then the last byte is padded with a zero byte. The result is
+    /// folded to 16 bits but not complemented, so that sums of separate pieces of data can be
+    /// combined with [`combine`].
+    fn ones_complement_sum(data: &[u8]) -> u16 {
+        portable_sum(data)
+    }
+}
+
+/// A portable implementation of [`Checksum`] in plain Rust.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct PortableChecksum;
+
+impl Checksum for PortableChecksum {}
+
+/// Combines two ones' complement sums, as returned by [`Checksum::ones_complement_sum`].
+///
+/// The first piece of data must have an even length, or the second sum will be misaligned.
+pub fn combine(a: u16, b: u16) -> u16 {
+    fold(u64::from(a) + u64::from(b))
+}
+
+/// Returns the Internet checksum of `data`, i.e. the complement of its ones' complement sum.
+pub fn internet_checksum<C: Checksum>(data: &[u8]) -> u16 {
+    !C::ones_complement_sum(data)
+}
+
+/// Returns whether `data`, which includes a checksum field, has a valid Internet checksum.
+pub fn verify<C: Checksum>(data: &[u8]) -> bool {
+    C::ones_complement_sum(data) == 0xffff
+}
+
+/// The portable ones' complement sum, which adds 32-bit words into a 64-bit accumulator so that
+/// carries only need to be folded in at the end.
+fn portable_sum(data: &[u8]) -> u16 {
+    let mut sum = 0u64;
+    let mut words = data.chunks_exact(4);
+    for word in &mut words {
+        sum += u64::from(u32::from_be_bytes([word[0], word[1], word[2], word[3]]));
+    }
+    for pair in words.remainder().chunks(2) {
+        sum += u64::from(u16::from_be_bytes([
+            pair[0],
+            pair.get(1).copied().unwrap_or(0),
+        ]));
+    }
+    fold(sum)
+}
+
+/// Folds the carries of a ones' complement sum back into the low 16 bits.
+fn fold(mut sum: u64) -> u16 {
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    sum as u16
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A straightforward implementation to compare against.
+    fn reference_sum(data: &[u8]) -> u16 {
+        let mut sum = 0u32;
+        for pair in data.chunks(2) {
+            sum += u32::from(pair[0]) << 8;
+            if let Some(&low) = pair.get(1) {
+                sum += u32::from(low);
+            }
+            sum = (sum & 0xffff) + (sum >> 16);
+        }
+        sum as u16
+    }
+
+    #[test]
+    fn rfc1071_example() {
+        // The example from RFC 1071 section 3.
+        let data = [0x00, 0x01, 0xf2, 0x03, 0xf4, 0xf5, 0xf6, 0xf7];
+        assert_eq!(PortableChecksum::ones_complement_sum(&data), 0xddf2);
+        assert_eq!(internet_checksum::<PortableChecksum>(&data), !0xddf2);
+    }
+
+    #[test]
+    fn matches_reference() {
+        let data: Vec<u8> = (0..1000u32).map(|i| (i * 7 + i / 3) as u8).collect();
+        for len in 0..data.len() {
+            assert_eq!(
+                PortableChecksum::ones_complement_sum(&data[..len]),
+                reference_sum(&data[..len]),
+                "len {}",
+                len
+            );
+        }
+    }
+
+    #[test]
+    fn combine_and_verify() {
+        let mut data = [0x45, 0x00, 0x00, 0x1c, 0x12, 0x34, 0x00, 0x00, 0x40, 0x11];
+        let sum = combine(
+            PortableChecksum::ones_complement_sum(&data[..4]),
+            PortableChecksum::ones_complement_sum(&data[4..]),
+        );
+        assert_eq!(sum, PortableChecksum::ones_complement_sum(&data));
+
+        // Store the checksum in the zeroed field, and it should then verify.
+        let checksum = internet_checksum::<PortableChecksum>(&data);
+        data[6..8].copy_from_slice(&checksum.to_be_bytes());
+        assert!(verify::<PortableChecksum>(&data));
+        data[9] ^= 1;
+        assert!(!verify::<PortableChecksum>(&data));
+    }
+}
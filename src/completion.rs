@@ -0,0 +1,402 @@
+//! Completion tracking for requests which are waited for asynchronously.
+//!
+//! A driver which hands out futures for its requests registers each request's token and buffers
+//! with a `Completions` registry when it adds them to a queue, and calls `complete_used` from its
+//! interrupt handler. That pops the used chains, keeping their buffers in the registry and waking
+//! the [`RequestFuture`]s waiting for them.
+//!
+//! If a [`RequestFuture`] is dropped before its request completes, e.g. because the caller gave up
+//! waiting, the request is marked as abandoned rather than forgotten. The device still owns the
+//! descriptor chain and may yet write to the buffers, so the registry keeps the buffers alive until
+//! the device returns the chain, and only then recycles the descriptors and drops the buffers. A
+//! timed out request therefore neither leaks descriptors nor leaves the device writing to freed
+//! memory.
+
+use crate::{queue::VirtQueue, Error, Hal, Result};
+use core::{
+    array,
+    cell::UnsafeCell,
+    future::Future,
+    hint::spin_loop,
+    mem,
+    pin::Pin,
+    sync::atomic::{AtomicBool, Ordering},
+    task::{Context, Poll, Waker},
+};
+
+/// The buffers of a request, owned so that they can outlive a future which is dropped before the
+/// request completes.
+///
+/// # Safety
+///
+/// The device may access the buffers while the registry moves the value implementing this trait
+/// around, so they must not be stored inline in it. Every call to `with_buffers` must pass the same
+/// buffers at the same addresses, e.g. by keeping them in a `Box`.
+pub unsafe trait RequestBuffers {
+    /// Calls `f` with the device-readable and device-writable buffers of the request, exactly as
+    /// they were added to the queue.
+    fn with_buffers<R>(
+        &mut self,
+        f: impl for<'a> FnOnce(&'a [&'a [u8]], &'a mut [&'a mut [u8]]) -> R,
+    ) -> R;
+}
+
+/// The state of the request with a given token.
+enum Slot<B> {
+    /// No request with the token is being tracked.
+    Free,
+    /// The device owns the request. The waker, if any, is woken when it completes.
+    InFlight { buffers: B, waker: Option<Waker> },
+    /// The device has returned the request, having written `len` bytes to its buffers.
+    Completed { buffers: B, len: u32 },
+    /// The future for the request was dropped, so its buffers are dropped once the device returns
+    /// it.
+    Abandoned { buffers: B },
+}
+
+/// The requests on a queue of size `SIZE` which are being waited for by [`RequestFuture`]s,
+/// indexed by token.
+pub(crate) struct Completions<B, const SIZE: usize> {
+    locked: AtomicBool,
+    slots: UnsafeCell<[Slot<B>; SIZE]>,
+}
+
+// Safe because the slots are only accessed with the lock held.
+unsafe impl<B: Send, const SIZE: usize> Sync for Completions<B, SIZE> {}
+
+impl<B: RequestBuffers, const SIZE: usize> Completions<B, SIZE> {
+    /// Creates a registry with no requests.
+    pub fn new() -> Self {
+        Self {
+            locked: AtomicBool::new(false),
+            slots: UnsafeCell::new(array::from_fn(|_| Slot::Free)),
+        }
+    }
+
+    /// Starts tracking a request which has been added to the queue with the given token, and
+    /// returns a future for its completion.
+    ///
+    /// Returns [`Error::WrongToken`] if the token is out of range for the queue.
+    ///
+    /// # Safety
+    ///
+    /// `buffers` must give exactly the buffers which were added to the queue with `token`, and the
+    /// registry must only be used with that queue.
+    pub unsafe fn submit(&self, token: u16, buffers: B) -> Result<RequestFuture<'_, B, SIZE>> {
+        // Safe because our caller promises the same.
+        unsafe { self.track(token, buffers) }?;
+        Ok(RequestFuture {
+            completions: self,
+            token,
+            done: false,
+        })
+    }
+
+    /// Starts tracking a request which has been added to the queue with the given token, for a
+    /// future which the caller builds itself around [`poll_token`](Self::poll_token) and
+    /// [`abandon`](Self::abandon), e.g. one which owns a reference count on the registry.
+    ///
+    /// Returns [`Error::WrongToken`] if the token is out of range for the queue.
+    ///
+    /// # Safety
+    ///
+    /// The same as for [`submit`](Self::submit).
+    pub unsafe fn track(&self, token: u16, buffers: B) -> Result {
+        self.with_slots(|slots| {
+            let slot = slots.get_mut(usize::from(token)).ok_or(Error::WrongToken)?;
+            debug_assert!(matches!(slot, Slot::Free));
+            *slot = Slot::InFlight {
+                buffers,
+                waker: None,
+            };
+            Ok(())
+        })
+    }
+
+    /// Pops the used chains at the front of the queue which belong to tracked requests, waking the
+    /// futures waiting for them and recycling the buffers of abandoned requests.
+    ///
+    /// Stops at the first used chain which isn't tracked, so that the caller can pop requests
+    /// which it is waiting for synchronously. Returns the number of chains popped.
+    ///
+    /// # Safety
+    ///
+    /// `queue` must be the queue which the tracked requests were added to.
+    pub unsafe fn complete_used<H: Hal>(&self, queue: &mut VirtQueue<H, SIZE>) -> Result<usize> {
+        let mut popped = 0;
+        // The waker is called without the lock held, in case it polls the future straight away.
+        while let Some(waker) = self.with_slots(|slots| Self::pop_next(slots, queue))? {
+            popped += 1;
+            if let Some(waker) = waker {
+                waker.wake();
+            }
+        }
+        Ok(popped)
+    }
+
+    /// Returns whether no requests are being tracked, i.e. every request has either not been
+    /// submitted or been taken by its future.
+    pub fn is_idle(&self) -> bool {
+        self.with_slots(|slots| slots.iter().all(|slot| matches!(slot, Slot::Free)))
+    }
+
+    /// Returns the number of requests which were abandoned and which the device hasn't returned
+    /// yet.
+    pub fn abandoned(&self) -> usize {
+        self.with_slots(|slots| {
+            slots
+                .iter()
+                .filter(|slot| matches!(slot, Slot::Abandoned { .. }))
+                .count()
+        })
+    }
+
+    /// Pops the next used chain if it belongs to a tracked request, returning the waker to wake
+    /// for it.
+    unsafe fn pop_next<H: Hal>(
+        slots: &mut [Slot<B>; SIZE],
+        queue: &mut VirtQueue<H, SIZE>,
+    ) -> Result<Option<Option<Waker>>> {
+        let Some(token) = queue.peek_used() else {
+            return Ok(None);
+        };
+        let Some(slot) = slots.get_mut(usize::from(token)) else {
+            return Ok(None);
+        };
+        match mem::replace(slot, Slot::Free) {
+            Slot::InFlight { mut buffers, waker } => match buffers
+                .with_buffers(|inputs, outputs| queue.pop_used(token, inputs, outputs))
+            {
+                Ok(len) => {
+                    *slot = Slot::Completed { buffers, len };
+                    Ok(Some(waker))
+                }
+                Err(e) => {
+                    *slot = Slot::InFlight { buffers, waker };
+                    Err(e)
+                }
+            },
+            Slot::Abandoned { mut buffers } => {
+                if let Err(e) =
+                    buffers.with_buffers(|inputs, outputs| queue.pop_used(token, inputs, outputs))
+                {
+                    *slot = Slot::Abandoned { buffers };
+                    return Err(e);
+                }
+                Ok(Some(None))
+            }
+            other => {
+                *slot = other;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Marks the request with the given token as abandoned, or drops its buffers if it has already
+    /// completed.
+    pub fn abandon(&self, token: u16) {
+        let completed = self.with_slots(|slots| {
+            let slot = slots.get_mut(usize::from(token))?;
+            match mem::replace(slot, Slot::Free) {
+                Slot::InFlight { buffers, .. } => {
+                    *slot = Slot::Abandoned { buffers };
+                    None
+                }
+                Slot::Completed { buffers, .. } => Some(buffers),
+                other => {
+                    *slot = other;
+                    None
+                }
+            }
+        });
+        // Drop the buffers of a completed request without the lock held.
+        drop(completed);
+    }
+
+    /// Takes the buffers of the request with the given token if it has completed, or otherwise
+    /// registers the waker to wake when it does.
+    pub fn poll_token(&self, token: u16, waker: &Waker) -> Option<(B, u32)> {
+        self.with_slots(|slots| {
+            let slot = slots.get_mut(usize::from(token))?;
+            match mem::replace(slot, Slot::Free) {
+                Slot::Completed { buffers, len } => Some((buffers, len)),
+                Slot::InFlight { buffers, .. } => {
+                    *slot = Slot::InFlight {
+                        buffers,
+                        waker: Some(waker.clone()),
+                    };
+                    None
+                }
+                other => {
+                    *slot = other;
+                    None
+                }
+            }
+        })
+    }
+
+    /// Calls `f` with the lock held.
+    fn with_slots<R>(&self, f: impl FnOnce(&mut [Slot<B>; SIZE]) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        // Safe because the lock is held, so nothing else is accessing the slots.
+        let result = f(unsafe { &mut *self.slots.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// A future for the completion of a request on a virtqueue.
+///
+/// Resolves to the request's buffers and the number of bytes which the device wrote to them.
+/// Dropping the future before then abandons the request: its descriptors and buffers are recycled
+/// when the device eventually returns it.
+#[must_use = "futures do nothing unless polled"]
+pub struct RequestFuture<'a, B: RequestBuffers, const SIZE: usize> {
+    completions: &'a Completions<B, SIZE>,
+    token: u16,
+    done: bool,
+}
+
+impl<B: RequestBuffers, const SIZE: usize> RequestFuture<'_, B, SIZE> {
+    /// Returns the token of the request.
+    pub fn token(&self) -> u16 {
+        self.token
+    }
+}
+
+impl<B: RequestBuffers, const SIZE: usize> Future for RequestFuture<'_, B, SIZE> {
+    type Output = (B, u32);
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.completions.poll_token(self.token, cx.waker()) {
+            Some(completed) => {
+                self.done = true;
+                Poll::Ready(completed)
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl<B: RequestBuffers, const SIZE: usize> Drop for RequestFuture<'_, B, SIZE> {
+    fn drop(&mut self) {
+        if !self.done {
+            self.completions.abandon(self.token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{boxed::Box, sync::Arc, task::Wake};
+    use core::{ptr::NonNull, sync::atomic::AtomicUsize};
+    use std::sync::Mutex;
+
+    /// A request with one device-readable and one device-writable buffer.
+    struct Request {
+        request: Box<[u8; 1]>,
+        response: Box<[u8; 2]>,
+    }
+
+    // Safe because the buffers are boxed, so they don't move with the request.
+    unsafe impl RequestBuffers for Request {
+        fn with_buffers<R>(
+            &mut self,
+            f: impl for<'a> FnOnce(&'a [&'a [u8]], &'a mut [&'a mut [u8]]) -> R,
+        ) -> R {
+            f(&[&*self.request], &mut [&mut *self.response])
+        }
+    }
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn abandoned_request_is_recycled() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let completions = Completions::<Request, 4>::new();
+
+        let futures = [1, 2].map(|data| {
+            let mut request = Request {
+                request: Box::new([data]),
+                response: Box::new([0; 2]),
+            };
+            let token =
+                request.with_buffers(|inputs, outputs| unsafe { queue.add(inputs, outputs) });
+            unsafe { completions.submit(token.unwrap(), request) }.unwrap()
+        });
+        assert_eq!(queue.available_desc(), 0);
+
+        let waker_state = Arc::new(CountingWaker::default());
+        let waker = Waker::from(waker_state.clone());
+        let mut cx = Context::from_waker(&waker);
+        let [mut first, second] = futures;
+        assert!(Pin::new(&mut first).poll(&mut cx).is_pending());
+        drop(second);
+        assert_eq!(completions.abandoned(), 1);
+
+        // The device returns both requests, so both are popped and the abandoned one recycled.
+        for _ in 0..2 {
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<4>(0, |request| vec![request[0], 42]);
+        }
+        assert_eq!(unsafe { completions.complete_used(&mut queue) }, Ok(2));
+        assert_eq!(completions.abandoned(), 0);
+        assert_eq!(queue.available_desc(), 4);
+        assert_eq!(waker_state.0.load(Ordering::SeqCst), 1);
+
+        match Pin::new(&mut first).poll(&mut cx) {
+            Poll::Ready((request, _)) => {
+                assert_eq!(*request.response, [1, 42]);
+            }
+            Poll::Pending => panic!("Request should have completed"),
+        }
+    }
+
+    #[test]
+    fn submit_wrong_token() {
+        let completions = Completions::<Request, 4>::new();
+        let request = Request {
+            request: Box::new([1]),
+            response: Box::new([0; 2]),
+        };
+        assert!(matches!(
+            unsafe { completions.submit(4, request) },
+            Err(Error::WrongToken)
+        ));
+    }
+}
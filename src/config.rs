@@ -0,0 +1,347 @@
+//! Helpers for tracking changes to device configuration space.
+//!
+//! Drivers describe the layout of configuration space as a plain `#[repr(C)]` struct, and read and
+//! write its fields with [`Transport::read_config`] and [`Transport::write_config`] at offsets
+//! given by [`offset_of!`](core::mem::offset_of). These take care of the byte order of legacy
+//! devices, and of retrying reads which race with the device changing its configuration.
+//!
+//! Devices signal configuration changes (such as a block device being resized or a network link
+//! going down) with a configuration change interrupt, but don't say what changed. Drivers keep a
+//! copy of the fields they care about, and compare a fresh copy against it with
+//! [`ConfigDiff::diff`] when an interrupt arrives to find out which fields changed.
+
+use crate::transport::Transport;
+use crate::{Error, Result};
+use core::{
+    mem::{align_of, size_of},
+    ptr::{self, NonNull},
+};
+use zerocopy::{AsBytes, FromBytes};
+
+/// A field of device configuration space, which can be read and written with
+/// [`Transport::read_config`] and [`Transport::write_config`].
+pub trait ConfigField: AsBytes + FromBytes + Copy {
+    /// Converts between the byte order which the device uses for the field and the guest's native
+    /// byte order.
+    ///
+    /// Modern devices use little-endian, while legacy devices use the guest's native byte order.
+    /// The conversion is the same in both directions.
+    fn convert_byte_order(self, legacy: bool) -> Self;
+}
+
+macro_rules! impl_config_field_int {
+    ($($ty:ty),*) => {
+        $(
+            impl ConfigField for $ty {
+                fn convert_byte_order(self, legacy: bool) -> Self {
+                    if legacy {
+                        self
+                    } else {
+                        <$ty>::from_le(self)
+                    }
+                }
+            }
+        )*
+    };
+}
+
+impl_config_field_int!(u8, u16, u32, u64, i8, i16, i32, i64);
+
+impl<const N: usize> ConfigField for [u8; N] {
+    fn convert_byte_order(self, _legacy: bool) -> Self {
+        self
+    }
+}
+
+/// A copy of some fields of a device's configuration space, which can be compared against an
+/// earlier copy.
+pub trait ConfigDiff: Copy {
+    /// The fields which changed between two snapshots.
+    type Changes;
+
+    /// Returns the fields which differ between `previous` and `self`, or `None` if they are the
+    /// same.
+    fn diff(&self, previous: &Self) -> Option<Self::Changes>;
+}
+
+/// Returns `Some(new)` if `new` differs from `old`, for building [`ConfigDiff::Changes`].
+pub fn changed<V: PartialEq>(old: V, new: V) -> Option<V> {
+    if old == new {
+        None
+    } else {
+        Some(new)
+    }
+}
+
+/// Reads some fields from config space with the given function, retrying if the device changed its
+/// configuration part way through so that the result is consistent.
+///
+/// This relies on the transport's [configuration generation](Transport::config_generation), so it
+/// can't detect changes on transports which don't have one.
+pub fn read_config_consistent<T: Transport + ?Sized, C>(
+    transport: &T,
+    mut read: impl FnMut() -> C,
+) -> C {
+    loop {
+        let generation = transport.config_generation();
+        let value = read();
+        if transport.config_generation() == generation {
+            return value;
+        }
+    }
+}
+
+/// Returns a pointer to the field of type `V` at the given offset in the transport's configuration
+/// space, after checking that it is within the configuration space and suitably aligned.
+fn field_pointer<T: Transport + ?Sized, V>(transport: &T, offset: usize) -> Result<NonNull<V>> {
+    // Configuration space is only guaranteed to be 4 byte aligned, so wider fields are accessed in
+    // 32-bit pieces.
+    if !offset.is_multiple_of(align_of::<V>().min(4)) {
+        return Err(Error::Misaligned);
+    }
+    let config_space = transport.config_space::<u8>()?;
+    let end = offset
+        .checked_add(size_of::<V>())
+        .ok_or(Error::ConfigSpaceTooSmall)?;
+    if end > transport.config_space_size() {
+        return Err(Error::ConfigSpaceTooSmall);
+    }
+    NonNull::new(config_space.as_ptr().wrapping_add(offset).cast::<V>())
+        .ok_or(Error::ConfigSpaceMissing)
+}
+
+/// Reads the field of type `V` at the given offset in the transport's configuration space,
+/// retrying until the read is consistent, and converts it to the guest's byte order.
+pub(crate) fn read_field<T: Transport + ?Sized, V: ConfigField>(
+    transport: &T,
+    offset: usize,
+) -> Result<V> {
+    let field = field_pointer::<T, V>(transport, offset)?;
+    // Safe because `field_pointer` checked that the field is within the configuration space.
+    let read = || unsafe { copy_config(field) };
+    let legacy = transport.requires_legacy_layout();
+    let value = if legacy && size_of::<V>() > 4 {
+        // Legacy interfaces have no configuration generation, so instead fields wider than 32 bits
+        // are read until two reads in a row agree.
+        let mut value = read();
+        loop {
+            let again = read();
+            if again.as_bytes() == value.as_bytes() {
+                break value;
+            }
+            value = again;
+        }
+    } else {
+        read_config_consistent(transport, read)
+    };
+    Ok(value.convert_byte_order(legacy))
+}
+
+/// Writes the given value to the field of type `V` at the given offset in the transport's
+/// configuration space, converting it to the device's byte order.
+pub(crate) fn write_field<T: Transport + ?Sized, V: ConfigField>(
+    transport: &mut T,
+    offset: usize,
+    value: V,
+) -> Result {
+    let field = field_pointer::<T, V>(transport, offset)?;
+    let value = value.convert_byte_order(transport.requires_legacy_layout());
+    // Safe because `field_pointer` checked that the field is within the configuration space.
+    unsafe { write_config(field, &value) };
+    Ok(())
+}
+
+/// Copies a `C` out of device memory with volatile reads of at most 32 bits.
+///
+/// # Safety
+///
+/// `config` must be valid for volatile reads of `size_of::<C>()` bytes.
+unsafe fn copy_config<C: FromBytes>(config: NonNull<C>) -> C {
+    let mut value = C::new_zeroed();
+    let src = config.as_ptr().cast::<u8>();
+    let dst = ptr::addr_of_mut!(value).cast::<u8>();
+    let mut offset = 0;
+    while offset < size_of::<C>() {
+        let remaining = size_of::<C>() - offset;
+        // Safe because the caller promises that `config` is valid for reading the whole of `C`,
+        // `value` is a `C` and any bytes are a valid `C`.
+        offset += unsafe {
+            let address = src.add(offset) as usize;
+            if address & 3 == 0 && remaining >= 4 {
+                let word = ptr::read_volatile(src.add(offset).cast::<u32>());
+                ptr::write_unaligned(dst.add(offset).cast::<u32>(), word);
+                4
+            } else if address & 1 == 0 && remaining >= 2 {
+                let half = ptr::read_volatile(src.add(offset).cast::<u16>());
+                ptr::write_unaligned(dst.add(offset).cast::<u16>(), half);
+                2
+            } else {
+                *dst.add(offset) = ptr::read_volatile(src.add(offset));
+                1
+            }
+        };
+    }
+    value
+}
+
+/// Copies the given `C` into device memory with volatile writes of at most 32 bits.
+///
+/// # Safety
+///
+/// `config` must be valid for volatile writes of `size_of::<C>()` bytes.
+unsafe fn write_config<C: AsBytes>(config: NonNull<C>, value: &C) {
+    let src = value.as_bytes();
+    let dst = config.as_ptr().cast::<u8>();
+    let mut offset = 0;
+    while offset < src.len() {
+        let remaining = src.len() - offset;
+        // Safe because the caller promises that `config` is valid for writing the whole of `C`.
+        offset += unsafe {
+            let address = dst.add(offset) as usize;
+            if address & 3 == 0 && remaining >= 4 {
+                let word = ptr::read_unaligned(src[offset..].as_ptr().cast::<u32>());
+                ptr::write_volatile(dst.add(offset).cast::<u32>(), word);
+                4
+            } else if address & 1 == 0 && remaining >= 2 {
+                let half = ptr::read_unaligned(src[offset..].as_ptr().cast::<u16>());
+                ptr::write_volatile(dst.add(offset).cast::<u16>(), half);
+                2
+            } else {
+                ptr::write_volatile(dst.add(offset), src[offset]);
+                1
+            }
+        };
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::{
+        fake::{FakeTransport, State},
+        DeviceType,
+    };
+    use alloc::{sync::Arc, vec::Vec};
+    use core::{cell::Cell, ptr::NonNull};
+    use std::sync::Mutex;
+    use zerocopy::{AsBytes, FromZeroes};
+
+    #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+    struct Snapshot {
+        size: u64,
+        flags: u8,
+    }
+
+    #[derive(Debug, Eq, PartialEq)]
+    struct Changes {
+        size: Option<u64>,
+        flags: Option<u8>,
+    }
+
+    impl ConfigDiff for Snapshot {
+        type Changes = Changes;
+
+        fn diff(&self, previous: &Self) -> Option<Changes> {
+            if self == previous {
+                return None;
+            }
+            Some(Changes {
+                size: changed(previous.size, self.size),
+                flags: changed(previous.flags, self.flags),
+            })
+        }
+    }
+
+    #[test]
+    fn read_retries_on_generation_change() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State::default()));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 0,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let size = Cell::new(10);
+        let read = || Snapshot {
+            size: size.get(),
+            flags: 1,
+        };
+        let snapshot = read_config_consistent(&transport, read);
+        assert_eq!(
+            read_config_consistent(&transport, read).diff(&snapshot),
+            None
+        );
+
+        // The device changes its config while the driver is reading it, so the first read is torn
+        // and should be discarded.
+        let mut reads = Vec::new();
+        let new = read_config_consistent(&transport, || {
+            reads.push(size.get());
+            if reads.len() == 1 {
+                size.set(20);
+                state.lock().unwrap().config_generation += 1;
+            }
+            Snapshot {
+                size: size.get(),
+                flags: 1,
+            }
+        });
+        assert_eq!(reads, [10, 20]);
+        assert_eq!(
+            new.diff(&snapshot),
+            Some(Changes {
+                size: Some(20),
+                flags: None,
+            })
+        );
+    }
+
+    #[repr(C)]
+    #[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+    struct TestConfig {
+        id: [u8; 3],
+        flags: u8,
+        count: u16,
+        limit: u16,
+        size: u32,
+    }
+
+    #[test]
+    fn read_and_write_fields() {
+        let mut config_space = TestConfig {
+            id: [1, 2, 3],
+            flags: 4,
+            count: 0x506,
+            limit: 0xb0c,
+            size: 0x708090a,
+        };
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 0,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: Arc::new(Mutex::new(State::default())),
+        };
+        assert_eq!(transport.read_config::<[u8; 3]>(0), Ok([1, 2, 3]));
+        assert_eq!(transport.read_config::<u16>(4), Ok(0x506));
+        // Fields wider than 32 bits only need 4 byte alignment.
+        assert_eq!(transport.read_config::<u64>(4), Ok(0x0708_090a_0b0c_0506));
+
+        transport.write_config::<u16>(6, 0x1234).unwrap();
+        transport.write_config::<u8>(3, 5).unwrap();
+        assert_eq!(transport.read_config::<u32>(4), Ok(0x1234_0506));
+        assert_eq!(transport.read_config::<u8>(3), Ok(5));
+
+        assert_eq!(transport.read_config::<u16>(5), Err(Error::Misaligned));
+        assert_eq!(
+            transport.read_config::<u32>(12),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        assert_eq!(
+            transport.write_config::<u32>(usize::MAX - 3, 0),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+    }
+}
@@ -0,0 +1,260 @@
+//! Driver for VirtIO memory balloon devices.
+//!
+//! The host asks the guest to give up memory by raising the target size of the balloon in config
+//! space. The guest then allocates pages and reports their page frame numbers to the device with
+//! [`VirtIOBalloon::inflate`], after which it must not touch them until they are returned with
+//! [`VirtIOBalloon::deflate`].
+//!
+//! Most users will want to drive this with a [`BalloonPolicy`], which takes care of chasing the
+//! target in batches.
+
+#[cfg(feature = "alloc")]
+mod policy;
+
+#[cfg(feature = "alloc")]
+pub use self::policy::{BalloonPolicy, BalloonStep, PageProvider};
+
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::display::impl_flags_display;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::registry::DriverId;
+use crate::transport::{DeviceType, Transport};
+use crate::Result;
+use bitflags::bitflags;
+use core::mem::offset_of;
+use log::info;
+use zerocopy::{little_endian::U32, AsBytes, FromZeroes};
+
+#[cfg(test)]
+const QUEUE_INFLATE: u16 = 0;
+#[cfg(test)]
+const QUEUE_DEFLATE: u16 = 1;
+const QUEUE_SIZE: usize = 8;
+
+/// The size of the pages which the balloon device deals in, in bytes.
+pub const BALLOON_PAGE_SIZE: usize = 4096;
+
+/// The maximum number of page frame numbers sent to the device in a single request.
+pub const MAX_PFNS_PER_REQUEST: usize = 256;
+
+/// Driver for a VirtIO memory balloon device.
+///
+/// Page frame numbers are in units of [`BALLOON_PAGE_SIZE`], i.e. the physical address of the page
+/// shifted right by 12 bits.
+pub struct VirtIOBalloon<H: Hal, T: Transport> {
+    id: DriverId,
+    transport: T,
+    negotiated_features: BalloonFeature,
+    queues: QueueLayout,
+    inflate_queue: VirtQueue<H, QUEUE_SIZE>,
+    deflate_queue: VirtQueue<H, QUEUE_SIZE>,
+}
+
+impl<H: Hal, T: Transport> VirtIOBalloon<H, T> {
+    /// Creates a new VirtIO balloon driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        info!("negotiated_features {}", negotiated_features);
+        let queues = QueueLayout::new(DeviceType::MemoryBalloon, negotiated_features.bits(), 0)?;
+        queues.validate(&mut transport)?;
+        let inflate_index =
+            queues.checked_index(&mut transport, QueueRole::Inflate, QUEUE_SIZE as u32)?;
+        let deflate_index =
+            queues.checked_index(&mut transport, QueueRole::Deflate, QUEUE_SIZE as u32)?;
+        let inflate_queue = VirtQueue::new(
+            &mut transport,
+            inflate_index,
+            false,
+            negotiated_features.contains(BalloonFeature::RING_EVENT_IDX),
+        )?;
+        let deflate_queue = VirtQueue::new(
+            &mut transport,
+            deflate_index,
+            false,
+            negotiated_features.contains(BalloonFeature::RING_EVENT_IDX),
+        )?;
+        transport.finish_init();
+
+        Ok(Self {
+            id: DriverId::allocate(),
+            transport,
+            negotiated_features,
+            queues,
+            inflate_queue,
+            deflate_queue,
+        })
+    }
+
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge. The device raises a configuration
+    /// change interrupt when the target changes.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Returns the number of pages which the host would like the balloon to hold.
+    pub fn target_pages(&self) -> Result<u32> {
+        self.transport
+            .read_config(offset_of!(BalloonConfig, num_pages))
+    }
+
+    /// Returns the number of pages which the driver last reported the balloon as holding.
+    pub fn actual_pages(&self) -> Result<u32> {
+        self.transport
+            .read_config(offset_of!(BalloonConfig, actual))
+    }
+
+    /// Reports to the device how many pages the balloon currently holds.
+    pub fn set_actual_pages(&mut self, pages: u32) -> Result {
+        self.transport
+            .write_config(offset_of!(BalloonConfig, actual), pages)
+    }
+
+    /// Returns whether the device must be told about pages before they are taken out of the
+    /// balloon, i.e. [`deflate`](Self::deflate) must be called before the guest reuses them.
+    ///
+    /// It is always safe to call `deflate` first anyway.
+    pub fn must_tell_host(&self) -> bool {
+        self.negotiated_features
+            .contains(BalloonFeature::MUST_TELL_HOST)
+    }
+
+    /// Gives the given pages to the host, and blocks until it has acknowledged them.
+    ///
+    /// The guest must not access the pages after this until they have been deflated again.
+    pub fn inflate(&mut self, pfns: &[u32]) -> Result {
+        Self::send_pfns(&mut self.inflate_queue, &mut self.transport, pfns)
+    }
+
+    /// Takes the given pages back from the host, and blocks until it has acknowledged them.
+    pub fn deflate(&mut self, pfns: &[u32]) -> Result {
+        Self::send_pfns(&mut self.deflate_queue, &mut self.transport, pfns)
+    }
+
+    /// Sends the given page frame numbers to the device on the given queue, in chunks of up to
+    /// [`MAX_PFNS_PER_REQUEST`].
+    fn send_pfns(queue: &mut VirtQueue<H, QUEUE_SIZE>, transport: &mut T, pfns: &[u32]) -> Result {
+        let mut buffer = [U32::new_zeroed(); MAX_PFNS_PER_REQUEST];
+        for chunk in pfns.chunks(MAX_PFNS_PER_REQUEST) {
+            for (le, &pfn) in buffer.iter_mut().zip(chunk) {
+                *le = pfn.into();
+            }
+            queue.add_notify_wait_pop(&[buffer[..chunk.len()].as_bytes()], &mut [], transport)?;
+        }
+        Ok(())
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOBalloon<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        for (index, _) in self.queues.queues() {
+            self.transport.queue_unset(index);
+        }
+    }
+}
+
+#[repr(C)]
+struct BalloonConfig {
+    /// The number of pages which the host wants the balloon to hold.
+    num_pages: u32,
+    /// The number of pages which the balloon actually holds.
+    actual: u32,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct BalloonFeature: u64 {
+        const MUST_TELL_HOST = 1 << 0;
+        const STATS_VQ = 1 << 1;
+        const DEFLATE_ON_OOM = 1 << 2;
+        const FREE_PAGE_HINT = 1 << 3;
+        const PAGE_POISON = 1 << 4;
+        const PAGE_REPORTING = 1 << 5;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+    }
+}
+
+impl_flags_display!(BalloonFeature);
+
+const SUPPORTED_FEATURES: BalloonFeature =
+    BalloonFeature::MUST_TELL_HOST.union(BalloonFeature::RING_EVENT_IDX);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+
+    #[test]
+    fn inflate_deflate() {
+        let mut config_space = BalloonConfig {
+            num_pages: 3,
+            actual: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::MemoryBalloon,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: BalloonFeature::MUST_TELL_HOST.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut balloon =
+            VirtIOBalloon::<FakeHal, FakeTransport<BalloonConfig>>::new(transport).unwrap();
+        assert_eq!(balloon.target_pages(), Ok(3));
+        assert!(balloon.must_tell_host());
+
+        // Start a thread to simulate the device acknowledging the requests.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_INFLATE);
+            let pfns = state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_INFLATE);
+            assert_eq!(pfns, [1u32, 2, 3].as_bytes());
+
+            State::wait_until_queue_notified(&state, QUEUE_DEFLATE);
+            let pfns = state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_DEFLATE);
+            assert_eq!(pfns, [2u32, 3].as_bytes());
+        });
+
+        balloon.inflate(&[1, 2, 3]).unwrap();
+        balloon.set_actual_pages(3).unwrap();
+        balloon.deflate(&[2, 3]).unwrap();
+        balloon.set_actual_pages(1).unwrap();
+        assert_eq!(balloon.actual_pages(), Ok(1));
+
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,296 @@
+//! A policy for moving the balloon towards the host's target without starving the guest.
+
+use super::{VirtIOBalloon, MAX_PFNS_PER_REQUEST};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::Result;
+use alloc::vec::Vec;
+use core::cmp::min;
+use log::{debug, warn};
+
+/// Hooks through which a [`BalloonPolicy`] takes pages from and returns pages to the guest's own
+/// page allocator.
+pub trait PageProvider {
+    /// Allocates up to `pfns.len()` pages for the balloon, writing their page frame numbers to the
+    /// start of `pfns`, and returns how many were allocated.
+    ///
+    /// Returning fewer than requested tells the policy that the guest is short of memory, so it
+    /// will stop inflating until the next step.
+    fn allocate_pages(&mut self, pfns: &mut [u32]) -> usize;
+
+    /// Returns the given pages, which were previously allocated by `allocate_pages` and have now
+    /// been taken back from the host, to the guest's allocator.
+    fn free_pages(&mut self, pfns: &[u32]);
+}
+
+/// What a single [`BalloonPolicy::step`] did.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct BalloonStep {
+    /// The target number of pages requested by the host at the start of the step.
+    pub target: u32,
+    /// The number of pages given to the host.
+    pub inflated: usize,
+    /// The number of pages taken back from the host.
+    pub deflated: usize,
+}
+
+/// Moves a [`VirtIOBalloon`] towards the target size requested by the host, in batches.
+///
+/// The caller should call [`step`](Self::step) periodically, and whenever the balloon device
+/// raises a configuration change interrupt. Deflation always goes straight to the target, but
+/// inflation is limited to a fixed number of pages per step and stops early if the guest can't
+/// spare any more memory, so that the guest doesn't run itself out of memory chasing a target
+/// which is too large.
+#[derive(Debug)]
+pub struct BalloonPolicy {
+    /// The page frame numbers of the pages currently in the balloon.
+    pages: Vec<u32>,
+    batch_size: usize,
+    max_inflate_per_step: usize,
+}
+
+impl BalloonPolicy {
+    /// Creates a new policy for an empty balloon, which will allocate and free pages in batches of
+    /// up to `batch_size` and give at most `max_inflate_per_step` pages to the host in each step.
+    ///
+    /// `batch_size` is capped at [`MAX_PFNS_PER_REQUEST`].
+    ///
+    /// # Panics
+    ///
+    /// Panics if either parameter is 0.
+    pub fn new(batch_size: usize, max_inflate_per_step: usize) -> Self {
+        assert_ne!(batch_size, 0);
+        assert_ne!(max_inflate_per_step, 0);
+        Self {
+            pages: Vec::new(),
+            batch_size: min(batch_size, MAX_PFNS_PER_REQUEST),
+            max_inflate_per_step,
+        }
+    }
+
+    /// Returns the number of pages currently in the balloon.
+    pub fn pages(&self) -> usize {
+        self.pages.len()
+    }
+
+    /// Inflates or deflates the balloon towards the host's current target, and reports the new
+    /// size to the device.
+    pub fn step<H: Hal, T: Transport, P: PageProvider>(
+        &mut self,
+        balloon: &mut VirtIOBalloon<H, T>,
+        provider: &mut P,
+    ) -> Result<BalloonStep> {
+        let target = balloon.target_pages()?;
+        let mut step = BalloonStep {
+            target,
+            ..Default::default()
+        };
+        let target = target as usize;
+
+        let result = if self.pages.len() < target {
+            let wanted = min(target - self.pages.len(), self.max_inflate_per_step);
+            self.inflate(balloon, provider, wanted, &mut step)
+        } else {
+            let excess = self.pages.len() - target;
+            self.deflate(balloon, provider, excess, &mut step)
+        };
+        let reported = balloon.set_actual_pages(self.pages.len() as u32);
+        debug!("Balloon step {:?}, now {} pages", step, self.pages.len());
+        result.and(reported).map(|()| step)
+    }
+
+    /// Takes all pages back from the host and returns them to the guest, e.g. before shutting down
+    /// or when the guest is critically short of memory.
+    ///
+    /// Returns the number of pages deflated.
+    pub fn deflate_all<H: Hal, T: Transport, P: PageProvider>(
+        &mut self,
+        balloon: &mut VirtIOBalloon<H, T>,
+        provider: &mut P,
+    ) -> Result<usize> {
+        let mut step = BalloonStep::default();
+        let result = self.deflate(balloon, provider, self.pages.len(), &mut step);
+        let reported = balloon.set_actual_pages(self.pages.len() as u32);
+        result.and(reported).map(|()| step.deflated)
+    }
+
+    fn inflate<H: Hal, T: Transport, P: PageProvider>(
+        &mut self,
+        balloon: &mut VirtIOBalloon<H, T>,
+        provider: &mut P,
+        count: usize,
+        step: &mut BalloonStep,
+    ) -> Result {
+        let mut batch = [0; MAX_PFNS_PER_REQUEST];
+        while step.inflated < count {
+            let wanted = min(count - step.inflated, self.batch_size);
+            let allocated = provider.allocate_pages(&mut batch[..wanted]);
+            if allocated > 0 {
+                if let Err(e) = balloon.inflate(&batch[..allocated]) {
+                    // The host didn't take the pages, so give them straight back.
+                    provider.free_pages(&batch[..allocated]);
+                    return Err(e);
+                }
+                self.pages.extend_from_slice(&batch[..allocated]);
+                step.inflated += allocated;
+            }
+            if allocated < wanted {
+                warn!(
+                    "Guest short of memory, balloon stopped {} pages short of target",
+                    count - step.inflated
+                );
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    fn deflate<H: Hal, T: Transport, P: PageProvider>(
+        &mut self,
+        balloon: &mut VirtIOBalloon<H, T>,
+        provider: &mut P,
+        count: usize,
+        step: &mut BalloonStep,
+    ) -> Result {
+        while step.deflated < count {
+            let batch_len = min(count - step.deflated, self.batch_size);
+            let batch_start = self.pages.len() - batch_len;
+            // Always tell the host before reusing the pages, whether or not it requires it.
+            balloon.deflate(&self.pages[batch_start..])?;
+            provider.free_pages(&self.pages[batch_start..]);
+            self.pages.truncate(batch_start);
+            step.deflated += batch_len;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::balloon::{
+            BalloonConfig, BalloonFeature, QUEUE_DEFLATE, QUEUE_INFLATE, QUEUE_SIZE,
+        },
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+    use zerocopy::AsBytes;
+
+    /// A page provider which has a fixed number of pages to give.
+    struct FakeProvider {
+        next_pfn: u32,
+        available: usize,
+        freed: Vec<u32>,
+    }
+
+    impl PageProvider for FakeProvider {
+        fn allocate_pages(&mut self, pfns: &mut [u32]) -> usize {
+            let count = min(pfns.len(), self.available);
+            for pfn in &mut pfns[..count] {
+                *pfn = self.next_pfn;
+                self.next_pfn += 1;
+            }
+            self.available -= count;
+            count
+        }
+
+        fn free_pages(&mut self, pfns: &[u32]) {
+            self.available += pfns.len();
+            self.freed.extend_from_slice(pfns);
+        }
+    }
+
+    #[test]
+    fn rate_limited_inflation() {
+        let mut config_space = BalloonConfig {
+            num_pages: 5,
+            actual: 0,
+        };
+        let config_space_ptr = NonNull::from(&mut config_space);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::MemoryBalloon,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: BalloonFeature::MUST_TELL_HOST.bits(),
+            config_space: config_space_ptr,
+            state: state.clone(),
+        };
+        let mut balloon =
+            VirtIOBalloon::<FakeHal, FakeTransport<BalloonConfig>>::new(transport).unwrap();
+        let mut provider = FakeProvider {
+            next_pfn: 100,
+            available: 4,
+            freed: Vec::new(),
+        };
+        let mut policy = BalloonPolicy::new(2, 3);
+
+        // Start a thread to simulate the device acknowledging the requests.
+        let handle = thread::spawn(move || {
+            let expected: [(u16, &[u32]); 5] = [
+                (QUEUE_INFLATE, &[100, 101]),
+                (QUEUE_INFLATE, &[102]),
+                (QUEUE_INFLATE, &[103]),
+                (QUEUE_DEFLATE, &[102, 103]),
+                (QUEUE_DEFLATE, &[101]),
+            ];
+            for (queue, pfns) in expected {
+                State::wait_until_queue_notified(&state, queue);
+                assert_eq!(
+                    state.lock().unwrap().read_from_queue::<QUEUE_SIZE>(queue),
+                    pfns.as_bytes()
+                );
+            }
+        });
+
+        // Only 3 pages may be inflated per step.
+        assert_eq!(
+            policy.step(&mut balloon, &mut provider).unwrap(),
+            BalloonStep {
+                target: 5,
+                inflated: 3,
+                deflated: 0,
+            }
+        );
+        assert_eq!(balloon.actual_pages(), Ok(3));
+
+        // The guest only has one more page to give.
+        assert_eq!(
+            policy.step(&mut balloon, &mut provider).unwrap(),
+            BalloonStep {
+                target: 5,
+                inflated: 1,
+                deflated: 0,
+            }
+        );
+        assert_eq!(balloon.actual_pages(), Ok(4));
+
+        // The host lowers the target, so deflate all the way at once.
+        // Safe because nothing else is accessing the config space at the moment.
+        unsafe {
+            (*config_space_ptr.as_ptr()).num_pages = 1;
+        }
+        assert_eq!(
+            policy.step(&mut balloon, &mut provider).unwrap(),
+            BalloonStep {
+                target: 1,
+                inflated: 0,
+                deflated: 3,
+            }
+        );
+        assert_eq!(balloon.actual_pages(), Ok(1));
+        assert_eq!(policy.pages(), 1);
+        assert_eq!(provider.freed, [102, 103, 101]);
+
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,415 @@
+//! Future-based completion of requests to a VirtIO block device.
+
+use super::{BlkReq, BlkReqOptions, BlkResp, ReqType, VirtIOBlk, QUEUE_SIZE, SECTOR_SIZE};
+use crate::completion::{Completions, RequestBuffers};
+use crate::hal::Hal;
+use crate::interrupt::{SharedIrqDevice, VirtioDevice};
+use crate::transport::Transport;
+use crate::{Error, Result};
+use alloc::{boxed::Box, sync::Arc};
+use core::{
+    fmt::{self, Debug, Formatter},
+    future::Future,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use zerocopy::AsBytes;
+
+/// The registry of a block device's requests which are waited for by [`BlkFuture`]s.
+pub(super) type BlkCompletions = Completions<AsyncRequest, { QUEUE_SIZE as usize }>;
+
+/// The buffers of a request which is waited for asynchronously.
+///
+/// Each part is boxed so that it stays at the same address while the registry moves the request
+/// around, as the device may be accessing it.
+pub(super) struct AsyncRequest {
+    req: Box<BlkReq>,
+    data: Box<[u8]>,
+    resp: Box<BlkResp>,
+    /// Whether `data` is written to the device, rather than read from it.
+    write: bool,
+}
+
+// Safe because each buffer is boxed, so it stays at the same address when the request is moved.
+unsafe impl RequestBuffers for AsyncRequest {
+    fn with_buffers<R>(
+        &mut self,
+        f: impl for<'a> FnOnce(&'a [&'a [u8]], &'a mut [&'a mut [u8]]) -> R,
+    ) -> R {
+        if self.write {
+            f(
+                &[self.req.as_bytes(), &self.data],
+                &mut [self.resp.as_bytes_mut()],
+            )
+        } else {
+            f(
+                &[self.req.as_bytes()],
+                &mut [&mut self.data, self.resp.as_bytes_mut()],
+            )
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
+    /// Submits a request to read one or more blocks into `buf`, and returns a future which
+    /// resolves to the buffer once the read completes.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`], otherwise this returns
+    /// [`Error::InvalidParam`]. The future only makes progress once
+    /// [`complete_async`](Self::complete_async) has handled the request's completion, so the
+    /// caller must call it from its interrupt handler, or poll it.
+    ///
+    /// The future doesn't borrow the driver, so it can be sent to another task. If it is dropped
+    /// before the read completes, the buffer is kept until the device returns it, and then freed.
+    pub fn read_blocks_async(&mut self, block_id: usize, buf: Box<[u8]>) -> Result<BlkFuture> {
+        self.submit_async(ReqType::In, block_id, buf)
+    }
+
+    /// Submits a request to write the contents of `buf` to one or more blocks, and returns a
+    /// future which resolves to the buffer once the write completes.
+    ///
+    /// See [`read_blocks_async`](Self::read_blocks_async) for the requirements and how the future
+    /// is completed.
+    pub fn write_blocks_async(&mut self, block_id: usize, buf: Box<[u8]>) -> Result<BlkFuture> {
+        self.submit_async(ReqType::Out, block_id, buf)
+    }
+
+    /// Acknowledges a pending interrupt and completes the [`BlkFuture`]s for requests which the
+    /// device has finished, waking the tasks waiting for them.
+    ///
+    /// This is meant to be called from the interrupt handler. It stops at the first completed
+    /// request which wasn't submitted asynchronously, so that it can be popped in the usual way.
+    /// Returns the number of asynchronous requests completed.
+    pub fn complete_async(&mut self) -> Result<usize> {
+        self.ack_interrupt();
+        self.complete_used_async()
+    }
+
+    /// Completes the [`BlkFuture`]s for requests which the device has finished, without
+    /// acknowledging an interrupt.
+    fn complete_used_async(&mut self) -> Result<usize> {
+        match &self.completions {
+            // Safe because the registry is only used with this device's queue.
+            Some(completions) => unsafe { completions.complete_used(&mut self.queue) },
+            None => Ok(0),
+        }
+    }
+
+    fn submit_async(
+        &mut self,
+        type_: ReqType,
+        block_id: usize,
+        buf: Box<[u8]>,
+    ) -> Result<BlkFuture> {
+        if buf.is_empty() || !buf.len().is_multiple_of(SECTOR_SIZE) {
+            return Err(Error::InvalidParam);
+        }
+        let write = matches!(type_, ReqType::Out);
+        let req = self.make_request(type_, block_id as u64, BlkReqOptions::default())?;
+        self.queue.throttle(&mut self.transport)?;
+        let mut request = AsyncRequest {
+            req: Box::new(req),
+            data: buf,
+            resp: Box::new(BlkResp::default()),
+            write,
+        };
+        // Safe because the buffers are owned by the request, which the registry keeps until the
+        // device returns them.
+        let token =
+            request.with_buffers(|inputs, outputs| unsafe { self.queue.add(inputs, outputs) })?;
+        let completions = self
+            .completions
+            .get_or_insert_with(|| Arc::new(BlkCompletions::new()))
+            .clone();
+        // Safe because the request holds exactly the buffers added with the token, and the
+        // registry belongs to this device's queue. The request is tracked before the device is
+        // notified so that its completion can't be missed.
+        unsafe { completions.track(token, request) }?;
+        self.pending_notify = true;
+        self.kick();
+        Ok(BlkFuture {
+            completions,
+            token,
+            done: false,
+        })
+    }
+}
+
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOBlk<H, T> {
+    /// Acknowledges any pending interrupt, and completes the [`BlkFuture`]s for requests which the
+    /// device has finished.
+    ///
+    /// Unlike [`complete_async`](VirtIOBlk::complete_async) this is called whether or not an
+    /// interrupt is pending, so it only counts an interrupt in the
+    /// [`interrupt_stats`](VirtIOBlk::interrupt_stats) if the device had raised one. Requests which
+    /// weren't submitted asynchronously are left for the caller to complete.
+    fn poll(&mut self) -> Result<usize> {
+        SharedIrqDevice::ack_interrupt(self);
+        self.complete_used_async()
+    }
+}
+
+impl<H: Hal, T: Transport> SharedIrqDevice for VirtIOBlk<H, T> {
+    /// Acknowledges the interrupt if the device raised it, counting it in the
+    /// [`interrupt_stats`](VirtIOBlk::interrupt_stats).
+    ///
+    /// An interrupt raised by another device on the line isn't counted as spurious for this one.
+    fn ack_interrupt(&mut self) -> bool {
+        let acked = self.transport.ack_interrupt();
+        if acked {
+            self.record_interrupt(true);
+        }
+        acked
+    }
+
+    /// Completes the [`BlkFuture`]s for requests which the device has finished, like
+    /// [`complete_async`](VirtIOBlk::complete_async).
+    fn handle_interrupt(&mut self) -> Result<usize> {
+        self.complete_used_async()
+    }
+}
+
+/// A future for the completion of a read or write request submitted with
+/// [`VirtIOBlk::read_blocks_async`] or [`VirtIOBlk::write_blocks_async`].
+///
+/// Resolves to the request's buffer, or the error status returned by the device. Dropping the
+/// future before then abandons the request.
+#[must_use = "futures do nothing unless polled"]
+pub struct BlkFuture {
+    completions: Arc<BlkCompletions>,
+    token: u16,
+    done: bool,
+}
+
+impl BlkFuture {
+    /// Returns the token of the request, which identifies it in the queue.
+    pub fn token(&self) -> u16 {
+        self.token
+    }
+}
+
+impl Debug for BlkFuture {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.debug_struct("BlkFuture")
+            .field("token", &self.token)
+            .field("done", &self.done)
+            .finish()
+    }
+}
+
+impl Future for BlkFuture {
+    type Output = Result<Box<[u8]>>;
+
+    fn poll(mut self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+        match self.completions.poll_token(self.token, cx.waker()) {
+            Some((request, _)) => {
+                self.done = true;
+                Poll::Ready(Result::from(request.resp.status).map(|()| request.data))
+            }
+            None => Poll::Pending,
+        }
+    }
+}
+
+impl Drop for BlkFuture {
+    fn drop(&mut self) {
+        if !self.done {
+            self.completions.abandon(self.token);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::blk::{tests::fake_blk, BlkConfig, BlkFeature, RespStatus, QUEUE},
+        hal::fake::FakeHal,
+        interrupt::InterruptStats,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{task::Wake, vec};
+    use core::{
+        ptr::NonNull,
+        sync::atomic::{AtomicUsize, Ordering},
+        task::Waker,
+    };
+    use std::sync::Mutex;
+
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
+    #[test]
+    fn read_async() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(
+            blk.read_blocks_async(42, vec![0; 100].into_boxed_slice())
+                .unwrap_err(),
+            Error::InvalidParam
+        );
+        let mut future = blk
+            .read_blocks_async(42, vec![0; SECTOR_SIZE].into_boxed_slice())
+            .unwrap();
+        let waker_state = Arc::new(CountingWaker::default());
+        let waker = Waker::from(waker_state.clone());
+        let mut cx = Context::from_waker(&waker);
+        assert!(Pin::new(&mut future).poll(&mut cx).is_pending());
+        assert_eq!(blk.complete_async(), Ok(0));
+
+        // The device completes the request.
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::In as u32,
+                        reserved: 0,
+                        sector: 42
+                    }
+                    .as_bytes()
+                );
+                let mut response = vec![0; SECTOR_SIZE];
+                response[0..9].copy_from_slice(b"Test data");
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+                response
+            });
+        assert_eq!(blk.complete_async(), Ok(1));
+        assert_eq!(waker_state.0.load(Ordering::SeqCst), 1);
+
+        match Pin::new(&mut future).poll(&mut cx) {
+            Poll::Ready(Ok(data)) => assert_eq!(&data[0..9], b"Test data"),
+            other => panic!("Unexpected poll result {:?}", other),
+        }
+    }
+
+    #[test]
+    fn save_with_async_request() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let mut future = blk
+            .read_blocks_async(42, vec![0; SECTOR_SIZE].into_boxed_slice())
+            .unwrap();
+
+        // The registry owns the buffers of the outstanding read, so the state can't be saved.
+        let (mut blk, error) = blk.save().unwrap_err();
+        assert_eq!(error, Error::NotReady);
+
+        // Nor can it once the device has completed the read but the future hasn't taken it.
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |_| {
+                let mut response = vec![0; SECTOR_SIZE];
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+                response
+            });
+        assert_eq!(blk.complete_async(), Ok(1));
+        let (blk, error) = blk.save().unwrap_err();
+        assert_eq!(error, Error::NotReady);
+
+        let waker = Waker::from(Arc::new(CountingWaker::default()));
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            Pin::new(&mut future).poll(&mut cx),
+            Poll::Ready(Ok(_))
+        ));
+        assert!(blk.save().is_ok());
+    }
+
+    #[test]
+    fn poll_device() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let mut future = blk
+            .write_blocks_async(7, vec![0x42; SECTOR_SIZE].into_boxed_slice())
+            .unwrap();
+
+        // Polling with no interrupt raised doesn't count a spurious interrupt.
+        assert_eq!(VirtioDevice::poll(&mut blk), Ok(0));
+        assert_eq!(blk.interrupt_stats(), InterruptStats::default());
+
+        {
+            let mut state = state.lock().unwrap();
+            state.read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |_| {
+                BlkResp {
+                    status: RespStatus::OK,
+                }
+                .as_bytes()
+                .to_vec()
+            });
+            state.interrupt_pending = true;
+        }
+        assert_eq!(VirtioDevice::poll(&mut blk), Ok(1));
+        assert_eq!(
+            blk.interrupt_stats(),
+            InterruptStats {
+                total: 1,
+                ..Default::default()
+            }
+        );
+
+        let waker_state = Arc::new(CountingWaker::default());
+        let waker = Waker::from(waker_state);
+        let mut cx = Context::from_waker(&waker);
+        assert!(matches!(
+            Pin::new(&mut future).poll(&mut cx),
+            Poll::Ready(Ok(_))
+        ));
+    }
+}
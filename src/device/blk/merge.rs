@@ -0,0 +1,295 @@
+//! Merging of adjacent reads and writes to a VirtIO block device into larger requests.
+
+use super::{BlkReqOptions, BlkResp, ReqType, VirtIOBlk, SECTOR_SIZE};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use alloc::vec::Vec;
+use log::warn;
+use zerocopy::AsBytes;
+
+/// The direction of a run of merged requests.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum Direction {
+    Read,
+    Write,
+}
+
+/// A buffer queued for a merged request.
+#[derive(Debug)]
+enum Segment<'b> {
+    Read(&'b mut [u8]),
+    Write(&'b [u8]),
+}
+
+impl Segment<'_> {
+    fn len(&self) -> usize {
+        match self {
+            Self::Read(buf) => buf.len(),
+            Self::Write(buf) => buf.len(),
+        }
+    }
+}
+
+/// Counts of the requests seen and submitted by a [`RequestMerger`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct MergeStats {
+    /// The number of reads and writes passed to the merger.
+    pub queued: usize,
+    /// The number of requests actually submitted to the device.
+    pub submitted: usize,
+}
+
+/// Merges sequential reads or writes to a VirtIO block device into single multi-segment requests
+/// before submitting them.
+///
+/// This helps callers such as simple filesystems which issue many small requests for adjacent
+/// blocks, as the device sees one large request in place of each run of them. A read or write
+/// is merged with the previous ones if it is in the same direction and starts at the sector after
+/// they end, and the merged request stays within the segment limits of the device
+/// ([`VirtIOBlk::max_segments`] and [`VirtIOBlk::max_segment_size`]) and the queue. Otherwise the
+/// pending run is submitted first. Requests are never reordered.
+///
+/// The buffers are not copied, but are borrowed until the run they are part of has been
+/// submitted and completed. This happens when [`flush`](Self::flush) is called, or when the merger
+/// is dropped. Errors are only reported by `flush`, and apply to the whole run, so callers which
+/// care about them should always flush explicitly.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::blk::{RequestMerger, VirtIOBlk, SECTOR_SIZE};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(disk: &mut VirtIOBlk<HalImpl, T>) -> Result<(), Error> {
+/// let mut buffers = [[0; SECTOR_SIZE]; 4];
+/// let mut merger = RequestMerger::new(disk);
+/// // These will be sent to the device as a single request.
+/// for (i, buf) in buffers.iter_mut().enumerate() {
+///     merger.read_blocks(8 + i, buf)?;
+/// }
+/// merger.flush()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct RequestMerger<'a, 'b, H: Hal, T: Transport> {
+    blk: &'a mut VirtIOBlk<H, T>,
+    /// The maximum number of data segments in a merged request.
+    max_segments: usize,
+    /// The maximum size in bytes of a single data segment, if any.
+    max_segment_size: Option<usize>,
+    /// The direction of the pending run, if there is one.
+    direction: Option<Direction>,
+    /// The first sector of the pending run.
+    start: usize,
+    /// The number of sectors in the pending run.
+    sectors: usize,
+    /// The number of data segments which the pending run will take.
+    segment_count: usize,
+    pending: Vec<Segment<'b>>,
+    stats: MergeStats,
+}
+
+impl<'a, 'b, H: Hal, T: Transport> RequestMerger<'a, 'b, H, T> {
+    /// Creates a new merger for requests to the given block device.
+    pub fn new(blk: &'a mut VirtIOBlk<H, T>) -> Self {
+        let max_segments = blk.max_data_segments();
+        let max_segment_size = blk.max_segment_size().map(|size_max| size_max as usize);
+        Self {
+            blk,
+            max_segments,
+            max_segment_size,
+            direction: None,
+            start: 0,
+            sectors: 0,
+            segment_count: 0,
+            pending: Vec::new(),
+            stats: MergeStats::default(),
+        }
+    }
+
+    /// Queues a read of one or more blocks into the given buffer, merging it with the pending
+    /// reads if possible.
+    ///
+    /// If the read can't be merged then the pending run is submitted first, and any error from it
+    /// is returned. Returns [`Error::InvalidParam`] if the buffer length isn't a non-zero multiple
+    /// of [`SECTOR_SIZE`], or if the buffer alone needs more segments than the device allows in
+    /// one request.
+    pub fn read_blocks(&mut self, block_id: usize, buf: &'b mut [u8]) -> Result {
+        self.queue(Direction::Read, block_id, Segment::Read(buf))
+    }
+
+    /// Queues a write of the given buffer to one or more blocks, merging it with the pending
+    /// writes if possible.
+    ///
+    /// If the write can't be merged then the pending run is submitted first, and any error from it
+    /// is returned. Returns [`Error::InvalidParam`] if the buffer length isn't a non-zero multiple
+    /// of [`SECTOR_SIZE`], or if the buffer alone needs more segments than the device allows in
+    /// one request.
+    pub fn write_blocks(&mut self, block_id: usize, buf: &'b [u8]) -> Result {
+        self.queue(Direction::Write, block_id, Segment::Write(buf))
+    }
+
+    /// Submits the pending run, if any, and waits for it to complete.
+    pub fn flush(&mut self) -> Result {
+        let Some(direction) = self.direction.take() else {
+            return Ok(());
+        };
+        let request = self.blk.make_request(
+            match direction {
+                Direction::Read => ReqType::In,
+                Direction::Write => ReqType::Out,
+            },
+            self.start as u64,
+            BlkReqOptions::default(),
+        );
+        let segment_size = self.max_segment_size.unwrap_or(usize::MAX);
+        let mut pending = core::mem::take(&mut self.pending);
+        self.sectors = 0;
+        self.segment_count = 0;
+        let request = request?;
+
+        let mut resp = BlkResp::default();
+        let mut inputs: Vec<&[u8]> = Vec::new();
+        let mut outputs: Vec<&mut [u8]> = Vec::new();
+        inputs.push(request.as_bytes());
+        for segment in pending.iter_mut() {
+            match segment {
+                Segment::Read(buf) => outputs.extend(buf.chunks_mut(segment_size)),
+                Segment::Write(buf) => inputs.extend(buf.chunks(segment_size)),
+            }
+        }
+        outputs.push(resp.as_bytes_mut());
+        self.stats.submitted += 1;
+        self.blk
+            .queue
+            .add_notify_wait_pop(&inputs, &mut outputs, &mut self.blk.transport)?;
+        resp.status.into()
+    }
+
+    /// Returns counts of the requests queued and submitted so far.
+    pub fn stats(&self) -> MergeStats {
+        self.stats
+    }
+
+    /// Adds the given buffer to the pending run, submitting the run first if it can't be merged.
+    fn queue(&mut self, direction: Direction, block_id: usize, segment: Segment<'b>) -> Result {
+        if segment.len() == 0 || !segment.len().is_multiple_of(SECTOR_SIZE) {
+            return Err(Error::InvalidParam);
+        }
+        let segments = match self.max_segment_size {
+            Some(size) => segment.len().div_ceil(size),
+            None => 1,
+        };
+        if segments > self.max_segments {
+            return Err(Error::InvalidParam);
+        }
+        let mergeable = self.direction == Some(direction)
+            && block_id == self.start + self.sectors
+            && self.segment_count + segments <= self.max_segments;
+        if !mergeable {
+            self.flush()?;
+            self.direction = Some(direction);
+            self.start = block_id;
+        }
+        self.stats.queued += 1;
+        self.sectors += segment.len() / SECTOR_SIZE;
+        self.segment_count += segments;
+        self.pending.push(segment);
+        Ok(())
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for RequestMerger<'_, '_, H, T> {
+    fn drop(&mut self) {
+        if let Err(e) = self.flush() {
+            warn!("Error submitting merged block requests: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::blk::{
+            tests::fake_blk, BlkConfig, BlkFeature, BlkReq, RespStatus, QUEUE, QUEUE_SIZE,
+        },
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{mem::size_of, ptr::NonNull};
+    use std::{sync::Mutex, thread};
+
+    #[test]
+    fn merge_writes() {
+        let mut config_space = BlkConfig {
+            seg_max: 2,
+            ..fake_blk(64)
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: (BlkFeature::RING_INDIRECT_DESC | BlkFeature::SEG_MAX).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.max_segments(), Some(2));
+
+        // Simulate the device handling two requests: one for the first two adjacent writes, which
+        // fill the segment limit, and one for the third.
+        let handle = thread::spawn(move || {
+            for (sector, data) in [(10u64, [1u8, 2].as_slice()), (12, [3].as_slice())] {
+                State::wait_until_queue_notified(&state, QUEUE);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            &request[..size_of::<BlkReq>()],
+                            BlkReq {
+                                type_: ReqType::Out as u32,
+                                reserved: 0,
+                                sector,
+                            }
+                            .as_bytes()
+                        );
+                        let expected: Vec<u8> =
+                            data.iter().flat_map(|&byte| [byte; SECTOR_SIZE]).collect();
+                        assert_eq!(&request[size_of::<BlkReq>()..], expected);
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes()
+                        .to_vec()
+                    });
+            }
+        });
+
+        let buffers = [[1; SECTOR_SIZE], [2; SECTOR_SIZE], [3; SECTOR_SIZE]];
+        let mut merger = RequestMerger::new(&mut blk);
+        for (i, buf) in buffers.iter().enumerate() {
+            merger.write_blocks(10 + i, buf).unwrap();
+        }
+        merger.flush().unwrap();
+        assert_eq!(
+            merger.stats(),
+            MergeStats {
+                queued: 3,
+                submitted: 2,
+            }
+        );
+
+        handle.join().unwrap();
+    }
+}
@@ -0,0 +1,2499 @@
+//! Driver for VirtIO block devices.
+
+#[cfg(feature = "async")]
+mod future;
+#[cfg(feature = "alloc")]
+mod merge;
+#[cfg(feature = "alloc")]
+mod readahead;
+#[cfg(feature = "alloc")]
+mod ring;
+mod zoned;
+
+#[cfg(feature = "async")]
+pub use self::future::BlkFuture;
+#[cfg(feature = "alloc")]
+pub use self::merge::{MergeStats, RequestMerger};
+#[cfg(feature = "alloc")]
+pub use self::readahead::ReadAhead;
+#[cfg(feature = "alloc")]
+pub use self::ring::{BlkCompletion, BlkOp, BlkRing, BlkSubmission};
+pub use self::zoned::{
+    BlkZone, BlkZoneAction, BlkZoneState, BlkZoneType, BlkZonedCharacteristics, BlkZonedModel,
+};
+
+use crate::config::{changed, ConfigDiff};
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::diagnostics::SlowPathThresholds;
+use crate::display::impl_flags_display;
+use crate::fixed::FixedString;
+use crate::hal::{Hal, MemoryLocality};
+use crate::interrupt::{InterruptAccounting, InterruptStats, LostInterruptWatchdog};
+use crate::queue::{
+    InFlightLimit, QueuePlacement, Reservation, VirtQueue, VirtQueueLayout, VirtQueueState,
+};
+use crate::registry::DriverId;
+use crate::retry::RetryPolicy;
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::tunable::{self, Tunable, TunableValue, Tunables};
+use crate::{Error, Result};
+use bitflags::bitflags;
+use core::mem::{offset_of, size_of};
+use log::{info, warn};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// The index of the request queue, which the tests simulate the device on.
+#[cfg(test)]
+const QUEUE: u16 = 0;
+const QUEUE_SIZE: u16 = 16;
+/// The number of descriptors used by a read or write request: the header, the data and the status.
+const DESCRIPTORS_PER_REQUEST: usize = 3;
+/// The maximum number of data segments in a request, as the header and status each take a
+/// descriptor and a chain can't be longer than the queue even if it is indirect.
+const MAX_DATA_SEGMENTS: usize = QUEUE_SIZE as usize - 2;
+const SUPPORTED_FEATURES: BlkFeature = BlkFeature::RO
+    .union(BlkFeature::BARRIER)
+    .union(BlkFeature::SIZE_MAX)
+    .union(BlkFeature::SEG_MAX)
+    .union(BlkFeature::BLK_SIZE)
+    .union(BlkFeature::TOPOLOGY)
+    .union(BlkFeature::FLUSH)
+    .union(BlkFeature::CONFIG_WCE)
+    .union(BlkFeature::SCSI)
+    .union(BlkFeature::ZONED)
+    .union(BlkFeature::RING_INDIRECT_DESC)
+    .union(BlkFeature::RING_EVENT_IDX);
+
+/// Driver for a VirtIO block device.
+///
+/// This is a simple virtual block device, e.g. disk.
+///
+/// Read and write requests (and other exotic requests) are placed in the queue and serviced
+/// (probably out of order) by the device except where noted.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::blk::{VirtIOBlk, SECTOR_SIZE};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut disk = VirtIOBlk::<HalImpl, _>::new(transport)?;
+///
+/// println!("VirtIO block device: {} kB", disk.capacity() * SECTOR_SIZE as u64 / 2);
+///
+/// // Read sector 0 and then copy it to sector 1.
+/// let mut buf = [0; SECTOR_SIZE];
+/// disk.read_blocks(0, &mut buf)?;
+/// disk.write_blocks(1, &buf)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOBlk<H: Hal, T: Transport> {
+    id: DriverId,
+    transport: T,
+    queues: QueueLayout,
+    queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    config_snapshot: BlkConfigSnapshot,
+    block_size: usize,
+    topology: Option<BlkTopology>,
+    /// The maximum size of any single segment of a request, if the device limits it.
+    size_max: Option<u32>,
+    /// The maximum number of segments in a request, if the device limits it.
+    seg_max: Option<u32>,
+    /// The zoned characteristics, if the device is zoned.
+    zoned: Option<BlkZonedCharacteristics>,
+    negotiated_features: BlkFeature,
+    interrupts: InterruptAccounting,
+    /// Whether requests were submitted without notifying the device, so it should be notified by
+    /// the next call to `kick`.
+    pending_notify: bool,
+    /// How blocking requests are retried after transient failures.
+    retry_policy: RetryPolicy,
+    /// The registry of requests waited for by futures, created by the first asynchronous request.
+    #[cfg(feature = "async")]
+    completions: Option<alloc::sync::Arc<future::BlkCompletions>>,
+}
+
+impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
+    /// Create a new VirtIO-Blk driver.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::new_with_locality(transport, MemoryLocality::Any)
+    }
+
+    /// Create a new VirtIO-Blk driver, asking the HAL to allocate the request queue according to
+    /// the given locality hint, e.g. local to the CPU which will submit requests.
+    pub fn new_with_locality(mut transport: T, locality: MemoryLocality) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let mut blk = Self::attach(transport, negotiated_features, |transport, index| {
+            VirtQueue::new_with_locality(
+                transport,
+                index,
+                negotiated_features.contains(BlkFeature::RING_INDIRECT_DESC),
+                negotiated_features.contains(BlkFeature::RING_EVENT_IDX),
+                locality,
+            )
+        })?;
+        blk.transport.finish_init();
+        Ok(blk)
+    }
+
+    /// Restores a driver from the state saved by [`VirtIOBlk::save`], without resetting the device.
+    ///
+    /// Requests which were in flight when the state was saved can be completed as usual, e.g. with
+    /// [`VirtIOBlk::complete_read_blocks`]. If restoring fails then the transport is dropped, which
+    /// resets the device.
+    ///
+    /// # Safety
+    ///
+    /// The state must have been saved by a driver using the same HAL for the same device as the
+    /// transport, and the device must not have been reset since. Each saved state must be restored
+    /// at most once.
+    pub unsafe fn restore(transport: T, state: &BlkState) -> Result<Self> {
+        if !transport.get_status().contains(DeviceStatus::DRIVER_OK) {
+            return Err(Error::NotReady);
+        }
+        let negotiated_features = BlkFeature::from_bits_truncate(state.features);
+        Self::attach(transport, negotiated_features, |transport, index| {
+            if state.queue.layout.queue_index != index {
+                return Err(Error::InvalidParam);
+            }
+            // SAFETY: Our caller promises that the state was saved from this device's queue.
+            unsafe { VirtQueue::restore(transport, &state.queue) }
+        })
+    }
+
+    /// Saves the state of the driver, so that it can be restored by [`VirtIOBlk::restore`]
+    /// without resetting the device, e.g. across an in-place update of the kernel.
+    ///
+    /// The device keeps running and the memory of the request queue stays allocated, so requests
+    /// may still be in flight. Their buffers must stay valid until they are completed by the
+    /// restored driver.
+    ///
+    /// If the state can't be saved yet because descriptors are reserved, a request using indirect
+    /// descriptors is in flight, or the result of an asynchronous request hasn't been taken by its
+    /// future, the driver is returned along with [`Error::NotReady`].
+    // The driver is returned on error so that it can keep running, which is no bigger than passing
+    // it in.
+    #[allow(clippy::result_large_err)]
+    pub fn save(mut self) -> core::result::Result<BlkState, (Self, Error)> {
+        // The registry owns the buffers of asynchronous requests and the restored driver starts
+        // without one, so the buffers would be freed while the device still owns them and the
+        // futures would never complete.
+        #[cfg(feature = "async")]
+        if self
+            .completions
+            .as_ref()
+            .is_some_and(|completions| !completions.is_idle())
+        {
+            return Err((self, Error::NotReady));
+        }
+        let queue = match self.queue.save() {
+            Ok(queue) => queue,
+            Err(e) => return Err((self, e)),
+        };
+        let state = BlkState {
+            features: self.negotiated_features.bits(),
+            queue,
+        };
+        // Leak the driver rather than dropping it, so the device isn't reset and the queue's memory
+        // isn't freed. The registry of asynchronous requests was checked to be empty above.
+        #[cfg(feature = "async")]
+        drop(self.completions.take());
+        core::mem::forget(self);
+        Ok(state)
+    }
+
+    /// Reads the configuration of the device and sets up the driver around the request queue
+    /// returned by `queue`.
+    fn attach(
+        mut transport: T,
+        negotiated_features: BlkFeature,
+        queue: impl FnOnce(&mut T, u16) -> Result<VirtQueue<H, { QUEUE_SIZE as usize }>>,
+    ) -> Result<Self> {
+        // Read configuration space.
+        let config_snapshot = read_snapshot(&transport)?;
+        info!(
+            "found a block device of size {}KB",
+            config_snapshot.capacity / 2
+        );
+        let block_size = if negotiated_features.contains(BlkFeature::BLK_SIZE) {
+            transport.read_config::<u32>(offset_of!(BlkConfig, blk_size))? as usize
+        } else {
+            SECTOR_SIZE
+        };
+        if block_size < SECTOR_SIZE || !block_size.is_power_of_two() {
+            warn!("Invalid block size {}", block_size);
+            return Err(Error::InvalidParam);
+        }
+        let topology = if negotiated_features.contains(BlkFeature::TOPOLOGY) {
+            Some(BlkTopology {
+                physical_block_exp: transport
+                    .read_config(offset_of!(BlkConfig, physical_block_exp))?,
+                alignment_offset: transport.read_config(offset_of!(BlkConfig, alignment_offset))?,
+                min_io_size: transport.read_config(offset_of!(BlkConfig, min_io_size))?,
+                opt_io_size: transport.read_config(offset_of!(BlkConfig, opt_io_size))?,
+            })
+        } else {
+            None
+        };
+
+        let size_max = negotiated_features
+            .contains(BlkFeature::SIZE_MAX)
+            .then(|| transport.read_config::<u32>(offset_of!(BlkConfig, size_max)))
+            .transpose()?
+            .filter(|&size_max| size_max != 0);
+        let seg_max = negotiated_features
+            .contains(BlkFeature::SEG_MAX)
+            .then(|| transport.read_config::<u32>(offset_of!(BlkConfig, seg_max)))
+            .transpose()?
+            .filter(|&seg_max| seg_max != 0);
+        let zoned = zoned::read_zoned(&transport, negotiated_features)?;
+
+        // Multiqueue isn't negotiated, so there is a single request queue.
+        let queues = QueueLayout::new(DeviceType::Block, negotiated_features.bits(), 0)?;
+        let index =
+            queues.checked_index(&mut transport, QueueRole::Request(0), QUEUE_SIZE.into())?;
+        let queue = queue(&mut transport, index)?;
+
+        Ok(VirtIOBlk {
+            id: DriverId::allocate(),
+            transport,
+            queues,
+            queue,
+            config_snapshot,
+            block_size,
+            topology,
+            size_max,
+            seg_max,
+            zoned,
+            negotiated_features,
+            interrupts: InterruptAccounting::default(),
+            pending_notify: false,
+            retry_policy: RetryPolicy::default(),
+            #[cfg(feature = "async")]
+            completions: None,
+        })
+    }
+
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
+    /// Gets the capacity of the block device, in 512 byte ([`SECTOR_SIZE`]) sectors.
+    pub fn capacity(&self) -> u64 {
+        self.config_snapshot.capacity
+    }
+
+    /// Returns the logical block size of the device in bytes.
+    ///
+    /// This is [`SECTOR_SIZE`] unless the device reports a larger size with `VIRTIO_BLK_F_BLK_SIZE`.
+    /// Requests should be aligned to and a multiple of this size.
+    pub fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    /// Returns the capacity of the device in logical blocks of [`block_size`](Self::block_size).
+    pub fn num_blocks(&self) -> u64 {
+        self.capacity() / self.sectors_per_block()
+    }
+
+    /// Returns the I/O topology reported by the device, if `VIRTIO_BLK_F_TOPOLOGY` was negotiated.
+    pub fn topology(&self) -> Option<BlkTopology> {
+        self.topology
+    }
+
+    /// Returns the maximum number of data segments the device accepts in a single request, if it
+    /// limits them (`VIRTIO_BLK_F_SEG_MAX`).
+    pub fn max_segments(&self) -> Option<u32> {
+        self.seg_max
+    }
+
+    /// Returns the maximum size in bytes of any single data segment of a request, if the device
+    /// limits it (`VIRTIO_BLK_F_SIZE_MAX`).
+    pub fn max_segment_size(&self) -> Option<u32> {
+        self.size_max
+    }
+
+    /// Returns the number of 512 byte sectors in each logical block.
+    fn sectors_per_block(&self) -> u64 {
+        (self.block_size / SECTOR_SIZE) as u64
+    }
+
+    /// Converts a logical block number to the number of the first 512 byte sector in it.
+    pub fn block_to_sector(&self, block: u64) -> u64 {
+        block * self.sectors_per_block()
+    }
+
+    /// Converts a 512 byte sector number to a logical block number.
+    ///
+    /// Returns [`Error::Misaligned`] if the sector is not at the start of a logical block.
+    pub fn sector_to_block(&self, sector: u64) -> Result<u64> {
+        if !sector.is_multiple_of(self.sectors_per_block()) {
+            return Err(Error::Misaligned);
+        }
+        Ok(sector / self.sectors_per_block())
+    }
+
+    /// Checks that the given buffer is a non-zero multiple of the logical block size.
+    fn check_block_buffer(&self, len: usize) -> Result {
+        if len == 0 || !len.is_multiple_of(self.block_size) {
+            return Err(Error::Misaligned);
+        }
+        Ok(())
+    }
+
+    /// Reads one or more logical blocks of [`block_size`](Self::block_size) into the given buffer.
+    ///
+    /// Returns [`Error::Misaligned`] unless the buffer length is a non-zero multiple of the block
+    /// size.
+    ///
+    /// Blocks until the read completes or there is an error.
+    pub fn read_logical_blocks(&mut self, block: u64, buf: &mut [u8]) -> Result {
+        self.check_block_buffer(buf.len())?;
+        let sector = self.block_to_sector(block);
+        self.read_blocks(sector as usize, buf)
+    }
+
+    /// Writes the contents of the given buffer to one or more logical blocks of
+    /// [`block_size`](Self::block_size).
+    ///
+    /// Returns [`Error::Misaligned`] unless the buffer length is a non-zero multiple of the block
+    /// size.
+    ///
+    /// Blocks until the write is complete or there is an error.
+    pub fn write_logical_blocks(&mut self, block: u64, buf: &[u8]) -> Result {
+        self.check_block_buffer(buf.len())?;
+        let sector = self.block_to_sector(block);
+        self.write_blocks(sector as usize, buf)
+    }
+
+    /// Returns true if the block device is read-only, or false if it allows writes.
+    pub fn readonly(&self) -> bool {
+        self.negotiated_features.contains(BlkFeature::RO)
+    }
+
+    /// Returns whether there is a medium in the device, e.g. a disc in a CD-ROM drive.
+    ///
+    /// Devices with removable media report a capacity of zero while there is no medium, such as
+    /// when the backing file has been removed on the host. This is based on the capacity when the
+    /// configuration was last read, so [`refresh_config`](Self::refresh_config) should be called
+    /// on configuration change interrupts to keep it up to date. Reads and writes fail with
+    /// [`Error::NotReady`] while there is no medium.
+    pub fn media_present(&self) -> bool {
+        self.capacity() != 0
+    }
+
+    /// Returns true if the device's cache is in writeback mode, so writes must be followed by a
+    /// [`flush`](Self::flush) to be sure they are persisted, or false if it is in writethrough
+    /// mode.
+    pub fn writeback(&self) -> Result<bool> {
+        if self.negotiated_features.contains(BlkFeature::CONFIG_WCE) {
+            let writeback: u8 = self
+                .transport
+                .read_config(offset_of!(BlkConfig, writeback))?;
+            Ok(writeback != 0)
+        } else {
+            // Without `VIRTIO_BLK_F_CONFIG_WCE`, legacy devices which support flushing (once
+            // called `VIRTIO_BLK_F_WCE`) are writeback.
+            Ok(self.negotiated_features.contains(BlkFeature::FLUSH))
+        }
+    }
+
+    /// Switches the device's cache between writeback (`true`) and writethrough (`false`) modes.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't offer `VIRTIO_BLK_F_CONFIG_WCE`, or
+    /// [`Error::IoError`] if it didn't accept the new mode.
+    pub fn set_writeback(&mut self, writeback: bool) -> Result {
+        if !self.negotiated_features.contains(BlkFeature::CONFIG_WCE) {
+            return Err(Error::Unsupported);
+        }
+        self.transport
+            .write_config(offset_of!(BlkConfig, writeback), u8::from(writeback))?;
+        if self.writeback()? == writeback {
+            Ok(())
+        } else {
+            Err(Error::IoError)
+        }
+    }
+
+    /// Ejects the medium from a removable device, such as a CD-ROM drive.
+    ///
+    /// This sends a SCSI START STOP UNIT command, so it is only supported by transitional devices
+    /// which offer the legacy `VIRTIO_BLK_F_SCSI` feature; otherwise it returns
+    /// [`Error::Unsupported`].
+    pub fn eject(&mut self) -> Result {
+        if !self.negotiated_features.contains(BlkFeature::SCSI) {
+            return Err(Error::Unsupported);
+        }
+        let request = BlkReq {
+            type_: ReqType::ScsiCmd as u32,
+            ..Default::default()
+        };
+        let mut sense = [0; SCSI_SENSE_SIZE];
+        let mut inhdr = ScsiInHdr::default();
+        let mut resp = BlkResp::default();
+        self.queue.add_notify_wait_pop(
+            &[request.as_bytes(), &CDB_START_STOP_UNIT_EJECT],
+            &mut [&mut sense, inhdr.as_bytes_mut(), resp.as_bytes_mut()],
+            &mut self.transport,
+        )?;
+        Result::from(resp.status)?;
+        if inhdr.errors != 0 {
+            warn!("Eject failed with SCSI errors {:#x}", inhdr.errors);
+            return Err(Error::IoError);
+        }
+        Ok(())
+    }
+
+    /// Returns the NUMA nodes on which the request queue's memory was allocated.
+    pub fn queue_placement(&self) -> QueuePlacement {
+        self.queue.placement()
+    }
+
+    /// Returns the physical layout of the request queue and the current positions in its rings.
+    pub fn queue_layout(&self) -> VirtQueueLayout {
+        self.queue.export_layout()
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    ///
+    /// This should be called from the interrupt handler before processing any completed requests,
+    /// as an interrupt with no completed requests is counted as spurious. If a threshold has been
+    /// set with [`set_spurious_interrupt_threshold`](Self::set_spurious_interrupt_threshold) and
+    /// is exceeded then interrupts are disabled, and the caller should poll for completions.
+    pub fn ack_interrupt(&mut self) -> bool {
+        let acked = self.transport.ack_interrupt();
+        self.record_interrupt(acked);
+        acked
+    }
+
+    /// Counts an interrupt, which is spurious if it wasn't acknowledged or there are no completed
+    /// requests, and switches to polling if there have been too many spurious interrupts.
+    fn record_interrupt(&mut self, acked: bool) {
+        let spurious = !acked || !self.queue.can_pop();
+        if self.interrupts.record(spurious) {
+            warn!("Too many spurious interrupts, switching to polling");
+            self.queue.set_dev_notify(false);
+        }
+    }
+
+    /// Re-reads the parts of the device configuration which may change at runtime, such as the
+    /// capacity, and returns what changed since they were last read.
+    ///
+    /// This should be called when the device raises a configuration change interrupt, e.g. after
+    /// the disk is resized.
+    pub fn refresh_config(&mut self) -> Result<Option<BlkConfigChanges>> {
+        let snapshot = read_snapshot(&self.transport)?;
+        let changes = snapshot.diff(&self.config_snapshot);
+        self.config_snapshot = snapshot;
+        if let Some(changes) = &changes {
+            info!("Block device configuration changed: {:?}", changes);
+        }
+        Ok(changes)
+    }
+
+    /// Returns counts of the interrupts acknowledged so far.
+    pub fn interrupt_stats(&self) -> InterruptStats {
+        self.interrupts.stats()
+    }
+
+    /// Sets the number of consecutive spurious interrupts after which to disable interrupts and
+    /// switch to polling, or `None` (the default) to never switch.
+    pub fn set_spurious_interrupt_threshold(&mut self, threshold: Option<u32>) {
+        self.interrupts.set_threshold(threshold);
+    }
+
+    /// Returns whether interrupts were disabled because of too many spurious interrupts.
+    pub fn switched_to_polling(&self) -> bool {
+        self.interrupts.polling()
+    }
+
+    /// Sets when to suspect that an interrupt was lost, for
+    /// [`check_lost_interrupt`](Self::check_lost_interrupt). The watchdog is disabled by default.
+    pub fn set_lost_interrupt_watchdog(&mut self, watchdog: LostInterruptWatchdog) {
+        self.interrupts.set_watchdog(watchdog);
+    }
+
+    /// Checks whether the device has finished requests without its interrupt arriving, once the
+    /// [`LostInterruptWatchdog`] has expired.
+    ///
+    /// This should be called periodically, e.g. from a timer tick. If it returns true then a lost
+    /// interrupt has been logged and counted, and the caller should complete the finished requests
+    /// as if the interrupt had arrived.
+    pub fn check_lost_interrupt(&mut self) -> bool {
+        let outstanding = self.queue.in_flight() != 0 || self.queue.can_pop();
+        if !self.interrupts.watchdog_check(outstanding, H::timestamp()) || !self.queue.can_pop() {
+            return false;
+        }
+        warn!(
+            "{}: requests completed but no interrupt arrived; the interrupt may have been lost",
+            self.id
+        );
+        self.interrupts.record_lost();
+        true
+    }
+
+    /// Limits the number of non-blocking requests which may be in flight at once, or removes the
+    /// limit if `limit` is `None` (the default).
+    ///
+    /// [`read_blocks_nb`](Self::read_blocks_nb) and [`write_blocks_nb`](Self::write_blocks_nb)
+    /// then fail with [`Error::Throttled`] when the limit is reached, after first waiting for a
+    /// completion if its mode is [`ThrottleMode::Block`](crate::queue::ThrottleMode::Block). Requests submitted with a [`Reservation`] are never throttled, as they must not
+    /// fail. Returns [`Error::InvalidParam`] if the limit is zero.
+    pub fn set_in_flight_limit(&mut self, limit: Option<InFlightLimit>) -> Result {
+        self.queue.set_in_flight_limit(limit)
+    }
+
+    /// Sets the thresholds beyond which to log warnings about slow paths, such as the queue
+    /// repeatedly being full. All are disabled by default.
+    pub fn set_slow_path_thresholds(&mut self, thresholds: SlowPathThresholds) {
+        self.queue.set_slow_path_thresholds(thresholds);
+        self.interrupts
+            .set_suppression_warning(thresholds.ignored_suppression);
+    }
+
+    /// Sets how the blocking methods such as [`read_blocks`](Self::read_blocks) retry requests
+    /// which fail transiently, e.g. because the queue is full of non-blocking requests. The default
+    /// is to never retry.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Enables interrupts from the device.
+    pub fn enable_interrupts(&mut self) {
+        self.interrupts.reset_polling();
+        self.interrupts.set_disabled(false);
+        self.queue.set_dev_notify(true);
+    }
+
+    /// Disables interrupts from the device.
+    pub fn disable_interrupts(&mut self) {
+        self.interrupts.set_disabled(true);
+        self.queue.set_dev_notify(false);
+    }
+
+    /// Builds a request header of the given type, checking the options against the negotiated
+    /// features.
+    fn make_request(&self, type_: ReqType, sector: u64, options: BlkReqOptions) -> Result<BlkReq> {
+        self.check_media()?;
+        if !BlkReqFlags::all().contains(options.flags)
+            || (options.flags.contains(BlkReqFlags::BARRIER)
+                && !self.negotiated_features.contains(BlkFeature::BARRIER))
+        {
+            return Err(Error::Unsupported);
+        }
+        // The priority field only exists for legacy devices, it is reserved for modern ones. As it
+        // is only a hint it is dropped rather than failing the request.
+        let priority = if self.negotiated_features.contains(BlkFeature::VERSION_1) {
+            0
+        } else {
+            options.priority
+        };
+        Ok(BlkReq {
+            type_: type_ as u32 | options.flags.bits(),
+            reserved: priority,
+            sector,
+        })
+    }
+
+    /// Returns [`Error::NotReady`] if there is no medium to read from or write to.
+    fn check_media(&self) -> Result {
+        if self.media_present() {
+            Ok(())
+        } else {
+            Err(Error::NotReady)
+        }
+    }
+
+    /// Sends the given request to the device and waits for a response, with no extra data.
+    fn request(&mut self, request: BlkReq) -> Result {
+        let mut resp = BlkResp::default();
+        self.retry_policy.run(|| {
+            self.queue.add_notify_wait_pop(
+                &[request.as_bytes()],
+                &mut [resp.as_bytes_mut()],
+                &mut self.transport,
+            )
+        })?;
+        resp.status.into()
+    }
+
+    /// Sends the given request to the device and waits for a response, including the given data.
+    ///
+    /// The data is split into as many segments as the device's maximum segment size requires.
+    fn request_read(&mut self, request: BlkReq, data: &mut [u8]) -> Result {
+        let segment_size = self.segment_size(data.len())?;
+        let mut resp = BlkResp::default();
+        let read = data.len() + size_of::<BlkResp>();
+        let written = self.retry_policy.run(|| {
+            let mut outputs: [&mut [u8]; MAX_DATA_SEGMENTS + 1] = Default::default();
+            let mut count = 0;
+            for (output, segment) in outputs.iter_mut().zip(data.chunks_mut(segment_size)) {
+                *output = segment;
+                count += 1;
+            }
+            outputs[count] = resp.as_bytes_mut();
+            self.queue.add_notify_wait_pop(
+                &[request.as_bytes()],
+                &mut outputs[..=count],
+                &mut self.transport,
+            )
+        })?;
+        Result::from(resp.status)?;
+        self.queue.check_written(written, read);
+        Ok(())
+    }
+
+    /// Sends the given request and data to the device and waits for a response.
+    ///
+    /// The data is split into as many segments as the device's maximum segment size requires.
+    fn request_write(&mut self, request: BlkReq, data: &[u8]) -> Result {
+        let segment_size = self.segment_size(data.len())?;
+        let mut inputs: [&[u8]; MAX_DATA_SEGMENTS + 1] = Default::default();
+        inputs[0] = request.as_bytes();
+        let mut count = 1;
+        for (input, segment) in inputs[1..].iter_mut().zip(data.chunks(segment_size)) {
+            *input = segment;
+            count += 1;
+        }
+        let mut resp = BlkResp::default();
+        self.retry_policy.run(|| {
+            self.queue.add_notify_wait_pop(
+                &inputs[..count],
+                &mut [resp.as_bytes_mut()],
+                &mut self.transport,
+            )
+        })?;
+        resp.status.into()
+    }
+
+    /// Returns the size of the segments into which data of the given length must be split to
+    /// respect the device's maximum segment size, or [`Error::InvalidParam`] if that would take
+    /// more segments than one request may have.
+    fn segment_size(&self, len: usize) -> Result<usize> {
+        let Some(size_max) = self.size_max else {
+            return Ok(usize::MAX);
+        };
+        let segment_size = size_max as usize;
+        if len.div_ceil(segment_size) > self.max_data_segments() {
+            return Err(Error::InvalidParam);
+        }
+        Ok(segment_size)
+    }
+
+    /// Returns the maximum number of data segments in a single request.
+    fn max_data_segments(&self) -> usize {
+        self.seg_max.map_or(MAX_DATA_SEGMENTS, |seg_max| {
+            MAX_DATA_SEGMENTS.min(seg_max as usize)
+        })
+    }
+
+    /// Returns the largest number of bytes which a single read or write request can carry within
+    /// the device's segment limits, or `None` if the device doesn't limit the segment size.
+    ///
+    /// Returns [`Error::InvalidParam`] if the limits don't even allow a single sector.
+    fn max_request_len(&self) -> Result<Option<usize>> {
+        let Some(size_max) = self.size_max else {
+            return Ok(None);
+        };
+        let len = self.max_data_segments() * size_max as usize / SECTOR_SIZE * SECTOR_SIZE;
+        if len == 0 {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Some(len))
+    }
+
+    /// Checks that a buffer for a non-blocking request fits in a single segment.
+    fn check_single_segment(&self, len: usize) -> Result {
+        match self.size_max {
+            Some(size_max) if len > size_max as usize => Err(Error::InvalidParam),
+            _ => Ok(()),
+        }
+    }
+
+    /// Requests the device to flush any pending writes to storage.
+    ///
+    /// This will be ignored if the device doesn't support the `VIRTIO_BLK_F_FLUSH` feature.
+    pub fn flush(&mut self) -> Result {
+        if self.negotiated_features.contains(BlkFeature::FLUSH) {
+            self.request(BlkReq {
+                type_: ReqType::Flush as u32,
+                ..Default::default()
+            })
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Gets the device ID (`VIRTIO_BLK_T_GET_ID`), i.e. the serial number of the disk.
+    ///
+    /// The ID is written as ASCII into the given buffer, which must be 20 bytes long, and the used
+    /// length returned. The device pads shorter IDs with NUL bytes, but a 20 byte ID has no
+    /// terminator, so only the returned length of the buffer should be used.
+    ///
+    /// This is useful for giving disks stable names which don't depend on the order in which they
+    /// were discovered.
+    pub fn device_id(&mut self, id: &mut [u8; 20]) -> Result<usize> {
+        self.request_read(
+            BlkReq {
+                type_: ReqType::GetId as u32,
+                ..Default::default()
+            },
+            id,
+        )?;
+
+        let length = id.iter().position(|&x| x == 0).unwrap_or(20);
+        Ok(length)
+    }
+
+    /// Gets the serial number of the disk, as with [`device_id`](Self::device_id), as a string.
+    ///
+    /// Returns [`Error::IoError`] if the device ID isn't valid UTF-8.
+    pub fn serial(&mut self) -> Result<FixedString<20>> {
+        let mut id = [0; 20];
+        let length = self.device_id(&mut id)?;
+        FixedString::from_nul_padded(&id[..length]).map_err(|_| Error::IoError)
+    }
+
+    /// Reads one or more blocks into the given buffer.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    ///
+    /// Blocks until the read completes or there is an error.
+    pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
+        self.read_blocks_with_options(block_id, buf, BlkReqOptions::default())
+    }
+
+    /// Reads one or more blocks into the given buffer, applying the given per-request options.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`]. If the buffer is larger
+    /// than the device's segment limits allow in one request, it is read with several requests.
+    ///
+    /// Returns [`Error::Unsupported`] if the options ask for something the device didn't negotiate.
+    /// Blocks until the read completes or there is an error.
+    pub fn read_blocks_with_options(
+        &mut self,
+        block_id: usize,
+        buf: &mut [u8],
+        options: BlkReqOptions,
+    ) -> Result {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        let max_len = self.max_request_len()?.unwrap_or(buf.len());
+        for (i, chunk) in buf.chunks_mut(max_len).enumerate() {
+            let sector = block_id + i * max_len / SECTOR_SIZE;
+            let request = self.make_request(ReqType::In, sector as u64, options)?;
+            self.request_read(request, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Submits a request to read one or more blocks, but returns immediately without waiting for
+    /// the read to complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id` - The identifier of the first block to read.
+    /// * `req` - A buffer which the driver can use for the request to send to the device. The
+    ///   contents don't matter as `read_blocks_nb` will initialise it, but like the other buffers
+    ///   it needs to be valid (and not otherwise used) until the corresponding
+    ///   `complete_read_blocks` call. Its length must be a non-zero multiple of [`SECTOR_SIZE`].
+    /// * `buf` - The buffer in memory into which the block should be read. It is sent as a single
+    ///   segment, so [`Error::InvalidParam`] is returned if it is longer than
+    ///   [`max_segment_size`](Self::max_segment_size).
+    /// * `resp` - A mutable reference to a variable provided by the caller
+    ///   to contain the status of the request. The caller can safely
+    ///   read the variable only after the request is complete.
+    ///
+    /// # Usage
+    ///
+    /// It will submit request to the VirtIO block device and return a token identifying
+    /// the position of the first Descriptor in the chain. If there are not enough
+    /// Descriptors to allocate, then it returns [`Error::QueueFull`].
+    ///
+    /// The caller can then call `peek_used` with the returned token to check whether the device has
+    /// finished handling the request. Once it has, the caller must call `complete_read_blocks` with
+    /// the same buffers before reading the response.
+    ///
+    /// ```
+    /// # use virtio_drivers::{Error, Hal};
+    /// # use virtio_drivers::device::blk::VirtIOBlk;
+    /// # use virtio_drivers::transport::Transport;
+    /// use virtio_drivers::device::blk::{BlkReq, BlkResp, RespStatus};
+    ///
+    /// # fn example<H: Hal, T: Transport>(blk: &mut VirtIOBlk<H, T>) -> Result<(), Error> {
+    /// let mut request = BlkReq::default();
+    /// let mut buffer = [0; 512];
+    /// let mut response = BlkResp::default();
+    /// let token = unsafe { blk.read_blocks_nb(42, &mut request, &mut buffer, &mut response) }?;
+    ///
+    /// // Wait for an interrupt to tell us that the request completed...
+    /// assert_eq!(blk.peek_used(), Some(token));
+    ///
+    /// unsafe {
+    ///   blk.complete_read_blocks(token, &request, &mut buffer, &mut response)?;
+    /// }
+    /// if response.status() == RespStatus::OK {
+    ///   println!("Successfully read block.");
+    /// } else {
+    ///   println!("Error {:?} reading block.", response.status());
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    ///
+    /// # Safety
+    ///
+    /// `req`, `buf` and `resp` are still borrowed by the underlying VirtIO block device even after
+    /// this method returns. Thus, it is the caller's responsibility to guarantee that they are not
+    /// accessed before the request is completed in order to avoid data races.
+    pub unsafe fn read_blocks_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        let token = self.read_blocks_nb_no_notify(block_id, req, buf, resp)?;
+        self.kick();
+        Ok(token)
+    }
+
+    /// Like [`read_blocks_nb`](Self::read_blocks_nb), but doesn't notify the device about the
+    /// request, so that several requests can be published with one notification from a later call
+    /// to [`kick`](Self::kick).
+    ///
+    /// # Safety
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn read_blocks_nb_no_notify(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.check_single_segment(buf.len())?;
+        self.check_media()?;
+        *req = BlkReq {
+            type_: ReqType::In as u32,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        self.queue.throttle(&mut self.transport)?;
+        let token = self
+            .queue
+            .add(&[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
+        self.pending_notify = true;
+        Ok(token)
+    }
+
+    /// Notifies the device about requests submitted without notifying it, e.g. by
+    /// [`read_blocks_nb_no_notify`](Self::read_blocks_nb_no_notify), if there are any and the
+    /// device wants to be notified.
+    ///
+    /// This lets callers with their own batching scheduler publish several requests and then ring
+    /// the doorbell once. Returns whether the device was notified.
+    pub fn kick(&mut self) -> bool {
+        if core::mem::take(&mut self.pending_notify) && self.queue.should_notify() {
+            self.queue.notify(&mut self.transport);
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Completes a read operation which was started by `read_blocks_nb`.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `read_blocks_nb` when it returned
+    /// the token.
+    pub unsafe fn complete_read_blocks(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        let read = buf.len() + size_of::<BlkResp>();
+        let written =
+            self.queue
+                .pop_used(token, &[req.as_bytes()], &mut [buf, resp.as_bytes_mut()])?;
+        Result::from(resp.status)?;
+        self.queue.check_written(written, read);
+        Ok(())
+    }
+
+    /// Writes the contents of the given buffer to a block or blocks.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    ///
+    /// Blocks until the write is complete or there is an error.
+    pub fn write_blocks(&mut self, block_id: usize, buf: &[u8]) -> Result {
+        self.write_blocks_with_options(block_id, buf, BlkReqOptions::default())
+    }
+
+    /// Writes the contents of the given buffer to a block or blocks, applying the given per-request
+    /// options.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`]. If the buffer is larger
+    /// than the device's segment limits allow in one request, it is written with several requests,
+    /// so a failure may leave only some of the blocks written.
+    ///
+    /// Returns [`Error::Unsupported`] if the options ask for something the device didn't negotiate.
+    /// Blocks until the write is complete or there is an error.
+    pub fn write_blocks_with_options(
+        &mut self,
+        block_id: usize,
+        buf: &[u8],
+        options: BlkReqOptions,
+    ) -> Result {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        let max_len = self.max_request_len()?.unwrap_or(buf.len());
+        for (i, chunk) in buf.chunks(max_len).enumerate() {
+            let sector = block_id + i * max_len / SECTOR_SIZE;
+            let request = self.make_request(ReqType::Out, sector as u64, options)?;
+            self.request_write(request, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Submits a request to write one or more blocks, but returns immediately without waiting for
+    /// the write to complete.
+    ///
+    /// # Arguments
+    ///
+    /// * `block_id` - The identifier of the first block to write.
+    /// * `req` - A buffer which the driver can use for the request to send to the device. The
+    ///   contents don't matter as `read_blocks_nb` will initialise it, but like the other buffers
+    ///   it needs to be valid (and not otherwise used) until the corresponding
+    ///   `complete_write_blocks` call.
+    /// * `buf` - The buffer in memory containing the data to write to the blocks. Its length must
+    ///   be a non-zero multiple of [`SECTOR_SIZE`], and no more than
+    ///   [`max_segment_size`](Self::max_segment_size) as it is sent as a single segment.
+    /// * `resp` - A mutable reference to a variable provided by the caller
+    ///   to contain the status of the request. The caller can safely
+    ///   read the variable only after the request is complete.
+    ///
+    /// # Usage
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    ///
+    /// # Safety
+    ///
+    /// See  [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn write_blocks_nb(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        let token = self.write_blocks_nb_no_notify(block_id, req, buf, resp)?;
+        self.kick();
+        Ok(token)
+    }
+
+    /// Like [`write_blocks_nb`](Self::write_blocks_nb), but doesn't notify the device about the
+    /// request, so that several requests can be published with one notification from a later call
+    /// to [`kick`](Self::kick).
+    ///
+    /// # Safety
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn write_blocks_nb_no_notify(
+        &mut self,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.check_single_segment(buf.len())?;
+        self.check_media()?;
+        *req = BlkReq {
+            type_: ReqType::Out as u32,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        self.queue.throttle(&mut self.transport)?;
+        let token = self
+            .queue
+            .add(&[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
+        self.pending_notify = true;
+        Ok(token)
+    }
+
+    /// Reserves space in the queue for the given number of requests, so that they can later be
+    /// submitted with [`read_blocks_nb_reserved`](Self::read_blocks_nb_reserved) or
+    /// [`write_blocks_nb_reserved`](Self::write_blocks_nb_reserved) without the risk of the
+    /// queue being full or of allocating memory.
+    ///
+    /// This is useful for block layers which must not sleep while submitting requests, e.g.
+    /// because they hold a spinlock. Returns [`Error::QueueFull`] if there isn't enough space.
+    /// Any unused space must be given back with [`release`](Self::release).
+    pub fn reserve(&mut self, requests: usize) -> Result<Reservation> {
+        let descriptors = requests
+            .checked_mul(DESCRIPTORS_PER_REQUEST)
+            .ok_or(Error::QueueFull)?;
+        self.queue.reserve(descriptors)
+    }
+
+    /// Gives back any space left in the given reservation.
+    ///
+    /// Returns [`Error::InvalidParam`] if the reservation isn't from this device's queue.
+    pub fn release(&mut self, reservation: Reservation) -> Result {
+        self.queue.release(reservation)
+    }
+
+    /// Submits a request to read one or more blocks using space previously set aside with
+    /// [`reserve`](Self::reserve), and returns immediately without waiting for the read to
+    /// complete.
+    ///
+    /// This behaves like [`read_blocks_nb`](Self::read_blocks_nb), except that it never fails
+    /// because the queue is full or memory is short. It returns [`Error::InvalidParam`] if the
+    /// reservation is used up or from a different device.
+    ///
+    /// # Safety
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn read_blocks_nb_reserved(
+        &mut self,
+        reservation: &mut Reservation,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &mut [u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.check_single_segment(buf.len())?;
+        self.check_media()?;
+        *req = BlkReq {
+            type_: ReqType::In as u32,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let token = self.queue.add_reserved(
+            reservation,
+            &[req.as_bytes()],
+            &mut [buf, resp.as_bytes_mut()],
+        )?;
+        self.pending_notify = true;
+        self.kick();
+        Ok(token)
+    }
+
+    /// Submits a request to write one or more blocks using space previously set aside with
+    /// [`reserve`](Self::reserve), and returns immediately without waiting for the write to
+    /// complete.
+    ///
+    /// This behaves like [`write_blocks_nb`](Self::write_blocks_nb), except that it never fails
+    /// because the queue is full or memory is short. It returns [`Error::InvalidParam`] if the
+    /// reservation is used up or from a different device.
+    ///
+    /// # Safety
+    ///
+    /// See [VirtIOBlk::read_blocks_nb].
+    pub unsafe fn write_blocks_nb_reserved(
+        &mut self,
+        reservation: &mut Reservation,
+        block_id: usize,
+        req: &mut BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<u16> {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        self.check_single_segment(buf.len())?;
+        self.check_media()?;
+        *req = BlkReq {
+            type_: ReqType::Out as u32,
+            reserved: 0,
+            sector: block_id as u64,
+        };
+        let token = self.queue.add_reserved(
+            reservation,
+            &[req.as_bytes(), buf],
+            &mut [resp.as_bytes_mut()],
+        )?;
+        self.pending_notify = true;
+        self.kick();
+        Ok(token)
+    }
+
+    /// Completes a write operation which was started by `write_blocks_nb`.
+    ///
+    /// # Safety
+    ///
+    /// The same buffers must be passed in again as were passed to `write_blocks_nb` when it
+    /// returned the token.
+    pub unsafe fn complete_write_blocks(
+        &mut self,
+        token: u16,
+        req: &BlkReq,
+        buf: &[u8],
+        resp: &mut BlkResp,
+    ) -> Result<()> {
+        self.queue
+            .pop_used(token, &[req.as_bytes(), buf], &mut [resp.as_bytes_mut()])?;
+        resp.status.into()
+    }
+
+    /// Fetches the token of the next completed request from the used ring and returns it, without
+    /// removing it from the used ring. If there are no pending completed requests returns `None`.
+    pub fn peek_used(&mut self) -> Option<u16> {
+        self.queue.peek_used()
+    }
+
+    /// Returns the size of the device's VirtQueue.
+    ///
+    /// This can be used to tell the caller how many channels to monitor on.
+    pub fn virt_queue_size(&self) -> u16 {
+        QUEUE_SIZE
+    }
+}
+
+/// The state of a block device driver saved by [`VirtIOBlk::save`].
+///
+/// The struct has a fixed layout with no padding, so it can be serialised with
+/// [`AsBytes::as_bytes`] and parsed with [`FromBytes::read_from`].
+#[repr(C)]
+#[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct BlkState {
+    /// The features negotiated with the device.
+    pub features: u64,
+    /// The state of the request queue.
+    pub queue: VirtQueueState,
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOBlk<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        for (index, _) in self.queues.queues() {
+            self.transport.queue_unset(index);
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> Tunables for VirtIOBlk<H, T> {
+    fn tunables(&self) -> &'static [Tunable] {
+        &[
+            tunable::INTERRUPTS_ENABLED_TUNABLE,
+            tunable::SPURIOUS_INTERRUPT_THRESHOLD_TUNABLE,
+            tunable::QUEUE_SIZE_TUNABLE,
+        ]
+    }
+
+    fn tunable(&self, name: &str) -> Result<TunableValue> {
+        match name {
+            tunable::INTERRUPTS_ENABLED => Ok(TunableValue::Bool(self.interrupts.enabled())),
+            tunable::SPURIOUS_INTERRUPT_THRESHOLD => {
+                Ok(TunableValue::OptionalU32(self.interrupts.threshold()))
+            }
+            tunable::QUEUE_SIZE => Ok(TunableValue::U32(QUEUE_SIZE.into())),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    fn set_tunable(&mut self, name: &str, value: TunableValue) -> Result {
+        match name {
+            tunable::INTERRUPTS_ENABLED => {
+                if tunable::expect_bool(value)? {
+                    self.enable_interrupts();
+                } else {
+                    self.disable_interrupts();
+                }
+            }
+            tunable::SPURIOUS_INTERRUPT_THRESHOLD => {
+                self.set_spurious_interrupt_threshold(tunable::expect_optional_u32(value)?);
+            }
+            _ => return Err(tunable::set_error(self.tunables(), name)),
+        }
+        Ok(())
+    }
+}
+
+#[repr(C)]
+struct BlkConfig {
+    /// Number of 512 Bytes sectors
+    capacity_low: u32,
+    capacity_high: u32,
+    size_max: u32,
+    seg_max: u32,
+    cylinders: u16,
+    heads: u8,
+    sectors: u8,
+    blk_size: u32,
+    physical_block_exp: u8,
+    alignment_offset: u8,
+    min_io_size: u16,
+    opt_io_size: u32,
+    writeback: u8,
+    // ... ignored
+}
+
+/// Reads the fields of the block device configuration which may change at runtime.
+fn read_snapshot<T: Transport>(transport: &T) -> Result<BlkConfigSnapshot> {
+    Ok(BlkConfigSnapshot {
+        // The capacity is read as a single 64-bit field so that both halves are consistent.
+        capacity: transport.read_config(offset_of!(BlkConfig, capacity_low))?,
+    })
+}
+
+/// The fields of the block device configuration which may change at runtime.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct BlkConfigSnapshot {
+    capacity: u64,
+}
+
+impl ConfigDiff for BlkConfigSnapshot {
+    type Changes = BlkConfigChanges;
+
+    fn diff(&self, previous: &Self) -> Option<BlkConfigChanges> {
+        if self == previous {
+            return None;
+        }
+        Some(BlkConfigChanges {
+            capacity: changed(previous.capacity, self.capacity),
+        })
+    }
+}
+
+/// Changes to the configuration of a block device, as returned by
+/// [`VirtIOBlk::refresh_config`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct BlkConfigChanges {
+    /// The new capacity in 512 byte sectors, if it changed.
+    pub capacity: Option<u64>,
+}
+
+/// The I/O topology of a block device, used to choose efficient request sizes and alignment.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct BlkTopology {
+    /// The number of logical blocks per physical block, as a power of two.
+    pub physical_block_exp: u8,
+    /// The offset of the first aligned logical block.
+    pub alignment_offset: u8,
+    /// The suggested minimum I/O size, in logical blocks.
+    pub min_io_size: u16,
+    /// The optimal (and suggested maximum) I/O size, in logical blocks.
+    pub opt_io_size: u32,
+}
+
+impl BlkTopology {
+    /// Returns the physical block size in bytes, given the logical block size.
+    pub fn physical_block_size(&self, block_size: usize) -> usize {
+        block_size << self.physical_block_exp
+    }
+}
+
+/// A VirtIO block device request.
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+pub struct BlkReq {
+    /// The request type, possibly combined with [`BlkReqFlags`].
+    type_: u32,
+    /// The I/O priority for legacy devices, reserved for modern devices.
+    reserved: u32,
+    sector: u64,
+}
+
+impl Default for BlkReq {
+    fn default() -> Self {
+        Self {
+            type_: ReqType::In as u32,
+            reserved: 0,
+            sector: 0,
+        }
+    }
+}
+
+bitflags! {
+    /// Flags which may be combined with the type of a block request.
+    ///
+    /// Flags which the device didn't negotiate cause the request to fail with
+    /// [`Error::Unsupported`] rather than being silently ignored, as they may change the semantics
+    /// of the request.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct BlkReqFlags: u32 {
+        /// The request must be ordered with respect to all earlier and later requests
+        /// (`VIRTIO_BLK_T_BARRIER`).
+        ///
+        /// Only available with legacy devices which offer `VIRTIO_BLK_F_BARRIER`.
+        const BARRIER = 1 << 31;
+    }
+}
+
+impl_flags_display!(BlkReqFlags);
+
+/// Options which can be applied to an individual block request.
+///
+/// New options may be added as the specification grows, so this is constructed with
+/// [`BlkReqOptions::new`] or [`Default::default`] and the builder methods rather than a struct
+/// literal.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct BlkReqOptions {
+    /// Flags to combine with the request type.
+    pub flags: BlkReqFlags,
+    /// The I/O priority of the request.
+    ///
+    /// This is only a hint: it is passed to legacy devices and dropped for modern devices, where
+    /// the field is reserved.
+    pub priority: u32,
+}
+
+impl BlkReqOptions {
+    /// Returns the default options: no flags and the default priority.
+    pub const fn new() -> Self {
+        Self {
+            flags: BlkReqFlags::empty(),
+            priority: 0,
+        }
+    }
+
+    /// Returns a copy of the options with the given flags set.
+    pub const fn with_flags(mut self, flags: BlkReqFlags) -> Self {
+        self.flags = flags;
+        self
+    }
+
+    /// Returns a copy of the options with the given priority.
+    pub const fn with_priority(mut self, priority: u32) -> Self {
+        self.priority = priority;
+        self
+    }
+}
+
+/// Response of a VirtIOBlk request.
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+pub struct BlkResp {
+    status: RespStatus,
+}
+
+impl BlkResp {
+    /// Return the status of a VirtIOBlk request.
+    pub fn status(&self) -> RespStatus {
+        self.status
+    }
+}
+
+#[repr(u32)]
+#[derive(AsBytes, Debug)]
+enum ReqType {
+    In = 0,
+    Out = 1,
+    ScsiCmd = 2,
+    Flush = 4,
+    GetId = 8,
+    GetLifetime = 10,
+    Discard = 11,
+    WriteZeroes = 13,
+    SecureErase = 14,
+    ZoneAppend = 15,
+    ZoneReport = 16,
+    ZoneOpen = 18,
+    ZoneClose = 20,
+    ZoneFinish = 22,
+    ZoneReset = 24,
+    ZoneResetAll = 26,
+}
+
+/// The size of the sense buffer for legacy SCSI commands.
+const SCSI_SENSE_SIZE: usize = 96;
+
+/// A SCSI START STOP UNIT command with the load/eject bit set and the start bit clear, which
+/// ejects the medium.
+const CDB_START_STOP_UNIT_EJECT: [u8; 6] = [0x1b, 0, 0, 0, 0x02, 0];
+
+/// The status of a legacy SCSI command, written by the device after the sense data.
+#[repr(C)]
+#[derive(AsBytes, Debug, Default, FromBytes, FromZeroes)]
+struct ScsiInHdr {
+    errors: u32,
+    data_len: u32,
+    sense_len: u32,
+    residual: u32,
+}
+
+/// Status of a VirtIOBlk request.
+#[repr(transparent)]
+#[derive(AsBytes, Copy, Clone, Debug, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct RespStatus(u8);
+
+impl RespStatus {
+    /// Ok.
+    pub const OK: RespStatus = RespStatus(0);
+    /// IoErr.
+    pub const IO_ERR: RespStatus = RespStatus(1);
+    /// Unsupported yet.
+    pub const UNSUPPORTED: RespStatus = RespStatus(2);
+    /// Not ready.
+    pub const NOT_READY: RespStatus = RespStatus(3);
+    /// A zoned write wasn't at the write pointer of a sequential zone.
+    pub const ZONE_UNALIGNED_WP: RespStatus = RespStatus(4);
+    /// A zoned request needed to open a zone, but the maximum number of zones are already open.
+    pub const ZONE_OPEN_RESOURCE: RespStatus = RespStatus(5);
+    /// A zoned request needed to activate a zone, but the maximum number of zones are already
+    /// active.
+    pub const ZONE_ACTIVE_RESOURCE: RespStatus = RespStatus(6);
+}
+
+impl From<RespStatus> for Result {
+    fn from(status: RespStatus) -> Self {
+        match status {
+            RespStatus::OK => Ok(()),
+            RespStatus::IO_ERR => Err(Error::IoError),
+            RespStatus::UNSUPPORTED => Err(Error::Unsupported),
+            RespStatus::NOT_READY => Err(Error::NotReady),
+            _ => Err(Error::IoError),
+        }
+    }
+}
+
+impl Default for BlkResp {
+    fn default() -> Self {
+        BlkResp {
+            status: RespStatus::NOT_READY,
+        }
+    }
+}
+
+/// The standard sector size of a VirtIO block device. Data is read and written in multiples of this
+/// size.
+pub const SECTOR_SIZE: usize = 512;
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct BlkFeature: u64 {
+        /// Device supports request barriers. (legacy)
+        const BARRIER       = 1 << 0;
+        /// Maximum size of any single segment is in `size_max`.
+        const SIZE_MAX      = 1 << 1;
+        /// Maximum number of segments in a request is in `seg_max`.
+        const SEG_MAX       = 1 << 2;
+        /// Disk-style geometry specified in geometry.
+        const GEOMETRY      = 1 << 4;
+        /// Device is read-only.
+        const RO            = 1 << 5;
+        /// Block size of disk is in `blk_size`.
+        const BLK_SIZE      = 1 << 6;
+        /// Device supports scsi packet commands. (legacy)
+        const SCSI          = 1 << 7;
+        /// Cache flush command support.
+        const FLUSH         = 1 << 9;
+        /// Device exports information on optimal I/O alignment.
+        const TOPOLOGY      = 1 << 10;
+        /// Device can toggle its cache between writeback and writethrough modes.
+        const CONFIG_WCE    = 1 << 11;
+        /// Device supports multiqueue.
+        const MQ            = 1 << 12;
+        /// Device can support discard command, maximum discard sectors size in
+        /// `max_discard_sectors` and maximum discard segment number in
+        /// `max_discard_seg`.
+        const DISCARD       = 1 << 13;
+        /// Device can support write zeroes command, maximum write zeroes sectors
+        /// size in `max_write_zeroes_sectors` and maximum write zeroes segment
+        /// number in `max_write_zeroes_seg`.
+        const WRITE_ZEROES  = 1 << 14;
+        /// Device supports providing storage lifetime information.
+        const LIFETIME      = 1 << 15;
+        /// Device can support the secure erase command.
+        const SECURE_ERASE  = 1 << 16;
+        /// Device is a zoned block device, with its characteristics in `zoned`.
+        const ZONED         = 1 << 17;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // the following since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+impl_flags_display!(BlkFeature);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{mem::size_of, ptr::NonNull, sync::atomic::Ordering};
+    use std::{sync::Mutex, thread};
+
+    /// Returns the configuration space of a block device with the given capacity in sectors and
+    /// every other field zero.
+    pub(super) fn fake_blk(capacity: u64) -> BlkConfig {
+        BlkConfig {
+            capacity_low: capacity as u32,
+            capacity_high: (capacity >> 32) as u32,
+            size_max: 0,
+            seg_max: 0,
+            cylinders: 0,
+            heads: 0,
+            sectors: 0,
+            blk_size: 0,
+            physical_block_exp: 0,
+            alignment_offset: 0,
+            min_io_size: 0,
+            opt_io_size: 0,
+            writeback: 0,
+        }
+    }
+
+    #[test]
+    fn config() {
+        let mut config_space = fake_blk(0x02_0000_0042);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RO.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(blk.capacity(), 0x02_0000_0042);
+        assert!(blk.readonly());
+    }
+
+    #[test]
+    fn resize() {
+        let mut config_space = fake_blk(64);
+        let config_space_ptr = NonNull::from(&mut config_space);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: config_space_ptr,
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        assert_eq!(blk.refresh_config(), Ok(None));
+
+        // Simulate the host growing the disk.
+        // Safe because nothing else is accessing the config space at the moment.
+        unsafe {
+            (*config_space_ptr.as_ptr()).capacity_low = 128;
+        }
+        state.lock().unwrap().config_generation += 1;
+        assert_eq!(
+            blk.refresh_config(),
+            Ok(Some(BlkConfigChanges {
+                capacity: Some(128)
+            }))
+        );
+        assert_eq!(blk.capacity(), 128);
+        assert_eq!(blk.refresh_config(), Ok(None));
+    }
+
+    #[test]
+    fn logical_block_size() {
+        let mut config_space = BlkConfig {
+            blk_size: 4096,
+            physical_block_exp: 1,
+            min_io_size: 1,
+            opt_io_size: 8,
+            ..fake_blk(64)
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: (BlkFeature::BLK_SIZE | BlkFeature::TOPOLOGY).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert_eq!(blk.block_size(), 4096);
+        assert_eq!(blk.num_blocks(), 8);
+        let topology = blk.topology().unwrap();
+        assert_eq!(topology.physical_block_size(blk.block_size()), 8192);
+        assert_eq!(topology.opt_io_size, 8);
+        assert_eq!(blk.block_to_sector(3), 24);
+        assert_eq!(blk.sector_to_block(24), Ok(3));
+        assert_eq!(blk.sector_to_block(25), Err(Error::Misaligned));
+
+        let mut buffer = [0; SECTOR_SIZE];
+        assert_eq!(
+            blk.read_logical_blocks(1, &mut buffer),
+            Err(Error::Misaligned)
+        );
+        assert_eq!(blk.write_logical_blocks(1, &buffer), Err(Error::Misaligned));
+    }
+
+    #[test]
+    fn spurious_interrupts() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        blk.set_spurious_interrupt_threshold(Some(2));
+
+        // An interrupt with no completed requests is spurious.
+        state.lock().unwrap().interrupt_pending = true;
+        assert!(blk.ack_interrupt());
+        assert!(!blk.switched_to_polling());
+        assert!(!blk.ack_interrupt());
+        assert!(blk.switched_to_polling());
+        assert_eq!(
+            blk.interrupt_stats(),
+            InterruptStats {
+                total: 2,
+                spurious: 2,
+                lost: 0,
+            }
+        );
+
+        blk.enable_interrupts();
+        assert!(!blk.switched_to_polling());
+    }
+
+    #[test]
+    fn lost_interrupt() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        blk.set_lost_interrupt_watchdog(LostInterruptWatchdog {
+            checks: Some(2),
+            timeout: None,
+        });
+
+        let mut request = BlkReq::default();
+        let mut buffer = [0; SECTOR_SIZE];
+        let mut response = BlkResp::default();
+        let token =
+            unsafe { blk.read_blocks_nb(42, &mut request, &mut buffer, &mut response) }.unwrap();
+
+        // The device is slow, so there's nothing to report yet.
+        assert!(!blk.check_lost_interrupt());
+        assert!(!blk.check_lost_interrupt());
+
+        // The device completes the request, but its interrupt never arrives.
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |_| {
+                let mut response = vec![0; SECTOR_SIZE];
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+                response
+            });
+        assert!(!blk.check_lost_interrupt());
+        assert!(blk.check_lost_interrupt());
+        assert_eq!(blk.interrupt_stats().lost, 1);
+        assert_eq!(blk.peek_used(), Some(token));
+        unsafe {
+            blk.complete_read_blocks(token, &request, &mut buffer, &mut response)
+                .unwrap();
+        }
+        assert!(!blk.check_lost_interrupt());
+    }
+
+    #[test]
+    fn writeback() {
+        let mut config_space = BlkConfig {
+            writeback: 1,
+            ..fake_blk(66)
+        };
+        let config_space_ptr = NonNull::from(&mut config_space);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::CONFIG_WCE.bits(),
+            config_space: config_space_ptr,
+            state,
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        assert!(blk.writeback().unwrap());
+        blk.set_writeback(false).unwrap();
+        assert!(!blk.writeback().unwrap());
+        // Safe because nothing else is accessing the config space at the moment.
+        assert_eq!(unsafe { (*config_space_ptr.as_ptr()).writeback }, 0);
+    }
+
+    #[test]
+    fn no_media() {
+        let mut config_space = BlkConfig {
+            writeback: 1,
+            ..fake_blk(0)
+        };
+        let config_space_ptr = NonNull::from(&mut config_space);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: config_space_ptr,
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Without a medium, I/O fails without sending anything to the device.
+        assert!(!blk.media_present());
+        let mut buffer = [0; SECTOR_SIZE];
+        assert_eq!(blk.read_blocks(0, &mut buffer), Err(Error::NotReady));
+        assert_eq!(blk.write_blocks(0, &buffer), Err(Error::NotReady));
+        assert!(!state.lock().unwrap().queues[0]
+            .notified
+            .load(Ordering::SeqCst));
+        // Ejecting needs the legacy SCSI feature.
+        assert_eq!(blk.eject(), Err(Error::Unsupported));
+        assert!(!blk.writeback().unwrap());
+        assert_eq!(blk.set_writeback(true), Err(Error::Unsupported));
+
+        // Simulate the host inserting a medium.
+        // Safe because nothing else is accessing the config space at the moment.
+        unsafe {
+            (*config_space_ptr.as_ptr()).capacity_low = 8;
+        }
+        state.lock().unwrap().config_generation += 1;
+        assert_eq!(
+            blk.refresh_config(),
+            Ok(Some(BlkConfigChanges { capacity: Some(8) }))
+        );
+        assert!(blk.media_present());
+    }
+
+    #[test]
+    fn tunables() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        for tunable in blk.tunables() {
+            assert!(blk.tunable(tunable.name).is_ok());
+        }
+        assert_eq!(
+            blk.tunable("queue_size"),
+            Ok(TunableValue::U32(QUEUE_SIZE.into()))
+        );
+        assert_eq!(blk.tunable("nonexistent"), Err(Error::InvalidParam));
+
+        blk.set_tunable(
+            "spurious_interrupt_threshold",
+            TunableValue::OptionalU32(Some(3)),
+        )
+        .unwrap();
+        assert_eq!(
+            blk.tunable("spurious_interrupt_threshold"),
+            Ok(TunableValue::OptionalU32(Some(3)))
+        );
+        blk.set_tunable("interrupts_enabled", TunableValue::Bool(false))
+            .unwrap();
+        assert_eq!(
+            blk.tunable("interrupts_enabled"),
+            Ok(TunableValue::Bool(false))
+        );
+
+        assert_eq!(
+            blk.set_tunable("interrupts_enabled", TunableValue::U32(1)),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            blk.set_tunable("queue_size", TunableValue::U32(4)),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(
+            blk.set_tunable("nonexistent", TunableValue::U32(4)),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn read() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device waiting for a read request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::In as u32,
+                            reserved: 0,
+                            sector: 42
+                        }
+                        .as_bytes()
+                    );
+
+                    let mut response = vec![0; SECTOR_SIZE];
+                    response[0..9].copy_from_slice(b"Test data");
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+
+                    response
+                });
+        });
+
+        // Read a block from the device.
+        let mut buffer = [0; 512];
+        blk.read_blocks(42, &mut buffer).unwrap();
+        assert_eq!(&buffer[0..9], b"Test data");
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn segment_limits() {
+        let mut config_space = BlkConfig {
+            size_max: SECTOR_SIZE as u32,
+            seg_max: 2,
+            ..fake_blk(66)
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: (BlkFeature::SIZE_MAX | BlkFeature::SEG_MAX).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // A request can carry at most two sectors, so a four sector write is split in two.
+        let handle = thread::spawn(move || {
+            for (sector, data) in [(10u64, [1u8, 2]), (12, [3, 4])] {
+                State::wait_until_queue_notified(&state, QUEUE);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            &request[..size_of::<BlkReq>()],
+                            BlkReq {
+                                type_: ReqType::Out as u32,
+                                reserved: 0,
+                                sector,
+                            }
+                            .as_bytes()
+                        );
+                        let expected: Vec<u8> =
+                            data.iter().flat_map(|&byte| [byte; SECTOR_SIZE]).collect();
+                        assert_eq!(&request[size_of::<BlkReq>()..], expected);
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes()
+                        .to_vec()
+                    });
+            }
+        });
+        let buffer: Vec<u8> = [1, 2, 3, 4]
+            .iter()
+            .flat_map(|&byte| [byte; SECTOR_SIZE])
+            .collect();
+        blk.write_blocks(10, &buffer).unwrap();
+        handle.join().unwrap();
+
+        // Non-blocking requests send the buffer as one segment, so it must fit in one.
+        let mut request = BlkReq::default();
+        let mut buffer = [0; 2 * SECTOR_SIZE];
+        let mut response = BlkResp::default();
+        assert_eq!(
+            unsafe { blk.read_blocks_nb(0, &mut request, &mut buffer, &mut response) },
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn save_restore() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::FLUSH.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // State can't be saved while descriptors are reserved.
+        let reservation = blk.reserve(1).unwrap();
+        let (mut blk, error) = blk.save().unwrap_err();
+        assert_eq!(error, Error::NotReady);
+        blk.release(reservation).unwrap();
+
+        // Start a read and save the state while it is in flight.
+        let mut request = BlkReq::default();
+        let mut buffer = [0; 512];
+        let mut response = BlkResp::default();
+        let token =
+            unsafe { blk.read_blocks_nb(42, &mut request, &mut buffer, &mut response) }.unwrap();
+        let Ok(saved) = blk.save() else {
+            panic!("Failed to save state");
+        };
+        let saved = BlkState::read_from(saved.as_bytes()).unwrap();
+        assert_eq!(saved.features, BlkFeature::FLUSH.bits());
+        assert_eq!(saved.queue.layout.descriptors_in_use, 3);
+        assert!(state
+            .lock()
+            .unwrap()
+            .status
+            .contains(DeviceStatus::DRIVER_OK));
+
+        // The device completes the request while the driver is being replaced.
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::In as u32,
+                        reserved: 0,
+                        sector: 42
+                    }
+                    .as_bytes()
+                );
+                let mut response = vec![0; SECTOR_SIZE];
+                response[0..9].copy_from_slice(b"Test data");
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+                response
+            });
+
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::FLUSH.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk =
+            unsafe { VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::restore(transport, &saved) }
+                .unwrap();
+        assert_eq!(blk.capacity(), 66);
+        assert_eq!(blk.peek_used(), Some(token));
+        unsafe { blk.complete_read_blocks(token, &request, &mut buffer, &mut response) }.unwrap();
+        assert_eq!(response.status(), RespStatus::OK);
+        assert_eq!(&buffer[0..9], b"Test data");
+        assert_eq!(blk.queue_layout().descriptors_in_use, 0);
+    }
+
+    #[test]
+    fn write() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device waiting for a write request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        &request[0..size_of::<BlkReq>()],
+                        BlkReq {
+                            type_: ReqType::Out as u32,
+                            reserved: 0,
+                            sector: 42
+                        }
+                        .as_bytes()
+                    );
+                    let data = &request[size_of::<BlkReq>()..];
+                    assert_eq!(data.len(), SECTOR_SIZE);
+                    assert_eq!(&data[0..9], b"Test data");
+
+                    let mut response = Vec::new();
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+
+                    response
+                });
+        });
+
+        // Write a block to the device.
+        let mut buffer = [0; 512];
+        buffer[0..9].copy_from_slice(b"Test data");
+        blk.write_blocks(42, &buffer).unwrap();
+
+        // Request to flush should be ignored as the device doesn't support it.
+        blk.flush().unwrap();
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn no_notify_then_kick() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let notified = |state: &Mutex<State>| {
+            state.lock().unwrap().queues[usize::from(QUEUE)]
+                .notified
+                .swap(false, Ordering::SeqCst)
+        };
+
+        // Nothing to notify about yet.
+        assert!(!blk.kick());
+
+        // Publish a read and a write without notifying the device.
+        let mut read_request = BlkReq::default();
+        let mut read_buffer = [0; SECTOR_SIZE];
+        let mut read_response = BlkResp::default();
+        let read_token = unsafe {
+            blk.read_blocks_nb_no_notify(1, &mut read_request, &mut read_buffer, &mut read_response)
+        }
+        .unwrap();
+        let mut write_request = BlkReq::default();
+        let write_buffer = [0x42; SECTOR_SIZE];
+        let mut write_response = BlkResp::default();
+        let write_token = unsafe {
+            blk.write_blocks_nb_no_notify(2, &mut write_request, &write_buffer, &mut write_response)
+        }
+        .unwrap();
+        assert!(!notified(&state));
+        assert_eq!(blk.peek_used(), None);
+
+        // One kick notifies the device about both, and a second has nothing left to do.
+        assert!(blk.kick());
+        assert!(notified(&state));
+        assert!(!blk.kick());
+        assert!(!notified(&state));
+
+        // The device completes both requests in order.
+        for _ in 0..2 {
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    // Only a write request has data after the header for the device to read.
+                    let mut response = if request.len() == size_of::<BlkReq>() {
+                        vec![0x55; SECTOR_SIZE]
+                    } else {
+                        assert_eq!(&request[size_of::<BlkReq>()..], &[0x42; SECTOR_SIZE]);
+                        Vec::new()
+                    };
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+                    response
+                });
+        }
+        assert_eq!(blk.peek_used(), Some(read_token));
+        unsafe {
+            blk.complete_read_blocks(
+                read_token,
+                &read_request,
+                &mut read_buffer,
+                &mut read_response,
+            )
+        }
+        .unwrap();
+        assert_eq!(read_response.status(), RespStatus::OK);
+        assert_eq!(read_buffer, [0x55; SECTOR_SIZE]);
+        assert_eq!(blk.peek_used(), Some(write_token));
+        unsafe {
+            blk.complete_write_blocks(
+                write_token,
+                &write_request,
+                &write_buffer,
+                &mut write_response,
+            )
+        }
+        .unwrap();
+        assert_eq!(write_response.status(), RespStatus::OK);
+    }
+
+    #[test]
+    fn barrier_unsupported() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::VERSION_1.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        let buffer = [0; 512];
+        assert_eq!(
+            blk.write_blocks_with_options(
+                42,
+                &buffer,
+                BlkReqOptions::new().with_flags(BlkReqFlags::BARRIER)
+            ),
+            Err(Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn write_barrier_legacy() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::BARRIER.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device waiting for a write request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        &request[0..size_of::<BlkReq>()],
+                        BlkReq {
+                            type_: ReqType::Out as u32 | 1 << 31,
+                            reserved: 3,
+                            sector: 42
+                        }
+                        .as_bytes()
+                    );
+
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes()
+                    .to_vec()
+                });
+        });
+
+        let buffer = [0; 512];
+        blk.write_blocks_with_options(
+            42,
+            &buffer,
+            BlkReqOptions::new()
+                .with_flags(BlkReqFlags::BARRIER)
+                .with_priority(3),
+        )
+        .unwrap();
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn flush() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: (BlkFeature::RING_INDIRECT_DESC | BlkFeature::FLUSH).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device waiting for a flush request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::Flush as u32,
+                            reserved: 0,
+                            sector: 0,
+                        }
+                        .as_bytes()
+                    );
+
+                    let mut response = Vec::new();
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+
+                    response
+                });
+        });
+
+        // Request to flush.
+        blk.flush().unwrap();
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn device_id() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device waiting for a flush request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::GetId as u32,
+                            reserved: 0,
+                            sector: 0,
+                        }
+                        .as_bytes()
+                    );
+
+                    let mut response = Vec::new();
+                    response.extend_from_slice(b"device_id\0\0\0\0\0\0\0\0\0\0\0");
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+
+                    response
+                });
+        });
+
+        let mut id = [0; 20];
+        let length = blk.device_id(&mut id).unwrap();
+        assert_eq!(&id[0..length], b"device_id");
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn device_id_full_length() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device waiting for a flush request.
+        let handle = thread::spawn(move || {
+            println!("Device waiting for a request.");
+            State::wait_until_queue_notified(&state, QUEUE);
+            println!("Transmit queue was notified.");
+
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::GetId as u32,
+                            reserved: 0,
+                            sector: 0,
+                        }
+                        .as_bytes()
+                    );
+
+                    let mut response = Vec::new();
+                    response.extend_from_slice(b"0123456789abcdefghij");
+                    response.extend_from_slice(
+                        BlkResp {
+                            status: RespStatus::OK,
+                        }
+                        .as_bytes(),
+                    );
+
+                    response
+                });
+        });
+
+        let mut id = [0; 20];
+        let length = blk.device_id(&mut id).unwrap();
+        assert_eq!(length, 20);
+        assert_eq!(&id, b"0123456789abcdefghij");
+
+        handle.join().unwrap();
+    }
+}
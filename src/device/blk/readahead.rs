@@ -0,0 +1,405 @@
+//! Read-ahead for sequential reads from a VirtIO block device.
+
+use super::{BlkReq, BlkResp, VirtIOBlk, SECTOR_SIZE};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use alloc::{boxed::Box, vec::Vec};
+use core::{
+    hint::spin_loop,
+    mem::{forget, take},
+};
+use log::warn;
+
+/// The state of one read-ahead request.
+#[derive(Debug)]
+enum SlotState {
+    /// No request is associated with the slot.
+    Idle,
+    /// The request has been submitted to the device with the given token.
+    InFlight(u16),
+    /// The request has completed with the given result.
+    Done(Result),
+}
+
+/// A buffer for one read-ahead request, along with the request and response headers.
+#[derive(Debug)]
+struct Slot {
+    /// The first block covered by the request.
+    start: usize,
+    /// The number of blocks covered by the request.
+    blocks: usize,
+    req: BlkReq,
+    resp: BlkResp,
+    buf: Box<[u8]>,
+    state: SlotState,
+}
+
+impl Slot {
+    /// Returns whether the slot holds or will hold the given block.
+    fn contains(&self, block: usize) -> bool {
+        !matches!(self.state, SlotState::Idle)
+            && block >= self.start
+            && block < self.start + self.blocks
+    }
+}
+
+/// Keeps a number of sequential read requests in flight to a VirtIO block device, and serves reads
+/// from the completed buffers.
+///
+/// This can greatly improve throughput for callers which read one block at a time in order, such as
+/// a simple kernel loading files at boot, as the device can work on later blocks while the caller
+/// is busy with earlier ones. Reads which don't follow on from the previous one discard the
+/// prefetched data and restart the pipeline from the new position.
+///
+/// The device must not be written to while the read-ahead is active; this is enforced by borrowing
+/// the [`VirtIOBlk`] mutably. Requests submitted before the `ReadAhead` was created must have
+/// completed and been popped from the used ring, as otherwise their tokens are in the way and reads
+/// fail with [`Error::WrongToken`]. Any requests still in flight are waited for when the
+/// `ReadAhead` is dropped.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::blk::{ReadAhead, VirtIOBlk, SECTOR_SIZE};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(disk: &mut VirtIOBlk<HalImpl, T>) -> Result<(), Error> {
+/// // Keep up to 4 requests of 8 blocks each in flight.
+/// let mut reader = ReadAhead::new(disk, 4, 8)?;
+/// let mut buf = [0; SECTOR_SIZE];
+/// for block in 0..64 {
+///     reader.read_blocks(block, &mut buf)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct ReadAhead<'a, H: Hal, T: Transport> {
+    blk: &'a mut VirtIOBlk<H, T>,
+    /// The request slots. These are boxed so that the buffers passed to the device don't move.
+    slots: Box<[Slot]>,
+    /// The next block to request from the device.
+    next_block: usize,
+}
+
+impl<'a, H: Hal, T: Transport> ReadAhead<'a, H, T> {
+    /// Creates a new read-ahead layer over the given block device, which will keep up to `depth`
+    /// requests of `blocks_per_request` blocks each in flight.
+    ///
+    /// Returns [`Error::InvalidParam`] if either is zero. If the queue doesn't have room for `depth`
+    /// requests then fewer will be kept in flight.
+    pub fn new(
+        blk: &'a mut VirtIOBlk<H, T>,
+        depth: usize,
+        blocks_per_request: usize,
+    ) -> Result<Self> {
+        if depth == 0 || blocks_per_request == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let slots = (0..depth)
+            .map(|_| Slot {
+                start: 0,
+                blocks: 0,
+                req: BlkReq::default(),
+                resp: BlkResp::default(),
+                buf: alloc::vec![0; blocks_per_request * SECTOR_SIZE].into_boxed_slice(),
+                state: SlotState::Idle,
+            })
+            .collect::<Vec<_>>()
+            .into_boxed_slice();
+        Ok(Self {
+            blk,
+            slots,
+            next_block: 0,
+        })
+    }
+
+    /// Reads one or more blocks into the given buffer, from prefetched data where possible.
+    ///
+    /// The buffer length must be a non-zero multiple of [`SECTOR_SIZE`].
+    ///
+    /// Blocks until the data is available or there is an error. Returns [`Error::WrongToken`] if the
+    /// next completion in the used ring is for a request which the read-ahead didn't submit.
+    pub fn read_blocks(&mut self, block_id: usize, buf: &mut [u8]) -> Result {
+        assert_ne!(buf.len(), 0);
+        assert_eq!(buf.len() % SECTOR_SIZE, 0);
+        for (i, chunk) in buf.chunks_exact_mut(SECTOR_SIZE).enumerate() {
+            self.read_block(block_id + i, chunk)?;
+        }
+        Ok(())
+    }
+
+    /// Reads a single block into the given buffer.
+    fn read_block(&mut self, block: usize, buf: &mut [u8]) -> Result {
+        let index = match self.slots.iter().position(|slot| slot.contains(block)) {
+            Some(index) => index,
+            None => {
+                // The read isn't sequential, so discard everything and start again from here.
+                self.drain()?;
+                for slot in self.slots.iter_mut() {
+                    slot.state = SlotState::Idle;
+                }
+                self.next_block = block;
+                self.fill();
+                match self.slots.iter().position(|slot| slot.contains(block)) {
+                    Some(index) => index,
+                    // Past the end of the device or no room in the queue, so let the device deal
+                    // with it directly.
+                    None => return self.blk.read_blocks(block, buf),
+                }
+            }
+        };
+
+        self.wait(index)?;
+        let slot = &self.slots[index];
+        if let SlotState::Done(result) = slot.state {
+            result?;
+        }
+        let offset = (block - slot.start) * SECTOR_SIZE;
+        buf.copy_from_slice(&slot.buf[offset..offset + SECTOR_SIZE]);
+
+        // Everything before this block has been consumed, so reuse those slots for later blocks.
+        for slot in self.slots.iter_mut() {
+            if matches!(slot.state, SlotState::Done(_)) && slot.start + slot.blocks <= block {
+                slot.state = SlotState::Idle;
+            }
+        }
+        self.fill();
+        Ok(())
+    }
+
+    /// Submits requests for the following blocks in all idle slots, until the end of the device or
+    /// the queue is full.
+    fn fill(&mut self) {
+        let capacity = self.blk.capacity() as usize;
+        for slot in self.slots.iter_mut() {
+            if !matches!(slot.state, SlotState::Idle) {
+                continue;
+            }
+            if self.next_block >= capacity {
+                return;
+            }
+            let blocks = (slot.buf.len() / SECTOR_SIZE).min(capacity - self.next_block);
+            // Safe because the slots are boxed so won't move, and aren't accessed or dropped until
+            // the request is completed in `complete_one`.
+            let token = unsafe {
+                self.blk.read_blocks_nb(
+                    self.next_block,
+                    &mut slot.req,
+                    &mut slot.buf[..blocks * SECTOR_SIZE],
+                    &mut slot.resp,
+                )
+            };
+            match token {
+                Ok(token) => {
+                    slot.start = self.next_block;
+                    slot.blocks = blocks;
+                    slot.state = SlotState::InFlight(token);
+                    self.next_block += blocks;
+                }
+                // Leave the slot idle and try again after some requests have completed.
+                Err(_) => return,
+            }
+        }
+    }
+
+    /// Waits until the request in the given slot has completed.
+    fn wait(&mut self, index: usize) -> Result {
+        while matches!(self.slots[index].state, SlotState::InFlight(_)) {
+            if !self.complete_one()? {
+                spin_loop();
+            }
+        }
+        Ok(())
+    }
+
+    /// Waits until all requests in flight have completed.
+    fn drain(&mut self) -> Result {
+        for index in 0..self.slots.len() {
+            self.wait(index)?;
+        }
+        Ok(())
+    }
+
+    /// Completes the next request which the device has finished with, if any.
+    ///
+    /// Returns true if a request was completed, or [`Error::WrongToken`] if the next completion is
+    /// for a request which isn't in any slot, as it would otherwise block ours forever.
+    fn complete_one(&mut self) -> Result<bool> {
+        let Some(token) = self.blk.peek_used() else {
+            return Ok(false);
+        };
+        let slot = self
+            .slots
+            .iter_mut()
+            .find(|slot| matches!(slot.state, SlotState::InFlight(t) if t == token))
+            .ok_or(Error::WrongToken)?;
+        // Safe because these are the same buffers as were passed to `read_blocks_nb`.
+        let result = unsafe {
+            self.blk.complete_read_blocks(
+                token,
+                &slot.req,
+                &mut slot.buf[..slot.blocks * SECTOR_SIZE],
+                &mut slot.resp,
+            )
+        };
+        slot.state = SlotState::Done(result);
+        Ok(true)
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for ReadAhead<'_, H, T> {
+    fn drop(&mut self) {
+        // The device may still be writing to the buffers, so wait for it to finish before they are
+        // freed.
+        if self.drain().is_err() {
+            // Another request is in the way, so there is no way to tell when ours finish. Leak the
+            // buffers rather than let the device write to freed memory.
+            warn!("Leaking read-ahead buffers as a foreign request is blocking the used ring");
+            forget(take(&mut self.slots));
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::blk::{
+            tests::fake_blk, BlkConfig, BlkFeature, ReqType, RespStatus, QUEUE, QUEUE_SIZE,
+        },
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{mem::size_of, ptr::NonNull, time::Duration};
+    use std::{sync::Mutex, thread};
+    use zerocopy::AsBytes;
+
+    #[test]
+    fn sequential_reads() {
+        let mut config_space = fake_blk(4);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device handling a read request for each block in turn,
+        // filling each block with its own index.
+        let handle = thread::spawn(move || {
+            for expected_sector in 0..4u8 {
+                while !state
+                    .lock()
+                    .unwrap()
+                    .queue_has_available::<{ QUEUE_SIZE as usize }>(QUEUE)
+                {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                        assert_eq!(
+                            &request[..size_of::<BlkReq>()],
+                            BlkReq {
+                                type_: ReqType::In as u32,
+                                reserved: 0,
+                                sector: expected_sector.into(),
+                            }
+                            .as_bytes()
+                        );
+
+                        let mut response = vec![expected_sector; SECTOR_SIZE];
+                        response.extend_from_slice(
+                            BlkResp {
+                                status: RespStatus::OK,
+                            }
+                            .as_bytes(),
+                        );
+                        response
+                    });
+            }
+        });
+
+        let mut reader = ReadAhead::new(&mut blk, 2, 1).unwrap();
+        let mut buffer = [0; SECTOR_SIZE];
+        for block in 0..4 {
+            reader.read_blocks(block, &mut buffer).unwrap();
+            assert_eq!(buffer, [block as u8; SECTOR_SIZE]);
+        }
+        drop(reader);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn foreign_token() {
+        let mut config_space = fake_blk(4);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+
+        // Submit a request which the read-ahead doesn't know about.
+        let mut req = BlkReq::default();
+        let mut resp = BlkResp::default();
+        let mut buf = [0; SECTOR_SIZE];
+        let token = unsafe { blk.read_blocks_nb(3, &mut req, &mut buf, &mut resp) }.unwrap();
+
+        // Complete it and the read-ahead's request, in that order.
+        let handle = thread::spawn(move || {
+            for _ in 0..2 {
+                while !state
+                    .lock()
+                    .unwrap()
+                    .queue_has_available::<{ QUEUE_SIZE as usize }>(QUEUE)
+                {
+                    thread::sleep(Duration::from_millis(1));
+                }
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |_| {
+                        let mut response = vec![0; SECTOR_SIZE];
+                        response.extend_from_slice(
+                            BlkResp {
+                                status: RespStatus::OK,
+                            }
+                            .as_bytes(),
+                        );
+                        response
+                    });
+            }
+        });
+
+        let mut reader = ReadAhead::new(&mut blk, 1, 1).unwrap();
+        let mut buffer = [0; SECTOR_SIZE];
+        assert_eq!(reader.read_blocks(0, &mut buffer), Err(Error::WrongToken));
+        drop(reader);
+        handle.join().unwrap();
+
+        // The foreign request can still be completed.
+        assert_eq!(blk.peek_used(), Some(token));
+        unsafe { blk.complete_read_blocks(token, &req, &mut buf, &mut resp) }.unwrap();
+    }
+}
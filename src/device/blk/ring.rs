@@ -0,0 +1,541 @@
+//! A submission/completion ring interface to a VirtIO block device, in the style of io_uring.
+
+use super::{
+    BlkFeature, BlkReq, BlkReqOptions, BlkResp, ReqType, VirtIOBlk, MAX_DATA_SEGMENTS, QUEUE_SIZE,
+    SECTOR_SIZE,
+};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use alloc::{boxed::Box, collections::VecDeque, vec::Vec};
+use core::hint::spin_loop;
+use core::mem::{forget, size_of};
+use log::warn;
+use zerocopy::AsBytes;
+
+/// An operation to submit to a block device through a [`BlkRing`].
+#[derive(Debug)]
+pub enum BlkOp {
+    /// Reads one or more blocks, starting at the given block, into the buffer.
+    Read {
+        /// The first block to read.
+        block_id: usize,
+        /// The buffer to read into, whose length must be a non-zero multiple of [`SECTOR_SIZE`],
+        /// and which must fit in the device's segment limits.
+        buf: Box<[u8]>,
+    },
+    /// Writes the buffer to one or more blocks, starting at the given block.
+    Write {
+        /// The first block to write.
+        block_id: usize,
+        /// The data to write, whose length must be a non-zero multiple of [`SECTOR_SIZE`], and
+        /// which must fit in the device's segment limits.
+        buf: Box<[u8]>,
+    },
+    /// Flushes previously completed writes to storage, like [`VirtIOBlk::flush`].
+    Flush,
+}
+
+impl BlkOp {
+    /// Returns the operation's buffer, if it has one.
+    fn into_buf(self) -> Option<Box<[u8]>> {
+        match self {
+            Self::Read { buf, .. } | Self::Write { buf, .. } => Some(buf),
+            Self::Flush => None,
+        }
+    }
+}
+
+/// An entry in the submission queue of a [`BlkRing`].
+#[derive(Debug)]
+pub struct BlkSubmission {
+    /// A value chosen by the caller, which is passed back in the operation's [`BlkCompletion`].
+    pub user_data: u64,
+    /// The operation to perform.
+    pub op: BlkOp,
+}
+
+/// An entry in the completion queue of a [`BlkRing`], for an operation which has finished.
+#[derive(Debug)]
+pub struct BlkCompletion {
+    /// The value passed in the operation's [`BlkSubmission`].
+    pub user_data: u64,
+    /// Whether the operation succeeded, or the error it failed with.
+    pub result: Result,
+    /// The operation's buffer, given back to the caller. For a successful read this holds the
+    /// data read.
+    pub buf: Option<Box<[u8]>>,
+}
+
+/// An operation which has been added to the virtqueue.
+///
+/// This is boxed so that the request header and response stay at the same address while the
+/// device may be accessing them.
+struct InFlight {
+    user_data: u64,
+    op: BlkOp,
+    req: BlkReq,
+    resp: BlkResp,
+    /// The size of the segments which the operation's buffer is split into.
+    segment_size: usize,
+}
+
+impl InFlight {
+    /// Calls the given function with the input and output buffers of the request, with the data
+    /// split into segments of `segment_size`.
+    fn with_buffers<R>(
+        &mut self,
+        f: impl for<'a> FnOnce(&'a [&'a [u8]], &'a mut [&'a mut [u8]]) -> R,
+    ) -> R {
+        match &mut self.op {
+            BlkOp::Read { buf, .. } => {
+                let mut outputs: [&mut [u8]; MAX_DATA_SEGMENTS + 1] = Default::default();
+                let mut count = 0;
+                for (output, segment) in outputs.iter_mut().zip(buf.chunks_mut(self.segment_size)) {
+                    *output = segment;
+                    count += 1;
+                }
+                outputs[count] = self.resp.as_bytes_mut();
+                f(&[self.req.as_bytes()], &mut outputs[..=count])
+            }
+            BlkOp::Write { buf, .. } => {
+                let mut inputs: [&[u8]; MAX_DATA_SEGMENTS + 1] = Default::default();
+                inputs[0] = self.req.as_bytes();
+                let mut count = 1;
+                for (input, segment) in inputs[1..].iter_mut().zip(buf.chunks(self.segment_size)) {
+                    *input = segment;
+                    count += 1;
+                }
+                f(&inputs[..count], &mut [self.resp.as_bytes_mut()])
+            }
+            BlkOp::Flush => f(&[self.req.as_bytes()], &mut [self.resp.as_bytes_mut()]),
+        }
+    }
+}
+
+/// A submission queue and completion queue over a VirtIO block device's virtqueue, so that a
+/// kernel can expose an io_uring-like asynchronous block interface backed directly by the driver.
+///
+/// Operations are [`push`](Self::push)ed onto the submission queue, and then
+/// [`submit`](Self::submit) moves as many of them as fit onto the virtqueue and notifies the
+/// device once for the whole batch. Those which don't fit stay queued for the next call. Once the
+/// device has finished with them, [`reap`](Self::reap) collects their completions, in the order
+/// the device finished them, together with their buffers.
+///
+/// Buffers larger than the device's maximum segment size are split into several segments, as by
+/// [`VirtIOBlk::read_blocks`]. Errors with individual operations, such as a buffer of the wrong
+/// size or too large for the device's segment limits, or an error status from the device, are
+/// reported in their completions rather than by `submit`. A flush of a device
+/// without a volatile write cache completes immediately.
+///
+/// The ring only handles completions of the operations submitted through it, so `reap` stops at
+/// any request which was submitted to the device another way. When the ring is dropped it waits
+/// for the device to finish with the operations still in flight; operations which were never
+/// submitted are discarded.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal};
+/// # use virtio_drivers::transport::Transport;
+/// use virtio_drivers::device::blk::{BlkOp, BlkRing, BlkSubmission, VirtIOBlk, SECTOR_SIZE};
+///
+/// # fn example<HalImpl: Hal, T: Transport>(disk: &mut VirtIOBlk<HalImpl, T>) -> Result<(), Error> {
+/// let mut ring = BlkRing::new(disk);
+/// for i in 0..4 {
+///     ring.push(BlkSubmission {
+///         user_data: i,
+///         op: BlkOp::Read {
+///             block_id: i as usize,
+///             buf: vec![0; SECTOR_SIZE].into_boxed_slice(),
+///         },
+///     });
+/// }
+/// ring.submit();
+///
+/// // Wait for an interrupt to tell us that some of the reads completed...
+/// let mut completions = Vec::new();
+/// ring.reap(&mut completions, 4);
+/// for completion in completions {
+///     println!("Read {} finished: {:?}", completion.user_data, completion.result);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct BlkRing<'a, H: Hal, T: Transport> {
+    blk: &'a mut VirtIOBlk<H, T>,
+    /// Operations which haven't been added to the virtqueue yet.
+    submissions: VecDeque<BlkSubmission>,
+    /// Operations which have finished without being added to the virtqueue.
+    completions: VecDeque<BlkCompletion>,
+    /// The operations in flight on the virtqueue, indexed by token.
+    in_flight: [Option<Box<InFlight>>; QUEUE_SIZE as usize],
+}
+
+impl<'a, H: Hal, T: Transport> BlkRing<'a, H, T> {
+    /// Creates a new ring for operations on the given block device.
+    pub fn new(blk: &'a mut VirtIOBlk<H, T>) -> Self {
+        const NONE: Option<Box<InFlight>> = None;
+        Self {
+            blk,
+            submissions: VecDeque::new(),
+            completions: VecDeque::new(),
+            in_flight: [NONE; QUEUE_SIZE as usize],
+        }
+    }
+
+    /// Adds the given operation to the submission queue, without submitting it to the device.
+    pub fn push(&mut self, submission: BlkSubmission) {
+        self.submissions.push_back(submission);
+    }
+
+    /// Adds as many operations from the submission queue to the virtqueue as fit, in order, and
+    /// then notifies the device if it wants to be.
+    ///
+    /// Returns the number of operations submitted to the device. Operations which fail before
+    /// they reach the device, e.g. because their buffer is the wrong size, are moved straight to
+    /// the completion queue and aren't counted.
+    pub fn submit(&mut self) -> usize {
+        let mut submitted = 0;
+        while let Some(submission) = self.submissions.pop_front() {
+            let user_data = submission.user_data;
+            match self.start(submission) {
+                Ok(true) => submitted += 1,
+                Ok(false) => {}
+                Err((op, Error::QueueFull | Error::Throttled)) => {
+                    self.submissions.push_front(BlkSubmission { user_data, op });
+                    break;
+                }
+                Err((op, e)) => self.completions.push_back(BlkCompletion {
+                    user_data,
+                    result: Err(e),
+                    buf: op.into_buf(),
+                }),
+            }
+        }
+        self.blk.kick();
+        submitted
+    }
+
+    /// Moves up to `max` finished operations to the end of `completions`, returning how many were
+    /// moved.
+    pub fn reap(&mut self, completions: &mut Vec<BlkCompletion>, max: usize) -> usize {
+        let mut reaped = 0;
+        while reaped < max {
+            let Some(completion) = self.completions.pop_front().or_else(|| self.pop_used()) else {
+                break;
+            };
+            completions.push(completion);
+            reaped += 1;
+        }
+        reaped
+    }
+
+    /// Returns the number of operations in the submission queue, waiting to be submitted.
+    pub fn queued(&self) -> usize {
+        self.submissions.len()
+    }
+
+    /// Returns the number of operations which have been submitted to the device but not yet
+    /// reaped.
+    pub fn in_flight(&self) -> usize {
+        self.in_flight
+            .iter()
+            .filter(|entry| entry.is_some())
+            .count()
+    }
+
+    /// Adds the given operation to the virtqueue, or completes it immediately if it needn't go to
+    /// the device. Returns whether it was added.
+    ///
+    /// On failure the operation is given back with the error.
+    fn start(&mut self, submission: BlkSubmission) -> core::result::Result<bool, (BlkOp, Error)> {
+        let BlkSubmission { user_data, op } = submission;
+        let (type_, sector, segment_size) = match &op {
+            BlkOp::Read { block_id, buf } | BlkOp::Write { block_id, buf } => {
+                if buf.is_empty() || buf.len() % SECTOR_SIZE != 0 {
+                    return Err((op, Error::InvalidParam));
+                }
+                let segment_size = match self.blk.segment_size(buf.len()) {
+                    Ok(segment_size) => segment_size,
+                    Err(e) => return Err((op, e)),
+                };
+                let type_ = if matches!(op, BlkOp::Read { .. }) {
+                    ReqType::In
+                } else {
+                    ReqType::Out
+                };
+                (type_, *block_id as u64, segment_size)
+            }
+            BlkOp::Flush if !self.blk.negotiated_features.contains(BlkFeature::FLUSH) => {
+                self.completions.push_back(BlkCompletion {
+                    user_data,
+                    result: Ok(()),
+                    buf: None,
+                });
+                return Ok(false);
+            }
+            BlkOp::Flush => (ReqType::Flush, 0, usize::MAX),
+        };
+        let req = match self
+            .blk
+            .make_request(type_, sector, BlkReqOptions::default())
+            .and_then(|req| {
+                self.blk
+                    .queue
+                    .throttle(&mut self.blk.transport)
+                    .map(|()| req)
+            }) {
+            Ok(req) => req,
+            Err(e) => return Err((op, e)),
+        };
+        let mut request = Box::new(InFlight {
+            user_data,
+            op,
+            req,
+            resp: BlkResp::default(),
+            segment_size,
+        });
+        // Safe because the buffers are owned by the request, which is kept in `in_flight` until
+        // the device returns them.
+        let token = match request
+            .with_buffers(|inputs, outputs| unsafe { self.blk.queue.add(inputs, outputs) })
+        {
+            Ok(token) => token,
+            Err(e) => return Err((request.op, e)),
+        };
+        self.in_flight[usize::from(token)] = Some(request);
+        self.blk.pending_notify = true;
+        Ok(true)
+    }
+
+    /// Pops the next operation which the device has finished, if it was submitted through this
+    /// ring.
+    fn pop_used(&mut self) -> Option<BlkCompletion> {
+        let token = self.blk.queue.peek_used()?;
+        let mut request = self.in_flight.get_mut(usize::from(token))?.take()?;
+        // Safe because the request holds exactly the buffers which were added with the token.
+        let result = request
+            .with_buffers(|inputs, outputs| unsafe {
+                self.blk.queue.pop_used(token, inputs, outputs)
+            })
+            .and_then(|written| {
+                if let BlkOp::Read { buf, .. } = &request.op {
+                    self.blk
+                        .queue
+                        .check_written(written, buf.len() + size_of::<BlkResp>());
+                }
+                request.resp.status.into()
+            });
+        Some(BlkCompletion {
+            user_data: request.user_data,
+            result,
+            buf: request.op.into_buf(),
+        })
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for BlkRing<'_, H, T> {
+    fn drop(&mut self) {
+        self.blk.kick();
+        while self.in_flight() > 0 {
+            match self.blk.queue.peek_used() {
+                None => spin_loop(),
+                Some(token) if self.in_flight[usize::from(token)].is_some() => {
+                    self.pop_used();
+                }
+                Some(_) => {
+                    // The device returned a request which isn't ours first, so we can't wait for
+                    // the rest. Leak their buffers rather than free them while the device may
+                    // still be using them.
+                    warn!(
+                        "Leaking {} block requests still in flight when their ring was dropped",
+                        self.in_flight()
+                    );
+                    self.in_flight
+                        .iter_mut()
+                        .filter_map(Option::take)
+                        .for_each(forget);
+                    break;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::blk::{tests::fake_blk, BlkConfig, RespStatus, QUEUE},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::ptr::NonNull;
+    use std::sync::Mutex;
+
+    #[test]
+    fn submit_and_reap() {
+        let mut config_space = fake_blk(66);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::RING_INDIRECT_DESC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let mut ring = BlkRing::new(&mut blk);
+
+        ring.push(BlkSubmission {
+            user_data: 1,
+            op: BlkOp::Read {
+                block_id: 42,
+                buf: vec![0; SECTOR_SIZE].into_boxed_slice(),
+            },
+        });
+        ring.push(BlkSubmission {
+            user_data: 2,
+            op: BlkOp::Write {
+                block_id: 7,
+                buf: vec![0; 100].into_boxed_slice(),
+            },
+        });
+        // The device has no write cache, so there's nothing to flush.
+        ring.push(BlkSubmission {
+            user_data: 3,
+            op: BlkOp::Flush,
+        });
+        assert_eq!(ring.queued(), 3);
+        assert_eq!(ring.submit(), 1);
+        assert_eq!(ring.queued(), 0);
+        assert_eq!(ring.in_flight(), 1);
+        assert!(state.lock().unwrap().queues[usize::from(QUEUE)]
+            .notified
+            .load(core::sync::atomic::Ordering::SeqCst));
+
+        // The device completes the read.
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                assert_eq!(
+                    request,
+                    BlkReq {
+                        type_: ReqType::In as u32,
+                        reserved: 0,
+                        sector: 42
+                    }
+                    .as_bytes()
+                );
+                let mut response = vec![0; SECTOR_SIZE];
+                response[0..9].copy_from_slice(b"Test data");
+                response.extend_from_slice(
+                    BlkResp {
+                        status: RespStatus::OK,
+                    }
+                    .as_bytes(),
+                );
+                response
+            });
+
+        let mut completions = Vec::new();
+        assert_eq!(ring.reap(&mut completions, 1), 1);
+        assert_eq!(ring.reap(&mut completions, 10), 2);
+        assert_eq!(ring.reap(&mut completions, 10), 0);
+        assert_eq!(ring.in_flight(), 0);
+        let summary: Vec<_> = completions
+            .iter()
+            .map(|completion| (completion.user_data, completion.result))
+            .collect();
+        assert_eq!(
+            summary,
+            vec![(2, Err(Error::InvalidParam)), (3, Ok(())), (1, Ok(()))]
+        );
+        assert_eq!(completions[0].buf.as_ref().unwrap().len(), 100);
+        assert_eq!(&completions[2].buf.as_ref().unwrap()[0..9], b"Test data");
+    }
+
+    #[test]
+    fn segment_limits() {
+        let mut config_space = BlkConfig {
+            size_max: SECTOR_SIZE as u32,
+            seg_max: 2,
+            ..fake_blk(66)
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: (BlkFeature::SIZE_MAX | BlkFeature::SEG_MAX).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkConfig>>::new(transport).unwrap();
+        let mut ring = BlkRing::new(&mut blk);
+
+        let buffer: Vec<u8> = [1, 2]
+            .iter()
+            .flat_map(|&byte| [byte; SECTOR_SIZE])
+            .collect();
+        ring.push(BlkSubmission {
+            user_data: 1,
+            op: BlkOp::Write {
+                block_id: 10,
+                buf: buffer.clone().into_boxed_slice(),
+            },
+        });
+        // Three sectors need more segments than the device allows.
+        ring.push(BlkSubmission {
+            user_data: 2,
+            op: BlkOp::Read {
+                block_id: 10,
+                buf: vec![0; 3 * SECTOR_SIZE].into_boxed_slice(),
+            },
+        });
+        assert_eq!(ring.submit(), 1);
+        // The write is split into one segment per sector, between the header and the status.
+        assert_eq!(usize::from(QUEUE_SIZE) - ring.blk.queue.available_desc(), 4);
+
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                assert_eq!(
+                    &request[..size_of::<BlkReq>()],
+                    BlkReq {
+                        type_: ReqType::Out as u32,
+                        reserved: 0,
+                        sector: 10,
+                    }
+                    .as_bytes()
+                );
+                assert_eq!(&request[size_of::<BlkReq>()..], buffer);
+                BlkResp {
+                    status: RespStatus::OK,
+                }
+                .as_bytes()
+                .to_vec()
+            });
+
+        let mut completions = Vec::new();
+        assert_eq!(ring.reap(&mut completions, 10), 2);
+        let summary: Vec<_> = completions
+            .iter()
+            .map(|completion| (completion.user_data, completion.result))
+            .collect();
+        assert_eq!(summary, vec![(2, Err(Error::InvalidParam)), (1, Ok(()))]);
+        assert_eq!(completions[0].buf.as_ref().unwrap().len(), 3 * SECTOR_SIZE);
+    }
+}
@@ -0,0 +1,451 @@
+//! Support for zoned block devices (`VIRTIO_BLK_F_ZONED`).
+//!
+//! A zoned device, such as one backed by an NVMe ZNS namespace, divides its sectors into zones
+//! which must be written sequentially from a write pointer, and reset before they can be rewritten.
+//! Zone append writes let the device choose where in the zone the data goes, so several can be in
+//! flight at once without the driver having to order them.
+
+use super::{BlkFeature, BlkReq, BlkReqOptions, BlkResp, ReqType, VirtIOBlk, SECTOR_SIZE};
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use core::{cmp::min, mem::offset_of};
+use log::warn;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// The zoned characteristics at the end of the block device configuration.
+///
+/// This is separate from `BlkConfig` as devices which don't offer `VIRTIO_BLK_F_ZONED` may not
+/// expose this much configuration space.
+#[repr(C)]
+pub(super) struct BlkZonedConfig {
+    /// The fields before the zoned characteristics, which are read through `BlkConfig`.
+    preceding: [u32; 18],
+    zone_sectors: u32,
+    max_open_zones: u32,
+    max_active_zones: u32,
+    max_append_sectors: u32,
+    write_granularity: u32,
+    model: u8,
+}
+
+const VIRTIO_BLK_Z_HM: u8 = 1;
+const VIRTIO_BLK_Z_HA: u8 = 2;
+
+/// The zoned model of a block device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BlkZonedModel {
+    /// Host-managed: writes to sequential zones must be at the write pointer, or they fail.
+    HostManaged,
+    /// Host-aware: writes anywhere are allowed, but sequential writes perform better.
+    HostAware,
+}
+
+/// The zoned characteristics of a block device, as returned by [`VirtIOBlk::zoned`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct BlkZonedCharacteristics {
+    /// The zoned model of the device.
+    pub model: BlkZonedModel,
+    /// The size of each zone, in 512 byte sectors.
+    pub zone_sectors: u32,
+    /// The maximum number of zones which may be open at once, or 0 for no limit.
+    pub max_open_zones: u32,
+    /// The maximum number of zones which may be active (open or closed) at once, or 0 for no
+    /// limit.
+    pub max_active_zones: u32,
+    /// The maximum size of a zone append write, in 512 byte sectors.
+    pub max_append_sectors: u32,
+    /// The offset and size alignment of writes in sequential zones, in bytes.
+    pub write_granularity: u32,
+}
+
+impl BlkZonedCharacteristics {
+    /// Reads the zoned characteristics from the configuration space, returning `None` if the
+    /// device reports an unknown or non-zoned model.
+    pub(super) fn read<T: Transport>(transport: &T) -> Result<Option<Self>> {
+        let model = match transport.read_config(offset_of!(BlkZonedConfig, model))? {
+            VIRTIO_BLK_Z_HM => BlkZonedModel::HostManaged,
+            VIRTIO_BLK_Z_HA => BlkZonedModel::HostAware,
+            model => {
+                warn!("Device offered zoned feature with model {}", model);
+                return Ok(None);
+            }
+        };
+        Ok(Some(Self {
+            model,
+            zone_sectors: transport.read_config(offset_of!(BlkZonedConfig, zone_sectors))?,
+            max_open_zones: transport.read_config(offset_of!(BlkZonedConfig, max_open_zones))?,
+            max_active_zones: transport
+                .read_config(offset_of!(BlkZonedConfig, max_active_zones))?,
+            max_append_sectors: transport
+                .read_config(offset_of!(BlkZonedConfig, max_append_sectors))?,
+            write_granularity: transport
+                .read_config(offset_of!(BlkZonedConfig, write_granularity))?,
+        }))
+    }
+}
+
+/// The header of a zone report, before the zone descriptors.
+#[repr(C)]
+#[derive(AsBytes, FromBytes, FromZeroes)]
+struct ZoneReportHeader {
+    nr_zones: u64,
+    reserved: [u8; 56],
+}
+
+/// A zone of a zoned block device, as returned by [`VirtIOBlk::report_zones`].
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct BlkZone {
+    capacity: u64,
+    start: u64,
+    write_pointer: u64,
+    zone_type: BlkZoneType,
+    state: BlkZoneState,
+    reserved: [u8; 38],
+}
+
+impl BlkZone {
+    /// Returns the first sector of the zone.
+    pub fn start(&self) -> u64 {
+        self.start
+    }
+
+    /// Returns the number of sectors in the zone which can be written, which may be less than the
+    /// zone size.
+    pub fn capacity(&self) -> u64 {
+        self.capacity
+    }
+
+    /// Returns the sector at which the next write to the zone must start.
+    ///
+    /// This is only meaningful for sequential zones which aren't full, read-only or offline.
+    pub fn write_pointer(&self) -> u64 {
+        self.write_pointer
+    }
+
+    /// Returns the type of the zone.
+    pub fn zone_type(&self) -> BlkZoneType {
+        self.zone_type
+    }
+
+    /// Returns the condition of the zone.
+    pub fn state(&self) -> BlkZoneState {
+        self.state
+    }
+}
+
+impl Default for BlkZone {
+    fn default() -> Self {
+        Self::new_zeroed()
+    }
+}
+
+/// The type of a zone.
+#[repr(transparent)]
+#[derive(AsBytes, Copy, Clone, Debug, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct BlkZoneType(u8);
+
+impl BlkZoneType {
+    /// A conventional zone, which can be written anywhere.
+    pub const CONVENTIONAL: BlkZoneType = BlkZoneType(1);
+    /// A sequential zone which must be written at the write pointer.
+    pub const SEQUENTIAL_WRITE_REQUIRED: BlkZoneType = BlkZoneType(2);
+    /// A sequential zone which should preferably be written at the write pointer.
+    pub const SEQUENTIAL_WRITE_PREFERRED: BlkZoneType = BlkZoneType(3);
+}
+
+/// The condition of a zone.
+#[repr(transparent)]
+#[derive(AsBytes, Copy, Clone, Debug, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct BlkZoneState(u8);
+
+impl BlkZoneState {
+    /// A conventional zone, which has no write pointer.
+    pub const NOT_WRITE_POINTER: BlkZoneState = BlkZoneState(0);
+    /// A sequential zone which hasn't been written since it was last reset.
+    pub const EMPTY: BlkZoneState = BlkZoneState(1);
+    /// A zone which the device opened because it was written to.
+    pub const IMPLICITLY_OPEN: BlkZoneState = BlkZoneState(2);
+    /// A zone which was opened with [`BlkZoneAction::Open`].
+    pub const EXPLICITLY_OPEN: BlkZoneState = BlkZoneState(3);
+    /// A partially written zone which isn't open.
+    pub const CLOSED: BlkZoneState = BlkZoneState(4);
+    /// A zone which can only be read.
+    pub const READ_ONLY: BlkZoneState = BlkZoneState(13);
+    /// A zone which has been written up to its capacity, or finished.
+    pub const FULL: BlkZoneState = BlkZoneState(14);
+    /// A zone which can't be read or written.
+    pub const OFFLINE: BlkZoneState = BlkZoneState(15);
+}
+
+/// A zone management operation, for [`VirtIOBlk::manage_zone`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum BlkZoneAction {
+    /// Explicitly opens the zone, so that it stays open until closed or finished.
+    Open,
+    /// Closes an open zone, releasing its open resources.
+    Close,
+    /// Moves the write pointer of the zone to the end, making it full.
+    Finish,
+    /// Moves the write pointer of the zone back to the start, discarding its data.
+    Reset,
+    /// Resets all sequential zones of the device; the sector is ignored.
+    ResetAll,
+}
+
+impl BlkZoneAction {
+    fn req_type(self) -> ReqType {
+        match self {
+            Self::Open => ReqType::ZoneOpen,
+            Self::Close => ReqType::ZoneClose,
+            Self::Finish => ReqType::ZoneFinish,
+            Self::Reset => ReqType::ZoneReset,
+            Self::ResetAll => ReqType::ZoneResetAll,
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> VirtIOBlk<H, T> {
+    /// Returns the zoned characteristics of the device, or `None` if it isn't a zoned device.
+    pub fn zoned(&self) -> Option<BlkZonedCharacteristics> {
+        self.zoned
+    }
+
+    /// Returns [`Error::Unsupported`] unless the device is zoned.
+    fn check_zoned(&self) -> Result<BlkZonedCharacteristics> {
+        self.zoned.ok_or(Error::Unsupported)
+    }
+
+    /// Reports the zones starting with the one containing the given 512 byte sector, filling in as
+    /// many as fit in `zones`.
+    ///
+    /// Returns the number of zones filled in, which is less than the length of `zones` if the
+    /// report reached the last zone of the device. Returns [`Error::Unsupported`] if the device
+    /// isn't zoned, or [`Error::InvalidParam`] if `zones` is empty.
+    pub fn report_zones(&mut self, sector: u64, zones: &mut [BlkZone]) -> Result<usize> {
+        self.check_zoned()?;
+        if zones.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let request = self.make_request(ReqType::ZoneReport, sector, BlkReqOptions::default())?;
+        let mut header = ZoneReportHeader::new_zeroed();
+        let mut resp = BlkResp::default();
+        self.queue.add_notify_wait_pop(
+            &[request.as_bytes()],
+            &mut [
+                header.as_bytes_mut(),
+                zones.as_bytes_mut(),
+                resp.as_bytes_mut(),
+            ],
+            &mut self.transport,
+        )?;
+        Result::from(resp.status)?;
+        Ok(min(header.nr_zones, zones.len() as u64) as usize)
+    }
+
+    /// Performs the given management operation on the zone starting at the given 512 byte sector.
+    ///
+    /// Returns [`Error::Unsupported`] if the device isn't zoned, or [`Error::IoError`] if the
+    /// device rejected the operation, e.g. because the sector isn't the start of a zone or too
+    /// many zones are open.
+    pub fn manage_zone(&mut self, action: BlkZoneAction, sector: u64) -> Result {
+        self.check_zoned()?;
+        let sector = if action == BlkZoneAction::ResetAll {
+            0
+        } else {
+            sector
+        };
+        let request = self.make_request(action.req_type(), sector, BlkReqOptions::default())?;
+        self.request(request)
+    }
+
+    /// Appends the contents of the given buffer to the zone starting at the given 512 byte sector,
+    /// at a position of the device's choosing, and returns the first sector which was written.
+    ///
+    /// Returns [`Error::Unsupported`] if the device isn't zoned, or [`Error::InvalidParam`] if the
+    /// buffer length isn't a non-zero multiple of [`SECTOR_SIZE`] or is more than the device's
+    /// maximum append size.
+    pub fn zone_append(&mut self, sector: u64, buf: &[u8]) -> Result<u64> {
+        let zoned = self.check_zoned()?;
+        if buf.is_empty()
+            || !buf.len().is_multiple_of(SECTOR_SIZE)
+            || buf.len() / SECTOR_SIZE > zoned.max_append_sectors as usize
+        {
+            return Err(Error::InvalidParam);
+        }
+        let request: BlkReq =
+            self.make_request(ReqType::ZoneAppend, sector, BlkReqOptions::default())?;
+        let mut append_sector = 0u64;
+        let mut resp = BlkResp::default();
+        self.queue.add_notify_wait_pop(
+            &[request.as_bytes(), buf],
+            &mut [append_sector.as_bytes_mut(), resp.as_bytes_mut()],
+            &mut self.transport,
+        )?;
+        Result::from(resp.status)?;
+        Ok(append_sector)
+    }
+}
+
+/// Returns the zoned characteristics of the device, if it negotiated `VIRTIO_BLK_F_ZONED`.
+pub(super) fn read_zoned<T: Transport>(
+    transport: &T,
+    negotiated_features: BlkFeature,
+) -> Result<Option<BlkZonedCharacteristics>> {
+    if !negotiated_features.contains(BlkFeature::ZONED) {
+        return Ok(None);
+    }
+    BlkZonedCharacteristics::read(transport)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::blk::{RespStatus, QUEUE, QUEUE_SIZE},
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{mem::size_of, ptr::NonNull};
+    use std::{sync::Mutex, thread};
+
+    #[test]
+    fn zoned_requests() {
+        let mut preceding = [0; 18];
+        // 1024 sectors of capacity.
+        preceding[0] = 1024;
+        let mut config_space = BlkZonedConfig {
+            preceding,
+            zone_sectors: 256,
+            max_open_zones: 2,
+            max_active_zones: 3,
+            max_append_sectors: 8,
+            write_granularity: 512,
+            model: VIRTIO_BLK_Z_HM,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: BlkFeature::ZONED.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut blk = VirtIOBlk::<FakeHal, FakeTransport<BlkZonedConfig>>::new(transport).unwrap();
+        assert_eq!(
+            blk.zoned(),
+            Some(BlkZonedCharacteristics {
+                model: BlkZonedModel::HostManaged,
+                zone_sectors: 256,
+                max_open_zones: 2,
+                max_active_zones: 3,
+                max_append_sectors: 8,
+                write_granularity: 512,
+            })
+        );
+
+        let handle = thread::spawn(move || {
+            let ok = BlkResp {
+                status: RespStatus::OK,
+            };
+            // The report, with space for four zones but only two left from sector 512.
+            State::wait_until_queue_notified(&state, QUEUE);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::ZoneReport as u32,
+                            reserved: 0,
+                            sector: 512,
+                        }
+                        .as_bytes()
+                    );
+                    let mut response = vec![0; size_of::<ZoneReportHeader>()];
+                    response[0] = 2;
+                    for start in [512, 768] {
+                        response.extend_from_slice(
+                            BlkZone {
+                                capacity: 256,
+                                start,
+                                write_pointer: start + 16,
+                                zone_type: BlkZoneType::SEQUENTIAL_WRITE_REQUIRED,
+                                state: BlkZoneState::IMPLICITLY_OPEN,
+                                reserved: [0; 38],
+                            }
+                            .as_bytes(),
+                        );
+                    }
+                    response.resize(size_of::<ZoneReportHeader>() + 4 * size_of::<BlkZone>(), 0);
+                    response.extend_from_slice(ok.as_bytes());
+                    response
+                });
+
+            // The append, which the device puts at the write pointer.
+            State::wait_until_queue_notified(&state, QUEUE);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    let (header, data) = request.split_at(size_of::<BlkReq>());
+                    assert_eq!(
+                        header,
+                        BlkReq {
+                            type_: ReqType::ZoneAppend as u32,
+                            reserved: 0,
+                            sector: 512,
+                        }
+                        .as_bytes()
+                    );
+                    assert_eq!(data, [42; SECTOR_SIZE]);
+                    let mut response = 528u64.to_le_bytes().to_vec();
+                    response.extend_from_slice(ok.as_bytes());
+                    response
+                });
+
+            // Resetting all zones ignores the sector.
+            State::wait_until_queue_notified(&state, QUEUE);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE, |request| {
+                    assert_eq!(
+                        request,
+                        BlkReq {
+                            type_: ReqType::ZoneResetAll as u32,
+                            reserved: 0,
+                            sector: 0,
+                        }
+                        .as_bytes()
+                    );
+                    ok.as_bytes().to_vec()
+                });
+        });
+
+        let mut zones = [BlkZone::default(); 4];
+        assert_eq!(blk.report_zones(512, &mut zones), Ok(2));
+        assert_eq!(zones[1].start(), 768);
+        assert_eq!(zones[1].write_pointer(), 784);
+        assert_eq!(zones[1].state(), BlkZoneState::IMPLICITLY_OPEN);
+        assert_eq!(zones[0].zone_type(), BlkZoneType::SEQUENTIAL_WRITE_REQUIRED);
+
+        assert_eq!(
+            blk.zone_append(512, &[0; 9 * SECTOR_SIZE]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(blk.zone_append(512, &[42; SECTOR_SIZE]), Ok(528));
+        blk.manage_zone(BlkZoneAction::ResetAll, 512).unwrap();
+
+        handle.join().unwrap();
+    }
+}
@@ -1,5 +1,6 @@
 //! Common part shared across all the devices.
 
+use crate::display::impl_flags_display;
 use bitflags::bitflags;
 
 bitflags! {
@@ -22,3 +23,5 @@ bitflags! {
         const NOTIFICATION_DATA     = 1 << 38;
     }
 }
+
+impl_flags_display!(Feature);
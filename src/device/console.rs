@@ -1,24 +1,37 @@
 //! Driver for VirtIO console devices.
 
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::display::impl_flags_display;
 use crate::hal::Hal;
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::{volread, ReadOnly, WriteOnly};
-use crate::{Result, PAGE_SIZE};
-use alloc::boxed::Box;
+use crate::interrupt::{SharedIrqDevice, VirtioDevice};
+use crate::queue::AnyQueue;
+use crate::registry::DriverId;
+use crate::transport::{DeviceType, Transport};
+use crate::{Error, Result, PAGE_SIZE};
+use alloc::{boxed::Box, collections::VecDeque};
 use bitflags::bitflags;
-use core::ptr::NonNull;
+use core::{
+    fmt::{self, Display, Formatter},
+    mem::offset_of,
+    task::Waker,
+};
 
+#[cfg(test)]
 const QUEUE_RECEIVEQ_PORT_0: u16 = 0;
+#[cfg(test)]
 const QUEUE_TRANSMITQ_PORT_0: u16 = 1;
 const QUEUE_SIZE: usize = 2;
-const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX.union(Features::RING_PACKED);
 
 /// Driver for a VirtIO console device.
 ///
 /// Only a single port is allowed since `alloc` is disabled. Emergency write and cols/rows are not
 /// implemented.
 ///
+/// Received data is kept until it is read, and the device is only given more buffers while the
+/// backlog is below the [`RxWatermarks`], so a slow reader pushes back on the host rather than
+/// losing data.
+///
 /// # Example
 ///
 /// ```
@@ -27,7 +40,7 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
 /// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
 /// let mut console = VirtIOConsole::<HalImpl, _>::new(transport)?;
 ///
-/// let info = console.info();
+/// let info = console.info()?;
 /// println!("VirtIO console {}x{}", info.rows, info.columns);
 ///
 /// for &c in b"Hello console!\n" {
@@ -40,17 +53,74 @@ const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
 /// # }
 /// ```
 pub struct VirtIOConsole<H: Hal, T: Transport> {
+    id: DriverId,
     transport: T,
-    config_space: NonNull<Config>,
-    receiveq: VirtQueue<H, QUEUE_SIZE>,
-    transmitq: VirtQueue<H, QUEUE_SIZE>,
+    queues: QueueLayout,
+    receiveq: AnyQueue<H, QUEUE_SIZE>,
+    transmitq: AnyQueue<H, QUEUE_SIZE>,
     queue_buf_rx: Box<[u8; PAGE_SIZE]>,
-    cursor: usize,
-    pending_len: usize,
+    /// Data which has been received from the device but not yet read.
+    rx_backlog: VecDeque<u8>,
+    rx_watermarks: RxWatermarks,
+    /// Whether the receive buffer is being withheld from the device until the backlog drains.
+    rx_paused: bool,
+    rx_flow_hook: Option<RxFlowHook>,
     /// The token of the outstanding receive request, if there is one.
     receive_token: Option<u16>,
+    /// The waker to wake when the console becomes readable, if any.
+    readable_waker: Option<Waker>,
+    /// The waker to wake when the console becomes writable, if any.
+    writable_waker: Option<Waker>,
+}
+
+bitflags! {
+    /// The readiness of a console port for I/O, as used for `poll`/`select` style interfaces.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct Readiness: u8 {
+        /// There is received data which can be read without blocking.
+        const READABLE = 1 << 0;
+        /// Data can be sent without blocking.
+        const WRITABLE = 1 << 1;
+    }
 }
 
+impl_flags_display!(Readiness);
+
+/// Thresholds on the amount of received but unread data, for flow control of the receive queue.
+///
+/// Once the backlog of unread data reaches `high` bytes the driver stops giving receive buffers to
+/// the device, so that the host's writes block rather than data being lost. Once it has been read
+/// down to `low` bytes the driver starts giving the device buffers again.
+///
+/// The default is a high watermark of 1 and a low watermark of 0, i.e. no more data is received
+/// until everything received so far has been read.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RxWatermarks {
+    /// The backlog size in bytes at which receiving is paused.
+    pub high: usize,
+    /// The backlog size in bytes at or below which receiving is resumed.
+    pub low: usize,
+}
+
+impl Default for RxWatermarks {
+    fn default() -> Self {
+        Self { high: 1, low: 0 }
+    }
+}
+
+/// A change in whether the console is accepting data from the host, as reported to an
+/// [`RxFlowHook`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RxFlow {
+    /// The backlog reached the high watermark, so the host is no longer being given buffers.
+    Paused,
+    /// The backlog drained to the low watermark, so the host is being given buffers again.
+    Resumed,
+}
+
+/// A callback which is told when receiving is paused or resumed by flow control.
+pub type RxFlowHook = Box<dyn FnMut(RxFlow) + Send>;
+
 /// Information about a console device, read from its configuration space.
 #[derive(Clone, Debug, Eq, PartialEq)]
 pub struct ConsoleInfo {
@@ -62,20 +132,38 @@ pub struct ConsoleInfo {
     pub max_ports: u32,
 }
 
+impl Display for ConsoleInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}x{} characters, {} ports",
+            self.columns, self.rows, self.max_ports
+        )
+    }
+}
+
 impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
     /// Creates a new VirtIO console driver.
     pub fn new(mut transport: T) -> Result<Self> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
-        let config_space = transport.config_space::<Config>()?;
-        let receiveq = VirtQueue::new(
+        // Multiport isn't negotiated, so there is only port 0.
+        let queues = QueueLayout::new(DeviceType::Console, negotiated_features.bits(), 0)?;
+        queues.validate(&mut transport)?;
+        let receive_index =
+            queues.checked_index(&mut transport, QueueRole::Receive(0), QUEUE_SIZE as u32)?;
+        let transmit_index =
+            queues.checked_index(&mut transport, QueueRole::Transmit(0), QUEUE_SIZE as u32)?;
+        let receiveq = AnyQueue::new(
             &mut transport,
-            QUEUE_RECEIVEQ_PORT_0,
+            receive_index,
+            negotiated_features.contains(Features::RING_PACKED),
             false,
             negotiated_features.contains(Features::RING_EVENT_IDX),
         )?;
-        let transmitq = VirtQueue::new(
+        let transmitq = AnyQueue::new(
             &mut transport,
-            QUEUE_TRANSMITQ_PORT_0,
+            transmit_index,
+            negotiated_features.contains(Features::RING_PACKED),
             false,
             negotiated_features.contains(Features::RING_EVENT_IDX),
         )?;
@@ -87,38 +175,92 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
 
         transport.finish_init();
         let mut console = VirtIOConsole {
+            id: DriverId::allocate(),
             transport,
-            config_space,
+            queues,
             receiveq,
             transmitq,
             queue_buf_rx,
-            cursor: 0,
-            pending_len: 0,
+            rx_backlog: VecDeque::new(),
+            rx_watermarks: RxWatermarks::default(),
+            rx_paused: false,
+            rx_flow_hook: None,
             receive_token: None,
+            readable_waker: None,
+            writable_waker: None,
         };
         console.poll_retrieve()?;
         Ok(console)
     }
 
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
     /// Returns a struct with information about the console device, such as the number of rows and columns.
-    pub fn info(&self) -> ConsoleInfo {
-        // Safe because config_space is a valid pointer to the device configuration space.
-        unsafe {
-            let columns = volread!(self.config_space, cols);
-            let rows = volread!(self.config_space, rows);
-            let max_ports = volread!(self.config_space, max_nr_ports);
-            ConsoleInfo {
-                rows,
-                columns,
-                max_ports,
-            }
+    pub fn info(&self) -> Result<ConsoleInfo> {
+        Ok(ConsoleInfo {
+            rows: self.transport.read_config(offset_of!(Config, rows))?,
+            columns: self.transport.read_config(offset_of!(Config, cols))?,
+            max_ports: self
+                .transport
+                .read_config(offset_of!(Config, max_nr_ports))?,
+        })
+    }
+
+    /// Sets the watermarks for receive flow control.
+    ///
+    /// Returns [`Error::InvalidParam`] unless `low` is less than `high`. Receiving is paused or
+    /// resumed immediately if the current backlog calls for it.
+    pub fn set_rx_watermarks(&mut self, watermarks: RxWatermarks) -> Result<()> {
+        if watermarks.low >= watermarks.high {
+            return Err(Error::InvalidParam);
         }
+        self.rx_watermarks = watermarks;
+        self.update_rx_flow()
+    }
+
+    /// Sets a callback to be told when receiving is paused or resumed by flow control, or removes
+    /// it if `None` is passed.
+    pub fn set_rx_flow_hook(&mut self, hook: Option<RxFlowHook>) {
+        self.rx_flow_hook = hook;
+    }
+
+    /// Returns whether receiving is currently paused because too much data is waiting to be read.
+    pub fn rx_paused(&self) -> bool {
+        self.rx_paused
+    }
+
+    /// Returns the number of bytes which have been received but not yet read.
+    pub fn rx_backlog(&self) -> usize {
+        self.rx_backlog.len()
+    }
+
+    /// Pauses or resumes receiving according to the backlog and watermarks, and gives the device a
+    /// receive buffer if it should have one.
+    fn update_rx_flow(&mut self) -> Result<()> {
+        let backlog = self.rx_backlog.len();
+        let flow = if !self.rx_paused && backlog >= self.rx_watermarks.high {
+            self.rx_paused = true;
+            Some(RxFlow::Paused)
+        } else if self.rx_paused && backlog <= self.rx_watermarks.low {
+            self.rx_paused = false;
+            Some(RxFlow::Resumed)
+        } else {
+            None
+        };
+        if let (Some(flow), Some(hook)) = (flow, &mut self.rx_flow_hook) {
+            hook(flow);
+        }
+        self.poll_retrieve()
     }
 
     /// Makes a request to the device to receive data, if there is not already an outstanding
-    /// receive request or some data already received and not yet returned.
+    /// receive request and receiving isn't paused.
     fn poll_retrieve(&mut self) -> Result<()> {
-        if self.receive_token.is_none() && self.cursor == self.pending_len {
+        if self.receive_token.is_none() && !self.rx_paused {
             // Safe because the buffer lasts at least as long as the queue, and there are no other
             // outstanding requests using the buffer.
             self.receive_token = Some(unsafe {
@@ -126,7 +268,7 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
                     .add(&[], &mut [self.queue_buf_rx.as_mut_slice()])
             }?);
             if self.receiveq.should_notify() {
-                self.transport.notify(QUEUE_RECEIVEQ_PORT_0);
+                self.receiveq.notify(&mut self.transport);
             }
         }
         Ok(())
@@ -141,7 +283,60 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
             return Ok(false);
         }
 
-        self.finish_receive()
+        let received = self.finish_receive()?;
+        self.wake_ready();
+        Ok(received)
+    }
+
+    /// Returns whether the console port is currently readable and writable.
+    ///
+    /// Only a single port is supported, so this applies to port 0.
+    pub fn readiness(&mut self) -> Result<Readiness> {
+        self.finish_receive()?;
+        Ok(self.current_readiness())
+    }
+
+    /// Registers a waker to be woken once the console becomes ready for any of the given kinds of
+    /// I/O.
+    ///
+    /// Each waker is woken at most once, from [`ack_interrupt`](Self::ack_interrupt) or
+    /// immediately if the console is already ready, so callers should register again after being
+    /// woken if they are still interested. Registering a new waker for a kind of readiness replaces
+    /// any previous one for the same kind.
+    pub fn register_waker(&mut self, interest: Readiness, waker: &Waker) {
+        if interest.contains(Readiness::READABLE) {
+            self.readable_waker = Some(waker.clone());
+        }
+        if interest.contains(Readiness::WRITABLE) {
+            self.writable_waker = Some(waker.clone());
+        }
+        self.wake_ready();
+    }
+
+    /// Returns the readiness based on the data already received.
+    fn current_readiness(&self) -> Readiness {
+        // Sending always waits for the device to consume the data, so there is never anything
+        // outstanding on the transmit queue.
+        let mut readiness = Readiness::WRITABLE;
+        if !self.rx_backlog.is_empty() {
+            readiness |= Readiness::READABLE;
+        }
+        readiness
+    }
+
+    /// Wakes any registered wakers whose readiness condition is now met.
+    fn wake_ready(&mut self) {
+        let readiness = self.current_readiness();
+        if readiness.contains(Readiness::READABLE) {
+            if let Some(waker) = self.readable_waker.take() {
+                waker.wake();
+            }
+        }
+        if readiness.contains(Readiness::WRITABLE) {
+            if let Some(waker) = self.writable_waker.take() {
+                waker.wake();
+            }
+        }
     }
 
     /// If there is an outstanding receive request and it has finished, completes it.
@@ -162,11 +357,11 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
                 };
                 flag = true;
                 assert_ne!(len, 0);
-                self.cursor = 0;
-                self.pending_len = len as usize;
-                // Clear `receive_token` so that when the buffer is used up the next call to
-                // `poll_retrieve` will add a new pending request.
+                self.rx_backlog.extend(&self.queue_buf_rx[..len as usize]);
+                // Clear `receive_token` so that the buffer is given back to the device, unless
+                // the backlog is now too big.
                 self.receive_token.take();
+                self.update_rx_flow()?;
             }
         }
         Ok(flag)
@@ -177,13 +372,12 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
     /// If no data has been received this will not block but immediately return `Ok<None>`.
     pub fn recv(&mut self, pop: bool) -> Result<Option<u8>> {
         self.finish_receive()?;
-        if self.cursor == self.pending_len {
+        let Some(&ch) = self.rx_backlog.front() else {
             return Ok(None);
-        }
-        let ch = self.queue_buf_rx[self.cursor];
+        };
         if pop {
-            self.cursor += 1;
-            self.poll_retrieve()?;
+            self.rx_backlog.pop_front();
+            self.update_rx_flow()?;
         }
         Ok(Some(ch))
     }
@@ -197,21 +391,49 @@ impl<H: Hal, T: Transport> VirtIOConsole<H, T> {
     }
 }
 
+impl<H: Hal, T: Transport> VirtioDevice for VirtIOConsole<H, T> {
+    /// Acknowledges any pending interrupt, completes the outstanding read request if the device
+    /// has finished it, and wakes any wakers waiting for the console to become ready.
+    ///
+    /// Returns 1 if new data has been received, or 0 otherwise.
+    fn poll(&mut self) -> Result<usize> {
+        self.transport.ack_interrupt();
+        self.handle_interrupt()
+    }
+}
+
+impl<H: Hal, T: Transport> SharedIrqDevice for VirtIOConsole<H, T> {
+    fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Completes the outstanding read request if the device has finished it, and wakes any wakers
+    /// waiting for the console to become ready.
+    ///
+    /// Returns 1 if new data has been received, or 0 otherwise.
+    fn handle_interrupt(&mut self) -> Result<usize> {
+        let received = self.finish_receive()?;
+        self.wake_ready();
+        Ok(received.into())
+    }
+}
+
 impl<H: Hal, T: Transport> Drop for VirtIOConsole<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
-        self.transport.queue_unset(QUEUE_RECEIVEQ_PORT_0);
-        self.transport.queue_unset(QUEUE_TRANSMITQ_PORT_0);
+        for (index, _) in self.queues.queues() {
+            self.transport.queue_unset(index);
+        }
     }
 }
 
 #[repr(C)]
 struct Config {
-    cols: ReadOnly<u16>,
-    rows: ReadOnly<u16>,
-    max_nr_ports: ReadOnly<u32>,
-    emerg_wr: WriteOnly<u32>,
+    cols: u16,
+    rows: u16,
+    max_nr_ports: u32,
+    emerg_wr: u32,
 }
 
 bitflags! {
@@ -239,27 +461,43 @@ bitflags! {
     }
 }
 
+impl_flags_display!(Features);
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::{
         hal::fake::FakeHal,
+        interrupt::{poll_all, PollSummary},
         transport::{
             fake::{FakeTransport, QueueStatus, State},
             DeviceType,
         },
     };
-    use alloc::{sync::Arc, vec};
-    use core::ptr::NonNull;
+    use alloc::{sync::Arc, task::Wake, vec};
+    use core::{
+        ptr::NonNull,
+        sync::atomic::{AtomicUsize, Ordering},
+    };
     use std::{sync::Mutex, thread};
 
+    /// A waker which counts how many times it has been woken.
+    #[derive(Default)]
+    struct CountingWaker(AtomicUsize);
+
+    impl Wake for CountingWaker {
+        fn wake(self: Arc<Self>) {
+            self.0.fetch_add(1, Ordering::SeqCst);
+        }
+    }
+
     #[test]
     fn receive() {
         let mut config_space = Config {
-            cols: ReadOnly::new(0),
-            rows: ReadOnly::new(0),
-            max_nr_ports: ReadOnly::new(0),
-            emerg_wr: WriteOnly::default(),
+            cols: 0,
+            rows: 0,
+            max_nr_ports: 0,
+            emerg_wr: 0,
         };
         let state = Arc::new(Mutex::new(State {
             queues: vec![QueueStatus::default(), QueueStatus::default()],
@@ -290,7 +528,7 @@ mod tests {
             state.interrupt_pending = true;
         }
         assert_eq!(console.ack_interrupt(), Ok(true));
-        assert_eq!(state.lock().unwrap().interrupt_pending, false);
+        assert!(!state.lock().unwrap().interrupt_pending);
 
         // Receive the character. If we don't pop it it is still there to read again.
         assert_eq!(console.recv(false).unwrap(), Some(42));
@@ -298,13 +536,166 @@ mod tests {
         assert_eq!(console.recv(true).unwrap(), None);
     }
 
+    #[test]
+    fn flow_control() {
+        let mut config_space = Config {
+            cols: 0,
+            rows: 0,
+            max_nr_ports: 0,
+            emerg_wr: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        let flows = Arc::new(Mutex::new(vec![]));
+        let hook_flows = flows.clone();
+        console.set_rx_flow_hook(Some(Box::new(move |flow| {
+            hook_flows.lock().unwrap().push(flow)
+        })));
+        assert_eq!(
+            console.set_rx_watermarks(RxWatermarks { high: 2, low: 2 }),
+            Err(Error::InvalidParam)
+        );
+        console
+            .set_rx_watermarks(RxWatermarks { high: 4, low: 1 })
+            .unwrap();
+
+        // Below the high watermark the buffer is given straight back to the device.
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"abc");
+        assert_eq!(console.recv(false).unwrap(), Some(b'a'));
+        assert!(!console.rx_paused());
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"de");
+        assert_eq!(console.recv(false).unwrap(), Some(b'a'));
+        assert_eq!(console.rx_backlog(), 5);
+        assert!(console.rx_paused());
+        // The device has no buffer to write to.
+        assert_eq!(console.receive_token, None);
+        assert_eq!(*flows.lock().unwrap(), [RxFlow::Paused]);
+
+        // Reading down to the low watermark resumes receiving.
+        for &expected in b"abc" {
+            assert_eq!(console.recv(true).unwrap(), Some(expected));
+            assert!(console.rx_paused());
+        }
+        assert_eq!(console.recv(true).unwrap(), Some(b'd'));
+        assert!(!console.rx_paused());
+        assert_eq!(*flows.lock().unwrap(), [RxFlow::Paused, RxFlow::Resumed]);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, b"f");
+        assert_eq!(console.recv(true).unwrap(), Some(b'e'));
+        assert_eq!(console.recv(true).unwrap(), Some(b'f'));
+        assert_eq!(console.recv(true).unwrap(), None);
+    }
+
+    #[test]
+    fn poll_without_interrupt() {
+        let mut config_space = Config {
+            cols: 0,
+            rows: 0,
+            max_nr_ports: 0,
+            emerg_wr: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(poll_all(&mut [&mut console]).completions, 0);
+
+        // Polling picks up the character even though no interrupt was raised.
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, &[42]);
+        assert_eq!(
+            poll_all(&mut [&mut console]),
+            PollSummary {
+                completions: 1,
+                errors: 0
+            }
+        );
+        assert_eq!(console.recv(true).unwrap(), Some(42));
+    }
+
+    #[test]
+    fn readiness() {
+        let mut config_space = Config {
+            cols: 0,
+            rows: 0,
+            max_nr_ports: 0,
+            emerg_wr: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Console,
+            max_queue_size: 2,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut console = VirtIOConsole::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(console.readiness(), Ok(Readiness::WRITABLE));
+
+        // Waiting to read doesn't wake the waker until there is data.
+        let counter = Arc::new(CountingWaker::default());
+        console.register_waker(Readiness::READABLE, &counter.clone().into());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 0);
+
+        // Make a character available, and simulate an interrupt.
+        {
+            let mut state = state.lock().unwrap();
+            state.write_to_queue::<QUEUE_SIZE>(QUEUE_RECEIVEQ_PORT_0, &[42]);
+            state.interrupt_pending = true;
+        }
+        assert_eq!(console.ack_interrupt(), Ok(true));
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+        assert_eq!(
+            console.readiness(),
+            Ok(Readiness::READABLE | Readiness::WRITABLE)
+        );
+
+        // Registering when already readable wakes immediately.
+        console.register_waker(Readiness::READABLE, &counter.clone().into());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+
+        assert_eq!(console.recv(true).unwrap(), Some(42));
+        assert_eq!(console.readiness(), Ok(Readiness::WRITABLE));
+    }
+
     #[test]
     fn send() {
         let mut config_space = Config {
-            cols: ReadOnly::new(0),
-            rows: ReadOnly::new(0),
-            max_nr_ports: ReadOnly::new(0),
-            emerg_wr: WriteOnly::default(),
+            cols: 0,
+            rows: 0,
+            max_nr_ports: 0,
+            emerg_wr: 0,
         };
         let state = Arc::new(Mutex::new(State {
             queues: vec![QueueStatus::default(), QueueStatus::default()],
@@ -0,0 +1,805 @@
+//! Driver for VirtIO crypto devices.
+//!
+//! Crypto operations are carried out in the context of a session, which the device creates for an
+//! algorithm and key. [`VirtIOCrypto`] creates and destroys sessions on the control queue and runs
+//! operations on the first data queue. [`SessionCache`] keeps sessions around between operations,
+//! for it or any other [`SessionBackend`].
+
+mod session;
+
+pub use self::session::{KeyHandle, SessionBackend, SessionCache, SessionCacheStats};
+
+use super::common::Feature;
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::display::impl_flags_display;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::registry::DriverId;
+use crate::transport::{DeviceType, Transport};
+use crate::{Error, Result};
+use alloc::collections::BTreeMap;
+use bitflags::bitflags;
+use core::convert::TryFrom;
+use core::fmt::{self, Display, Formatter};
+use core::mem::offset_of;
+use log::info;
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// The number of the data queue which the driver uses, as given to the device in control requests.
+const QUEUE_DATA: u16 = 0;
+const CONTROL_QUEUE_SIZE: usize = 4;
+const DATA_QUEUE_SIZE: usize = 16;
+const SUPPORTED_FEATURES: Feature = Feature::RING_INDIRECT_DESC.union(Feature::RING_EVENT_IDX);
+
+/// Driver for a VirtIO crypto device, which offloads symmetric ciphers and hashes to the host.
+///
+/// A session is created for an algorithm and key with
+/// [`create_cipher_session`](Self::create_cipher_session) or
+/// [`create_hash_session`](Self::create_hash_session), and then used for any number of
+/// [`cipher`](Self::cipher) or [`hash`](Self::hash) operations until it is destroyed with
+/// [`destroy_session`](Self::destroy_session). The driver also implements [`SessionBackend`], so a
+/// [`SessionCache`] can manage its sessions.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::crypto::{CipherAlgorithm, CipherDirection, VirtIOCrypto};
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut crypto = VirtIOCrypto::<HalImpl, _>::new(transport)?;
+///
+/// let key = [0x2b; 16];
+/// let session = crypto.create_cipher_session(
+///     CipherAlgorithm::AesCbc,
+///     CipherDirection::Encrypt,
+///     &key,
+/// )?;
+/// let iv = [0; 16];
+/// let plaintext = [0x42; 64];
+/// let mut ciphertext = [0; 64];
+/// crypto.cipher(session, &iv, &plaintext, &mut ciphertext)?;
+/// crypto.destroy_session(session)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOCrypto<H: Hal, T: Transport> {
+    id: DriverId,
+    transport: T,
+    queues: QueueLayout,
+    control_queue: VirtQueue<H, CONTROL_QUEUE_SIZE>,
+    data_queue: VirtQueue<H, DATA_QUEUE_SIZE>,
+    info: CryptoInfo,
+    /// The algorithm of each live session, by session ID.
+    sessions: BTreeMap<u64, CryptoAlgorithm>,
+}
+
+/// Information about a crypto device, read from its configuration space.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CryptoInfo {
+    /// The number of data queues.
+    pub max_dataqueues: u32,
+    /// The services which the device offers.
+    pub services: CryptoServices,
+    /// The bitmap of supported [`CipherAlgorithm`]s. See
+    /// [`supports_cipher`](Self::supports_cipher).
+    pub cipher_algorithms: u64,
+    /// The bitmap of supported [`HashAlgorithm`]s. See [`supports_hash`](Self::supports_hash).
+    pub hash_algorithms: u32,
+    /// The maximum length of a cipher key, in bytes.
+    pub max_cipher_key_len: u32,
+    /// The maximum size of the data in a single request, in bytes.
+    pub max_size: u64,
+}
+
+impl CryptoInfo {
+    /// Returns whether the device supports the given cipher algorithm.
+    pub fn supports_cipher(&self, algorithm: CipherAlgorithm) -> bool {
+        self.services.contains(CryptoServices::CIPHER)
+            && self.cipher_algorithms & (1 << algorithm as u32) != 0
+    }
+
+    /// Returns whether the device supports the given hash algorithm.
+    pub fn supports_hash(&self, algorithm: HashAlgorithm) -> bool {
+        self.services.contains(CryptoServices::HASH)
+            && self.hash_algorithms & (1 << algorithm as u32) != 0
+    }
+}
+
+impl Display for CryptoInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} data queues, services {}, ciphers {:#x}, hashes {:#x}, max key length {}, max size {}",
+            self.max_dataqueues,
+            self.services,
+            self.cipher_algorithms,
+            self.hash_algorithms,
+            self.max_cipher_key_len,
+            self.max_size
+        )
+    }
+}
+
+impl<H: Hal, T: Transport> VirtIOCrypto<H, T> {
+    /// Creates a new VirtIO crypto driver.
+    ///
+    /// Returns [`Error::NotReady`] if the device reports that its backend isn't ready.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+
+        let status: u32 = transport.read_config(offset_of!(Config, status))?;
+        let info = CryptoInfo {
+            max_dataqueues: transport.read_config(offset_of!(Config, max_dataqueues))?,
+            services: CryptoServices::from_bits_retain(
+                transport.read_config(offset_of!(Config, crypto_services))?,
+            ),
+            cipher_algorithms: u64::from(
+                transport.read_config::<u32>(offset_of!(Config, cipher_algo_l))?,
+            ) | u64::from(
+                transport.read_config::<u32>(offset_of!(Config, cipher_algo_h))?,
+            ) << 32,
+            hash_algorithms: transport.read_config(offset_of!(Config, hash_algo))?,
+            max_cipher_key_len: transport.read_config(offset_of!(Config, max_cipher_key_len))?,
+            max_size: transport.read_config(offset_of!(Config, max_size))?,
+        };
+        info!("found a crypto device: {}", info);
+        if status & STATUS_HW_READY == 0 {
+            return Err(Error::NotReady);
+        }
+        // The control queue comes after all the data queues.
+        let max_dataqueues = u16::try_from(info.max_dataqueues).map_err(|_| Error::InvalidParam)?;
+        let queues = QueueLayout::new(
+            DeviceType::Crypto,
+            negotiated_features.bits(),
+            max_dataqueues,
+        )?;
+        queues.validate(&mut transport)?;
+        let data_index = queues.checked_index(
+            &mut transport,
+            QueueRole::Request(QUEUE_DATA),
+            DATA_QUEUE_SIZE as u32,
+        )?;
+        let control_index = queues.checked_index(
+            &mut transport,
+            QueueRole::Control,
+            CONTROL_QUEUE_SIZE as u32,
+        )?;
+
+        let indirect_desc = negotiated_features.contains(Feature::RING_INDIRECT_DESC);
+        let event_idx = negotiated_features.contains(Feature::RING_EVENT_IDX);
+        let data_queue = VirtQueue::new(&mut transport, data_index, indirect_desc, event_idx)?;
+        let control_queue =
+            VirtQueue::new(&mut transport, control_index, indirect_desc, event_idx)?;
+        transport.finish_init();
+
+        Ok(Self {
+            id: DriverId::allocate(),
+            transport,
+            queues,
+            control_queue,
+            data_queue,
+            info,
+            sessions: BTreeMap::new(),
+        })
+    }
+
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
+    /// Returns information about the device read from its configuration space.
+    pub fn info(&self) -> &CryptoInfo {
+        &self.info
+    }
+
+    /// Creates a session to encrypt or decrypt with the given cipher and key, and returns its ID.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the cipher, or
+    /// [`Error::InvalidParam`] if the key is empty or too long.
+    pub fn create_cipher_session(
+        &mut self,
+        algorithm: CipherAlgorithm,
+        direction: CipherDirection,
+        key: &[u8],
+    ) -> Result<u64> {
+        if !self.info.supports_cipher(algorithm) {
+            return Err(Error::Unsupported);
+        }
+        let key_len = u32::try_from(key.len()).map_err(|_| Error::InvalidParam)?;
+        if key.is_empty() || key_len > self.info.max_cipher_key_len {
+            return Err(Error::InvalidParam);
+        }
+        let mut request = CtrlReq::new(CIPHER_CREATE_SESSION, algorithm as u32);
+        let session = CipherSessionPara {
+            algo: algorithm as u32,
+            key_len,
+            op: direction as u32,
+            padding: 0,
+        };
+        request.body[..16].copy_from_slice(session.as_bytes());
+        request.body[48..52].copy_from_slice(&SYM_OP_CIPHER.to_le_bytes());
+        let session_id = self.create_session_with(&request, key)?;
+        self.sessions.insert(
+            session_id,
+            CryptoAlgorithm::Cipher {
+                algorithm,
+                direction,
+            },
+        );
+        Ok(session_id)
+    }
+
+    /// Creates a session to hash with the given algorithm, producing results of `result_len`
+    /// bytes, and returns its ID.
+    ///
+    /// Returns [`Error::Unsupported`] if the device doesn't support the hash algorithm.
+    pub fn create_hash_session(
+        &mut self,
+        algorithm: HashAlgorithm,
+        result_len: u32,
+    ) -> Result<u64> {
+        if !self.info.supports_hash(algorithm) {
+            return Err(Error::Unsupported);
+        }
+        let mut request = CtrlReq::new(HASH_CREATE_SESSION, algorithm as u32);
+        request.body[..4].copy_from_slice(&(algorithm as u32).to_le_bytes());
+        request.body[4..8].copy_from_slice(&result_len.to_le_bytes());
+        let session_id = self.create_session_with(&request, &[])?;
+        self.sessions.insert(
+            session_id,
+            CryptoAlgorithm::Hash {
+                algorithm,
+                result_len,
+            },
+        );
+        Ok(session_id)
+    }
+
+    /// Destroys the session with the given ID.
+    ///
+    /// Returns [`Error::InvalidParam`] if there is no such session.
+    pub fn destroy_session(&mut self, session_id: u64) -> Result {
+        let algorithm = *self.sessions.get(&session_id).ok_or(Error::InvalidParam)?;
+        let (opcode, algo) = match algorithm {
+            CryptoAlgorithm::Cipher { algorithm, .. } => (CIPHER_DESTROY_SESSION, algorithm as u32),
+            CryptoAlgorithm::Hash { algorithm, .. } => (HASH_DESTROY_SESSION, algorithm as u32),
+        };
+        let mut request = CtrlReq::new(opcode, algo);
+        request.body[..8].copy_from_slice(&session_id.to_le_bytes());
+        let mut status = 0u8;
+        self.control_queue.add_notify_wait_pop(
+            &[request.as_bytes()],
+            &mut [status.as_bytes_mut()],
+            &mut self.transport,
+        )?;
+        status_result(status.into())?;
+        self.sessions.remove(&session_id);
+        Ok(())
+    }
+
+    /// Encrypts or decrypts `src` into `dst` with the given cipher session and initialisation
+    /// vector, which may be empty for ciphers which don't use one.
+    ///
+    /// Returns [`Error::InvalidParam`] if the session isn't a cipher session, `src` is empty or
+    /// larger than the device's maximum request size, or `dst` is shorter than `src`.
+    pub fn cipher(&mut self, session_id: u64, iv: &[u8], src: &[u8], dst: &mut [u8]) -> Result {
+        let Some(&CryptoAlgorithm::Cipher {
+            algorithm,
+            direction,
+        }) = self.sessions.get(&session_id)
+        else {
+            return Err(Error::InvalidParam);
+        };
+        self.check_size(src)?;
+        if dst.len() < src.len() {
+            return Err(Error::InvalidParam);
+        }
+        let opcode = match direction {
+            CipherDirection::Encrypt => CIPHER_ENCRYPT,
+            CipherDirection::Decrypt => CIPHER_DECRYPT,
+        };
+        let mut request = DataReq::new(opcode, algorithm as u32, session_id);
+        let para = CipherDataPara {
+            iv_len: iv.len() as u32,
+            src_data_len: src.len() as u32,
+            dst_data_len: src.len() as u32,
+            padding: 0,
+        };
+        request.body[..16].copy_from_slice(para.as_bytes());
+        request.body[40..44].copy_from_slice(&SYM_OP_CIPHER.to_le_bytes());
+        let mut status = 0u8;
+        let with_iv = [request.as_bytes(), iv, src];
+        let without_iv = [request.as_bytes(), src];
+        // The queue doesn't accept empty buffers, so leave out the IV if there is none.
+        let inputs: &[&[u8]] = if iv.is_empty() { &without_iv } else { &with_iv };
+        self.data_queue.add_notify_wait_pop(
+            inputs,
+            &mut [&mut dst[..src.len()], status.as_bytes_mut()],
+            &mut self.transport,
+        )?;
+        status_result(status.into())
+    }
+
+    /// Hashes `src` with the given hash session, and writes the result to the start of `result`.
+    ///
+    /// Returns [`Error::InvalidParam`] if the session isn't a hash session, `src` is empty or
+    /// larger than the device's maximum request size, or `result` is shorter than the session's
+    /// result length.
+    pub fn hash(&mut self, session_id: u64, src: &[u8], result: &mut [u8]) -> Result {
+        let Some(&CryptoAlgorithm::Hash {
+            algorithm,
+            result_len,
+        }) = self.sessions.get(&session_id)
+        else {
+            return Err(Error::InvalidParam);
+        };
+        self.check_size(src)?;
+        let result = result
+            .get_mut(..result_len as usize)
+            .filter(|result| !result.is_empty())
+            .ok_or(Error::InvalidParam)?;
+        let mut request = DataReq::new(HASH, algorithm as u32, session_id);
+        request.body[..4].copy_from_slice(&(src.len() as u32).to_le_bytes());
+        request.body[4..8].copy_from_slice(&result_len.to_le_bytes());
+        let mut status = 0u8;
+        self.data_queue.add_notify_wait_pop(
+            &[request.as_bytes(), src],
+            &mut [result, status.as_bytes_mut()],
+            &mut self.transport,
+        )?;
+        status_result(status.into())
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Sends the given session creation request with the given key, if any, and returns the ID of
+    /// the new session.
+    fn create_session_with(&mut self, request: &CtrlReq, key: &[u8]) -> Result<u64> {
+        let mut response = SessionInput::default();
+        let inputs: &[&[u8]] = if key.is_empty() {
+            &[request.as_bytes()]
+        } else {
+            &[request.as_bytes(), key]
+        };
+        self.control_queue.add_notify_wait_pop(
+            inputs,
+            &mut [response.as_bytes_mut()],
+            &mut self.transport,
+        )?;
+        status_result(response.status)?;
+        Ok(response.session_id)
+    }
+
+    /// Returns [`Error::InvalidParam`] if the given data is empty or too large for one request.
+    fn check_size(&self, data: &[u8]) -> Result {
+        if data.is_empty() || data.len() as u64 > self.info.max_size {
+            Err(Error::InvalidParam)
+        } else {
+            Ok(())
+        }
+    }
+}
+
+impl<H: Hal, T: Transport> SessionBackend for VirtIOCrypto<H, T> {
+    type Algorithm = CryptoAlgorithm;
+
+    fn create_session(&mut self, algorithm: CryptoAlgorithm, key: &[u8]) -> Result<u64> {
+        match algorithm {
+            CryptoAlgorithm::Cipher {
+                algorithm,
+                direction,
+            } => self.create_cipher_session(algorithm, direction, key),
+            CryptoAlgorithm::Hash {
+                algorithm,
+                result_len,
+            } => {
+                if !key.is_empty() {
+                    return Err(Error::InvalidParam);
+                }
+                self.create_hash_session(algorithm, result_len)
+            }
+        }
+    }
+
+    fn destroy_session(&mut self, session_id: u64) -> Result {
+        VirtIOCrypto::destroy_session(self, session_id)
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOCrypto<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        for role in [QueueRole::Request(QUEUE_DATA), QueueRole::Control] {
+            if let Some(index) = self.queues.index(role) {
+                self.transport.queue_unset(index);
+            }
+        }
+    }
+}
+
+/// Converts a status code from the device to a result.
+fn status_result(status: u32) -> Result {
+    match status {
+        STATUS_OK => Ok(()),
+        STATUS_BADMSG | STATUS_INVSESS | STATUS_KEY_REJECTED => Err(Error::InvalidParam),
+        STATUS_NOTSUPP => Err(Error::Unsupported),
+        STATUS_NOSPC => Err(Error::QueueFull),
+        _ => Err(Error::IoError),
+    }
+}
+
+/// The algorithm of a session, and anything else about it other than its key.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CryptoAlgorithm {
+    /// A symmetric cipher.
+    Cipher {
+        /// The cipher algorithm.
+        algorithm: CipherAlgorithm,
+        /// Whether the session encrypts or decrypts.
+        direction: CipherDirection,
+    },
+    /// A hash, which takes no key.
+    Hash {
+        /// The hash algorithm.
+        algorithm: HashAlgorithm,
+        /// The length of the hash result, in bytes.
+        result_len: u32,
+    },
+}
+
+/// Whether a cipher session encrypts or decrypts.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum CipherDirection {
+    /// Encrypt plaintext into ciphertext.
+    Encrypt = 1,
+    /// Decrypt ciphertext into plaintext.
+    Decrypt = 2,
+}
+
+/// A symmetric cipher algorithm.
+#[allow(missing_docs)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum CipherAlgorithm {
+    Arc4 = 1,
+    AesEcb = 2,
+    AesCbc = 3,
+    AesCtr = 4,
+    DesEcb = 5,
+    DesCbc = 6,
+    TripleDesEcb = 7,
+    TripleDesCbc = 8,
+    TripleDesCtr = 9,
+    KasumiF8 = 10,
+    Snow3gUea2 = 11,
+    AesF8 = 12,
+    AesXts = 13,
+    ZucEea3 = 14,
+}
+
+/// A hash algorithm.
+#[allow(missing_docs)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u32)]
+pub enum HashAlgorithm {
+    Md5 = 1,
+    Sha1 = 2,
+    Sha224 = 3,
+    Sha256 = 4,
+    Sha384 = 5,
+    Sha512 = 6,
+    Sha3_224 = 7,
+    Sha3_256 = 8,
+    Sha3_384 = 9,
+    Sha3_512 = 10,
+    Sha3Shake128 = 11,
+    Sha3Shake256 = 12,
+}
+
+bitflags! {
+    /// The services which a crypto device offers.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct CryptoServices: u32 {
+        /// Symmetric ciphers.
+        const CIPHER = 1 << 0;
+        /// Hashes.
+        const HASH = 1 << 1;
+        /// Message authentication codes.
+        const MAC = 1 << 2;
+        /// Authenticated encryption with associated data.
+        const AEAD = 1 << 3;
+        /// Asymmetric ciphers.
+        const AKCIPHER = 1 << 4;
+    }
+}
+
+impl_flags_display!(CryptoServices);
+
+#[repr(C)]
+struct Config {
+    status: u32,
+    max_dataqueues: u32,
+    crypto_services: u32,
+    cipher_algo_l: u32,
+    cipher_algo_h: u32,
+    hash_algo: u32,
+    mac_algo_l: u32,
+    mac_algo_h: u32,
+    aead_algo: u32,
+    max_cipher_key_len: u32,
+    max_auth_key_len: u32,
+    akcipher_algo: u32,
+    max_size: u64,
+}
+
+const STATUS_HW_READY: u32 = 1;
+
+const SERVICE_CIPHER: u32 = 0;
+const SERVICE_HASH: u32 = 1;
+
+/// Returns the opcode for the given operation of the given service.
+const fn opcode(service: u32, op: u32) -> u32 {
+    service << 8 | op
+}
+
+const CIPHER_ENCRYPT: u32 = opcode(SERVICE_CIPHER, 0);
+const CIPHER_DECRYPT: u32 = opcode(SERVICE_CIPHER, 1);
+const CIPHER_CREATE_SESSION: u32 = opcode(SERVICE_CIPHER, 2);
+const CIPHER_DESTROY_SESSION: u32 = opcode(SERVICE_CIPHER, 3);
+const HASH: u32 = opcode(SERVICE_HASH, 0);
+const HASH_CREATE_SESSION: u32 = opcode(SERVICE_HASH, 2);
+const HASH_DESTROY_SESSION: u32 = opcode(SERVICE_HASH, 3);
+
+/// `VIRTIO_CRYPTO_SYM_OP_CIPHER`, for a plain cipher rather than one chained with a hash.
+const SYM_OP_CIPHER: u32 = 1;
+
+const STATUS_OK: u32 = 0;
+const STATUS_BADMSG: u32 = 2;
+const STATUS_NOTSUPP: u32 = 3;
+const STATUS_INVSESS: u32 = 4;
+const STATUS_NOSPC: u32 = 5;
+const STATUS_KEY_REJECTED: u32 = 6;
+
+/// `struct virtio_crypto_op_ctrl_req`, a request on the control queue.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, FromBytes, FromZeroes)]
+struct CtrlReq {
+    opcode: u32,
+    algo: u32,
+    flag: u32,
+    queue_id: u32,
+    /// The opcode-specific part of the request.
+    body: [u8; 56],
+}
+
+impl CtrlReq {
+    fn new(opcode: u32, algo: u32) -> Self {
+        Self {
+            opcode,
+            algo,
+            flag: 0,
+            queue_id: QUEUE_DATA.into(),
+            body: [0; 56],
+        }
+    }
+}
+
+/// `struct virtio_crypto_cipher_session_para`.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct CipherSessionPara {
+    algo: u32,
+    key_len: u32,
+    op: u32,
+    padding: u32,
+}
+
+/// `struct virtio_crypto_session_input`, the response to a session creation request.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct SessionInput {
+    session_id: u64,
+    status: u32,
+    padding: u32,
+}
+
+/// `struct virtio_crypto_op_data_req`, a request on a data queue.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, FromBytes, FromZeroes)]
+struct DataReq {
+    opcode: u32,
+    algo: u32,
+    session_id: u64,
+    flag: u32,
+    padding: u32,
+    /// The opcode-specific part of the request.
+    body: [u8; 48],
+}
+
+impl DataReq {
+    fn new(opcode: u32, algo: u32, session_id: u64) -> Self {
+        Self {
+            opcode,
+            algo,
+            session_id,
+            flag: 0,
+            padding: 0,
+            body: [0; 48],
+        }
+    }
+}
+
+/// `struct virtio_crypto_cipher_para`.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct CipherDataPara {
+    iv_len: u32,
+    src_data_len: u32,
+    dst_data_len: u32,
+    padding: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+
+    const QUEUE_CONTROL: u16 = 1;
+
+    fn make_config(status: u32) -> Config {
+        Config {
+            status,
+            max_dataqueues: 1,
+            crypto_services: CryptoServices::CIPHER.bits(),
+            cipher_algo_l: 1 << CipherAlgorithm::AesCbc as u32,
+            cipher_algo_h: 0,
+            hash_algo: 0,
+            mac_algo_l: 0,
+            mac_algo_h: 0,
+            aead_algo: 0,
+            max_cipher_key_len: 32,
+            max_auth_key_len: 0,
+            akcipher_algo: 0,
+            max_size: 4096,
+        }
+    }
+
+    fn make_transport(config_space: &mut Config) -> (FakeTransport<Config>, Arc<Mutex<State>>) {
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Crypto,
+            max_queue_size: DATA_QUEUE_SIZE as u32,
+            device_features: 0,
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        (transport, state)
+    }
+
+    #[test]
+    fn not_ready() {
+        let mut config_space = make_config(0);
+        let (transport, _state) = make_transport(&mut config_space);
+        assert_eq!(
+            VirtIOCrypto::<FakeHal, FakeTransport<Config>>::new(transport).err(),
+            Some(Error::NotReady)
+        );
+    }
+
+    #[test]
+    fn cipher_session() {
+        let mut config_space = make_config(STATUS_HW_READY);
+        let (transport, state) = make_transport(&mut config_space);
+        let mut crypto = VirtIOCrypto::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device creating a session, "encrypting" by reversing the
+        // data, and destroying the session.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_CONTROL, |request| {
+                    let (header, key) = request.split_at(size_of::<CtrlReq>());
+                    let header = CtrlReq::read_from(header).unwrap();
+                    assert_eq!(header.opcode, CIPHER_CREATE_SESSION);
+                    assert_eq!(
+                        CipherSessionPara::read_from(&header.body[..16])
+                            .unwrap()
+                            .key_len,
+                        16
+                    );
+                    assert_eq!(header.body[48..52], SYM_OP_CIPHER.to_le_bytes());
+                    assert_eq!(key, [0x2b; 16]);
+                    SessionInput {
+                        session_id: 7,
+                        status: STATUS_OK,
+                        padding: 0,
+                    }
+                    .as_bytes()
+                    .to_vec()
+                });
+
+            State::wait_until_queue_notified(&state, QUEUE_DATA);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<DATA_QUEUE_SIZE>(QUEUE_DATA, |request| {
+                    let (header, rest) = request.split_at(size_of::<DataReq>());
+                    let header = DataReq::read_from(header).unwrap();
+                    assert_eq!(header.opcode, CIPHER_ENCRYPT);
+                    assert_eq!(header.session_id, 7);
+                    let (iv, src) = rest.split_at(16);
+                    assert_eq!(iv, [0x11; 16]);
+                    let mut response = src.iter().rev().copied().collect::<Vec<_>>();
+                    response.push(STATUS_OK as u8);
+                    response
+                });
+
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_CONTROL, |request| {
+                    let header = CtrlReq::read_from(request.as_slice()).unwrap();
+                    assert_eq!(header.opcode, CIPHER_DESTROY_SESSION);
+                    assert_eq!(header.body[..8], 7u64.to_le_bytes());
+                    vec![STATUS_OK as u8]
+                });
+        });
+
+        let session = crypto
+            .create_cipher_session(
+                CipherAlgorithm::AesCbc,
+                CipherDirection::Encrypt,
+                &[0x2b; 16],
+            )
+            .unwrap();
+        assert_eq!(session, 7);
+        let src = (0..32).collect::<Vec<u8>>();
+        let mut dst = [0; 32];
+        crypto.cipher(session, &[0x11; 16], &src, &mut dst).unwrap();
+        assert_eq!(dst.to_vec(), src.iter().rev().copied().collect::<Vec<_>>());
+        crypto.destroy_session(session).unwrap();
+        handle.join().unwrap();
+
+        // Requests which the device can't handle are rejected without being sent.
+        assert_eq!(
+            crypto.create_cipher_session(
+                CipherAlgorithm::AesEcb,
+                CipherDirection::Encrypt,
+                &[0; 16]
+            ),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(
+            crypto.create_hash_session(HashAlgorithm::Sha256, 32),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(
+            crypto.cipher(session, &[], &src, &mut dst),
+            Err(Error::InvalidParam)
+        );
+    }
+}
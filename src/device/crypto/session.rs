@@ -0,0 +1,408 @@
+//! Caching of crypto device sessions, so that a session is created once per key rather than once
+//! per operation.
+
+use crate::{Error, Result};
+use alloc::vec::Vec;
+use core::{
+    fmt::Debug,
+    ptr,
+    sync::atomic::{compiler_fence, Ordering},
+};
+use log::warn;
+
+/// Something which can create and destroy sessions on a crypto device, such as a driver's control
+/// queue.
+pub trait SessionBackend {
+    /// Identifies the algorithm, and anything else about the session other than its key, such as
+    /// the cipher direction.
+    type Algorithm: Copy + Debug + Eq;
+
+    /// Creates a session for the given algorithm and key, and returns the device's ID for it.
+    fn create_session(&mut self, algorithm: Self::Algorithm, key: &[u8]) -> Result<u64>;
+
+    /// Destroys the session with the given ID.
+    fn destroy_session(&mut self, session_id: u64) -> Result;
+}
+
+/// A handle to a key registered with a [`SessionCache`].
+///
+/// A handle is only valid for the cache which returned it, until it is released.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct KeyHandle {
+    index: u32,
+    generation: u32,
+}
+
+/// Counts of how a [`SessionCache`] has been used.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SessionCacheStats {
+    /// The number of lookups which found a live session.
+    pub hits: u64,
+    /// The number of lookups which had to create a session.
+    pub misses: u64,
+    /// The number of sessions destroyed to make room for others.
+    pub evictions: u64,
+    /// The number of sessions created again after a device reset.
+    pub recreations: u64,
+}
+
+/// A key registered with the cache, and its device session if it has one.
+#[derive(Debug)]
+struct Entry<A> {
+    algorithm: A,
+    key: Vec<u8>,
+    session_id: Option<u64>,
+    /// The value of the cache's clock when the session was last used, for LRU eviction.
+    last_used: u64,
+    /// The number of times the key has been registered without being released.
+    refs: u32,
+    /// Whether the key had a session which was lost when the device was reset.
+    lost: bool,
+}
+
+impl<A> Drop for Entry<A> {
+    fn drop(&mut self) {
+        // Zero the key with volatile writes, as plain writes to memory which is about to be freed
+        // may be optimised away.
+        for byte in self.key.iter_mut() {
+            // SAFETY: `byte` is a valid and aligned pointer to a `u8`.
+            unsafe { ptr::write_volatile(byte, 0) };
+        }
+        compiler_fence(Ordering::SeqCst);
+    }
+}
+
+/// A slot in the cache, which may hold an entry.
+#[derive(Debug)]
+struct Slot<A> {
+    generation: u32,
+    entry: Option<Entry<A>>,
+}
+
+/// Caches device sessions per (algorithm, key), and manages handles to the keys.
+///
+/// Creating and destroying a session each take a round trip on the device's control queue, which
+/// for small operations costs far more than the operation itself. Instead, callers register each
+/// key once with [`register`](Self::register), and call [`session`](Self::session) before each
+/// operation to get a device session ID for it. Registering the same algorithm and key again
+/// returns the same handle.
+///
+/// At most `max_sessions` device sessions are kept at once. When another is needed the least
+/// recently used one is destroyed, but its key stays registered and a session is created for it
+/// again the next time it is used. Likewise after the device is reset the OS should call
+/// [`device_reset`](Self::device_reset), and sessions will be created again as they are used.
+///
+/// Keys are zeroed when they are released, and when the cache is dropped.
+///
+/// # Example
+///
+/// ```
+/// use virtio_drivers::device::crypto::{SessionBackend, SessionCache};
+/// # use virtio_drivers::Result;
+///
+/// struct Device {
+///     next_id: u64,
+/// }
+///
+/// impl SessionBackend for Device {
+///     type Algorithm = u32;
+///
+///     fn create_session(&mut self, _algorithm: u32, _key: &[u8]) -> Result<u64> {
+///         self.next_id += 1;
+///         Ok(self.next_id)
+///     }
+///
+///     fn destroy_session(&mut self, _session_id: u64) -> Result {
+///         Ok(())
+///     }
+/// }
+///
+/// # fn main() -> Result {
+/// let mut device = Device { next_id: 0 };
+/// let mut cache = SessionCache::new(16);
+/// let handle = cache.register(3, &[0x2b; 16]);
+/// let session_id = cache.session(&mut device, handle)?;
+/// // The same session is used for every operation with the key.
+/// assert_eq!(cache.session(&mut device, handle)?, session_id);
+/// cache.release(&mut device, handle)?;
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct SessionCache<A> {
+    slots: Vec<Slot<A>>,
+    max_sessions: usize,
+    live_sessions: usize,
+    /// Incremented on every lookup, to order sessions by when they were last used.
+    clock: u64,
+    stats: SessionCacheStats,
+}
+
+impl<A: Copy + Debug + Eq> SessionCache<A> {
+    /// Creates an empty cache which keeps at most `max_sessions` device sessions at once.
+    ///
+    /// `max_sessions` should be at least 1, and no more than the device can hold.
+    pub fn new(max_sessions: usize) -> Self {
+        Self {
+            slots: Vec::new(),
+            max_sessions: max_sessions.max(1),
+            live_sessions: 0,
+            clock: 0,
+            stats: SessionCacheStats::default(),
+        }
+    }
+
+    /// Registers the given algorithm and key, and returns a handle to use them.
+    ///
+    /// This doesn't create a device session until the key is first used. If the same algorithm and
+    /// key are already registered, this returns the existing handle, which must then be released
+    /// once more.
+    pub fn register(&mut self, algorithm: A, key: &[u8]) -> KeyHandle {
+        if let Some(handle) = self.find(algorithm, key) {
+            self.entry_mut(handle).unwrap().refs += 1;
+            return handle;
+        }
+        let entry = Entry {
+            algorithm,
+            key: key.to_vec(),
+            session_id: None,
+            last_used: 0,
+            refs: 1,
+            lost: false,
+        };
+        let index = match self.slots.iter().position(|slot| slot.entry.is_none()) {
+            Some(index) => index,
+            None => {
+                self.slots.push(Slot {
+                    generation: 0,
+                    entry: None,
+                });
+                self.slots.len() - 1
+            }
+        };
+        let slot = &mut self.slots[index];
+        slot.entry = Some(entry);
+        KeyHandle {
+            index: index as u32,
+            generation: slot.generation,
+        }
+    }
+
+    /// Returns the device session ID for the given key, creating a session if it doesn't have one.
+    ///
+    /// If creating the session would exceed the cache's limit, the least recently used session is
+    /// destroyed first. Returns [`Error::InvalidParam`] if the handle has been released.
+    pub fn session<B: SessionBackend<Algorithm = A>>(
+        &mut self,
+        backend: &mut B,
+        handle: KeyHandle,
+    ) -> Result<u64> {
+        self.clock += 1;
+        let clock = self.clock;
+        let entry = self.entry_mut(handle).ok_or(Error::InvalidParam)?;
+        entry.last_used = clock;
+        if let Some(session_id) = entry.session_id {
+            self.stats.hits += 1;
+            return Ok(session_id);
+        }
+        self.stats.misses += 1;
+        if self.live_sessions >= self.max_sessions {
+            self.evict_lru(backend);
+        }
+        let entry = self.entry_mut(handle).ok_or(Error::InvalidParam)?;
+        let session_id = backend.create_session(entry.algorithm, &entry.key)?;
+        entry.session_id = Some(session_id);
+        if entry.lost {
+            entry.lost = false;
+            self.stats.recreations += 1;
+        }
+        self.live_sessions += 1;
+        Ok(session_id)
+    }
+
+    /// Releases one registration of the given key. Once all have been released, its device
+    /// session is destroyed, its key is zeroed and the handle becomes invalid.
+    ///
+    /// Returns [`Error::InvalidParam`] if the handle has already been released.
+    pub fn release<B: SessionBackend<Algorithm = A>>(
+        &mut self,
+        backend: &mut B,
+        handle: KeyHandle,
+    ) -> Result {
+        let entry = self.entry_mut(handle).ok_or(Error::InvalidParam)?;
+        entry.refs -= 1;
+        if entry.refs > 0 {
+            return Ok(());
+        }
+        let slot = &mut self.slots[handle.index as usize];
+        // The key is zeroed when the entry is dropped.
+        let entry = slot.entry.take().unwrap();
+        slot.generation = slot.generation.wrapping_add(1);
+        if let Some(session_id) = entry.session_id {
+            self.live_sessions -= 1;
+            backend.destroy_session(session_id)?;
+        }
+        Ok(())
+    }
+
+    /// Forgets all device sessions, because the device has been reset and so has forgotten them
+    /// too.
+    ///
+    /// The keys stay registered, and sessions are created for them again as they are used.
+    pub fn device_reset(&mut self) {
+        for entry in self.slots.iter_mut().filter_map(|slot| slot.entry.as_mut()) {
+            if entry.session_id.take().is_some() {
+                entry.lost = true;
+            }
+        }
+        self.live_sessions = 0;
+    }
+
+    /// Returns the number of device sessions which the cache currently holds.
+    pub fn live_sessions(&self) -> usize {
+        self.live_sessions
+    }
+
+    /// Returns counts of how the cache has been used.
+    pub fn stats(&self) -> SessionCacheStats {
+        self.stats
+    }
+
+    /// Returns the handle of the given algorithm and key, if they are registered.
+    fn find(&self, algorithm: A, key: &[u8]) -> Option<KeyHandle> {
+        self.slots.iter().enumerate().find_map(|(index, slot)| {
+            let entry = slot.entry.as_ref()?;
+            (entry.algorithm == algorithm && entry.key == key).then_some(KeyHandle {
+                index: index as u32,
+                generation: slot.generation,
+            })
+        })
+    }
+
+    fn entry_mut(&mut self, handle: KeyHandle) -> Option<&mut Entry<A>> {
+        let slot = self.slots.get_mut(handle.index as usize)?;
+        if slot.generation != handle.generation {
+            return None;
+        }
+        slot.entry.as_mut()
+    }
+
+    /// Destroys the least recently used device session, keeping its key registered.
+    fn evict_lru<B: SessionBackend<Algorithm = A>>(&mut self, backend: &mut B) {
+        let Some(entry) = self
+            .slots
+            .iter_mut()
+            .filter_map(|slot| slot.entry.as_mut())
+            .filter(|entry| entry.session_id.is_some())
+            .min_by_key(|entry| entry.last_used)
+        else {
+            return;
+        };
+        let session_id = entry.session_id.take().unwrap();
+        self.live_sessions -= 1;
+        self.stats.evictions += 1;
+        // The session is forgotten either way, so there is nothing more to do if the device
+        // fails to destroy it.
+        if let Err(e) = backend.destroy_session(session_id) {
+            warn!("Failed to destroy evicted session {}: {}", session_id, e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// A fake device which records the sessions it has.
+    #[derive(Default)]
+    struct FakeDevice {
+        next_id: u64,
+        sessions: Vec<(u64, u32, Vec<u8>)>,
+    }
+
+    impl SessionBackend for FakeDevice {
+        type Algorithm = u32;
+
+        fn create_session(&mut self, algorithm: u32, key: &[u8]) -> Result<u64> {
+            self.next_id += 1;
+            self.sessions.push((self.next_id, algorithm, key.to_vec()));
+            Ok(self.next_id)
+        }
+
+        fn destroy_session(&mut self, session_id: u64) -> Result {
+            let index = self
+                .sessions
+                .iter()
+                .position(|(id, _, _)| *id == session_id)
+                .ok_or(Error::InvalidParam)?;
+            self.sessions.remove(index);
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn lru_eviction() {
+        let mut device = FakeDevice::default();
+        let mut cache = SessionCache::new(2);
+        let a = cache.register(1, &[0xaa; 16]);
+        let b = cache.register(1, &[0xbb; 16]);
+        let c = cache.register(2, &[0xaa; 16]);
+        assert_eq!(cache.register(1, &[0xaa; 16]), a);
+
+        assert_eq!(cache.session(&mut device, a), Ok(1));
+        assert_eq!(cache.session(&mut device, b), Ok(2));
+        assert_eq!(cache.session(&mut device, a), Ok(1));
+        // b is the least recently used, so it is evicted to make room for c.
+        assert_eq!(cache.session(&mut device, c), Ok(3));
+        assert_eq!(cache.live_sessions(), 2);
+        assert_eq!(
+            device.sessions,
+            vec![(1, 1, vec![0xaa; 16]), (3, 2, vec![0xaa; 16])]
+        );
+        assert_eq!(cache.session(&mut device, b), Ok(4));
+        assert_eq!(
+            cache.stats(),
+            SessionCacheStats {
+                hits: 1,
+                misses: 4,
+                evictions: 2,
+                recreations: 0,
+            }
+        );
+
+        // a was registered twice, so its session is only destroyed on the second release.
+        cache.release(&mut device, a).unwrap();
+        assert_eq!(device.sessions.len(), 2);
+        cache.release(&mut device, b).unwrap();
+        cache.release(&mut device, a).unwrap();
+        assert_eq!(device.sessions, vec![(3, 2, vec![0xaa; 16])]);
+        assert_eq!(cache.session(&mut device, a), Err(Error::InvalidParam));
+        assert_eq!(cache.release(&mut device, b), Err(Error::InvalidParam));
+
+        // The freed slot is reused, but the stale handle doesn't refer to the new key.
+        let d = cache.register(3, &[0xdd; 32]);
+        assert_ne!(d, a);
+        assert_ne!(d, b);
+        assert_eq!(cache.session(&mut device, b), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn device_reset() {
+        let mut device = FakeDevice::default();
+        let mut cache = SessionCache::new(4);
+        let a = cache.register(1, &[0xaa; 16]);
+        let b = cache.register(1, &[0xbb; 16]);
+        assert_eq!(cache.session(&mut device, a), Ok(1));
+
+        // The device forgets its sessions when it is reset.
+        device.sessions.clear();
+        cache.device_reset();
+        assert_eq!(cache.live_sessions(), 0);
+
+        assert_eq!(cache.session(&mut device, a), Ok(2));
+        assert_eq!(cache.session(&mut device, b), Ok(3));
+        assert_eq!(cache.stats().recreations, 1);
+        assert_eq!(device.sessions.len(), 2);
+    }
+}
@@ -0,0 +1,695 @@
+//! Driver for VirtIO file system devices.
+
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::display::impl_flags_display;
+use crate::fixed::FixedString;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::registry::DriverId;
+use crate::transport::{DeviceType, SharedMemoryRegion, Transport};
+use crate::{Error, Result};
+use bitflags::bitflags;
+use core::{
+    convert::TryFrom,
+    mem::{offset_of, size_of},
+};
+use log::{info, warn};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// The index of the first request queue, as the driver doesn't negotiate the notification queue
+/// which would otherwise come before it.
+#[cfg(test)]
+const QUEUE_HIPRIO: u16 = 0;
+#[cfg(test)]
+const QUEUE_REQUEST: u16 = 1;
+const QUEUE_SIZE: usize = 16;
+const SUPPORTED_FEATURES: FsFeature =
+    FsFeature::RING_INDIRECT_DESC.union(FsFeature::RING_EVENT_IDX);
+
+/// The maximum length of a file system tag, in bytes.
+pub const TAG_SIZE: usize = 36;
+
+/// The ID of the shared memory region used as the DAX window.
+const SHM_ID_CACHE: u8 = 0;
+
+const FUSE_SETUPMAPPING: u32 = 48;
+const FUSE_REMOVEMAPPING: u32 = 49;
+
+/// Driver for a VirtIO file system device, which carries FUSE messages to a file system daemon in
+/// the host, such as virtiofsd.
+///
+/// The driver only transports messages; building and parsing them, and so implementing a file
+/// system, is up to the caller. Requests are sent on the first request queue with
+/// [`request`](Self::request), which waits for the reply, and `FUSE_FORGET`, `FUSE_BATCH_FORGET`
+/// and `FUSE_INTERRUPT` on the high priority queue with [`hiprio_request`](Self::hiprio_request).
+///
+/// If the device has a DAX window, file contents can be mapped directly into it with
+/// [`setup_mapping`](Self::setup_mapping) rather than copied through the request queue.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::fs::{FuseInHeader, FuseOutHeader, VirtIOFs};
+/// use zerocopy::AsBytes;
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut fs = VirtIOFs::<HalImpl, _>::new(transport)?;
+/// println!("File system tag {}", fs.tag());
+///
+/// // FUSE_INIT for protocol version 7.31.
+/// let init_in: [u32; 16] = [7, 31, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0];
+/// let header = FuseInHeader {
+///     len: (size_of::<FuseInHeader>() + init_in.as_bytes().len()) as u32,
+///     opcode: 26,
+///     unique: 1,
+///     ..Default::default()
+/// };
+/// let mut reply_header = [0; size_of::<FuseOutHeader>()];
+/// let mut init_out = [0; 64];
+/// let reply = fs.request(
+///     &[header.as_bytes(), init_in.as_bytes()],
+///     &mut [&mut reply_header, &mut init_out],
+/// )?;
+/// assert_eq!(reply.error, 0);
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOFs<H: Hal, T: Transport> {
+    id: DriverId,
+    transport: T,
+    queues: QueueLayout,
+    hiprio_queue: VirtQueue<H, QUEUE_SIZE>,
+    request_queue: VirtQueue<H, QUEUE_SIZE>,
+    tag: FixedString<TAG_SIZE>,
+    num_request_queues: u32,
+    /// The unique ID to use for the next request which the driver builds itself.
+    next_unique: u64,
+}
+
+impl<H: Hal, T: Transport> VirtIOFs<H, T> {
+    /// Creates a new VirtIO file system driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let tag: [u8; TAG_SIZE] = transport.read_config(offset_of!(Config, tag))?;
+        let num_request_queues: u32 =
+            transport.read_config(offset_of!(Config, num_request_queues))?;
+        let tag = FixedString::from_nul_padded(&tag).map_err(|_| {
+            warn!("VirtIO file system tag isn't valid UTF-8");
+            Error::IoError
+        })?;
+        info!(
+            "VirtIO file system tag {:?}, {} request queues",
+            tag, num_request_queues
+        );
+        let queues = QueueLayout::new(
+            DeviceType::FileSystem,
+            negotiated_features.bits(),
+            u16::try_from(num_request_queues).map_err(|_| Error::InvalidParam)?,
+        )?;
+        queues.validate(&mut transport)?;
+        let hiprio_index =
+            queues.checked_index(&mut transport, QueueRole::HighPriority, QUEUE_SIZE as u32)?;
+        let request_index =
+            queues.checked_index(&mut transport, QueueRole::Request(0), QUEUE_SIZE as u32)?;
+        let indirect = negotiated_features.contains(FsFeature::RING_INDIRECT_DESC);
+        let event_idx = negotiated_features.contains(FsFeature::RING_EVENT_IDX);
+        let hiprio_queue = VirtQueue::new(&mut transport, hiprio_index, indirect, event_idx)?;
+        let request_queue = VirtQueue::new(&mut transport, request_index, indirect, event_idx)?;
+        transport.finish_init();
+
+        Ok(Self {
+            id: DriverId::allocate(),
+            transport,
+            queues,
+            hiprio_queue,
+            request_queue,
+            tag,
+            num_request_queues,
+            next_unique: 1,
+        })
+    }
+
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
+    /// Returns the tag which identifies the file system, for the guest to choose which one to
+    /// mount.
+    pub fn tag(&self) -> &str {
+        &self.tag
+    }
+
+    /// Returns the number of request queues the device has, of which the driver uses the first.
+    pub fn num_request_queues(&self) -> u32 {
+        self.num_request_queues
+    }
+
+    /// Sends a FUSE request, made up of the given buffers, and waits for the reply to be written to
+    /// the given reply buffers.
+    ///
+    /// The request must start with a [`FuseInHeader`] whose length covers all of the request
+    /// buffers, and the first reply buffer must be big enough for a [`FuseOutHeader`], otherwise
+    /// this returns [`Error::InvalidParam`]. The buffers may be split however is convenient, e.g.
+    /// to send the data of a `FUSE_WRITE` from a separate buffer to its header.
+    ///
+    /// Returns the header of the reply, which is also at the start of the first reply buffer. A
+    /// reply with a non-zero [`error`](FuseOutHeader::error) is returned like any other, for the
+    /// caller to decode. Returns [`Error::IoError`] if the reply is malformed or doesn't have the
+    /// request's unique ID.
+    pub fn request<'a>(
+        &mut self,
+        request: &[&'a [u8]],
+        reply: &mut [&'a mut [u8]],
+    ) -> Result<FuseOutHeader> {
+        let header = check_request(request)?;
+        if reply
+            .first()
+            .is_none_or(|buffer| buffer.len() < size_of::<FuseOutHeader>())
+        {
+            return Err(Error::InvalidParam);
+        }
+        let written =
+            self.request_queue
+                .add_notify_wait_pop(request, reply, &mut self.transport)? as usize;
+        let reply_header = FuseOutHeader::read_from_prefix(&*reply[0]).unwrap();
+        let len = reply_header.len as usize;
+        if written < size_of::<FuseOutHeader>()
+            || len < size_of::<FuseOutHeader>()
+            || len > written
+            || reply_header.unique != header.unique
+        {
+            warn!(
+                "Malformed FUSE reply {:?} of {} bytes to {:?}",
+                reply_header, written, header
+            );
+            return Err(Error::IoError);
+        }
+        Ok(reply_header)
+    }
+
+    /// Sends a FUSE request on the high priority queue, i.e. a `FUSE_FORGET`, `FUSE_BATCH_FORGET`
+    /// or `FUSE_INTERRUPT`, which have no reply.
+    ///
+    /// The request must start with a [`FuseInHeader`] whose length matches the request, otherwise
+    /// this returns [`Error::InvalidParam`].
+    pub fn hiprio_request(&mut self, request: &[u8]) -> Result {
+        check_request(&[request])?;
+        self.hiprio_queue
+            .add_notify_wait_pop(&[request], &mut [], &mut self.transport)?;
+        Ok(())
+    }
+
+    /// Returns the location of the device's DAX window, if it has one.
+    ///
+    /// File contents mapped with [`setup_mapping`](Self::setup_mapping) appear in this window,
+    /// which the caller must map into its address space to access them.
+    pub fn dax_window(&mut self) -> Option<SharedMemoryRegion> {
+        self.transport.shared_memory_region(SHM_ID_CACHE)
+    }
+
+    /// Maps part of an open file into the DAX window, with `FUSE_SETUPMAPPING`.
+    ///
+    /// Returns [`Error::Unsupported`] if the device has no DAX window, [`Error::InvalidParam`] if
+    /// the mapping doesn't fit in it, or [`Error::IoError`] if the file system daemon fails the
+    /// request.
+    pub fn setup_mapping(&mut self, nodeid: u64, mapping: &DaxMapping) -> Result {
+        self.check_dax_range(mapping.moffset, mapping.len)?;
+        let args = SetupMappingIn {
+            fh: mapping.fh,
+            foffset: mapping.foffset,
+            len: mapping.len,
+            flags: mapping.flags.bits(),
+            moffset: mapping.moffset,
+        };
+        self.simple_request(FUSE_SETUPMAPPING, nodeid, &[args.as_bytes()])
+    }
+
+    /// Removes a range of the DAX window previously mapped with
+    /// [`setup_mapping`](Self::setup_mapping), with `FUSE_REMOVEMAPPING`.
+    ///
+    /// Returns [`Error::Unsupported`] if the device has no DAX window, [`Error::InvalidParam`] if
+    /// the range doesn't fit in it, or [`Error::IoError`] if the file system daemon fails the
+    /// request.
+    pub fn remove_mapping(&mut self, nodeid: u64, moffset: u64, len: u64) -> Result {
+        self.check_dax_range(moffset, len)?;
+        let count = RemoveMappingIn {
+            count: 1,
+            _padding: 0,
+        };
+        let one = RemoveMappingOne { moffset, len };
+        self.simple_request(
+            FUSE_REMOVEMAPPING,
+            nodeid,
+            &[count.as_bytes(), one.as_bytes()],
+        )
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Checks that the given range fits in the DAX window.
+    fn check_dax_range(&mut self, moffset: u64, len: u64) -> Result {
+        let window = self.dax_window().ok_or(Error::Unsupported)?;
+        match moffset.checked_add(len) {
+            Some(end) if len != 0 && end <= window.len => Ok(()),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Sends a request which the driver builds itself, with the given arguments and no reply data.
+    fn simple_request(&mut self, opcode: u32, nodeid: u64, args: &[&[u8]]) -> Result {
+        let args_len: usize = args.iter().map(|arg| arg.len()).sum();
+        let header = FuseInHeader {
+            len: (size_of::<FuseInHeader>() + args_len) as u32,
+            opcode,
+            unique: self.next_unique,
+            nodeid,
+            ..Default::default()
+        };
+        self.next_unique = self.next_unique.wrapping_add(1);
+        let mut request = [header.as_bytes(); 3];
+        request[1..=args.len()].copy_from_slice(args);
+        let mut reply = FuseOutHeader::new_zeroed();
+        let reply = self.request(&request[..=args.len()], &mut [reply.as_bytes_mut()])?;
+        if reply.error != 0 {
+            warn!("FUSE request {} failed with error {}", opcode, reply.error);
+            return Err(Error::IoError);
+        }
+        Ok(())
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOFs<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        for role in [QueueRole::HighPriority, QueueRole::Request(0)] {
+            if let Some(index) = self.queues.index(role) {
+                self.transport.queue_unset(index);
+            }
+        }
+    }
+}
+
+/// Checks that the given request buffers start with a FUSE header whose length matches them, and
+/// returns the header.
+fn check_request(request: &[&[u8]]) -> Result<FuseInHeader> {
+    let header = request
+        .first()
+        .and_then(|buffer| FuseInHeader::read_from_prefix(buffer))
+        .ok_or(Error::InvalidParam)?;
+    let len: usize = request.iter().map(|buffer| buffer.len()).sum();
+    if header.len as usize != len {
+        return Err(Error::InvalidParam);
+    }
+    Ok(header)
+}
+
+/// `struct fuse_in_header`, at the start of every FUSE request.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct FuseInHeader {
+    /// The length of the whole request, including this header.
+    pub len: u32,
+    /// The operation, e.g. 26 for `FUSE_INIT`.
+    pub opcode: u32,
+    /// An ID for the request, which the reply echoes.
+    pub unique: u64,
+    /// The inode the operation applies to.
+    pub nodeid: u64,
+    /// The user ID of the process making the request.
+    pub uid: u32,
+    /// The group ID of the process making the request.
+    pub gid: u32,
+    /// The process ID of the process making the request.
+    pub pid: u32,
+    /// The length of any extensions after the arguments, in units of 8 bytes.
+    pub total_extlen: u16,
+    /// Reserved.
+    pub padding: u16,
+}
+
+/// `struct fuse_out_header`, at the start of every FUSE reply.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct FuseOutHeader {
+    /// The length of the whole reply, including this header.
+    pub len: u32,
+    /// Zero on success, otherwise a negative errno.
+    pub error: i32,
+    /// The unique ID of the request this is a reply to.
+    pub unique: u64,
+}
+
+bitflags! {
+    /// How a file is mapped into the DAX window.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct DaxMappingFlags: u64 {
+        /// The mapping may be written.
+        const WRITE = 1 << 0;
+        /// The mapping may be read.
+        const READ = 1 << 1;
+    }
+}
+
+/// A range of a file to map into the DAX window with [`VirtIOFs::setup_mapping`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct DaxMapping {
+    /// The handle of the open file.
+    pub fh: u64,
+    /// The offset within the file of the start of the range.
+    pub foffset: u64,
+    /// The length of the range, in bytes.
+    pub len: u64,
+    /// Whether the mapping may be read or written.
+    pub flags: DaxMappingFlags,
+    /// The offset within the DAX window to map the range at.
+    pub moffset: u64,
+}
+
+/// `struct fuse_setupmapping_in`.
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct SetupMappingIn {
+    fh: u64,
+    foffset: u64,
+    len: u64,
+    flags: u64,
+    moffset: u64,
+}
+
+/// `struct fuse_removemapping_in`.
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct RemoveMappingIn {
+    count: u32,
+    _padding: u32,
+}
+
+/// `struct fuse_removemapping_one`.
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct RemoveMappingOne {
+    moffset: u64,
+    len: u64,
+}
+
+/// `struct virtio_fs_config`.
+#[repr(C)]
+struct Config {
+    tag: [u8; TAG_SIZE],
+    num_request_queues: u32,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct FsFeature: u64 {
+        /// The device has a notification queue.
+        const NOTIFICATION          = 1 << 0;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // the following since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+impl_flags_display!(FsFeature);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{boxed::Box, sync::Arc, vec, vec::Vec};
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+
+    fn make_config() -> Config {
+        let mut tag = [0; TAG_SIZE];
+        tag[..6].copy_from_slice(b"shared");
+        Config {
+            tag,
+            num_request_queues: 1,
+        }
+    }
+
+    /// The length of the DAX window of the device made by `fake_fs`.
+    const DAX_WINDOW_LEN: u64 = 0x10_0000;
+
+    /// Makes a file system driver for a fake device with a DAX window.
+    fn fake_fs() -> (VirtIOFs<FakeHal, FakeTransport<Config>>, Arc<Mutex<State>>) {
+        let config_space = Box::leak(Box::new(make_config()));
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            shared_memory_regions: vec![(
+                SHM_ID_CACHE,
+                SharedMemoryRegion {
+                    paddr: 0x8000_0000,
+                    len: DAX_WINDOW_LEN,
+                },
+            )],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::FileSystem,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: 0,
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        (VirtIOFs::new(transport).unwrap(), state)
+    }
+
+    /// Simulates the daemon replying to the next request on the request queue with the given error,
+    /// and returns the request.
+    fn reply_to_request(state: &Arc<Mutex<State>>, error: i32) -> Vec<u8> {
+        State::wait_until_queue_notified(state, QUEUE_REQUEST);
+        let mut request = Vec::new();
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue::<QUEUE_SIZE>(QUEUE_REQUEST, |data| {
+                let header = FuseInHeader::read_from_prefix(&data).unwrap();
+                assert_eq!(header.len as usize, data.len());
+                request = data;
+                FuseOutHeader {
+                    len: size_of::<FuseOutHeader>() as u32,
+                    error,
+                    unique: header.unique,
+                }
+                .as_bytes()
+                .to_vec()
+            });
+        request
+    }
+
+    #[test]
+    fn request() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::FileSystem,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut fs = VirtIOFs::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(fs.tag(), "shared");
+        assert_eq!(fs.num_request_queues(), 1);
+
+        // Start a thread to simulate the daemon replying to a FUSE_READ.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_REQUEST);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<QUEUE_SIZE>(QUEUE_REQUEST, |request| {
+                    let header = FuseInHeader::read_from_prefix(&request).unwrap();
+                    assert_eq!(header.len as usize, request.len());
+                    assert_eq!((header.opcode, header.unique), (15, 42));
+                    let mut reply = FuseOutHeader {
+                        len: 16 + 20,
+                        error: 0,
+                        unique: 42,
+                    }
+                    .as_bytes()
+                    .to_vec();
+                    reply.extend_from_slice(&[0x66; 20]);
+                    reply
+                });
+        });
+
+        let header = FuseInHeader {
+            len: (size_of::<FuseInHeader>() + 40) as u32,
+            opcode: 15,
+            unique: 42,
+            nodeid: 3,
+            ..Default::default()
+        };
+        let mut reply_header = [0; size_of::<FuseOutHeader>()];
+        let mut data = [0; 32];
+        let reply = fs
+            .request(
+                &[header.as_bytes(), &[0; 40]],
+                &mut [&mut reply_header, &mut data],
+            )
+            .unwrap();
+        assert_eq!(
+            reply,
+            FuseOutHeader {
+                len: 36,
+                error: 0,
+                unique: 42,
+            }
+        );
+        assert_eq!(data[..20], [0x66; 20]);
+        handle.join().unwrap();
+
+        // Requests whose header doesn't match them are rejected without being sent.
+        assert_eq!(
+            fs.request(&[header.as_bytes()], &mut [&mut reply_header]),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            fs.request(&[header.as_bytes(), &[0; 40]], &mut [&mut data[..8]]),
+            Err(Error::InvalidParam)
+        );
+
+        // The fake transport has no shared memory, so there is no DAX window.
+        assert_eq!(fs.dax_window(), None);
+        assert_eq!(
+            fs.setup_mapping(3, &DaxMapping::default()),
+            Err(Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn hiprio_request() {
+        let (mut fs, state) = fake_fs();
+        let forget = FuseInHeader {
+            len: (size_of::<FuseInHeader>() + 8) as u32,
+            // FUSE_FORGET
+            opcode: 2,
+            unique: 7,
+            nodeid: 3,
+            ..Default::default()
+        };
+        let mut request = forget.as_bytes().to_vec();
+        request.extend_from_slice(&1u64.to_le_bytes());
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_HIPRIO);
+            state
+                .lock()
+                .unwrap()
+                .read_from_queue::<QUEUE_SIZE>(QUEUE_HIPRIO)
+        });
+        fs.hiprio_request(&request).unwrap();
+        assert_eq!(handle.join().unwrap(), request);
+
+        // A request whose header doesn't match it is rejected without being sent.
+        assert_eq!(
+            fs.hiprio_request(forget.as_bytes()),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn setup_and_remove_mapping() {
+        let (mut fs, state) = fake_fs();
+        assert_eq!(
+            fs.dax_window(),
+            Some(SharedMemoryRegion {
+                paddr: 0x8000_0000,
+                len: DAX_WINDOW_LEN,
+            })
+        );
+
+        let mapping = DaxMapping {
+            fh: 5,
+            foffset: 0x2000,
+            len: 0x1000,
+            flags: DaxMappingFlags::READ | DaxMappingFlags::WRITE,
+            moffset: DAX_WINDOW_LEN - 0x1000,
+        };
+        let device_state = state.clone();
+        let handle = thread::spawn(move || reply_to_request(&device_state, 0));
+        fs.setup_mapping(3, &mapping).unwrap();
+        let request = handle.join().unwrap();
+        let header = FuseInHeader::read_from_prefix(&request).unwrap();
+        assert_eq!((header.opcode, header.nodeid), (FUSE_SETUPMAPPING, 3));
+        let args = SetupMappingIn::read_from(&request[size_of::<FuseInHeader>()..]).unwrap();
+        assert_eq!(
+            (args.fh, args.foffset, args.len, args.flags, args.moffset),
+            (5, 0x2000, 0x1000, 0b11, DAX_WINDOW_LEN - 0x1000)
+        );
+
+        let device_state = state.clone();
+        let handle = thread::spawn(move || reply_to_request(&device_state, 0));
+        fs.remove_mapping(3, DAX_WINDOW_LEN - 0x1000, 0x1000)
+            .unwrap();
+        let request = handle.join().unwrap();
+        let header = FuseInHeader::read_from_prefix(&request).unwrap();
+        assert_eq!(header.opcode, FUSE_REMOVEMAPPING);
+        let args = &request[size_of::<FuseInHeader>()..];
+        assert_eq!(RemoveMappingIn::read_from_prefix(args).unwrap().count, 1);
+        let one = RemoveMappingOne::read_from(&args[size_of::<RemoveMappingIn>()..]).unwrap();
+        assert_eq!((one.moffset, one.len), (DAX_WINDOW_LEN - 0x1000, 0x1000));
+
+        // An error from the daemon is reported.
+        let handle = thread::spawn(move || reply_to_request(&state, -22));
+        assert_eq!(fs.remove_mapping(3, 0, 0x1000), Err(Error::IoError));
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn dax_range_bounds() {
+        let (mut fs, _) = fake_fs();
+        // Empty ranges, ranges past the end of the window and ranges whose end overflows are all
+        // rejected without sending a request.
+        let bad_ranges = [
+            (0, 0),
+            (DAX_WINDOW_LEN, 0),
+            (DAX_WINDOW_LEN - 0x1000, 0x1001),
+            (DAX_WINDOW_LEN, 1),
+            (u64::MAX, 2),
+            (1, u64::MAX),
+        ];
+        for (moffset, len) in bad_ranges {
+            let mapping = DaxMapping {
+                len,
+                moffset,
+                ..Default::default()
+            };
+            assert_eq!(fs.setup_mapping(3, &mapping), Err(Error::InvalidParam));
+            assert_eq!(fs.remove_mapping(3, moffset, len), Err(Error::InvalidParam));
+        }
+    }
+}
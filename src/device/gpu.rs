@@ -1,36 +1,64 @@
 //! Driver for VirtIO GPU devices.
 
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::display::impl_flags_display;
+use crate::fixed::FixedBytes;
 use crate::hal::{BufferDirection, Dma, Hal};
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::{volread, ReadOnly, Volatile, WriteOnly};
+use crate::queue::AnyQueue;
+use crate::registry::DriverId;
+use crate::transport::{DeviceType, Transport};
 use crate::{pages, Error, Result, PAGE_SIZE};
-use alloc::boxed::Box;
+use alloc::{boxed::Box, vec, vec::Vec};
 use bitflags::bitflags;
-use log::info;
+use core::mem::{offset_of, size_of};
+use log::{info, warn};
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 const QUEUE_SIZE: u16 = 2;
-const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX;
+
+/// The maximum size of an EDID blob returned by [`VirtIOGpu::edid`].
+pub const EDID_MAX_SIZE: usize = 1024;
+/// The largest capability set which [`VirtIOGpu::capset`] will fetch, so that a device can't make
+/// the driver allocate an arbitrary amount of memory.
+///
+/// This is far more than any capability set defined so far needs.
+pub const CAPSET_MAX_SIZE: usize = 64 * 1024;
+const SUPPORTED_FEATURES: Features = Features::RING_EVENT_IDX
+    .union(Features::RING_PACKED)
+    .union(Features::EDID);
 
 /// A virtio based graphics adapter.
 ///
 /// It can operate in 2D mode and in 3D (virgl) mode.
 /// 3D mode will offload rendering ops to the host gpu and therefore requires
-/// a gpu with 3D support on the host machine.
+/// a gpu with 3D support on the host machine. It is only used if requested with
+/// [`new_with_virgl`](Self::new_with_virgl).
 /// In 2D mode the virtio-gpu device provides support for ARGB Hardware cursors
 /// and multiple scanouts (aka heads).
+///
+/// It can also render without any display, to an offscreen resource which isn't attached to a
+/// scanout; see [`setup_offscreen`](Self::setup_offscreen).
 pub struct VirtIOGpu<H: Hal, T: Transport> {
+    id: DriverId,
     transport: T,
+    queues: QueueLayout,
+    /// Whether the device supports 3D mode, which is needed to read resources back from the host.
+    virgl: bool,
+    /// Whether the device can report the EDID of its displays.
+    edid: bool,
     rect: Option<Rect>,
     /// DMA area of frame buffer.
     frame_buffer_dma: Option<Dma<H>>,
     /// DMA area of cursor image buffer.
     cursor_buffer_dma: Option<Dma<H>>,
+    /// Staging buffer used as the frame buffer backing in streaming mode.
+    staging: Option<StagingBuffer<H>>,
+    /// The offscreen resource for headless rendering, if it has been set up.
+    offscreen: Option<Offscreen<H>>,
     /// Queue for sending control commands.
-    control_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    control_queue: AnyQueue<H, { QUEUE_SIZE as usize }>,
     /// Queue for sending cursor commands.
-    cursor_queue: VirtQueue<H, { QUEUE_SIZE as usize }>,
+    cursor_queue: AnyQueue<H, { QUEUE_SIZE as usize }>,
     /// Send buffer for queue.
     queue_buf_send: Box<[u8]>,
     /// Recv buffer for queue.
@@ -38,30 +66,48 @@ pub struct VirtIOGpu<H: Hal, T: Transport> {
 }
 
 impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
-    /// Create a new VirtIO-Gpu driver.
-    pub fn new(mut transport: T) -> Result<Self> {
-        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+    /// Create a new VirtIO-Gpu driver, in 2D mode.
+    pub fn new(transport: T) -> Result<Self> {
+        Self::new_with_virgl(transport, false)
+    }
 
-        // read configuration space
-        let config_space = transport.config_space::<Config>()?;
-        unsafe {
-            let events_read = volread!(config_space, events_read);
-            let num_scanouts = volread!(config_space, num_scanouts);
-            info!(
-                "events_read: {:#x}, num_scanouts: {:#x}",
-                events_read, num_scanouts
-            );
+    /// Create a new VirtIO-Gpu driver, negotiating 3D mode if `virgl` is true and the device
+    /// supports it.
+    ///
+    /// A device in 3D mode may expect the driver to use rendering contexts, so this is only worth
+    /// requesting if they will be used, or to read back offscreen resources.
+    pub fn new_with_virgl(mut transport: T, virgl: bool) -> Result<Self> {
+        let mut supported_features = SUPPORTED_FEATURES;
+        if virgl {
+            supported_features |= Features::VIRGL;
         }
+        let negotiated_features = transport.begin_init(supported_features);
 
-        let control_queue = VirtQueue::new(
+        // read configuration space
+        let events_read: u32 = transport.read_config(offset_of!(Config, events_read))?;
+        let num_scanouts: u32 = transport.read_config(offset_of!(Config, num_scanouts))?;
+        info!(
+            "events_read: {:#x}, num_scanouts: {:#x}",
+            events_read, num_scanouts
+        );
+
+        let queues = QueueLayout::new(DeviceType::GPU, negotiated_features.bits(), 0)?;
+        queues.validate(&mut transport)?;
+        let control_index =
+            queues.checked_index(&mut transport, QueueRole::Control, QUEUE_SIZE.into())?;
+        let cursor_index =
+            queues.checked_index(&mut transport, QueueRole::Cursor, QUEUE_SIZE.into())?;
+        let control_queue = AnyQueue::new(
             &mut transport,
-            QUEUE_TRANSMIT,
+            control_index,
+            negotiated_features.contains(Features::RING_PACKED),
             false,
             negotiated_features.contains(Features::RING_EVENT_IDX),
         )?;
-        let cursor_queue = VirtQueue::new(
+        let cursor_queue = AnyQueue::new(
             &mut transport,
-            QUEUE_CURSOR,
+            cursor_index,
+            negotiated_features.contains(Features::RING_PACKED),
             false,
             negotiated_features.contains(Features::RING_EVENT_IDX),
         )?;
@@ -72,9 +118,15 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         transport.finish_init();
 
         Ok(VirtIOGpu {
+            id: DriverId::allocate(),
             transport,
+            queues,
+            virgl: negotiated_features.contains(Features::VIRGL),
+            edid: negotiated_features.contains(Features::EDID),
             frame_buffer_dma: None,
             cursor_buffer_dma: None,
+            staging: None,
+            offscreen: None,
             rect: None,
             control_queue,
             cursor_queue,
@@ -83,11 +135,149 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         })
     }
 
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
     /// Acknowledge interrupt.
     pub fn ack_interrupt(&mut self) -> bool {
         self.transport.ack_interrupt()
     }
 
+    /// Checks for and clears any pending events from the device, such as a display change.
+    ///
+    /// This should be called when the device raises a configuration change interrupt. If the
+    /// display configuration changed, e.g. because the host window was resized, this fetches the
+    /// new display info and returns [`GpuEvent::DisplayChanged`]. Returns `None` if there are no
+    /// pending events.
+    pub fn poll_event(&mut self) -> Result<Option<GpuEvent>> {
+        let events: u32 = self
+            .transport
+            .read_config(offset_of!(Config, events_read))?;
+        if events & EVENT_DISPLAY == 0 {
+            return Ok(None);
+        }
+        // Clear the event before fetching the display info, so that a change which happens after
+        // this isn't lost.
+        self.transport
+            .write_config(offset_of!(Config, events_clear), EVENT_DISPLAY)?;
+        let display_info = self.get_display_info()?;
+        let event = GpuEvent::DisplayChanged {
+            width: display_info.rect.width,
+            height: display_info.rect.height,
+            enabled: display_info.enabled != 0,
+        };
+        info!("Display changed: {:?}", event);
+        Ok(Some(event))
+    }
+
+    /// Returns the number of capability sets which the device supports, which can be queried with
+    /// [`capset_info`](Self::capset_info).
+    pub fn num_capsets(&self) -> Result<u32> {
+        self.transport.read_config(offset_of!(Config, num_capsets))
+    }
+
+    /// Returns information about the capability set with the given index, which must be less
+    /// than [`num_capsets`](Self::num_capsets).
+    pub fn capset_info(&mut self, index: u32) -> Result<CapsetInfo> {
+        if index >= self.num_capsets()? {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: RespCapsetInfo = self.request(GetCapsetInfo {
+            header: CtrlHeader::with_type(Command::GET_CAPSET_INFO),
+            capset_index: index,
+            _padding: 0,
+        })?;
+        rsp.header.check_type(Command::OK_CAPSET_INFO)?;
+        Ok(CapsetInfo {
+            id: CapsetId(rsp.capset_id),
+            max_version: rsp.capset_max_version,
+            max_size: rsp.capset_max_size,
+        })
+    }
+
+    /// Fetches the given version of a capability set from the device.
+    ///
+    /// The contents are specific to the kind of capability set, e.g. `struct virgl_caps_v2` for
+    /// [`CapsetId::VIRGL2`]. Returns [`Error::InvalidParam`] if `version` is greater than the
+    /// capability set's [`max_version`](CapsetInfo::max_version), or [`Error::Unsupported`] if
+    /// its [`max_size`](CapsetInfo::max_size) is more than [`CAPSET_MAX_SIZE`].
+    pub fn capset(&mut self, info: &CapsetInfo, version: u32) -> Result<Vec<u8>> {
+        if version > info.max_version {
+            return Err(Error::InvalidParam);
+        }
+        let max_size = info.max_size as usize;
+        if max_size > CAPSET_MAX_SIZE {
+            warn!(
+                "Capability set {:?} may be {} bytes, more than the maximum of {}",
+                info.id, max_size, CAPSET_MAX_SIZE
+            );
+            return Err(Error::Unsupported);
+        }
+        GetCapset {
+            header: CtrlHeader::with_type(Command::GET_CAPSET),
+            capset_id: info.id.0,
+            capset_version: version,
+        }
+        .write_to_prefix(&mut self.queue_buf_send)
+        .unwrap();
+        // The capability set may not fit in the usual response buffer.
+        let header_len = size_of::<CtrlHeader>();
+        let mut response = vec![0; header_len + max_size];
+        let written = self.control_queue.add_notify_wait_pop(
+            &[&self.queue_buf_send],
+            &mut [&mut response],
+            &mut self.transport,
+        )? as usize;
+        self.control_queue.check_written(written as u32, header_len);
+        CtrlHeader::read_from_prefix(&response)
+            .unwrap()
+            .check_type(Command::OK_CAPSET)?;
+        response.truncate(written.clamp(header_len, response.len()));
+        response.drain(..header_len);
+        Ok(response)
+    }
+
+    /// Returns the EDID blob describing the display attached to the given scanout, such as its
+    /// name and supported modes.
+    ///
+    /// This doesn't allocate, so a kernel can identify its displays before it has a heap. Returns
+    /// [`Error::Unsupported`] if the device can't report EDIDs, or [`Error::InvalidParam`] if there
+    /// is no such scanout.
+    pub fn edid(&mut self, scanout_id: u32) -> Result<FixedBytes<EDID_MAX_SIZE>> {
+        if !self.edid {
+            return Err(Error::Unsupported);
+        }
+        let num_scanouts: u32 = self
+            .transport
+            .read_config(offset_of!(Config, num_scanouts))?;
+        if scanout_id >= num_scanouts {
+            return Err(Error::InvalidParam);
+        }
+        let rsp: RespEdid = self.request(GetEdid {
+            header: CtrlHeader::with_type(Command::GET_EDID),
+            scanout: scanout_id,
+            _padding: 0,
+        })?;
+        rsp.header.check_type(Command::OK_EDID)?;
+        let edid = rsp.edid.get(..rsp.size as usize).ok_or(Error::IoError)?;
+        FixedBytes::from_slice(edid)
+    }
+
+    /// Queries all of the device's capability sets, so that the caller can tell which kinds of 3D
+    /// rendering it supports.
+    pub fn capabilities(&mut self) -> Result<GpuCapabilities> {
+        let capsets = (0..self.num_capsets()?)
+            .map(|index| self.capset_info(index))
+            .collect::<Result<_>>()?;
+        Ok(GpuCapabilities {
+            virgl: self.virgl,
+            capsets,
+        })
+    }
+
     /// Get the resolution (width, height).
     pub fn resolution(&mut self) -> Result<(u32, u32)> {
         let display_info = self.get_display_info()?;
@@ -123,6 +313,120 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         Ok(buf)
     }
 
+    /// Sets up the display for streaming updates, without a frame buffer covering the whole
+    /// screen in guest memory.
+    ///
+    /// Instead a staging buffer big enough for `staging_rows` rows of the display is allocated, and
+    /// [`update_rect`](Self::update_rect) streams pixel data through it in chunks. This is useful
+    /// for large displays where memory is tight. Returns the resolution (width, height).
+    pub fn setup_framebuffer_streaming(&mut self, staging_rows: u32) -> Result<(u32, u32)> {
+        if staging_rows == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let display_info = self.get_display_info()?;
+        info!("=> {:?}", display_info);
+        let rect = display_info.rect;
+
+        self.resource_create_2d(RESOURCE_ID_FB, rect.width, rect.height)?;
+
+        let rows = staging_rows.min(rect.height);
+        let size = rect.width * rows * 4;
+        let dma = Dma::new(pages(size as usize), BufferDirection::DriverToDevice)?;
+        self.resource_attach_backing(RESOURCE_ID_FB, dma.paddr() as u64, size)?;
+        self.set_scanout(rect, SCANOUT_ID, RESOURCE_ID_FB)?;
+
+        self.staging = Some(StagingBuffer { dma, rect, rows });
+        Ok((rect.width, rect.height))
+    }
+
+    /// Updates the given rectangle of the display from a source of pixel rows, and flushes it to
+    /// the screen.
+    ///
+    /// Each item from `rows` must be one row of the rectangle, i.e. `width * 4` bytes of pixel
+    /// data in B8G8R8A8 format, and there must be exactly `height` of them. The rows are copied
+    /// into the staging buffer and transferred to the host a chunk at a time, so the whole
+    /// rectangle never needs to exist contiguously in memory.
+    ///
+    /// [`setup_framebuffer_streaming`](Self::setup_framebuffer_streaming) must be called first.
+    pub fn update_rect<'a>(
+        &mut self,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+        rows: impl IntoIterator<Item = &'a [u8]>,
+    ) -> Result {
+        let staging = self.staging.as_ref().ok_or(Error::NotReady)?;
+        let screen = staging.rect;
+        let staging_rows = staging.rows;
+        let rect = Rect::new(x, y, width, height);
+        if rect.is_empty() || !screen.contains(&rect) {
+            return Err(Error::InvalidParam);
+        }
+        let stride = screen.width as usize * 4;
+        let row_offset = x as usize * 4;
+        let row_len = width as usize * 4;
+
+        let mut rows = rows.into_iter();
+        let mut chunk_start = 0;
+        while chunk_start < height {
+            let chunk_rows = staging_rows.min(height - chunk_start);
+            {
+                // Safe because the staging buffer is only accessed by the device during
+                // `transfer_to_host_2d`, which we aren't in the middle of.
+                let buf = unsafe { self.staging.as_ref().unwrap().dma.raw_slice().as_mut() };
+                for i in 0..chunk_rows as usize {
+                    let row = rows.next().ok_or(Error::InvalidParam)?;
+                    if row.len() != row_len {
+                        return Err(Error::InvalidParam);
+                    }
+                    let start = i * stride + row_offset;
+                    buf[start..start + row_len].copy_from_slice(row);
+                }
+            }
+            let chunk_rect = Rect::new(x, y + chunk_start, width, chunk_rows);
+            self.transfer_to_host_2d(chunk_rect, row_offset as u64, RESOURCE_ID_FB)?;
+            chunk_start += chunk_rows;
+        }
+        if rows.next().is_some() {
+            return Err(Error::InvalidParam);
+        }
+
+        self.resource_flush(rect, RESOURCE_ID_FB)
+    }
+
+    /// Flushes only the given damaged region of the framebuffer to the screen.
+    ///
+    /// This is much cheaper than [`flush`](Self::flush) when only a small part of the screen has
+    /// changed. The region must lie within the display.
+    pub fn flush_rect(&mut self, x: u32, y: u32, width: u32, height: u32) -> Result {
+        self.flush_rects(&[Rect::new(x, y, width, height)])
+    }
+
+    /// Flushes a batch of damaged regions of the framebuffer to the screen, e.g. all the regions
+    /// changed in one frame.
+    ///
+    /// Each region is transferred to the host separately, and then the smallest rectangle covering
+    /// them all is flushed to the screen once. Empty regions are ignored. All regions must lie
+    /// within the display, or [`Error::InvalidParam`] is returned before anything is transferred.
+    pub fn flush_rects(&mut self, rects: &[Rect]) -> Result {
+        let screen = self.rect.ok_or(Error::NotReady)?;
+        if rects.iter().any(|rect| !screen.contains(rect)) {
+            return Err(Error::InvalidParam);
+        }
+        let mut bounds: Option<Rect> = None;
+        for rect in rects.iter().filter(|rect| !rect.is_empty()) {
+            // The offset of the top-left pixel of the rectangle within the framebuffer.
+            let offset = (u64::from(rect.y) * u64::from(screen.width) + u64::from(rect.x)) * 4;
+            self.transfer_to_host_2d(*rect, offset, RESOURCE_ID_FB)?;
+            bounds = Some(bounds.map_or(*rect, |bounds| bounds.union(rect)));
+        }
+        if let Some(bounds) = bounds {
+            self.resource_flush(bounds, RESOURCE_ID_FB)?;
+        }
+        Ok(())
+    }
+
     /// Flush framebuffer to screen.
     pub fn flush(&mut self) -> Result {
         let rect = self.rect.ok_or(Error::NotReady)?;
@@ -133,6 +437,96 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         Ok(())
     }
 
+    /// Fills the given rectangle of the framebuffer with a single colour, and flushes it to the
+    /// screen.
+    ///
+    /// The colour is a B8G8R8A8 pixel read as a little-endian `u32`, i.e. `0xAARRGGBB`. The
+    /// rectangle must lie within the display, and [`setup_framebuffer`](Self::setup_framebuffer)
+    /// must have been called first.
+    pub fn fill_rect(&mut self, rect: Rect, color: u32) -> Result {
+        let screen = self.rect.ok_or(Error::NotReady)?;
+        let frame_buffer_dma = self.frame_buffer_dma.as_ref().ok_or(Error::NotReady)?;
+        if !screen.contains(&rect) {
+            return Err(Error::InvalidParam);
+        }
+        // Safe because the frame buffer is only accessed by the device during
+        // `transfer_to_host_2d`, which we aren't in the middle of.
+        let buf = unsafe { frame_buffer_dma.raw_slice().as_mut() };
+        let stride = screen.width as usize * 4;
+        let pixel = color.to_le_bytes();
+        for y in rect.y..rect.y + rect.height {
+            let start = y as usize * stride + rect.x as usize * 4;
+            for dest in buf[start..start + rect.width as usize * 4].chunks_exact_mut(4) {
+                dest.copy_from_slice(&pixel);
+            }
+        }
+        self.flush_rects(&[rect])
+    }
+
+    /// Sets up an offscreen resource of the given size for headless rendering, without any
+    /// scanout attached, and returns its backing buffer in guest memory.
+    ///
+    /// This works without a display, e.g. for CI screenshot tests or generating thumbnails. Pixel
+    /// data is in B8G8R8A8 format, with rows of `width * 4` bytes. After drawing to the buffer,
+    /// call [`upload_offscreen`](Self::upload_offscreen) to copy it to the host resource, and
+    /// [`read_back_offscreen`](Self::read_back_offscreen) to copy the host resource's contents
+    /// back into the buffer.
+    ///
+    /// Returns [`Error::AlreadyUsed`] if the offscreen resource has already been set up.
+    pub fn setup_offscreen(&mut self, width: u32, height: u32) -> Result<&mut [u8]> {
+        if self.offscreen.is_some() {
+            return Err(Error::AlreadyUsed);
+        }
+        let rect = Rect::new(0, 0, width, height);
+        if rect.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let size = width
+            .checked_mul(height)
+            .and_then(|pixels| pixels.checked_mul(4));
+        let size = size.ok_or(Error::InvalidParam)?;
+
+        self.resource_create_2d(RESOURCE_ID_OFFSCREEN, width, height)?;
+        let dma = Dma::new(pages(size as usize), BufferDirection::Both)?;
+        self.resource_attach_backing(RESOURCE_ID_OFFSCREEN, dma.paddr() as u64, size)?;
+
+        // Safe because the device only accesses the backing during transfers, which we aren't in
+        // the middle of.
+        let buf = unsafe { &mut dma.raw_slice().as_mut()[..size as usize] };
+        self.offscreen = Some(Offscreen { dma, rect });
+        Ok(buf)
+    }
+
+    /// Copies the given region of the offscreen buffer to the host resource.
+    ///
+    /// [`setup_offscreen`](Self::setup_offscreen) must be called first.
+    pub fn upload_offscreen(&mut self, rect: Rect) -> Result {
+        let offscreen = self.offscreen.as_ref().ok_or(Error::NotReady)?;
+        let offset = offscreen.offset_of(&rect)?;
+        self.transfer_to_host_2d(rect, offset, RESOURCE_ID_OFFSCREEN)
+    }
+
+    /// Copies the given region of the host resource back into the offscreen buffer, and returns
+    /// the whole buffer.
+    ///
+    /// This uses `TRANSFER_FROM_HOST_3D`, so returns [`Error::Unsupported`] unless 3D mode was
+    /// negotiated with [`new_with_virgl`](Self::new_with_virgl).
+    /// [`setup_offscreen`](Self::setup_offscreen) must be called first.
+    pub fn read_back_offscreen(&mut self, rect: Rect) -> Result<&[u8]> {
+        if !self.virgl {
+            return Err(Error::Unsupported);
+        }
+        let offscreen = self.offscreen.as_ref().ok_or(Error::NotReady)?;
+        let offset = offscreen.offset_of(&rect)?;
+        let stride = offscreen.rect.width * 4;
+        let size = offscreen.rect.width as usize * offscreen.rect.height as usize * 4;
+        self.transfer_from_host_3d(rect, offset, stride, RESOURCE_ID_OFFSCREEN)?;
+
+        let offscreen = self.offscreen.as_ref().ok_or(Error::NotReady)?;
+        // Safe because the transfer has completed, so the device isn't accessing the backing.
+        Ok(unsafe { &offscreen.dma.raw_slice().as_ref()[..size] })
+    }
+
     /// Set the pointer shape and position.
     pub fn setup_cursor(
         &mut self,
@@ -175,11 +569,14 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
     /// Send a request to the device and block for a response.
     fn request<Req: AsBytes, Rsp: FromBytes>(&mut self, req: Req) -> Result<Rsp> {
         req.write_to_prefix(&mut self.queue_buf_send).unwrap();
-        self.control_queue.add_notify_wait_pop(
+        let written = self.control_queue.add_notify_wait_pop(
             &[&self.queue_buf_send],
             &mut [&mut self.queue_buf_recv],
             &mut self.transport,
         )?;
+        // Error responses are only a header, so callers check the rest when the type is right.
+        self.control_queue
+            .check_written(written, size_of::<CtrlHeader>());
         Ok(Rsp::read_from_prefix(&self.queue_buf_recv).unwrap())
     }
 
@@ -243,6 +640,32 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         rsp.check_type(Command::OK_NODATA)
     }
 
+    fn transfer_from_host_3d(
+        &mut self,
+        rect: Rect,
+        offset: u64,
+        stride: u32,
+        resource_id: u32,
+    ) -> Result {
+        let rsp: CtrlHeader = self.request(TransferHost3D {
+            header: CtrlHeader::with_type(Command::TRANSFER_FROM_HOST_3D),
+            box_: Box3D {
+                x: rect.x,
+                y: rect.y,
+                z: 0,
+                width: rect.width,
+                height: rect.height,
+                depth: 1,
+            },
+            offset,
+            resource_id,
+            level: 0,
+            stride,
+            layer_stride: 0,
+        })?;
+        rsp.check_type(Command::OK_NODATA)
+    }
+
     fn resource_attach_backing(&mut self, resource_id: u32, paddr: u64, length: u32) -> Result {
         let rsp: CtrlHeader = self.request(ResourceAttachBacking {
             header: CtrlHeader::with_type(Command::RESOURCE_ATTACH_BACKING),
@@ -255,6 +678,7 @@ impl<H: Hal, T: Transport> VirtIOGpu<H, T> {
         rsp.check_type(Command::OK_NODATA)
     }
 
+    #[allow(clippy::too_many_arguments)]
     fn update_cursor(
         &mut self,
         resource_id: u32,
@@ -289,28 +713,113 @@ impl<H: Hal, T: Transport> Drop for VirtIOGpu<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
-        self.transport.queue_unset(QUEUE_TRANSMIT);
-        self.transport.queue_unset(QUEUE_CURSOR);
+        for (index, _) in self.queues.queues() {
+            self.transport.queue_unset(index);
+        }
     }
 }
 
 #[repr(C)]
 struct Config {
     /// Signals pending events to the driver。
-    events_read: ReadOnly<u32>,
+    events_read: u32,
 
     /// Clears pending events in the device.
-    events_clear: WriteOnly<u32>,
+    events_clear: u32,
 
     /// Specifies the maximum number of scanouts supported by the device.
     ///
     /// Minimum value is 1, maximum value is 16.
-    num_scanouts: Volatile<u32>,
+    num_scanouts: u32,
+
+    /// Specifies the maximum number of capability sets supported by the device.
+    num_capsets: u32,
+}
+
+/// Identifies a kind of capability set, which describes what a 3D rendering context type can do.
+#[derive(Copy, Clone, Debug, Eq, Hash, PartialEq)]
+pub struct CapsetId(pub u32);
+
+impl CapsetId {
+    /// OpenGL rendering with virglrenderer, with `struct virgl_caps_v1`.
+    pub const VIRGL: CapsetId = CapsetId(1);
+    /// OpenGL rendering with virglrenderer, with `struct virgl_caps_v2`.
+    pub const VIRGL2: CapsetId = CapsetId(2);
+    /// Vulkan rendering with gfxstream.
+    pub const GFXSTREAM_VULKAN: CapsetId = CapsetId(3);
+    /// Vulkan rendering with Venus.
+    pub const VENUS: CapsetId = CapsetId(4);
+    /// Cross-domain contexts, which share resources such as Wayland buffers with the host.
+    pub const CROSS_DOMAIN: CapsetId = CapsetId(5);
+    /// Native DRM contexts, which pass the host GPU's own commands through.
+    pub const DRM: CapsetId = CapsetId(6);
+}
+
+/// Information about a capability set supported by the device, from
+/// [`VirtIOGpu::capset_info`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct CapsetInfo {
+    /// The kind of capability set.
+    pub id: CapsetId,
+    /// The latest version of the capability set which the device supports.
+    pub max_version: u32,
+    /// The maximum size of the capability set data, in bytes.
+    pub max_size: u32,
+}
+
+/// Which kinds of 3D rendering a GPU device supports, from [`VirtIOGpu::capabilities`].
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct GpuCapabilities {
+    /// Whether 3D mode was negotiated, which is needed to create a rendering context of any kind.
+    pub virgl: bool,
+    /// The capability sets supported by the device.
+    pub capsets: Vec<CapsetInfo>,
+}
+
+impl GpuCapabilities {
+    /// Returns information about the capability set with the given ID, if the device supports it.
+    pub fn get(&self, id: CapsetId) -> Option<&CapsetInfo> {
+        self.capsets.iter().find(|info| info.id == id)
+    }
+
+    /// Returns whether OpenGL rendering with virglrenderer is supported.
+    pub fn supports_virgl(&self) -> bool {
+        self.virgl && (self.get(CapsetId::VIRGL).is_some() || self.get(CapsetId::VIRGL2).is_some())
+    }
+
+    /// Returns whether Vulkan rendering with Venus is supported.
+    pub fn supports_venus(&self) -> bool {
+        self.virgl && self.get(CapsetId::VENUS).is_some()
+    }
+
+    /// Returns whether cross-domain contexts are supported.
+    pub fn supports_cross_domain(&self) -> bool {
+        self.virgl && self.get(CapsetId::CROSS_DOMAIN).is_some()
+    }
 }
 
 /// Display configuration has changed.
 const EVENT_DISPLAY: u32 = 1 << 0;
 
+/// An event reported by a GPU device, returned by [`VirtIOGpu::poll_event`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub enum GpuEvent {
+    /// The display configuration changed, e.g. because the host window was resized or the display
+    /// was connected or disconnected.
+    ///
+    /// The framebuffer is not resized automatically; the caller should set up a new one if it
+    /// wants to use the new resolution.
+    DisplayChanged {
+        /// The new width of the first scanout, in pixels.
+        width: u32,
+        /// The new height of the first scanout, in pixels.
+        height: u32,
+        /// Whether the first scanout is enabled.
+        enabled: bool,
+    },
+}
+
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
     struct Features: u64 {
@@ -337,6 +846,8 @@ bitflags! {
     }
 }
 
+impl_flags_display!(Features);
+
 #[repr(transparent)]
 #[derive(AsBytes, Clone, Copy, Debug, Eq, PartialEq, FromBytes, FromZeroes)]
 struct Command(u32);
@@ -354,6 +865,8 @@ impl Command {
     const GET_CAPSET: Command = Command(0x109);
     const GET_EDID: Command = Command(0x10a);
 
+    const TRANSFER_FROM_HOST_3D: Command = Command(0x206);
+
     const UPDATE_CURSOR: Command = Command(0x300);
     const MOVE_CURSOR: Command = Command(0x301);
 
@@ -401,13 +914,54 @@ impl CtrlHeader {
     }
 }
 
+/// A rectangle on the display, in pixels.
 #[repr(C)]
-#[derive(AsBytes, Debug, Copy, Clone, Default, FromBytes, FromZeroes)]
-struct Rect {
-    x: u32,
-    y: u32,
-    width: u32,
-    height: u32,
+#[derive(AsBytes, Debug, Copy, Clone, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct Rect {
+    /// The x coordinate of the left edge.
+    pub x: u32,
+    /// The y coordinate of the top edge.
+    pub y: u32,
+    /// The width of the rectangle.
+    pub width: u32,
+    /// The height of the rectangle.
+    pub height: u32,
+}
+
+impl Rect {
+    /// Creates a new rectangle with the given position and size.
+    pub const fn new(x: u32, y: u32, width: u32, height: u32) -> Self {
+        Self {
+            x,
+            y,
+            width,
+            height,
+        }
+    }
+
+    /// Returns whether the rectangle has no area.
+    pub fn is_empty(&self) -> bool {
+        self.width == 0 || self.height == 0
+    }
+
+    /// Returns the smallest rectangle containing both this one and the given one.
+    pub fn union(&self, other: &Rect) -> Rect {
+        let x = self.x.min(other.x);
+        let y = self.y.min(other.y);
+        let right = (self.x + self.width).max(other.x + other.width);
+        let bottom = (self.y + self.height).max(other.y + other.height);
+        Rect::new(x, y, right - x, bottom - y)
+    }
+
+    /// Returns whether the given rectangle lies entirely within this one.
+    fn contains(&self, other: &Rect) -> bool {
+        other.x >= self.x
+            && other.y >= self.y
+            && u64::from(other.x) + u64::from(other.width)
+                <= u64::from(self.x) + u64::from(self.width)
+            && u64::from(other.y) + u64::from(other.height)
+                <= u64::from(self.y) + u64::from(self.height)
+    }
 }
 
 #[repr(C)]
@@ -465,6 +1019,74 @@ struct TransferToHost2D {
     _padding: u32,
 }
 
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct GetCapsetInfo {
+    header: CtrlHeader,
+    capset_index: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, FromZeroes)]
+struct RespCapsetInfo {
+    header: CtrlHeader,
+    capset_id: u32,
+    capset_max_version: u32,
+    capset_max_size: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct GetEdid {
+    header: CtrlHeader,
+    scanout: u32,
+    _padding: u32,
+}
+
+#[repr(C)]
+#[derive(Debug, FromBytes, FromZeroes)]
+struct RespEdid {
+    header: CtrlHeader,
+    size: u32,
+    _padding: u32,
+    edid: [u8; EDID_MAX_SIZE],
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct GetCapset {
+    header: CtrlHeader,
+    capset_id: u32,
+    capset_version: u32,
+}
+
+/// `struct virtio_gpu_box`.
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct Box3D {
+    x: u32,
+    y: u32,
+    z: u32,
+    width: u32,
+    height: u32,
+    depth: u32,
+}
+
+/// `struct virtio_gpu_transfer_host_3d`, used for transfers in both directions.
+#[repr(C)]
+#[derive(AsBytes, Debug)]
+struct TransferHost3D {
+    header: CtrlHeader,
+    box_: Box3D,
+    offset: u64,
+    resource_id: u32,
+    level: u32,
+    stride: u32,
+    layer_stride: u32,
+}
+
 #[repr(C)]
 #[derive(AsBytes, Debug)]
 struct ResourceFlush {
@@ -494,12 +1116,41 @@ struct UpdateCursor {
     _padding: u32,
 }
 
+/// A buffer of a few rows of the display, used to stream updates to the frame buffer resource.
+struct StagingBuffer<H: Hal> {
+    dma: Dma<H>,
+    /// The rectangle of the whole display.
+    rect: Rect,
+    /// The number of rows of the display which fit in the buffer.
+    rows: u32,
+}
+
+/// A resource which isn't attached to any scanout, for headless rendering.
+struct Offscreen<H: Hal> {
+    /// The backing of the resource in guest memory.
+    dma: Dma<H>,
+    /// The rectangle covering the whole resource.
+    rect: Rect,
+}
+
+impl<H: Hal> Offscreen<H> {
+    /// Returns the offset in the backing of the top-left pixel of the given region, or
+    /// `InvalidParam` if it doesn't lie within the resource.
+    fn offset_of(&self, rect: &Rect) -> Result<u64> {
+        if rect.is_empty() || !self.rect.contains(rect) {
+            return Err(Error::InvalidParam);
+        }
+        Ok((u64::from(rect.y) * u64::from(self.rect.width) + u64::from(rect.x)) * 4)
+    }
+}
+
+#[cfg(test)]
 const QUEUE_TRANSMIT: u16 = 0;
-const QUEUE_CURSOR: u16 = 1;
 
 const SCANOUT_ID: u32 = 0;
 const RESOURCE_ID_FB: u32 = 0xbabe;
 const RESOURCE_ID_CURSOR: u32 = 0xdade;
+const RESOURCE_ID_OFFSCREEN: u32 = 0xcafe;
 
 const CURSOR_RECT: Rect = Rect {
     x: 0,
@@ -507,3 +1158,627 @@ const CURSOR_RECT: Rect = Rect {
     width: 64,
     height: 64,
 };
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{
+        convert::TryInto,
+        ptr::{addr_of, NonNull},
+    };
+    use std::{sync::Mutex, thread};
+
+    #[test]
+    fn display_changed_event() {
+        let mut config_space = Config {
+            events_read: EVENT_DISPLAY,
+            events_clear: 0,
+            num_scanouts: 1,
+            num_capsets: 0,
+        };
+        let config_space_ptr = NonNull::from(&mut config_space);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: config_space_ptr,
+            state: state.clone(),
+        };
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device answering the display info request.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                    assert_eq!(
+                        &request[..size_of::<CtrlHeader>()],
+                        CtrlHeader::with_type(Command::GET_DISPLAY_INFO).as_bytes()
+                    );
+                    let mut response = CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                        .as_bytes()
+                        .to_vec();
+                    response.extend_from_slice(Rect::new(0, 0, 1024, 768).as_bytes());
+                    response.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+                    response
+                });
+        });
+
+        assert_eq!(
+            gpu.poll_event().unwrap(),
+            Some(GpuEvent::DisplayChanged {
+                width: 1024,
+                height: 768,
+                enabled: true,
+            })
+        );
+        // Safe because the driver isn't accessing the config space at the moment.
+        let cleared = unsafe { addr_of!((*config_space_ptr.as_ptr()).events_clear).read() };
+        assert_eq!(cleared, EVENT_DISPLAY);
+
+        // Simulate the device clearing the event.
+        // Safe because the driver isn't accessing the config space at the moment.
+        unsafe {
+            (*config_space_ptr.as_ptr()).events_read = 0;
+        }
+        assert_eq!(gpu.poll_event().unwrap(), None);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn virgl_not_requested() {
+        let mut config_space = Config {
+            events_read: 0,
+            events_clear: 0,
+            num_scanouts: 1,
+            num_capsets: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: Features::VIRGL.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // The device offers 3D mode, but it isn't negotiated unless the driver asks for it.
+        assert_eq!(
+            state.lock().unwrap().driver_features & Features::VIRGL.bits(),
+            0
+        );
+        assert_eq!(
+            gpu.read_back_offscreen(Rect::new(0, 0, 1, 1)),
+            Err(Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn offscreen_read_back() {
+        let mut config_space = Config {
+            events_read: 0,
+            events_clear: 0,
+            num_scanouts: 1,
+            num_capsets: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: Features::VIRGL.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut gpu =
+            VirtIOGpu::<FakeHal, FakeTransport<Config>>::new_with_virgl(transport, true).unwrap();
+
+        // Simulate the device, keeping the host copy of the resource in a vector.
+        let handle = thread::spawn(move || {
+            let ok = CtrlHeader::with_type(Command::OK_NODATA)
+                .as_bytes()
+                .to_vec();
+            let mut backing = 0;
+            let mut host_resource = [0u8; 4 * 2 * 4];
+            for _ in 0..4 {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                        let header = CtrlHeader::read_from_prefix(&request).unwrap();
+                        let field = |offset: usize| {
+                            u32::from_le_bytes(request[offset..offset + 4].try_into().unwrap())
+                        };
+                        if header.hdr_type == Command::RESOURCE_ATTACH_BACKING {
+                            assert_eq!(field(24), RESOURCE_ID_OFFSCREEN);
+                            backing = u64::from_le_bytes(request[32..40].try_into().unwrap());
+                        } else if header.hdr_type == Command::TRANSFER_TO_HOST_2D {
+                            // Upload the whole resource.
+                            assert_eq!(&request[24..40], Rect::new(0, 0, 4, 2).as_bytes());
+                            assert_eq!(field(48), RESOURCE_ID_OFFSCREEN);
+                            // Safe because FakeHal uses identity-mapped DMA buffers, and the
+                            // driver isn't accessing the backing during the transfer.
+                            host_resource.copy_from_slice(unsafe {
+                                core::slice::from_raw_parts(backing as *const u8, 32)
+                            });
+                        } else if header.hdr_type == Command::TRANSFER_FROM_HOST_3D {
+                            // Read back the second pixel of the second row, after the host has
+                            // changed it.
+                            let (x, y, width, height) =
+                                (field(24), field(28), field(36), field(40));
+                            assert_eq!((x, y, width, height), (1, 1, 1, 1));
+                            assert_eq!(field(56), RESOURCE_ID_OFFSCREEN);
+                            assert_eq!(field(64), 16);
+                            let offset = u64::from_le_bytes(request[48..56].try_into().unwrap());
+                            assert_eq!(offset, 20);
+                            host_resource[20..24].copy_from_slice(&[9, 9, 9, 9]);
+                            // Safe for the same reason as above.
+                            unsafe {
+                                core::slice::from_raw_parts_mut((backing + offset) as *mut u8, 4)
+                                    .copy_from_slice(&host_resource[20..24]);
+                            }
+                        } else {
+                            assert_eq!(header.hdr_type, Command::RESOURCE_CREATE_2D);
+                            assert_eq!(field(24), RESOURCE_ID_OFFSCREEN);
+                        }
+                        ok.clone()
+                    });
+            }
+        });
+
+        let buf = gpu.setup_offscreen(4, 2).unwrap();
+        assert_eq!(buf.len(), 32);
+        buf.fill(1);
+        assert_eq!(gpu.setup_offscreen(4, 2).unwrap_err(), Error::AlreadyUsed);
+        assert_eq!(
+            gpu.upload_offscreen(Rect::new(3, 0, 2, 1)),
+            Err(Error::InvalidParam)
+        );
+        gpu.upload_offscreen(Rect::new(0, 0, 4, 2)).unwrap();
+        let buf = gpu.read_back_offscreen(Rect::new(1, 1, 1, 1)).unwrap();
+        assert_eq!(&buf[16..24], &[1, 1, 1, 1, 9, 9, 9, 9]);
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn fill_rect() {
+        let mut config_space = Config {
+            events_read: 0,
+            events_clear: 0,
+            num_scanouts: 1,
+            num_capsets: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Simulate a 4x2 display, checking the framebuffer when it is transferred.
+        let handle = thread::spawn(move || {
+            let mut backing = 0;
+            let mut commands = vec![];
+            for _ in 0..6 {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                        let header = CtrlHeader::read_from_prefix(&request).unwrap();
+                        commands.push(header.hdr_type);
+                        if header.hdr_type == Command::GET_DISPLAY_INFO {
+                            let mut response = CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                                .as_bytes()
+                                .to_vec();
+                            response.extend_from_slice(Rect::new(0, 0, 4, 2).as_bytes());
+                            response.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+                            return response;
+                        }
+                        if header.hdr_type == Command::RESOURCE_ATTACH_BACKING {
+                            backing = u64::from_le_bytes(request[32..40].try_into().unwrap());
+                        } else if header.hdr_type == Command::TRANSFER_TO_HOST_2D {
+                            assert_eq!(&request[24..40], Rect::new(1, 1, 2, 1).as_bytes());
+                            // Safe because FakeHal uses identity-mapped DMA buffers, and the
+                            // driver isn't accessing the backing during the transfer.
+                            let pixels =
+                                unsafe { core::slice::from_raw_parts(backing as *const u8, 32) };
+                            assert_eq!(
+                                &pixels[16..32],
+                                &[0, 0, 0, 0, 3, 2, 1, 0xff, 3, 2, 1, 0xff, 0, 0, 0, 0]
+                            );
+                        }
+                        CtrlHeader::with_type(Command::OK_NODATA)
+                            .as_bytes()
+                            .to_vec()
+                    });
+            }
+            commands
+        });
+
+        assert_eq!(
+            gpu.fill_rect(Rect::new(0, 0, 1, 1), 0),
+            Err(Error::NotReady)
+        );
+        gpu.setup_framebuffer().unwrap();
+        assert_eq!(
+            gpu.fill_rect(Rect::new(3, 0, 2, 1), 0),
+            Err(Error::InvalidParam)
+        );
+        gpu.fill_rect(Rect::new(1, 1, 2, 1), 0xff01_0203).unwrap();
+
+        assert_eq!(
+            handle.join().unwrap(),
+            [
+                Command::GET_DISPLAY_INFO,
+                Command::RESOURCE_CREATE_2D,
+                Command::RESOURCE_ATTACH_BACKING,
+                Command::SET_SCANOUT,
+                Command::TRANSFER_TO_HOST_2D,
+                Command::RESOURCE_FLUSH,
+            ]
+        );
+    }
+
+    #[test]
+    fn update_rect_streaming() {
+        let mut config_space = Config {
+            events_read: 0,
+            events_clear: 0,
+            num_scanouts: 1,
+            num_capsets: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Simulate a 4x3 display, checking the staging buffer each time it is transferred.
+        let handle = thread::spawn(move || {
+            let mut backing = 0;
+            let mut commands = vec![];
+            for _ in 0..7 {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                        let header = CtrlHeader::read_from_prefix(&request).unwrap();
+                        if header.hdr_type == Command::GET_DISPLAY_INFO {
+                            let mut response = CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                                .as_bytes()
+                                .to_vec();
+                            response.extend_from_slice(Rect::new(0, 0, 4, 3).as_bytes());
+                            response.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+                            return response;
+                        }
+                        if header.hdr_type == Command::RESOURCE_ATTACH_BACKING {
+                            backing = u64::from_le_bytes(request[32..40].try_into().unwrap());
+                            // Room for two rows of four pixels.
+                            assert_eq!(&request[40..44], &32u32.to_le_bytes());
+                        } else if header.hdr_type == Command::TRANSFER_TO_HOST_2D {
+                            let offset = u64::from_le_bytes(request[40..48].try_into().unwrap());
+                            assert_eq!(offset, 4);
+                            // Safe because FakeHal uses identity-mapped DMA buffers, and the
+                            // driver isn't accessing the staging buffer during the transfer.
+                            let staging =
+                                unsafe { core::slice::from_raw_parts(backing as *const u8, 32) };
+                            if request[24..40] == *Rect::new(1, 0, 2, 2).as_bytes() {
+                                assert_eq!(&staging[4..12], &[1; 8]);
+                                assert_eq!(&staging[20..28], &[2; 8]);
+                            } else {
+                                assert_eq!(&request[24..40], Rect::new(1, 2, 2, 1).as_bytes());
+                                assert_eq!(&staging[4..12], &[3; 8]);
+                            }
+                        } else if header.hdr_type == Command::RESOURCE_FLUSH {
+                            assert_eq!(&request[24..40], Rect::new(1, 0, 2, 3).as_bytes());
+                        }
+                        commands.push(header.hdr_type);
+                        CtrlHeader::with_type(Command::OK_NODATA)
+                            .as_bytes()
+                            .to_vec()
+                    });
+            }
+            commands
+        });
+
+        let rows = [[1; 8], [2; 8], [3; 8]];
+        assert_eq!(
+            gpu.update_rect(1, 0, 2, 3, rows.iter().map(|row| &row[..])),
+            Err(Error::NotReady)
+        );
+        assert_eq!(gpu.setup_framebuffer_streaming(2).unwrap(), (4, 3));
+        assert_eq!(
+            gpu.update_rect(3, 0, 2, 3, rows.iter().map(|row| &row[..])),
+            Err(Error::InvalidParam)
+        );
+        gpu.update_rect(1, 0, 2, 3, rows.iter().map(|row| &row[..]))
+            .unwrap();
+
+        assert_eq!(
+            handle.join().unwrap(),
+            [
+                Command::RESOURCE_CREATE_2D,
+                Command::RESOURCE_ATTACH_BACKING,
+                Command::SET_SCANOUT,
+                Command::TRANSFER_TO_HOST_2D,
+                Command::TRANSFER_TO_HOST_2D,
+                Command::RESOURCE_FLUSH,
+            ]
+        );
+    }
+
+    #[test]
+    fn flush_rects() {
+        let mut config_space = Config {
+            events_read: 0,
+            events_clear: 0,
+            num_scanouts: 1,
+            num_capsets: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Simulate a 4x2 display, recording the rectangle and offset of each transfer and flush.
+        let handle = thread::spawn(move || {
+            let mut commands = vec![];
+            for _ in 0..7 {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                        let header = CtrlHeader::read_from_prefix(&request).unwrap();
+                        if header.hdr_type == Command::GET_DISPLAY_INFO {
+                            let mut response = CtrlHeader::with_type(Command::OK_DISPLAY_INFO)
+                                .as_bytes()
+                                .to_vec();
+                            response.extend_from_slice(Rect::new(0, 0, 4, 2).as_bytes());
+                            response.extend_from_slice(&[1, 0, 0, 0, 0, 0, 0, 0]);
+                            return response;
+                        }
+                        let rect = Rect::read_from(&request[24..40]).unwrap();
+                        if header.hdr_type == Command::TRANSFER_TO_HOST_2D {
+                            let offset = u64::from_le_bytes(request[40..48].try_into().unwrap());
+                            commands.push((header.hdr_type, rect, offset));
+                        } else if header.hdr_type == Command::RESOURCE_FLUSH {
+                            commands.push((header.hdr_type, rect, 0));
+                        }
+                        CtrlHeader::with_type(Command::OK_NODATA)
+                            .as_bytes()
+                            .to_vec()
+                    });
+            }
+            commands
+        });
+
+        assert_eq!(gpu.flush_rects(&[]), Err(Error::NotReady));
+        gpu.setup_framebuffer().unwrap();
+        assert_eq!(
+            gpu.flush_rects(&[Rect::new(0, 0, 1, 1), Rect::new(2, 1, 3, 1)]),
+            Err(Error::InvalidParam)
+        );
+        gpu.flush_rects(&[
+            Rect::new(0, 0, 1, 1),
+            Rect::new(3, 0, 0, 0),
+            Rect::new(2, 1, 2, 1),
+        ])
+        .unwrap();
+        // Nothing is flushed if all the regions are empty.
+        gpu.flush_rects(&[Rect::new(1, 1, 0, 0)]).unwrap();
+
+        assert_eq!(
+            handle.join().unwrap(),
+            [
+                (Command::TRANSFER_TO_HOST_2D, Rect::new(0, 0, 1, 1), 0),
+                (Command::TRANSFER_TO_HOST_2D, Rect::new(2, 1, 2, 1), 24),
+                (Command::RESOURCE_FLUSH, Rect::new(0, 0, 4, 2), 0),
+            ]
+        );
+    }
+
+    #[test]
+    fn capsets() {
+        let mut config_space = Config {
+            events_read: 0,
+            events_clear: 0,
+            num_scanouts: 1,
+            num_capsets: 2,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: Features::VIRGL.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut gpu =
+            VirtIOGpu::<FakeHal, FakeTransport<Config>>::new_with_virgl(transport, true).unwrap();
+
+        // Start a thread to simulate the device answering two capset info requests and then a
+        // capset request.
+        let handle = thread::spawn(move || {
+            let capsets = [(CapsetId::VIRGL2, 2, 16), (CapsetId::VENUS, 0, 8)];
+            for (index, &(id, max_version, max_size)) in capsets.iter().enumerate() {
+                State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                        let request = GetCapsetInfo::read_from_prefix(&request).unwrap();
+                        assert_eq!(request.header.hdr_type, Command::GET_CAPSET_INFO);
+                        assert_eq!(request.capset_index, index as u32);
+                        let mut response = CtrlHeader::with_type(Command::OK_CAPSET_INFO)
+                            .as_bytes()
+                            .to_vec();
+                        for field in [id.0, max_version, max_size, 0] {
+                            response.extend_from_slice(&field.to_le_bytes());
+                        }
+                        response
+                    });
+            }
+
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                    let request = GetCapset::read_from_prefix(&request).unwrap();
+                    assert_eq!(request.header.hdr_type, Command::GET_CAPSET);
+                    assert_eq!(
+                        (request.capset_id, request.capset_version),
+                        (CapsetId::VENUS.0, 0)
+                    );
+                    let mut response = CtrlHeader::with_type(Command::OK_CAPSET)
+                        .as_bytes()
+                        .to_vec();
+                    response.extend_from_slice(&[0x56; 8]);
+                    response
+                });
+        });
+
+        assert_eq!(gpu.num_capsets().unwrap(), 2);
+        let capabilities = gpu.capabilities().unwrap();
+        assert!(capabilities.supports_virgl());
+        assert!(capabilities.supports_venus());
+        assert!(!capabilities.supports_cross_domain());
+        let venus = *capabilities.get(CapsetId::VENUS).unwrap();
+        assert_eq!(
+            venus,
+            CapsetInfo {
+                id: CapsetId::VENUS,
+                max_version: 0,
+                max_size: 8,
+            }
+        );
+        assert_eq!(gpu.capset(&venus, 0).unwrap(), vec![0x56; 8]);
+        handle.join().unwrap();
+
+        // Invalid indices and versions are rejected without sending a request.
+        assert_eq!(gpu.capset_info(2), Err(Error::InvalidParam));
+        assert_eq!(gpu.capset(&venus, 1), Err(Error::InvalidParam));
+
+        // Nor is a capability set too big to allocate a buffer for.
+        let huge = CapsetInfo {
+            max_size: CAPSET_MAX_SIZE as u32 + 1,
+            ..venus
+        };
+        assert_eq!(gpu.capset(&huge, 0), Err(Error::Unsupported));
+    }
+
+    #[test]
+    fn edid() {
+        let mut config_space = Config {
+            events_read: 0,
+            events_clear: 0,
+            num_scanouts: 1,
+            num_capsets: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::GPU,
+            max_queue_size: QUEUE_SIZE.into(),
+            device_features: Features::EDID.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut gpu = VirtIOGpu::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device returning a 128 byte EDID.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ QUEUE_SIZE as usize }>(QUEUE_TRANSMIT, |request| {
+                    let request = GetEdid::read_from_prefix(&request).unwrap();
+                    assert_eq!(request.header.hdr_type, Command::GET_EDID);
+                    assert_eq!(request.scanout, 0);
+                    let mut response = CtrlHeader::with_type(Command::OK_EDID).as_bytes().to_vec();
+                    response.extend_from_slice(&128u32.to_le_bytes());
+                    response.extend_from_slice(&0u32.to_le_bytes());
+                    response.extend_from_slice(&[0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+                    response.resize(response.len() + EDID_MAX_SIZE - 8, 0x12);
+                    response
+                });
+        });
+
+        let edid = gpu.edid(0).unwrap();
+        assert_eq!(edid.len(), 128);
+        assert_eq!(edid[..8], [0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff, 0x00]);
+        handle.join().unwrap();
+
+        assert_eq!(gpu.edid(1), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn rect_contains() {
+        let screen = Rect::new(0, 0, 640, 480);
+        assert!(screen.contains(&Rect::new(0, 0, 640, 480)));
+        assert!(screen.contains(&Rect::new(600, 400, 40, 80)));
+        assert!(!screen.contains(&Rect::new(600, 400, 41, 80)));
+        assert!(!screen.contains(&Rect::new(u32::MAX, 0, 2, 1)));
+    }
+
+    #[test]
+    fn rect_union() {
+        assert_eq!(
+            Rect::new(10, 20, 5, 5).union(&Rect::new(0, 30, 2, 10)),
+            Rect::new(0, 20, 15, 20)
+        );
+    }
+}
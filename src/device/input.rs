@@ -1,13 +1,16 @@
 //! Driver for VirtIO input devices.
 
 use super::common::Feature;
+use crate::device::queues::{QueueLayout, QueueRole};
 use crate::hal::Hal;
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::{volread, volwrite, ReadOnly, WriteOnly};
-use crate::Result;
-use alloc::boxed::Box;
-use core::ptr::NonNull;
+use crate::queue::AnyQueue;
+use crate::registry::DriverId;
+use crate::transport::{DeviceType, Transport};
+use crate::{Error, Result};
+use alloc::{boxed::Box, string::String};
+use bitflags::bitflags;
+use core::mem::{offset_of, size_of};
+use log::warn;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 /// Virtual human interface devices such as keyboards, mice and tablets.
@@ -16,11 +19,25 @@ use zerocopy::{AsBytes, FromBytes, FromZeroes};
 /// Device behavior mirrors that of the evdev layer in Linux,
 /// making pass-through implementations on top of evdev easy.
 pub struct VirtIOInput<H: Hal, T: Transport> {
+    id: DriverId,
     transport: T,
-    event_queue: VirtQueue<H, QUEUE_SIZE>,
-    status_queue: VirtQueue<H, QUEUE_SIZE>,
+    queues: QueueLayout,
+    event_queue: AnyQueue<H, QUEUE_SIZE>,
+    status_queue: AnyQueue<H, QUEUE_SIZE>,
     event_buf: Box<[InputEvent; 32]>,
-    config: NonNull<Config>,
+    /// Whether events have been lost and this hasn't been reported yet.
+    events_lost: bool,
+    /// If an event buffer couldn't be re-posted, the number of events which the device wrote
+    /// before the gap and which haven't been popped yet.
+    lost_after: Option<usize>,
+    /// Whether events are being discarded until the next `SYN_REPORT`, after events were lost.
+    resyncing: bool,
+    /// The number of event buffers which couldn't be re-posted to the device after being popped.
+    missing_buffers: u32,
+    /// The keys and buttons currently held down, if tracking was enabled.
+    key_state: Option<KeyState>,
+    /// The consumer which has grabbed the device for exclusive use, if any.
+    grabbed_by: Option<u32>,
 }
 
 impl<H: Hal, T: Transport> VirtIOInput<H, T> {
@@ -30,17 +47,23 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
 
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
-        let config = transport.config_space::<Config>()?;
-
-        let mut event_queue = VirtQueue::new(
+        let queues = QueueLayout::new(DeviceType::Input, negotiated_features.bits(), 0)?;
+        queues.validate(&mut transport)?;
+        let event_index =
+            queues.checked_index(&mut transport, QueueRole::Event, QUEUE_SIZE as u32)?;
+        let status_index =
+            queues.checked_index(&mut transport, QueueRole::Status, QUEUE_SIZE as u32)?;
+        let mut event_queue = AnyQueue::new(
             &mut transport,
-            QUEUE_EVENT,
+            event_index,
+            negotiated_features.contains(Feature::RING_PACKED),
             false,
             negotiated_features.contains(Feature::RING_EVENT_IDX),
         )?;
-        let status_queue = VirtQueue::new(
+        let status_queue = AnyQueue::new(
             &mut transport,
-            QUEUE_STATUS,
+            status_index,
+            negotiated_features.contains(Feature::RING_PACKED),
             false,
             negotiated_features.contains(Feature::RING_EVENT_IDX),
         )?;
@@ -50,52 +73,201 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
             assert_eq!(token, i as u16);
         }
         if event_queue.should_notify() {
-            transport.notify(QUEUE_EVENT);
+            event_queue.notify(&mut transport);
         }
 
         transport.finish_init();
 
         Ok(VirtIOInput {
+            id: DriverId::allocate(),
             transport,
+            queues,
             event_queue,
             status_queue,
             event_buf,
-            config,
+            events_lost: false,
+            lost_after: None,
+            resyncing: false,
+            missing_buffers: 0,
+            key_state: None,
+            grabbed_by: None,
         })
     }
 
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
     /// Acknowledge interrupt and process events.
     pub fn ack_interrupt(&mut self) -> bool {
         self.transport.ack_interrupt()
     }
 
     /// Pop the pending event.
+    ///
+    /// This returns events exactly as the device sent them, with no indication of whether any were
+    /// lost. Use [`pop_notification`](Self::pop_notification) instead to find out about lost
+    /// events.
     pub fn pop_pending_event(&mut self) -> Option<InputEvent> {
-        if let Some(token) = self.event_queue.peek_used() {
-            let event = &mut self.event_buf[token as usize];
-            // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it
-            // is still valid.
-            unsafe {
-                self.event_queue
-                    .pop_used(token, &[], &mut [event.as_bytes_mut()])
-                    .ok()?;
+        let token = self.event_queue.peek_used()?;
+        let event = &mut self.event_buf[token as usize];
+        // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it is
+        // still valid.
+        let written = unsafe {
+            self.event_queue
+                .pop_used(token, &[], &mut [event.as_bytes_mut()])
+                .ok()?
+        };
+        self.event_queue
+            .check_written(written, size_of::<InputEvent>());
+        let event_saved = *event;
+        if let Some(key_state) = &mut self.key_state {
+            key_state.update(&event_saved);
+        }
+        match self.lost_after {
+            Some(1) => {
+                self.lost_after = None;
+                self.events_lost = true;
             }
-            let event_saved = *event;
-            // requeue
-            // Safe because buffer lasts as long as the queue.
-            if let Ok(new_token) = unsafe { self.event_queue.add(&[], &mut [event.as_bytes_mut()]) }
-            {
+            Some(remaining) => self.lost_after = Some(remaining - 1),
+            None => {}
+        }
+        // requeue
+        // Safe because buffer lasts as long as the queue.
+        match unsafe { self.event_queue.add(&[], &mut [event.as_bytes_mut()]) } {
+            Ok(new_token) => {
                 // This only works because nothing happen between `pop_used` and `add` that affects
                 // the list of free descriptors in the queue, so `add` reuses the descriptor which
                 // was just freed by `pop_used`.
                 assert_eq!(new_token, token);
                 if self.event_queue.should_notify() {
-                    self.transport.notify(QUEUE_EVENT);
+                    self.event_queue.notify(&mut self.transport);
+                }
+            }
+            Err(e) => {
+                // The device has one less buffer to write events to, so is more likely to drop
+                // some.
+                warn!("Failed to re-post input event buffer {}: {:?}", token, e);
+                self.missing_buffers += 1;
+                match self.event_queue.pending_used() {
+                    0 => self.events_lost = true,
+                    pending => {
+                        self.lost_after = Some(self.lost_after.map_or(pending, |n| n.min(pending)))
+                    }
                 }
-                return Some(event_saved);
             }
         }
-        None
+        Some(event_saved)
+    }
+
+    /// Pops the next pending event, or a notification that events were lost.
+    ///
+    /// Events may be lost if the device sent them faster than they were popped, so it ran out of
+    /// buffers (which the device reports with a `SYN_DROPPED` event), or if the driver couldn't
+    /// give a buffer back to the device. In that case this returns [`InputNotification::EventsLost`]
+    /// and then discards events up to and including the next `SYN_REPORT`, as the rest of that
+    /// packet is incomplete. The caller should then treat any state it has built up from earlier
+    /// events, such as which keys are pressed, as stale and resynchronise it.
+    pub fn pop_notification(&mut self) -> Option<InputNotification> {
+        loop {
+            if self.events_lost {
+                self.events_lost = false;
+                self.resyncing = true;
+                return Some(InputNotification::EventsLost);
+            }
+            let event = self.pop_pending_event()?;
+            if event.event_type == EV_SYN && event.code == SYN_DROPPED {
+                self.events_lost = true;
+            } else if self.resyncing {
+                if event.event_type == EV_SYN && event.code == SYN_REPORT {
+                    self.resyncing = false;
+                }
+            } else {
+                return Some(InputNotification::Event(event));
+            }
+        }
+    }
+
+    /// Returns the number of event buffers which couldn't be given back to the device after their
+    /// events were popped, so the device has fewer buffers than it should.
+    pub fn missing_buffers(&self) -> u32 {
+        self.missing_buffers
+    }
+
+    /// Enables or disables tracking of which keys and buttons are held down.
+    ///
+    /// While enabled, every event popped from the device updates a [`KeyState`], so consumers
+    /// don't each need to build the same state machine. Enabling tracking starts from the state
+    /// where nothing is held, so it should be done before any events are popped.
+    pub fn track_key_state(&mut self, enable: bool) {
+        if enable {
+            self.key_state.get_or_insert_with(KeyState::default);
+        } else {
+            self.key_state = None;
+        }
+    }
+
+    /// Returns the keys and buttons currently held down, or `None` if tracking isn't enabled.
+    pub fn key_state(&self) -> Option<&KeyState> {
+        self.key_state.as_ref()
+    }
+
+    /// Returns events which bring a new consumer up to date with the keys and buttons held down.
+    ///
+    /// This is meant to be used after input focus moves, or a client grabs the device for
+    /// exclusive use with [`grab`](Self::grab): the returned iterator yields a key press event for
+    /// every key which is currently held, followed by a `SYN_REPORT`, which can be delivered to the
+    /// new consumer before any further events. It is empty if tracking isn't enabled.
+    pub fn sync_state(&self) -> KeySync<'_> {
+        KeySync {
+            key_state: self.key_state.as_ref(),
+            next_code: 0,
+            reported: false,
+        }
+    }
+
+    /// Grabs the device for exclusive use by the consumer with the given ID, like `EVIOCGRAB`.
+    ///
+    /// The driver doesn't deliver events to consumers itself, so this only keeps the books for the
+    /// caller: while the device is grabbed, [`receives_events`](Self::receives_events) returns
+    /// false for every other consumer. On success this returns the events which bring the grabbing
+    /// consumer up to date, as from [`sync_state`](Self::sync_state).
+    ///
+    /// Grabbing the device again by the consumer which already holds it succeeds. Returns
+    /// [`Error::AlreadyUsed`] if another consumer holds the grab.
+    pub fn grab(&mut self, consumer: u32) -> Result<KeySync<'_>> {
+        match self.grabbed_by {
+            Some(holder) if holder != consumer => Err(Error::AlreadyUsed),
+            _ => {
+                self.grabbed_by = Some(consumer);
+                Ok(self.sync_state())
+            }
+        }
+    }
+
+    /// Releases the grab held by the consumer with the given ID, so that every consumer receives
+    /// events again.
+    ///
+    /// Returns [`Error::InvalidParam`] if the consumer doesn't hold the grab.
+    pub fn ungrab(&mut self, consumer: u32) -> Result {
+        if self.grabbed_by != Some(consumer) {
+            return Err(Error::InvalidParam);
+        }
+        self.grabbed_by = None;
+        Ok(())
+    }
+
+    /// Returns the ID of the consumer which has grabbed the device, if any.
+    pub fn grabbed_by(&self) -> Option<u32> {
+        self.grabbed_by
+    }
+
+    /// Returns whether events should be delivered to the consumer with the given ID, i.e. whether
+    /// the device isn't grabbed or that consumer holds the grab.
+    pub fn receives_events(&self, consumer: u32) -> bool {
+        self.grabbed_by.is_none_or(|holder| holder == consumer)
     }
 
     /// Query a specific piece of information by `select` and `subsel`, and write
@@ -105,18 +277,54 @@ impl<H: Hal, T: Transport> VirtIOInput<H, T> {
         select: InputConfigSelect,
         subsel: u8,
         out: &mut [u8],
-    ) -> u8 {
-        let size;
-        let data;
-        // Safe because config points to a valid MMIO region for the config space.
-        unsafe {
-            volwrite!(self.config, select, select as u8);
-            volwrite!(self.config, subsel, subsel);
-            size = volread!(self.config, size);
-            data = volread!(self.config, data);
-        }
+    ) -> Result<u8> {
+        self.transport
+            .write_config(offset_of!(Config, select), select as u8)?;
+        self.transport
+            .write_config(offset_of!(Config, subsel), subsel)?;
+        let size: u8 = self.transport.read_config(offset_of!(Config, size))?;
+        let data: [u8; 128] = self.transport.read_config(offset_of!(Config, data))?;
         out[..size as usize].copy_from_slice(&data[..size as usize]);
-        size
+        Ok(size)
+    }
+
+    /// Returns the name of the device.
+    pub fn name(&mut self) -> Result<String> {
+        self.query_string(InputConfigSelect::IdName)
+    }
+
+    /// Returns the serial number of the device.
+    pub fn serial_number(&mut self) -> Result<String> {
+        self.query_string(InputConfigSelect::IdSerial)
+    }
+
+    /// Returns the bus type, vendor, product and version IDs of the device, if it provides them.
+    pub fn ids(&mut self) -> Result<Option<DevIDs>> {
+        let mut data = [0; 128];
+        let size = self.query_config_select(InputConfigSelect::IdDevids, 0, &mut data)?;
+        Ok(DevIDs::read_from_prefix(&data[..size.into()]))
+    }
+
+    /// Returns information about the given absolute axis (an `ABS_*` code), if the device has it.
+    pub fn abs_info(&mut self, axis: u8) -> Result<Option<AbsInfo>> {
+        let mut data = [0; 128];
+        let size = self.query_config_select(InputConfigSelect::AbsInfo, axis, &mut data)?;
+        Ok(AbsInfo::read_from_prefix(&data[..size.into()]))
+    }
+
+    /// Returns whether the device can send events of the given type (an `EV_*` constant) with the
+    /// given code.
+    pub fn supports_event(&mut self, event_type: u8, code: u16) -> Result<bool> {
+        let mut bitmap = [0; 128];
+        let size = self.query_config_select(InputConfigSelect::EvBits, event_type, &mut bitmap)?;
+        let byte = usize::from(code / 8);
+        Ok(byte < usize::from(size) && bitmap[byte] & (1 << (code % 8)) != 0)
+    }
+
+    fn query_string(&mut self, select: InputConfigSelect) -> Result<String> {
+        let mut data = [0; 128];
+        let size = self.query_config_select(select, 0, &mut data)?;
+        Ok(String::from_utf8_lossy(&data[..size.into()]).into_owned())
     }
 }
 
@@ -124,8 +332,9 @@ impl<H: Hal, T: Transport> Drop for VirtIOInput<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
-        self.transport.queue_unset(QUEUE_EVENT);
-        self.transport.queue_unset(QUEUE_STATUS);
+        for (index, _) in self.queues.queues() {
+            self.transport.queue_unset(index);
+        }
     }
 }
 
@@ -156,36 +365,49 @@ pub enum InputConfigSelect {
 
 #[repr(C)]
 struct Config {
-    select: WriteOnly<u8>,
-    subsel: WriteOnly<u8>,
-    size: ReadOnly<u8>,
-    _reversed: [ReadOnly<u8>; 5],
-    data: ReadOnly<[u8; 128]>,
+    select: u8,
+    subsel: u8,
+    size: u8,
+    _reserved: [u8; 5],
+    data: [u8; 128],
 }
 
+/// Information about an absolute axis of an input device, as returned by
+/// [`VirtIOInput::abs_info`].
 #[repr(C)]
-#[derive(Debug)]
-struct AbsInfo {
-    min: u32,
-    max: u32,
-    fuzz: u32,
-    flat: u32,
-    res: u32,
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct AbsInfo {
+    /// The minimum value of the axis.
+    pub min: u32,
+    /// The maximum value of the axis.
+    pub max: u32,
+    /// The size of the fuzz filter used to discard noise.
+    pub fuzz: u32,
+    /// The size of the dead zone around the centre, within which values are reported as the
+    /// centre.
+    pub flat: u32,
+    /// The resolution of the axis, in units per millimetre (or per radian for rotational axes).
+    pub res: u32,
 }
 
+/// The identifiers of an input device, as returned by [`VirtIOInput::ids`].
 #[repr(C)]
-#[derive(Debug)]
-struct DevIDs {
-    bustype: u16,
-    vendor: u16,
-    product: u16,
-    version: u16,
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct DevIDs {
+    /// The bus type, as a `BUS_*` constant.
+    pub bustype: u16,
+    /// The vendor ID.
+    pub vendor: u16,
+    /// The product ID.
+    pub product: u16,
+    /// The version number.
+    pub version: u16,
 }
 
 /// Both queues use the same `virtio_input_event` struct. `type`, `code` and `value`
 /// are filled according to the Linux input layer (evdev) interface.
 #[repr(C)]
-#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
 pub struct InputEvent {
     /// Event type.
     pub event_type: u16,
@@ -195,9 +417,385 @@ pub struct InputEvent {
     pub value: u32,
 }
 
+/// An item returned by [`VirtIOInput::pop_notification`].
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum InputNotification {
+    /// An event from the device.
+    Event(InputEvent),
+    /// Some events were lost, so the caller should resynchronise its state.
+    EventsLost,
+}
+
+/// The highest key or button code defined by evdev.
+const KEY_MAX: u16 = 0x2ff;
+
+/// The set of keys and buttons held down, built up from the event stream.
+#[derive(Clone, Debug, Default, Eq, PartialEq)]
+pub struct KeyState {
+    /// A bitmap of the key codes which are held down.
+    pressed: [u64; (KEY_MAX as usize + 1) / 64],
+    modifiers: Modifiers,
+}
+
+impl KeyState {
+    /// Updates the state for the given event. Events other than key and button events are
+    /// ignored.
+    pub fn update(&mut self, event: &InputEvent) {
+        if event.event_type != EV_KEY || event.code > KEY_MAX {
+            return;
+        }
+        let (word, bit) = (usize::from(event.code / 64), event.code % 64);
+        // A value of 2 is an autorepeat, so the key is still held.
+        let pressed = event.value != 0;
+        if pressed {
+            self.pressed[word] |= 1 << bit;
+        } else {
+            self.pressed[word] &= !(1 << bit);
+        }
+        if let Some(modifier) = Modifiers::from_code(event.code) {
+            self.modifiers.set(modifier, pressed);
+        }
+    }
+
+    /// Returns whether the key or button with the given evdev code is held down.
+    pub fn is_pressed(&self, code: u16) -> bool {
+        code <= KEY_MAX && self.pressed[usize::from(code / 64)] & (1 << (code % 64)) != 0
+    }
+
+    /// Returns the modifier keys which are held down.
+    pub fn modifiers(&self) -> Modifiers {
+        self.modifiers
+    }
+
+    /// Returns an iterator over the codes of all keys and buttons which are held down, in
+    /// ascending order.
+    pub fn pressed_keys(&self) -> impl Iterator<Item = u16> + '_ {
+        (0..=KEY_MAX).filter(move |&code| self.is_pressed(code))
+    }
+
+    /// Forgets all keys and buttons, as if they had all been released.
+    pub fn clear(&mut self) {
+        *self = Self::default();
+    }
+}
+
+bitflags! {
+    /// Modifier keys which are held down.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct Modifiers: u8 {
+        /// The left control key.
+        const LEFT_CTRL = 1 << 0;
+        /// The left shift key.
+        const LEFT_SHIFT = 1 << 1;
+        /// The left alt key.
+        const LEFT_ALT = 1 << 2;
+        /// The left meta (logo) key.
+        const LEFT_META = 1 << 3;
+        /// The right control key.
+        const RIGHT_CTRL = 1 << 4;
+        /// The right shift key.
+        const RIGHT_SHIFT = 1 << 5;
+        /// The right alt key.
+        const RIGHT_ALT = 1 << 6;
+        /// The right meta (logo) key.
+        const RIGHT_META = 1 << 7;
+    }
+}
+
+impl Modifiers {
+    /// Returns the modifier for the given evdev key code, if it is one.
+    fn from_code(code: u16) -> Option<Self> {
+        match code {
+            29 => Some(Self::LEFT_CTRL),
+            42 => Some(Self::LEFT_SHIFT),
+            56 => Some(Self::LEFT_ALT),
+            125 => Some(Self::LEFT_META),
+            97 => Some(Self::RIGHT_CTRL),
+            54 => Some(Self::RIGHT_SHIFT),
+            100 => Some(Self::RIGHT_ALT),
+            126 => Some(Self::RIGHT_META),
+            _ => None,
+        }
+    }
+}
+
+/// An iterator over the events returned by [`VirtIOInput::sync_state`].
+#[derive(Clone, Debug)]
+pub struct KeySync<'a> {
+    key_state: Option<&'a KeyState>,
+    next_code: u16,
+    reported: bool,
+}
+
+impl Iterator for KeySync<'_> {
+    type Item = InputEvent;
+
+    fn next(&mut self) -> Option<InputEvent> {
+        let key_state = self.key_state?;
+        while self.next_code <= KEY_MAX {
+            let code = self.next_code;
+            self.next_code += 1;
+            if key_state.is_pressed(code) {
+                return Some(InputEvent {
+                    event_type: EV_KEY,
+                    code,
+                    value: 1,
+                });
+            }
+        }
+        if self.reported {
+            return None;
+        }
+        self.reported = true;
+        Some(InputEvent {
+            event_type: EV_SYN,
+            code: SYN_REPORT,
+            value: 0,
+        })
+    }
+}
+
+/// The event type for synchronisation events.
+const EV_SYN: u16 = 0x00;
+/// The event type for key and button events.
+const EV_KEY: u16 = 0x01;
+/// The end of a packet of events which happened at the same time.
+const SYN_REPORT: u16 = 0;
+/// Events were dropped because the device's buffer overran.
+const SYN_DROPPED: u16 = 3;
+
+#[cfg(test)]
 const QUEUE_EVENT: u16 = 0;
-const QUEUE_STATUS: u16 = 1;
-const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX.union(Feature::RING_PACKED);
 
 // a parameter that can change
 const QUEUE_SIZE: usize = 32;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec, vec::Vec};
+    use core::ptr::NonNull;
+    use std::sync::Mutex;
+
+    fn event(event_type: u16, code: u16, value: u32) -> InputEvent {
+        InputEvent {
+            event_type,
+            code,
+            value,
+        }
+    }
+
+    fn make_input(
+        config_space: &mut Config,
+    ) -> (
+        VirtIOInput<FakeHal, FakeTransport<Config>>,
+        Arc<Mutex<State>>,
+    ) {
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Input,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: 0,
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        (VirtIOInput::new(transport).unwrap(), state)
+    }
+
+    fn make_config() -> Config {
+        Config {
+            select: 0,
+            subsel: 0,
+            size: 0,
+            _reserved: [0; 5],
+            data: [0; 128],
+        }
+    }
+
+    #[test]
+    fn config_queries() {
+        let mut config_space = make_config();
+        let config_ptr = NonNull::from(&mut config_space);
+        let (mut input, _state) = make_input(&mut config_space);
+        let set_data = |data: &[u8]| {
+            let mut config = make_config();
+            config.size = data.len() as u8;
+            let mut bytes = [0; 128];
+            bytes[..data.len()].copy_from_slice(data);
+            config.data = bytes;
+            // Safe because the driver isn't accessing the config space at the moment.
+            unsafe { config_ptr.as_ptr().write(config) };
+        };
+
+        set_data(b"QEMU Virtio Keyboard");
+        assert_eq!(input.name().unwrap(), "QEMU Virtio Keyboard");
+
+        let ids = DevIDs {
+            bustype: 6,
+            vendor: 0x0627,
+            product: 1,
+            version: 1,
+        };
+        set_data(ids.as_bytes());
+        assert_eq!(input.ids(), Ok(Some(ids)));
+        set_data(&[]);
+        assert_eq!(input.ids(), Ok(None));
+        assert_eq!(input.abs_info(0), Ok(None));
+
+        // KEY_A (30) and KEY_S (31) are supported.
+        set_data(&[0, 0, 0, 0xc0]);
+        assert_eq!(input.supports_event(EV_KEY as u8, 30), Ok(true));
+        assert_eq!(input.supports_event(EV_KEY as u8, 31), Ok(true));
+        assert_eq!(input.supports_event(EV_KEY as u8, 29), Ok(false));
+        assert_eq!(input.supports_event(EV_KEY as u8, 100), Ok(false));
+    }
+
+    #[test]
+    fn syn_dropped() {
+        let mut config_space = make_config();
+        let (mut input, state) = make_input(&mut config_space);
+        for event in [
+            event(EV_KEY, 30, 1),
+            event(EV_SYN, SYN_DROPPED, 0),
+            event(EV_KEY, 30, 0),
+            event(EV_SYN, SYN_REPORT, 0),
+            event(EV_KEY, 31, 1),
+        ] {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event.as_bytes());
+        }
+
+        assert_eq!(
+            input.pop_notification(),
+            Some(InputNotification::Event(event(EV_KEY, 30, 1)))
+        );
+        assert_eq!(
+            input.pop_notification(),
+            Some(InputNotification::EventsLost)
+        );
+        // The rest of the incomplete packet is discarded.
+        assert_eq!(
+            input.pop_notification(),
+            Some(InputNotification::Event(event(EV_KEY, 31, 1)))
+        );
+        assert_eq!(input.pop_notification(), None);
+    }
+
+    #[test]
+    fn exactly_full() {
+        let mut config_space = make_config();
+        let (mut input, state) = make_input(&mut config_space);
+        // The device uses every buffer, but filling the queue doesn't mean that any were dropped.
+        for i in 0..QUEUE_SIZE {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event(EV_KEY, i as u16, 1).as_bytes());
+        }
+
+        for i in 0..QUEUE_SIZE {
+            assert_eq!(
+                input.pop_notification(),
+                Some(InputNotification::Event(event(EV_KEY, i as u16, 1)))
+            );
+        }
+        assert_eq!(input.pop_notification(), None);
+        assert_eq!(input.missing_buffers(), 0);
+
+        // If it had to drop any it says so with SYN_DROPPED, once there is a buffer to say it in.
+        for event in [event(EV_SYN, SYN_DROPPED, 0), event(EV_KEY, 31, 1)] {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event.as_bytes());
+        }
+        assert_eq!(
+            input.pop_notification(),
+            Some(InputNotification::EventsLost)
+        );
+    }
+
+    #[test]
+    fn key_state() {
+        let mut config_space = make_config();
+        let (mut input, state) = make_input(&mut config_space);
+        assert_eq!(input.sync_state().next(), None);
+        input.track_key_state(true);
+        for event in [
+            event(EV_KEY, 42, 1),
+            event(EV_KEY, 30, 1),
+            event(EV_SYN, SYN_REPORT, 0),
+            event(EV_KEY, 30, 2),
+            event(EV_KEY, 0x110, 1),
+            event(EV_KEY, 30, 0),
+            event(EV_SYN, SYN_REPORT, 0),
+        ] {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event.as_bytes());
+        }
+        while input.pop_notification().is_some() {}
+
+        let key_state = input.key_state().unwrap();
+        assert!(key_state.is_pressed(42));
+        assert!(!key_state.is_pressed(30));
+        assert!(key_state.is_pressed(0x110));
+        assert_eq!(key_state.modifiers(), Modifiers::LEFT_SHIFT);
+        assert_eq!(
+            input.sync_state().collect::<Vec<_>>(),
+            vec![
+                event(EV_KEY, 42, 1),
+                event(EV_KEY, 0x110, 1),
+                event(EV_SYN, SYN_REPORT, 0),
+            ]
+        );
+
+        input.track_key_state(false);
+        assert_eq!(input.key_state(), None);
+    }
+
+    #[test]
+    fn grab() {
+        let mut config_space = make_config();
+        let (mut input, state) = make_input(&mut config_space);
+        input.track_key_state(true);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_EVENT, event(EV_KEY, 42, 1).as_bytes());
+        while input.pop_notification().is_some() {}
+        assert!(input.receives_events(1));
+        assert!(input.receives_events(2));
+
+        // The grabbing consumer is brought up to date with the held keys.
+        assert_eq!(
+            input.grab(1).unwrap().collect::<Vec<_>>(),
+            vec![event(EV_KEY, 42, 1), event(EV_SYN, SYN_REPORT, 0)]
+        );
+        assert_eq!(input.grabbed_by(), Some(1));
+        assert!(input.receives_events(1));
+        assert!(!input.receives_events(2));
+        assert!(input.grab(1).is_ok());
+        assert_eq!(input.grab(2).err(), Some(Error::AlreadyUsed));
+
+        assert_eq!(input.ungrab(2), Err(Error::InvalidParam));
+        assert_eq!(input.ungrab(1), Ok(()));
+        assert_eq!(input.grabbed_by(), None);
+        assert!(input.receives_events(2));
+        assert_eq!(input.ungrab(1), Err(Error::InvalidParam));
+    }
+}
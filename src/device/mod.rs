@@ -1,15 +1,35 @@
 //! Drivers for specific VirtIO devices.
+//!
+//! Each driver is behind a cargo feature of the same name, all of which are enabled by the default
+//! `full` feature.
 
+#[cfg(feature = "balloon")]
+pub mod balloon;
+#[cfg(feature = "blk")]
 pub mod blk;
-#[cfg(feature = "alloc")]
+#[cfg(feature = "console")]
 pub mod console;
-#[cfg(feature = "alloc")]
+#[cfg(feature = "crypto")]
+pub mod crypto;
+#[cfg(feature = "fs")]
+pub mod fs;
+#[cfg(feature = "gpu")]
 pub mod gpu;
-#[cfg(feature = "alloc")]
+#[cfg(feature = "input")]
 pub mod input;
 
+#[cfg(feature = "net")]
 pub mod net;
+#[cfg(feature = "p9")]
+pub mod p9;
+pub mod queues;
+#[cfg(feature = "rng")]
+pub mod rng;
+#[cfg(feature = "scsi")]
+pub mod scsi;
 
 pub mod socket;
+#[cfg(feature = "sound")]
+pub mod sound;
 
 pub(crate) mod common;
@@ -1,8 +1,60 @@
-use alloc::vec;
+use alloc::{boxed::Box, vec, vec::Vec};
 
-use super::net_buf::{RxBuffer, TxBuffer};
-use super::{EthernetAddress, VirtIONetRaw};
-use crate::{hal::Hal, transport::Transport, Error, Result};
+use super::net_buf::{RxBuffer, RxBufferLayout, TxBuffer};
+use super::{
+    EthernetAddress, Gro, GuestOffloads, NetConfigChanges, RssHashTypes, RssMapping, RxFeatures,
+    TxCompletion, TxToken, VirtIONetRaw, VirtioNetHdr,
+};
+use crate::{
+    checksum::Checksum,
+    diagnostics::SlowPathThresholds,
+    failover::FailoverMember,
+    hal::{AllocFailurePolicy, Hal},
+    interrupt::{InterruptStats, LostInterruptWatchdog, SharedIrqDevice, VirtioDevice},
+    queue::InFlightLimit,
+    registry::DriverId,
+    transport::Transport,
+    tunable::{self, Tunable, TunableValue, Tunables},
+    Error, Result,
+};
+use log::warn;
+
+/// The name of the tunable for the number of receive buffers.
+const RX_BUFFERS: &str = "rx_buffers";
+const RX_BUFFERS_TUNABLE: Tunable = Tunable {
+    name: RX_BUFFERS,
+    description: "Number of receive buffers allocated by the driver",
+    writable: false,
+};
+
+/// What [`VirtIONet::receive`] does with a received packet, as decided by the receive hook.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum RxVerdict {
+    /// Return the packet from `receive` as usual.
+    Pass,
+    /// Discard the packet, e.g. because a firewall rule rejected it. The buffer goes straight back
+    /// to the receive queue.
+    Drop,
+    /// The hook has dealt with the packet itself, e.g. by copying it elsewhere or answering it, so
+    /// the buffer goes straight back to the receive queue.
+    Recycle,
+}
+
+/// A callback which [`VirtIONet::receive`] runs on each received packet before returning it, to
+/// decide whether to return it at all.
+///
+/// The hook sees the buffer exactly as the device filled it, so can also look at the header or
+/// use the headroom. It may modify the packet before passing it on.
+pub type RxHook = Box<dyn FnMut(&mut RxBuffer) -> RxVerdict + Send>;
+
+/// Counts of the received packets which the receive hook didn't pass on.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct RxHookStats {
+    /// The number of packets for which the hook returned [`RxVerdict::Drop`].
+    pub dropped: u64,
+    /// The number of packets for which the hook returned [`RxVerdict::Recycle`].
+    pub recycled: u64,
+}
 
 /// Driver for a VirtIO network device.
 ///
@@ -20,24 +72,127 @@ use crate::{hal::Hal, transport::Transport, Error, Result};
 pub struct VirtIONet<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
     inner: VirtIONetRaw<H, T, QUEUE_SIZE>,
     rx_buffers: [Option<RxBuffer>; QUEUE_SIZE],
+    /// The number of receive buffers which were allocated.
+    rx_buffer_total: usize,
+    /// The callback to filter received packets, if any.
+    rx_hook: Option<RxHook>,
+    rx_hook_stats: RxHookStats,
 }
 
 impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE> {
     /// Create a new VirtIO-Net driver.
     pub fn new(transport: T, buf_len: usize) -> Result<Self> {
-        let mut inner = VirtIONetRaw::new(transport)?;
+        Self::new_with_policy(transport, buf_len, AllocFailurePolicy::Fail)
+    }
+
+    /// Creates a new VirtIO-Net driver which also negotiates the given receive features, if the
+    /// device offers them.
+    ///
+    /// With [`RxFeatures::MRG_RXBUF`] packets must be received with
+    /// [`receive_packet`](Self::receive_packet), as [`receive`](Self::receive) may return only
+    /// the first part of one. With [`RxFeatures::GUEST_CSUM`] received packets may need their
+    /// checksums completed, e.g. with [`RxBuffer::complete_checksum`].
+    pub fn new_with_rx_features(
+        transport: T,
+        buf_len: usize,
+        rx_features: RxFeatures,
+    ) -> Result<Self> {
+        let inner = VirtIONetRaw::init(transport, 1, AllocFailurePolicy::Fail, rx_features)?;
+        Self::with_rx_buffers(
+            inner,
+            buf_len,
+            RxBufferLayout::default(),
+            AllocFailurePolicy::Fail,
+        )
+    }
+
+    /// Create a new VirtIO-Net driver, handling failure to allocate receive buffers according to
+    /// the given policy.
+    ///
+    /// With [`AllocFailurePolicy::Degrade`], if memory runs out while allocating the `QUEUE_SIZE`
+    /// receive buffers the driver carries on with however many it managed to allocate, which can
+    /// be checked with [`rx_buffer_count`](Self::rx_buffer_count). Likewise the queues are made
+    /// smaller if there isn't enough DMA memory for `QUEUE_SIZE` entries, in which case at most as
+    /// many receive buffers as fit in the receive queue are allocated.
+    pub fn new_with_policy(
+        transport: T,
+        buf_len: usize,
+        policy: AllocFailurePolicy,
+    ) -> Result<Self> {
+        Self::new_with_rx_layout(transport, buf_len, RxBufferLayout::default(), policy)
+    }
+
+    /// Create a new VirtIO-Net driver whose receive buffers have the given headroom and alignment,
+    /// handling failure to allocate them according to the given policy.
+    ///
+    /// `buf_len` is the length of each buffer after the headroom, which must be enough for the
+    /// virtio header and the largest packet. Returns [`Error::InvalidParam`] if the layout is
+    /// invalid.
+    pub fn new_with_rx_layout(
+        transport: T,
+        buf_len: usize,
+        rx_layout: RxBufferLayout,
+        policy: AllocFailurePolicy,
+    ) -> Result<Self> {
+        rx_layout.check()?;
+        let inner = VirtIONetRaw::new_with_policy(transport, policy)?;
+        Self::with_rx_buffers(inner, buf_len, rx_layout, policy)
+    }
+
+    /// Wraps the given driver, allocating receive buffers with the given layout and handing them
+    /// to the device.
+    fn with_rx_buffers(
+        mut inner: VirtIONetRaw<H, T, QUEUE_SIZE>,
+        buf_len: usize,
+        rx_layout: RxBufferLayout,
+        policy: AllocFailurePolicy,
+    ) -> Result<Self> {
+        let (rx_queue_size, _) = inner.queue_sizes();
 
         const NONE_BUF: Option<RxBuffer> = None;
         let mut rx_buffers = [NONE_BUF; QUEUE_SIZE];
-        for (i, rx_buf_place) in rx_buffers.iter_mut().enumerate() {
-            let mut rx_buf = RxBuffer::new(i, buf_len);
+        let mut rx_buffer_total = 0;
+        for (i, rx_buf_place) in rx_buffers.iter_mut().take(rx_queue_size.into()).enumerate() {
+            let mut rx_buf = match RxBuffer::try_new(i, buf_len, rx_layout) {
+                Ok(rx_buf) => rx_buf,
+                Err(_) if policy == AllocFailurePolicy::Degrade && i > 0 => {
+                    warn!(
+                        "Out of memory for receive buffers, continuing with {} of {}",
+                        i, rx_queue_size
+                    );
+                    break;
+                }
+                Err(e) => return Err(e),
+            };
             // Safe because the buffer lives as long as the queue.
             let token = unsafe { inner.receive_begin(rx_buf.as_bytes_mut())? };
             assert_eq!(token, i as u16);
             *rx_buf_place = Some(rx_buf);
+            rx_buffer_total += 1;
         }
 
-        Ok(VirtIONet { inner, rx_buffers })
+        Ok(VirtIONet {
+            inner,
+            rx_buffers,
+            rx_buffer_total,
+            rx_hook: None,
+            rx_hook_stats: RxHookStats::default(),
+        })
+    }
+
+    /// Returns the number of receive buffers which the driver owns, whether they are currently in
+    /// the receive queue or not.
+    ///
+    /// This is `QUEUE_SIZE` unless the driver was created with [`AllocFailurePolicy::Degrade`] and
+    /// ran out of memory for the buffers or the receive queue.
+    pub fn rx_buffer_count(&self) -> usize {
+        self.rx_buffer_total
+    }
+
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.inner.id()
     }
 
     /// Acknowledge interrupt.
@@ -45,6 +200,66 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
         self.inner.ack_interrupt()
     }
 
+    /// Returns counts of the interrupts acknowledged so far.
+    pub fn interrupt_stats(&self) -> InterruptStats {
+        self.inner.interrupt_stats()
+    }
+
+    /// Sets the number of consecutive spurious interrupts after which to disable interrupts and
+    /// switch to polling, or `None` (the default) to never switch.
+    pub fn set_spurious_interrupt_threshold(&mut self, threshold: Option<u32>) {
+        self.inner.set_spurious_interrupt_threshold(threshold);
+    }
+
+    /// Returns whether interrupts were disabled because of too many spurious interrupts.
+    pub fn switched_to_polling(&self) -> bool {
+        self.inner.switched_to_polling()
+    }
+
+    /// Sets when to suspect that an interrupt was lost, for
+    /// [`check_lost_interrupt`](Self::check_lost_interrupt). The watchdog is disabled by default.
+    pub fn set_lost_interrupt_watchdog(&mut self, watchdog: LostInterruptWatchdog) {
+        self.inner.set_lost_interrupt_watchdog(watchdog);
+    }
+
+    /// Checks whether the device has used buffers without its interrupt arriving, once the
+    /// [`LostInterruptWatchdog`] has expired.
+    ///
+    /// This should be called periodically, e.g. from a timer tick. If it returns true then the
+    /// caller should process the used buffers as if the interrupt had arrived.
+    pub fn check_lost_interrupt(&mut self) -> bool {
+        self.inner.check_lost_interrupt()
+    }
+
+    /// Limits the number of transmissions which may be in flight at once, or removes the limit if
+    /// `limit` is `None` (the default).
+    ///
+    /// See [`VirtIONetRaw::set_in_flight_limit`].
+    pub fn set_in_flight_limit(&mut self, limit: Option<InFlightLimit>) -> Result {
+        self.inner.set_in_flight_limit(limit)
+    }
+
+    /// Sets the thresholds beyond which to log warnings about slow paths.
+    ///
+    /// See [`VirtIONetRaw::set_slow_path_thresholds`].
+    pub fn set_slow_path_thresholds(&mut self, thresholds: SlowPathThresholds) {
+        self.inner.set_slow_path_thresholds(thresholds)
+    }
+
+    /// Defers notifying the device about transmitted packets and recycled receive buffers until
+    /// [`flush_notifications`](Self::flush_notifications) is called.
+    ///
+    /// See [`VirtIONetRaw::defer_notifications`].
+    pub fn defer_notifications(&mut self) {
+        self.inner.defer_notifications()
+    }
+
+    /// Notifies the device about everything queued since
+    /// [`defer_notifications`](Self::defer_notifications) was called.
+    pub fn flush_notifications(&mut self) {
+        self.inner.flush_notifications()
+    }
+
     /// Disable interrupts.
     pub fn disable_interrupts(&mut self) {
         self.inner.disable_interrupts()
@@ -60,6 +275,61 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
         self.inner.mac_address()
     }
 
+    /// Returns whether the link was up when the configuration was last read.
+    pub fn link_up(&self) -> bool {
+        self.inner.link_up()
+    }
+
+    /// Returns whether the device is a standby for a primary device with the same MAC address.
+    pub fn is_standby(&self) -> bool {
+        self.inner.is_standby()
+    }
+
+    /// Re-reads the parts of the device configuration which may change at runtime, and returns
+    /// what changed since they were last read.
+    pub fn refresh_config(&mut self) -> Result<Option<NetConfigChanges>> {
+        self.inner.refresh_config()
+    }
+
+    /// Changes the MAC address of the device.
+    ///
+    /// See [`VirtIONetRaw::set_mac`].
+    pub fn set_mac(&mut self, mac: EthernetAddress) -> Result {
+        self.inner.set_mac(mac)
+    }
+
+    /// Enables the given receive offloads and disables all others.
+    ///
+    /// See [`VirtIONetRaw::set_guest_offloads`].
+    pub fn set_guest_offloads(&mut self, offloads: GuestOffloads) -> Result {
+        self.inner.set_guest_offloads(offloads)
+    }
+
+    /// Returns the kinds of packets which the device can hash for receive side scaling, if it
+    /// supports RSS.
+    pub fn rss_hash_types(&self) -> Option<RssHashTypes> {
+        self.inner.rss_hash_types()
+    }
+
+    /// Programs the device's receive side scaling configuration.
+    ///
+    /// See [`VirtIONetRaw::set_rss`].
+    pub fn set_rss(&mut self, mapping: &RssMapping) -> Result {
+        self.inner.set_rss(mapping)
+    }
+
+    /// Programs a symmetric receive side scaling key generated from the given entropy, and returns
+    /// the resulting mapping.
+    ///
+    /// See [`VirtIONetRaw::enable_symmetric_rss`].
+    pub fn enable_symmetric_rss(
+        &mut self,
+        entropy: u16,
+        hash_types: RssHashTypes,
+    ) -> Result<RssMapping> {
+        self.inner.enable_symmetric_rss(entropy, hash_types)
+    }
+
     /// Whether can send packet.
     pub fn can_send(&self) -> bool {
         self.inner.can_send()
@@ -70,12 +340,160 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
         self.inner.poll_receive().is_some()
     }
 
+    /// Returns whether the device can complete partial checksums of transmitted packets.
+    ///
+    /// See [`VirtIONetRaw::checksum_offload`].
+    pub fn checksum_offload(&self) -> bool {
+        self.inner.checksum_offload()
+    }
+
+    /// Returns whether the device may leave partial checksums in received packets or mark them
+    /// as already validated.
+    ///
+    /// See [`VirtIONetRaw::guest_checksum`].
+    pub fn guest_checksum(&self) -> bool {
+        self.inner.guest_checksum()
+    }
+
+    /// Returns whether the device may spread a received packet across several receive buffers,
+    /// in which case packets should be received with [`receive_packet`](Self::receive_packet).
+    pub fn mergeable_rx_buffers(&self) -> bool {
+        self.inner.mergeable_rx_buffers()
+    }
+
     /// Receives a [`RxBuffer`] from network. If currently no data, returns an
     /// error with type [`Error::NotReady`].
     ///
     /// It will try to pop a buffer that completed data reception in the
-    /// NIC queue.
+    /// NIC queue. If `VIRTIO_NET_F_MRG_RXBUF` was negotiated this may be only the first part of
+    /// the packet; use [`receive_packet`](Self::receive_packet) to get all of it.
     pub fn receive(&mut self) -> Result<RxBuffer> {
+        loop {
+            let mut rx_buf = self.pop_rx_buffer(false)?;
+            let Some(hook) = &mut self.rx_hook else {
+                return Ok(rx_buf);
+            };
+            match hook(&mut rx_buf) {
+                RxVerdict::Pass => return Ok(rx_buf),
+                RxVerdict::Drop => self.rx_hook_stats.dropped += 1,
+                RxVerdict::Recycle => self.rx_hook_stats.recycled += 1,
+            }
+            self.recycle_rx_buffer(rx_buf)?;
+        }
+    }
+
+    /// Sets a hook to run on each received packet before [`receive`](Self::receive) or
+    /// [`receive_packet`](Self::receive_packet) returns it, or removes it if `hook` is `None`.
+    ///
+    /// This allows cheap filtering, such as firewalling, in the driver: packets which the hook
+    /// drops or recycles are never returned, and their buffers are put straight back in the
+    /// receive queue without any allocation or copying. Note that
+    /// [`can_recv`](Self::can_recv) still reports packets which the hook will drop.
+    pub fn set_rx_hook(&mut self, hook: Option<RxHook>) {
+        self.rx_hook = hook;
+    }
+
+    /// Returns counts of the packets which the receive hook dropped or recycled.
+    pub fn rx_hook_stats(&self) -> RxHookStats {
+        self.rx_hook_stats
+    }
+
+    /// Receives every packet which is ready, passing them through the given software GRO layer to
+    /// coalesce consecutive TCP segments, and calls `deliver` with each resulting frame.
+    ///
+    /// Packets are received as by [`receive`](Self::receive), so the receive hook still runs, and
+    /// their buffers are recycled once they have been passed on. Any partial checksum left by the
+    /// device is completed first. The GRO layer is flushed before this returns, so nothing is held
+    /// back until the next call.
+    ///
+    /// Returns the number of packets received, which may be more than the number of frames
+    /// delivered.
+    pub fn receive_coalesced<C: Checksum>(
+        &mut self,
+        gro: &mut Gro<C>,
+        mut deliver: impl FnMut(&[u8]),
+    ) -> Result<usize> {
+        let mut received = 0;
+        let result = loop {
+            let mut rx_buf = match self.receive() {
+                Ok(rx_buf) => rx_buf,
+                Err(Error::NotReady) => break Ok(received),
+                Err(e) => break Err(e),
+            };
+            received += 1;
+            // If the offsets of a partial checksum are bad, the segment fails verification and is
+            // passed on unchanged.
+            let checksum_valid =
+                rx_buf.header().data_valid() || rx_buf.complete_checksum::<C>().unwrap_or(false);
+            gro.receive(rx_buf.packet(), checksum_valid, &mut deliver);
+            if let Err(e) = self.recycle_rx_buffer(rx_buf) {
+                break Err(e);
+            }
+        };
+        gro.flush(&mut deliver);
+        result
+    }
+
+    /// Receives a whole packet, which may be spread across several [`RxBuffer`]s if
+    /// `VIRTIO_NET_F_MRG_RXBUF` was negotiated. If currently no data, returns an error with type
+    /// [`Error::NotReady`].
+    ///
+    /// Only the first buffer has a header; the packet is the concatenation of the
+    /// [`packet`](RxBuffer::packet)s of all of them. Each buffer must be given back with
+    /// [`recycle_rx_buffer`](Self::recycle_rx_buffer).
+    ///
+    /// The receive hook runs on the first buffer, which has the header and the start of the
+    /// packet. If it drops or recycles the packet, every buffer of it goes back to the receive
+    /// queue.
+    ///
+    /// If the device reports more buffers than it has completed, the buffers are recycled and
+    /// [`Error::IoError`] is returned.
+    pub fn receive_packet(&mut self) -> Result<Vec<RxBuffer>> {
+        loop {
+            let mut buffers = self.pop_packet()?;
+            let Some(hook) = &mut self.rx_hook else {
+                return Ok(buffers);
+            };
+            match hook(&mut buffers[0]) {
+                RxVerdict::Pass => return Ok(buffers),
+                RxVerdict::Drop => self.rx_hook_stats.dropped += 1,
+                RxVerdict::Recycle => self.rx_hook_stats.recycled += 1,
+            }
+            for rx_buf in buffers {
+                self.recycle_rx_buffer(rx_buf)?;
+            }
+        }
+    }
+
+    /// Takes all the completed receive buffers of the next packet.
+    fn pop_packet(&mut self) -> Result<Vec<RxBuffer>> {
+        let first = self.pop_rx_buffer(false)?;
+        let num_buffers = first.num_buffers();
+        let mut buffers = Vec::with_capacity(num_buffers.into());
+        buffers.push(first);
+        for _ in 1..num_buffers {
+            match self.pop_rx_buffer(true) {
+                Ok(rx_buf) => buffers.push(rx_buf),
+                Err(e) => {
+                    warn!(
+                        "Packet spread across {} buffers but only {} received: {:?}",
+                        num_buffers,
+                        buffers.len(),
+                        e
+                    );
+                    for rx_buf in buffers {
+                        self.recycle_rx_buffer(rx_buf)?;
+                    }
+                    return Err(Error::IoError);
+                }
+            }
+        }
+        Ok(buffers)
+    }
+
+    /// Takes the next completed receive buffer, which has no header if it is a `continuation` of
+    /// a packet spread across several buffers.
+    fn pop_rx_buffer(&mut self, continuation: bool) -> Result<RxBuffer> {
         if let Some(token) = self.inner.poll_receive() {
             let mut rx_buf = self.rx_buffers[token as usize]
                 .take()
@@ -86,8 +504,15 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
 
             // Safe because `token` == `rx_buf.idx`, we are passing the same
             // buffer as we passed to `VirtQueue::add` and it is still valid.
-            let (_hdr_len, pkt_len) =
-                unsafe { self.inner.receive_complete(token, rx_buf.as_bytes_mut())? };
+            let (hdr_len, pkt_len) = if continuation {
+                (0, unsafe {
+                    self.inner
+                        .receive_complete_continuation(token, rx_buf.as_bytes_mut())?
+                })
+            } else {
+                unsafe { self.inner.receive_complete(token, rx_buf.as_bytes_mut())? }
+            };
+            rx_buf.set_header_len(hdr_len);
             rx_buf.set_packet_len(pkt_len);
             Ok(rx_buf)
         } else {
@@ -122,4 +547,112 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONet<H, T, QUEUE_SIZE>
     pub fn send(&mut self, tx_buf: TxBuffer) -> Result {
         self.inner.send(tx_buf.packet())
     }
+
+    /// Sends a [`TxBuffer`] to the network preceded by the given header, e.g. to offload its
+    /// checksum, and blocks until the request completed.
+    ///
+    /// See [`VirtIONetRaw::send_with_header`].
+    pub fn send_with_header(&mut self, header: &VirtioNetHdr, tx_buf: TxBuffer) -> Result {
+        self.inner.send_with_header(header, tx_buf.packet())
+    }
+
+    /// Starts sending a packet directly from the caller's buffer, without copying it into a
+    /// [`TxBuffer`] or waiting for it to complete.
+    ///
+    /// See [`VirtIONetRaw::send_borrowed`].
+    ///
+    /// # Safety
+    ///
+    /// See [`VirtIONetRaw::send_borrowed`].
+    pub unsafe fn send_borrowed<'a>(&mut self, packet: &'a [u8]) -> Result<TxToken<'a>> {
+        // Safe because the caller upholds the same contract.
+        unsafe { self.inner.send_borrowed(packet) }
+    }
+
+    /// Completes a transmission started by [`send_borrowed`](Self::send_borrowed), or gives the
+    /// token back if the device hasn't finished with it yet.
+    ///
+    /// See [`VirtIONetRaw::reap_borrowed`].
+    pub fn reap_borrowed<'a>(
+        &mut self,
+        tx_token: TxToken<'a>,
+    ) -> core::result::Result<&'a [u8], TxToken<'a>> {
+        self.inner.reap_borrowed(tx_token)
+    }
+
+    /// Completes a transmission started by [`send_borrowed`](Self::send_borrowed) and returns when
+    /// it was reclaimed, or gives the token back if the device hasn't finished with it yet.
+    ///
+    /// See [`VirtIONetRaw::reap_borrowed_timestamped`].
+    pub fn reap_borrowed_timestamped<'a>(
+        &mut self,
+        tx_token: TxToken<'a>,
+    ) -> core::result::Result<(&'a [u8], TxCompletion), TxToken<'a>> {
+        self.inner.reap_borrowed_timestamped(tx_token)
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtioDevice for VirtIONet<H, T, QUEUE_SIZE> {
+    /// Acknowledges any pending interrupt, counting it in the
+    /// [`interrupt_stats`](VirtIONet::interrupt_stats) only if the device had raised one.
+    ///
+    /// Received packets stay in the receive queue until the caller takes them with
+    /// [`receive`](VirtIONet::receive), as only the caller knows where they should go.
+    ///
+    /// Returns 1 if a received packet is waiting, or 0 otherwise.
+    fn poll(&mut self) -> Result<usize> {
+        self.inner.ack_raised_interrupt();
+        self.handle_interrupt()
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> SharedIrqDevice
+    for VirtIONet<H, T, QUEUE_SIZE>
+{
+    /// Acknowledges the interrupt if the device raised it, counting it in the
+    /// [`interrupt_stats`](VirtIONet::interrupt_stats).
+    ///
+    /// An interrupt raised by another device on the line isn't counted as spurious for this one.
+    fn ack_interrupt(&mut self) -> bool {
+        self.inner.ack_raised_interrupt()
+    }
+
+    /// Received packets stay in the receive queue until the caller takes them with
+    /// [`receive`](VirtIONet::receive).
+    ///
+    /// Returns 1 if a received packet is waiting, or 0 otherwise.
+    fn handle_interrupt(&mut self) -> Result<usize> {
+        Ok(self.can_recv().into())
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> FailoverMember for VirtIONet<H, T, QUEUE_SIZE> {
+    fn is_usable(&self) -> bool {
+        self.link_up()
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Tunables for VirtIONet<H, T, QUEUE_SIZE> {
+    fn tunables(&self) -> &'static [Tunable] {
+        &[
+            tunable::INTERRUPTS_ENABLED_TUNABLE,
+            tunable::SPURIOUS_INTERRUPT_THRESHOLD_TUNABLE,
+            tunable::QUEUE_SIZE_TUNABLE,
+            RX_BUFFERS_TUNABLE,
+        ]
+    }
+
+    fn tunable(&self, name: &str) -> Result<TunableValue> {
+        match name {
+            RX_BUFFERS => Ok(TunableValue::U32(self.rx_buffer_total as u32)),
+            _ => self.inner.tunable(name),
+        }
+    }
+
+    fn set_tunable(&mut self, name: &str, value: TunableValue) -> Result {
+        match name {
+            RX_BUFFERS => Err(Error::Unsupported),
+            _ => self.inner.set_tunable(name, value),
+        }
+    }
 }
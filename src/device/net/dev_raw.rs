@@ -1,10 +1,28 @@
-use super::{Config, EthernetAddress, Features, VirtioNetHdr};
-use super::{MIN_BUFFER_LEN, NET_HDR_SIZE, QUEUE_RECEIVE, QUEUE_TRANSMIT, SUPPORTED_FEATURES};
-use crate::hal::Hal;
-use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::volread;
+use super::rss::{RssLimits, RSS_COMMAND_MAX_LEN};
+use super::{
+    CtrlAck, CtrlClass, CtrlHeader, EthernetAddress, Features, GuestOffloads, NetConfigChanges,
+    NetConfigSnapshot, RxFeatures, Status, VirtioNetHdr, CTRL_GUEST_OFFLOADS_SET,
+    CTRL_MAC_ADDR_SET, CTRL_MQ_RSS_CONFIG, CTRL_MQ_VQ_PAIRS_SET, CTRL_QUEUE_SIZE,
+};
+use super::{RssHashTypes, RssKey, RssMapping, VirtioNetConfig, RSS_MAX_INDIRECTION_TABLE_LEN};
+use super::{
+    MIN_BUFFER_LEN, NET_HDR_MRG_SIZE, NET_HDR_SIZE, QUEUE_RECEIVE, QUEUE_TRANSMIT,
+    SUPPORTED_FEATURES,
+};
+use crate::config::ConfigDiff;
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::diagnostics::SlowPathThresholds;
+use crate::failover::FailoverMember;
+use crate::hal::{self, AllocFailurePolicy, BufferDirection, Dma, Hal, MemoryLocality};
+use crate::interrupt::{InterruptAccounting, InterruptStats, LostInterruptWatchdog};
+use crate::queue::{InFlightLimit, VirtQueue, VirtQueueLayout};
+use crate::registry::DriverId;
+use crate::transport::{DeviceType, Transport};
+use crate::tunable::{self, Tunable, TunableValue, Tunables};
 use crate::{Error, Result};
+#[cfg(feature = "alloc")]
+use alloc::vec::Vec;
+use core::{mem::offset_of, time::Duration};
 use log::{debug, info, warn};
 use zerocopy::AsBytes;
 
@@ -17,65 +35,462 @@ use zerocopy::AsBytes;
 ///
 /// [`VirtIONet`]: super::VirtIONet
 pub struct VirtIONetRaw<H: Hal, T: Transport, const QUEUE_SIZE: usize> {
+    id: DriverId,
     transport: T,
+    config_snapshot: NetConfigSnapshot,
     mac: EthernetAddress,
+    negotiated_features: Features,
+    /// The length of the header which precedes each packet, which depends on the negotiated
+    /// features.
+    header_len: usize,
+    /// The device's virtqueues, of which the driver uses the first queue pair and the control
+    /// queue.
+    queues: QueueLayout,
     recv_queue: VirtQueue<H, QUEUE_SIZE>,
     send_queue: VirtQueue<H, QUEUE_SIZE>,
+    /// The queue pairs after the first, if more were asked for with `new_multiqueue`.
+    #[cfg(feature = "alloc")]
+    extra_pairs: Vec<QueuePair<H, QUEUE_SIZE>>,
+    /// The number of queue pairs which the device has been told to use.
+    active_queue_pairs: u16,
+    /// The control queue, if `VIRTIO_NET_F_CTRL_VQ` was negotiated.
+    ctrl_queue: Option<VirtQueue<H, CTRL_QUEUE_SIZE>>,
+    /// The receive side scaling capabilities, if `VIRTIO_NET_F_RSS` was negotiated.
+    rss: Option<RssLimits>,
+    interrupts: InterruptAccounting,
+    /// An empty header shared by all packets sent with `send_borrowed`, allocated on first use.
+    tx_header: Option<Dma<H>>,
+    /// Whether notifications are being deferred until `flush_notifications` is called.
+    defer_notify: bool,
+    /// The queues which have had buffers added while notifications were deferred, indexed by queue
+    /// number.
+    pending_notify: [bool; 2],
+}
+
+/// The receive and transmit queues of a queue pair other than the first.
+#[cfg(feature = "alloc")]
+struct QueuePair<H: Hal, const QUEUE_SIZE: usize> {
+    recv_queue: VirtQueue<H, QUEUE_SIZE>,
+    send_queue: VirtQueue<H, QUEUE_SIZE>,
+    /// Whether buffers have been added to the receive and transmit queue respectively while
+    /// notifications were deferred.
+    pending_notify: [bool; 2],
+}
+
+/// A packet which is being transmitted from a buffer borrowed from the caller, returned by
+/// [`VirtIONetRaw::send_borrowed`].
+///
+/// The buffer stays borrowed until the token is passed back to
+/// [`VirtIONetRaw::reap_borrowed`] after the device has finished with it. Dropping the token doesn't
+/// stop the device from reading the buffer, so see the safety requirements of
+/// [`VirtIONetRaw::send_borrowed`].
+#[must_use = "the transmission must be reaped with `reap_borrowed`"]
+#[derive(Debug)]
+pub struct TxToken<'a> {
+    token: u16,
+    packet: &'a [u8],
+}
+
+impl TxToken<'_> {
+    /// Returns the token identifying the transmission in the queue, as returned by
+    /// [`VirtIONetRaw::poll_transmit`].
+    pub fn token(&self) -> u16 {
+        self.token
+    }
+}
+
+/// A transmission which the device has finished with, as returned by
+/// [`VirtIONetRaw::transmit_complete_timestamped`] and
+/// [`VirtIONetRaw::reap_borrowed_timestamped`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct TxCompletion {
+    /// The token identifying the transmission in the queue.
+    pub token: u16,
+    /// The time at which the driver reclaimed the buffer from the device, from
+    /// [`Hal::timestamp`], or `None` if the HAL has no time source.
+    ///
+    /// Network stacks can use this to estimate when the packet left, e.g. for pacing.
+    pub timestamp: Option<Duration>,
 }
 
 impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZE> {
     /// Create a new VirtIO-Net driver.
-    pub fn new(mut transport: T) -> Result<Self> {
-        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
-        info!("negotiated_features {:?}", negotiated_features);
+    pub fn new(transport: T) -> Result<Self> {
+        Self::init(transport, 1, AllocFailurePolicy::Fail, RxFeatures::empty())
+    }
+
+    /// Creates a new VirtIO-Net driver which also negotiates the given receive features, if the
+    /// device offers them.
+    ///
+    /// Without them the device always passes whole packets with complete checksums, which is
+    /// what [`receive_complete`](Self::receive_complete) callers expect unless they handle the
+    /// features. Whether each was negotiated can be checked with
+    /// [`guest_checksum`](Self::guest_checksum) and
+    /// [`mergeable_rx_buffers`](Self::mergeable_rx_buffers).
+    pub fn new_with_rx_features(transport: T, rx_features: RxFeatures) -> Result<Self> {
+        Self::init(transport, 1, AllocFailurePolicy::Fail, rx_features)
+    }
+
+    /// Create a new VirtIO-Net driver, handling failure to allocate the virtqueues according to
+    /// the given policy.
+    ///
+    /// With [`AllocFailurePolicy::Degrade`], queues which can't be allocated with `QUEUE_SIZE`
+    /// entries are set up with fewer, which can be checked with
+    /// [`queue_sizes`](Self::queue_sizes).
+    pub fn new_with_policy(transport: T, policy: AllocFailurePolicy) -> Result<Self> {
+        Self::init(transport, 1, policy, RxFeatures::empty())
+    }
+
+    /// Creates a new VirtIO-Net driver which sets up to `queue_pairs` transmit and receive queue
+    /// pairs, if the device supports multiqueue.
+    ///
+    /// All the pairs' queues are made ready up front, but the device only uses the first until
+    /// more are enabled with [`set_active_queue_pairs`](Self::set_active_queue_pairs). The pairs
+    /// after the first are used with the `_on` methods, such as
+    /// [`transmit_begin_on`](Self::transmit_begin_on).
+    /// Without the `alloc` feature only the first pair is set up.
+    pub fn new_multiqueue(transport: T, queue_pairs: u16) -> Result<Self> {
+        Self::init(
+            transport,
+            queue_pairs,
+            AllocFailurePolicy::Fail,
+            RxFeatures::empty(),
+        )
+    }
+
+    /// Initialises the device, setting up as many of the given number of queue pairs as it has
+    /// and negotiating the given receive features along with the ones always supported.
+    pub(super) fn init(
+        mut transport: T,
+        queue_pairs: u16,
+        policy: AllocFailurePolicy,
+        rx_features: RxFeatures,
+    ) -> Result<Self> {
+        let negotiated_features = transport
+            .begin_init(SUPPORTED_FEATURES.union(Features::from_bits_truncate(rx_features.bits())));
+        info!("negotiated_features {}", negotiated_features);
         // read configuration space
-        let config = transport.config_space::<Config>()?;
-        let mac;
-        // Safe because config points to a valid MMIO region for the config space.
-        unsafe {
-            mac = volread!(config, mac);
-            debug!(
-                "Got MAC={:02x?}, status={:?}",
-                mac,
-                volread!(config, status)
-            );
-        }
-        let send_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_TRANSMIT,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
-        )?;
-        let recv_queue = VirtQueue::new(
-            &mut transport,
-            QUEUE_RECEIVE,
-            false,
-            negotiated_features.contains(Features::RING_EVENT_IDX),
+        let mac: EthernetAddress = transport.read_config(offset_of!(VirtioNetConfig, mac))?;
+        let status: u16 = transport.read_config(offset_of!(VirtioNetConfig, status))?;
+        debug!(
+            "Got MAC={:02x?}, status={:?}",
+            mac,
+            Status::from_bits_truncate(status)
+        );
+        let has_status = negotiated_features.contains(Features::STATUS);
+        let config_snapshot = read_snapshot(&transport, has_status)?;
+        // The control queue comes after all the queue pairs, so its index depends on how many
+        // there are.
+        let max_queue_pairs = if negotiated_features.intersects(Features::MQ | Features::RSS) {
+            transport.read_config(offset_of!(VirtioNetConfig, max_virtqueue_pairs))?
+        } else {
+            1
+        };
+        let queues = QueueLayout::new(
+            DeviceType::Network,
+            negotiated_features.bits(),
+            max_queue_pairs,
         )?;
+        queues.validate(&mut transport)?;
+        let new_queue = |transport: &mut T, index| {
+            VirtQueue::new_with_policy(
+                transport,
+                index,
+                negotiated_features.contains(Features::RING_INDIRECT_DESC),
+                negotiated_features.contains(Features::RING_EVENT_IDX),
+                MemoryLocality::Any,
+                policy,
+            )
+        };
+        let send_queue = new_queue(&mut transport, QUEUE_TRANSMIT)?;
+        let recv_queue = new_queue(&mut transport, QUEUE_RECEIVE)?;
+        #[cfg(feature = "alloc")]
+        let extra_pairs = (1..queue_pairs.min(queues.instances()))
+            .map(|pair| {
+                let mut pair_queue = |role| {
+                    let index = queues.checked_index(&mut transport, role, QUEUE_SIZE as u32)?;
+                    new_queue(&mut transport, index)
+                };
+                Ok(QueuePair {
+                    recv_queue: pair_queue(QueueRole::Receive(pair))?,
+                    send_queue: pair_queue(QueueRole::Transmit(pair))?,
+                    pending_notify: [false; 2],
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        #[cfg(not(feature = "alloc"))]
+        let _ = queue_pairs;
+        let ctrl_queue = if negotiated_features.contains(Features::CTRL_VQ) {
+            let index =
+                queues.checked_index(&mut transport, QueueRole::Control, CTRL_QUEUE_SIZE as u32)?;
+            Some(VirtQueue::new(
+                &mut transport,
+                index,
+                negotiated_features.contains(Features::RING_INDIRECT_DESC),
+                negotiated_features.contains(Features::RING_EVENT_IDX),
+            )?)
+        } else {
+            None
+        };
+        let rss = if negotiated_features.contains(Features::RSS) {
+            Some(RssLimits::read(&transport)?)
+        } else {
+            None
+        };
 
         transport.finish_init();
 
+        let header_len =
+            if negotiated_features.intersects(Features::MRG_RXBUF | Features::VERSION_1) {
+                NET_HDR_MRG_SIZE
+            } else {
+                NET_HDR_SIZE
+            };
         Ok(VirtIONetRaw {
+            id: DriverId::allocate(),
             transport,
+            config_snapshot,
             mac,
+            negotiated_features,
+            header_len,
+            queues,
             recv_queue,
             send_queue,
+            #[cfg(feature = "alloc")]
+            extra_pairs,
+            active_queue_pairs: 1,
+            ctrl_queue,
+            rss,
+            interrupts: InterruptAccounting::default(),
+            tx_header: None,
+            defer_notify: false,
+            pending_notify: [false; 2],
         })
     }
 
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
+    /// Defers notifying the device about buffers added to the transmit and receive queues until
+    /// [`flush_notifications`](Self::flush_notifications) is called.
+    ///
+    /// This lets a caller which services both queues in one pass, e.g. refilling the receive queue
+    /// and transmitting a batch of packets from an interrupt handler, notify the device once per
+    /// queue at the end rather than once per buffer, reducing VM exits.
+    pub fn defer_notifications(&mut self) {
+        self.defer_notify = true;
+    }
+
+    /// Notifies the device about all buffers added since
+    /// [`defer_notifications`](Self::defer_notifications) was called, with a single
+    /// [`Transport::notify_multi`] call, and goes back to notifying immediately.
+    pub fn flush_notifications(&mut self) {
+        self.defer_notify = false;
+        self.kick();
+    }
+
+    /// Notifies the device about any buffers added to the transmit and receive queues which it
+    /// hasn't been told about yet, e.g. by
+    /// [`transmit_begin_no_notify`](Self::transmit_begin_no_notify) or while notifications are
+    /// deferred, if it wants to be notified.
+    ///
+    /// Unlike [`flush_notifications`](Self::flush_notifications) this doesn't change whether
+    /// notifications are deferred, so callers with their own batching scheduler can decide exactly
+    /// when to ring the doorbell. Returns whether the device was notified.
+    pub fn kick(&mut self) -> bool {
+        let mut queues = [0; 2];
+        let mut count = 0;
+        for queue in [QUEUE_RECEIVE, QUEUE_TRANSMIT] {
+            if core::mem::take(&mut self.pending_notify[usize::from(queue)])
+                && self.queue_mut(queue).should_notify()
+            {
+                queues[count] = queue;
+                count += 1;
+            }
+        }
+        if count > 0 {
+            hal::notify_multi::<H>(&mut self.transport, &queues[..count]);
+        }
+        let notified_pairs = self.kick_extra_pairs();
+        count > 0 || notified_pairs
+    }
+
+    /// Notifies the device about the queue pairs after the first which have had buffers added
+    /// while notifications were deferred, individually. Returns whether any were notified.
+    #[cfg(feature = "alloc")]
+    fn kick_extra_pairs(&mut self) -> bool {
+        let mut notified = false;
+        for pair in 1..self.queue_pairs() {
+            for role in [QueueRole::Receive(pair), QueueRole::Transmit(pair)] {
+                if self.take_pending_pair(pair, role) {
+                    notified |= self.notify_pair(pair, role);
+                }
+            }
+        }
+        notified
+    }
+
+    #[cfg(not(feature = "alloc"))]
+    fn kick_extra_pairs(&mut self) -> bool {
+        false
+    }
+
+    /// Returns the transmit or receive queue with the given index.
+    fn queue_mut(&mut self, queue: u16) -> &mut VirtQueue<H, QUEUE_SIZE> {
+        if queue == QUEUE_TRANSMIT {
+            &mut self.send_queue
+        } else {
+            &mut self.recv_queue
+        }
+    }
+
+    /// Notifies the device that buffers were added to the given transmit or receive queue, or
+    /// records it for later if notifications are deferred.
+    fn kick_queue(&mut self, queue: u16) {
+        if self.defer_notify {
+            self.pending_notify[usize::from(queue)] = true;
+        } else {
+            // This notification covers any buffers added earlier without one.
+            self.pending_notify[usize::from(queue)] = false;
+            if self.queue_mut(queue).should_notify() {
+                hal::notify::<H>(&mut self.transport, queue);
+            }
+        }
+    }
+
+    /// Sends a command on the control queue and waits for the device to acknowledge it.
+    ///
+    /// Returns [`Error::Unsupported`] if the control queue wasn't negotiated, or
+    /// [`Error::IoError`] if the device rejected the command.
+    fn control_command(&mut self, class: CtrlClass, command: u8, data: &[u8]) -> Result {
+        let ctrl_queue = self.ctrl_queue.as_mut().ok_or(Error::Unsupported)?;
+        let header = CtrlHeader { class, command };
+        let mut ack = CtrlAck::ERR;
+        ctrl_queue.add_notify_wait_pop(
+            &[header.as_bytes(), data],
+            &mut [ack.as_bytes_mut()],
+            &mut self.transport,
+        )?;
+        if ack == CtrlAck::OK {
+            Ok(())
+        } else {
+            warn!("Control command {:?}/{} failed", class, command);
+            Err(Error::IoError)
+        }
+    }
+
     /// Acknowledge interrupt.
+    ///
+    /// This should be called from the interrupt handler before processing any completed buffers,
+    /// as an interrupt with no used buffers in either queue is counted as spurious. If a threshold
+    /// has been set with [`set_spurious_interrupt_threshold`](Self::set_spurious_interrupt_threshold)
+    /// and is exceeded then interrupts are disabled, and the caller should poll instead.
     pub fn ack_interrupt(&mut self) -> bool {
-        self.transport.ack_interrupt()
+        let acked = self.transport.ack_interrupt();
+        self.record_interrupt(acked);
+        acked
+    }
+
+    /// Acknowledges a pending interrupt, if any, but only counts it in the
+    /// [`interrupt_stats`](Self::interrupt_stats) if there was one.
+    ///
+    /// This is for drivers which poll the device whether or not an interrupt arrived, where an
+    /// interrupt which wasn't raised isn't spurious.
+    pub(crate) fn ack_raised_interrupt(&mut self) -> bool {
+        let acked = self.transport.ack_interrupt();
+        if acked {
+            self.record_interrupt(true);
+        }
+        acked
+    }
+
+    /// Counts an interrupt, which is spurious if it wasn't acknowledged or neither queue has used
+    /// buffers, and switches to polling if there have been too many spurious interrupts.
+    fn record_interrupt(&mut self, acked: bool) {
+        let spurious = !acked || !(self.send_queue.can_pop() || self.recv_queue.can_pop());
+        if self.interrupts.record(spurious) {
+            warn!("Too many spurious interrupts, switching to polling");
+            self.send_queue.set_dev_notify(false);
+            self.recv_queue.set_dev_notify(false);
+        }
+    }
+
+    /// Returns counts of the interrupts acknowledged so far.
+    pub fn interrupt_stats(&self) -> InterruptStats {
+        self.interrupts.stats()
+    }
+
+    /// Sets the number of consecutive spurious interrupts after which to disable interrupts and
+    /// switch to polling, or `None` (the default) to never switch.
+    pub fn set_spurious_interrupt_threshold(&mut self, threshold: Option<u32>) {
+        self.interrupts.set_threshold(threshold);
+    }
+
+    /// Returns whether interrupts were disabled because of too many spurious interrupts.
+    pub fn switched_to_polling(&self) -> bool {
+        self.interrupts.polling()
+    }
+
+    /// Sets when to suspect that an interrupt was lost, for
+    /// [`check_lost_interrupt`](Self::check_lost_interrupt). The watchdog is disabled by default.
+    pub fn set_lost_interrupt_watchdog(&mut self, watchdog: LostInterruptWatchdog) {
+        self.interrupts.set_watchdog(watchdog);
+    }
+
+    /// Checks whether the device has used buffers from either queue without its interrupt
+    /// arriving, once the [`LostInterruptWatchdog`] has expired.
+    ///
+    /// This should be called periodically, e.g. from a timer tick. If it returns true then a lost
+    /// interrupt has been logged and counted, and the caller should process the used buffers as if
+    /// the interrupt had arrived.
+    pub fn check_lost_interrupt(&mut self) -> bool {
+        let used = self.send_queue.can_pop() || self.recv_queue.can_pop();
+        let outstanding =
+            used || self.send_queue.in_flight() != 0 || self.recv_queue.in_flight() != 0;
+        if !self.interrupts.watchdog_check(outstanding, H::timestamp()) || !used {
+            return false;
+        }
+        warn!(
+            "{}: buffers used but no interrupt arrived; the interrupt may have been lost",
+            self.id
+        );
+        self.interrupts.record_lost();
+        true
+    }
+
+    /// Limits the number of transmissions which may be in flight at once, or removes the limit if
+    /// `limit` is `None` (the default).
+    ///
+    /// When the limit is reached, [`transmit_begin`](Self::transmit_begin) and
+    /// [`send_borrowed`](Self::send_borrowed) fail with [`Error::Throttled`], after first waiting
+    /// for a completion if its mode is [`ThrottleMode::Block`](crate::queue::ThrottleMode::Block). Returns [`Error::InvalidParam`] if the limit is zero.
+    pub fn set_in_flight_limit(&mut self, limit: Option<InFlightLimit>) -> Result {
+        self.send_queue.set_in_flight_limit(limit)
+    }
+
+    /// Sets the thresholds beyond which to log warnings about slow paths, such as the transmit
+    /// queue repeatedly being full. All are disabled by default.
+    pub fn set_slow_path_thresholds(&mut self, thresholds: SlowPathThresholds) {
+        self.send_queue.set_slow_path_thresholds(thresholds);
+        self.recv_queue.set_slow_path_thresholds(thresholds);
+        self.interrupts
+            .set_suppression_warning(thresholds.ignored_suppression);
     }
 
     /// Disable interrupts.
     pub fn disable_interrupts(&mut self) {
+        self.interrupts.set_disabled(true);
         self.send_queue.set_dev_notify(false);
         self.recv_queue.set_dev_notify(false);
     }
 
     /// Enable interrupts.
     pub fn enable_interrupts(&mut self) {
+        self.interrupts.reset_polling();
+        self.interrupts.set_disabled(false);
         self.send_queue.set_dev_notify(true);
         self.recv_queue.set_dev_notify(true);
     }
@@ -85,11 +500,418 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         self.mac
     }
 
+    /// Returns whether the link was up when the configuration was last read.
+    ///
+    /// If the device doesn't support `VIRTIO_NET_F_STATUS` then the link is assumed to always be
+    /// up.
+    pub fn link_up(&self) -> bool {
+        self.config_snapshot.link_up
+    }
+
+    /// Returns whether the device is a standby for a primary device with the same MAC address,
+    /// i.e. whether `VIRTIO_NET_F_STANDBY` was negotiated.
+    ///
+    /// Such a device is meant to be used as the backup of a
+    /// [`Failover`](crate::failover::Failover) pair.
+    pub fn is_standby(&self) -> bool {
+        self.negotiated_features.contains(Features::STANDBY)
+    }
+
+    /// Re-reads the parts of the device configuration which may change at runtime, such as the
+    /// link status, and returns what changed since they were last read.
+    ///
+    /// This should be called when the device raises a configuration change interrupt.
+    pub fn refresh_config(&mut self) -> Result<Option<NetConfigChanges>> {
+        let has_status = self.negotiated_features.contains(Features::STATUS);
+        let snapshot = read_snapshot(&self.transport, has_status)?;
+        let changes = snapshot.diff(&self.config_snapshot);
+        self.config_snapshot = snapshot;
+        if let Some(changes) = &changes {
+            info!("Network device configuration changed: {:?}", changes);
+        }
+        Ok(changes)
+    }
+
+    /// Changes the MAC address of the device.
+    ///
+    /// This uses the `VIRTIO_NET_CTRL_MAC_ADDR_SET` control command if `VIRTIO_NET_F_CTRL_MAC_ADDR`
+    /// was negotiated, or else writes the config space for legacy devices. Returns
+    /// [`Error::Unsupported`] if neither is possible.
+    pub fn set_mac(&mut self, mac: EthernetAddress) -> Result {
+        if self.negotiated_features.contains(Features::CTL_MAC_ADDR) {
+            self.control_command(CtrlClass::MAC, CTRL_MAC_ADDR_SET, &mac)?;
+        } else if !self.negotiated_features.contains(Features::VERSION_1) {
+            self.transport
+                .write_config(offset_of!(VirtioNetConfig, mac), mac)?;
+        } else {
+            return Err(Error::Unsupported);
+        }
+        self.mac = mac;
+        Ok(())
+    }
+
+    /// Enables the given receive offloads and disables all others.
+    ///
+    /// This is useful when forwarding or bridging packets, where offloads such as LRO must be
+    /// disabled. Returns [`Error::Unsupported`] if `VIRTIO_NET_F_CTRL_GUEST_OFFLOADS` wasn't
+    /// negotiated, or [`Error::InvalidParam`] if any of the offloads weren't negotiated.
+    pub fn set_guest_offloads(&mut self, offloads: GuestOffloads) -> Result {
+        if !self
+            .negotiated_features
+            .contains(Features::CTRL_GUEST_OFFLOADS)
+        {
+            return Err(Error::Unsupported);
+        }
+        if !self
+            .negotiated_features
+            .contains(Features::from_bits_retain(offloads.bits()))
+        {
+            return Err(Error::InvalidParam);
+        }
+        self.control_command(
+            CtrlClass::GUEST_OFFLOADS,
+            CTRL_GUEST_OFFLOADS_SET,
+            &offloads.bits().to_le_bytes(),
+        )
+    }
+
+    /// Returns the kinds of packets which the device can hash for receive side scaling, or `None`
+    /// if `VIRTIO_NET_F_RSS` wasn't negotiated.
+    pub fn rss_hash_types(&self) -> Option<RssHashTypes> {
+        self.rss.map(|rss| rss.supported_hash_types)
+    }
+
+    /// Programs the device's receive side scaling key, hash types and indirection table.
+    ///
+    /// This also tells the device to use the mapping's number of queue pairs, as
+    /// [`set_active_queue_pairs`](Self::set_active_queue_pairs) would.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_NET_F_RSS` wasn't negotiated, or
+    /// [`Error::InvalidParam`] if the mapping uses a longer key or indirection table or hash types
+    /// which the device doesn't support, or more queue pairs than the driver uses.
+    pub fn set_rss(&mut self, mapping: &RssMapping) -> Result {
+        let rss = self.rss.ok_or(Error::Unsupported)?;
+        if mapping.key().as_bytes().len() > rss.max_key_size
+            || mapping.indirection_table().len() > rss.max_indirection_table_len
+            || !rss.supported_hash_types.contains(mapping.hash_types())
+            || mapping.queue_pairs() > self.queue_pairs()
+        {
+            return Err(Error::InvalidParam);
+        }
+        let mut command = [0; RSS_COMMAND_MAX_LEN];
+        let len = mapping.write_command(&mut command);
+        self.control_command(CtrlClass::MQ, CTRL_MQ_RSS_CONFIG, &command[..len])?;
+        // The command also tells the device how many queue pairs to use.
+        self.active_queue_pairs = mapping.queue_pairs();
+        Ok(())
+    }
+
+    /// Generates a symmetric RSS key from 16 bits of entropy, e.g. from an RNG device, and programs
+    /// the device to spread packets of the given kinds over all receive queues with it.
+    ///
+    /// The key and indirection table are as long as the device supports. The returned mapping
+    /// computes the same receive queue as the device for each flow, so the network stack can
+    /// process flows on the CPU which services their queue. As the key is symmetric, both
+    /// directions of a flow map to the same queue. See [`RssKey::symmetric`] and
+    /// [`set_rss`](Self::set_rss) for errors.
+    pub fn enable_symmetric_rss(
+        &mut self,
+        entropy: u16,
+        hash_types: RssHashTypes,
+    ) -> Result<RssMapping> {
+        let rss = self.rss.ok_or(Error::Unsupported)?;
+        let key = RssKey::symmetric(entropy, rss.max_key_size.min(super::RSS_MAX_KEY_SIZE))?;
+        let max_table_len = rss
+            .max_indirection_table_len
+            .clamp(1, RSS_MAX_INDIRECTION_TABLE_LEN);
+        // Round down to a power of two.
+        let table_len = 1 << (usize::BITS - 1 - max_table_len.leading_zeros());
+        let mapping = RssMapping::new(key, hash_types, self.queue_pairs(), table_len)?;
+        self.set_rss(&mapping)?;
+        Ok(mapping)
+    }
+
+    /// Returns the number of transmit and receive queue pairs which the driver has set up.
+    pub fn queue_pairs(&self) -> u16 {
+        #[cfg(feature = "alloc")]
+        return 1 + self.extra_pairs.len() as u16;
+        #[cfg(not(feature = "alloc"))]
+        1
+    }
+
+    /// Returns the number of queue pairs which the device is currently using.
+    pub fn active_queue_pairs(&self) -> u16 {
+        self.active_queue_pairs
+    }
+
+    /// Tells the device to use only the first `queue_pairs` queue pairs, e.g. to shrink the number
+    /// of active queues under low load and expand it again later without reinitialising the
+    /// device.
+    ///
+    /// The device stops receiving on the pairs beyond, and they mustn't be transmitted on, but
+    /// their queues stay set up so any receive buffers already added to them are used once they
+    /// are enabled again.
+    ///
+    /// Returns [`Error::Unsupported`] if `VIRTIO_NET_F_MQ` wasn't negotiated, or
+    /// [`Error::InvalidParam`] if `queue_pairs` is zero or more than the driver has set up.
+    pub fn set_active_queue_pairs(&mut self, queue_pairs: u16) -> Result {
+        if !self.negotiated_features.contains(Features::MQ) {
+            return Err(Error::Unsupported);
+        }
+        if queue_pairs == 0 || queue_pairs > self.queue_pairs() {
+            return Err(Error::InvalidParam);
+        }
+        self.control_command(
+            CtrlClass::MQ,
+            CTRL_MQ_VQ_PAIRS_SET,
+            &queue_pairs.to_le_bytes(),
+        )?;
+        self.active_queue_pairs = queue_pairs;
+        Ok(())
+    }
+
+    /// Returns the physical layout of the queue with the given index and the current positions in
+    /// its rings, or `None` if the driver hasn't set up such a queue.
+    pub fn queue_layout(&self, queue: u16) -> Option<VirtQueueLayout> {
+        match self.queues.role(queue)? {
+            QueueRole::Receive(pair) => Some(self.pair_queue(pair, false).ok()?.export_layout()),
+            QueueRole::Transmit(pair) => Some(self.pair_queue(pair, true).ok()?.export_layout()),
+            QueueRole::Control => Some(self.ctrl_queue.as_ref()?.export_layout()),
+            _ => None,
+        }
+    }
+
+    /// Returns the receive or transmit queue of the given queue pair.
+    fn pair_queue(&self, pair: u16, transmit: bool) -> Result<&VirtQueue<H, QUEUE_SIZE>> {
+        match (pair, transmit) {
+            (0, false) => Ok(&self.recv_queue),
+            (0, true) => Ok(&self.send_queue),
+            #[cfg(feature = "alloc")]
+            _ => {
+                let queue_pair = self
+                    .extra_pairs
+                    .get(usize::from(pair) - 1)
+                    .ok_or(Error::InvalidParam)?;
+                Ok(if transmit {
+                    &queue_pair.send_queue
+                } else {
+                    &queue_pair.recv_queue
+                })
+            }
+            #[cfg(not(feature = "alloc"))]
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Returns the receive or transmit queue of the given queue pair, mutably.
+    fn pair_queue_mut(
+        &mut self,
+        pair: u16,
+        transmit: bool,
+    ) -> Result<&mut VirtQueue<H, QUEUE_SIZE>> {
+        match (pair, transmit) {
+            (0, false) => Ok(&mut self.recv_queue),
+            (0, true) => Ok(&mut self.send_queue),
+            #[cfg(feature = "alloc")]
+            _ => {
+                let queue_pair = self
+                    .extra_pairs
+                    .get_mut(usize::from(pair) - 1)
+                    .ok_or(Error::InvalidParam)?;
+                Ok(if transmit {
+                    &mut queue_pair.send_queue
+                } else {
+                    &mut queue_pair.recv_queue
+                })
+            }
+            #[cfg(not(feature = "alloc"))]
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    /// Notifies the device that buffers were added to the given queue of a queue pair after the
+    /// first, or records it for later if notifications are deferred.
+    fn kick_pair(&mut self, pair: u16, role: QueueRole) {
+        if self.defer_notify {
+            #[cfg(feature = "alloc")]
+            if let Some(pending) = self.pending_pair_mut(pair, role) {
+                *pending = true;
+            }
+        } else {
+            // This notification covers any buffers added earlier without one.
+            #[cfg(feature = "alloc")]
+            self.take_pending_pair(pair, role);
+            self.notify_pair(pair, role);
+        }
+    }
+
+    /// Returns whether buffers were added to the given queue of a queue pair after the first while
+    /// notifications were deferred, and clears the flag.
+    #[cfg(feature = "alloc")]
+    fn take_pending_pair(&mut self, pair: u16, role: QueueRole) -> bool {
+        self.pending_pair_mut(pair, role)
+            .is_some_and(core::mem::take)
+    }
+
+    /// Returns the flag recording whether buffers were added to the given queue of a queue pair
+    /// after the first while notifications were deferred.
+    #[cfg(feature = "alloc")]
+    fn pending_pair_mut(&mut self, pair: u16, role: QueueRole) -> Option<&mut bool> {
+        let transmit = matches!(role, QueueRole::Transmit(_));
+        let queue_pair = self
+            .extra_pairs
+            .get_mut(usize::from(pair).checked_sub(1)?)?;
+        Some(&mut queue_pair.pending_notify[usize::from(transmit)])
+    }
+
+    /// Notifies the device about the given queue of a queue pair after the first, if it wants to
+    /// be. Returns whether it was notified.
+    fn notify_pair(&mut self, pair: u16, role: QueueRole) -> bool {
+        let transmit = matches!(role, QueueRole::Transmit(_));
+        let should_notify = self
+            .pair_queue_mut(pair, transmit)
+            .is_ok_and(|queue| queue.should_notify());
+        if let (true, Some(index)) = (should_notify, self.queues.index(role)) {
+            hal::notify::<H>(&mut self.transport, index);
+            return true;
+        }
+        false
+    }
+
+    /// Like [`transmit_begin`](Self::transmit_begin), but on the given queue pair.
+    ///
+    /// Returns [`Error::NotReady`] if the pair isn't active, or [`Error::InvalidParam`] if the
+    /// driver hasn't set it up.
+    ///
+    /// # Safety
+    ///
+    /// As for [`transmit_begin`](Self::transmit_begin).
+    pub unsafe fn transmit_begin_on(&mut self, pair: u16, tx_buf: &[u8]) -> Result<u16> {
+        if pair == 0 {
+            return self.transmit_begin(tx_buf);
+        }
+        if pair >= self.active_queue_pairs {
+            return Err(Error::NotReady);
+        }
+        self.check_tx_buf_len(tx_buf)?;
+        let token = self.pair_queue_mut(pair, true)?.add(&[tx_buf], &mut [])?;
+        self.kick_pair(pair, QueueRole::Transmit(pair));
+        Ok(token)
+    }
+
+    /// Like [`poll_transmit`](Self::poll_transmit), but on the given queue pair.
+    pub fn poll_transmit_on(&self, pair: u16) -> Option<u16> {
+        self.pair_queue(pair, true).ok()?.peek_used()
+    }
+
+    /// Like [`transmit_complete`](Self::transmit_complete), but on the given queue pair.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed in again as was passed to
+    /// [`transmit_begin_on`](Self::transmit_begin_on) for the same pair when it returned the
+    /// token.
+    pub unsafe fn transmit_complete_on(
+        &mut self,
+        pair: u16,
+        token: u16,
+        tx_buf: &[u8],
+    ) -> Result<usize> {
+        let len = self
+            .pair_queue_mut(pair, true)?
+            .pop_used(token, &[tx_buf], &mut [])?;
+        Ok(len as usize)
+    }
+
+    /// Like [`receive_begin`](Self::receive_begin), but on the given queue pair.
+    ///
+    /// Buffers may be added to a pair which isn't active, so that it is ready to receive as soon
+    /// as it is enabled. Returns [`Error::InvalidParam`] if the driver hasn't set the pair up.
+    ///
+    /// # Safety
+    ///
+    /// As for [`receive_begin`](Self::receive_begin).
+    pub unsafe fn receive_begin_on(&mut self, pair: u16, rx_buf: &mut [u8]) -> Result<u16> {
+        if pair == 0 {
+            return self.receive_begin(rx_buf);
+        }
+        Self::check_rx_buf_len(rx_buf)?;
+        let token = self.pair_queue_mut(pair, false)?.add(&[], &mut [rx_buf])?;
+        self.kick_pair(pair, QueueRole::Receive(pair));
+        Ok(token)
+    }
+
+    /// Like [`poll_receive`](Self::poll_receive), but on the given queue pair.
+    pub fn poll_receive_on(&self, pair: u16) -> Option<u16> {
+        self.pair_queue(pair, false).ok()?.peek_used()
+    }
+
+    /// Like [`receive_complete`](Self::receive_complete), but on the given queue pair.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed in again as was passed to
+    /// [`receive_begin_on`](Self::receive_begin_on) for the same pair when it returned the token.
+    pub unsafe fn receive_complete_on(
+        &mut self,
+        pair: u16,
+        token: u16,
+        rx_buf: &mut [u8],
+    ) -> Result<(usize, usize)> {
+        let len = self
+            .pair_queue_mut(pair, false)?
+            .pop_used(token, &[], &mut [rx_buf])? as usize;
+        let packet_len = len.checked_sub(self.header_len).ok_or(Error::IoError)?;
+        Ok((self.header_len, packet_len))
+    }
+
+    /// Returns the number of entries in the first pair's receive and transmit queues.
+    ///
+    /// These are both `QUEUE_SIZE` unless the driver was created with
+    /// [`AllocFailurePolicy::Degrade`] and ran out of memory.
+    pub fn queue_sizes(&self) -> (u16, u16) {
+        (self.recv_queue.size(), self.send_queue.size())
+    }
+
     /// Whether can send packet.
     pub fn can_send(&self) -> bool {
         self.send_queue.available_desc() >= 2
     }
 
+    /// Returns the length of the header which precedes each packet in the transmit and receive
+    /// buffers.
+    ///
+    /// This is the size of [`VirtioNetHdr`], plus 2 bytes for the `num_buffers` field if
+    /// `VIRTIO_NET_F_MRG_RXBUF` or `VIRTIO_F_VERSION_1` was negotiated.
+    pub fn header_len(&self) -> usize {
+        self.header_len
+    }
+
+    /// Returns whether the device can complete partial checksums of transmitted packets, i.e.
+    /// whether `VIRTIO_NET_F_CSUM` was negotiated, so that
+    /// [`VirtioNetHdr::partial_checksum`] may be used.
+    pub fn checksum_offload(&self) -> bool {
+        self.negotiated_features.contains(Features::CSUM)
+    }
+
+    /// Returns whether the device may leave partial checksums in received packets or mark them
+    /// as already validated, i.e. whether `VIRTIO_NET_F_GUEST_CSUM` was negotiated.
+    ///
+    /// This is only negotiated if [`RxFeatures::GUEST_CSUM`] was asked for.
+    pub fn guest_checksum(&self) -> bool {
+        self.negotiated_features.contains(Features::GUEST_CSUM)
+    }
+
+    /// Returns whether the device may spread a received packet across several receive buffers,
+    /// i.e. whether `VIRTIO_NET_F_MRG_RXBUF` was negotiated.
+    ///
+    /// If so, the header in the first buffer says how many buffers the packet uses, and the rest
+    /// contain only packet data. This is only negotiated if [`RxFeatures::MRG_RXBUF`] was asked
+    /// for.
+    pub fn mergeable_rx_buffers(&self) -> bool {
+        self.negotiated_features.contains(Features::MRG_RXBUF)
+    }
+
     /// Whether the length of the receive buffer is valid.
     fn check_rx_buf_len(rx_buf: &[u8]) -> Result<()> {
         if rx_buf.len() < MIN_BUFFER_LEN {
@@ -101,8 +923,8 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     }
 
     /// Whether the length of the transmit buffer is valid.
-    fn check_tx_buf_len(tx_buf: &[u8]) -> Result<()> {
-        if tx_buf.len() < NET_HDR_SIZE {
+    fn check_tx_buf_len(&self, tx_buf: &[u8]) -> Result<()> {
+        if tx_buf.len() < self.header_len {
             warn!("Transmit buffer len {} is too small", tx_buf.len());
             Err(Error::InvalidParam)
         } else {
@@ -114,12 +936,36 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     ///
     /// If the `buffer` is not large enough, it returns [`Error::InvalidParam`].
     pub fn fill_buffer_header(&self, buffer: &mut [u8]) -> Result<usize> {
-        if buffer.len() < NET_HDR_SIZE {
-            return Err(Error::InvalidParam);
+        self.fill_buffer_header_with(buffer, &VirtioNetHdr::default())
+    }
+
+    /// Fill the header of the `buffer` with the given [`VirtioNetHdr`], e.g. one from
+    /// [`VirtioNetHdr::partial_checksum`], and returns the length of the header.
+    ///
+    /// If the `buffer` is not large enough, it returns [`Error::InvalidParam`]. If the header asks
+    /// for an offload which wasn't negotiated, it returns [`Error::Unsupported`].
+    pub fn fill_buffer_header_with(
+        &self,
+        buffer: &mut [u8],
+        header: &VirtioNetHdr,
+    ) -> Result<usize> {
+        self.check_tx_header(header)?;
+        let header_buf = buffer
+            .get_mut(..self.header_len)
+            .ok_or(Error::InvalidParam)?;
+        header_buf.fill(0);
+        header_buf[..NET_HDR_SIZE].copy_from_slice(header.as_bytes());
+        Ok(self.header_len)
+    }
+
+    /// Returns [`Error::Unsupported`] if the header asks for an offload which wasn't negotiated.
+    fn check_tx_header(&self, header: &VirtioNetHdr) -> Result {
+        if header.needs_checksum() && !self.checksum_offload() {
+            warn!("Checksum offload requested but VIRTIO_NET_F_CSUM wasn't negotiated");
+            Err(Error::Unsupported)
+        } else {
+            Ok(())
         }
-        let header = VirtioNetHdr::default();
-        buffer[..NET_HDR_SIZE].copy_from_slice(header.as_bytes());
-        Ok(NET_HDR_SIZE)
     }
 
     /// Submits a request to transmit a buffer immediately without waiting for
@@ -147,14 +993,121 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     /// [`poll_transmit`]: Self::poll_transmit
     /// [`transmit_complete`]: Self::transmit_complete
     pub unsafe fn transmit_begin(&mut self, tx_buf: &[u8]) -> Result<u16> {
-        Self::check_tx_buf_len(tx_buf)?;
+        self.check_tx_buf_len(tx_buf)?;
+        self.send_queue.throttle(&mut self.transport)?;
         let token = self.send_queue.add(&[tx_buf], &mut [])?;
-        if self.send_queue.should_notify() {
-            self.transport.notify(QUEUE_TRANSMIT);
-        }
+        self.kick_queue(QUEUE_TRANSMIT);
+        Ok(token)
+    }
+
+    /// Like [`transmit_begin`](Self::transmit_begin), but doesn't notify the device about the
+    /// buffer, so that several can be published with one notification from a later call to
+    /// [`kick`](Self::kick).
+    ///
+    /// # Safety
+    ///
+    /// See [`transmit_begin`](Self::transmit_begin).
+    pub unsafe fn transmit_begin_no_notify(&mut self, tx_buf: &[u8]) -> Result<u16> {
+        self.check_tx_buf_len(tx_buf)?;
+        self.send_queue.throttle(&mut self.transport)?;
+        let token = self.send_queue.add(&[tx_buf], &mut [])?;
+        self.pending_notify[usize::from(QUEUE_TRANSMIT)] = true;
         Ok(token)
     }
 
+    /// Submits a packet for transmission directly from the caller's buffer, without copying it or
+    /// waiting for the transmission to complete.
+    ///
+    /// Unlike [`transmit_begin`](Self::transmit_begin), the buffer should contain just the packet;
+    /// the driver supplies the header. The returned [`TxToken`] keeps `packet` borrowed until it is
+    /// passed to [`reap_borrowed`](Self::reap_borrowed), once [`poll_transmit`](Self::poll_transmit)
+    /// returns its token. If there are not enough descriptors available it returns
+    /// [`Error::QueueFull`].
+    ///
+    /// # Safety
+    ///
+    /// As with [`transmit_begin`](Self::transmit_begin), the device may read `packet` until the
+    /// transmission completes. The token's borrow only protects it while the token exists, so the
+    /// caller must not drop or forget the token before it has been reaped; if it does anyway,
+    /// `packet` must stay valid and unmodified until the device has used the buffer or been reset.
+    pub unsafe fn send_borrowed<'a>(&mut self, packet: &'a [u8]) -> Result<TxToken<'a>> {
+        if self.tx_header.is_none() {
+            // The DMA region is zeroed, which is what we want for the header.
+            self.tx_header = Some(Dma::new(1, BufferDirection::DriverToDevice)?);
+        }
+        self.send_queue.throttle(&mut self.transport)?;
+        let inputs =
+            Self::borrowed_inputs(self.tx_header.as_ref().unwrap(), self.header_len, packet);
+        // Safe because the header lives as long as the queue, and the caller promises that the
+        // packet stays valid until the device has finished with it.
+        let token = unsafe { self.send_queue.add(&inputs[..], &mut [])? };
+        self.kick_queue(QUEUE_TRANSMIT);
+        Ok(TxToken { token, packet })
+    }
+
+    /// Completes a transmission started by [`send_borrowed`](Self::send_borrowed) and releases the
+    /// borrowed buffer, returning it.
+    ///
+    /// If the device hasn't finished with the packet yet, i.e. [`poll_transmit`](Self::poll_transmit)
+    /// doesn't return the token's [`token`](TxToken::token), the token is returned back as an error
+    /// so that the caller can try again later.
+    pub fn reap_borrowed<'a>(
+        &mut self,
+        tx_token: TxToken<'a>,
+    ) -> core::result::Result<&'a [u8], TxToken<'a>> {
+        if self.poll_transmit() != Some(tx_token.token) {
+            return Err(tx_token);
+        }
+        let inputs = Self::borrowed_inputs(
+            self.tx_header.as_ref().unwrap(),
+            self.header_len,
+            tx_token.packet,
+        );
+        // Safe because these are the same buffers as were passed to `add` in `send_borrowed`.
+        match unsafe {
+            self.send_queue
+                .pop_used(tx_token.token, &inputs[..], &mut [])
+        } {
+            Ok(_) => Ok(tx_token.packet),
+            Err(_) => Err(tx_token),
+        }
+    }
+
+    /// Completes a transmission started by [`send_borrowed`](Self::send_borrowed) like
+    /// [`reap_borrowed`](Self::reap_borrowed), and also returns when it was reclaimed.
+    pub fn reap_borrowed_timestamped<'a>(
+        &mut self,
+        tx_token: TxToken<'a>,
+    ) -> core::result::Result<(&'a [u8], TxCompletion), TxToken<'a>> {
+        let token = tx_token.token;
+        let packet = self.reap_borrowed(tx_token)?;
+        Ok((
+            packet,
+            TxCompletion {
+                token,
+                timestamp: H::timestamp(),
+            },
+        ))
+    }
+
+    /// Returns the buffers to add to the transmit queue for a packet sent with `send_borrowed`.
+    fn borrowed_inputs<'a>(
+        tx_header: &'a Dma<H>,
+        header_len: usize,
+        packet: &'a [u8],
+    ) -> [&'a [u8]; 2] {
+        // Safe because the DMA region is only ever read, and lives as long as the borrow.
+        let header = unsafe { &tx_header.raw_slice().as_ref()[..header_len] };
+        // Avoid adding an empty buffer to the virtqueue for an empty packet, by splitting the header
+        // instead.
+        if packet.is_empty() {
+            let (first, second) = header.split_at(1);
+            [first, second]
+        } else {
+            [header, packet]
+        }
+    }
+
     /// Fetches the token of the next completed transmission request from the
     /// used ring and returns it, without removing it from the used ring. If
     /// there are no pending completed requests it returns [`None`].
@@ -176,6 +1129,28 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         Ok(len as usize)
     }
 
+    /// Completes a transmission operation which was started by [`transmit_begin`] like
+    /// [`transmit_complete`], and returns when it was reclaimed.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed in again as was passed to
+    /// [`transmit_begin`] when it returned the token.
+    ///
+    /// [`transmit_begin`]: Self::transmit_begin
+    /// [`transmit_complete`]: Self::transmit_complete
+    pub unsafe fn transmit_complete_timestamped(
+        &mut self,
+        token: u16,
+        tx_buf: &[u8],
+    ) -> Result<TxCompletion> {
+        self.send_queue.pop_used(token, &[tx_buf], &mut [])?;
+        Ok(TxCompletion {
+            token,
+            timestamp: H::timestamp(),
+        })
+    }
+
     /// Submits a request to receive a buffer immediately without waiting for
     /// the reception to complete.
     ///
@@ -201,9 +1176,21 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     pub unsafe fn receive_begin(&mut self, rx_buf: &mut [u8]) -> Result<u16> {
         Self::check_rx_buf_len(rx_buf)?;
         let token = self.recv_queue.add(&[], &mut [rx_buf])?;
-        if self.recv_queue.should_notify() {
-            self.transport.notify(QUEUE_RECEIVE);
-        }
+        self.kick_queue(QUEUE_RECEIVE);
+        Ok(token)
+    }
+
+    /// Like [`receive_begin`](Self::receive_begin), but doesn't notify the device about the
+    /// buffer, so that several can be published with one notification from a later call to
+    /// [`kick`](Self::kick).
+    ///
+    /// # Safety
+    ///
+    /// See [`receive_begin`](Self::receive_begin).
+    pub unsafe fn receive_begin_no_notify(&mut self, rx_buf: &mut [u8]) -> Result<u16> {
+        Self::check_rx_buf_len(rx_buf)?;
+        let token = self.recv_queue.add(&[], &mut [rx_buf])?;
+        self.pending_notify[usize::from(QUEUE_RECEIVE)] = true;
         Ok(token)
     }
 
@@ -232,27 +1219,51 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
         rx_buf: &mut [u8],
     ) -> Result<(usize, usize)> {
         let len = self.recv_queue.pop_used(token, &[], &mut [rx_buf])? as usize;
-        let packet_len = len.checked_sub(NET_HDR_SIZE).ok_or(Error::IoError)?;
-        Ok((NET_HDR_SIZE, packet_len))
+        let packet_len = len.checked_sub(self.header_len).ok_or(Error::IoError)?;
+        Ok((self.header_len, packet_len))
+    }
+
+    /// Completes a reception like [`receive_complete`](Self::receive_complete), for a buffer
+    /// which continues a packet spread across several buffers with `VIRTIO_NET_F_MRG_RXBUF`.
+    ///
+    /// Such a buffer has no header, so it returns just the length of the packet data in it.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed in again as was passed to
+    /// [`receive_begin`](Self::receive_begin) when it returned the token.
+    pub unsafe fn receive_complete_continuation(
+        &mut self,
+        token: u16,
+        rx_buf: &mut [u8],
+    ) -> Result<usize> {
+        Ok(self.recv_queue.pop_used(token, &[], &mut [rx_buf])? as usize)
     }
 
     /// Sends a packet to the network, and blocks until the request completed.
     pub fn send(&mut self, tx_buf: &[u8]) -> Result {
-        let header = VirtioNetHdr::default();
+        self.send_with_header(&VirtioNetHdr::default(), tx_buf)
+    }
+
+    /// Sends a packet to the network preceded by the given header, e.g. one from
+    /// [`VirtioNetHdr::partial_checksum`] to have the device complete its checksum, and blocks
+    /// until the request completed.
+    ///
+    /// If the header asks for an offload which wasn't negotiated, it returns
+    /// [`Error::Unsupported`].
+    pub fn send_with_header(&mut self, header: &VirtioNetHdr, tx_buf: &[u8]) -> Result {
+        self.check_tx_header(header)?;
+        let mut header_buf = [0; NET_HDR_MRG_SIZE];
+        header_buf[..NET_HDR_SIZE].copy_from_slice(header.as_bytes());
+        let header = &header_buf[..self.header_len];
         if tx_buf.is_empty() {
             // Special case sending an empty packet, to avoid adding an empty buffer to the
             // virtqueue.
-            self.send_queue.add_notify_wait_pop(
-                &[header.as_bytes()],
-                &mut [],
-                &mut self.transport,
-            )?;
+            self.send_queue
+                .add_notify_wait_pop(&[header], &mut [], &mut self.transport)?;
         } else {
-            self.send_queue.add_notify_wait_pop(
-                &[header.as_bytes(), tx_buf],
-                &mut [],
-                &mut self.transport,
-            )?;
+            self.send_queue
+                .add_notify_wait_pop(&[header, tx_buf], &mut [], &mut self.transport)?;
         }
         Ok(())
     }
@@ -271,11 +1282,1117 @@ impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> VirtIONetRaw<H, T, QUEUE_SIZ
     }
 }
 
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> FailoverMember
+    for VirtIONetRaw<H, T, QUEUE_SIZE>
+{
+    fn is_usable(&self) -> bool {
+        self.link_up()
+    }
+}
+
+impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Tunables for VirtIONetRaw<H, T, QUEUE_SIZE> {
+    fn tunables(&self) -> &'static [Tunable] {
+        &[
+            tunable::INTERRUPTS_ENABLED_TUNABLE,
+            tunable::SPURIOUS_INTERRUPT_THRESHOLD_TUNABLE,
+            tunable::QUEUE_SIZE_TUNABLE,
+        ]
+    }
+
+    fn tunable(&self, name: &str) -> Result<TunableValue> {
+        match name {
+            tunable::INTERRUPTS_ENABLED => Ok(TunableValue::Bool(self.interrupts.enabled())),
+            tunable::SPURIOUS_INTERRUPT_THRESHOLD => {
+                Ok(TunableValue::OptionalU32(self.interrupts.threshold()))
+            }
+            tunable::QUEUE_SIZE => Ok(TunableValue::U32(self.recv_queue.size().into())),
+            _ => Err(Error::InvalidParam),
+        }
+    }
+
+    fn set_tunable(&mut self, name: &str, value: TunableValue) -> Result {
+        match name {
+            tunable::INTERRUPTS_ENABLED => {
+                if tunable::expect_bool(value)? {
+                    self.enable_interrupts();
+                } else {
+                    self.disable_interrupts();
+                }
+            }
+            tunable::SPURIOUS_INTERRUPT_THRESHOLD => {
+                self.set_spurious_interrupt_threshold(tunable::expect_optional_u32(value)?);
+            }
+            _ => return Err(tunable::set_error(self.tunables(), name)),
+        }
+        Ok(())
+    }
+}
+
 impl<H: Hal, T: Transport, const QUEUE_SIZE: usize> Drop for VirtIONetRaw<H, T, QUEUE_SIZE> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
         self.transport.queue_unset(QUEUE_RECEIVE);
         self.transport.queue_unset(QUEUE_TRANSMIT);
+        #[cfg(feature = "alloc")]
+        for pair in 1..self.queue_pairs() {
+            for role in [QueueRole::Receive(pair), QueueRole::Transmit(pair)] {
+                if let Some(index) = self.queues.index(role) {
+                    self.transport.queue_unset(index);
+                }
+            }
+        }
+        if self.ctrl_queue.is_some() {
+            if let Some(index) = self.queues.index(QueueRole::Control) {
+                self.transport.queue_unset(index);
+            }
+        }
+    }
+}
+
+/// Reads the fields of the network device configuration which may change at runtime.
+fn read_snapshot<T: Transport>(transport: &T, has_status: bool) -> Result<NetConfigSnapshot> {
+    let link_up = if has_status {
+        let status: u16 = transport.read_config(offset_of!(VirtioNetConfig, status))?;
+        Status::from_bits_truncate(status).contains(Status::LINK_UP)
+    } else {
+        true
+    };
+    Ok(NetConfigSnapshot { link_up })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::net::Status,
+        hal::fake::{FakeHal, FakeHalHooks, HookedHal},
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+        PAGE_SIZE,
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{ptr::NonNull, sync::atomic::Ordering};
+    use std::{sync::Mutex, thread};
+
+    /// The index of the control queue, with a single queue pair.
+    const QUEUE_CONTROL: u16 = 2;
+
+    /// The fields of [`VirtioNetConfig`] before `speed`, which is as much configuration space as
+    /// devices without `VIRTIO_NET_F_SPEED_DUPLEX` or `VIRTIO_NET_F_RSS` need to expose.
+    #[repr(C)]
+    struct Config {
+        mac: EthernetAddress,
+        status: u16,
+        max_virtqueue_pairs: u16,
+        mtu: u16,
+    }
+
+    fn make_config() -> Config {
+        Config {
+            mac: [0x02, 0, 0, 0, 0, 0x01],
+            status: 0,
+            max_virtqueue_pairs: 1,
+            mtu: 1500,
+        }
+    }
+
+    #[test]
+    fn set_mac_ctrl() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: (Features::MAC
+                | Features::CTRL_VQ
+                | Features::CTL_MAC_ADDR
+                | Features::VERSION_1)
+                .bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+
+        // Start a thread to simulate the device handling the control command.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CTRL_QUEUE_SIZE>(QUEUE_CONTROL, |request| {
+                    assert_eq!(request, [1, 1, 0x02, 0x11, 0x22, 0x33, 0x44, 0x55]);
+                    CtrlAck::OK.as_bytes().to_vec()
+                });
+        });
+
+        net.set_mac([0x02, 0x11, 0x22, 0x33, 0x44, 0x55]).unwrap();
+        assert_eq!(net.mac_address(), [0x02, 0x11, 0x22, 0x33, 0x44, 0x55]);
+
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn indirect_descriptors() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: (Features::MAC | Features::RING_INDIRECT_DESC).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+
+        assert_eq!(
+            state.lock().unwrap().driver_features,
+            (Features::MAC | Features::RING_INDIRECT_DESC).bits()
+        );
+        let transmit = net.queue_layout(QUEUE_TRANSMIT).unwrap();
+        assert!(transmit
+            .flags
+            .contains(crate::queue::QueueLayoutFlags::INDIRECT));
+    }
+
+    #[test]
+    fn link_status_change() {
+        let mut config_space = make_config();
+        let config_space_ptr = NonNull::from(&mut config_space);
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: (Features::MAC | Features::STATUS).bits(),
+            config_space: config_space_ptr,
+            state: state.clone(),
+        };
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+        assert!(!net.link_up());
+        assert_eq!(net.refresh_config(), Ok(None));
+
+        // Safe because nothing else is accessing the config space at the moment.
+        unsafe {
+            (*config_space_ptr.as_ptr()).status = Status::LINK_UP.bits();
+        }
+        state.lock().unwrap().config_generation += 1;
+        assert_eq!(
+            net.refresh_config(),
+            Ok(Some(NetConfigChanges {
+                link_up: Some(true)
+            }))
+        );
+        assert!(net.link_up());
+    }
+
+    #[test]
+    fn deferred_notifications() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+        let notified = |queue: u16| {
+            state.lock().unwrap().queues[usize::from(queue)]
+                .notified
+                .swap(false, Ordering::SeqCst)
+        };
+
+        let tx_buf = [0; NET_HDR_SIZE + 4];
+        let mut rx_buf = [0; MIN_BUFFER_LEN];
+        net.defer_notifications();
+        // Safe because the buffers outlive the driver and aren't otherwise accessed.
+        unsafe {
+            net.transmit_begin(&tx_buf).unwrap();
+            net.transmit_begin(&tx_buf).unwrap();
+            net.receive_begin(&mut rx_buf).unwrap();
+        }
+        assert!(!notified(QUEUE_TRANSMIT));
+        assert!(!notified(QUEUE_RECEIVE));
+
+        net.flush_notifications();
+        assert!(notified(QUEUE_TRANSMIT));
+        assert!(notified(QUEUE_RECEIVE));
+
+        // Nothing more to flush, and later buffers are notified immediately again.
+        net.flush_notifications();
+        assert!(!notified(QUEUE_TRANSMIT));
+        unsafe {
+            net.transmit_begin(&tx_buf).unwrap();
+        }
+        assert!(notified(QUEUE_TRANSMIT));
+    }
+
+    #[test]
+    fn no_notify_then_kick() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+        let notified = |queue: u16| {
+            state.lock().unwrap().queues[usize::from(queue)]
+                .notified
+                .swap(false, Ordering::SeqCst)
+        };
+
+        let tx_buf = [0; NET_HDR_SIZE + 4];
+        let mut rx_buf = [0; MIN_BUFFER_LEN];
+        // Safe because the buffers outlive the driver and aren't otherwise accessed.
+        unsafe {
+            net.transmit_begin_no_notify(&tx_buf).unwrap();
+            net.receive_begin_no_notify(&mut rx_buf).unwrap();
+        }
+        assert!(!notified(QUEUE_TRANSMIT));
+        assert!(!notified(QUEUE_RECEIVE));
+
+        assert!(net.kick());
+        assert!(notified(QUEUE_TRANSMIT));
+        assert!(notified(QUEUE_RECEIVE));
+
+        // Nothing is left to notify about.
+        assert!(!net.kick());
+    }
+
+    #[test]
+    fn set_mac_legacy_config() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state,
+        };
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+
+        net.set_mac([0x02, 0x11, 0x22, 0x33, 0x44, 0x55]).unwrap();
+        assert_eq!(net.mac_address(), [0x02, 0x11, 0x22, 0x33, 0x44, 0x55]);
+        drop(net);
+        assert_eq!(config_space.mac, [0x02, 0x11, 0x22, 0x33, 0x44, 0x55]);
+    }
+
+    #[test]
+    fn set_guest_offloads() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: (Features::MAC | Features::CTRL_VQ | Features::CTRL_GUEST_OFFLOADS)
+                .bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+
+        // The driver didn't negotiate any receive offloads, so can't enable them.
+        assert_eq!(
+            net.set_guest_offloads(GuestOffloads::TSO4),
+            Err(Error::InvalidParam)
+        );
+
+        // Start a thread to simulate the device handling the control command.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CTRL_QUEUE_SIZE>(QUEUE_CONTROL, |request| {
+                    assert_eq!(request, [5, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+                    CtrlAck::OK.as_bytes().to_vec()
+                });
+        });
+
+        net.set_guest_offloads(GuestOffloads::empty()).unwrap();
+
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn enable_symmetric_rss() {
+        let mut config_space = VirtioNetConfig {
+            mac: [0x02, 0, 0, 0, 0, 0x01],
+            // A single queue pair, so the control queue is at index 2.
+            max_virtqueue_pairs: 1,
+            rss_max_key_size: 40,
+            rss_max_indirection_table_length: 100,
+            supported_hash_types: (RssHashTypes::IPV4 | RssHashTypes::TCPV4).bits(),
+            ..Default::default()
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: (Features::MAC | Features::CTRL_VQ | Features::RSS).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net =
+            VirtIONetRaw::<FakeHal, FakeTransport<VirtioNetConfig>, 16>::new(transport).unwrap();
+        assert_eq!(
+            net.rss_hash_types(),
+            Some(RssHashTypes::IPV4 | RssHashTypes::TCPV4)
+        );
+        assert_eq!(
+            net.enable_symmetric_rss(0x1234, RssHashTypes::UDPV4),
+            Err(Error::InvalidParam)
+        );
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CTRL_QUEUE_SIZE>(QUEUE_CONTROL, |request| {
+                    // The class and command, hash types, a mask for a 64 entry table and the
+                    // unclassified queue.
+                    assert_eq!(request[..10], [4, 1, 0x02, 0, 0, 0, 63, 0, 0, 0]);
+                    let table_end = 10 + 2 * 64;
+                    assert!(request[10..table_end].iter().all(|&byte| byte == 0));
+                    // One transmit queue, then the key.
+                    assert_eq!(request[table_end..table_end + 3], [1, 0, 40]);
+                    assert_eq!(request[table_end + 3..table_end + 5], [0x12, 0x34]);
+                    assert_eq!(request.len(), table_end + 3 + 40);
+                    CtrlAck::OK.as_bytes().to_vec()
+                });
+        });
+
+        let mapping = net
+            .enable_symmetric_rss(0x1234, RssHashTypes::TCPV4)
+            .unwrap();
+        assert_eq!(mapping.indirection_table().len(), 64);
+        assert_eq!(mapping.key().as_bytes().len(), 40);
+        assert_eq!(
+            mapping.queue_for_ipv4([10, 0, 0, 1], [10, 0, 0, 2], Some((1234, 80))),
+            0
+        );
+        assert_eq!(net.active_queue_pairs(), mapping.queue_pairs());
+
+        handle.join().unwrap();
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn active_queue_pairs() {
+        let mut config_space = make_config();
+        config_space.max_virtqueue_pairs = 2;
+        let state = Arc::new(Mutex::new(State {
+            queues: (0..5).map(|_| QueueStatus::default()).collect(),
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: (Features::MAC | Features::CTRL_VQ | Features::MQ).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        // Only as many pairs as the device has are set up.
+        let mut net =
+            VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new_multiqueue(transport, 4)
+                .unwrap();
+        assert_eq!(net.queue_pairs(), 2);
+        assert_eq!(net.active_queue_pairs(), 1);
+        assert_eq!(
+            net.queue_layout(3).map(|layout| layout.queue_index),
+            Some(3)
+        );
+        assert_eq!(
+            net.queue_layout(4).map(|layout| layout.size),
+            Some(CTRL_QUEUE_SIZE as u16)
+        );
+        assert_eq!(net.queue_layout(5), None);
+
+        let tx_buf = [0; NET_HDR_SIZE + 4];
+        assert_eq!(
+            unsafe { net.transmit_begin_on(1, &tx_buf) },
+            Err(Error::NotReady)
+        );
+        assert_eq!(net.set_active_queue_pairs(3), Err(Error::InvalidParam));
+
+        // The control queue comes after both pairs.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, 4);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CTRL_QUEUE_SIZE>(4, |request| {
+                    assert_eq!(request, [4, 0, 2, 0]);
+                    CtrlAck::OK.as_bytes().to_vec()
+                });
+            state
+        });
+        net.set_active_queue_pairs(2).unwrap();
+        let state = handle.join().unwrap();
+        assert_eq!(net.active_queue_pairs(), 2);
+
+        let token = unsafe { net.transmit_begin_on(1, &tx_buf) }.unwrap();
+        assert!(state.lock().unwrap().queues[3]
+            .notified
+            .swap(false, Ordering::SeqCst));
+        state.lock().unwrap().read_write_queue::<16>(3, |packet| {
+            assert_eq!(packet.len(), tx_buf.len());
+            vec![]
+        });
+        assert_eq!(net.poll_transmit(), None);
+        assert_eq!(net.poll_transmit_on(1), Some(token));
+        assert_eq!(
+            unsafe { net.transmit_complete_on(1, token, &tx_buf) },
+            Ok(0)
+        );
+
+        // While notifications are deferred, the pair is only notified when they are flushed.
+        net.defer_notifications();
+        unsafe { net.transmit_begin_on(1, &tx_buf) }.unwrap();
+        assert!(!state.lock().unwrap().queues[3]
+            .notified
+            .load(Ordering::SeqCst));
+        net.flush_notifications();
+        assert!(state.lock().unwrap().queues[3]
+            .notified
+            .swap(false, Ordering::SeqCst));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn rx_buffers_out_of_memory() {
+        use crate::{device::net::VirtIONet, hal::AllocFailurePolicy};
+        use core::mem::size_of;
+
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state,
+        };
+
+        // Even when degrading, at least one receive buffer is needed.
+        let result = VirtIONet::<FakeHal, FakeTransport<Config>, 16>::new_with_policy(
+            transport,
+            usize::MAX,
+            AllocFailurePolicy::Degrade,
+        );
+        assert_eq!(
+            result.err(),
+            Some(Error::OutOfMemory {
+                requested: usize::MAX / size_of::<usize>() * size_of::<usize>(),
+            })
+        );
+    }
+
+    /// Allows DMA allocations of at most one page.
+    #[derive(Debug)]
+    struct OnePage;
+
+    impl FakeHalHooks for OnePage {
+        const MAX_DMA_PAGES: usize = 1;
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn queues_degrade() {
+        use crate::{device::net::VirtIONet, hal::AllocFailurePolicy};
+
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 256,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // The descriptors and available ring of a 256 entry queue need two pages, so it is halved.
+        let mut net = VirtIONet::<HookedHal<OnePage>, FakeTransport<Config>, 256>::new_with_policy(
+            transport,
+            2048,
+            AllocFailurePolicy::Degrade,
+        )
+        .unwrap();
+        assert_eq!(net.rx_buffer_count(), 128);
+        assert_eq!(net.tunable(tunable::QUEUE_SIZE), Ok(TunableValue::U32(128)));
+
+        let mut frame = vec![0; NET_HDR_SIZE];
+        frame.extend_from_slice(&[0x42; 60]);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<128>(QUEUE_RECEIVE, &frame);
+        let rx_buf = net.receive().unwrap();
+        assert_eq!(rx_buf.packet(), &[0x42; 60]);
+        net.recycle_rx_buffer(rx_buf).unwrap();
+    }
+
+    #[test]
+    fn queues_fail() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 256,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state,
+        };
+
+        assert_eq!(
+            VirtIONetRaw::<HookedHal<OnePage>, FakeTransport<Config>, 256>::new(transport).err(),
+            Some(Error::OutOfDmaMemory {
+                requested: 2 * PAGE_SIZE,
+                align: PAGE_SIZE,
+            })
+        );
+    }
+
+    #[test]
+    fn send_borrowed_token_dropped() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        // The packet must outlive the driver, as the token is dropped before the device has read it.
+        let packet = [0x42; 20];
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+
+        let tx_token = unsafe { net.send_borrowed(&packet) }.unwrap();
+        let token = tx_token.token();
+        drop(tx_token);
+
+        // Dropping the token doesn't take the packet back from the device.
+        let sent = state.lock().unwrap().read_from_queue::<16>(QUEUE_TRANSMIT);
+        assert_eq!(sent[NET_HDR_SIZE..], packet);
+        assert_eq!(net.poll_transmit(), Some(token));
+    }
+
+    #[test]
+    fn send_borrowed() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+
+        let packet = [0x42; 20];
+        let tx_token = unsafe { net.send_borrowed(&packet) }.unwrap();
+        // The device hasn't processed it yet, so it can't be reaped.
+        let tx_token = net.reap_borrowed(tx_token).unwrap_err();
+
+        let sent = state.lock().unwrap().read_from_queue::<16>(QUEUE_TRANSMIT);
+        assert_eq!(sent[..NET_HDR_SIZE], [0; NET_HDR_SIZE]);
+        assert_eq!(sent[NET_HDR_SIZE..], packet);
+
+        assert_eq!(net.poll_transmit(), Some(tx_token.token()));
+        assert_eq!(net.reap_borrowed(tx_token).unwrap(), &packet);
+        assert_eq!(net.poll_transmit(), None);
+    }
+
+    #[test]
+    fn checksum_offload() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: (Features::MAC | Features::CSUM | Features::VERSION_1).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+        assert!(net.checksum_offload());
+        assert!(!net.mergeable_rx_buffers());
+        assert_eq!(net.header_len(), NET_HDR_MRG_SIZE);
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_TRANSMIT);
+            state.lock().unwrap().read_from_queue::<16>(QUEUE_TRANSMIT)
+        });
+        let packet = [0x42; 20];
+        net.send_with_header(&VirtioNetHdr::partial_checksum(14, 16), &packet)
+            .unwrap();
+        let sent = handle.join().unwrap();
+        assert_eq!(
+            sent[..NET_HDR_MRG_SIZE],
+            [1, 0, 0, 0, 0, 0, 14, 0, 16, 0, 0, 0]
+        );
+        assert_eq!(sent[NET_HDR_MRG_SIZE..], packet);
+    }
+
+    #[test]
+    fn checksum_offload_unsupported() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state,
+        };
+        let net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+        assert!(!net.checksum_offload());
+        assert_eq!(net.header_len(), NET_HDR_SIZE);
+
+        let mut tx_buf = [0xff; NET_HDR_SIZE + 4];
+        assert_eq!(
+            net.fill_buffer_header_with(&mut tx_buf, &VirtioNetHdr::partial_checksum(0, 2)),
+            Err(Error::Unsupported)
+        );
+        assert_eq!(net.fill_buffer_header(&mut tx_buf), Ok(NET_HDR_SIZE));
+        assert_eq!(tx_buf[..NET_HDR_SIZE], [0; NET_HDR_SIZE]);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn mergeable_rx_buffers() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: (Features::MAC | Features::MRG_RXBUF | Features::GUEST_CSUM).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net =
+            crate::device::net::VirtIONet::<FakeHal, FakeTransport<Config>, 16>::new_with_rx_features(
+                transport,
+                2048,
+                RxFeatures::GUEST_CSUM | RxFeatures::MRG_RXBUF,
+            )
+            .unwrap();
+        assert!(net.mergeable_rx_buffers());
+        assert!(net.guest_checksum());
+
+        // A packet spread across two buffers, with num_buffers = 2 and DATA_VALID set.
+        let mut first = vec![2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0];
+        first.extend_from_slice(&[0x11; 100]);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<16>(QUEUE_RECEIVE, &first);
+        assert_eq!(net.receive_packet().err(), Some(Error::IoError));
+        // The first buffer was recycled rather than returned.
+        assert_eq!(net.receive_packet().err(), Some(Error::NotReady));
+
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<16>(QUEUE_RECEIVE, &first);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<16>(QUEUE_RECEIVE, &[0x22; 5]);
+        let buffers = net.receive_packet().unwrap();
+        assert_eq!(buffers.len(), 2);
+        assert_eq!(buffers[0].num_buffers(), 2);
+        assert!(buffers[0].header().data_valid());
+        assert_eq!(buffers[0].packet(), &[0x11; 100]);
+        assert_eq!(buffers[1].header_len(), 0);
+        assert_eq!(buffers[1].packet(), &[0x22; 5]);
+        for rx_buf in buffers {
+            net.recycle_rx_buffer(rx_buf).unwrap();
+        }
+        assert_eq!(net.receive_packet().err(), Some(Error::NotReady));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn mergeable_rx_buffers_hook_drop() {
+        use crate::device::net::{RxHookStats, RxVerdict};
+
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: (Features::MAC | Features::MRG_RXBUF).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net =
+            crate::device::net::VirtIONet::<FakeHal, FakeTransport<Config>, 16>::new_with_rx_features(
+                transport,
+                2048,
+                RxFeatures::MRG_RXBUF,
+            )
+            .unwrap();
+        // Drop packets starting with 0x11, and pass everything else.
+        net.set_rx_hook(Some(Box::new(|rx_buf| match rx_buf.packet()[0] {
+            0x11 => RxVerdict::Drop,
+            _ => RxVerdict::Pass,
+        })));
+
+        let mut dropped = vec![2, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0];
+        dropped.extend_from_slice(&[0x11; 100]);
+        // More packets than there are receive buffers, so this only works if both buffers of each
+        // dropped packet go back to the queue.
+        for _ in 0..16 {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<16>(QUEUE_RECEIVE, &dropped);
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<16>(QUEUE_RECEIVE, &[0x22; 5]);
+            assert_eq!(net.receive_packet().err(), Some(Error::NotReady));
+        }
+        assert_eq!(
+            net.rx_hook_stats(),
+            RxHookStats {
+                dropped: 16,
+                recycled: 0,
+            }
+        );
+
+        // A packet the hook passes is returned whole.
+        let mut passed = vec![0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 2, 0];
+        passed.extend_from_slice(&[0x33; 10]);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<16>(QUEUE_RECEIVE, &passed);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<16>(QUEUE_RECEIVE, &[0x22; 5]);
+        let buffers = net.receive_packet().unwrap();
+        assert_eq!(buffers.len(), 2);
+        assert_eq!(buffers[0].packet(), &[0x33; 10]);
+        assert_eq!(buffers[1].packet(), &[0x22; 5]);
+        assert_eq!(net.rx_hook_stats().dropped, 16);
+    }
+
+    #[test]
+    fn rx_features_opt_in() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let device_features = Features::MAC | Features::MRG_RXBUF | Features::GUEST_CSUM;
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: device_features.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+
+        // The device offers both, but they aren't negotiated unless asked for.
+        let net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+        assert!(!net.mergeable_rx_buffers());
+        assert!(!net.guest_checksum());
+        assert_eq!(net.header_len(), NET_HDR_SIZE);
+        assert_eq!(state.lock().unwrap().driver_features, Features::MAC.bits());
+        drop(net);
+
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: device_features.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new_with_rx_features(
+            transport,
+            RxFeatures::MRG_RXBUF,
+        )
+        .unwrap();
+        assert!(net.mergeable_rx_buffers());
+        assert!(!net.guest_checksum());
+        assert_eq!(net.header_len(), NET_HDR_MRG_SIZE);
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn poll_device() {
+        use crate::interrupt::VirtioDevice;
+
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net = crate::device::net::VirtIONet::<FakeHal, FakeTransport<Config>, 16>::new(
+            transport, 2048,
+        )
+        .unwrap();
+
+        // Polling with no interrupt raised doesn't count a spurious interrupt.
+        assert_eq!(net.poll(), Ok(0));
+        assert_eq!(net.interrupt_stats(), InterruptStats::default());
+
+        let mut packet = vec![0; NET_HDR_SIZE];
+        packet.extend_from_slice(&[0x33; 10]);
+        {
+            let mut state = state.lock().unwrap();
+            state.write_to_queue::<16>(QUEUE_RECEIVE, &packet);
+            state.interrupt_pending = true;
+        }
+        assert_eq!(net.poll(), Ok(1));
+        assert_eq!(
+            net.interrupt_stats(),
+            InterruptStats {
+                total: 1,
+                ..Default::default()
+            }
+        );
+        // The packet is left for the caller to receive.
+        assert_eq!(net.receive().unwrap().packet(), &[0x33; 10]);
+        assert_eq!(net.poll(), Ok(0));
+    }
+
+    #[cfg(feature = "alloc")]
+    #[test]
+    fn shared_irq() {
+        use crate::device::net::VirtIONet;
+        use crate::interrupt::{dispatch_shared_irq, SharedIrqSummary};
+
+        let mut config_spaces = [make_config(), make_config()];
+        let states = [
+            Arc::new(Mutex::new(State {
+                queues: vec![QueueStatus::default(), QueueStatus::default()],
+                ..Default::default()
+            })),
+            Arc::new(Mutex::new(State {
+                queues: vec![QueueStatus::default(), QueueStatus::default()],
+                ..Default::default()
+            })),
+        ];
+        let [first_config, second_config] = &mut config_spaces;
+        let mut first = VirtIONet::<FakeHal, FakeTransport<Config>, 16>::new(
+            FakeTransport {
+                device_type: DeviceType::Network,
+                max_queue_size: 16,
+                device_features: Features::MAC.bits(),
+                config_space: NonNull::from(first_config),
+                state: states[0].clone(),
+            },
+            2048,
+        )
+        .unwrap();
+        let mut second = VirtIONet::<FakeHal, FakeTransport<Config>, 16>::new(
+            FakeTransport {
+                device_type: DeviceType::Network,
+                max_queue_size: 16,
+                device_features: Features::MAC.bits(),
+                config_space: NonNull::from(second_config),
+                state: states[1].clone(),
+            },
+            2048,
+        )
+        .unwrap();
+
+        // Nobody on the line asserted the interrupt.
+        assert!(!dispatch_shared_irq(&mut [&mut first, &mut second]).handled());
+
+        // Only the second device received a packet and raised the interrupt.
+        let mut packet = vec![0; NET_HDR_SIZE];
+        packet.extend_from_slice(&[0x44; 10]);
+        {
+            let mut state = states[1].lock().unwrap();
+            state.write_to_queue::<16>(QUEUE_RECEIVE, &packet);
+            state.interrupt_pending = true;
+        }
+        assert_eq!(
+            dispatch_shared_irq(&mut [&mut first, &mut second]),
+            SharedIrqSummary {
+                asserted: 1,
+                completions: 1,
+                errors: 0,
+            }
+        );
+        assert!(!states[1].lock().unwrap().interrupt_pending);
+        // The device which didn't raise the interrupt doesn't count it as spurious.
+        assert_eq!(first.interrupt_stats(), InterruptStats::default());
+        assert_eq!(
+            second.interrupt_stats(),
+            InterruptStats {
+                total: 1,
+                ..Default::default()
+            }
+        );
+        assert_eq!(second.receive().unwrap().packet(), &[0x44; 10]);
+    }
+
+    #[test]
+    fn modern_header_len() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: (Features::MAC | Features::VERSION_1).bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+        assert_eq!(net.header_len(), NET_HDR_MRG_SIZE);
+
+        // Sent packets get the 12 byte header with `num_buffers`.
+        let packet = [0x42; 20];
+        let tx_token = unsafe { net.send_borrowed(&packet) }.unwrap();
+        let sent = state.lock().unwrap().read_from_queue::<16>(QUEUE_TRANSMIT);
+        assert_eq!(sent[..NET_HDR_MRG_SIZE], [0; NET_HDR_MRG_SIZE]);
+        assert_eq!(sent[NET_HDR_MRG_SIZE..], packet);
+        net.reap_borrowed(tx_token).unwrap();
+
+        let mut tx_buf = [0xff; NET_HDR_MRG_SIZE + 4];
+        assert_eq!(net.fill_buffer_header(&mut tx_buf), Ok(NET_HDR_MRG_SIZE));
+        assert_eq!(tx_buf[..NET_HDR_MRG_SIZE], [0; NET_HDR_MRG_SIZE]);
+
+        // Received packets start after it too.
+        let mut rx_buf = [0; MIN_BUFFER_LEN];
+        let token = unsafe { net.receive_begin(&mut rx_buf) }.unwrap();
+        let mut frame = vec![0; NET_HDR_MRG_SIZE];
+        frame[NET_HDR_SIZE] = 1;
+        frame.extend_from_slice(&packet);
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<16>(QUEUE_RECEIVE, &frame);
+        assert_eq!(
+            unsafe { net.receive_complete(token, &mut rx_buf) },
+            Ok((NET_HDR_MRG_SIZE, packet.len()))
+        );
+        assert_eq!(rx_buf[NET_HDR_MRG_SIZE..][..packet.len()], packet);
+    }
+
+    #[test]
+    fn transmit_complete_timestamped() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 16,
+            device_features: Features::MAC.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut net = VirtIONetRaw::<FakeHal, FakeTransport<Config>, 16>::new(transport).unwrap();
+
+        let mut tx_buf = [0; NET_HDR_SIZE + 20];
+        net.fill_buffer_header(&mut tx_buf).unwrap();
+        let token = unsafe { net.transmit_begin(&tx_buf) }.unwrap();
+        let before = FakeHal::timestamp().unwrap();
+        state.lock().unwrap().read_from_queue::<16>(QUEUE_TRANSMIT);
+
+        assert_eq!(net.poll_transmit(), Some(token));
+        let completion = unsafe { net.transmit_complete_timestamped(token, &tx_buf) }.unwrap();
+        assert_eq!(completion.token, token);
+        assert!(completion.timestamp.unwrap() >= before);
+
+        let packet = [0x42; 20];
+        let tx_token = unsafe { net.send_borrowed(&packet) }.unwrap();
+        state.lock().unwrap().read_from_queue::<16>(QUEUE_TRANSMIT);
+        let (reaped, completion) = net.reap_borrowed_timestamped(tx_token).unwrap();
+        assert_eq!(reaped, &packet);
+        assert!(completion.timestamp.unwrap() >= before);
     }
 }
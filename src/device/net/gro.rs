@@ -0,0 +1,431 @@
+//! Software generic receive offload, which coalesces consecutive TCP segments of the same flow into
+//! one larger packet before they are handed to the network stack.
+
+use crate::checksum::{combine, internet_checksum, verify, Checksum, PortableChecksum};
+use alloc::vec::Vec;
+use core::{marker::PhantomData, ops::Range};
+
+const ETHERNET_HEADER_LEN: usize = 14;
+const ETHERTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const IPV4_HEADER_LEN: usize = 20;
+const IP_PROTOCOL_TCP: u8 = 6;
+const TCP_HEADER_MIN_LEN: usize = 20;
+const TCP_FLAG_PSH: u8 = 0x08;
+const TCP_FLAG_ACK: u8 = 0x10;
+/// The offset of the TCP header in a frame, as IPv4 options aren't supported.
+const TCP_OFFSET: usize = ETHERNET_HEADER_LEN + IPV4_HEADER_LEN;
+
+/// When [`Gro`] stops adding segments to a coalesced packet and delivers it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct GroConfig {
+    /// The maximum number of segments to coalesce into one packet.
+    pub max_segments: usize,
+    /// The maximum size of a coalesced IP packet in bytes, which can't be more than 65535.
+    pub max_packet_len: usize,
+}
+
+impl Default for GroConfig {
+    fn default() -> Self {
+        Self {
+            max_segments: 16,
+            max_packet_len: 0xffff,
+        }
+    }
+}
+
+/// Counts of the frames which passed through a [`Gro`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct GroStats {
+    /// The number of frames received.
+    pub received: u64,
+    /// The number of frames which were appended to an earlier segment rather than delivered
+    /// separately.
+    pub coalesced: u64,
+    /// The number of frames delivered, including coalesced packets.
+    pub delivered: u64,
+}
+
+/// A software generic receive offload (GRO) layer.
+///
+/// Frames are passed in as they are received with [`receive`](Self::receive). Consecutive TCP
+/// segments of the same flow which carry data in order are coalesced into one Ethernet frame, with
+/// the IPv4 total length and both checksums fixed up, so the network stack handles one large packet
+/// instead of many small ones. Anything else is delivered unchanged and in order, after whatever
+/// was being coalesced.
+///
+/// Only TCP over IPv4 without IP options or VLAN tags is coalesced, and only segments whose flags
+/// are ACK and optionally PSH, and whose TCP options are identical. A segment with PSH set ends the
+/// coalesced packet, as do the limits in the [`GroConfig`]. The caller should
+/// [`flush`](Self::flush) at the end of each batch of received frames, so that a coalesced packet
+/// isn't held back waiting for the next segment.
+///
+/// Segments are only coalesced if their checksums are known to be valid, so that a corrupt segment
+/// can't be given a valid checksum. `C` is used to verify and compute checksums.
+#[derive(Debug)]
+pub struct Gro<C: Checksum = PortableChecksum> {
+    config: GroConfig,
+    /// The packet being coalesced, if `held_segment` is set.
+    held: Vec<u8>,
+    /// The headers of the packet being coalesced, as they will be once it is flushed.
+    held_segment: Option<Segment>,
+    /// The number of segments in `held`.
+    held_segments: usize,
+    stats: GroStats,
+    _checksum: PhantomData<C>,
+}
+
+impl<C: Checksum> Gro<C> {
+    /// Creates a GRO layer with the given limits.
+    pub fn new(config: GroConfig) -> Self {
+        Self {
+            config: GroConfig {
+                max_packet_len: config.max_packet_len.min(0xffff),
+                ..config
+            },
+            held: Vec::new(),
+            held_segment: None,
+            held_segments: 0,
+            stats: GroStats::default(),
+            _checksum: PhantomData,
+        }
+    }
+
+    /// Returns counts of the frames which have passed through.
+    pub fn stats(&self) -> GroStats {
+        self.stats
+    }
+
+    /// Takes the given received Ethernet frame, and calls `deliver` with any frames which are ready
+    /// to be handed to the network stack.
+    ///
+    /// `checksum_valid` says whether the TCP checksum of the frame is already known to be valid,
+    /// e.g. because the device set [`VirtioNetHdr::data_valid`](super::VirtioNetHdr::data_valid).
+    /// Otherwise it is verified before the frame is coalesced.
+    pub fn receive(&mut self, frame: &[u8], checksum_valid: bool, deliver: &mut impl FnMut(&[u8])) {
+        self.stats.received += 1;
+        let Some(segment) = Segment::parse::<C>(frame) else {
+            self.flush(deliver);
+            self.deliver(frame, deliver);
+            return;
+        };
+        if !checksum_valid && !verify_tcp_checksum::<C>(frame, &segment) {
+            self.flush(deliver);
+            self.deliver(frame, deliver);
+            return;
+        }
+
+        let payload = &frame[segment.payload.clone()];
+        if let Some(held) = &mut self.held_segment {
+            if held.continued_by(&self.held, &segment, frame)
+                && held.ip_len + payload.len() <= self.config.max_packet_len
+            {
+                self.held.extend_from_slice(payload);
+                // Take the flags of the last segment, so a final PSH is kept.
+                self.held[TCP_OFFSET + 13] = frame[TCP_OFFSET + 13];
+                held.ip_len += payload.len();
+                held.payload.end += payload.len();
+                held.push = segment.push;
+                self.held_segments += 1;
+                self.stats.coalesced += 1;
+                if segment.push || self.held_segments >= self.config.max_segments {
+                    self.flush(deliver);
+                }
+                return;
+            }
+            self.flush(deliver);
+        }
+
+        if segment.push || self.config.max_segments <= 1 {
+            self.deliver(frame, deliver);
+        } else {
+            self.held.clear();
+            // Leave out any padding after the IP packet.
+            self.held.extend_from_slice(&frame[..segment.payload.end]);
+            self.held_segment = Some(segment);
+            self.held_segments = 1;
+        }
+    }
+
+    /// Delivers the packet being coalesced, if any.
+    pub fn flush(&mut self, deliver: &mut impl FnMut(&[u8])) {
+        if self.held_segment.take().is_none() {
+            return;
+        }
+        if self.held_segments > 1 {
+            fix_up_headers::<C>(&mut self.held);
+        }
+        self.stats.delivered += 1;
+        deliver(&self.held);
+    }
+
+    fn deliver(&mut self, frame: &[u8], deliver: &mut impl FnMut(&[u8])) {
+        self.stats.delivered += 1;
+        deliver(frame);
+    }
+}
+
+impl<C: Checksum> Default for Gro<C> {
+    fn default() -> Self {
+        Self::new(GroConfig::default())
+    }
+}
+
+/// The parts of a TCP segment in an Ethernet frame which decide whether it can be coalesced.
+#[derive(Debug)]
+struct Segment {
+    /// The length of the IP packet.
+    ip_len: usize,
+    /// The range of the TCP header in the frame.
+    tcp_header: Range<usize>,
+    /// The range of the TCP payload in the frame.
+    payload: Range<usize>,
+    sequence: u32,
+    push: bool,
+}
+
+impl Segment {
+    /// Parses the given frame, if it is a TCP segment carrying data which may be coalesced.
+    ///
+    /// The IPv4 header checksum is verified here, as the device only ever vouches for the TCP
+    /// checksum, and a coalesced frame gets a freshly computed header checksum.
+    fn parse<C: Checksum>(frame: &[u8]) -> Option<Self> {
+        let ip = frame.get(ETHERNET_HEADER_LEN..)?;
+        if frame[12..14] != ETHERTYPE_IPV4
+            || ip.len() < IPV4_HEADER_LEN
+            // Version 4 without options.
+            || ip[0] != 0x45
+            // Not a fragment, i.e. More Fragments is clear and the offset is 0.
+            || u16::from_be_bytes([ip[6], ip[7]]) & 0x3fff != 0
+            || ip[9] != IP_PROTOCOL_TCP
+            || !verify::<C>(&ip[..IPV4_HEADER_LEN])
+        {
+            return None;
+        }
+        let ip_len = usize::from(u16::from_be_bytes([ip[2], ip[3]]));
+        if ip_len > ip.len() {
+            return None;
+        }
+        let tcp = &ip[IPV4_HEADER_LEN..ip_len];
+        if tcp.len() < TCP_HEADER_MIN_LEN {
+            return None;
+        }
+        let tcp_header_len = usize::from(tcp[12] >> 4) * 4;
+        let flags = tcp[13];
+        if tcp_header_len < TCP_HEADER_MIN_LEN
+            || tcp_header_len >= tcp.len()
+            || flags & !TCP_FLAG_PSH != TCP_FLAG_ACK
+        {
+            return None;
+        }
+        Some(Self {
+            ip_len,
+            tcp_header: TCP_OFFSET..TCP_OFFSET + tcp_header_len,
+            payload: TCP_OFFSET + tcp_header_len..ETHERNET_HEADER_LEN + ip_len,
+            sequence: u32::from_be_bytes([tcp[4], tcp[5], tcp[6], tcp[7]]),
+            push: flags & TCP_FLAG_PSH != 0,
+        })
+    }
+
+    /// Returns whether `next` in `next_frame` carries the data following this segment in `frame`,
+    /// on the same flow and with the same headers apart from the sequence number, so can be
+    /// appended to it.
+    fn continued_by(&self, frame: &[u8], next: &Self, next_frame: &[u8]) -> bool {
+        let payload_len = (self.payload.end - self.payload.start) as u32;
+        !self.push
+            && next.sequence == self.sequence.wrapping_add(payload_len)
+            // Ethernet addresses and type.
+            && frame[..ETHERNET_HEADER_LEN] == next_frame[..ETHERNET_HEADER_LEN]
+            // TOS, DF, TTL, protocol and addresses, but not the length, ID or checksum.
+            && frame[15] == next_frame[15]
+            && frame[20] & 0x40 == next_frame[20] & 0x40
+            && frame[22..24] == next_frame[22..24]
+            && frame[26..TCP_OFFSET] == next_frame[26..TCP_OFFSET]
+            // Ports.
+            && frame[TCP_OFFSET..TCP_OFFSET + 4] == next_frame[TCP_OFFSET..TCP_OFFSET + 4]
+            // Acknowledgement number, header length and window, but not the flags as PSH may
+            // differ.
+            && frame[TCP_OFFSET + 8..TCP_OFFSET + 13]
+                == next_frame[TCP_OFFSET + 8..TCP_OFFSET + 13]
+            && frame[TCP_OFFSET + 14..TCP_OFFSET + 16]
+                == next_frame[TCP_OFFSET + 14..TCP_OFFSET + 16]
+            // Options, including timestamps.
+            && frame[TCP_OFFSET + TCP_HEADER_MIN_LEN..self.tcp_header.end]
+                == next_frame[TCP_OFFSET + TCP_HEADER_MIN_LEN..next.tcp_header.end]
+    }
+}
+
+/// Returns the ones' complement sum of the TCP pseudo-header for a segment of the given length in
+/// the given frame.
+fn pseudo_header_sum<C: Checksum>(frame: &[u8], tcp_len: usize) -> u16 {
+    let mut pseudo_header = [0; 12];
+    pseudo_header[..8].copy_from_slice(&frame[ETHERNET_HEADER_LEN + 12..TCP_OFFSET]);
+    pseudo_header[9] = IP_PROTOCOL_TCP;
+    // The IP packet length was checked to fit in 16 bits.
+    pseudo_header[10..].copy_from_slice(&(tcp_len as u16).to_be_bytes());
+    C::ones_complement_sum(&pseudo_header)
+}
+
+/// Returns whether the TCP checksum of the given segment is valid.
+fn verify_tcp_checksum<C: Checksum>(frame: &[u8], segment: &Segment) -> bool {
+    let tcp = &frame[TCP_OFFSET..segment.payload.end];
+    combine(
+        pseudo_header_sum::<C>(frame, tcp.len()),
+        C::ones_complement_sum(tcp),
+    ) == 0xffff
+}
+
+/// Fixes up the IPv4 total length and the IPv4 and TCP checksums of a coalesced frame.
+fn fix_up_headers<C: Checksum>(frame: &mut [u8]) {
+    let ip_len = frame.len() - ETHERNET_HEADER_LEN;
+    // The caller limits coalesced packets to 65535 bytes.
+    frame[ETHERNET_HEADER_LEN + 2..ETHERNET_HEADER_LEN + 4]
+        .copy_from_slice(&(ip_len as u16).to_be_bytes());
+    frame[ETHERNET_HEADER_LEN + 10..ETHERNET_HEADER_LEN + 12].fill(0);
+    let ip_checksum = internet_checksum::<C>(&frame[ETHERNET_HEADER_LEN..TCP_OFFSET]);
+    frame[ETHERNET_HEADER_LEN + 10..ETHERNET_HEADER_LEN + 12]
+        .copy_from_slice(&ip_checksum.to_be_bytes());
+
+    frame[TCP_OFFSET + 16..TCP_OFFSET + 18].fill(0);
+    let tcp_len = frame.len() - TCP_OFFSET;
+    let tcp_checksum = !combine(
+        pseudo_header_sum::<C>(frame, tcp_len),
+        C::ones_complement_sum(&frame[TCP_OFFSET..]),
+    );
+    frame[TCP_OFFSET + 16..TCP_OFFSET + 18].copy_from_slice(&tcp_checksum.to_be_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec;
+
+    /// Builds an Ethernet frame containing a TCP segment with valid checksums.
+    fn segment(sequence: u32, flags: u8, payload: &[u8]) -> Vec<u8> {
+        let mut frame = vec![0; TCP_OFFSET + TCP_HEADER_MIN_LEN];
+        frame[..12].copy_from_slice(&[2, 0, 0, 0, 0, 1, 2, 0, 0, 0, 0, 2]);
+        frame[12..14].copy_from_slice(&ETHERTYPE_IPV4);
+        let ip = &mut frame[ETHERNET_HEADER_LEN..];
+        ip[0] = 0x45;
+        ip[6] = 0x40;
+        ip[8] = 64;
+        ip[9] = IP_PROTOCOL_TCP;
+        ip[12..20].copy_from_slice(&[10, 0, 0, 1, 10, 0, 0, 2]);
+        let tcp = &mut frame[TCP_OFFSET..];
+        tcp[..4].copy_from_slice(&[0x04, 0x00, 0x00, 0x50]);
+        tcp[4..8].copy_from_slice(&sequence.to_be_bytes());
+        tcp[8..12].copy_from_slice(&7u32.to_be_bytes());
+        tcp[12] = 5 << 4;
+        tcp[13] = flags;
+        tcp[14..16].copy_from_slice(&1024u16.to_be_bytes());
+        frame.extend_from_slice(payload);
+        fix_up_headers::<PortableChecksum>(&mut frame);
+        frame
+    }
+
+    fn receive_all(gro: &mut Gro, frames: &[(&[u8], bool)]) -> Vec<Vec<u8>> {
+        let mut delivered = Vec::new();
+        let mut deliver = |frame: &[u8]| delivered.push(frame.to_vec());
+        for (frame, checksum_valid) in frames {
+            gro.receive(frame, *checksum_valid, &mut deliver);
+        }
+        gro.flush(&mut deliver);
+        delivered
+    }
+
+    #[test]
+    fn coalesces_consecutive_segments() {
+        let mut gro = Gro::default();
+        let first = segment(100, TCP_FLAG_ACK, b"hello ");
+        let second = segment(106, TCP_FLAG_ACK, b"big ");
+        let third = segment(110, TCP_FLAG_ACK | TCP_FLAG_PSH, b"world");
+        let after_push = segment(115, TCP_FLAG_ACK, b"!");
+        let delivered = receive_all(
+            &mut gro,
+            &[
+                (&first, false),
+                (&second, true),
+                (&third, false),
+                (&after_push, false),
+            ],
+        );
+        assert_eq!(
+            delivered,
+            vec![
+                segment(100, TCP_FLAG_ACK | TCP_FLAG_PSH, b"hello big world"),
+                after_push
+            ]
+        );
+        assert!(verify_tcp_checksum::<PortableChecksum>(
+            &delivered[0],
+            &Segment::parse::<PortableChecksum>(&delivered[0]).unwrap()
+        ));
+        assert_eq!(
+            gro.stats(),
+            GroStats {
+                received: 4,
+                coalesced: 2,
+                delivered: 2,
+            }
+        );
+    }
+
+    #[test]
+    fn passes_other_frames_through_in_order() {
+        let mut gro = Gro::new(GroConfig {
+            max_segments: 2,
+            ..Default::default()
+        });
+        let first = segment(0, TCP_FLAG_ACK, b"a");
+        let gap = segment(5, TCP_FLAG_ACK, b"b");
+        let mut corrupt = segment(6, TCP_FLAG_ACK, b"c");
+        *corrupt.last_mut().unwrap() = b'x';
+        // The device only validates the TCP checksum, so a bad IPv4 header checksum must still
+        // stop the segment being coalesced with the one before.
+        let before_bad_ip_checksum = segment(7, TCP_FLAG_ACK, b"e");
+        let mut bad_ip_checksum = segment(8, TCP_FLAG_ACK, b"f");
+        bad_ip_checksum[ETHERNET_HEADER_LEN + 10] ^= 0xff;
+        let syn = segment(0, 0x02, b"d");
+        let arp = [0xff; 42];
+        let delivered = receive_all(
+            &mut gro,
+            &[
+                (&first, false),
+                (&gap, false),
+                (&corrupt, false),
+                (&before_bad_ip_checksum, true),
+                (&bad_ip_checksum, true),
+                (&syn, false),
+                (&arp, false),
+            ],
+        );
+        assert_eq!(
+            delivered,
+            vec![
+                first,
+                gap,
+                corrupt,
+                before_bad_ip_checksum,
+                bad_ip_checksum,
+                syn,
+                arp.to_vec()
+            ]
+        );
+
+        // The limit on the number of segments flushes the packet.
+        let delivered = receive_all(
+            &mut gro,
+            &[
+                (&segment(0, TCP_FLAG_ACK, b"a"), true),
+                (&segment(1, TCP_FLAG_ACK, b"b"), true),
+                (&segment(2, TCP_FLAG_ACK, b"c"), true),
+            ],
+        );
+        assert_eq!(
+            delivered,
+            vec![
+                segment(0, TCP_FLAG_ACK, b"ab"),
+                segment(2, TCP_FLAG_ACK, b"c")
+            ]
+        );
+    }
+}
@@ -4,19 +4,35 @@
 mod dev;
 mod dev_raw;
 #[cfg(feature = "alloc")]
+mod gro;
+#[cfg(feature = "alloc")]
 mod net_buf;
+mod rss;
 
-pub use self::dev_raw::VirtIONetRaw;
+pub use self::dev_raw::{TxCompletion, TxToken, VirtIONetRaw};
+pub use self::rss::{
+    RssHashTypes, RssKey, RssMapping, RSS_MAX_INDIRECTION_TABLE_LEN, RSS_MAX_KEY_SIZE,
+};
 #[cfg(feature = "alloc")]
-pub use self::{dev::VirtIONet, net_buf::RxBuffer, net_buf::TxBuffer};
+pub use self::{
+    dev::{RxHook, RxHookStats, RxVerdict, VirtIONet},
+    gro::{Gro, GroConfig, GroStats},
+    net_buf::{RxBuffer, RxBufferLayout, TxBuffer},
+};
 
-use crate::volatile::ReadOnly;
+use crate::checksum::{internet_checksum, Checksum};
+use crate::config::{changed, ConfigDiff};
+use crate::display::impl_flags_display;
+use crate::{Error, Result};
 use bitflags::bitflags;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
 const MAX_BUFFER_LEN: usize = 65535;
 const MIN_BUFFER_LEN: usize = 1526;
 const NET_HDR_SIZE: usize = core::mem::size_of::<VirtioNetHdr>();
+/// The size of the header when it is followed by `num_buffers`, i.e. when
+/// `VIRTIO_NET_F_MRG_RXBUF` or `VIRTIO_F_VERSION_1` was negotiated.
+const NET_HDR_MRG_SIZE: usize = NET_HDR_SIZE + 2;
 
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -72,6 +88,10 @@ bitflags! {
         const MQ = 1 << 22;
         /// Set MAC address through control channel.
         const CTL_MAC_ADDR = 1 << 23;
+        /// Device supports receive side scaling, configured through the control channel.
+        const RSS = 1 << 60;
+        /// Device may act as a standby for a primary device with the same MAC address.
+        const STANDBY = 1 << 62;
 
         // device independent
         const RING_INDIRECT_DESC = 1 << 28;
@@ -80,6 +100,8 @@ bitflags! {
     }
 }
 
+impl_flags_display!(Features);
+
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
     struct Status: u16 {
@@ -88,6 +110,8 @@ bitflags! {
     }
 }
 
+impl_flags_display!(Status);
+
 bitflags! {
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
     struct InterruptStatus : u32 {
@@ -96,23 +120,77 @@ bitflags! {
     }
 }
 
+/// The network device configuration space up to and including the receive side scaling
+/// capabilities.
+///
+/// Devices which don't offer `VIRTIO_NET_F_SPEED_DUPLEX` or `VIRTIO_NET_F_RSS` may not expose
+/// this much configuration space, so the driver reads only the fields which the negotiated
+/// features cover, with [`Transport::read_config`](crate::transport::Transport::read_config).
 #[repr(C)]
-struct Config {
-    mac: ReadOnly<EthernetAddress>,
-    status: ReadOnly<Status>,
-    max_virtqueue_pairs: ReadOnly<u16>,
-    mtu: ReadOnly<u16>,
+#[derive(Clone, Copy, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct VirtioNetConfig {
+    /// The MAC address, if `VIRTIO_NET_F_MAC` was negotiated. Only legacy drivers may write it.
+    pub mac: [u8; 6],
+    /// The link status bits, if `VIRTIO_NET_F_STATUS` was negotiated.
+    pub status: u16,
+    /// The maximum number of queue pairs, if `VIRTIO_NET_F_MQ` or `VIRTIO_NET_F_RSS` was
+    /// negotiated.
+    pub max_virtqueue_pairs: u16,
+    /// The maximum MTU, if `VIRTIO_NET_F_MTU` was negotiated.
+    pub mtu: u16,
+    /// The link speed in Mbit/s, if `VIRTIO_NET_F_SPEED_DUPLEX` was negotiated.
+    pub speed: u32,
+    /// The duplex mode, if `VIRTIO_NET_F_SPEED_DUPLEX` was negotiated.
+    pub duplex: u8,
+    /// The maximum supported length of an RSS key, if `VIRTIO_NET_F_RSS` was negotiated.
+    pub rss_max_key_size: u8,
+    /// The maximum number of indirection table entries, if `VIRTIO_NET_F_RSS` was negotiated.
+    pub rss_max_indirection_table_length: u16,
+    /// The supported hash types, as [`RssHashTypes`] bits, if `VIRTIO_NET_F_RSS` was negotiated.
+    pub supported_hash_types: u32,
 }
 
 type EthernetAddress = [u8; 6];
 
+/// The fields of the network device configuration which may change at runtime.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct NetConfigSnapshot {
+    link_up: bool,
+}
+
+impl ConfigDiff for NetConfigSnapshot {
+    type Changes = NetConfigChanges;
+
+    fn diff(&self, previous: &Self) -> Option<NetConfigChanges> {
+        if self == previous {
+            return None;
+        }
+        Some(NetConfigChanges {
+            link_up: changed(previous.link_up, self.link_up),
+        })
+    }
+}
+
+/// Changes to the configuration of a network device, as returned by
+/// [`VirtIONetRaw::refresh_config`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct NetConfigChanges {
+    /// Whether the link is now up, if that changed.
+    pub link_up: Option<bool>,
+}
+
 /// VirtIO 5.1.6 Device Operation:
 ///
 /// Packets are transmitted by placing them in the transmitq1. . .transmitqN,
 /// and buffers for incoming packets are placed in the receiveq1. . .receiveqN.
 /// In each case, the packet itself is preceded by a header.
+///
+/// If `VIRTIO_NET_F_MRG_RXBUF` or `VIRTIO_F_VERSION_1` was negotiated the header is followed by
+/// a 16-bit `num_buffers` field, which the drivers handle separately; see
+/// [`VirtIONetRaw::header_len`].
 #[repr(C)]
-#[derive(AsBytes, Debug, Default, FromBytes, FromZeroes)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
 pub struct VirtioNetHdr {
     flags: Flags,
     gso_type: GsoType,
@@ -124,6 +202,68 @@ pub struct VirtioNetHdr {
     // payload starts from here
 }
 
+impl VirtioNetHdr {
+    /// Returns a header for transmitting a packet with only a partial checksum, which the device
+    /// should complete.
+    ///
+    /// The device sums everything from `csum_start` to the end of the packet and stores the
+    /// checksum at `csum_offset` after `csum_start`. The caller must already have stored the sum of
+    /// the pseudo-header there. This needs `VIRTIO_NET_F_CSUM`; see
+    /// [`VirtIONetRaw::checksum_offload`].
+    pub fn partial_checksum(csum_start: u16, csum_offset: u16) -> Self {
+        Self {
+            flags: Flags::NEEDS_CSUM,
+            csum_start,
+            csum_offset,
+            ..Default::default()
+        }
+    }
+
+    /// Returns whether the device has already validated the checksum of the received packet
+    /// following this header, so the network stack needn't check it again.
+    pub fn data_valid(&self) -> bool {
+        self.flags.contains(Flags::DATA_VALID)
+    }
+
+    /// Returns the offset within the packet from which the partial checksum is calculated, if
+    /// [`needs_checksum`](Self::needs_checksum) is set.
+    pub fn csum_start(&self) -> u16 {
+        self.csum_start
+    }
+
+    /// Returns the offset after [`csum_start`](Self::csum_start) at which the checksum is stored,
+    /// if [`needs_checksum`](Self::needs_checksum) is set.
+    pub fn csum_offset(&self) -> u16 {
+        self.csum_offset
+    }
+
+    /// Returns whether the packet following this header has only a partial checksum, which must
+    /// be completed before it is passed to anything which checks it.
+    pub fn needs_checksum(&self) -> bool {
+        self.flags.contains(Flags::NEEDS_CSUM)
+    }
+
+    /// Completes the partial checksum of the given packet which followed this header, if it needs
+    /// one, and returns whether it did.
+    ///
+    /// The device leaves the sum of the pseudo-header in the checksum field, so this stores the
+    /// checksum of everything from `csum_start` to the end of the packet at `csum_offset` after
+    /// that. Returns [`Error::InvalidParam`] if the offsets are outside the packet.
+    pub fn complete_checksum<C: Checksum>(&self, packet: &mut [u8]) -> Result<bool> {
+        if !self.needs_checksum() {
+            return Ok(false);
+        }
+        let start = usize::from(self.csum_start);
+        let field = start + usize::from(self.csum_offset);
+        if field + 2 > packet.len() {
+            return Err(Error::InvalidParam);
+        }
+        let checksum = internet_checksum::<C>(&packet[start..]);
+        packet[field..field + 2].copy_from_slice(&checksum.to_be_bytes());
+        Ok(true)
+    }
+}
+
 #[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
 #[repr(transparent)]
 struct Flags(u8);
@@ -148,8 +288,96 @@ impl GsoType {
     const ECN: GsoType = GsoType(0x80);
 }
 
+/// The header of a command sent on the control queue.
+///
+/// Ref: 5.1.6.5 Control Virtqueue
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct CtrlHeader {
+    class: CtrlClass,
+    command: u8,
+}
+
+#[repr(transparent)]
+#[derive(AsBytes, Debug, Copy, Clone, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+struct CtrlClass(u8);
+
+impl CtrlClass {
+    const MAC: CtrlClass = CtrlClass(1);
+    const MQ: CtrlClass = CtrlClass(4);
+    const GUEST_OFFLOADS: CtrlClass = CtrlClass(5);
+}
+
+/// Commands in the [`CtrlClass::MAC`] class.
+const CTRL_MAC_ADDR_SET: u8 = 1;
+/// Commands in the [`CtrlClass::MQ`] class.
+const CTRL_MQ_VQ_PAIRS_SET: u8 = 0;
+const CTRL_MQ_RSS_CONFIG: u8 = 1;
+/// Commands in the [`CtrlClass::GUEST_OFFLOADS`] class.
+const CTRL_GUEST_OFFLOADS_SET: u8 = 0;
+
+bitflags! {
+    /// Receive offloads which the driver can enable or disable at runtime with
+    /// [`VirtIONetRaw::set_guest_offloads`].
+    ///
+    /// The bits match the corresponding feature bits.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct GuestOffloads: u64 {
+        /// The device may pass packets with a partial checksum to the driver.
+        const CSUM = Features::GUEST_CSUM.bits();
+        /// The device may pass TSOv4 packets (i.e. coalesce received TCPv4 segments) to the driver.
+        const TSO4 = Features::GUEST_TSO4.bits();
+        /// The device may pass TSOv6 packets to the driver.
+        const TSO6 = Features::GUEST_TSO6.bits();
+        /// The device may pass TSO packets with ECN to the driver.
+        const ECN = Features::GUEST_ECN.bits();
+        /// The device may pass UFO packets to the driver.
+        const UFO = Features::GUEST_UFO.bits();
+    }
+}
+
+impl_flags_display!(GuestOffloads);
+
+bitflags! {
+    /// Receive features which change what received packets look like, so are only negotiated if
+    /// the caller asks for them, e.g. with [`VirtIONetRaw::new_with_rx_features`].
+    ///
+    /// The bits match the corresponding feature bits.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct RxFeatures: u64 {
+        /// `VIRTIO_NET_F_GUEST_CSUM`: the device may pass packets with only a partial checksum,
+        /// which the caller must complete with [`VirtioNetHdr::complete_checksum`], and may mark
+        /// packets whose checksum it has validated.
+        const GUEST_CSUM = Features::GUEST_CSUM.bits();
+        /// `VIRTIO_NET_F_MRG_RXBUF`: the device may spread a packet across several receive
+        /// buffers, which the caller must reassemble, e.g. with `VirtIONet::receive_packet`.
+        const MRG_RXBUF = Features::MRG_RXBUF.bits();
+    }
+}
+
+impl_flags_display!(RxFeatures);
+
+/// The status written by the device in response to a control queue command.
+#[repr(transparent)]
+#[derive(AsBytes, Debug, Copy, Clone, Eq, FromBytes, FromZeroes, PartialEq)]
+struct CtrlAck(u8);
+
+impl CtrlAck {
+    const OK: CtrlAck = CtrlAck(0);
+    const ERR: CtrlAck = CtrlAck(1);
+}
+
 const QUEUE_RECEIVE: u16 = 0;
 const QUEUE_TRANSMIT: u16 = 1;
+const CTRL_QUEUE_SIZE: usize = 16;
 const SUPPORTED_FEATURES: Features = Features::MAC
     .union(Features::STATUS)
+    .union(Features::CTRL_VQ)
+    .union(Features::CTRL_GUEST_OFFLOADS)
+    .union(Features::CTL_MAC_ADDR)
+    .union(Features::MQ)
+    .union(Features::RSS)
+    .union(Features::STANDBY)
+    .union(Features::CSUM)
+    .union(Features::RING_INDIRECT_DESC)
     .union(Features::RING_EVENT_IDX);
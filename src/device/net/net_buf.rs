@@ -1,7 +1,12 @@
-use super::{VirtioNetHdr, NET_HDR_SIZE};
+use super::{VirtioNetHdr, NET_HDR_MRG_SIZE, NET_HDR_SIZE};
+use crate::checksum::Checksum;
+use crate::{Error, Result};
 use alloc::{vec, vec::Vec};
-use core::{convert::TryInto, mem::size_of};
-use zerocopy::AsBytes;
+use core::{
+    convert::TryInto,
+    mem::{align_of, size_of},
+};
+use zerocopy::{AsBytes, FromBytes};
 
 /// A buffer used for transmitting.
 pub struct TxBuffer(pub(crate) Vec<u8>);
@@ -9,10 +14,55 @@ pub struct TxBuffer(pub(crate) Vec<u8>);
 /// A buffer used for receiving.
 pub struct RxBuffer {
     pub(crate) buf: Vec<usize>, // for alignment
+    /// The offset in bytes of the start of the headroom within `buf`.
+    offset: usize,
+    headroom: usize,
+    /// The length in bytes of the part of the buffer given to the device.
+    len: usize,
+    /// The length in bytes of the header before the packet, or 0 if this buffer continues a
+    /// packet from a previous buffer.
+    hdr_len: usize,
     pub(crate) packet_len: usize,
     pub(crate) idx: u16,
 }
 
+/// How the receive buffers allocated by [`VirtIONet`](super::VirtIONet) are laid out in memory.
+///
+/// Each buffer starts with `headroom` bytes which the device never writes, followed by the virtio
+/// header and then the packet. This lets the caller prepend encapsulation headers or its own
+/// metadata to a received frame in place, rather than copying it into a new buffer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RxBufferLayout {
+    /// The number of bytes to reserve before the virtio header. This must be a multiple of the
+    /// alignment of [`VirtioNetHdr`].
+    pub headroom: usize,
+    /// The alignment in bytes of the start of the buffer, including the headroom. This must be a
+    /// power of two.
+    pub align: usize,
+}
+
+impl Default for RxBufferLayout {
+    fn default() -> Self {
+        Self {
+            headroom: 0,
+            align: align_of::<usize>(),
+        }
+    }
+}
+
+impl RxBufferLayout {
+    /// Returns [`Error::InvalidParam`] if the layout can't be used.
+    pub(crate) fn check(&self) -> Result {
+        if !self.align.is_power_of_two()
+            || !self.headroom.is_multiple_of(align_of::<VirtioNetHdr>())
+        {
+            Err(Error::InvalidParam)
+        } else {
+            Ok(())
+        }
+    }
+}
+
 impl TxBuffer {
     /// Constructs the buffer from the given slice.
     pub fn from(buf: &[u8]) -> Self {
@@ -40,44 +90,220 @@ impl RxBuffer {
     pub(crate) fn new(idx: usize, buf_len: usize) -> Self {
         Self {
             buf: vec![0; buf_len / size_of::<usize>()],
+            offset: 0,
+            headroom: 0,
+            len: buf_len / size_of::<usize>() * size_of::<usize>(),
+            hdr_len: NET_HDR_SIZE,
             packet_len: 0,
             idx: idx.try_into().unwrap(),
         }
     }
 
+    /// Allocates a new buffer with length `buf_len` after the headroom, laid out as given, or
+    /// returns [`Error::OutOfMemory`] if there isn't enough memory.
+    ///
+    /// The layout must already have been checked with [`RxBufferLayout::check`].
+    pub(crate) fn try_new(idx: usize, buf_len: usize, layout: RxBufferLayout) -> Result<Self> {
+        // Over-allocate so that the start of the headroom can be moved up to the requested
+        // alignment. The vector is never resized, so the offset stays valid.
+        let padding = layout.align.saturating_sub(align_of::<usize>());
+        let len = (padding + layout.headroom + buf_len) / size_of::<usize>();
+        let mut buf = Vec::new();
+        buf.try_reserve_exact(len).map_err(|_| Error::OutOfMemory {
+            requested: len * size_of::<usize>(),
+        })?;
+        buf.resize(len, 0);
+        let offset = buf.as_ptr().cast::<u8>().align_offset(layout.align);
+        let len = (len * size_of::<usize>() - padding - layout.headroom) / size_of::<usize>()
+            * size_of::<usize>();
+        Ok(Self {
+            buf,
+            offset,
+            headroom: layout.headroom,
+            len,
+            hdr_len: NET_HDR_SIZE,
+            packet_len: 0,
+            idx: idx.try_into().unwrap(),
+        })
+    }
+
+    /// Returns the offset in bytes of the virtio header within `buf`.
+    fn start(&self) -> usize {
+        self.offset + self.headroom
+    }
+
     /// Set the network packet length.
     pub(crate) fn set_packet_len(&mut self, packet_len: usize) {
         self.packet_len = packet_len
     }
 
+    /// Sets the length of the header before the packet.
+    pub(crate) fn set_header_len(&mut self, hdr_len: usize) {
+        self.hdr_len = hdr_len
+    }
+
+    /// Returns the length of the header before the packet, which is 0 for the buffers after the
+    /// first of a packet spread across several.
+    pub const fn header_len(&self) -> usize {
+        self.hdr_len
+    }
+
+    /// Returns the number of receive buffers the packet starting in this buffer is spread across,
+    /// including this one.
+    ///
+    /// This is always 1 unless `VIRTIO_NET_F_MRG_RXBUF` was negotiated.
+    pub fn num_buffers(&self) -> u16 {
+        if self.hdr_len < NET_HDR_MRG_SIZE {
+            return 1;
+        }
+        let bytes = &self.as_bytes()[NET_HDR_SIZE..NET_HDR_MRG_SIZE];
+        u16::from_le_bytes([bytes[0], bytes[1]])
+    }
+
     /// Returns the network packet length (witout header).
     pub const fn packet_len(&self) -> usize {
         self.packet_len
     }
 
-    /// Returns all data in the buffer, including both the header and the packet.
+    /// Returns all data in the buffer, including both the header and the packet but not the
+    /// headroom.
     pub fn as_bytes(&self) -> &[u8] {
-        self.buf.as_bytes()
+        let start = self.start();
+        &self.buf.as_bytes()[start..start + self.len]
     }
 
     /// Returns all data in the buffer with the mutable reference,
-    /// including both the header and the packet.
+    /// including both the header and the packet but not the headroom.
     pub fn as_bytes_mut(&mut self) -> &mut [u8] {
-        self.buf.as_bytes_mut()
+        let start = self.start();
+        &mut self.buf.as_bytes_mut()[start..start + self.len]
+    }
+
+    /// Returns the headroom reserved before the header, as requested by
+    /// [`RxBufferLayout::headroom`].
+    pub fn headroom(&self) -> &[u8] {
+        &self.buf.as_bytes()[self.offset..self.start()]
+    }
+
+    /// Returns the headroom reserved before the header as a mutable slice.
+    ///
+    /// The device never writes to the headroom, so anything stored here is kept when the buffer
+    /// is recycled.
+    pub fn headroom_mut(&mut self) -> &mut [u8] {
+        let start = self.start();
+        &mut self.buf.as_bytes_mut()[self.offset..start]
     }
 
     /// Returns the reference of the header.
+    ///
+    /// This is only meaningful for the first buffer of a packet, i.e. if
+    /// [`header_len`](Self::header_len) isn't 0.
     pub fn header(&self) -> &VirtioNetHdr {
-        unsafe { &*(self.buf.as_ptr() as *const VirtioNetHdr) }
+        // Safe because `RxBufferLayout::check` ensures that the header is suitably aligned.
+        unsafe { &*(self.as_bytes().as_ptr() as *const VirtioNetHdr) }
     }
 
     /// Returns the network packet as a slice.
     pub fn packet(&self) -> &[u8] {
-        &self.buf.as_bytes()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+        &self.as_bytes()[self.hdr_len..self.hdr_len + self.packet_len]
     }
 
     /// Returns the network packet as a mutable slice.
     pub fn packet_mut(&mut self) -> &mut [u8] {
-        &mut self.buf.as_bytes_mut()[NET_HDR_SIZE..NET_HDR_SIZE + self.packet_len]
+        let (hdr_len, packet_len) = (self.hdr_len, self.packet_len);
+        &mut self.as_bytes_mut()[hdr_len..hdr_len + packet_len]
+    }
+
+    /// Completes the partial checksum of the packet if the device left one, and returns whether
+    /// it did.
+    ///
+    /// See [`VirtioNetHdr::complete_checksum`].
+    pub fn complete_checksum<C: Checksum>(&mut self) -> Result<bool> {
+        let (hdr_len, packet_len) = (self.hdr_len, self.packet_len);
+        let (header, packet) = self.as_bytes_mut().split_at_mut(hdr_len);
+        let header = VirtioNetHdr::read_from_prefix(header).ok_or(Error::InvalidParam)?;
+        header.complete_checksum::<C>(&mut packet[..packet_len])
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::device::net::Flags;
+
+    #[test]
+    fn rx_buffer_layout() {
+        let layout = RxBufferLayout {
+            headroom: 32,
+            align: 256,
+        };
+        layout.check().unwrap();
+        let mut rx_buf = RxBuffer::try_new(0, 1526, layout).unwrap();
+        assert_eq!(rx_buf.headroom().len(), 32);
+        assert_eq!(rx_buf.headroom().as_ptr() as usize % 256, 0);
+        assert_eq!(
+            rx_buf.as_bytes().as_ptr() as usize,
+            rx_buf.headroom().as_ptr() as usize + 32
+        );
+        assert!(rx_buf.as_bytes().len() >= 1520);
+
+        rx_buf.headroom_mut().fill(0xaa);
+        rx_buf.set_packet_len(4);
+        rx_buf.packet_mut().copy_from_slice(&[1, 2, 3, 4]);
+        assert_eq!(rx_buf.as_bytes()[..NET_HDR_SIZE], [0; NET_HDR_SIZE]);
+        assert_eq!(rx_buf.packet(), &[1, 2, 3, 4]);
+        assert!(rx_buf.headroom().iter().all(|&byte| byte == 0xaa));
+    }
+
+    #[test]
+    fn complete_checksum() {
+        use crate::checksum::{verify, PortableChecksum};
+
+        let mut rx_buf = RxBuffer::new(0, 64);
+        // A UDP header with the pseudo-header sum in its checksum field, and 2 bytes of payload.
+        let packet = [0x12, 0x34, 0x00, 0x35, 0x00, 0x0a, 0xab, 0xcd, 0x68, 0x69];
+        rx_buf.set_packet_len(packet.len());
+        rx_buf.packet_mut().copy_from_slice(&packet);
+        assert_eq!(rx_buf.complete_checksum::<PortableChecksum>(), Ok(false));
+        assert_eq!(rx_buf.packet(), &packet);
+
+        let header = VirtioNetHdr {
+            flags: Flags::NEEDS_CSUM,
+            csum_start: 0,
+            csum_offset: 6,
+            ..Default::default()
+        };
+        rx_buf.as_bytes_mut()[..NET_HDR_SIZE].copy_from_slice(header.as_bytes());
+        assert!(rx_buf.header().needs_checksum());
+        assert_eq!(rx_buf.complete_checksum::<PortableChecksum>(), Ok(true));
+        // The checksum covers the pseudo-header sum which was in the field, so adding it back in
+        // should give a valid checksum.
+        let mut data = rx_buf.packet().to_vec();
+        data.extend_from_slice(&[0xab, 0xcd]);
+        assert!(verify::<PortableChecksum>(&data));
+
+        let header = VirtioNetHdr {
+            csum_offset: 9,
+            ..header
+        };
+        rx_buf.as_bytes_mut()[..NET_HDR_SIZE].copy_from_slice(header.as_bytes());
+        assert_eq!(
+            rx_buf.complete_checksum::<PortableChecksum>(),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn invalid_rx_buffer_layout() {
+        let layout = RxBufferLayout {
+            headroom: 0,
+            align: 48,
+        };
+        assert_eq!(layout.check(), Err(Error::InvalidParam));
+        let layout = RxBufferLayout {
+            headroom: 3,
+            align: 8,
+        };
+        assert_eq!(layout.check(), Err(Error::InvalidParam));
     }
 }
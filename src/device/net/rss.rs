@@ -0,0 +1,347 @@
+//! Receive side scaling (`VIRTIO_NET_F_RSS`).
+//!
+//! With RSS the device hashes each received packet's addresses and ports with a Toeplitz hash,
+//! and uses the hash to pick a receive queue from an indirection table. [`RssMapping`] holds the
+//! key and table which were programmed, so that the guest network stack can compute the same
+//! mapping and process each flow on the CPU which services its receive queue.
+
+use super::VirtioNetConfig;
+use crate::display::impl_flags_display;
+use crate::transport::Transport;
+use crate::{Error, Result};
+use bitflags::bitflags;
+use core::mem::offset_of;
+
+/// The maximum length of an RSS hash key, in bytes.
+pub const RSS_MAX_KEY_SIZE: usize = 40;
+/// The maximum length of an RSS indirection table.
+pub const RSS_MAX_INDIRECTION_TABLE_LEN: usize = 128;
+
+bitflags! {
+    /// The kinds of packets for which the device calculates an RSS hash.
+    ///
+    /// For packets of a kind which isn't enabled, the device falls back to a less specific kind
+    /// (e.g. from TCPv4 to IPv4), or sends the packet to the first receive queue.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct RssHashTypes: u32 {
+        /// Hash IPv4 packets by source and destination address.
+        const IPV4 = 1 << 0;
+        /// Hash TCP over IPv4 packets by addresses and ports.
+        const TCPV4 = 1 << 1;
+        /// Hash UDP over IPv4 packets by addresses and ports.
+        const UDPV4 = 1 << 2;
+        /// Hash IPv6 packets by source and destination address.
+        const IPV6 = 1 << 3;
+        /// Hash TCP over IPv6 packets by addresses and ports.
+        const TCPV6 = 1 << 4;
+        /// Hash UDP over IPv6 packets by addresses and ports.
+        const UDPV6 = 1 << 5;
+        /// Hash IPv6 packets using the addresses from extension headers where present.
+        const IP_EX = 1 << 6;
+        /// Hash TCP over IPv6 packets using the addresses from extension headers where present.
+        const TCP_EX = 1 << 7;
+        /// Hash UDP over IPv6 packets using the addresses from extension headers where present.
+        const UDP_EX = 1 << 8;
+    }
+}
+
+impl_flags_display!(RssHashTypes);
+
+/// The RSS capabilities of a device, read from its configuration space.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(super) struct RssLimits {
+    pub max_key_size: usize,
+    pub max_indirection_table_len: usize,
+    pub supported_hash_types: RssHashTypes,
+}
+
+impl RssLimits {
+    /// Reads the RSS capabilities from the configuration space.
+    pub fn read<T: Transport>(transport: &T) -> Result<Self> {
+        let max_key_size: u8 =
+            transport.read_config(offset_of!(VirtioNetConfig, rss_max_key_size))?;
+        let max_indirection_table_len: u16 = transport.read_config(offset_of!(
+            VirtioNetConfig,
+            rss_max_indirection_table_length
+        ))?;
+        Ok(Self {
+            max_key_size: max_key_size.into(),
+            max_indirection_table_len: max_indirection_table_len.into(),
+            supported_hash_types: RssHashTypes::from_bits_truncate(
+                transport.read_config(offset_of!(VirtioNetConfig, supported_hash_types))?,
+            ),
+        })
+    }
+}
+
+/// A Toeplitz hash key for RSS.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct RssKey {
+    bytes: [u8; RSS_MAX_KEY_SIZE],
+    len: usize,
+}
+
+impl RssKey {
+    /// Creates a key from the given bytes.
+    ///
+    /// Returns [`Error::InvalidParam`] if the key is shorter than 4 bytes or longer than
+    /// [`RSS_MAX_KEY_SIZE`].
+    pub fn new(key: &[u8]) -> Result<Self> {
+        if key.len() < 4 || key.len() > RSS_MAX_KEY_SIZE {
+            return Err(Error::InvalidParam);
+        }
+        let mut bytes = [0; RSS_MAX_KEY_SIZE];
+        bytes[..key.len()].copy_from_slice(key);
+        Ok(Self {
+            bytes,
+            len: key.len(),
+        })
+    }
+
+    /// Creates a symmetric key of the given length, by repeating 16 bits of entropy from the
+    /// caller, e.g. from an RNG device.
+    ///
+    /// With a key which repeats every 16 bits, swapping the source and destination addresses and
+    /// ports of a packet doesn't change its hash, so both directions of a flow land on the same
+    /// receive queue. Returns [`Error::InvalidParam`] if the entropy is zero, which would hash
+    /// everything to the same queue, or the length is out of range as for [`new`](Self::new).
+    pub fn symmetric(entropy: u16, len: usize) -> Result<Self> {
+        if entropy == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let mut bytes = [0; RSS_MAX_KEY_SIZE];
+        for pair in bytes.chunks_exact_mut(2) {
+            pair.copy_from_slice(&entropy.to_be_bytes());
+        }
+        Self::new(bytes.get(..len).ok_or(Error::InvalidParam)?)
+    }
+
+    /// Returns the bytes of the key.
+    pub fn as_bytes(&self) -> &[u8] {
+        &self.bytes[..self.len]
+    }
+
+    /// Calculates the Toeplitz hash of the given input with the key.
+    ///
+    /// Bits of the input beyond the key length less 32 are hashed with zeroes, so the input should
+    /// be at most that long; 36 bytes (the longest, for TCP or UDP over IPv6) needs a 40 byte key.
+    pub fn hash(&self, input: &[u8]) -> u32 {
+        let key = self.as_bytes();
+        let key_bit = |index: usize| {
+            key.get(index / 8)
+                .is_some_and(|byte| byte & (0x80 >> (index % 8)) != 0)
+        };
+        let mut window = u32::from_be_bytes([key[0], key[1], key[2], key[3]]);
+        let mut hash = 0;
+        for (i, byte) in input.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    hash ^= window;
+                }
+                window = window << 1 | u32::from(key_bit(32 + i * 8 + bit));
+            }
+        }
+        hash
+    }
+}
+
+/// The RSS configuration of a device: the key, the kinds of packets to hash, and the indirection
+/// table from hashes to receive queues.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct RssMapping {
+    key: RssKey,
+    hash_types: RssHashTypes,
+    table: [u16; RSS_MAX_INDIRECTION_TABLE_LEN],
+    table_len: usize,
+    queue_pairs: u16,
+}
+
+impl RssMapping {
+    /// Creates a mapping which spreads hashes evenly over the given number of receive queues,
+    /// with an indirection table of the given length.
+    ///
+    /// Returns [`Error::InvalidParam`] if there are no queues, or the table length isn't a power of
+    /// two no more than [`RSS_MAX_INDIRECTION_TABLE_LEN`].
+    pub fn new(
+        key: RssKey,
+        hash_types: RssHashTypes,
+        queue_pairs: u16,
+        table_len: usize,
+    ) -> Result<Self> {
+        if queue_pairs == 0
+            || !table_len.is_power_of_two()
+            || table_len > RSS_MAX_INDIRECTION_TABLE_LEN
+        {
+            return Err(Error::InvalidParam);
+        }
+        let mut table = [0; RSS_MAX_INDIRECTION_TABLE_LEN];
+        for (i, entry) in table[..table_len].iter_mut().enumerate() {
+            *entry = (i % usize::from(queue_pairs)) as u16;
+        }
+        Ok(Self {
+            key,
+            hash_types,
+            table,
+            table_len,
+            queue_pairs,
+        })
+    }
+
+    /// Returns the hash key.
+    pub fn key(&self) -> &RssKey {
+        &self.key
+    }
+
+    /// Returns the kinds of packets which are hashed.
+    pub fn hash_types(&self) -> RssHashTypes {
+        self.hash_types
+    }
+
+    /// Returns the indirection table, mapping the low bits of hashes to receive queue numbers.
+    pub fn indirection_table(&self) -> &[u16] {
+        &self.table[..self.table_len]
+    }
+
+    /// Returns the number of queue pairs which the mapping spreads packets over.
+    pub fn queue_pairs(&self) -> u16 {
+        self.queue_pairs
+    }
+
+    /// Returns the receive queue which the device picks for a packet with the given hash.
+    pub fn queue_for_hash(&self, hash: u32) -> u16 {
+        self.table[hash as usize & (self.table_len - 1)]
+    }
+
+    /// Returns the receive queue which the device picks for an IPv4 packet, given its source and
+    /// destination addresses, and its source and destination ports if it is TCP or UDP and the
+    /// corresponding hash type is enabled.
+    pub fn queue_for_ipv4(
+        &self,
+        source: [u8; 4],
+        destination: [u8; 4],
+        ports: Option<(u16, u16)>,
+    ) -> u16 {
+        let mut input = [0; 12];
+        input[0..4].copy_from_slice(&source);
+        input[4..8].copy_from_slice(&destination);
+        self.queue_for_input(&mut input, 8, ports)
+    }
+
+    /// Returns the receive queue which the device picks for an IPv6 packet, as for
+    /// [`queue_for_ipv4`](Self::queue_for_ipv4).
+    pub fn queue_for_ipv6(
+        &self,
+        source: [u8; 16],
+        destination: [u8; 16],
+        ports: Option<(u16, u16)>,
+    ) -> u16 {
+        let mut input = [0; 36];
+        input[0..16].copy_from_slice(&source);
+        input[16..32].copy_from_slice(&destination);
+        self.queue_for_input(&mut input, 32, ports)
+    }
+
+    /// Appends the ports, if any, to the addresses in the first `len` bytes of `input` and returns
+    /// the queue for the resulting hash input.
+    fn queue_for_input(&self, input: &mut [u8], len: usize, ports: Option<(u16, u16)>) -> u16 {
+        let len = match ports {
+            Some((source, destination)) => {
+                input[len..len + 2].copy_from_slice(&source.to_be_bytes());
+                input[len + 2..len + 4].copy_from_slice(&destination.to_be_bytes());
+                len + 4
+            }
+            None => len,
+        };
+        self.queue_for_hash(self.key.hash(&input[..len]))
+    }
+
+    /// Writes the `struct virtio_net_rss_config` for the mapping to the given buffer, returning
+    /// the length written.
+    pub(super) fn write_command(&self, buffer: &mut [u8; RSS_COMMAND_MAX_LEN]) -> usize {
+        let mut len = 0;
+        let mut push = |bytes: &[u8]| {
+            buffer[len..len + bytes.len()].copy_from_slice(bytes);
+            len += bytes.len();
+        };
+        push(&self.hash_types.bits().to_le_bytes());
+        push(&((self.table_len - 1) as u16).to_le_bytes());
+        // Unclassified packets go to the first receive queue.
+        push(&0u16.to_le_bytes());
+        for entry in self.indirection_table() {
+            push(&entry.to_le_bytes());
+        }
+        push(&self.queue_pairs.to_le_bytes());
+        push(&[self.key.len as u8]);
+        push(self.key.as_bytes());
+        len
+    }
+}
+
+/// The maximum length of a `VIRTIO_NET_CTRL_MQ_RSS_CONFIG` command.
+pub(super) const RSS_COMMAND_MAX_LEN: usize =
+    4 + 2 + 2 + 2 * RSS_MAX_INDIRECTION_TABLE_LEN + 2 + 1 + RSS_MAX_KEY_SIZE;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The key from the Microsoft RSS verification suite.
+    const MS_KEY: [u8; 40] = [
+        0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f,
+        0xb0, 0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30,
+        0xf2, 0x0c, 0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+    ];
+
+    #[test]
+    fn toeplitz_verification_suite() {
+        let key = RssKey::new(&MS_KEY).unwrap();
+        // 66.9.149.187:2794 -> 161.142.100.80:1766
+        let mut input = [66, 9, 149, 187, 161, 142, 100, 80, 0, 0, 0, 0];
+        input[8..10].copy_from_slice(&2794u16.to_be_bytes());
+        input[10..12].copy_from_slice(&1766u16.to_be_bytes());
+        assert_eq!(key.hash(&input[..8]), 0x323e8fc2);
+        assert_eq!(key.hash(&input), 0x51ccc178);
+    }
+
+    #[test]
+    fn symmetric_key() {
+        assert_eq!(RssKey::symmetric(0, 40), Err(Error::InvalidParam));
+        assert_eq!(RssKey::symmetric(0x6d5a, 41), Err(Error::InvalidParam));
+        let key = RssKey::symmetric(0x6d5a, 40).unwrap();
+        assert_eq!(&key.as_bytes()[..4], [0x6d, 0x5a, 0x6d, 0x5a]);
+
+        let mapping = RssMapping::new(key, RssHashTypes::TCPV4, 4, 128).unwrap();
+        let (a, b) = ([10, 0, 0, 1], [192, 168, 7, 42]);
+        for ports in [(40000, 443), (1, 2), (65535, 80)] {
+            assert_eq!(
+                mapping.queue_for_ipv4(a, b, Some(ports)),
+                mapping.queue_for_ipv4(b, a, Some((ports.1, ports.0)))
+            );
+        }
+        assert_eq!(key.hash(&[a, b].concat()), key.hash(&[b, a].concat()));
+    }
+
+    #[test]
+    fn mapping() {
+        let key = RssKey::new(&MS_KEY).unwrap();
+        assert_eq!(
+            RssMapping::new(key, RssHashTypes::IPV4, 0, 8),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            RssMapping::new(key, RssHashTypes::IPV4, 2, 6),
+            Err(Error::InvalidParam)
+        );
+        let mapping = RssMapping::new(key, RssHashTypes::IPV4 | RssHashTypes::TCPV4, 3, 8).unwrap();
+        assert_eq!(mapping.indirection_table(), [0, 1, 2, 0, 1, 2, 0, 1]);
+        assert_eq!(mapping.queue_for_hash(0x51ccc178), 0);
+        assert_eq!(mapping.queue_for_hash(0x51ccc179), 1);
+
+        let mut command = [0; RSS_COMMAND_MAX_LEN];
+        let len = mapping.write_command(&mut command);
+        assert_eq!(len, 4 + 2 + 2 + 2 * 8 + 2 + 1 + 40);
+        assert_eq!(command[..8], [0x03, 0, 0, 0, 7, 0, 0, 0]);
+        assert_eq!(command[24..27], [3, 0, 40]);
+        assert_eq!(command[27..len], MS_KEY);
+    }
+}
@@ -0,0 +1,406 @@
+//! Driver for VirtIO 9P transport devices.
+
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::display::impl_flags_display;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::registry::DriverId;
+use crate::transport::{DeviceType, Transport};
+use crate::{Error, Result, PAGE_SIZE};
+use alloc::{string::String, vec, vec::Vec};
+use bitflags::bitflags;
+use core::mem::{offset_of, size_of};
+use log::{info, warn};
+
+#[cfg(test)]
+const QUEUE_REQUEST: u16 = 0;
+const QUEUE_SIZE: usize = 128;
+const SUPPORTED_FEATURES: P9Feature = P9Feature::MOUNT_TAG
+    .union(P9Feature::RING_INDIRECT_DESC)
+    .union(P9Feature::RING_EVENT_IDX);
+
+/// The size of the `size[4] type[1] tag[2]` header at the start of every 9P message.
+const HEADER_SIZE: usize = 7;
+
+/// The maximum message size used until [`VirtIO9p::set_msize`] is called, which is what a client
+/// should propose in its `Tversion` message.
+pub const DEFAULT_MSIZE: usize = 8192;
+
+/// The largest maximum message size the driver supports.
+///
+/// Each message is split into a descriptor per page it touches, so that it need not be physically
+/// contiguous, and a request and its reply must fit in the queue together.
+pub const MAX_MSIZE: usize = (QUEUE_SIZE / 2 - 1) * PAGE_SIZE;
+
+/// Driver for a VirtIO 9P transport device, which carries 9P2000.L messages to a file server in the
+/// host, such as for a directory shared with QEMU's `-virtfs` option.
+///
+/// The driver only transports messages; building and parsing them, and so mounting the shared
+/// directory, is up to the caller. Each request is sent with [`send_msg`](Self::send_msg), which
+/// waits for the server's reply.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::p9::{VirtIO9p, DEFAULT_MSIZE};
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut p9 = VirtIO9p::<HalImpl, _>::new(transport)?;
+/// println!("Mount tag {:?}", p9.mount_tag());
+///
+/// // Tversion, with tag NOTAG.
+/// let mut tversion = Vec::new();
+/// tversion.extend_from_slice(&21u32.to_le_bytes());
+/// tversion.push(100);
+/// tversion.extend_from_slice(&0xffffu16.to_le_bytes());
+/// tversion.extend_from_slice(&(DEFAULT_MSIZE as u32).to_le_bytes());
+/// tversion.extend_from_slice(&8u16.to_le_bytes());
+/// tversion.extend_from_slice(b"9P2000.L");
+/// let rversion = p9.send_msg(&tversion)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIO9p<H: Hal, T: Transport> {
+    id: DriverId,
+    transport: T,
+    queues: QueueLayout,
+    queue: VirtQueue<H, QUEUE_SIZE>,
+    mount_tag: Option<String>,
+    msize: usize,
+}
+
+impl<H: Hal, T: Transport> VirtIO9p<H, T> {
+    /// Creates a new VirtIO 9P driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let mount_tag = if negotiated_features.contains(P9Feature::MOUNT_TAG) {
+            Some(read_mount_tag(&transport)?)
+        } else {
+            None
+        };
+        info!("VirtIO 9P mount tag {:?}", mount_tag);
+        let queues = QueueLayout::new(DeviceType::_9P, negotiated_features.bits(), 0)?;
+        let index =
+            queues.checked_index(&mut transport, QueueRole::Request(0), QUEUE_SIZE as u32)?;
+        let queue = VirtQueue::new(
+            &mut transport,
+            index,
+            negotiated_features.contains(P9Feature::RING_INDIRECT_DESC),
+            negotiated_features.contains(P9Feature::RING_EVENT_IDX),
+        )?;
+        transport.finish_init();
+
+        Ok(Self {
+            id: DriverId::allocate(),
+            transport,
+            queues,
+            queue,
+            mount_tag,
+            msize: DEFAULT_MSIZE,
+        })
+    }
+
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
+    /// Returns the tag which identifies the shared directory to mount, if the device has one.
+    pub fn mount_tag(&self) -> Option<&str> {
+        self.mount_tag.as_deref()
+    }
+
+    /// Returns the maximum size of a message, in either direction.
+    pub fn msize(&self) -> usize {
+        self.msize
+    }
+
+    /// Sets the maximum size of a message, as agreed with the server in its `Rversion` reply.
+    ///
+    /// Returns [`Error::InvalidParam`] if it is too small for a message header or larger than
+    /// [`MAX_MSIZE`].
+    pub fn set_msize(&mut self, msize: usize) -> Result {
+        if !(HEADER_SIZE..=MAX_MSIZE).contains(&msize) {
+            return Err(Error::InvalidParam);
+        }
+        self.msize = msize;
+        Ok(())
+    }
+
+    /// Sends the given 9P message to the server, waits for its reply, and returns the reply.
+    ///
+    /// The request must be a whole message whose size field matches its length, and no longer than
+    /// [`msize`](Self::msize), otherwise this returns [`Error::InvalidParam`]. Both the request and
+    /// reply are split into a descriptor per page, so large payloads such as the data of `Twrite`
+    /// and `Rread` need not be physically contiguous.
+    ///
+    /// Returns [`Error::IoError`] if the reply is malformed or doesn't have the request's tag. An
+    /// `Rlerror` reply is returned like any other, for the caller to decode.
+    pub fn send_msg(&mut self, request: &[u8]) -> Result<Vec<u8>> {
+        let Some(size) = message_size(request) else {
+            return Err(Error::InvalidParam);
+        };
+        if size != request.len() || size > self.msize {
+            return Err(Error::InvalidParam);
+        }
+        let mut response = vec![0; self.msize];
+        let written = self.queue.add_notify_wait_pop(
+            &page_segments(request),
+            &mut page_segments_mut(&mut response),
+            &mut self.transport,
+        )? as usize;
+
+        let len = match message_size(&response[..written.min(response.len())]) {
+            Some(len) if len <= written => len,
+            _ => {
+                warn!("Malformed 9P reply of {} bytes", written);
+                return Err(Error::IoError);
+            }
+        };
+        if response[5..7] != request[5..7] {
+            warn!("9P reply has the wrong tag");
+            return Err(Error::IoError);
+        }
+        response.truncate(len);
+        Ok(response)
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIO9p<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        for (index, _) in self.queues.queues() {
+            self.transport.queue_unset(index);
+        }
+    }
+}
+
+/// Returns the size of the 9P message starting at the beginning of the given buffer, or `None` if
+/// the buffer is too short to hold a message header or the size is too small for one.
+fn message_size(message: &[u8]) -> Option<usize> {
+    if message.len() < HEADER_SIZE {
+        return None;
+    }
+    let size = u32::from_le_bytes([message[0], message[1], message[2], message[3]]) as usize;
+    if size < HEADER_SIZE {
+        return None;
+    }
+    Some(size)
+}
+
+/// Splits the given buffer at page boundaries.
+fn page_segments(mut buffer: &[u8]) -> Vec<&[u8]> {
+    let mut segments = Vec::new();
+    while !buffer.is_empty() {
+        let (segment, rest) = buffer.split_at(page_remaining(buffer.as_ptr(), buffer.len()));
+        segments.push(segment);
+        buffer = rest;
+    }
+    segments
+}
+
+/// Splits the given mutable buffer at page boundaries.
+fn page_segments_mut(mut buffer: &mut [u8]) -> Vec<&mut [u8]> {
+    let mut segments = Vec::new();
+    while !buffer.is_empty() {
+        let len = page_remaining(buffer.as_ptr(), buffer.len());
+        let (segment, rest) = buffer.split_at_mut(len);
+        segments.push(segment);
+        buffer = rest;
+    }
+    segments
+}
+
+/// Returns the number of bytes from `start` to the end of its page, or `len` if that is less.
+fn page_remaining(start: *const u8, len: usize) -> usize {
+    (PAGE_SIZE - start as usize % PAGE_SIZE).min(len)
+}
+
+/// Reads the mount tag, which is `tag_len` bytes immediately after the config space fields.
+///
+/// The tag is truncated to the end of the config space if the device reports a longer one.
+fn read_mount_tag(transport: &impl Transport) -> Result<String> {
+    let reported_len = usize::from(transport.read_config::<u16>(offset_of!(Config, tag_len))?);
+    let available = transport
+        .config_space_size()
+        .saturating_sub(size_of::<Config>());
+    let tag_len = if reported_len > available {
+        warn!(
+            "9P mount tag length {} runs past the end of the config space, truncating to {}",
+            reported_len, available
+        );
+        available
+    } else {
+        reported_len
+    };
+    let tag = (0..tag_len)
+        .map(|i| transport.read_config::<u8>(size_of::<Config>() + i))
+        .collect::<Result<Vec<u8>>>()?;
+    // The tag isn't NUL terminated, but QEMU pads it with NULs on some versions.
+    let tag = tag.split(|&byte| byte == 0).next().unwrap_or_default();
+    Ok(String::from_utf8_lossy(tag).into_owned())
+}
+
+/// `struct virtio_9p_config`, without the variable-length tag which follows it.
+#[repr(C)]
+struct Config {
+    tag_len: u16,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct P9Feature: u64 {
+        /// The device has a mount tag in its config space.
+        const MOUNT_TAG             = 1 << 0;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // the following since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+impl_flags_display!(P9Feature);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{boxed::Box, sync::Arc};
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+
+    /// The config space of a device with the mount tag `share`.
+    #[repr(C)]
+    struct TagConfig {
+        config: Config,
+        tag: [u8; 6],
+    }
+
+    fn fake_9p(
+        device_features: u64,
+    ) -> (
+        VirtIO9p<FakeHal, FakeTransport<TagConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        fake_9p_with_tag(device_features, 5, *b"share\0")
+    }
+
+    fn fake_9p_with_tag(
+        device_features: u64,
+        tag_len: u16,
+        tag: [u8; 6],
+    ) -> (
+        VirtIO9p<FakeHal, FakeTransport<TagConfig>>,
+        Arc<Mutex<State>>,
+    ) {
+        let config_space = Box::leak(Box::new(TagConfig {
+            config: Config { tag_len },
+            tag,
+        }));
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::_9P,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features,
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        (VirtIO9p::new(transport).unwrap(), state)
+    }
+
+    #[test]
+    fn mount_tag() {
+        let (p9, _) = fake_9p(P9Feature::MOUNT_TAG.bits());
+        assert_eq!(p9.mount_tag(), Some("share"));
+        let (p9, _) = fake_9p(0);
+        assert_eq!(p9.mount_tag(), None);
+    }
+
+    #[test]
+    fn mount_tag_past_config_space() {
+        // The tag length runs past the end of the config space, so only the part inside it is read.
+        let (p9, _) = fake_9p_with_tag(P9Feature::MOUNT_TAG.bits(), 200, *b"shares");
+        assert_eq!(p9.mount_tag(), Some("shares"));
+    }
+
+    #[test]
+    fn send_msg() {
+        let (mut p9, state) = fake_9p(0);
+        p9.set_msize(3 * PAGE_SIZE).unwrap();
+
+        // A Twrite larger than a page, which gets split across several descriptors.
+        let mut twrite = Vec::new();
+        twrite.extend_from_slice(&(HEADER_SIZE as u32 + 6000).to_le_bytes());
+        twrite.push(118);
+        twrite.extend_from_slice(&7u16.to_le_bytes());
+        twrite.resize(HEADER_SIZE + 6000, 0x42);
+
+        // Start a thread to simulate the server handling the request.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_REQUEST);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<QUEUE_SIZE>(QUEUE_REQUEST, |request| {
+                    assert_eq!(request.len(), HEADER_SIZE + 6000);
+                    assert!(request[HEADER_SIZE..].iter().all(|&byte| byte == 0x42));
+                    // Rwrite, with tag 7 and a count of 6000.
+                    let mut response = vec![11, 0, 0, 0, 119, 7, 0];
+                    response.extend_from_slice(&6000u32.to_le_bytes());
+                    response
+                });
+        });
+
+        let response = p9.send_msg(&twrite).unwrap();
+        assert_eq!(response, [11, 0, 0, 0, 119, 7, 0, 0x70, 0x17, 0, 0]);
+        handle.join().unwrap();
+
+        // Messages whose size field is wrong or which are larger than msize are rejected.
+        assert_eq!(p9.send_msg(&twrite[..100]), Err(Error::InvalidParam));
+        assert_eq!(p9.send_msg(&[0; 4]), Err(Error::InvalidParam));
+        p9.set_msize(PAGE_SIZE).unwrap();
+        assert_eq!(p9.send_msg(&twrite), Err(Error::InvalidParam));
+        assert_eq!(p9.set_msize(MAX_MSIZE + 1), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn segments() {
+        let buffer = vec![0u8; 3 * PAGE_SIZE];
+        let offset = page_remaining(buffer.as_ptr(), buffer.len()) % PAGE_SIZE + 100;
+        let segments = page_segments(&buffer[offset..offset + PAGE_SIZE + 1]);
+        assert_eq!(
+            segments.iter().map(|s| s.len()).collect::<Vec<_>>(),
+            [PAGE_SIZE - 100, 101]
+        );
+    }
+}
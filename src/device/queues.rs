@@ -0,0 +1,405 @@
+//! Discovery of the virtqueues which a device has, from its type and negotiated features.
+//!
+//! How many virtqueues a device has, and which index each of them is at, depends on the device
+//! type, on which features were negotiated, and for some devices on a count in the configuration
+//! space. For example a network device with `VIRTIO_NET_F_MQ` has `2N` receive and transmit queues
+//! followed by its control queue, where `N` is `max_virtqueue_pairs`, while a SCSI host has its
+//! control and event queues followed by `num_queues` request queues. [`QueueLayout`] works this
+//! out in one place, so that drivers don't hard-code indices which move when a feature is
+//! negotiated, and checks that the transport actually provides every queue.
+
+use crate::transport::{DeviceType, Transport};
+use crate::{Error, Result};
+use core::convert::TryFrom;
+use log::warn;
+
+/// `VIRTIO_NET_F_CTRL_VQ`
+const NET_CTRL_VQ: u64 = 1 << 17;
+/// `VIRTIO_NET_F_MQ`
+const NET_MQ: u64 = 1 << 22;
+/// `VIRTIO_NET_F_RSS`
+const NET_RSS: u64 = 1 << 60;
+/// `VIRTIO_BLK_F_MQ`
+const BLK_MQ: u64 = 1 << 12;
+/// `VIRTIO_CONSOLE_F_MULTIPORT`
+const CONSOLE_MULTIPORT: u64 = 1 << 1;
+/// `VIRTIO_BALLOON_F_STATS_VQ`
+const BALLOON_STATS_VQ: u64 = 1 << 1;
+/// `VIRTIO_BALLOON_F_FREE_PAGE_HINT`
+const BALLOON_FREE_PAGE_HINT: u64 = 1 << 3;
+/// `VIRTIO_BALLOON_F_PAGE_REPORTING`
+const BALLOON_PAGE_REPORTING: u64 = 1 << 5;
+/// `VIRTIO_FS_F_NOTIFICATION`
+const FS_NOTIFICATION: u64 = 1 << 0;
+
+/// What a virtqueue is used for.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum QueueRole {
+    /// The receive queue of the given queue pair or console port.
+    Receive(u16),
+    /// The transmit queue of the given queue pair or console port.
+    Transmit(u16),
+    /// The request queue with the given number, for block, entropy, 9P and file system devices and
+    /// SCSI hosts, or the data queue with the given number for crypto devices.
+    Request(u16),
+    /// The file system high priority queue.
+    HighPriority,
+    /// The file system notification queue.
+    Notification,
+    /// The control queue.
+    Control,
+    /// The queue on which a multiport console sends control messages to the driver.
+    ControlReceive,
+    /// The queue on which the driver sends control messages to a multiport console.
+    ControlTransmit,
+    /// The queue on which the device sends events to the driver.
+    Event,
+    /// The queue on which the driver sends status updates to an input device.
+    Status,
+    /// The GPU cursor queue.
+    Cursor,
+    /// The balloon inflate queue.
+    Inflate,
+    /// The balloon deflate queue.
+    Deflate,
+    /// The balloon statistics queue.
+    Stats,
+    /// The balloon free page hinting queue.
+    FreePageHint,
+    /// The balloon free page reporting queue.
+    Reporting,
+}
+
+/// The virtqueues of a device, as determined by its type and negotiated features.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct QueueLayout {
+    device_type: DeviceType,
+    features: u64,
+    /// The number of queue pairs, request queues or ports, where that depends on the device.
+    instances: u16,
+    len: u16,
+}
+
+impl QueueLayout {
+    /// Works out the virtqueues of a device of the given type with the given negotiated features.
+    ///
+    /// `instances` is the count from the device's configuration space which the number of queues
+    /// depends on, if any: `max_virtqueue_pairs` for a network device with `VIRTIO_NET_F_MQ` or
+    /// `VIRTIO_NET_F_RSS`, `num_queues` for a block device with `VIRTIO_BLK_F_MQ` or for a SCSI
+    /// host, `max_nr_ports` for a console with `VIRTIO_CONSOLE_F_MULTIPORT`, `max_dataqueues` for a
+    /// crypto device and `num_request_queues` for a file system device. It is ignored otherwise.
+    ///
+    /// Returns [`Error::InvalidParam`] if `instances` is needed but zero, or gives more queues than
+    /// can be addressed, and [`Error::Unsupported`] for device types whose queues aren't known.
+    pub fn new(device_type: DeviceType, features: u64, instances: u16) -> Result<Self> {
+        let has = |feature: u64| features & feature != 0;
+        let counted = match device_type {
+            DeviceType::Network => has(NET_MQ | NET_RSS),
+            DeviceType::Block => has(BLK_MQ),
+            DeviceType::Console => has(CONSOLE_MULTIPORT),
+            DeviceType::ScsiHost | DeviceType::Crypto | DeviceType::FileSystem => true,
+            _ => false,
+        };
+        if counted && instances == 0 {
+            warn!(
+                "{} reports no queues with features {:#x}",
+                device_type.name(),
+                features
+            );
+            return Err(Error::InvalidParam);
+        }
+        let instances = if counted { instances } else { 1 };
+
+        let n = u32::from(instances);
+        let len = match device_type {
+            DeviceType::Network => 2 * n + u32::from(has(NET_CTRL_VQ)),
+            DeviceType::Block => n,
+            DeviceType::Console if has(CONSOLE_MULTIPORT) => 2 * n + 2,
+            DeviceType::Console => 2,
+            DeviceType::EntropySource | DeviceType::_9P => 1,
+            DeviceType::MemoryBallooning | DeviceType::MemoryBalloon => {
+                2 + [
+                    BALLOON_STATS_VQ,
+                    BALLOON_FREE_PAGE_HINT,
+                    BALLOON_PAGE_REPORTING,
+                ]
+                .iter()
+                .filter(|&&feature| has(feature))
+                .count() as u32
+            }
+            DeviceType::ScsiHost => 2 + n,
+            DeviceType::GPU | DeviceType::Input => 2,
+            DeviceType::Socket => 3,
+            DeviceType::Crypto => n + 1,
+            DeviceType::FileSystem => 1 + u32::from(has(FS_NOTIFICATION)) + n,
+            DeviceType::Sound => 4,
+            _ => return Err(Error::Unsupported),
+        };
+        Ok(Self {
+            device_type,
+            features,
+            instances,
+            len: u16::try_from(len).map_err(|_| Error::InvalidParam)?,
+        })
+    }
+
+    /// Returns the number of virtqueues the device has.
+    pub fn len(&self) -> u16 {
+        self.len
+    }
+
+    /// Returns whether the device has no virtqueues.
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Returns the number of network queue pairs, request or data queues, or console ports.
+    pub fn instances(&self) -> u16 {
+        self.instances
+    }
+
+    /// Returns the role of the virtqueue with the given index, or `None` if there is no such
+    /// queue.
+    pub fn role(&self, index: u16) -> Option<QueueRole> {
+        if index >= self.len {
+            return None;
+        }
+        let has = |feature: u64| self.features & feature != 0;
+        let pair = |index: u16| {
+            if index & 1 == 0 {
+                QueueRole::Receive(index / 2)
+            } else {
+                QueueRole::Transmit(index / 2)
+            }
+        };
+        Some(match self.device_type {
+            DeviceType::Network if index == 2 * self.instances => QueueRole::Control,
+            DeviceType::Network => pair(index),
+            DeviceType::Block | DeviceType::EntropySource | DeviceType::_9P => {
+                QueueRole::Request(index)
+            }
+            DeviceType::Crypto if index == self.instances => QueueRole::Control,
+            DeviceType::Crypto => QueueRole::Request(index),
+            DeviceType::FileSystem => match (index, has(FS_NOTIFICATION)) {
+                (0, _) => QueueRole::HighPriority,
+                (1, true) => QueueRole::Notification,
+                (_, true) => QueueRole::Request(index - 2),
+                (_, false) => QueueRole::Request(index - 1),
+            },
+            DeviceType::Sound => [
+                QueueRole::Control,
+                QueueRole::Event,
+                QueueRole::Transmit(0),
+                QueueRole::Receive(0),
+            ][usize::from(index)],
+            DeviceType::Console => match index {
+                0 | 1 => pair(index),
+                2 => QueueRole::ControlReceive,
+                3 => QueueRole::ControlTransmit,
+                // Port 1 onwards come after the control queues.
+                _ => pair(index - 2),
+            },
+            DeviceType::ScsiHost => match index {
+                0 => QueueRole::Control,
+                1 => QueueRole::Event,
+                _ => QueueRole::Request(index - 2),
+            },
+            DeviceType::GPU => [QueueRole::Control, QueueRole::Cursor][usize::from(index)],
+            DeviceType::Input => [QueueRole::Event, QueueRole::Status][usize::from(index)],
+            DeviceType::Socket => [
+                QueueRole::Receive(0),
+                QueueRole::Transmit(0),
+                QueueRole::Event,
+            ][usize::from(index)],
+            // Ballooning queues which aren't negotiated are skipped over.
+            _ => {
+                [
+                    (0, QueueRole::Inflate),
+                    (0, QueueRole::Deflate),
+                    (BALLOON_STATS_VQ, QueueRole::Stats),
+                    (BALLOON_FREE_PAGE_HINT, QueueRole::FreePageHint),
+                    (BALLOON_PAGE_REPORTING, QueueRole::Reporting),
+                ]
+                .iter()
+                .filter(|(feature, _)| *feature == 0 || has(*feature))
+                .nth(usize::from(index))?
+                .1
+            }
+        })
+    }
+
+    /// Returns the index of the virtqueue with the given role, or `None` if the device doesn't
+    /// have one.
+    pub fn index(&self, role: QueueRole) -> Option<u16> {
+        self.queues()
+            .find(|&(_, queue_role)| queue_role == role)
+            .map(|(index, _)| index)
+    }
+
+    /// Returns an iterator over the index and role of each of the device's virtqueues.
+    pub fn queues(&self) -> impl Iterator<Item = (u16, QueueRole)> + '_ {
+        (0..self.len).filter_map(move |index| Some((index, self.role(index)?)))
+    }
+
+    /// Returns the index of the virtqueue with the given role, after checking that the transport
+    /// supports a queue of at least `size` entries there.
+    ///
+    /// Returns [`Error::InvalidParam`] if the device has no such queue or its maximum size is too
+    /// small.
+    pub fn checked_index<T: Transport + ?Sized>(
+        &self,
+        transport: &mut T,
+        role: QueueRole,
+        size: u32,
+    ) -> Result<u16> {
+        let index = self.index(role).ok_or(Error::InvalidParam)?;
+        let max_size = transport.max_queue_size(index);
+        if max_size < size {
+            warn!(
+                "{} queue {} ({:?}) has maximum size {}, but {} is needed",
+                self.device_type.name(),
+                index,
+                role,
+                max_size,
+                size
+            );
+            return Err(Error::InvalidParam);
+        }
+        Ok(index)
+    }
+
+    /// Checks that the transport provides every virtqueue of the layout, i.e. that none of them
+    /// has a maximum size of zero.
+    ///
+    /// Returns [`Error::InvalidParam`] if one is missing, which means that the device's
+    /// configuration space claims more queues than it has.
+    pub fn validate<T: Transport + ?Sized>(&self, transport: &mut T) -> Result {
+        for (index, role) in self.queues() {
+            if transport.max_queue_size(index) == 0 {
+                warn!(
+                    "{} has no queue {} ({:?})",
+                    self.device_type.name(),
+                    index,
+                    role
+                );
+                return Err(Error::InvalidParam);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::fake::{FakeTransport, State};
+    use alloc::{sync::Arc, vec::Vec};
+    use core::ptr::NonNull;
+    use std::sync::Mutex;
+
+    #[test]
+    fn net_queues() {
+        let layout = QueueLayout::new(DeviceType::Network, NET_CTRL_VQ, 4).unwrap();
+        assert_eq!(
+            layout.queues().collect::<Vec<_>>(),
+            [
+                (0, QueueRole::Receive(0)),
+                (1, QueueRole::Transmit(0)),
+                (2, QueueRole::Control)
+            ]
+        );
+
+        let layout = QueueLayout::new(DeviceType::Network, NET_CTRL_VQ | NET_MQ, 4).unwrap();
+        assert_eq!(layout.len(), 9);
+        assert_eq!(layout.role(5), Some(QueueRole::Transmit(2)));
+        assert_eq!(layout.index(QueueRole::Receive(3)), Some(6));
+        assert_eq!(layout.index(QueueRole::Control), Some(8));
+        assert_eq!(layout.role(9), None);
+
+        assert_eq!(
+            QueueLayout::new(DeviceType::Network, NET_RSS, 0),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn other_queues() {
+        let layout = QueueLayout::new(DeviceType::ScsiHost, 0, 2).unwrap();
+        assert_eq!(
+            layout.queues().map(|(_, role)| role).collect::<Vec<_>>(),
+            [
+                QueueRole::Control,
+                QueueRole::Event,
+                QueueRole::Request(0),
+                QueueRole::Request(1)
+            ]
+        );
+
+        let layout = QueueLayout::new(DeviceType::Console, CONSOLE_MULTIPORT, 3).unwrap();
+        assert_eq!(layout.len(), 8);
+        assert_eq!(layout.index(QueueRole::ControlTransmit), Some(3));
+        assert_eq!(layout.index(QueueRole::Receive(1)), Some(4));
+        assert_eq!(layout.index(QueueRole::Transmit(2)), Some(7));
+
+        let layout = QueueLayout::new(
+            DeviceType::MemoryBalloon,
+            BALLOON_STATS_VQ | BALLOON_PAGE_REPORTING,
+            0,
+        )
+        .unwrap();
+        assert_eq!(layout.len(), 4);
+        assert_eq!(layout.index(QueueRole::Reporting), Some(3));
+        assert_eq!(layout.index(QueueRole::FreePageHint), None);
+
+        let layout = QueueLayout::new(DeviceType::Crypto, 0, 2).unwrap();
+        assert_eq!(layout.len(), 3);
+        assert_eq!(layout.index(QueueRole::Request(1)), Some(1));
+        assert_eq!(layout.index(QueueRole::Control), Some(2));
+
+        let layout = QueueLayout::new(DeviceType::FileSystem, 0, 2).unwrap();
+        assert_eq!(layout.index(QueueRole::HighPriority), Some(0));
+        assert_eq!(layout.index(QueueRole::Request(0)), Some(1));
+        assert_eq!(layout.index(QueueRole::Notification), None);
+        let layout = QueueLayout::new(DeviceType::FileSystem, FS_NOTIFICATION, 2).unwrap();
+        assert_eq!(layout.len(), 4);
+        assert_eq!(layout.index(QueueRole::Notification), Some(1));
+        assert_eq!(layout.index(QueueRole::Request(1)), Some(3));
+
+        let layout = QueueLayout::new(DeviceType::Sound, 0, 0).unwrap();
+        assert_eq!(layout.index(QueueRole::Receive(0)), Some(3));
+
+        assert_eq!(
+            QueueLayout::new(DeviceType::IOMMU, 0, 1),
+            Err(Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn checks_max_size() {
+        let mut config_space = ();
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Network,
+            max_queue_size: 8,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: Arc::new(Mutex::new(State::default())),
+        };
+        let layout = QueueLayout::new(DeviceType::Network, NET_CTRL_VQ, 1).unwrap();
+        assert_eq!(layout.validate(&mut transport), Ok(()));
+        assert_eq!(
+            layout.checked_index(&mut transport, QueueRole::Control, 8),
+            Ok(2)
+        );
+        assert_eq!(
+            layout.checked_index(&mut transport, QueueRole::Control, 16),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            layout.checked_index(&mut transport, QueueRole::Event, 8),
+            Err(Error::InvalidParam)
+        );
+
+        transport.max_queue_size = 0;
+        assert_eq!(layout.validate(&mut transport), Err(Error::InvalidParam));
+    }
+}
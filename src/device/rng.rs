@@ -0,0 +1,352 @@
+//! Driver for VirtIO entropy devices.
+
+use super::common::Feature;
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::hal::Hal;
+use crate::queue::{VirtQueue, VirtQueueState};
+use crate::registry::DriverId;
+use crate::retry::RetryPolicy;
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::{Error, Result};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+#[cfg(test)]
+const QUEUE_REQUEST: u16 = 0;
+const QUEUE_SIZE: usize = 8;
+const SUPPORTED_FEATURES: Feature = Feature::RING_INDIRECT_DESC.union(Feature::RING_EVENT_IDX);
+
+/// Driver for a VirtIO entropy device, which supplies random bytes from the host.
+///
+/// This is useful to seed a kernel's own random number generator early in boot. The bytes can be
+/// requested either with the blocking [`fill`](Self::fill), or by posting a buffer with
+/// [`fill_nb`](Self::fill_nb) and completing it with [`complete_fill`](Self::complete_fill) once
+/// [`peek_used`](Self::peek_used) returns its token.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::rng::VirtIORng;
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut rng = VirtIORng::<HalImpl, _>::new(transport)?;
+///
+/// let mut seed = [0; 32];
+/// rng.fill(&mut seed)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIORng<H: Hal, T: Transport> {
+    id: DriverId,
+    transport: T,
+    queues: QueueLayout,
+    queue: VirtQueue<H, QUEUE_SIZE>,
+    negotiated_features: Feature,
+    /// How blocking requests are retried after transient failures.
+    retry_policy: RetryPolicy,
+}
+
+impl<H: Hal, T: Transport> VirtIORng<H, T> {
+    /// Creates a new VirtIO entropy driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+        let mut rng = Self::attach(transport, negotiated_features, |transport, index| {
+            VirtQueue::new(
+                transport,
+                index,
+                negotiated_features.contains(Feature::RING_INDIRECT_DESC),
+                negotiated_features.contains(Feature::RING_EVENT_IDX),
+            )
+        })?;
+        rng.transport.finish_init();
+        Ok(rng)
+    }
+
+    /// Restores a driver from the state saved by [`VirtIORng::save`], without resetting the device.
+    ///
+    /// Requests which were in flight when the state was saved can be completed as usual with
+    /// [`VirtIORng::complete_fill`]. If restoring fails then the transport is dropped, which resets
+    /// the device.
+    ///
+    /// # Safety
+    ///
+    /// The state must have been saved by a driver using the same HAL for the same device as the
+    /// transport, and the device must not have been reset since. Each saved state must be restored
+    /// at most once.
+    pub unsafe fn restore(transport: T, state: &RngState) -> Result<Self> {
+        if !transport.get_status().contains(DeviceStatus::DRIVER_OK) {
+            return Err(Error::NotReady);
+        }
+        let negotiated_features = Feature::from_bits_truncate(state.features);
+        Self::attach(transport, negotiated_features, |transport, index| {
+            if state.queue.layout.queue_index != index {
+                return Err(Error::InvalidParam);
+            }
+            // SAFETY: Our caller promises that the state was saved from this device's queue.
+            unsafe { VirtQueue::restore(transport, &state.queue) }
+        })
+    }
+
+    /// Saves the state of the driver, so that it can be restored by [`VirtIORng::restore`]
+    /// without resetting the device, e.g. across an in-place update of the kernel.
+    ///
+    /// The device keeps running and the memory of the request queue stays allocated, so requests
+    /// posted with [`fill_nb`](Self::fill_nb) may still be in flight. Their buffers must stay valid
+    /// until they are completed by the restored driver. The retry policy isn't saved.
+    ///
+    /// If the state can't be saved yet because a request using indirect descriptors is in flight,
+    /// the driver is returned along with [`Error::NotReady`].
+    // The driver is returned on error so that it can keep running, which is no bigger than passing
+    // it in.
+    #[allow(clippy::result_large_err)]
+    pub fn save(mut self) -> core::result::Result<RngState, (Self, Error)> {
+        let queue = match self.queue.save() {
+            Ok(queue) => queue,
+            Err(e) => return Err((self, e)),
+        };
+        let state = RngState {
+            features: self.negotiated_features.bits(),
+            queue,
+        };
+        // Leak the driver rather than dropping it, so the device isn't reset and the queue's memory
+        // isn't freed.
+        core::mem::forget(self);
+        Ok(state)
+    }
+
+    /// Sets up the driver around the request queue returned by `queue`, which is given the index
+    /// of the queue.
+    fn attach(
+        mut transport: T,
+        negotiated_features: Feature,
+        queue: impl FnOnce(&mut T, u16) -> Result<VirtQueue<H, QUEUE_SIZE>>,
+    ) -> Result<Self> {
+        let queues = QueueLayout::new(DeviceType::EntropySource, negotiated_features.bits(), 0)?;
+        let index =
+            queues.checked_index(&mut transport, QueueRole::Request(0), QUEUE_SIZE as u32)?;
+        let queue = queue(&mut transport, index)?;
+
+        Ok(Self {
+            id: DriverId::allocate(),
+            transport,
+            queues,
+            queue,
+            negotiated_features,
+            retry_policy: RetryPolicy::default(),
+        })
+    }
+
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
+    /// Fills the given buffer with random bytes from the device, blocking until it is full.
+    ///
+    /// The device may supply fewer bytes than asked for at once, so this may take several requests.
+    /// Returns [`Error::IoError`] if the device supplies no bytes at all for a request.
+    pub fn fill(&mut self, buf: &mut [u8]) -> Result {
+        let mut filled = 0;
+        while filled < buf.len() {
+            let written = self.retry_policy.run(|| {
+                self.queue
+                    .add_notify_wait_pop(&[], &mut [&mut buf[filled..]], &mut self.transport)
+            })? as usize;
+            if written == 0 {
+                return Err(Error::IoError);
+            }
+            filled += written.min(buf.len() - filled);
+        }
+        Ok(())
+    }
+
+    /// Sets how [`fill`](Self::fill) retries requests which fail transiently, e.g. because the
+    /// queue is full of requests posted with [`fill_nb`](Self::fill_nb). The default is to never
+    /// retry.
+    pub fn set_retry_policy(&mut self, policy: RetryPolicy) {
+        self.retry_policy = policy;
+    }
+
+    /// Posts the given buffer for the device to write random bytes to, and returns a token for the
+    /// request without waiting for it to complete.
+    ///
+    /// Returns [`Error::InvalidParam`] if the buffer is empty, or [`Error::QueueFull`] if too many
+    /// requests are already outstanding.
+    ///
+    /// # Safety
+    ///
+    /// `buf` is still borrowed by the device after this returns, so the caller must not access it
+    /// until the request has been completed by passing the same buffer to
+    /// [`complete_fill`](Self::complete_fill).
+    pub unsafe fn fill_nb(&mut self, buf: &mut [u8]) -> Result<u16> {
+        if buf.is_empty() {
+            return Err(Error::InvalidParam);
+        }
+        let token = self.queue.add(&[], &mut [buf])?;
+        if self.queue.should_notify() {
+            self.queue.notify(&mut self.transport);
+        }
+        Ok(token)
+    }
+
+    /// Returns the token of the next completed request, if any, without removing it from the used
+    /// ring.
+    pub fn peek_used(&self) -> Option<u16> {
+        self.queue.peek_used()
+    }
+
+    /// Completes a request started by [`fill_nb`](Self::fill_nb), and returns the number of random
+    /// bytes the device wrote to the start of the buffer.
+    ///
+    /// This may be fewer than the length of the buffer.
+    ///
+    /// # Safety
+    ///
+    /// The same buffer must be passed in again as was passed to [`fill_nb`](Self::fill_nb) when it
+    /// returned the token.
+    pub unsafe fn complete_fill(&mut self, token: u16, buf: &mut [u8]) -> Result<usize> {
+        let written = self.queue.pop_used(token, &[], &mut [&mut *buf])? as usize;
+        Ok(written.min(buf.len()))
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+}
+
+/// The state of an entropy device driver saved by [`VirtIORng::save`].
+///
+/// The struct has a fixed layout with no padding, so it can be serialised with
+/// [`AsBytes::as_bytes`] and parsed with [`FromBytes::read_from`].
+#[repr(C)]
+#[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct RngState {
+    /// The features negotiated with the device.
+    pub features: u64,
+    /// The state of the request queue.
+    pub queue: VirtQueueState,
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIORng<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        for (index, _) in self.queues.queues() {
+            self.transport.queue_unset(index);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+
+    fn fake_rng() -> (VirtIORng<FakeHal, FakeTransport<()>>, Arc<Mutex<State>>) {
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::EntropySource,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: 0,
+            config_space: NonNull::dangling(),
+            state: state.clone(),
+        };
+        (VirtIORng::new(transport).unwrap(), state)
+    }
+
+    #[test]
+    fn fill() {
+        let (mut rng, state) = fake_rng();
+
+        // Start a thread to simulate the device, which only supplies 6 bytes at a time.
+        let handle = thread::spawn(move || {
+            for _ in 0..2 {
+                State::wait_until_queue_notified(&state, QUEUE_REQUEST);
+                state
+                    .lock()
+                    .unwrap()
+                    .write_to_queue::<QUEUE_SIZE>(QUEUE_REQUEST, &[0x42; 6]);
+            }
+        });
+
+        let mut buf = [0; 12];
+        rng.fill(&mut buf).unwrap();
+        assert_eq!(buf, [0x42; 12]);
+        handle.join().unwrap();
+
+        // Nothing needs to be requested to fill an empty buffer.
+        rng.fill(&mut []).unwrap();
+    }
+
+    #[test]
+    fn fill_nb() {
+        let (mut rng, state) = fake_rng();
+
+        let mut buf = [0; 16];
+        assert_eq!(unsafe { rng.fill_nb(&mut []) }, Err(Error::InvalidParam));
+        let token = unsafe { rng.fill_nb(&mut buf) }.unwrap();
+        assert_eq!(rng.peek_used(), None);
+
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_REQUEST, &[0x55; 4]);
+        assert_eq!(rng.peek_used(), Some(token));
+        assert_eq!(unsafe { rng.complete_fill(token, &mut buf) }, Ok(4));
+        assert_eq!(buf[..4], [0x55; 4]);
+        assert_eq!(rng.peek_used(), None);
+    }
+
+    #[test]
+    fn save_restore() {
+        let (mut rng, state) = fake_rng();
+
+        // Post a request and save the state while it is in flight.
+        let mut buf = [0; 16];
+        let token = unsafe { rng.fill_nb(&mut buf) }.unwrap();
+        let Ok(saved) = rng.save() else {
+            panic!("Failed to save state");
+        };
+        let saved = RngState::read_from(saved.as_bytes()).unwrap();
+        assert_eq!(saved.queue.layout.descriptors_in_use, 1);
+        assert!(state
+            .lock()
+            .unwrap()
+            .status
+            .contains(DeviceStatus::DRIVER_OK));
+
+        // The device completes the request while the driver is being replaced.
+        state
+            .lock()
+            .unwrap()
+            .write_to_queue::<QUEUE_SIZE>(QUEUE_REQUEST, &[0x66; 8]);
+
+        let transport = FakeTransport {
+            device_type: DeviceType::EntropySource,
+            max_queue_size: QUEUE_SIZE as u32,
+            device_features: 0,
+            config_space: NonNull::dangling(),
+            state: state.clone(),
+        };
+        let mut rng =
+            unsafe { VirtIORng::<FakeHal, FakeTransport<()>>::restore(transport, &saved) }.unwrap();
+        assert_eq!(rng.peek_used(), Some(token));
+        assert_eq!(unsafe { rng.complete_fill(token, &mut buf) }, Ok(8));
+        assert_eq!(buf[..8], [0x66; 8]);
+    }
+}
@@ -0,0 +1,95 @@
+//! Construction of command descriptor blocks for common SCSI commands.
+//!
+//! These can be sent with [`VirtIOScsi::command`](super::VirtIOScsi::command), which
+//! [`VirtIOScsi::inquiry`](super::VirtIOScsi::inquiry) and the other helpers on the driver do for
+//! the usual cases.
+
+const TEST_UNIT_READY: u8 = 0x00;
+const INQUIRY: u8 = 0x12;
+const READ_CAPACITY_10: u8 = 0x25;
+const READ_10: u8 = 0x28;
+const WRITE_10: u8 = 0x2a;
+const READ_16: u8 = 0x88;
+const WRITE_16: u8 = 0x8a;
+const SERVICE_ACTION_IN_16: u8 = 0x9e;
+const SA_READ_CAPACITY_16: u8 = 0x10;
+
+/// Returns a TEST UNIT READY command, which checks whether the logical unit is ready.
+pub fn test_unit_ready() -> [u8; 6] {
+    [TEST_UNIT_READY, 0, 0, 0, 0, 0]
+}
+
+/// Returns an INQUIRY command for the standard inquiry data, of up to `allocation_length` bytes.
+pub fn inquiry(allocation_length: u16) -> [u8; 6] {
+    let [len_high, len_low] = allocation_length.to_be_bytes();
+    [INQUIRY, 0, 0, len_high, len_low, 0]
+}
+
+/// Returns a READ CAPACITY (10) command, which returns 8 bytes of data.
+pub fn read_capacity_10() -> [u8; 10] {
+    [READ_CAPACITY_10, 0, 0, 0, 0, 0, 0, 0, 0, 0]
+}
+
+/// Returns a READ CAPACITY (16) command, which returns up to `allocation_length` bytes of data.
+pub fn read_capacity_16(allocation_length: u32) -> [u8; 16] {
+    let mut cdb = [0; 16];
+    cdb[0] = SERVICE_ACTION_IN_16;
+    cdb[1] = SA_READ_CAPACITY_16;
+    cdb[10..14].copy_from_slice(&allocation_length.to_be_bytes());
+    cdb
+}
+
+/// Returns a READ (10) command for `blocks` blocks starting at `lba`.
+pub fn read_10(lba: u32, blocks: u16) -> [u8; 10] {
+    rw_10(READ_10, lba, blocks)
+}
+
+/// Returns a WRITE (10) command for `blocks` blocks starting at `lba`.
+pub fn write_10(lba: u32, blocks: u16) -> [u8; 10] {
+    rw_10(WRITE_10, lba, blocks)
+}
+
+/// Returns a READ (16) command for `blocks` blocks starting at `lba`.
+pub fn read_16(lba: u64, blocks: u32) -> [u8; 16] {
+    rw_16(READ_16, lba, blocks)
+}
+
+/// Returns a WRITE (16) command for `blocks` blocks starting at `lba`.
+pub fn write_16(lba: u64, blocks: u32) -> [u8; 16] {
+    rw_16(WRITE_16, lba, blocks)
+}
+
+fn rw_10(opcode: u8, lba: u32, blocks: u16) -> [u8; 10] {
+    let mut cdb = [0; 10];
+    cdb[0] = opcode;
+    cdb[2..6].copy_from_slice(&lba.to_be_bytes());
+    cdb[7..9].copy_from_slice(&blocks.to_be_bytes());
+    cdb
+}
+
+fn rw_16(opcode: u8, lba: u64, blocks: u32) -> [u8; 16] {
+    let mut cdb = [0; 16];
+    cdb[0] = opcode;
+    cdb[2..10].copy_from_slice(&lba.to_be_bytes());
+    cdb[10..14].copy_from_slice(&blocks.to_be_bytes());
+    cdb
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode() {
+        assert_eq!(inquiry(96), [0x12, 0, 0, 0, 96, 0]);
+        assert_eq!(read_10(0x01020304, 8), [0x28, 0, 1, 2, 3, 4, 0, 0, 8, 0]);
+        assert_eq!(
+            write_16(0x1_0000_0000, 0x10000),
+            [0x8a, 0, 0, 0, 0, 1, 0, 0, 0, 0, 0, 1, 0, 0, 0, 0]
+        );
+        assert_eq!(
+            read_capacity_16(32),
+            [0x9e, 0x10, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 32, 0, 0]
+        );
+    }
+}
@@ -0,0 +1,1298 @@
+//! Driver for VirtIO SCSI host devices.
+
+pub mod cdb;
+mod sense;
+
+pub use self::sense::{SenseData, SenseFormat, SenseKey};
+
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::display::impl_flags_display;
+use crate::hal::{BufferDirection, Dma, Hal};
+use crate::queue::VirtQueue;
+use crate::registry::DriverId;
+use crate::transport::{DeviceType, Transport};
+use crate::{Error, Result};
+use bitflags::bitflags;
+use core::convert::TryFrom;
+use core::fmt::{self, Display, Formatter};
+use core::mem::{offset_of, size_of};
+use log::{info, warn};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+const CONTROL_QUEUE_SIZE: u16 = 4;
+const EVENT_QUEUE_SIZE: u16 = 4;
+const REQUEST_QUEUE_SIZE: u16 = 16;
+/// The size of the CDB field in requests, which the driver sets in the configuration space.
+const CDB_SIZE: usize = 32;
+/// The size of the sense field in responses, which the driver sets in the configuration space.
+const SENSE_SIZE: usize = 96;
+const SUPPORTED_FEATURES: ScsiFeature = ScsiFeature::HOTPLUG
+    .union(ScsiFeature::CHANGE)
+    .union(ScsiFeature::RING_INDIRECT_DESC)
+    .union(ScsiFeature::RING_EVENT_IDX);
+
+/// Driver for a VirtIO SCSI host device.
+///
+/// This sends SCSI commands on the first request queue, either built by the helpers such as
+/// [`inquiry`](Self::inquiry) and [`read`](Self::read) or passed raw to
+/// [`command`](Self::command). Commands which fail return an [`Error::ScsiError`] with the decoded
+/// sense data and residual count. Task management functions go on the control queue, which an OS's
+/// SCSI error handling uses to recover commands which have got stuck, and hotplug and parameter
+/// change notifications arrive on the event queue; see [`poll_event`](Self::poll_event).
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::scsi::{ScsiLun, VirtIOScsi};
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+/// let mut scsi = VirtIOScsi::<HalImpl, _>::new(transport)?;
+///
+/// let lun = ScsiLun::new(0, 0);
+/// let capacity = scsi.read_capacity(lun)?;
+/// let mut block = [0; 512];
+/// if capacity.block_size == 512 {
+///     scsi.read(lun, 0, capacity.block_size, &mut block)?;
+/// }
+///
+/// let response = scsi.lun_reset(ScsiLun::new(0, 0))?;
+/// if !response.is_success() {
+///     println!("LUN reset failed: {:?}", response);
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOScsi<H: Hal, T: Transport> {
+    id: DriverId,
+    transport: T,
+    /// The device's virtqueues, of which the driver uses the control queue, the event queue and the
+    /// first request queue.
+    queues: QueueLayout,
+    control_queue: VirtQueue<H, { CONTROL_QUEUE_SIZE as usize }>,
+    event_queue: VirtQueue<H, { EVENT_QUEUE_SIZE as usize }>,
+    request_queue: VirtQueue<H, { REQUEST_QUEUE_SIZE as usize }>,
+    /// The buffers for the event queue, one per descriptor.
+    event_buf: Dma<H>,
+    info: ScsiInfo,
+}
+
+/// Information about a SCSI host device, read from its configuration space.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub struct ScsiInfo {
+    /// The number of request queues.
+    pub num_queues: u32,
+    /// The largest target ID which may be addressed.
+    pub max_target: u16,
+    /// The largest LUN which may be addressed.
+    pub max_lun: u32,
+    /// The maximum size of a command descriptor block which the device accepted before the driver
+    /// configured it, in bytes.
+    pub cdb_size: u32,
+    /// The size of the sense data which the device wrote before the driver configured it, in
+    /// bytes.
+    pub sense_size: u32,
+}
+
+impl Display for ScsiInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} request queues, max target {}, max LUN {}, CDB size {}, sense size {}",
+            self.num_queues, self.max_target, self.max_lun, self.cdb_size, self.sense_size
+        )
+    }
+}
+
+impl<H: Hal, T: Transport> VirtIOScsi<H, T> {
+    /// Creates a new VirtIO SCSI driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+
+        let info = ScsiInfo {
+            num_queues: transport.read_config(offset_of!(Config, num_queues))?,
+            max_target: transport.read_config(offset_of!(Config, max_target))?,
+            max_lun: transport.read_config(offset_of!(Config, max_lun))?,
+            cdb_size: transport.read_config(offset_of!(Config, cdb_size))?,
+            sense_size: transport.read_config(offset_of!(Config, sense_size))?,
+        };
+        info!("found a SCSI host: {:?}", info);
+        transport.write_config(offset_of!(Config, cdb_size), CDB_SIZE as u32)?;
+        transport.write_config(offset_of!(Config, sense_size), SENSE_SIZE as u32)?;
+
+        let num_queues = u16::try_from(info.num_queues).map_err(|_| Error::InvalidParam)?;
+        let queues =
+            QueueLayout::new(DeviceType::ScsiHost, negotiated_features.bits(), num_queues)?;
+        queues.validate(&mut transport)?;
+        let control_index = queues.checked_index(
+            &mut transport,
+            QueueRole::Control,
+            CONTROL_QUEUE_SIZE.into(),
+        )?;
+        let control_queue = VirtQueue::new(
+            &mut transport,
+            control_index,
+            negotiated_features.contains(ScsiFeature::RING_INDIRECT_DESC),
+            negotiated_features.contains(ScsiFeature::RING_EVENT_IDX),
+        )?;
+        let event_index =
+            queues.checked_index(&mut transport, QueueRole::Event, EVENT_QUEUE_SIZE.into())?;
+        let mut event_queue = VirtQueue::new(
+            &mut transport,
+            event_index,
+            negotiated_features.contains(ScsiFeature::RING_INDIRECT_DESC),
+            negotiated_features.contains(ScsiFeature::RING_EVENT_IDX),
+        )?;
+        let mut event_buf = Dma::new(1, BufferDirection::DeviceToDriver)?;
+        for index in 0..EVENT_QUEUE_SIZE {
+            // Safe because the buffer lives as long as the queue.
+            let token = unsafe { event_queue.add(&[], &mut [event_slot(&mut event_buf, index)])? };
+            assert_eq!(token, index);
+        }
+        if event_queue.should_notify() {
+            event_queue.notify(&mut transport);
+        }
+        let request_index = queues.checked_index(
+            &mut transport,
+            QueueRole::Request(0),
+            REQUEST_QUEUE_SIZE.into(),
+        )?;
+        let request_queue = VirtQueue::new(
+            &mut transport,
+            request_index,
+            negotiated_features.contains(ScsiFeature::RING_INDIRECT_DESC),
+            negotiated_features.contains(ScsiFeature::RING_EVENT_IDX),
+        )?;
+        transport.finish_init();
+
+        Ok(Self {
+            id: DriverId::allocate(),
+            transport,
+            queues,
+            control_queue,
+            event_queue,
+            request_queue,
+            event_buf,
+            info,
+        })
+    }
+
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
+    /// Returns information about the device read from its configuration space.
+    pub fn info(&self) -> &ScsiInfo {
+        &self.info
+    }
+
+    /// Sends the given command descriptor block to the given LUN along with any data, and waits for
+    /// it to complete.
+    ///
+    /// Returns the number of bytes of data actually transferred, which may be less than the length
+    /// of the buffer. If the device couldn't deliver the command or the target didn't complete it
+    /// with GOOD status, returns [`Error::ScsiError`] with the details, including the decoded sense
+    /// data for a CHECK CONDITION.
+    pub fn command(&mut self, lun: ScsiLun, cdb: &[u8], data: DataTransfer) -> Result<usize> {
+        self.check_lun(lun)?;
+        let mut request = CmdReq {
+            lun: lun.encode(),
+            ..Default::default()
+        };
+        request
+            .cdb
+            .get_mut(..cdb.len())
+            .ok_or(Error::InvalidParam)?
+            .copy_from_slice(cdb);
+        let mut response = CmdResp::new_zeroed();
+        let data_len = match data {
+            DataTransfer::None => {
+                self.request_queue.add_notify_wait_pop(
+                    &[request.as_bytes()],
+                    &mut [response.as_bytes_mut()],
+                    &mut self.transport,
+                )?;
+                0
+            }
+            DataTransfer::ToDevice(data) => {
+                self.request_queue.add_notify_wait_pop(
+                    &[request.as_bytes(), data],
+                    &mut [response.as_bytes_mut()],
+                    &mut self.transport,
+                )?;
+                data.len()
+            }
+            DataTransfer::FromDevice(data) => {
+                let data_len = data.len();
+                self.request_queue.add_notify_wait_pop(
+                    &[request.as_bytes()],
+                    &mut [response.as_bytes_mut(), data],
+                    &mut self.transport,
+                )?;
+                data_len
+            }
+        };
+        response.into_result(data_len)
+    }
+
+    /// Checks whether the given logical unit is ready, returning an error such as a NOT READY or
+    /// UNIT ATTENTION [`Error::ScsiError`] if not.
+    pub fn test_unit_ready(&mut self, lun: ScsiLun) -> Result {
+        self.command(lun, &cdb::test_unit_ready(), DataTransfer::None)?;
+        Ok(())
+    }
+
+    /// Returns the standard inquiry data of the given logical unit, saying what kind of device it
+    /// is.
+    pub fn inquiry(&mut self, lun: ScsiLun) -> Result<InquiryData> {
+        let mut data = [0; INQUIRY_LEN];
+        let len = self.command(
+            lun,
+            &cdb::inquiry(INQUIRY_LEN as u16),
+            DataTransfer::FromDevice(&mut data),
+        )?;
+        if len < INQUIRY_LEN {
+            warn!("Short inquiry data: {} bytes", len);
+            return Err(Error::IoError);
+        }
+        Ok(InquiryData::from_bytes(&data))
+    }
+
+    /// Returns the number and size of the logical blocks of the given logical unit.
+    ///
+    /// This uses READ CAPACITY (10), or READ CAPACITY (16) if the logical unit is too large for it.
+    pub fn read_capacity(&mut self, lun: ScsiLun) -> Result<Capacity> {
+        let mut data = [0; 8];
+        if self.command(
+            lun,
+            &cdb::read_capacity_10(),
+            DataTransfer::FromDevice(&mut data),
+        )? < data.len()
+        {
+            return Err(Error::IoError);
+        }
+        let [a, b, c, d, e, f, g, h] = data;
+        let last_lba = u32::from_be_bytes([a, b, c, d]);
+        let block_size = u32::from_be_bytes([e, f, g, h]);
+        if last_lba != u32::MAX {
+            return Ok(Capacity {
+                blocks: u64::from(last_lba) + 1,
+                block_size,
+            });
+        }
+        let mut data = [0; 32];
+        if self.command(
+            lun,
+            &cdb::read_capacity_16(data.len() as u32),
+            DataTransfer::FromDevice(&mut data),
+        )? < 12
+        {
+            return Err(Error::IoError);
+        }
+        let mut last_lba = [0; 8];
+        last_lba.copy_from_slice(&data[..8]);
+        Ok(Capacity {
+            blocks: u64::from_be_bytes(last_lba).wrapping_add(1),
+            block_size: u32::from_be_bytes([data[8], data[9], data[10], data[11]]),
+        })
+    }
+
+    /// Reads whole blocks of the given size starting at `lba` from the given logical unit into
+    /// `buf`.
+    ///
+    /// The buffer length must be a non-zero multiple of `block_size`, otherwise this returns
+    /// [`Error::InvalidParam`]. A short read returns [`Error::IoError`].
+    pub fn read(&mut self, lun: ScsiLun, lba: u64, block_size: u32, buf: &mut [u8]) -> Result {
+        let blocks = block_count(block_size, buf.len())?;
+        let len = buf.len();
+        let transferred = match (u32::try_from(lba), u16::try_from(blocks)) {
+            (Ok(lba), Ok(blocks)) => self.command(
+                lun,
+                &cdb::read_10(lba, blocks),
+                DataTransfer::FromDevice(buf),
+            )?,
+            _ => self.command(
+                lun,
+                &cdb::read_16(lba, blocks),
+                DataTransfer::FromDevice(buf),
+            )?,
+        };
+        if transferred == len {
+            Ok(())
+        } else {
+            Err(Error::IoError)
+        }
+    }
+
+    /// Writes whole blocks of the given size starting at `lba` to the given logical unit from
+    /// `buf`.
+    ///
+    /// The buffer length must be a non-zero multiple of `block_size`, otherwise this returns
+    /// [`Error::InvalidParam`]. A short write returns [`Error::IoError`].
+    pub fn write(&mut self, lun: ScsiLun, lba: u64, block_size: u32, buf: &[u8]) -> Result {
+        let blocks = block_count(block_size, buf.len())?;
+        let transferred = match (u32::try_from(lba), u16::try_from(blocks)) {
+            (Ok(lba), Ok(blocks)) => self.command(
+                lun,
+                &cdb::write_10(lba, blocks),
+                DataTransfer::ToDevice(buf),
+            )?,
+            _ => self.command(
+                lun,
+                &cdb::write_16(lba, blocks),
+                DataTransfer::ToDevice(buf),
+            )?,
+        };
+        if transferred == buf.len() {
+            Ok(())
+        } else {
+            Err(Error::IoError)
+        }
+    }
+
+    /// Pops the next event from the event queue, if there is one, and gives its buffer back to the
+    /// device.
+    ///
+    /// This should be called when the device raises an interrupt. If it returns
+    /// [`ScsiEvent::EventsMissed`] the caller should rescan the bus, as the device had nowhere to
+    /// put some events.
+    pub fn poll_event(&mut self) -> Result<Option<ScsiEvent>> {
+        let Some(token) = self.event_queue.peek_used() else {
+            return Ok(None);
+        };
+        let buffer = event_slot(&mut self.event_buf, token);
+        // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it is
+        // still valid.
+        let written = unsafe { self.event_queue.pop_used(token, &[], &mut [&mut *buffer])? };
+        self.event_queue
+            .check_written(written, size_of::<RawEvent>());
+        let event = RawEvent::read_from(&*buffer).unwrap();
+        // Safe because the buffer lives as long as the queue.
+        let new_token = unsafe { self.event_queue.add(&[], &mut [buffer])? };
+        // Nothing else has used the queue since `pop_used` freed the descriptor, so `add` reuses
+        // it.
+        assert_eq!(new_token, token);
+        if self.event_queue.should_notify() {
+            self.event_queue.notify(&mut self.transport);
+        }
+        Ok(Some(event.into()))
+    }
+
+    /// Asks the device to abort the command with the given tag which was sent to the given LUN.
+    ///
+    /// Returns [`TmfResponse::FunctionComplete`] if the command was aborted or had already
+    /// completed.
+    pub fn abort_task(&mut self, lun: ScsiLun, tag: u64) -> Result<TmfResponse> {
+        self.task_management(TmfSubtype::AbortTask, lun, tag)
+    }
+
+    /// Asks the device to abort all outstanding commands to the given LUN, and reset it.
+    pub fn lun_reset(&mut self, lun: ScsiLun) -> Result<TmfResponse> {
+        self.task_management(TmfSubtype::LogicalUnitReset, lun, 0)
+    }
+
+    /// Asks the device to abort all outstanding commands to the target which the given LUN belongs
+    /// to, and reset the target.
+    ///
+    /// VirtIO has no separate target reset function, so this sends an I_T nexus reset, which has
+    /// the same effect for the single initiator which the device presents.
+    pub fn target_reset(&mut self, lun: ScsiLun) -> Result<TmfResponse> {
+        self.task_management(TmfSubtype::ITNexusReset, lun, 0)
+    }
+
+    /// Sends a task management function request on the control queue and waits for the response.
+    fn task_management(
+        &mut self,
+        subtype: TmfSubtype,
+        lun: ScsiLun,
+        tag: u64,
+    ) -> Result<TmfResponse> {
+        self.check_lun(lun)?;
+        let request = TmfReq {
+            type_: CTRL_TYPE_TMF,
+            subtype: subtype as u32,
+            lun: lun.encode(),
+            id: tag,
+        };
+        let mut response = TmfResp::default();
+        self.control_queue.add_notify_wait_pop(
+            &[request.as_bytes()],
+            &mut [response.as_bytes_mut()],
+            &mut self.transport,
+        )?;
+        Ok(response.response.into())
+    }
+
+    /// Returns [`Error::InvalidParam`] if the device doesn't have the given target or LUN.
+    fn check_lun(&self, lun: ScsiLun) -> Result {
+        if u16::from(lun.target) > self.info.max_target || u32::from(lun.lun) > self.info.max_lun {
+            Err(Error::InvalidParam)
+        } else {
+            Ok(())
+        }
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOScsi<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        for role in [QueueRole::Control, QueueRole::Event, QueueRole::Request(0)] {
+            if let Some(index) = self.queues.index(role) {
+                self.transport.queue_unset(index);
+            }
+        }
+    }
+}
+
+/// The address of a logical unit behind a SCSI host device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ScsiLun {
+    /// The target ID, up to [`ScsiInfo::max_target`].
+    pub target: u8,
+    /// The logical unit number within the target, up to [`ScsiInfo::max_lun`]. Only the low 14
+    /// bits are used.
+    pub lun: u16,
+}
+
+impl ScsiLun {
+    /// Creates a new address for the given target and LUN.
+    pub const fn new(target: u8, lun: u16) -> Self {
+        Self { target, lun }
+    }
+
+    /// Encodes the address in the 8 byte format used by VirtIO requests: bus 1, then the target,
+    /// then the LUN in the SAM flat addressing format.
+    fn encode(&self) -> [u8; 8] {
+        let [lun_high, lun_low] = (self.lun & 0x3fff).to_be_bytes();
+        [1, self.target, 0x40 | lun_high, lun_low, 0, 0, 0, 0]
+    }
+
+    /// Decodes an address in the format used by VirtIO events.
+    fn decode(lun: &[u8; 8]) -> Self {
+        Self {
+            target: lun[1],
+            lun: u16::from_be_bytes([lun[2] & 0x3f, lun[3]]),
+        }
+    }
+}
+
+/// Returns the number of blocks of the given size in a buffer of the given length, or
+/// [`Error::InvalidParam`] if it isn't a non-zero whole number.
+fn block_count(block_size: u32, len: usize) -> Result<u32> {
+    let block_size = block_size as usize;
+    if len == 0 || block_size == 0 || !len.is_multiple_of(block_size) {
+        return Err(Error::InvalidParam);
+    }
+    u32::try_from(len / block_size).map_err(|_| Error::InvalidParam)
+}
+
+/// Returns the buffer for the event queue descriptor with the given index.
+fn event_slot<H: Hal>(event_buf: &mut Dma<H>, index: u16) -> &mut [u8] {
+    let start = usize::from(index) * size_of::<RawEvent>();
+    // Safe because we have a mutable reference to the DMA region, so nothing else can access it
+    // for the lifetime of the slice.
+    unsafe { &mut event_buf.raw_slice().as_mut()[start..start + size_of::<RawEvent>()] }
+}
+
+/// The number of bytes of standard inquiry data which the driver asks for.
+const INQUIRY_LEN: usize = 36;
+
+/// The standard inquiry data of a logical unit, from [`VirtIOScsi::inquiry`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InquiryData {
+    /// The peripheral device type, e.g. 0x00 for a direct access block device or 0x05 for a CD or
+    /// DVD drive.
+    pub device_type: u8,
+    /// Whether the medium is removable.
+    pub removable: bool,
+    /// The version of the SCSI standard which the logical unit claims to conform to.
+    pub version: u8,
+    /// The vendor identification, padded with spaces.
+    pub vendor: [u8; 8],
+    /// The product identification, padded with spaces.
+    pub product: [u8; 16],
+    /// The product revision level, padded with spaces.
+    pub revision: [u8; 4],
+}
+
+impl InquiryData {
+    fn from_bytes(data: &[u8; INQUIRY_LEN]) -> Self {
+        let mut inquiry = Self {
+            device_type: data[0] & 0x1f,
+            removable: data[1] & 0x80 != 0,
+            version: data[2],
+            vendor: [0; 8],
+            product: [0; 16],
+            revision: [0; 4],
+        };
+        inquiry.vendor.copy_from_slice(&data[8..16]);
+        inquiry.product.copy_from_slice(&data[16..32]);
+        inquiry.revision.copy_from_slice(&data[32..36]);
+        inquiry
+    }
+}
+
+/// The size of a logical unit, from [`VirtIOScsi::read_capacity`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Capacity {
+    /// The number of logical blocks.
+    pub blocks: u64,
+    /// The size of each logical block, in bytes.
+    pub block_size: u32,
+}
+
+const EVT_NO_EVENT: u32 = 0;
+const EVT_TRANSPORT_RESET: u32 = 1;
+const EVT_ASYNC_NOTIFY: u32 = 2;
+const EVT_PARAM_CHANGE: u32 = 3;
+/// Set in the event type if the device had to drop events because there were no buffers.
+const EVT_EVENTS_MISSED: u32 = 0x8000_0000;
+
+/// `struct virtio_scsi_event`.
+#[repr(C)]
+#[derive(AsBytes, Debug, Default, FromBytes, FromZeroes)]
+struct RawEvent {
+    event: u32,
+    lun: [u8; 8],
+    reason: u32,
+}
+
+/// A notification from the event queue of a SCSI host device, from [`VirtIOScsi::poll_event`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ScsiEvent {
+    /// The device dropped some events because it had no buffers to put them in, so the caller
+    /// should rescan the bus to find out what changed.
+    EventsMissed,
+    /// A logical unit was reset, added or removed.
+    TransportReset {
+        /// The affected logical unit.
+        lun: ScsiLun,
+        /// What happened to it.
+        reason: ResetReason,
+    },
+    /// An asynchronous notification, e.g. of a medium change, for the given logical unit.
+    AsyncNotify {
+        /// The affected logical unit.
+        lun: ScsiLun,
+        /// The events which occurred, as a bitmask of the SCSI MMC event classes.
+        events: u32,
+    },
+    /// The parameters of a logical unit changed, e.g. its capacity, as described by the additional
+    /// sense code and qualifier of the unit attention condition which reported it.
+    ParamChange {
+        /// The affected logical unit.
+        lun: ScsiLun,
+        /// The additional sense code.
+        asc: u8,
+        /// The additional sense code qualifier.
+        ascq: u8,
+    },
+    /// An event which the driver doesn't know about.
+    Unknown {
+        /// The event type.
+        event: u32,
+        /// The reason field of the event.
+        reason: u32,
+    },
+}
+
+/// Why a [`ScsiEvent::TransportReset`] happened.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ResetReason {
+    /// The logical unit was reset.
+    Hard,
+    /// A logical unit was added, so the bus should be rescanned.
+    Rescan,
+    /// The logical unit was removed.
+    Removed,
+    /// A reason which the driver doesn't know about.
+    Unknown(u32),
+}
+
+impl From<RawEvent> for ScsiEvent {
+    fn from(raw: RawEvent) -> Self {
+        if raw.event & EVT_EVENTS_MISSED != 0 {
+            return Self::EventsMissed;
+        }
+        let lun = ScsiLun::decode(&raw.lun);
+        match raw.event {
+            EVT_TRANSPORT_RESET => Self::TransportReset {
+                lun,
+                reason: match raw.reason {
+                    0 => ResetReason::Hard,
+                    1 => ResetReason::Rescan,
+                    2 => ResetReason::Removed,
+                    other => ResetReason::Unknown(other),
+                },
+            },
+            EVT_ASYNC_NOTIFY => Self::AsyncNotify {
+                lun,
+                events: raw.reason,
+            },
+            EVT_PARAM_CHANGE => {
+                let [asc, ascq, _, _] = raw.reason.to_le_bytes();
+                Self::ParamChange { lun, asc, ascq }
+            }
+            event => Self::Unknown {
+                event,
+                reason: raw.reason,
+            },
+        }
+    }
+}
+
+/// The data buffer for a SCSI command, if any.
+#[derive(Debug)]
+pub enum DataTransfer<'a> {
+    /// The command doesn't transfer any data.
+    None,
+    /// The command sends the data in the buffer to the target, e.g. WRITE.
+    ToDevice(&'a [u8]),
+    /// The command reads data from the target into the buffer, e.g. READ or INQUIRY.
+    FromDevice(&'a mut [u8]),
+}
+
+/// Why a SCSI command failed.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ScsiError {
+    /// The response from the device. This is [`CommandResponse::Ok`] if the command was delivered
+    /// to the target but completed with a status other than GOOD.
+    pub response: CommandResponse,
+    /// The status returned by the target.
+    pub status: ScsiStatus,
+    /// The decoded sense data, if the target returned any in a format which could be decoded.
+    pub sense: Option<SenseData>,
+    /// The number of bytes of the data buffer which weren't transferred.
+    pub residual: u32,
+}
+
+impl Display for ScsiError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        if self.response == CommandResponse::Ok {
+            write!(f, "status {:?}", self.status)?;
+        } else {
+            write!(f, "response {:?}", self.response)?;
+        }
+        if let Some(sense) = &self.sense {
+            write!(f, ", sense {sense}")?;
+        }
+        write!(f, ", residual {}", self.residual)
+    }
+}
+
+/// The response from the device to a SCSI command, which says whether it was delivered to the
+/// target.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum CommandResponse {
+    /// The command was delivered to the target, which returned a status.
+    Ok,
+    /// The data buffer was too small for the data which the target returned.
+    Overrun,
+    /// The command was aborted by a task management function.
+    Aborted,
+    /// The target doesn't exist.
+    BadTarget,
+    /// The command was cancelled by a reset of the device or target.
+    Reset,
+    /// The device is busy, so the command should be retried.
+    Busy,
+    /// The command failed because of a problem connecting to the target, and may succeed on a
+    /// different path.
+    TransportFailure,
+    /// The target failed, and the command shouldn't be retried on another path.
+    TargetFailure,
+    /// The nexus failed, and the command shouldn't be retried on another path.
+    NexusFailure,
+    /// The command failed for some other reason.
+    Failure,
+    /// The device returned a response code which the driver doesn't know about.
+    Unknown(u8),
+}
+
+impl From<u8> for CommandResponse {
+    fn from(response: u8) -> Self {
+        match response {
+            0 => Self::Ok,
+            1 => Self::Overrun,
+            2 => Self::Aborted,
+            3 => Self::BadTarget,
+            4 => Self::Reset,
+            5 => Self::Busy,
+            6 => Self::TransportFailure,
+            7 => Self::TargetFailure,
+            8 => Self::NexusFailure,
+            9 => Self::Failure,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// The SCSI status with which a target completed a command.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum ScsiStatus {
+    /// The command completed successfully.
+    Good,
+    /// The command failed, and the sense data says why.
+    CheckCondition,
+    /// The requested operation is satisfied.
+    ConditionMet,
+    /// The logical unit is busy, so the command should be retried later.
+    Busy,
+    /// The logical unit is reserved by another initiator.
+    ReservationConflict,
+    /// The logical unit's task set is full.
+    TaskSetFull,
+    /// An ACA condition exists, so the command was rejected.
+    AcaActive,
+    /// The command was aborted.
+    TaskAborted,
+    /// A status which the driver doesn't know about.
+    Unknown(u8),
+}
+
+impl From<u8> for ScsiStatus {
+    fn from(status: u8) -> Self {
+        match status {
+            0x00 => Self::Good,
+            0x02 => Self::CheckCondition,
+            0x04 => Self::ConditionMet,
+            0x08 => Self::Busy,
+            0x18 => Self::ReservationConflict,
+            0x28 => Self::TaskSetFull,
+            0x30 => Self::AcaActive,
+            0x40 => Self::TaskAborted,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+/// The response to a task management function request.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TmfResponse {
+    /// The function completed, e.g. the task was aborted or the LUN was reset.
+    FunctionComplete,
+    /// The function succeeded.
+    FunctionSucceeded,
+    /// The function was rejected by the target.
+    FunctionRejected,
+    /// The LUN doesn't exist.
+    IncorrectLun,
+    /// The target doesn't exist.
+    BadTarget,
+    /// The request was aborted by a reset or another task management function.
+    Aborted,
+    /// The request was cancelled by a reset of the device or target.
+    Reset,
+    /// The device is busy, so the request should be retried.
+    Busy,
+    /// The request failed because of a problem connecting to the target, and may succeed on a
+    /// different path.
+    TransportFailure,
+    /// The target failed, and the request shouldn't be retried on another path.
+    TargetFailure,
+    /// The nexus failed, and the request shouldn't be retried on another path.
+    NexusFailure,
+    /// The request failed for some other reason.
+    Failure,
+    /// The device returned a response code which the driver doesn't know about.
+    Unknown(u8),
+}
+
+impl TmfResponse {
+    /// Returns whether the function completed successfully.
+    pub fn is_success(self) -> bool {
+        matches!(self, Self::FunctionComplete | Self::FunctionSucceeded)
+    }
+}
+
+impl From<u8> for TmfResponse {
+    fn from(response: u8) -> Self {
+        match response {
+            0 => Self::FunctionComplete,
+            2 => Self::Aborted,
+            3 => Self::BadTarget,
+            4 => Self::Reset,
+            5 => Self::Busy,
+            6 => Self::TransportFailure,
+            7 => Self::TargetFailure,
+            8 => Self::NexusFailure,
+            9 => Self::Failure,
+            10 => Self::FunctionSucceeded,
+            11 => Self::FunctionRejected,
+            12 => Self::IncorrectLun,
+            other => Self::Unknown(other),
+        }
+    }
+}
+
+#[repr(C)]
+struct Config {
+    num_queues: u32,
+    seg_max: u32,
+    max_sectors: u32,
+    cmd_per_lun: u32,
+    event_info_size: u32,
+    sense_size: u32,
+    cdb_size: u32,
+    max_channel: u16,
+    max_target: u16,
+    max_lun: u32,
+}
+
+#[repr(C, packed)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, Eq, PartialEq)]
+struct CmdReq {
+    lun: [u8; 8],
+    id: u64,
+    task_attr: u8,
+    prio: u8,
+    crn: u8,
+    cdb: [u8; CDB_SIZE],
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, FromBytes, FromZeroes)]
+struct CmdResp {
+    sense_len: u32,
+    resid: u32,
+    status_qualifier: u16,
+    status: u8,
+    response: u8,
+    sense: [u8; SENSE_SIZE],
+}
+
+impl CmdResp {
+    /// Converts the response to a command with the given length of data into the number of bytes
+    /// transferred, or the error.
+    fn into_result(self, data_len: usize) -> Result<usize> {
+        let response = CommandResponse::from(self.response);
+        let status = ScsiStatus::from(self.status);
+        if response == CommandResponse::Ok && status == ScsiStatus::Good {
+            return Ok(data_len.saturating_sub(self.resid as usize));
+        }
+        let sense_len = (self.sense_len as usize).min(SENSE_SIZE);
+        Err(ScsiError {
+            response,
+            status,
+            sense: SenseData::parse(&self.sense[..sense_len]),
+            residual: self.resid,
+        }
+        .into())
+    }
+}
+
+/// The type of control queue requests for task management functions.
+const CTRL_TYPE_TMF: u32 = 0;
+
+/// Task management functions.
+#[repr(u32)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum TmfSubtype {
+    AbortTask = 0,
+    AbortTaskSet = 1,
+    ClearAca = 2,
+    ClearTaskSet = 3,
+    ITNexusReset = 4,
+    LogicalUnitReset = 5,
+    QueryTask = 6,
+    QueryTaskSet = 7,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, Default, Eq, PartialEq)]
+struct TmfReq {
+    type_: u32,
+    subtype: u32,
+    lun: [u8; 8],
+    id: u64,
+}
+
+#[repr(C)]
+#[derive(AsBytes, Debug, Default, FromBytes, FromZeroes)]
+struct TmfResp {
+    response: u8,
+}
+
+bitflags! {
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    struct ScsiFeature: u64 {
+        /// A single request can include both device-readable and device-writable data buffers.
+        const INOUT                 = 1 << 0;
+        /// The host sends events on the event queue when targets are hotplugged or removed.
+        const HOTPLUG               = 1 << 1;
+        /// The host sends events on the event queue when LUN parameters change.
+        const CHANGE                = 1 << 2;
+        /// The extended fields for T10 protection information are supported.
+        const T10_PI                = 1 << 3;
+
+        // device independent
+        const NOTIFY_ON_EMPTY       = 1 << 24; // legacy
+        const ANY_LAYOUT            = 1 << 27; // legacy
+        const RING_INDIRECT_DESC    = 1 << 28;
+        const RING_EVENT_IDX        = 1 << 29;
+        const UNUSED                = 1 << 30; // legacy
+        const VERSION_1             = 1 << 32; // detect legacy
+
+        // the following since virtio v1.1
+        const ACCESS_PLATFORM       = 1 << 33;
+        const RING_PACKED           = 1 << 34;
+        const IN_ORDER              = 1 << 35;
+        const ORDER_PLATFORM        = 1 << 36;
+        const SR_IOV                = 1 << 37;
+        const NOTIFICATION_DATA     = 1 << 38;
+    }
+}
+
+impl_flags_display!(ScsiFeature);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::{sync::Arc, vec};
+    use core::{mem::size_of, ptr::NonNull};
+    use std::{sync::Mutex, thread};
+
+    const QUEUE_CONTROL: u16 = 0;
+    const QUEUE_EVENT: u16 = 1;
+    const QUEUE_REQUEST: u16 = 2;
+
+    fn make_config() -> Config {
+        Config {
+            num_queues: 1,
+            seg_max: 0,
+            max_sectors: 0,
+            cmd_per_lun: 0,
+            event_info_size: 0,
+            sense_size: 96,
+            cdb_size: 32,
+            max_channel: 0,
+            max_target: 1,
+            max_lun: 16383,
+        }
+    }
+
+    #[test]
+    fn encode_lun() {
+        assert_eq!(ScsiLun::new(2, 5).encode(), [1, 2, 0x40, 5, 0, 0, 0, 0]);
+        assert_eq!(
+            ScsiLun::new(0, 0x1234).encode(),
+            [1, 0, 0x52, 0x34, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn abort_task() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::ScsiHost,
+            max_queue_size: REQUEST_QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut scsi = VirtIOScsi::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(scsi.info().sense_size, 96);
+
+        // Start a thread to simulate the device handling the request.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ CONTROL_QUEUE_SIZE as usize }>(QUEUE_CONTROL, |request| {
+                    assert_eq!(
+                        request,
+                        TmfReq {
+                            type_: CTRL_TYPE_TMF,
+                            subtype: TmfSubtype::AbortTask as u32,
+                            lun: [1, 1, 0x40, 3, 0, 0, 0, 0],
+                            id: 42,
+                        }
+                        .as_bytes()
+                    );
+                    vec![12]
+                });
+        });
+
+        let response = scsi.abort_task(ScsiLun::new(1, 3), 42).unwrap();
+        assert_eq!(response, TmfResponse::IncorrectLun);
+        assert!(!response.is_success());
+        handle.join().unwrap();
+
+        // Targets the device doesn't have are rejected without sending a request.
+        assert_eq!(
+            scsi.target_reset(ScsiLun::new(2, 0)),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn command() {
+        let mut config_space = make_config();
+        config_space.sense_size = 252;
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::ScsiHost,
+            max_queue_size: REQUEST_QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut scsi = VirtIOScsi::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Start a thread to simulate the device handling two commands: a short read, then one
+        // which fails with CHECK CONDITION.
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_REQUEST);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ REQUEST_QUEUE_SIZE as usize }>(QUEUE_REQUEST, |request| {
+                    assert_eq!(request.len(), size_of::<CmdReq>());
+                    assert_eq!(request[..8], [1, 0, 0x40, 2, 0, 0, 0, 0]);
+                    assert_eq!(request[19..25], [0x12, 0, 0, 0, 8, 0]);
+                    let mut response = CmdResp::new_zeroed();
+                    response.resid = 4;
+                    let mut response = response.as_bytes().to_vec();
+                    response.extend_from_slice(&[0x11; 4]);
+                    response
+                });
+
+            State::wait_until_queue_notified(&state, QUEUE_REQUEST);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ REQUEST_QUEUE_SIZE as usize }>(QUEUE_REQUEST, |request| {
+                    assert_eq!(request.len(), size_of::<CmdReq>() + 512);
+                    let mut response = CmdResp::new_zeroed();
+                    response.status = 0x02;
+                    response.resid = 512;
+                    response.sense_len = 18;
+                    response.sense[..18].copy_from_slice(&[
+                        0xf0, 0, 0x03, 0, 0, 0, 0x10, 10, 0, 0, 0, 0, 0x0c, 0x02, 0, 0, 0, 0,
+                    ]);
+                    response.as_bytes().to_vec()
+                });
+        });
+
+        // The driver configures the sense and CDB sizes it uses.
+        assert_eq!(scsi.info().sense_size, 252);
+        assert_eq!(config_space.sense_size, SENSE_SIZE as u32);
+
+        let mut data = [0; 8];
+        let cdb = [0x12, 0, 0, 0, 8, 0];
+        assert_eq!(
+            scsi.command(
+                ScsiLun::new(0, 2),
+                &cdb,
+                DataTransfer::FromDevice(&mut data)
+            ),
+            Ok(4)
+        );
+        assert_eq!(data[..4], [0x11; 4]);
+
+        let result = scsi.command(
+            ScsiLun::new(0, 2),
+            &[0x2a, 0, 0, 0, 0, 0x10, 0, 0, 1, 0],
+            DataTransfer::ToDevice(&[0; 512]),
+        );
+        let Err(Error::ScsiError(error)) = result else {
+            panic!("Unexpected result {:?}", result);
+        };
+        assert_eq!(error.response, CommandResponse::Ok);
+        assert_eq!(error.status, ScsiStatus::CheckCondition);
+        assert_eq!(error.residual, 512);
+        let sense = error.sense.unwrap();
+        assert_eq!(
+            (sense.key, sense.asc, sense.ascq, sense.information),
+            (SenseKey::MediumError, 0x0c, 0x02, Some(0x10))
+        );
+        handle.join().unwrap();
+
+        // CDBs longer than the driver supports are rejected without sending a request.
+        assert_eq!(
+            scsi.command(ScsiLun::new(0, 0), &[0; 33], DataTransfer::None),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn read_capacity_and_read() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::ScsiHost,
+            max_queue_size: REQUEST_QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut scsi = VirtIOScsi::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        // Start a thread to simulate a device too large for READ CAPACITY (10), which then handles
+        // a READ (16) beyond the range of READ (10).
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_REQUEST);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ REQUEST_QUEUE_SIZE as usize }>(QUEUE_REQUEST, |request| {
+                    assert_eq!(request[19..29], cdb::read_capacity_10());
+                    let mut response = CmdResp::new_zeroed().as_bytes().to_vec();
+                    response.extend_from_slice(&[0xff, 0xff, 0xff, 0xff, 0, 0, 0x10, 0]);
+                    response
+                });
+
+            State::wait_until_queue_notified(&state, QUEUE_REQUEST);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ REQUEST_QUEUE_SIZE as usize }>(QUEUE_REQUEST, |request| {
+                    assert_eq!(request[19..35], cdb::read_capacity_16(32));
+                    let mut response = CmdResp::new_zeroed();
+                    response.resid = 20;
+                    let mut response = response.as_bytes().to_vec();
+                    response.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 0, 0, 0, 0x10, 0]);
+                    response
+                });
+
+            State::wait_until_queue_notified(&state, QUEUE_REQUEST);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<{ REQUEST_QUEUE_SIZE as usize }>(QUEUE_REQUEST, |request| {
+                    assert_eq!(request[19..35], cdb::read_16(0x1_0000_0000, 2));
+                    let mut response = CmdResp::new_zeroed().as_bytes().to_vec();
+                    response.extend_from_slice(&[0x33; 8192]);
+                    response
+                });
+        });
+
+        let lun = ScsiLun::new(0, 0);
+        assert_eq!(
+            scsi.read_capacity(lun),
+            Ok(Capacity {
+                blocks: 0x1_0000_0001,
+                block_size: 4096,
+            })
+        );
+        let mut buf = vec![0; 8192];
+        scsi.read(lun, 0x1_0000_0000, 4096, &mut buf).unwrap();
+        assert!(buf.iter().all(|&byte| byte == 0x33));
+        handle.join().unwrap();
+
+        // Buffers which aren't a whole number of blocks are rejected without sending a request.
+        assert_eq!(
+            scsi.read(lun, 0, 4096, &mut buf[..100]),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn events() {
+        let mut config_space = make_config();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::ScsiHost,
+            max_queue_size: REQUEST_QUEUE_SIZE.into(),
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut scsi = VirtIOScsi::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(scsi.poll_event(), Ok(None));
+
+        // More events than there are buffers can be received, as each is given back to the device.
+        let events = [
+            (
+                (EVT_TRANSPORT_RESET, [1, 0, 0x40, 3, 0, 0, 0, 0], 1),
+                ScsiEvent::TransportReset {
+                    lun: ScsiLun::new(0, 3),
+                    reason: ResetReason::Rescan,
+                },
+            ),
+            (
+                (EVT_PARAM_CHANGE, [1, 1, 0x40, 0, 0, 0, 0, 0], 0x092a),
+                ScsiEvent::ParamChange {
+                    lun: ScsiLun::new(1, 0),
+                    asc: 0x2a,
+                    ascq: 0x09,
+                },
+            ),
+            (
+                (EVT_ASYNC_NOTIFY, [1, 0, 0x40, 0, 0, 0, 0, 0], 0x10),
+                ScsiEvent::AsyncNotify {
+                    lun: ScsiLun::new(0, 0),
+                    events: 0x10,
+                },
+            ),
+            (
+                (EVT_TRANSPORT_RESET, [1, 0, 0x40, 3, 0, 0, 0, 0], 2),
+                ScsiEvent::TransportReset {
+                    lun: ScsiLun::new(0, 3),
+                    reason: ResetReason::Removed,
+                },
+            ),
+            (
+                (EVT_NO_EVENT | EVT_EVENTS_MISSED, [0; 8], 0),
+                ScsiEvent::EventsMissed,
+            ),
+        ];
+        for &((event, lun, reason), expected) in events.iter() {
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<{ EVENT_QUEUE_SIZE as usize }>(
+                    QUEUE_EVENT,
+                    RawEvent { event, lun, reason }.as_bytes(),
+                );
+            assert_eq!(scsi.poll_event(), Ok(Some(expected)));
+        }
+        assert_eq!(scsi.poll_event(), Ok(None));
+    }
+}
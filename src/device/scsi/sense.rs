@@ -0,0 +1,246 @@
+//! Decoding of SCSI sense data, as returned with a CHECK CONDITION status.
+
+use core::fmt::{self, Display, Formatter};
+
+/// The general category of a SCSI error, from the sense data.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SenseKey {
+    /// There is no specific sense key information to report.
+    NoSense,
+    /// The command completed successfully, but the target had to recover from an error.
+    RecoveredError,
+    /// The logical unit isn't accessible, e.g. because the medium is being loaded.
+    NotReady,
+    /// The command failed because of a flaw in the medium or in the recorded data.
+    MediumError,
+    /// The target detected a hardware failure.
+    HardwareError,
+    /// The command or its parameters were invalid.
+    IllegalRequest,
+    /// The logical unit was reset, its medium was changed, or its parameters changed.
+    UnitAttention,
+    /// The block is protected against the operation attempted.
+    DataProtect,
+    /// Blank medium or a format-defined end-of-data indication was encountered.
+    BlankCheck,
+    /// A vendor specific condition.
+    VendorSpecific,
+    /// A copy operation was aborted.
+    CopyAborted,
+    /// The target aborted the command, and the initiator may be able to recover by retrying it.
+    AbortedCommand,
+    /// A buffered peripheral device reached the end of its partition.
+    VolumeOverflow,
+    /// The data on the medium didn't match the data from the initiator.
+    Miscompare,
+    /// A command completed, in sense data describing a completed command.
+    Completed,
+    /// A sense key which is reserved by the SCSI standard.
+    Reserved(u8),
+}
+
+impl From<u8> for SenseKey {
+    fn from(key: u8) -> Self {
+        match key & 0x0f {
+            0x0 => Self::NoSense,
+            0x1 => Self::RecoveredError,
+            0x2 => Self::NotReady,
+            0x3 => Self::MediumError,
+            0x4 => Self::HardwareError,
+            0x5 => Self::IllegalRequest,
+            0x6 => Self::UnitAttention,
+            0x7 => Self::DataProtect,
+            0x8 => Self::BlankCheck,
+            0x9 => Self::VendorSpecific,
+            0xa => Self::CopyAborted,
+            0xb => Self::AbortedCommand,
+            0xd => Self::VolumeOverflow,
+            0xe => Self::Miscompare,
+            0xf => Self::Completed,
+            other => Self::Reserved(other),
+        }
+    }
+}
+
+/// The format in which the target returned sense data.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SenseFormat {
+    /// Fixed format sense data, with response code 0x70 or 0x71.
+    Fixed,
+    /// Descriptor format sense data, with response code 0x72 or 0x73.
+    Descriptor,
+}
+
+/// Sense data decoded from either the fixed or the descriptor format.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SenseData {
+    /// The format the data was returned in.
+    pub format: SenseFormat,
+    /// Whether the error is a deferred error for an earlier command, rather than for the command
+    /// which returned it.
+    pub deferred: bool,
+    /// The sense key.
+    pub key: SenseKey,
+    /// The additional sense code, which refines the sense key.
+    pub asc: u8,
+    /// The additional sense code qualifier, which refines the additional sense code.
+    pub ascq: u8,
+    /// The command-specific information field if it is valid, e.g. the LBA of an unrecovered read
+    /// error.
+    pub information: Option<u64>,
+}
+
+/// The type of the information sense data descriptor.
+const DESCRIPTOR_INFORMATION: u8 = 0x00;
+
+impl SenseData {
+    /// Decodes the given sense data, returning `None` if it is too short or in a format which
+    /// isn't understood.
+    pub fn parse(sense: &[u8]) -> Option<Self> {
+        let response_code = sense.first()? & 0x7f;
+        match response_code {
+            0x70 | 0x71 => Self::parse_fixed(sense, response_code == 0x71),
+            0x72 | 0x73 => Self::parse_descriptor(sense, response_code == 0x73),
+            _ => None,
+        }
+    }
+
+    fn parse_fixed(sense: &[u8], deferred: bool) -> Option<Self> {
+        let key = *sense.get(2)?;
+        let information = if sense[0] & 0x80 != 0 {
+            let bytes = sense.get(3..7)?;
+            Some(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]).into())
+        } else {
+            None
+        };
+        // The additional sense code and qualifier are only present if the additional length
+        // covers them.
+        let len = sense
+            .len()
+            .min(8 + usize::from(*sense.get(7).unwrap_or(&0)));
+        let byte = |index: usize| if index < len { sense[index] } else { 0 };
+        Some(Self {
+            format: SenseFormat::Fixed,
+            deferred,
+            key: key.into(),
+            asc: byte(12),
+            ascq: byte(13),
+            information,
+        })
+    }
+
+    fn parse_descriptor(sense: &[u8], deferred: bool) -> Option<Self> {
+        let header = sense.get(..4)?;
+        let len = sense
+            .len()
+            .min(8 + usize::from(*sense.get(7).unwrap_or(&0)));
+        let mut descriptors = sense.get(8..len).unwrap_or_default();
+        let mut information = None;
+        while let [descriptor_type, additional_len, rest @ ..] = descriptors {
+            let Some(body) = rest.get(..usize::from(*additional_len)) else {
+                break;
+            };
+            if *descriptor_type == DESCRIPTOR_INFORMATION && body.len() >= 10 && body[0] & 0x80 != 0
+            {
+                let mut bytes = [0; 8];
+                bytes.copy_from_slice(&body[2..10]);
+                information = Some(u64::from_be_bytes(bytes));
+            }
+            descriptors = &rest[body.len()..];
+        }
+        Some(Self {
+            format: SenseFormat::Descriptor,
+            deferred,
+            key: header[1].into(),
+            asc: header[2],
+            ascq: header[3],
+            information,
+        })
+    }
+}
+
+impl Display for SenseData {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:?} (ASC {:#04x}, ASCQ {:#04x})",
+            self.key, self.asc, self.ascq
+        )?;
+        if let Some(information) = self.information {
+            write!(f, ", information {information:#x}")?;
+        }
+        if self.deferred {
+            write!(f, ", deferred")?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed() {
+        // A medium error reading LBA 0x1234: unrecovered read error.
+        let mut sense = [0; 18];
+        sense[0] = 0xf0;
+        sense[2] = 0x03;
+        sense[3..7].copy_from_slice(&[0, 0, 0x12, 0x34]);
+        sense[7] = 10;
+        sense[12] = 0x11;
+        assert_eq!(
+            SenseData::parse(&sense),
+            Some(SenseData {
+                format: SenseFormat::Fixed,
+                deferred: false,
+                key: SenseKey::MediumError,
+                asc: 0x11,
+                ascq: 0,
+                information: Some(0x1234),
+            })
+        );
+
+        // Truncated to before the additional sense code, and deferred without valid information.
+        let sense = [0x71, 0, 0x06, 0, 0, 0, 0, 0];
+        let decoded = SenseData::parse(&sense).unwrap();
+        assert!(decoded.deferred);
+        assert_eq!(decoded.key, SenseKey::UnitAttention);
+        assert_eq!(
+            (decoded.asc, decoded.ascq, decoded.information),
+            (0, 0, None)
+        );
+    }
+
+    #[test]
+    fn descriptor() {
+        // Illegal request, invalid field in CDB, with an information descriptor.
+        let sense = [
+            0x72, 0x05, 0x24, 0x00, 0, 0, 0, 16, // header
+            0x02, 0x02, 0, 0, // a descriptor which isn't understood
+            0x00, 0x0a, 0x80, 0, 0, 0, 0, 0, 0, 0, 0x56, 0x78, // information
+        ];
+        assert_eq!(
+            SenseData::parse(&sense),
+            Some(SenseData {
+                format: SenseFormat::Descriptor,
+                deferred: false,
+                key: SenseKey::IllegalRequest,
+                asc: 0x24,
+                ascq: 0,
+                information: Some(0x5678),
+            })
+        );
+        assert_eq!(
+            SenseData::parse(&sense).unwrap().to_string(),
+            "IllegalRequest (ASC 0x24, ASCQ 0x00), information 0x5678"
+        );
+    }
+
+    #[test]
+    fn invalid() {
+        assert_eq!(SenseData::parse(&[]), None);
+        assert_eq!(SenseData::parse(&[0x7f, 0, 0x05]), None);
+        assert_eq!(SenseData::parse(&[0x70, 0]), None);
+    }
+}
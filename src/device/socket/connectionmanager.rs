@@ -2,7 +2,7 @@ use super::{
     protocol::VsockAddr, vsock::ConnectionInfo, DisconnectReason, SocketError, VirtIOSocket,
     VsockEvent, VsockEventType,
 };
-use crate::{transport::Transport, Hal, Result};
+use crate::{transport::Transport, Error, Hal, Result};
 use alloc::{boxed::Box, vec::Vec};
 use core::cmp::min;
 use core::convert::TryInto;
@@ -12,6 +12,70 @@ use zerocopy::FromZeroes;
 
 const PER_CONNECTION_BUFFER_CAPACITY: usize = 1024;
 
+/// Options controlling the resources used by a [`VsockConnectionManager`].
+///
+/// The defaults allow any number of incoming connections, silently ignore packets for unknown
+/// connections and give each connection a 1 KiB receive buffer.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[non_exhaustive]
+pub struct ConnectionManagerConfig {
+    /// The maximum number of connections which may be open at once on each listening port, or
+    /// `None` for no limit. Connection requests beyond this are rejected with a RST.
+    pub listen_backlog: Option<usize>,
+    /// Whether to reply with a RST to packets which don't match any known connection, as the
+    /// VirtIO specification recommends, rather than silently dropping them.
+    pub reset_unmatched: bool,
+    /// The size in bytes of the receive buffer for each connection. This is advertised to the peer
+    /// as our buffer allocation, so bounds how much data it may send before we read it.
+    pub buffer_capacity: u32,
+}
+
+impl Default for ConnectionManagerConfig {
+    fn default() -> Self {
+        Self {
+            listen_backlog: None,
+            reset_unmatched: false,
+            buffer_capacity: PER_CONNECTION_BUFFER_CAPACITY as u32,
+        }
+    }
+}
+
+impl ConnectionManagerConfig {
+    /// Returns the default configuration.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Limits the number of connections which may be open at once on each listening port.
+    pub fn with_listen_backlog(self, listen_backlog: usize) -> Self {
+        Self {
+            listen_backlog: Some(listen_backlog),
+            ..self
+        }
+    }
+
+    /// Sets whether to reply with a RST to packets which don't match any known connection.
+    pub fn with_reset_unmatched(self, reset_unmatched: bool) -> Self {
+        Self {
+            reset_unmatched,
+            ..self
+        }
+    }
+
+    /// Sets the size in bytes of the receive buffer for each connection.
+    ///
+    /// Returns [`Error::InvalidParam`] if `buffer_capacity` is 0.
+    pub fn with_buffer_capacity(self, buffer_capacity: u32) -> Result<Self> {
+        if buffer_capacity == 0 {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Self {
+            buffer_capacity,
+            ..self
+        })
+    }
+}
+
 /// A higher level interface for VirtIO socket (vsock) devices.
 ///
 /// This keeps track of multiple vsock connections.
@@ -44,6 +108,7 @@ pub struct VsockConnectionManager<H: Hal, T: Transport> {
     driver: VirtIOSocket<H, T>,
     connections: Vec<Connection>,
     listening_ports: Vec<u32>,
+    config: ConnectionManagerConfig,
 }
 
 #[derive(Debug)]
@@ -56,12 +121,12 @@ struct Connection {
 }
 
 impl Connection {
-    fn new(peer: VsockAddr, local_port: u32) -> Self {
+    fn new(peer: VsockAddr, local_port: u32, buffer_capacity: u32) -> Self {
         let mut info = ConnectionInfo::new(peer, local_port);
-        info.buf_alloc = PER_CONNECTION_BUFFER_CAPACITY.try_into().unwrap();
+        info.buf_alloc = buffer_capacity;
         Self {
             info,
-            buffer: RingBuffer::new(PER_CONNECTION_BUFFER_CAPACITY),
+            buffer: RingBuffer::new(buffer_capacity.try_into().unwrap()),
             peer_requested_shutdown: false,
         }
     }
@@ -70,13 +135,25 @@ impl Connection {
 impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
     /// Construct a new connection manager wrapping the given low-level VirtIO socket driver.
     pub fn new(driver: VirtIOSocket<H, T>) -> Self {
+        Self::new_with_config(driver, ConnectionManagerConfig::default())
+    }
+
+    /// Construct a new connection manager wrapping the given low-level VirtIO socket driver, with
+    /// the given limits on incoming connections and buffers.
+    pub fn new_with_config(driver: VirtIOSocket<H, T>, config: ConnectionManagerConfig) -> Self {
         Self {
             driver,
             connections: Vec::new(),
             listening_ports: Vec::new(),
+            config,
         }
     }
 
+    /// Returns the configuration the connection manager was created with.
+    pub fn config(&self) -> ConnectionManagerConfig {
+        self.config
+    }
+
     /// Returns the CID which has been assigned to this guest.
     pub fn guest_cid(&self) -> u64 {
         self.driver.guest_cid()
@@ -106,7 +183,7 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
             return Err(SocketError::ConnectionExists.into());
         }
 
-        let new_connection = Connection::new(destination, src_port);
+        let new_connection = Connection::new(destination, src_port, self.config.buffer_capacity);
 
         self.driver.connect(&new_connection.info)?;
         debug!("Connection requested: {:?}", new_connection.info);
@@ -125,6 +202,8 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
     pub fn poll(&mut self) -> Result<Option<VsockEvent>> {
         let guest_cid = self.driver.guest_cid();
         let connections = &mut self.connections;
+        let buffer_capacity = self.config.buffer_capacity;
+        let mut unmatched = None;
 
         let result = self.driver.poll(|event, body| {
             let connection = get_connection_for_event(connections, &event, guest_cid);
@@ -140,9 +219,14 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
                 }
                 // Add the new connection to our list, at least for now. It will be removed again
                 // below if we weren't listening on the port.
-                connections.push(Connection::new(event.source, event.destination.port));
+                connections.push(Connection::new(
+                    event.source,
+                    event.destination.port,
+                    buffer_capacity,
+                ));
                 connections.last_mut().unwrap()
             } else {
+                unmatched = Some(event);
                 return Ok(None);
             };
 
@@ -160,6 +244,9 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
         })?;
 
         let Some(event) = result else {
+            if let Some(event) = unmatched {
+                self.reset_unmatched(&event)?;
+            }
             return Ok(None);
         };
 
@@ -169,7 +256,18 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
 
         match event.event_type {
             VsockEventType::ConnectionRequest => {
-                if self.listening_ports.contains(&event.destination.port) {
+                // The new connection is included in the count.
+                let open_on_port = self
+                    .connections
+                    .iter()
+                    .filter(|connection| connection.info.src_port == event.destination.port)
+                    .count();
+                let within_backlog = self
+                    .config
+                    .listen_backlog
+                    .is_none_or(|backlog| open_on_port <= backlog);
+                let connection = &mut self.connections[connection_index];
+                if self.listening_ports.contains(&event.destination.port) && within_backlog {
                     self.driver.accept(&connection.info)?;
                 } else {
                     // Reject the connection request and remove it from our list.
@@ -207,6 +305,23 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
         Ok(Some(event))
     }
 
+    /// Replies with a RST to an event which didn't match any connection, if configured to do so.
+    fn reset_unmatched(&mut self, event: &VsockEvent) -> Result {
+        // Never reply to a RST with another RST, or to packets which weren't meant for us.
+        if !self.config.reset_unmatched
+            || event.destination.cid != self.driver.guest_cid()
+            || event.event_type
+                == (VsockEventType::Disconnected {
+                    reason: DisconnectReason::Reset,
+                })
+        {
+            return Ok(());
+        }
+        debug!("Resetting unknown connection from {:?}", event.source);
+        self.driver
+            .force_close(&ConnectionInfo::new(event.source, event.destination.port))
+    }
+
     /// Reads data received from the given connection.
     pub fn recv(&mut self, peer: VsockAddr, src_port: u32, buffer: &mut [u8]) -> Result<usize> {
         let (connection_index, connection) = get_connection(&mut self.connections, peer, src_port)?;
@@ -272,6 +387,69 @@ impl<H: Hal, T: Transport> VsockConnectionManager<H, T> {
         self.connections.swap_remove(index);
         Ok(())
     }
+
+    /// Returns a stream-like handle to the connection with the given peer and local port, for
+    /// reading and writing without repeating the address each time.
+    ///
+    /// Returns [`SocketError::NotConnected`] if there is no such connection.
+    pub fn stream(&mut self, peer: VsockAddr, local_port: u32) -> Result<VsockStream<'_, H, T>> {
+        get_connection(&mut self.connections, peer, local_port)?;
+        Ok(VsockStream {
+            manager: self,
+            peer,
+            local_port,
+        })
+    }
+}
+
+/// A handle to one connection of a [`VsockConnectionManager`], returned by
+/// [`VsockConnectionManager::stream`].
+///
+/// Data only arrives in the connection's buffer when the manager is polled, so a caller waiting to
+/// read should drop the stream and [`poll`](VsockConnectionManager::poll) the manager until it
+/// reports that data was received.
+pub struct VsockStream<'a, H: Hal, T: Transport> {
+    manager: &'a mut VsockConnectionManager<H, T>,
+    peer: VsockAddr,
+    local_port: u32,
+}
+
+impl<H: Hal, T: Transport> VsockStream<'_, H, T> {
+    /// Returns the address of the peer.
+    pub fn peer(&self) -> VsockAddr {
+        self.peer
+    }
+
+    /// Returns the local port of the connection.
+    pub fn local_port(&self) -> u32 {
+        self.local_port
+    }
+
+    /// Sends all of the given data to the peer.
+    ///
+    /// Returns [`SocketError::InsufficientBufferSpaceInPeer`] if the peer hasn't given enough
+    /// credit for it, in which case nothing is sent.
+    pub fn write(&mut self, buffer: &[u8]) -> Result {
+        self.manager.send(self.peer, self.local_port, buffer)
+    }
+
+    /// Reads data which has been received on the connection into the given buffer, returning the
+    /// number of bytes read, which is 0 if none is available.
+    pub fn read(&mut self, buffer: &mut [u8]) -> Result<usize> {
+        self.manager.recv(self.peer, self.local_port, buffer)
+    }
+
+    /// Returns the number of bytes which have been received and can be read.
+    pub fn available(&mut self) -> Result<usize> {
+        self.manager
+            .recv_buffer_available_bytes(self.peer, self.local_port)
+    }
+
+    /// Requests to shut down the connection cleanly, as for
+    /// [`VsockConnectionManager::shutdown`].
+    pub fn shutdown(self) -> Result {
+        self.manager.shutdown(self.peer, self.local_port)
+    }
 }
 
 /// Returns the connection from the given list matching the given peer address and local port, and
@@ -368,9 +546,7 @@ impl RingBuffer {
         // The number of bytes to copy out between `start` and the end of the buffer.
         let read_before_wraparound = min(bytes_read, self.buffer.len() - self.start);
         // The number of bytes to copy out from the beginning of the buffer after wrapping around.
-        let read_after_wraparound = bytes_read
-            .checked_sub(read_before_wraparound)
-            .unwrap_or_default();
+        let read_after_wraparound = bytes_read.saturating_sub(read_before_wraparound);
 
         out[0..read_before_wraparound]
             .copy_from_slice(&self.buffer[self.start..self.start + read_before_wraparound]);
@@ -397,13 +573,27 @@ mod tests {
             fake::{FakeTransport, QueueStatus, State},
             DeviceType,
         },
-        volatile::ReadOnly,
     };
     use alloc::{sync::Arc, vec};
-    use core::{mem::size_of, ptr::NonNull};
+    use core::{convert::TryFrom, mem::size_of, ptr::NonNull};
     use std::{sync::Mutex, thread};
     use zerocopy::{AsBytes, FromBytes};
 
+    #[test]
+    fn zero_buffer_capacity() {
+        assert_eq!(
+            ConnectionManagerConfig::new().with_buffer_capacity(0).err(),
+            Some(Error::InvalidParam)
+        );
+        assert_eq!(
+            ConnectionManagerConfig::new()
+                .with_buffer_capacity(1)
+                .unwrap()
+                .buffer_capacity,
+            1
+        );
+    }
+
     #[test]
     fn send_recv() {
         let host_cid = 2;
@@ -418,8 +608,8 @@ mod tests {
         let hello_from_host = "Hello from host";
 
         let mut config_space = VirtioVsockConfig {
-            guest_cid_low: ReadOnly::new(66),
-            guest_cid_high: ReadOnly::new(0),
+            guest_cid_low: 66,
+            guest_cid_high: 0,
         };
         let state = Arc::new(Mutex::new(State {
             queues: vec![
@@ -617,6 +807,135 @@ mod tests {
         handle.join().unwrap();
     }
 
+    #[test]
+    fn stream() {
+        let host_cid = 2;
+        let guest_cid = 66;
+        let host_port = 1234;
+        let guest_port = 4321;
+        let host_address = VsockAddr {
+            cid: host_cid,
+            port: host_port,
+        };
+        let hello_from_guest = "Hello from guest";
+        let hello_from_host = "Hello from host";
+
+        let mut config_space = VirtioVsockConfig {
+            guest_cid_low: 66,
+            guest_cid_high: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Socket,
+            max_queue_size: 32,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut socket = VsockConnectionManager::new(
+            VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap(),
+        );
+
+        // Start a thread to simulate the device.
+        let handle = thread::spawn(move || {
+            let host_header = |op: VirtioVsockOp, len: usize, fwd_cnt: usize| VirtioVsockHdr {
+                op: op.into(),
+                src_cid: host_cid.into(),
+                dst_cid: guest_cid.into(),
+                src_port: host_port.into(),
+                dst_port: guest_port.into(),
+                len: (len as u32).into(),
+                socket_type: SocketType::Stream.into(),
+                flags: 0.into(),
+                buf_alloc: 50.into(),
+                fwd_cnt: (fwd_cnt as u32).into(),
+            };
+            let read_op = || {
+                State::wait_until_queue_notified(&state, TX_QUEUE_IDX);
+                let request = state
+                    .lock()
+                    .unwrap()
+                    .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX);
+                let header = VirtioVsockHdr::read_from_prefix(request.as_slice()).unwrap();
+                (
+                    VirtioVsockOp::try_from(header.op).unwrap(),
+                    request[size_of::<VirtioVsockHdr>()..].to_vec(),
+                )
+            };
+
+            // Accept the connection.
+            assert_eq!(read_op().0, VirtioVsockOp::Request);
+            state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+                RX_QUEUE_IDX,
+                host_header(VirtioVsockOp::Response, 0, 0).as_bytes(),
+            );
+
+            // Expect data written to the stream, and answer it.
+            assert_eq!(
+                read_op(),
+                (VirtioVsockOp::Rw, hello_from_guest.as_bytes().to_vec())
+            );
+            let mut response = host_header(
+                VirtioVsockOp::Rw,
+                hello_from_host.len(),
+                hello_from_guest.len(),
+            )
+            .as_bytes()
+            .to_vec();
+            response.extend_from_slice(hello_from_host.as_bytes());
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<QUEUE_SIZE>(RX_QUEUE_IDX, &response);
+
+            // Expect the stream to be shut down.
+            assert_eq!(read_op().0, VirtioVsockOp::Shutdown);
+        });
+
+        assert!(matches!(
+            socket.stream(host_address, guest_port),
+            Err(crate::Error::SocketDeviceError(SocketError::NotConnected))
+        ));
+        socket.connect(host_address, guest_port).unwrap();
+        assert_eq!(
+            socket.wait_for_event().unwrap().event_type,
+            VsockEventType::Connected
+        );
+
+        let mut stream = socket.stream(host_address, guest_port).unwrap();
+        assert_eq!(stream.peer(), host_address);
+        assert_eq!(stream.local_port(), guest_port);
+        assert_eq!(stream.available().unwrap(), 0);
+        stream.write(hello_from_guest.as_bytes()).unwrap();
+
+        // Data only arrives while the manager is polled, not through the stream.
+        assert_eq!(
+            socket.wait_for_event().unwrap().event_type,
+            VsockEventType::Received {
+                length: hello_from_host.len()
+            }
+        );
+        let mut stream = socket.stream(host_address, guest_port).unwrap();
+        assert_eq!(stream.available().unwrap(), hello_from_host.len());
+        let mut buffer = [0u8; 64];
+        assert_eq!(stream.read(&mut buffer).unwrap(), hello_from_host.len());
+        assert_eq!(
+            &buffer[0..hello_from_host.len()],
+            hello_from_host.as_bytes()
+        );
+        assert_eq!(stream.read(&mut buffer).unwrap(), 0);
+        stream.shutdown().unwrap();
+
+        handle.join().unwrap();
+    }
+
     #[test]
     fn incoming_connection() {
         let host_cid = 2;
@@ -630,8 +949,8 @@ mod tests {
         };
 
         let mut config_space = VirtioVsockConfig {
-            guest_cid_low: ReadOnly::new(66),
-            guest_cid_high: ReadOnly::new(0),
+            guest_cid_low: 66,
+            guest_cid_high: 0,
         };
         let state = Arc::new(Mutex::new(State {
             queues: vec![
@@ -769,4 +1088,120 @@ mod tests {
 
         handle.join().unwrap();
     }
+
+    #[test]
+    fn connection_limits() {
+        let host_cid = 2;
+        let guest_cid = 66;
+        let host_port = 1234;
+        let other_host_port = 1235;
+        let guest_port = 4321;
+
+        let mut config_space = VirtioVsockConfig {
+            guest_cid_low: 66,
+            guest_cid_high: 0,
+        };
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Socket,
+            max_queue_size: 32,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut socket = VsockConnectionManager::new_with_config(
+            VirtIOSocket::<FakeHal, FakeTransport<VirtioVsockConfig>>::new(transport).unwrap(),
+            ConnectionManagerConfig::new()
+                .with_listen_backlog(1)
+                .with_reset_unmatched(true)
+                .with_buffer_capacity(256)
+                .unwrap(),
+        );
+
+        socket.listen(guest_port);
+
+        let header = move |op: VirtioVsockOp, src_port: u32| VirtioVsockHdr {
+            op: op.into(),
+            src_cid: host_cid.into(),
+            dst_cid: guest_cid.into(),
+            src_port: src_port.into(),
+            dst_port: guest_port.into(),
+            len: 0.into(),
+            socket_type: SocketType::Stream.into(),
+            flags: 0.into(),
+            buf_alloc: 50.into(),
+            fwd_cnt: 0.into(),
+        };
+        let reply = move |op: VirtioVsockOp, dst_port: u32| VirtioVsockHdr {
+            op: op.into(),
+            src_cid: guest_cid.into(),
+            dst_cid: host_cid.into(),
+            src_port: guest_port.into(),
+            dst_port: dst_port.into(),
+            len: 0.into(),
+            socket_type: SocketType::Stream.into(),
+            flags: 0.into(),
+            buf_alloc: 256.into(),
+            fwd_cnt: 0.into(),
+        };
+
+        // Start a thread to simulate the device.
+        let handle = thread::spawn(move || {
+            let expect_reply = |expected: VirtioVsockHdr| {
+                State::wait_until_queue_notified(&state, TX_QUEUE_IDX);
+                assert_eq!(
+                    VirtioVsockHdr::read_from(
+                        state
+                            .lock()
+                            .unwrap()
+                            .read_from_queue::<QUEUE_SIZE>(TX_QUEUE_IDX)
+                            .as_slice()
+                    )
+                    .unwrap(),
+                    expected
+                );
+            };
+
+            // The first connection is accepted.
+            state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+                RX_QUEUE_IDX,
+                header(VirtioVsockOp::Request, host_port).as_bytes(),
+            );
+            expect_reply(reply(VirtioVsockOp::Response, host_port));
+
+            // The second is over the backlog so is rejected.
+            state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+                RX_QUEUE_IDX,
+                header(VirtioVsockOp::Request, other_host_port).as_bytes(),
+            );
+            expect_reply(reply(VirtioVsockOp::Rst, other_host_port));
+
+            // Packets for the rejected connection get a RST too, but without any buffer allocated.
+            state.lock().unwrap().write_to_queue::<QUEUE_SIZE>(
+                RX_QUEUE_IDX,
+                header(VirtioVsockOp::CreditUpdate, other_host_port).as_bytes(),
+            );
+            expect_reply(VirtioVsockHdr {
+                buf_alloc: 0.into(),
+                ..reply(VirtioVsockOp::Rst, other_host_port)
+            });
+        });
+
+        assert_eq!(
+            socket.wait_for_event().unwrap().event_type,
+            VsockEventType::ConnectionRequest
+        );
+        // The other packets are handled internally, so shouldn't produce any events.
+        while !handle.is_finished() {
+            assert_eq!(socket.poll().unwrap(), None);
+        }
+        handle.join().unwrap();
+    }
 }
@@ -6,17 +6,22 @@
 //! for a lower-level interface.
 //!
 //! See [`VsockConnectionManager`] for a usage example.
+//!
+//! The driver itself is only available with the `socket` feature, but [`SocketError`] is always
+//! available as it is part of [`Error`](crate::Error).
 
-#[cfg(feature = "alloc")]
+#[cfg(all(feature = "socket", feature = "alloc"))]
 mod connectionmanager;
 mod error;
+#[cfg(feature = "socket")]
 mod protocol;
-#[cfg(feature = "alloc")]
+#[cfg(all(feature = "socket", feature = "alloc"))]
 mod vsock;
 
-#[cfg(feature = "alloc")]
-pub use connectionmanager::VsockConnectionManager;
+#[cfg(all(feature = "socket", feature = "alloc"))]
+pub use connectionmanager::{ConnectionManagerConfig, VsockConnectionManager, VsockStream};
 pub use error::SocketError;
+#[cfg(feature = "socket")]
 pub use protocol::{VsockAddr, VMADDR_CID_HOST};
-#[cfg(feature = "alloc")]
+#[cfg(all(feature = "socket", feature = "alloc"))]
 pub use vsock::{DisconnectReason, VirtIOSocket, VsockEvent, VsockEventType};
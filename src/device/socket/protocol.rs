@@ -1,7 +1,7 @@
 //! This module defines the socket device protocol according to the virtio spec v1.1 5.10 Socket Device
 
 use super::error::{self, SocketError};
-use crate::volatile::ReadOnly;
+use crate::display::impl_flags_display;
 use bitflags::bitflags;
 use core::{
     convert::{TryFrom, TryInto},
@@ -39,13 +39,15 @@ pub struct VirtioVsockConfig {
     ///
     /// According to virtio spec v1.1 2.4.1 Driver Requirements: Device Configuration Space,
     /// drivers MUST NOT assume reads from fields greater than 32 bits wide are atomic.
-    /// So we need to split the u64 guest_cid into two parts.
-    pub guest_cid_low: ReadOnly<u32>,
-    pub guest_cid_high: ReadOnly<u32>,
+    /// So the u64 guest_cid is split into two parts, which
+    /// [`Transport::read_config`](crate::transport::Transport::read_config) reads consistently.
+    pub guest_cid_low: u32,
+    /// The upper 32 bits of the guest's context ID.
+    pub guest_cid_high: u32,
 }
 
 /// The message header for data packets sent on the tx/rx queues
-#[repr(packed)]
+#[repr(C, packed)]
 #[derive(AsBytes, Clone, Copy, Debug, Eq, FromBytes, FromZeroes, PartialEq)]
 pub struct VirtioVsockHdr {
     pub src_cid: U64<LittleEndian>,
@@ -214,3 +216,5 @@ bitflags! {
         const NOTIFICATION_DATA     = 1 << 38;
     }
 }
+
+impl_flags_display!(Feature);
@@ -3,23 +3,25 @@
 
 use super::error::SocketError;
 use super::protocol::{Feature, VirtioVsockConfig, VirtioVsockHdr, VirtioVsockOp, VsockAddr};
+use crate::device::queues::{QueueLayout, QueueRole};
 use crate::hal::Hal;
 use crate::queue::VirtQueue;
-use crate::transport::Transport;
-use crate::volatile::volread;
+use crate::registry::DriverId;
+use crate::transport::{DeviceType, Transport};
 use crate::{Error, Result};
 use alloc::boxed::Box;
-use core::mem::size_of;
+use core::mem::{offset_of, size_of};
 use core::ptr::{null_mut, NonNull};
 use log::debug;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
+#[cfg(test)]
 pub(crate) const RX_QUEUE_IDX: u16 = 0;
+#[cfg(test)]
 pub(crate) const TX_QUEUE_IDX: u16 = 1;
-const EVENT_QUEUE_IDX: u16 = 2;
 
 pub(crate) const QUEUE_SIZE: usize = 8;
-const SUPPORTED_FEATURES: Feature = Feature::RING_EVENT_IDX;
+const SUPPORTED_FEATURES: Feature = Feature::RING_INDIRECT_DESC.union(Feature::RING_EVENT_IDX);
 
 /// The size in bytes of each buffer used in the RX virtqueue. This must be bigger than size_of::<VirtioVsockHdr>().
 const RX_BUFFER_SIZE: usize = 512;
@@ -211,7 +213,9 @@ pub enum VsockEventType {
 /// You probably want to use [`VsockConnectionManager`](super::VsockConnectionManager) rather than
 /// using this directly.
 pub struct VirtIOSocket<H: Hal, T: Transport> {
+    id: DriverId,
     transport: T,
+    queues: QueueLayout,
     /// Virtqueue to receive packets.
     rx: VirtQueue<H, { QUEUE_SIZE }>,
     tx: VirtQueue<H, { QUEUE_SIZE }>,
@@ -227,9 +231,9 @@ impl<H: Hal, T: Transport> Drop for VirtIOSocket<H, T> {
     fn drop(&mut self) {
         // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
         // after they have been freed.
-        self.transport.queue_unset(RX_QUEUE_IDX);
-        self.transport.queue_unset(TX_QUEUE_IDX);
-        self.transport.queue_unset(EVENT_QUEUE_IDX);
+        for (index, _) in self.queues.queues() {
+            self.transport.queue_unset(index);
+        }
 
         for buffer in self.rx_queue_buffers {
             // Safe because we obtained the RX buffer pointer from Box::into_raw, and it won't be
@@ -244,32 +248,23 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
     pub fn new(mut transport: T) -> Result<Self> {
         let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
 
-        let config = transport.config_space::<VirtioVsockConfig>()?;
-        debug!("config: {:?}", config);
-        // Safe because config is a valid pointer to the device configuration space.
-        let guest_cid = unsafe {
-            volread!(config, guest_cid_low) as u64 | (volread!(config, guest_cid_high) as u64) << 32
-        };
+        let guest_cid: u64 = transport.read_config(offset_of!(VirtioVsockConfig, guest_cid_low))?;
         debug!("guest cid: {guest_cid:?}");
 
-        let mut rx = VirtQueue::new(
-            &mut transport,
-            RX_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        let tx = VirtQueue::new(
-            &mut transport,
-            TX_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
-        let event = VirtQueue::new(
-            &mut transport,
-            EVENT_QUEUE_IDX,
-            false,
-            negotiated_features.contains(Feature::RING_EVENT_IDX),
-        )?;
+        let queues = QueueLayout::new(DeviceType::Socket, negotiated_features.bits(), 0)?;
+        queues.validate(&mut transport)?;
+        let mut new_queue = |role| {
+            let index = queues.checked_index(&mut transport, role, QUEUE_SIZE as u32)?;
+            VirtQueue::new(
+                &mut transport,
+                index,
+                negotiated_features.contains(Feature::RING_INDIRECT_DESC),
+                negotiated_features.contains(Feature::RING_EVENT_IDX),
+            )
+        };
+        let mut rx = new_queue(QueueRole::Receive(0))?;
+        let tx = new_queue(QueueRole::Transmit(0))?;
+        let event = new_queue(QueueRole::Event)?;
 
         // Allocate and add buffers for the RX queue.
         let mut rx_queue_buffers = [null_mut(); QUEUE_SIZE];
@@ -285,11 +280,13 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
 
         transport.finish_init();
         if rx.should_notify() {
-            transport.notify(RX_QUEUE_IDX);
+            rx.notify(&mut transport);
         }
 
         Ok(Self {
+            id: DriverId::allocate(),
             transport,
+            queues,
             rx,
             tx,
             event,
@@ -298,6 +295,12 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         })
     }
 
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
     /// Returns the CID which has been assigned to this guest.
     pub fn guest_cid(&self) -> u64 {
         self.guest_cid
@@ -456,7 +459,7 @@ impl<H: Hal, T: Transport> VirtIOSocket<H, T> {
         }
 
         if self.rx.should_notify() {
-            self.transport.notify(RX_QUEUE_IDX);
+            self.rx.notify(&mut self.transport);
         }
 
         Ok(())
@@ -520,7 +523,6 @@ mod tests {
             fake::{FakeTransport, QueueStatus, State},
             DeviceType,
         },
-        volatile::ReadOnly,
     };
     use alloc::{sync::Arc, vec};
     use core::ptr::NonNull;
@@ -529,8 +531,8 @@ mod tests {
     #[test]
     fn config() {
         let mut config_space = VirtioVsockConfig {
-            guest_cid_low: ReadOnly::new(66),
-            guest_cid_high: ReadOnly::new(0),
+            guest_cid_low: 66,
+            guest_cid_high: 0,
         };
         let state = Arc::new(Mutex::new(State {
             queues: vec![
@@ -0,0 +1,181 @@
+//! Event notifications from VirtIO sound devices.
+//!
+//! The device sends notifications about jacks, PCM streams and control elements on its event
+//! queue, as 8-byte `struct virtio_snd_event` buffers. [`SoundEvent`] is the typed form of these,
+//! so that audio stacks can react to e.g. headphones being plugged into the host.
+
+use crate::display::impl_flags_display;
+use bitflags::bitflags;
+use core::{
+    fmt::{self, Display, Formatter},
+    mem::size_of,
+};
+use zerocopy::{
+    byteorder::{LittleEndian, U16, U32},
+    AsBytes, FromBytes, FromZeroes,
+};
+
+/// The size in bytes of an event buffer on the event queue.
+pub const EVENT_SIZE: usize = size_of::<RawEvent>();
+
+const VIRTIO_SND_EVT_JACK_CONNECTED: u32 = 0x1000;
+const VIRTIO_SND_EVT_JACK_DISCONNECTED: u32 = 0x1001;
+const VIRTIO_SND_EVT_PCM_PERIOD_ELAPSED: u32 = 0x1100;
+const VIRTIO_SND_EVT_PCM_XRUN: u32 = 0x1101;
+const VIRTIO_SND_EVT_CTL_NOTIFY: u32 = 0x1200;
+
+/// `struct virtio_snd_event`, or `struct virtio_snd_ctl_event` for control element notifications.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct RawEvent {
+    code: U32<LittleEndian>,
+    data: [u8; 4],
+}
+
+/// A notification from the event queue of a sound device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum SoundEvent {
+    /// An external device was plugged into the jack with the given ID.
+    JackConnected {
+        /// The ID of the jack.
+        jack_id: u32,
+    },
+    /// An external device was unplugged from the jack with the given ID.
+    JackDisconnected {
+        /// The ID of the jack.
+        jack_id: u32,
+    },
+    /// A hardware buffer period has elapsed for the given PCM stream, so the device has consumed
+    /// or produced another period's worth of frames.
+    PcmPeriodElapsed {
+        /// The ID of the PCM stream.
+        stream_id: u32,
+    },
+    /// An underflow (for playback) or overflow (for capture) occurred on the given PCM stream.
+    PcmXrun {
+        /// The ID of the PCM stream.
+        stream_id: u32,
+    },
+    /// Some part of the control element with the given ID changed.
+    ControlChanged {
+        /// The ID of the control element.
+        control_id: u16,
+        /// Which parts of the control element changed.
+        mask: ControlEventMask,
+    },
+    /// An event with a code which this driver doesn't know about.
+    Unknown {
+        /// The event code.
+        code: u32,
+        /// The raw event-specific data.
+        data: u32,
+    },
+}
+
+impl SoundEvent {
+    /// Parses an event from a buffer which the device has written to the event queue.
+    ///
+    /// Returns `None` if the buffer is too short to hold an event.
+    pub fn read_from(buffer: &[u8]) -> Option<Self> {
+        let raw = RawEvent::read_from_prefix(buffer)?;
+        let data = u32::from_le_bytes(raw.data);
+        Some(match raw.code.get() {
+            VIRTIO_SND_EVT_JACK_CONNECTED => Self::JackConnected { jack_id: data },
+            VIRTIO_SND_EVT_JACK_DISCONNECTED => Self::JackDisconnected { jack_id: data },
+            VIRTIO_SND_EVT_PCM_PERIOD_ELAPSED => Self::PcmPeriodElapsed { stream_id: data },
+            VIRTIO_SND_EVT_PCM_XRUN => Self::PcmXrun { stream_id: data },
+            VIRTIO_SND_EVT_CTL_NOTIFY => {
+                let control_id = U16::<LittleEndian>::read_from(&raw.data[0..2])?.get();
+                let mask = U16::<LittleEndian>::read_from(&raw.data[2..4])?.get();
+                Self::ControlChanged {
+                    control_id,
+                    mask: ControlEventMask::from_bits_retain(mask),
+                }
+            }
+            code => Self::Unknown { code, data },
+        })
+    }
+}
+
+impl Display for SoundEvent {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::JackConnected { jack_id } => write!(f, "jack {} connected", jack_id),
+            Self::JackDisconnected { jack_id } => write!(f, "jack {} disconnected", jack_id),
+            Self::PcmPeriodElapsed { stream_id } => {
+                write!(f, "PCM stream {} period elapsed", stream_id)
+            }
+            Self::PcmXrun { stream_id } => write!(f, "PCM stream {} xrun", stream_id),
+            Self::ControlChanged { control_id, mask } => {
+                write!(f, "control {} changed ({})", control_id, mask)
+            }
+            Self::Unknown { code, data } => {
+                write!(f, "unknown event {:#x} (data {:#x})", code, data)
+            }
+        }
+    }
+}
+
+bitflags! {
+    /// The parts of a control element which changed, in a [`SoundEvent::ControlChanged`].
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct ControlEventMask: u16 {
+        /// The value of the element changed.
+        const VALUE = 1 << 0;
+        /// The information about the element, such as its range, changed.
+        const INFO = 1 << 1;
+        /// The TLV metadata of the element changed.
+        const TLV = 1 << 2;
+    }
+}
+
+impl_flags_display!(ControlEventMask);
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(code: u32, data: [u8; 4]) -> [u8; EVENT_SIZE] {
+        let mut buffer = [0; EVENT_SIZE];
+        buffer[0..4].copy_from_slice(&code.to_le_bytes());
+        buffer[4..8].copy_from_slice(&data);
+        buffer
+    }
+
+    #[test]
+    fn parse_events() {
+        assert_eq!(
+            SoundEvent::read_from(&event(0x1000, 3u32.to_le_bytes())),
+            Some(SoundEvent::JackConnected { jack_id: 3 })
+        );
+        assert_eq!(
+            SoundEvent::read_from(&event(0x1001, 3u32.to_le_bytes())),
+            Some(SoundEvent::JackDisconnected { jack_id: 3 })
+        );
+        assert_eq!(
+            SoundEvent::read_from(&event(0x1100, 1u32.to_le_bytes())),
+            Some(SoundEvent::PcmPeriodElapsed { stream_id: 1 })
+        );
+        assert_eq!(
+            SoundEvent::read_from(&event(0x1101, 1u32.to_le_bytes())),
+            Some(SoundEvent::PcmXrun { stream_id: 1 })
+        );
+        let control = SoundEvent::read_from(&event(0x1200, [7, 0, 0b101, 0])).unwrap();
+        assert_eq!(
+            control,
+            SoundEvent::ControlChanged {
+                control_id: 7,
+                mask: ControlEventMask::VALUE | ControlEventMask::TLV,
+            }
+        );
+        assert_eq!(control.to_string(), "control 7 changed (VALUE|TLV)");
+        assert_eq!(
+            SoundEvent::read_from(&event(0x1300, 42u32.to_le_bytes())),
+            Some(SoundEvent::Unknown {
+                code: 0x1300,
+                data: 42
+            })
+        );
+        assert_eq!(SoundEvent::read_from(&[0; 4]), None);
+    }
+}
@@ -0,0 +1,1192 @@
+//! Driver for VirtIO sound devices.
+
+mod event;
+
+pub use self::event::{ControlEventMask, SoundEvent, EVENT_SIZE};
+
+use super::common::Feature;
+use crate::device::queues::{QueueLayout, QueueRole};
+use crate::display::impl_flags_display;
+use crate::fixed::FixedBytes;
+use crate::hal::Hal;
+use crate::queue::VirtQueue;
+use crate::registry::DriverId;
+use crate::transport::{DeviceStatus, DeviceType, Transport};
+use crate::{Error, Result};
+use alloc::{boxed::Box, vec, vec::Vec};
+use bitflags::bitflags;
+use core::fmt::{self, Display, Formatter};
+use core::hint::spin_loop;
+use core::mem::{offset_of, size_of};
+use core::ops::Range;
+use log::{info, warn};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+#[cfg(test)]
+const QUEUE_CONTROL: u16 = 0;
+#[cfg(test)]
+const QUEUE_EVENT: u16 = 1;
+#[cfg(test)]
+const QUEUE_TX: u16 = 2;
+const CONTROL_QUEUE_SIZE: usize = 4;
+const EVENT_QUEUE_SIZE: usize = 8;
+const PCM_QUEUE_SIZE: usize = 32;
+/// The most periods of a stream which the driver has in flight at once. Each takes up to three
+/// descriptors.
+const MAX_PERIODS_IN_FLIGHT: usize = PCM_QUEUE_SIZE / 3;
+const SUPPORTED_FEATURES: Feature = Feature::RING_INDIRECT_DESC.union(Feature::RING_EVENT_IDX);
+
+/// The most PCM streams which the driver supports, as it keeps the parameters of each.
+pub const MAX_STREAMS: u32 = 256;
+
+/// Driver for a VirtIO sound device.
+///
+/// The device has a number of jacks, PCM streams and channel maps, which can be enumerated with
+/// [`jack_info`](Self::jack_info), [`pcm_info`](Self::pcm_info) and
+/// [`chmap_info`](Self::chmap_info). To play audio, configure an output stream with
+/// [`pcm_set_params`](Self::pcm_set_params), then [`pcm_prepare`](Self::pcm_prepare) and
+/// [`pcm_start`](Self::pcm_start) it and write frames with [`pcm_write`](Self::pcm_write). Frames
+/// are sent to the device one period at a time, with up to a buffer's worth of periods in flight.
+///
+/// # Example
+///
+/// ```
+/// # use virtio_drivers::{Error, Hal, transport::Transport};
+/// use virtio_drivers::device::sound::{PcmDirection, PcmFormat, PcmParams, PcmRate, VirtIOSound};
+/// # fn example<HalImpl: Hal, T: Transport>(transport: T, frames: &[u8]) -> Result<(), Error> {
+/// let mut sound = VirtIOSound::<HalImpl, _>::new(transport)?;
+///
+/// let streams = sound.pcm_info(0, sound.info().streams)?;
+/// if let Some(stream) = streams.iter().position(|s| s.direction == PcmDirection::Output) {
+///     let stream = stream as u32;
+///     sound.pcm_set_params(
+///         stream,
+///         &PcmParams {
+///             buffer_bytes: 16384,
+///             period_bytes: 4096,
+///             channels: 2,
+///             format: PcmFormat::S16,
+///             rate: PcmRate::Rate48000,
+///         },
+///     )?;
+///     sound.pcm_prepare(stream)?;
+///     sound.pcm_start(stream)?;
+///     sound.pcm_write(stream, frames)?;
+///     sound.pcm_stop(stream)?;
+///     sound.pcm_release(stream)?;
+/// }
+/// # Ok(())
+/// # }
+/// ```
+pub struct VirtIOSound<H: Hal, T: Transport> {
+    id: DriverId,
+    transport: T,
+    queues: QueueLayout,
+    control_queue: VirtQueue<H, CONTROL_QUEUE_SIZE>,
+    event_queue: VirtQueue<H, EVENT_QUEUE_SIZE>,
+    tx_queue: VirtQueue<H, PCM_QUEUE_SIZE>,
+    rx_queue: VirtQueue<H, PCM_QUEUE_SIZE>,
+    /// The buffers for the event queue, one per descriptor.
+    event_buf: Box<[[u8; EVENT_SIZE]; EVENT_QUEUE_SIZE]>,
+    info: SoundInfo,
+    /// The parameters last set for each PCM stream.
+    params: Vec<Option<PcmParams>>,
+}
+
+/// Information about a sound device, read from its configuration space.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SoundInfo {
+    /// The number of jacks.
+    pub jacks: u32,
+    /// The number of PCM streams.
+    pub streams: u32,
+    /// The number of channel maps.
+    pub chmaps: u32,
+}
+
+impl Display for SoundInfo {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} jacks, {} PCM streams, {} channel maps",
+            self.jacks, self.streams, self.chmaps
+        )
+    }
+}
+
+impl<H: Hal, T: Transport> VirtIOSound<H, T> {
+    /// Creates a new VirtIO sound driver.
+    pub fn new(mut transport: T) -> Result<Self> {
+        let negotiated_features = transport.begin_init(SUPPORTED_FEATURES);
+
+        let info = SoundInfo {
+            jacks: transport.read_config(offset_of!(Config, jacks))?,
+            streams: transport.read_config(offset_of!(Config, streams))?,
+            chmaps: transport.read_config(offset_of!(Config, chmaps))?,
+        };
+        info!("found a sound device: {}", info);
+        if info.streams > MAX_STREAMS {
+            warn!(
+                "Sound device has {} PCM streams, more than the {} supported",
+                info.streams, MAX_STREAMS
+            );
+            return Err(Error::Unsupported);
+        }
+
+        let queues = QueueLayout::new(DeviceType::Sound, negotiated_features.bits(), 0)?;
+        queues.validate(&mut transport)?;
+        let mut index = |role, size: usize| queues.checked_index(&mut transport, role, size as u32);
+        let control_index = index(QueueRole::Control, CONTROL_QUEUE_SIZE)?;
+        let event_index = index(QueueRole::Event, EVENT_QUEUE_SIZE)?;
+        let tx_index = index(QueueRole::Transmit(0), PCM_QUEUE_SIZE)?;
+        let rx_index = index(QueueRole::Receive(0), PCM_QUEUE_SIZE)?;
+
+        let indirect_desc = negotiated_features.contains(Feature::RING_INDIRECT_DESC);
+        let event_idx = negotiated_features.contains(Feature::RING_EVENT_IDX);
+        let control_queue =
+            VirtQueue::new(&mut transport, control_index, indirect_desc, event_idx)?;
+        let mut event_queue =
+            VirtQueue::new(&mut transport, event_index, indirect_desc, event_idx)?;
+        let mut event_buf = Box::new([[0; EVENT_SIZE]; EVENT_QUEUE_SIZE]);
+        for (i, event) in event_buf.iter_mut().enumerate() {
+            // Safe because the buffer lives as long as the queue.
+            let token = unsafe { event_queue.add(&[], &mut [event])? };
+            assert_eq!(token, i as u16);
+        }
+        if event_queue.should_notify() {
+            event_queue.notify(&mut transport);
+        }
+        let tx_queue = VirtQueue::new(&mut transport, tx_index, indirect_desc, event_idx)?;
+        let rx_queue = VirtQueue::new(&mut transport, rx_index, indirect_desc, event_idx)?;
+        transport.finish_init();
+
+        Ok(Self {
+            id: DriverId::allocate(),
+            transport,
+            queues,
+            control_queue,
+            event_queue,
+            tx_queue,
+            rx_queue,
+            event_buf,
+            info,
+            params: vec![None; info.streams as usize],
+        })
+    }
+
+    /// Returns the ID assigned to this driver when it was constructed, by which interrupt handlers
+    /// and diagnostics can refer to it in a [`Registry`](crate::registry::Registry).
+    pub fn id(&self) -> DriverId {
+        self.id
+    }
+
+    /// Returns information about the device read from its configuration space.
+    pub fn info(&self) -> &SoundInfo {
+        &self.info
+    }
+
+    /// Returns information about `count` jacks, starting from the one with ID `start_id`.
+    pub fn jack_info(&mut self, start_id: u32, count: u32) -> Result<Vec<JackInfo>> {
+        let jacks = self.info.jacks;
+        Ok(self
+            .query_info::<RawJackInfo>(R_JACK_INFO, start_id, count, jacks)?
+            .into_iter()
+            .map(|raw| JackInfo {
+                hda_fn_nid: raw.hda_fn_nid,
+                features: raw.features,
+                hda_reg_defconf: raw.hda_reg_defconf,
+                hda_reg_caps: raw.hda_reg_caps,
+                connected: raw.connected != 0,
+            })
+            .collect())
+    }
+
+    /// Returns information about `count` PCM streams, starting from the one with ID `start_id`.
+    pub fn pcm_info(&mut self, start_id: u32, count: u32) -> Result<Vec<PcmInfo>> {
+        let streams = self.info.streams;
+        self.query_info::<RawPcmInfo>(R_PCM_INFO, start_id, count, streams)?
+            .into_iter()
+            .map(|raw| {
+                Ok(PcmInfo {
+                    hda_fn_nid: raw.hda_fn_nid,
+                    features: PcmFeatures::from_bits_retain(raw.features),
+                    formats: raw.formats,
+                    rates: raw.rates,
+                    direction: PcmDirection::from_raw(raw.direction)?,
+                    channels_min: raw.channels_min,
+                    channels_max: raw.channels_max,
+                })
+            })
+            .collect()
+    }
+
+    /// Returns information about `count` channel maps, starting from the one with ID `start_id`.
+    pub fn chmap_info(&mut self, start_id: u32, count: u32) -> Result<Vec<ChmapInfo>> {
+        let chmaps = self.info.chmaps;
+        self.query_info::<RawChmapInfo>(R_CHMAP_INFO, start_id, count, chmaps)?
+            .into_iter()
+            .map(|raw| {
+                let positions = raw
+                    .positions
+                    .get(..usize::from(raw.channels))
+                    .ok_or(Error::IoError)?;
+                Ok(ChmapInfo {
+                    hda_fn_nid: raw.hda_fn_nid,
+                    direction: PcmDirection::from_raw(raw.direction)?,
+                    positions: FixedBytes::from_slice(positions)?,
+                })
+            })
+            .collect()
+    }
+
+    /// Sets the format, rate, channel count and buffer layout of the given PCM stream.
+    ///
+    /// Returns [`Error::InvalidParam`] if the buffer isn't a non-zero whole number of periods.
+    pub fn pcm_set_params(&mut self, stream_id: u32, params: &PcmParams) -> Result {
+        self.check_stream(stream_id)?;
+        if params.period_bytes == 0 || !params.buffer_bytes.is_multiple_of(params.period_bytes) {
+            return Err(Error::InvalidParam);
+        }
+        let request = PcmSetParams {
+            code: R_PCM_SET_PARAMS,
+            stream_id,
+            buffer_bytes: params.buffer_bytes,
+            period_bytes: params.period_bytes,
+            features: 0,
+            channels: params.channels,
+            format: params.format as u8,
+            rate: params.rate as u8,
+            padding: 0,
+        };
+        self.control(request.as_bytes(), &mut [])?;
+        self.params[stream_id as usize] = Some(*params);
+        Ok(())
+    }
+
+    /// Prepares the given PCM stream to be started, once its parameters have been set.
+    pub fn pcm_prepare(&mut self, stream_id: u32) -> Result {
+        self.pcm_command(R_PCM_PREPARE, stream_id)
+    }
+
+    /// Releases the resources which the device allocated for the given PCM stream when it was
+    /// prepared.
+    pub fn pcm_release(&mut self, stream_id: u32) -> Result {
+        self.pcm_command(R_PCM_RELEASE, stream_id)
+    }
+
+    /// Starts the given PCM stream, so the device starts consuming or producing frames.
+    pub fn pcm_start(&mut self, stream_id: u32) -> Result {
+        self.pcm_command(R_PCM_START, stream_id)
+    }
+
+    /// Stops the given PCM stream.
+    pub fn pcm_stop(&mut self, stream_id: u32) -> Result {
+        self.pcm_command(R_PCM_STOP, stream_id)
+    }
+
+    /// Plays the given frames on the given output stream, blocking until the device has consumed
+    /// them all.
+    ///
+    /// The frames are sent one period at a time, keeping up to a buffer's worth of periods queued
+    /// so that the device doesn't run dry between them. The stream must have been started, as the
+    /// device only consumes periods while it is running.
+    ///
+    /// Returns [`Error::InvalidParam`] if the stream's parameters haven't been set or `frames`
+    /// isn't a whole number of periods.
+    pub fn pcm_write(&mut self, stream_id: u32, frames: &[u8]) -> Result {
+        let params = self.stream_params(stream_id, frames.len())?;
+        transfer(
+            &mut self.transport,
+            &mut self.tx_queue,
+            stream_id,
+            &params,
+            Frames::Playback(frames),
+        )
+    }
+
+    /// Records frames from the given input stream into `frames`, blocking until it is full.
+    ///
+    /// As for [`pcm_write`](Self::pcm_write), the stream must have been started and `frames` must
+    /// be a whole number of periods.
+    pub fn pcm_read(&mut self, stream_id: u32, frames: &mut [u8]) -> Result {
+        let params = self.stream_params(stream_id, frames.len())?;
+        transfer(
+            &mut self.transport,
+            &mut self.rx_queue,
+            stream_id,
+            &params,
+            Frames::Capture(frames),
+        )
+    }
+
+    /// Returns the next event from the device's event queue, if there is one, and gives its buffer
+    /// back to the device.
+    pub fn poll_event(&mut self) -> Result<Option<SoundEvent>> {
+        let Some(token) = self.event_queue.peek_used() else {
+            return Ok(None);
+        };
+        let buffer = self
+            .event_buf
+            .get_mut(usize::from(token))
+            .ok_or(Error::WrongToken)?;
+        // Safe because we are passing the same buffer as we passed to `VirtQueue::add` and it is
+        // still valid.
+        let written = unsafe { self.event_queue.pop_used(token, &[], &mut [buffer])? };
+        self.event_queue.check_written(written, EVENT_SIZE);
+        let event = SoundEvent::read_from(buffer).ok_or(Error::IoError)?;
+        // Safe because the buffer lives as long as the queue.
+        let new_token = unsafe { self.event_queue.add(&[], &mut [buffer])? };
+        // Nothing else has used the queue since `pop_used` freed the descriptor, so `add` reuses
+        // it.
+        assert_eq!(new_token, token);
+        if self.event_queue.should_notify() {
+            self.event_queue.notify(&mut self.transport);
+        }
+        Ok(Some(event))
+    }
+
+    /// Acknowledges a pending interrupt, if any.
+    ///
+    /// Returns true if there was an interrupt to acknowledge.
+    pub fn ack_interrupt(&mut self) -> bool {
+        self.transport.ack_interrupt()
+    }
+
+    /// Sends a query for `count` items of type `I` starting from `start_id`, of which the device
+    /// has `total`, and returns the device's response.
+    fn query_info<I: FromBytes>(
+        &mut self,
+        code: u32,
+        start_id: u32,
+        count: u32,
+        total: u32,
+    ) -> Result<Vec<I>> {
+        if start_id.checked_add(count).is_none_or(|end| end > total) {
+            return Err(Error::InvalidParam);
+        }
+        let request = QueryInfo {
+            code,
+            start_id,
+            count,
+            size: size_of::<I>() as u32,
+        };
+        let mut response = vec![0; count as usize * size_of::<I>()];
+        self.control(request.as_bytes(), &mut response)?;
+        Ok(response
+            .chunks_exact(size_of::<I>())
+            .filter_map(I::read_from)
+            .collect())
+    }
+
+    /// Sends a PCM command which takes no arguments other than the stream ID.
+    fn pcm_command(&mut self, code: u32, stream_id: u32) -> Result {
+        self.check_stream(stream_id)?;
+        self.control(PcmHdr { code, stream_id }.as_bytes(), &mut [])
+    }
+
+    /// Sends the given request on the control queue and waits for the response, which is written
+    /// to `response` after the status header.
+    fn control(&mut self, request: &[u8], response: &mut [u8]) -> Result {
+        let mut status = Hdr::default();
+        let mut outputs = [status.as_bytes_mut(), response];
+        // The queue doesn't accept empty buffers, so leave out the response if there is none.
+        let outputs = if outputs[1].is_empty() {
+            &mut outputs[..1]
+        } else {
+            &mut outputs[..]
+        };
+        self.control_queue
+            .add_notify_wait_pop(&[request], outputs, &mut self.transport)?;
+        status_result(status.code)
+    }
+
+    /// Returns [`Error::InvalidParam`] if the device doesn't have the given PCM stream.
+    fn check_stream(&self, stream_id: u32) -> Result {
+        if stream_id < self.info.streams {
+            Ok(())
+        } else {
+            Err(Error::InvalidParam)
+        }
+    }
+
+    /// Returns the parameters of the given stream, or [`Error::InvalidParam`] if they haven't been
+    /// set or `len` isn't a whole number of periods.
+    fn stream_params(&self, stream_id: u32, len: usize) -> Result<PcmParams> {
+        self.check_stream(stream_id)?;
+        let params = self.params[stream_id as usize].ok_or(Error::InvalidParam)?;
+        if !len.is_multiple_of(params.period_bytes as usize) {
+            return Err(Error::InvalidParam);
+        }
+        Ok(params)
+    }
+}
+
+impl<H: Hal, T: Transport> Drop for VirtIOSound<H, T> {
+    fn drop(&mut self) {
+        // Clear any pointers pointing to DMA regions, so the device doesn't try to access them
+        // after they have been freed.
+        for (index, _) in self.queues.queues() {
+            self.transport.queue_unset(index);
+        }
+    }
+}
+
+/// The frames for a transfer on one of the PCM queues.
+enum Frames<'a> {
+    Playback(&'a [u8]),
+    Capture(&'a mut [u8]),
+}
+
+/// Transfers the given frames to or from the given stream one period at a time, keeping as many
+/// periods in flight as the stream's buffer holds, and waits for them all to complete.
+///
+/// If a period fails, the periods already in flight are still waited for before returning the
+/// error. If the device returns a transfer which can't be popped, so the rest can't be waited for,
+/// the device is reset so that it stops accessing the frames.
+fn transfer<H: Hal, T: Transport>(
+    transport: &mut T,
+    queue: &mut VirtQueue<H, PCM_QUEUE_SIZE>,
+    stream_id: u32,
+    params: &PcmParams,
+    mut frames: Frames,
+) -> Result {
+    let period_bytes = params.period_bytes as usize;
+    let periods = match &frames {
+        Frames::Playback(data) => data.len(),
+        Frames::Capture(data) => data.len(),
+    } / period_bytes;
+    let max_in_flight =
+        ((params.buffer_bytes / params.period_bytes) as usize).clamp(1, MAX_PERIODS_IN_FLIGHT);
+    let period =
+        |index: usize| -> Range<usize> { index * period_bytes..(index + 1) * period_bytes };
+
+    let xfer = PcmXfer { stream_id };
+    let mut statuses = [PcmStatus::default(); MAX_PERIODS_IN_FLIGHT];
+    // The token and period index of the transfer using each status buffer, if any.
+    let mut slots = [None; MAX_PERIODS_IN_FLIGHT];
+    let mut next_period = 0;
+    let mut result = Ok(());
+    loop {
+        // Keep the device supplied with periods, unless something has already gone wrong.
+        while result.is_ok() && next_period < periods {
+            let Some(slot) = slots[..max_in_flight].iter().position(Option::is_none) else {
+                break;
+            };
+            let status = statuses[slot].as_bytes_mut();
+            let range = period(next_period);
+            // Safe because the buffers aren't accessed again until the transfer has been popped
+            // below, which happens for every transfer before this function returns.
+            let added = unsafe {
+                match &mut frames {
+                    Frames::Playback(data) => {
+                        queue.add(&[xfer.as_bytes(), &data[range]], &mut [status])
+                    }
+                    Frames::Capture(data) => {
+                        queue.add(&[xfer.as_bytes()], &mut [&mut data[range], status])
+                    }
+                }
+            };
+            match added {
+                Ok(token) => {
+                    slots[slot] = Some((token, next_period));
+                    next_period += 1;
+                }
+                Err(e) => result = Err(e),
+            }
+        }
+        if slots.iter().all(Option::is_none) {
+            return result;
+        }
+        if queue.should_notify() {
+            queue.notify(transport);
+        }
+
+        // Wait for the device to finish with one of the periods in flight.
+        let token = loop {
+            if let Some(token) = queue.peek_used() {
+                break token;
+            }
+            spin_loop();
+        };
+        let slot = slots
+            .iter()
+            .enumerate()
+            .find_map(|(slot, transfer)| match *transfer {
+                Some((t, index)) if t == token => Some((slot, index)),
+                _ => None,
+            });
+        let Some((slot, index)) = slot else {
+            return Err(abandon_transfers(transport, Error::WrongToken));
+        };
+        let status = statuses[slot].as_bytes_mut();
+        let range = period(index);
+        // Safe because these are the same buffers as were passed to `add` for the token.
+        let popped = unsafe {
+            match &mut frames {
+                Frames::Playback(data) => {
+                    queue.pop_used(token, &[xfer.as_bytes(), &data[range]], &mut [status])
+                }
+                Frames::Capture(data) => {
+                    queue.pop_used(token, &[xfer.as_bytes()], &mut [&mut data[range], status])
+                }
+            }
+        };
+        let written = match popped {
+            Ok(written) => written as usize,
+            Err(e) => return Err(abandon_transfers(transport, e)),
+        };
+        slots[slot] = None;
+        let expected = match frames {
+            Frames::Playback(_) => size_of::<PcmStatus>(),
+            Frames::Capture(_) => period_bytes + size_of::<PcmStatus>(),
+        };
+        if result.is_ok() {
+            result = if written < size_of::<PcmStatus>() {
+                Err(Error::IoError)
+            } else {
+                status_result(statuses[slot].status)
+            };
+            // A capture period which the device didn't fill would leave a gap in the frames.
+            if result.is_ok() && written != expected {
+                result = Err(Error::IoError);
+            }
+        }
+    }
+}
+
+/// Resets the device because the transfers in flight on one of its PCM queues can't be popped, so
+/// that it stops accessing their frames before they are given back to the caller.
+///
+/// Returns the error which caused this.
+fn abandon_transfers(transport: &mut impl Transport, error: Error) -> Error {
+    warn!("Resetting sound device after PCM queue error: {}", error);
+    transport.set_status(DeviceStatus::empty());
+    error
+}
+
+/// Converts a status code from the device to a result.
+fn status_result(code: u32) -> Result {
+    match code {
+        S_OK => Ok(()),
+        S_BAD_MSG => Err(Error::InvalidParam),
+        S_NOT_SUPP => Err(Error::Unsupported),
+        _ => Err(Error::IoError),
+    }
+}
+
+/// Information about a jack, from [`VirtIOSound::jack_info`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct JackInfo {
+    /// The HDA function group node ID which the jack belongs to.
+    pub hda_fn_nid: u32,
+    /// The jack's feature bits.
+    pub features: u32,
+    /// The HDA pin configuration default register value.
+    pub hda_reg_defconf: u32,
+    /// The HDA pin capabilities register value.
+    pub hda_reg_caps: u32,
+    /// Whether an external device is plugged into the jack.
+    pub connected: bool,
+}
+
+/// Information about a PCM stream, from [`VirtIOSound::pcm_info`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PcmInfo {
+    /// The HDA function group node ID which the stream belongs to.
+    pub hda_fn_nid: u32,
+    /// The optional features which the stream supports.
+    pub features: PcmFeatures,
+    /// The bitmap of supported [`PcmFormat`]s. See [`supports_format`](Self::supports_format).
+    pub formats: u64,
+    /// The bitmap of supported [`PcmRate`]s. See [`supports_rate`](Self::supports_rate).
+    pub rates: u64,
+    /// Whether the stream plays or records audio.
+    pub direction: PcmDirection,
+    /// The minimum number of channels.
+    pub channels_min: u8,
+    /// The maximum number of channels.
+    pub channels_max: u8,
+}
+
+impl PcmInfo {
+    /// Returns whether the stream supports the given sample format.
+    pub fn supports_format(&self, format: PcmFormat) -> bool {
+        self.formats & (1 << format as u8) != 0
+    }
+
+    /// Returns whether the stream supports the given frame rate.
+    pub fn supports_rate(&self, rate: PcmRate) -> bool {
+        self.rates & (1 << rate as u8) != 0
+    }
+}
+
+/// Information about a channel map, from [`VirtIOSound::chmap_info`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct ChmapInfo {
+    /// The HDA function group node ID which the channel map belongs to.
+    pub hda_fn_nid: u32,
+    /// The direction of the streams which the channel map applies to.
+    pub direction: PcmDirection,
+    /// The position of each channel, as `VIRTIO_SND_CHMAP_*` values.
+    pub positions: FixedBytes<MAX_CHANNELS>,
+}
+
+/// The maximum number of channels in a channel map.
+pub const MAX_CHANNELS: usize = 18;
+
+/// Whether a PCM stream plays or records audio.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PcmDirection {
+    /// The stream plays audio, with frames sent on the transmit queue.
+    Output,
+    /// The stream records audio, with frames received on the receive queue.
+    Input,
+}
+
+impl PcmDirection {
+    fn from_raw(direction: u8) -> Result<Self> {
+        match direction {
+            0 => Ok(Self::Output),
+            1 => Ok(Self::Input),
+            _ => Err(Error::IoError),
+        }
+    }
+}
+
+bitflags! {
+    /// Optional features of a PCM stream.
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct PcmFeatures: u32 {
+        /// Frames can be transferred through shared memory provided by the host.
+        const SHMEM_HOST = 1 << 0;
+        /// Frames can be transferred through shared memory provided by the guest.
+        const SHMEM_GUEST = 1 << 1;
+        /// The device can be polled for elapsed periods rather than notifying them.
+        const MSG_POLLING = 1 << 2;
+        /// The device sends [`SoundEvent::PcmPeriodElapsed`] for shared memory transfers.
+        const EVT_SHMEM_PERIODS = 1 << 3;
+        /// The device sends [`SoundEvent::PcmXrun`].
+        const EVT_XRUNS = 1 << 4;
+    }
+}
+
+impl_flags_display!(PcmFeatures);
+
+/// The parameters of a PCM stream, for [`VirtIOSound::pcm_set_params`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct PcmParams {
+    /// The size of the device's buffer for the stream, in bytes. This must be a whole number of
+    /// periods.
+    pub buffer_bytes: u32,
+    /// The size of a period, in bytes, which is the unit in which frames are transferred.
+    pub period_bytes: u32,
+    /// The number of channels.
+    pub channels: u8,
+    /// The sample format.
+    pub format: PcmFormat,
+    /// The frame rate.
+    pub rate: PcmRate,
+}
+
+/// A PCM sample format.
+#[allow(missing_docs)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum PcmFormat {
+    ImaAdpcm = 0,
+    MuLaw = 1,
+    ALaw = 2,
+    S8 = 3,
+    U8 = 4,
+    S16 = 5,
+    U16 = 6,
+    S18_3 = 7,
+    U18_3 = 8,
+    S20_3 = 9,
+    U20_3 = 10,
+    S24_3 = 11,
+    U24_3 = 12,
+    S20 = 13,
+    U20 = 14,
+    S24 = 15,
+    U24 = 16,
+    S32 = 17,
+    U32 = 18,
+    Float = 19,
+    Float64 = 20,
+    DsdU8 = 21,
+    DsdU16 = 22,
+    DsdU32 = 23,
+    Iec958Subframe = 24,
+}
+
+/// A PCM frame rate.
+#[allow(missing_docs)]
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+#[repr(u8)]
+pub enum PcmRate {
+    Rate5512 = 0,
+    Rate8000 = 1,
+    Rate11025 = 2,
+    Rate16000 = 3,
+    Rate22050 = 4,
+    Rate32000 = 5,
+    Rate44100 = 6,
+    Rate48000 = 7,
+    Rate64000 = 8,
+    Rate88200 = 9,
+    Rate96000 = 10,
+    Rate176400 = 11,
+    Rate192000 = 12,
+    Rate384000 = 13,
+}
+
+impl PcmRate {
+    /// Returns the rate in frames per second.
+    pub fn hz(self) -> u32 {
+        match self {
+            Self::Rate5512 => 5512,
+            Self::Rate8000 => 8000,
+            Self::Rate11025 => 11025,
+            Self::Rate16000 => 16000,
+            Self::Rate22050 => 22050,
+            Self::Rate32000 => 32000,
+            Self::Rate44100 => 44100,
+            Self::Rate48000 => 48000,
+            Self::Rate64000 => 64000,
+            Self::Rate88200 => 88200,
+            Self::Rate96000 => 96000,
+            Self::Rate176400 => 176400,
+            Self::Rate192000 => 192000,
+            Self::Rate384000 => 384000,
+        }
+    }
+}
+
+#[repr(C)]
+struct Config {
+    jacks: u32,
+    streams: u32,
+    chmaps: u32,
+}
+
+const R_JACK_INFO: u32 = 1;
+const R_PCM_INFO: u32 = 0x0100;
+const R_PCM_SET_PARAMS: u32 = 0x0101;
+const R_PCM_PREPARE: u32 = 0x0102;
+const R_PCM_RELEASE: u32 = 0x0103;
+const R_PCM_START: u32 = 0x0104;
+const R_PCM_STOP: u32 = 0x0105;
+const R_CHMAP_INFO: u32 = 0x0200;
+
+const S_OK: u32 = 0x8000;
+const S_BAD_MSG: u32 = 0x8001;
+const S_NOT_SUPP: u32 = 0x8002;
+
+/// `struct virtio_snd_hdr`, the header of control requests and responses.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct Hdr {
+    code: u32,
+}
+
+/// `struct virtio_snd_query_info`.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct QueryInfo {
+    code: u32,
+    start_id: u32,
+    count: u32,
+    size: u32,
+}
+
+/// `struct virtio_snd_jack_info`.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct RawJackInfo {
+    hda_fn_nid: u32,
+    features: u32,
+    hda_reg_defconf: u32,
+    hda_reg_caps: u32,
+    connected: u8,
+    padding: [u8; 7],
+}
+
+/// `struct virtio_snd_pcm_info`.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct RawPcmInfo {
+    hda_fn_nid: u32,
+    features: u32,
+    formats: u64,
+    rates: u64,
+    direction: u8,
+    channels_min: u8,
+    channels_max: u8,
+    padding: [u8; 5],
+}
+
+/// `struct virtio_snd_chmap_info`.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct RawChmapInfo {
+    hda_fn_nid: u32,
+    direction: u8,
+    channels: u8,
+    positions: [u8; MAX_CHANNELS],
+}
+
+/// `struct virtio_snd_pcm_hdr`, for PCM commands which take only a stream ID.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct PcmHdr {
+    code: u32,
+    stream_id: u32,
+}
+
+/// `struct virtio_snd_pcm_set_params`.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct PcmSetParams {
+    code: u32,
+    stream_id: u32,
+    buffer_bytes: u32,
+    period_bytes: u32,
+    features: u32,
+    channels: u8,
+    format: u8,
+    rate: u8,
+    padding: u8,
+}
+
+/// `struct virtio_snd_pcm_xfer`, the header of each period on the transmit and receive queues.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct PcmXfer {
+    stream_id: u32,
+}
+
+/// `struct virtio_snd_pcm_status`, which the device writes after each period.
+#[repr(C)]
+#[derive(AsBytes, Clone, Copy, Debug, Default, FromBytes, FromZeroes)]
+struct PcmStatus {
+    status: u32,
+    latency_bytes: u32,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use alloc::sync::Arc;
+    use core::ptr::NonNull;
+    use std::{sync::Mutex, thread};
+
+    fn make_transport(config_space: &mut Config) -> (FakeTransport<Config>, Arc<Mutex<State>>) {
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+                QueueStatus::default(),
+            ],
+            ..Default::default()
+        }));
+        let transport = FakeTransport {
+            device_type: DeviceType::Sound,
+            max_queue_size: PCM_QUEUE_SIZE as u32,
+            device_features: 0,
+            config_space: NonNull::from(config_space),
+            state: state.clone(),
+        };
+        (transport, state)
+    }
+
+    fn ok_response() -> Vec<u8> {
+        S_OK.to_le_bytes().to_vec()
+    }
+
+    #[test]
+    fn query_and_configure() {
+        let mut config_space = Config {
+            jacks: 0,
+            streams: 2,
+            chmaps: 1,
+        };
+        let (transport, state) = make_transport(&mut config_space);
+        let mut sound = VirtIOSound::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(
+            sound.info(),
+            &SoundInfo {
+                jacks: 0,
+                streams: 2,
+                chmaps: 1,
+            }
+        );
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_CONTROL, |request| {
+                    assert_eq!(
+                        request,
+                        QueryInfo {
+                            code: R_PCM_INFO,
+                            start_id: 0,
+                            count: 2,
+                            size: 32,
+                        }
+                        .as_bytes()
+                    );
+                    let mut response = ok_response();
+                    for direction in 0..2 {
+                        let info = RawPcmInfo {
+                            formats: 1 << PcmFormat::S16 as u8,
+                            rates: 1 << PcmRate::Rate48000 as u8,
+                            direction,
+                            channels_min: 1,
+                            channels_max: 2,
+                            ..Default::default()
+                        };
+                        response.extend_from_slice(info.as_bytes());
+                    }
+                    response
+                });
+
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_CONTROL, |_| {
+                    let mut response = ok_response();
+                    let mut positions = [0; MAX_CHANNELS];
+                    positions[..2].copy_from_slice(&[3, 4]);
+                    let info = RawChmapInfo {
+                        hda_fn_nid: 0,
+                        direction: 0,
+                        channels: 2,
+                        positions,
+                    };
+                    response.extend_from_slice(info.as_bytes());
+                    response
+                });
+
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_CONTROL, |request| {
+                    let request = PcmSetParams::read_from(request.as_slice()).unwrap();
+                    assert_eq!(request.code, R_PCM_SET_PARAMS);
+                    assert_eq!(request.stream_id, 1);
+                    assert_eq!(request.format, 5);
+                    assert_eq!(request.rate, 7);
+                    S_NOT_SUPP.to_le_bytes().to_vec()
+                });
+        });
+
+        let streams = sound.pcm_info(0, 2).unwrap();
+        assert_eq!(streams[0].direction, PcmDirection::Output);
+        assert_eq!(streams[1].direction, PcmDirection::Input);
+        assert!(streams[0].supports_format(PcmFormat::S16));
+        assert!(!streams[0].supports_format(PcmFormat::U8));
+        assert!(streams[0].supports_rate(PcmRate::Rate48000));
+        let chmaps = sound.chmap_info(0, 1).unwrap();
+        assert_eq!(&*chmaps[0].positions, &[3, 4]);
+        let params = PcmParams {
+            buffer_bytes: 8192,
+            period_bytes: 4096,
+            channels: 2,
+            format: PcmFormat::S16,
+            rate: PcmRate::Rate48000,
+        };
+        assert_eq!(sound.pcm_set_params(1, &params), Err(Error::Unsupported));
+        handle.join().unwrap();
+
+        // Invalid requests are rejected without being sent.
+        assert_eq!(sound.pcm_info(1, 2), Err(Error::InvalidParam));
+        assert_eq!(sound.pcm_start(2), Err(Error::InvalidParam));
+        assert_eq!(
+            sound.pcm_set_params(
+                0,
+                &PcmParams {
+                    period_bytes: 3000,
+                    ..params
+                }
+            ),
+            Err(Error::InvalidParam)
+        );
+        // Parameters which the device rejected aren't used for transfers.
+        assert_eq!(sound.pcm_read(1, &mut [0; 4096]), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn playback() {
+        let mut config_space = Config {
+            jacks: 0,
+            streams: 1,
+            chmaps: 0,
+        };
+        let (transport, state) = make_transport(&mut config_space);
+        let mut sound = VirtIOSound::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_CONTROL, |_| ok_response());
+
+            // Consume five periods, each of which is tagged with its index.
+            for period in 0..5u8 {
+                while !state
+                    .lock()
+                    .unwrap()
+                    .queue_has_available::<PCM_QUEUE_SIZE>(QUEUE_TX)
+                {
+                    thread::yield_now();
+                }
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<PCM_QUEUE_SIZE>(QUEUE_TX, |request| {
+                        assert_eq!(request.len(), 4 + 64);
+                        assert_eq!(request[..4], 0u32.to_le_bytes());
+                        assert!(request[4..].iter().all(|&byte| byte == period));
+                        PcmStatus {
+                            status: S_OK,
+                            latency_bytes: 0,
+                        }
+                        .as_bytes()
+                        .to_vec()
+                    });
+            }
+        });
+
+        sound
+            .pcm_set_params(
+                0,
+                &PcmParams {
+                    buffer_bytes: 128,
+                    period_bytes: 64,
+                    channels: 2,
+                    format: PcmFormat::S16,
+                    rate: PcmRate::Rate44100,
+                },
+            )
+            .unwrap();
+        let frames = (0..5u8).flat_map(|i| [i; 64]).collect::<Vec<_>>();
+        sound.pcm_write(0, &frames).unwrap();
+        handle.join().unwrap();
+        assert_eq!(sound.pcm_write(0, &frames[..100]), Err(Error::InvalidParam));
+    }
+
+    #[test]
+    fn playback_error_drains() {
+        let mut config_space = Config {
+            jacks: 0,
+            streams: 1,
+            chmaps: 0,
+        };
+        let (transport, state) = make_transport(&mut config_space);
+        let mut sound = VirtIOSound::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, QUEUE_CONTROL);
+            state
+                .lock()
+                .unwrap()
+                .read_write_queue::<CONTROL_QUEUE_SIZE>(QUEUE_CONTROL, |_| ok_response());
+
+            // Fail the first period, then complete the second which is already in flight and the
+            // single period of the next write.
+            for status in [S_BAD_MSG, S_OK, S_OK] {
+                while !state
+                    .lock()
+                    .unwrap()
+                    .queue_has_available::<PCM_QUEUE_SIZE>(QUEUE_TX)
+                {
+                    thread::yield_now();
+                }
+                state
+                    .lock()
+                    .unwrap()
+                    .read_write_queue::<PCM_QUEUE_SIZE>(QUEUE_TX, |_| {
+                        PcmStatus {
+                            status,
+                            latency_bytes: 0,
+                        }
+                        .as_bytes()
+                        .to_vec()
+                    });
+            }
+        });
+
+        sound
+            .pcm_set_params(
+                0,
+                &PcmParams {
+                    buffer_bytes: 128,
+                    period_bytes: 64,
+                    channels: 2,
+                    format: PcmFormat::S16,
+                    rate: PcmRate::Rate44100,
+                },
+            )
+            .unwrap();
+        assert_eq!(sound.pcm_write(0, &[0; 192]), Err(Error::InvalidParam));
+        // Both periods in flight were drained, so the queue can be used again.
+        sound.pcm_write(0, &[0; 64]).unwrap();
+        handle.join().unwrap();
+    }
+
+    #[test]
+    fn too_many_streams() {
+        let mut config_space = Config {
+            jacks: 0,
+            streams: MAX_STREAMS + 1,
+            chmaps: 0,
+        };
+        let (transport, _) = make_transport(&mut config_space);
+        assert_eq!(
+            VirtIOSound::<FakeHal, FakeTransport<Config>>::new(transport).err(),
+            Some(Error::Unsupported)
+        );
+    }
+
+    #[test]
+    fn events() {
+        let mut config_space = Config {
+            jacks: 1,
+            streams: 0,
+            chmaps: 0,
+        };
+        let (transport, state) = make_transport(&mut config_space);
+        let mut sound = VirtIOSound::<FakeHal, FakeTransport<Config>>::new(transport).unwrap();
+        assert_eq!(sound.poll_event(), Ok(None));
+
+        // More events than there are buffers can be received, as each is given back to the device.
+        for i in 0..EVENT_QUEUE_SIZE as u32 + 2 {
+            let mut event = [0; EVENT_SIZE];
+            event[..4].copy_from_slice(&0x1000u32.to_le_bytes());
+            event[4..].copy_from_slice(&i.to_le_bytes());
+            state
+                .lock()
+                .unwrap()
+                .write_to_queue::<EVENT_QUEUE_SIZE>(QUEUE_EVENT, &event);
+            assert_eq!(
+                sound.poll_event(),
+                Ok(Some(SoundEvent::JackConnected { jack_id: i }))
+            );
+        }
+        assert_eq!(sound.poll_event(), Ok(None));
+    }
+}
@@ -0,0 +1,191 @@
+//! Optional warnings about slow paths in the drivers, to help tune queue sizes and interrupt
+//! settings.
+//!
+//! All diagnostics are disabled by default. Drivers which support them take a
+//! [`SlowPathThresholds`], e.g. with [`VirtIOBlk::set_slow_path_thresholds`], and log a warning
+//! with the [`log`] crate whenever one of the thresholds is crossed:
+//!
+//! ```
+//! use core::time::Duration;
+//! use virtio_drivers::diagnostics::SlowPathThresholds;
+//!
+//! let thresholds = SlowPathThresholds {
+//!     queue_full: Some(16),
+//!     completion_latency: Some(Duration::from_millis(10)),
+//!     ..Default::default()
+//! };
+//! ```
+//!
+//! Each warning is only logged once when its threshold is crossed, and then not again until the
+//! condition has cleared, so that a persistently slow device doesn't flood the log.
+//!
+//! [`VirtIOBlk::set_slow_path_thresholds`]: crate::device::blk::VirtIOBlk::set_slow_path_thresholds
+
+use core::time::Duration;
+use log::warn;
+
+/// Thresholds beyond which drivers warn about slow paths.
+///
+/// Each threshold is `None` to disable the corresponding warning. The default disables them all.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SlowPathThresholds {
+    /// The number of consecutive attempts to add buffers to a queue which fail with
+    /// [`Error::QueueFull`](crate::Error::QueueFull), after which to warn that the queue may be
+    /// too small.
+    pub queue_full: Option<u32>,
+    /// The time from a buffer being made available to the device to the device using it, beyond
+    /// which to warn that the device is slow to respond to notifications.
+    ///
+    /// This needs a time source from [`Hal::timestamp`](crate::Hal::timestamp), and is ignored
+    /// without one. To keep the overhead constant only one buffer per queue is timed at once,
+    /// so it is a sample rather than every request.
+    pub completion_latency: Option<Duration>,
+    /// The number of consecutive interrupts which arrive while the driver has interrupts disabled,
+    /// after which to warn that interrupt suppression is ineffective and the device is ignoring
+    /// it.
+    pub ignored_suppression: Option<u32>,
+}
+
+/// Tracks the slow path diagnostics for a single virtqueue.
+#[derive(Debug, Default)]
+pub(crate) struct QueueMonitor {
+    thresholds: SlowPathThresholds,
+    /// The number of attempts to add buffers which failed with `QueueFull` since the last one which
+    /// succeeded.
+    consecutive_full: u32,
+    /// The head of the descriptor chain being timed, and when it was made available.
+    sample: Option<(u16, Duration)>,
+    /// Whether the last timed chain exceeded the latency threshold.
+    slow: bool,
+}
+
+impl QueueMonitor {
+    /// Sets the thresholds to warn at.
+    pub fn set_thresholds(&mut self, thresholds: SlowPathThresholds) {
+        self.thresholds = thresholds;
+        self.sample = None;
+    }
+
+    /// Records an attempt to add buffers to the queue, and whether it failed because the queue was
+    /// full.
+    ///
+    /// Returns true if this attempt crossed the threshold, and a warning was logged.
+    pub fn record_add(&mut self, queue: u16, full: bool) -> bool {
+        if !full {
+            self.consecutive_full = 0;
+            return false;
+        }
+        self.consecutive_full = self.consecutive_full.saturating_add(1);
+        if Some(self.consecutive_full) != self.thresholds.queue_full {
+            return false;
+        }
+        warn!(
+            "Queue {} was full {} times in a row; it may be too small for the workload",
+            queue, self.consecutive_full
+        );
+        true
+    }
+
+    /// Records that the descriptor chain with the given head was made available to the device,
+    /// starting to time it if no other chain is being timed.
+    pub fn record_available(&mut self, head: u16, timestamp: fn() -> Option<Duration>) {
+        if self.thresholds.completion_latency.is_none() || self.sample.is_some() {
+            return;
+        }
+        if let Some(now) = timestamp() {
+            self.sample = Some((head, now));
+        }
+    }
+
+    /// Records that the device used the descriptor chain with the given head.
+    ///
+    /// Returns true if the chain was being timed and exceeded the threshold for the first time
+    /// since one didn't, and a warning was logged.
+    pub fn record_used(
+        &mut self,
+        queue: u16,
+        head: u16,
+        timestamp: fn() -> Option<Duration>,
+    ) -> bool {
+        let (Some((sample_head, start)), Some(threshold)) =
+            (self.sample, self.thresholds.completion_latency)
+        else {
+            return false;
+        };
+        if sample_head != head {
+            return false;
+        }
+        self.sample = None;
+        let Some(now) = timestamp() else {
+            return false;
+        };
+        let latency = now.saturating_sub(start);
+        let was_slow = self.slow;
+        self.slow = latency > threshold;
+        if !self.slow || was_slow {
+            return false;
+        }
+        warn!(
+            "Queue {}: the device took {:?} to use a buffer, more than the {:?} threshold",
+            queue, latency, threshold
+        );
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicU64, Ordering};
+
+    #[test]
+    fn queue_full() {
+        let mut monitor = QueueMonitor::default();
+        assert!(!monitor.record_add(0, true));
+        monitor.set_thresholds(SlowPathThresholds {
+            queue_full: Some(2),
+            ..Default::default()
+        });
+        // A successful add resets the count.
+        assert!(!monitor.record_add(0, false));
+        assert!(!monitor.record_add(0, true));
+        assert!(monitor.record_add(0, true));
+        // Only warn once until the queue has space again.
+        assert!(!monitor.record_add(0, true));
+        assert!(!monitor.record_add(0, false));
+        assert!(!monitor.record_add(0, true));
+        assert!(monitor.record_add(0, true));
+    }
+
+    static NOW_MS: AtomicU64 = AtomicU64::new(0);
+
+    fn now() -> Option<Duration> {
+        Some(Duration::from_millis(NOW_MS.load(Ordering::SeqCst)))
+    }
+
+    #[test]
+    fn completion_latency() {
+        let mut monitor = QueueMonitor::default();
+        monitor.set_thresholds(SlowPathThresholds {
+            completion_latency: Some(Duration::from_millis(5)),
+            ..Default::default()
+        });
+
+        // Only the first chain is timed while it is outstanding.
+        monitor.record_available(1, now);
+        monitor.record_available(2, now);
+        NOW_MS.fetch_add(10, Ordering::SeqCst);
+        assert!(!monitor.record_used(0, 2, now));
+        assert!(monitor.record_used(0, 1, now));
+
+        // Don't warn again until a fast chain has been seen.
+        monitor.record_available(3, now);
+        NOW_MS.fetch_add(10, Ordering::SeqCst);
+        assert!(!monitor.record_used(0, 3, now));
+        monitor.record_available(4, now);
+        assert!(!monitor.record_used(0, 4, now));
+        monitor.record_available(5, now);
+        NOW_MS.fetch_add(10, Ordering::SeqCst);
+        assert!(monitor.record_used(0, 5, now));
+    }
+}
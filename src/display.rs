@@ -0,0 +1,82 @@
+//! Human-readable formatting of flags, for logs.
+
+use bitflags::{Bits, Flags};
+use core::fmt::{self, Display, Formatter, LowerHex};
+
+/// Formats a set of flags as the names of the flags which are set, separated by `|`, e.g.
+/// `CSUM|MRG_RXBUF|STATUS`.
+///
+/// Any bits without a name are appended in hex, and an empty set is formatted as `(none)`.
+pub(crate) struct FlagNames<'a, F>(pub &'a F);
+
+impl<F: Flags> Display for FlagNames<'_, F>
+where
+    F::Bits: LowerHex,
+{
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let mut names = self.0.iter_names();
+        let mut first = true;
+        for (name, _) in &mut names {
+            if !first {
+                f.write_str("|")?;
+            }
+            first = false;
+            f.write_str(name)?;
+        }
+        let remaining = names.remaining().bits();
+        if remaining != F::Bits::EMPTY {
+            if !first {
+                f.write_str("|")?;
+            }
+            write!(f, "{:#x}", remaining)
+        } else if first {
+            f.write_str("(none)")
+        } else {
+            Ok(())
+        }
+    }
+}
+
+/// Implements `Display` for the given flags types in terms of [`FlagNames`].
+macro_rules! impl_flags_display {
+    ($($flags:ty),+ $(,)?) => {
+        $(
+            impl core::fmt::Display for $flags {
+                fn fmt(&self, f: &mut core::fmt::Formatter) -> core::fmt::Result {
+                    core::fmt::Display::fmt(&$crate::display::FlagNames(self), f)
+                }
+            }
+        )+
+    };
+}
+
+pub(crate) use impl_flags_display;
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use bitflags::bitflags;
+
+    bitflags! {
+        #[derive(Copy, Clone, Debug, Eq, PartialEq)]
+        struct TestFlags: u8 {
+            const A = 1 << 0;
+            const B = 1 << 1;
+        }
+    }
+
+    #[test]
+    fn flag_names() {
+        assert_eq!(FlagNames(&TestFlags::empty()).to_string(), "(none)");
+        assert_eq!(FlagNames(&TestFlags::A).to_string(), "A");
+        assert_eq!(FlagNames(&TestFlags::all()).to_string(), "A|B");
+        assert_eq!(
+            FlagNames(&TestFlags::from_bits_retain(0b1010)).to_string(),
+            "B|0x8"
+        );
+        assert_eq!(
+            FlagNames(&TestFlags::from_bits_retain(0x80)).to_string(),
+            "0x80"
+        );
+    }
+}
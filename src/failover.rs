@@ -0,0 +1,233 @@
+//! Pairing of two devices of the same kind into a single logical device with automatic failover.
+//!
+//! A typical use is a VirtIO network device which offers `VIRTIO_NET_F_STANDBY` as the backup for a
+//! passthrough NIC with the same MAC address: traffic goes through the primary while it is usable,
+//! moves to the standby when its link goes down or it is hot-unplugged, and moves back once the
+//! primary is usable again.
+//!
+//! ```
+//! use virtio_drivers::failover::{Failover, FailoverMember, Slot};
+//!
+//! struct Nic {
+//!     link_up: bool,
+//! }
+//!
+//! impl FailoverMember for Nic {
+//!     fn is_usable(&self) -> bool {
+//!         self.link_up
+//!     }
+//! }
+//!
+//! let mut pair = Failover::new(Some(Nic { link_up: true }), Some(Nic { link_up: true }));
+//! assert_eq!(pair.active_slot(), Some(Slot::Primary));
+//!
+//! // The primary is unplugged, so traffic moves to the backup.
+//! pair.remove(Slot::Primary);
+//! assert_eq!(pair.active_slot(), Some(Slot::Backup));
+//! ```
+
+/// A device which can be one half of a [`Failover`] pair.
+pub trait FailoverMember {
+    /// Returns whether the device can currently carry traffic, e.g. whether its link is up.
+    fn is_usable(&self) -> bool;
+}
+
+/// One of the two positions in a [`Failover`] pair.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum Slot {
+    /// The preferred device, used whenever it is usable.
+    Primary,
+    /// The standby device, used while the primary isn't usable.
+    Backup,
+}
+
+impl Slot {
+    /// Returns the other slot of the pair.
+    pub fn other(self) -> Self {
+        match self {
+            Self::Primary => Self::Backup,
+            Self::Backup => Self::Primary,
+        }
+    }
+}
+
+/// Two devices presented as one, which switches between them as they become usable or unusable.
+///
+/// The pair doesn't watch the devices itself: the OS should call [`Failover::reevaluate`] after
+/// handling a configuration change interrupt from either device (e.g. a link status change), and
+/// [`Failover::remove`] and [`Failover::insert`] when a device is hot-unplugged or plugged.
+/// [`Failover::active_mut`] also reevaluates, so a driver which is only polled still fails over.
+#[derive(Debug)]
+pub struct Failover<D> {
+    primary: Option<D>,
+    backup: Option<D>,
+    active: Option<Slot>,
+    switches: u32,
+}
+
+impl<D: FailoverMember> Failover<D> {
+    /// Pairs the given devices, either of which may be missing to begin with.
+    pub fn new(primary: Option<D>, backup: Option<D>) -> Self {
+        let mut failover = Self {
+            primary,
+            backup,
+            active: None,
+            switches: 0,
+        };
+        failover.reevaluate();
+        failover.switches = 0;
+        failover
+    }
+
+    /// Returns the device in the given slot, if there is one.
+    pub fn get(&self, slot: Slot) -> Option<&D> {
+        match slot {
+            Slot::Primary => self.primary.as_ref(),
+            Slot::Backup => self.backup.as_ref(),
+        }
+    }
+
+    /// Returns the device in the given slot mutably, if there is one.
+    pub fn get_mut(&mut self, slot: Slot) -> Option<&mut D> {
+        match slot {
+            Slot::Primary => self.primary.as_mut(),
+            Slot::Backup => self.backup.as_mut(),
+        }
+    }
+
+    /// Returns which slot is currently carrying traffic, or `None` if the pair is empty.
+    pub fn active_slot(&self) -> Option<Slot> {
+        self.active
+    }
+
+    /// Returns the device currently carrying traffic, without reevaluating.
+    pub fn active(&self) -> Option<&D> {
+        self.get(self.active?)
+    }
+
+    /// Reevaluates which device should carry traffic, and returns it.
+    pub fn active_mut(&mut self) -> Option<&mut D> {
+        self.reevaluate();
+        self.get_mut(self.active?)
+    }
+
+    /// Returns the number of times traffic has moved from one device to the other.
+    pub fn switches(&self) -> u32 {
+        self.switches
+    }
+
+    /// Chooses which device should carry traffic, returning the new slot if it changed.
+    ///
+    /// The primary is chosen whenever it is usable. Otherwise the backup is chosen if it is
+    /// usable, and if neither is then traffic stays where it was, as long as that device is still
+    /// present.
+    pub fn reevaluate(&mut self) -> Option<Slot> {
+        let usable = |slot| self.get(slot).is_some_and(D::is_usable);
+        let new = if usable(Slot::Primary) {
+            Some(Slot::Primary)
+        } else if usable(Slot::Backup) {
+            Some(Slot::Backup)
+        } else if self.active.is_some_and(|slot| self.get(slot).is_some()) {
+            self.active
+        } else if self.primary.is_some() {
+            Some(Slot::Primary)
+        } else if self.backup.is_some() {
+            Some(Slot::Backup)
+        } else {
+            None
+        };
+        if new == self.active {
+            return None;
+        }
+        if self.active.is_some() && new.is_some() {
+            self.switches += 1;
+        }
+        self.active = new;
+        new
+    }
+
+    /// Removes the device from the given slot, e.g. because it was hot-unplugged, and fails over
+    /// to the other one if it was active.
+    pub fn remove(&mut self, slot: Slot) -> Option<D> {
+        let device = match slot {
+            Slot::Primary => self.primary.take(),
+            Slot::Backup => self.backup.take(),
+        };
+        self.reevaluate();
+        device
+    }
+
+    /// Puts a device into the given slot, e.g. because it was hot-plugged, and returns the device
+    /// which was there before if any.
+    ///
+    /// If the new device is the primary and is usable, traffic moves back to it.
+    pub fn insert(&mut self, slot: Slot, device: D) -> Option<D> {
+        let previous = match slot {
+            Slot::Primary => self.primary.replace(device),
+            Slot::Backup => self.backup.replace(device),
+        };
+        self.reevaluate();
+        previous
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Debug, Eq, PartialEq)]
+    struct FakeNic {
+        name: &'static str,
+        link_up: bool,
+    }
+
+    impl FailoverMember for FakeNic {
+        fn is_usable(&self) -> bool {
+            self.link_up
+        }
+    }
+
+    fn nic(name: &'static str, link_up: bool) -> FakeNic {
+        FakeNic { name, link_up }
+    }
+
+    #[test]
+    fn link_failover() {
+        let mut pair = Failover::new(Some(nic("vf", false)), Some(nic("standby", true)));
+        assert_eq!(pair.active().unwrap().name, "standby");
+        assert_eq!(pair.switches(), 0);
+
+        // The primary's link comes up, so traffic fails back to it.
+        pair.get_mut(Slot::Primary).unwrap().link_up = true;
+        assert_eq!(pair.active_mut().unwrap().name, "vf");
+        assert_eq!(pair.switches(), 1);
+
+        // With both links down, traffic stays where it is.
+        pair.get_mut(Slot::Primary).unwrap().link_up = false;
+        pair.get_mut(Slot::Backup).unwrap().link_up = false;
+        assert_eq!(pair.reevaluate(), None);
+        assert_eq!(pair.active_slot(), Some(Slot::Primary));
+
+        pair.get_mut(Slot::Backup).unwrap().link_up = true;
+        assert_eq!(pair.reevaluate(), Some(Slot::Backup));
+        assert_eq!(pair.switches(), 2);
+    }
+
+    #[test]
+    fn hotplug() {
+        let mut pair = Failover::new(Some(nic("vf", true)), Some(nic("standby", true)));
+        assert_eq!(pair.active_slot(), Some(Slot::Primary));
+
+        assert_eq!(pair.remove(Slot::Primary), Some(nic("vf", true)));
+        assert_eq!(pair.active_slot(), Some(Slot::Backup));
+
+        assert_eq!(pair.insert(Slot::Primary, nic("vf2", true)), None);
+        assert_eq!(pair.active().unwrap().name, "vf2");
+
+        pair.remove(Slot::Primary);
+        pair.remove(Slot::Backup);
+        assert_eq!(pair.active_slot(), None);
+        assert!(pair.active_mut().is_none());
+        assert_eq!(pair.switches(), 3);
+    }
+}
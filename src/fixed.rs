@@ -0,0 +1,163 @@
+//! Fixed-capacity buffers for device metadata, such as serial numbers and EDID blobs, which are
+//! available without the `alloc` feature.
+
+use crate::{Error, Result};
+use core::{
+    fmt::{self, Debug, Display, Formatter},
+    ops::Deref,
+    str::{self, FromStr},
+};
+
+/// Up to `N` bytes, stored inline.
+#[derive(Copy, Clone, Eq, PartialEq)]
+pub struct FixedBytes<const N: usize> {
+    data: [u8; N],
+    len: usize,
+}
+
+impl<const N: usize> FixedBytes<N> {
+    /// Returns an empty buffer.
+    pub const fn new() -> Self {
+        Self {
+            data: [0; N],
+            len: 0,
+        }
+    }
+
+    /// Copies the given bytes into a new buffer, or returns [`Error::InvalidParam`] if there are
+    /// more than `N` of them.
+    pub fn from_slice(bytes: &[u8]) -> Result<Self> {
+        let mut buffer = Self::new();
+        buffer
+            .data
+            .get_mut(..bytes.len())
+            .ok_or(Error::InvalidParam)?
+            .copy_from_slice(bytes);
+        buffer.len = bytes.len();
+        Ok(buffer)
+    }
+
+    /// Returns the maximum number of bytes the buffer can hold.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Returns the bytes in the buffer.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data[..self.len]
+    }
+}
+
+impl<const N: usize> Default for FixedBytes<N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl<const N: usize> Deref for FixedBytes<N> {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        self.as_slice()
+    }
+}
+
+impl<const N: usize> Debug for FixedBytes<N> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Debug::fmt(self.as_slice(), f)
+    }
+}
+
+/// A UTF-8 string of up to `N` bytes, stored inline.
+#[derive(Copy, Clone, Default, Eq, PartialEq)]
+pub struct FixedString<const N: usize>(FixedBytes<N>);
+
+impl<const N: usize> FixedString<N> {
+    /// Returns an empty string.
+    pub const fn new() -> Self {
+        Self(FixedBytes::new())
+    }
+
+    /// Copies a string which devices report in a fixed-size field, stopping at the first NUL byte
+    /// if there is one.
+    ///
+    /// Returns [`Error::InvalidParam`] if the string is longer than `N` bytes or isn't valid UTF-8.
+    pub fn from_nul_padded(bytes: &[u8]) -> Result<Self> {
+        let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        let s = str::from_utf8(&bytes[..len]).map_err(|_| Error::InvalidParam)?;
+        s.parse()
+    }
+
+    /// Returns the maximum length of the string in bytes.
+    pub const fn capacity(&self) -> usize {
+        N
+    }
+
+    /// Returns the string.
+    pub fn as_str(&self) -> &str {
+        // Safe because the buffer is only ever filled from a `str`.
+        unsafe { str::from_utf8_unchecked(self.0.as_slice()) }
+    }
+}
+
+impl<const N: usize> FromStr for FixedString<N> {
+    type Err = Error;
+
+    /// Copies the given string, or returns [`Error::InvalidParam`] if it is longer than `N` bytes.
+    fn from_str(s: &str) -> Result<Self> {
+        FixedBytes::from_slice(s.as_bytes()).map(Self)
+    }
+}
+
+impl<const N: usize> Deref for FixedString<N> {
+    type Target = str;
+
+    fn deref(&self) -> &str {
+        self.as_str()
+    }
+}
+
+impl<const N: usize> Debug for FixedString<N> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl<const N: usize> Display for FixedString<N> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn fixed_bytes() {
+        let bytes = FixedBytes::<4>::from_slice(&[1, 2, 3]).unwrap();
+        assert_eq!(&*bytes, &[1, 2, 3]);
+        assert_eq!(bytes.capacity(), 4);
+        assert_eq!(
+            FixedBytes::<4>::from_slice(&[0; 5]),
+            Err(Error::InvalidParam)
+        );
+        assert!(FixedBytes::<4>::default().is_empty());
+    }
+
+    #[test]
+    fn fixed_string() {
+        let serial = FixedString::<20>::from_nul_padded(b"disk-1\0\0\0\0").unwrap();
+        assert_eq!(serial.as_str(), "disk-1");
+        assert_eq!(serial.len(), 6);
+        assert_eq!(
+            FixedString::<20>::from_nul_padded(b"01234567890123456789").map(|s| s.len()),
+            Ok(20)
+        );
+        assert_eq!("hello".parse::<FixedString<4>>(), Err(Error::InvalidParam));
+        assert_eq!(
+            FixedString::<4>::from_nul_padded(&[0xff, 0]),
+            Err(Error::InvalidParam)
+        );
+    }
+}
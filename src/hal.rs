@@ -1,8 +1,13 @@
+#[cfg(any(feature = "hal-impls", test))]
+pub mod bounce;
 #[cfg(test)]
 pub mod fake;
+#[cfg(any(feature = "hal-impls", test))]
+pub mod offset;
 
+use crate::transport::{QueueNotifyAddress, Transport};
 use crate::{Error, Result, PAGE_SIZE};
-use core::{marker::PhantomData, ptr::NonNull};
+use core::{marker::PhantomData, ptr::NonNull, time::Duration};
 
 /// A physical address as used for virtio.
 pub type PhysAddr = usize;
@@ -22,9 +27,27 @@ impl<H: Hal> Dma<H> {
     ///
     /// The pages will be zeroed.
     pub fn new(pages: usize, direction: BufferDirection) -> Result<Self> {
-        let (paddr, vaddr) = H::dma_alloc(pages, direction);
+        Self::new_with_locality(pages, direction, MemoryLocality::Any)
+    }
+
+    /// Allocates the given number of pages of physically contiguous memory to be used for DMA in
+    /// the given direction, preferably placed according to the given locality hint.
+    ///
+    /// The pages will be zeroed.
+    pub fn new_with_locality(
+        pages: usize,
+        direction: BufferDirection,
+        locality: MemoryLocality,
+    ) -> Result<Self> {
+        let (paddr, vaddr) = match locality {
+            MemoryLocality::Any => H::dma_alloc(pages, direction),
+            _ => H::dma_alloc_with_locality(pages, direction, locality),
+        };
         if paddr == 0 {
-            return Err(Error::DmaError);
+            return Err(Error::OutOfDmaMemory {
+                requested: pages * PAGE_SIZE,
+                align: PAGE_SIZE,
+            });
         }
         Ok(Self {
             paddr,
@@ -34,11 +57,32 @@ impl<H: Hal> Dma<H> {
         })
     }
 
+    /// Takes back ownership of a DMA region which was allocated by [`Dma::new`] and then leaked,
+    /// e.g. by a driver whose state was saved.
+    ///
+    /// # Safety
+    ///
+    /// The region must have been allocated by the HAL with the given number of pages at the given
+    /// physical and virtual addresses, not yet deallocated, and not owned by any other `Dma`.
+    pub(crate) unsafe fn from_raw_parts(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> Self {
+        Self {
+            paddr,
+            vaddr,
+            pages,
+            _hal: PhantomData,
+        }
+    }
+
     /// Returns the physical address of the start of the DMA region, as seen by devices.
     pub fn paddr(&self) -> usize {
         self.paddr
     }
 
+    /// Returns the NUMA node on which the DMA region was allocated, if the HAL knows it.
+    pub fn numa_node(&self) -> Option<NumaNode> {
+        H::dma_numa_node(self.paddr)
+    }
+
     /// Returns a pointer to the given offset within the DMA region.
     pub fn vaddr(&self, offset: usize) -> NonNull<u8> {
         assert!(offset < self.pages * PAGE_SIZE);
@@ -64,6 +108,19 @@ impl<H: Hal> Drop for Dma<H> {
 
 /// The interface which a particular hardware implementation must implement.
 ///
+/// See the example kernels in the repository for implementations on x86_64, aarch64 and RISC-V.
+///
+/// # Device addresses
+///
+/// The physical addresses returned by [`dma_alloc`](Self::dma_alloc) and [`share`](Self::share)
+/// are the ones which the device uses to access the memory. For a device behind an IOMMU these are
+/// the addresses mapped for it in the IOMMU rather than CPU physical addresses.
+///
+/// Every address which a driver gives a device comes from one of these methods, so drivers always
+/// accept `VIRTIO_F_ACCESS_PLATFORM` when a device offers it. A device behind an IOMMU offers it so
+/// that it translates the addresses it is given, and would otherwise either refuse the driver or
+/// treat them as raw guest physical addresses, bypassing the IOMMU.
+///
 /// # Safety
 ///
 /// Implementations of this trait must follow the "implementation safety" requirements documented
@@ -73,7 +130,11 @@ pub unsafe trait Hal {
     /// use.
     ///
     /// Returns both the physical address which the device can use to access the memory, and a
-    /// pointer to the start of it which the driver can use to access it.
+    /// pointer to the start of it which the driver can use to access it. See
+    /// [Device addresses](Hal#device-addresses).
+    ///
+    /// If the memory can't be allocated, implementations should return a physical address of 0,
+    /// which drivers will report as [`Error::OutOfDmaMemory`].
     ///
     /// # Implementation safety
     ///
@@ -92,6 +153,42 @@ pub unsafe trait Hal {
     /// `paddr` and `vaddr` must be the values returned by `dma_alloc`.
     unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32;
 
+    /// Allocates and zeroes the given number of contiguous physical pages of DMA memory for VirtIO
+    /// use, preferably placed according to the given locality hint.
+    ///
+    /// The hint is only a preference: implementations which can't honour it may fall back to any
+    /// memory. The default implementation ignores the hint and calls [`Hal::dma_alloc`]. Memory
+    /// allocated by this method is deallocated by [`Hal::dma_dealloc`] like any other DMA memory.
+    ///
+    /// # Implementation safety
+    ///
+    /// The same requirements apply as for [`Hal::dma_alloc`].
+    fn dma_alloc_with_locality(
+        pages: usize,
+        direction: BufferDirection,
+        locality: MemoryLocality,
+    ) -> (PhysAddr, NonNull<u8>) {
+        let _ = locality;
+        Self::dma_alloc(pages, direction)
+    }
+
+    /// Returns the NUMA node on which the DMA memory at the given physical address lives, if known.
+    ///
+    /// The default implementation returns `None`.
+    fn dma_numa_node(paddr: PhysAddr) -> Option<NumaNode> {
+        let _ = paddr;
+        None
+    }
+
+    /// Returns the current time from a monotonic clock, if the HAL has one.
+    ///
+    /// This is used for timestamps such as when transmitted packets were reclaimed, which network
+    /// stacks can use for pacing. The epoch doesn't matter, as only differences between timestamps
+    /// are meaningful. The default implementation returns `None`.
+    fn timestamp() -> Option<Duration> {
+        None
+    }
+
     /// Converts a physical address used for MMIO to a virtual address which the driver can access.
     ///
     /// This is only used for MMIO addresses within BARs read from the device, for the PCI
@@ -114,7 +211,8 @@ pub unsafe trait Hal {
     /// device can use to access it.
     ///
     /// This may involve mapping the buffer into an IOMMU, giving the host permission to access the
-    /// memory, or copying it to a special region where it can be accessed.
+    /// memory, or copying it to a special region where it can be accessed. The address returned is
+    /// the one which the device uses, as described in [Device addresses](Hal#device-addresses).
     ///
     /// # Safety
     ///
@@ -125,12 +223,87 @@ pub unsafe trait Hal {
     /// Unshares the given memory range from the device and (if necessary) copies it back to the
     /// original buffer.
     ///
+    /// Buffers shared as [`BufferDirection::DriverToDevice`] may come from shared references, so
+    /// must never be written to; only buffers which the device could write need be copied back.
+    /// The virtqueues check that the direction and length passed here match those passed to
+    /// `share`.
+    ///
     /// # Safety
     ///
     /// The buffer must be a valid pointer to a non-empty memory range which will not be accessed by
     /// any other thread for the duration of this method call. The `paddr` must be the value
     /// previously returned by the corresponding `share` call.
     unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection);
+
+    /// Notifies the device about new buffers in the given queues, where `notify` writes to the
+    /// transport's notification register as usual.
+    ///
+    /// Platforms where the doorbell needs special handling can override this, e.g. to switch to an
+    /// address space in which the notification region is mapped, or to signal the device with an
+    /// SBI call under a nested hypervisor instead of (or as well as) calling `notify`. Drivers call
+    /// this whenever they notify a device, so transports stay portable. The default implementation
+    /// just calls `notify`.
+    fn notify_hook(queues: &[u16], notify: &mut dyn FnMut()) {
+        let _ = queues;
+        notify();
+    }
+}
+
+/// Notifies the given queue on the device, through [`Hal::notify_hook`].
+pub(crate) fn notify<H: Hal>(transport: &mut impl Transport, queue: u16) {
+    H::notify_hook(&[queue], &mut || transport.notify(queue));
+}
+
+/// Notifies the given queue on the device through its doorbell, if the transport provided one,
+/// or otherwise through the transport, in either case through [`Hal::notify_hook`].
+///
+/// The doorbell must have come from [`Transport::queue_notify_address`] on the given transport.
+pub(crate) fn notify_queue<H: Hal>(
+    transport: &mut impl Transport,
+    queue: u16,
+    doorbell: Option<QueueNotifyAddress>,
+) {
+    match doorbell {
+        // Safe because the doorbell came from the transport, which is still alive as we have a
+        // reference to it.
+        Some(doorbell) => H::notify_hook(&[queue], &mut || unsafe { doorbell.notify() }),
+        None => notify::<H>(transport, queue),
+    }
+}
+
+/// Notifies the given queues on the device in one go, through [`Hal::notify_hook`].
+pub(crate) fn notify_multi<H: Hal>(transport: &mut impl Transport, queues: &[u16]) {
+    H::notify_hook(queues, &mut || transport.notify_multi(queues));
+}
+
+/// The identifier of a NUMA node, as understood by the [`Hal`] implementation.
+pub type NumaNode = u32;
+
+/// A hint about where DMA memory should be placed on a system with non-uniform memory access.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum MemoryLocality {
+    /// No preference; the memory may be allocated anywhere.
+    #[default]
+    Any,
+    /// The memory should be local to the given NUMA node.
+    Node(NumaNode),
+    /// The memory should be local to the given CPU, i.e. on the NUMA node which the CPU belongs to.
+    Cpu(u32),
+}
+
+/// What a driver should do if it can't allocate all the memory it would like during
+/// initialization.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum AllocFailurePolicy {
+    /// Fail initialization with [`Error::OutOfDmaMemory`] or [`Error::OutOfMemory`].
+    #[default]
+    Fail,
+    /// Carry on with fewer buffers than requested, as long as at least one could be allocated, and
+    /// with smaller virtqueues if there isn't enough DMA memory for full-size ones.
+    ///
+    /// Allocations which the driver can't work without, such as a virtqueue of the minimum size,
+    /// still fail.
+    Degrade,
 }
 
 /// The direction in which a buffer is passed.
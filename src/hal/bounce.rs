@@ -0,0 +1,388 @@
+//! A HAL adapter which bounces buffers through a pool of memory already shared with the host.
+
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use crate::{BufferDirection, Error, Hal, MemoryLocality, NumaNode, PhysAddr, Result, PAGE_SIZE};
+use alloc::vec::Vec;
+use core::{
+    marker::PhantomData,
+    ptr::NonNull,
+    sync::atomic::{AtomicU64, Ordering},
+    time::Duration,
+};
+
+/// A size class of a [`BouncePool`]: the pool has `count` slots which can each hold a buffer of up
+/// to `size` bytes.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SizeClass {
+    /// The size of each slot in bytes.
+    pub size: usize,
+    /// The number of slots of this size.
+    pub count: usize,
+}
+
+/// The slots of a single size class within the pool.
+#[derive(Debug)]
+struct ClassSlots {
+    size: usize,
+    /// The offset of the first slot from the start of the pool.
+    offset: usize,
+    count: usize,
+    /// A bitmap of which slots are in use.
+    used: Vec<AtomicU64>,
+}
+
+impl ClassSlots {
+    /// Claims a free slot, returning its index.
+    fn claim(&self) -> Option<usize> {
+        for (word_index, word) in self.used.iter().enumerate() {
+            let mut current = word.load(Ordering::Relaxed);
+            loop {
+                let bit = (!current).trailing_zeros() as usize;
+                let slot = word_index * 64 + bit;
+                if bit == 64 || slot >= self.count {
+                    break;
+                }
+                match word.compare_exchange_weak(
+                    current,
+                    current | 1 << bit,
+                    Ordering::Acquire,
+                    Ordering::Relaxed,
+                ) {
+                    Ok(_) => return Some(slot),
+                    Err(actual) => current = actual,
+                }
+            }
+        }
+        None
+    }
+
+    /// Releases a slot previously returned by `claim`.
+    fn release(&self, slot: usize) {
+        self.used[slot / 64].fetch_and(!(1 << (slot % 64)), Ordering::Release);
+    }
+
+    fn in_use(&self) -> usize {
+        self.used
+            .iter()
+            .map(|word| word.load(Ordering::Relaxed).count_ones() as usize)
+            .sum()
+    }
+}
+
+/// A pool of fixed-size slots in a region of memory which is permanently shared with the host, for
+/// bouncing buffers in confidential VMs such as SEV-SNP or TDX guests.
+///
+/// In such guests the host can't access private guest memory, so every buffer passed to the device
+/// must be copied to and from shared (unencrypted) memory. Converting memory between private and
+/// shared is expensive, so the pool is set up once over a region which the caller has already
+/// shared, and divided into slots of a few size classes. Each buffer is copied into the smallest
+/// free slot which can hold it, so no memory is allocated or converted per request.
+///
+/// The pool is used through [`BounceHal`].
+#[derive(Debug)]
+pub struct BouncePool {
+    paddr: PhysAddr,
+    vaddr: NonNull<u8>,
+    len: usize,
+    /// The size classes, in increasing order of size.
+    classes: Vec<ClassSlots>,
+}
+
+// Safe because the pool owns its region, and slots are only handed out to one user at a time.
+unsafe impl Send for BouncePool {}
+unsafe impl Sync for BouncePool {}
+
+impl BouncePool {
+    /// Creates a pool over the given region of shared memory, divided into the given size classes.
+    ///
+    /// The classes must be in increasing order of size, and each have a non-zero size and count.
+    /// Slots of each class are aligned to their size rounded up to a power of two, up to
+    /// [`PAGE_SIZE`]. Returns [`Error::InvalidParam`] if the classes are invalid or don't fit in
+    /// the region.
+    ///
+    /// # Safety
+    ///
+    /// `vaddr` must be valid for reads and writes of `len` bytes for the lifetime of the pool, and
+    /// not be accessed other than through the pool. `paddr` must be the physical address of the
+    /// region as the device sees it, and the region must already be shared with the host.
+    pub unsafe fn new(
+        paddr: PhysAddr,
+        vaddr: NonNull<u8>,
+        len: usize,
+        classes: &[SizeClass],
+    ) -> Result<Self> {
+        let mut slots = Vec::with_capacity(classes.len());
+        let mut end = 0;
+        let mut previous_size = 0;
+        for class in classes {
+            if class.size <= previous_size || class.count == 0 {
+                return Err(Error::InvalidParam);
+            }
+            previous_size = class.size;
+            let align = class.size.next_power_of_two().min(PAGE_SIZE);
+            let offset = (paddr + end).next_multiple_of(align) - paddr;
+            end = class
+                .size
+                .checked_mul(class.count)
+                .and_then(|size| size.checked_add(offset))
+                .ok_or(Error::InvalidParam)?;
+            slots.push(ClassSlots {
+                size: class.size,
+                offset,
+                count: class.count,
+                used: (0..class.count.div_ceil(64))
+                    .map(|_| AtomicU64::new(0))
+                    .collect(),
+            });
+        }
+        if end > len {
+            return Err(Error::InvalidParam);
+        }
+        Ok(Self {
+            paddr,
+            vaddr,
+            len,
+            classes: slots,
+        })
+    }
+
+    /// Returns the number of slots currently holding a buffer.
+    pub fn slots_in_use(&self) -> usize {
+        self.classes.iter().map(ClassSlots::in_use).sum()
+    }
+
+    /// Claims the smallest free slot which can hold `len` bytes, returning its class and index.
+    fn claim(&self, len: usize) -> Option<(usize, usize)> {
+        self.classes
+            .iter()
+            .enumerate()
+            .filter(|(_, class)| class.size >= len)
+            .find_map(|(class_index, class)| Some((class_index, class.claim()?)))
+    }
+
+    /// Returns the class and index of the slot starting at the given physical address, if it is
+    /// within the pool.
+    fn slot_at(&self, paddr: PhysAddr) -> Option<(usize, usize)> {
+        let offset = paddr
+            .checked_sub(self.paddr)
+            .filter(|&offset| offset < self.len)?;
+        self.classes
+            .iter()
+            .enumerate()
+            .find_map(|(class_index, class)| {
+                let slot = offset.checked_sub(class.offset)? / class.size;
+                (slot < class.count).then_some((class_index, slot))
+            })
+    }
+
+    fn slot_offset(&self, class: usize, slot: usize) -> usize {
+        let class = &self.classes[class];
+        class.offset + slot * class.size
+    }
+}
+
+/// Provides the [`BouncePool`] used by a [`BounceHal`].
+///
+/// As [`Hal`] methods don't take `self`, the pool must be reachable statically, e.g. from a
+/// `static` initialised during boot.
+pub trait BouncePoolSource {
+    /// Returns the pool to bounce buffers through.
+    fn bounce_pool() -> &'static BouncePool;
+}
+
+/// A [`Hal`] adapter for confidential VMs, which bounces shared buffers through the
+/// [`BouncePool`] provided by `P`.
+///
+/// Buffers are copied into a pool slot when shared, and back out when unshared if the device may
+/// have written them. Buffers which don't fit in any free slot fall back to `H::share`, as does
+/// everything other than sharing: DMA allocation, in particular, must return memory which is
+/// already shared with the host.
+#[derive(Debug)]
+pub struct BounceHal<H, P> {
+    _hal: PhantomData<H>,
+    _pool: PhantomData<P>,
+}
+
+unsafe impl<H: Hal, P: BouncePoolSource> Hal for BounceHal<H, P> {
+    fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+        H::dma_alloc(pages, direction)
+    }
+
+    unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        // Safe because our caller promises the same as the inner HAL requires.
+        unsafe { H::dma_dealloc(paddr, vaddr, pages) }
+    }
+
+    fn dma_alloc_with_locality(
+        pages: usize,
+        direction: BufferDirection,
+        locality: MemoryLocality,
+    ) -> (PhysAddr, NonNull<u8>) {
+        H::dma_alloc_with_locality(pages, direction, locality)
+    }
+
+    fn dma_numa_node(paddr: PhysAddr) -> Option<NumaNode> {
+        H::dma_numa_node(paddr)
+    }
+
+    fn timestamp() -> Option<Duration> {
+        H::timestamp()
+    }
+
+    unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+        // Safe because our caller promises the same as the inner HAL requires.
+        unsafe { H::mmio_phys_to_virt(paddr, size) }
+    }
+
+    unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+        let pool = P::bounce_pool();
+        let Some((class, slot)) = pool.claim(buffer.len()) else {
+            // Safe because our caller promises the same as the inner HAL requires.
+            return unsafe { H::share(buffer, direction) };
+        };
+        let offset = pool.slot_offset(class, slot);
+        // Safe because the slot is within the pool's region, which the pool owns, and we have
+        // just claimed it so nothing else is using it.
+        let shared = unsafe { pool.vaddr.as_ptr().add(offset) };
+        match direction {
+            // Safe because our caller promises that the buffer is valid, and the slot is at least
+            // as long.
+            BufferDirection::DriverToDevice | BufferDirection::Both => unsafe {
+                shared.copy_from_nonoverlapping(buffer.as_ptr().cast(), buffer.len());
+            },
+            // Clear the slot so that a short write by the device doesn't leave another request's
+            // data in the buffer.
+            BufferDirection::DeviceToDriver => unsafe {
+                shared.write_bytes(0, buffer.len());
+            },
+        }
+        pool.paddr + offset
+    }
+
+    unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+        let pool = P::bounce_pool();
+        let Some((class, slot)) = pool.slot_at(paddr) else {
+            // Safe because our caller promises the same as the inner HAL requires.
+            return unsafe { H::unshare(paddr, buffer, direction) };
+        };
+        if let BufferDirection::DeviceToDriver | BufferDirection::Both = direction {
+            // Safe because the slot is within the pool's region and holds this buffer, which our
+            // caller promises is valid.
+            unsafe {
+                pool.vaddr
+                    .as_ptr()
+                    .add(pool.slot_offset(class, slot))
+                    .copy_to_nonoverlapping(buffer.as_ptr().cast(), buffer.len());
+            }
+        }
+        pool.classes[class].release(slot);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+    use alloc::{boxed::Box, vec};
+    use core::ptr;
+    use std::sync::OnceLock;
+
+    const CLASSES: [SizeClass; 2] = [
+        SizeClass { size: 64, count: 2 },
+        SizeClass {
+            size: PAGE_SIZE,
+            count: 1,
+        },
+    ];
+
+    struct TestPool;
+
+    impl BouncePoolSource for TestPool {
+        fn bounce_pool() -> &'static BouncePool {
+            static POOL: OnceLock<BouncePool> = OnceLock::new();
+            POOL.get_or_init(|| {
+                // The region isn't page aligned, so leave room to align the page-sized slot.
+                let region = Box::leak(vec![0u8; 3 * PAGE_SIZE].into_boxed_slice());
+                let vaddr = NonNull::new(region.as_mut_ptr()).unwrap();
+                // Safe because the region is leaked, so lives forever and isn't used elsewhere.
+                unsafe { BouncePool::new(vaddr.as_ptr() as usize, vaddr, region.len(), &CLASSES) }
+                    .unwrap()
+            })
+        }
+    }
+
+    type TestHal = BounceHal<FakeHal, TestPool>;
+
+    fn slice_ptr(buffer: &mut [u8]) -> NonNull<[u8]> {
+        NonNull::new(ptr::slice_from_raw_parts_mut(
+            buffer.as_mut_ptr(),
+            buffer.len(),
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn invalid_classes() {
+        let mut region = [0u8; 256];
+        let vaddr = NonNull::new(region.as_mut_ptr()).unwrap();
+        let new = |classes: &[SizeClass]| unsafe { BouncePool::new(0x1000, vaddr, 256, classes) };
+        assert!(new(&CLASSES).is_err());
+        assert!(new(&[CLASSES[1], CLASSES[0]]).is_err());
+        assert!(new(&[SizeClass { size: 64, count: 0 }]).is_err());
+        let pool = new(&[SizeClass { size: 64, count: 4 }]).unwrap();
+        assert_eq!(pool.slot_at(0x1000 + 130), Some((0, 2)));
+        assert_eq!(pool.slot_at(0x1000 + 256), None);
+    }
+
+    #[test]
+    fn bounce() {
+        let pool = TestPool::bounce_pool();
+        let mut small = [42u8; 16];
+        let mut response = [0xffu8; 48];
+        let mut large = [7u8; 100];
+        let mut huge = vec![0u8; 2 * PAGE_SIZE];
+        unsafe {
+            let small_paddr =
+                TestHal::share(slice_ptr(&mut small), BufferDirection::DriverToDevice);
+            let response_paddr =
+                TestHal::share(slice_ptr(&mut response), BufferDirection::DeviceToDriver);
+            // Both small slots are taken, so this goes in the next class up.
+            let large_paddr = TestHal::share(slice_ptr(&mut large), BufferDirection::Both);
+            // Nothing in the pool is big enough, so this falls back to the inner HAL.
+            let huge_paddr = TestHal::share(slice_ptr(&mut huge), BufferDirection::DeviceToDriver);
+            assert!(pool.slot_at(huge_paddr).is_none());
+            assert_eq!(pool.slots_in_use(), 3);
+
+            // The device reads the request and writes the response.
+            let small_shared = small_paddr as *mut u8;
+            assert_eq!(*small_shared, 42);
+            small_shared.write(0);
+            let response_shared = response_paddr as *mut u8;
+            assert_eq!(*response_shared, 0);
+            response_shared.write_bytes(1, 48);
+            (large_paddr as *mut u8).write(8);
+
+            TestHal::unshare(
+                small_paddr,
+                slice_ptr(&mut small),
+                BufferDirection::DriverToDevice,
+            );
+            TestHal::unshare(
+                response_paddr,
+                slice_ptr(&mut response),
+                BufferDirection::DeviceToDriver,
+            );
+            TestHal::unshare(large_paddr, slice_ptr(&mut large), BufferDirection::Both);
+            TestHal::unshare(
+                huge_paddr,
+                slice_ptr(&mut huge),
+                BufferDirection::DeviceToDriver,
+            );
+        }
+        assert_eq!(small, [42; 16]);
+        assert_eq!(response, [1; 48]);
+        assert_eq!(large[..2], [8, 7]);
+        assert_eq!(pool.slots_in_use(), 0);
+    }
+}
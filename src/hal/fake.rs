@@ -2,18 +2,28 @@
 
 #![deny(unsafe_op_in_unsafe_fn)]
 
-use crate::{BufferDirection, Hal, PhysAddr, PAGE_SIZE};
-use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
+use crate::{BufferDirection, Hal, MemoryLocality, NumaNode, PhysAddr, PAGE_SIZE};
+use alloc::{
+    alloc::{alloc_zeroed, dealloc, handle_alloc_error},
+    collections::BTreeMap,
+};
 use core::{
     alloc::Layout,
+    fmt::Debug,
+    marker::PhantomData,
     ptr::{self, NonNull},
+    time::Duration,
 };
+use std::{sync::Mutex, time::Instant};
 use zerocopy::FromZeroes;
 
+/// The NUMA node requested for each DMA allocation made with a node hint, by physical address.
+static DMA_NODES: Mutex<BTreeMap<PhysAddr, NumaNode>> = Mutex::new(BTreeMap::new());
+
+/// Fake HAL implementation for use in unit tests.
 #[derive(Debug)]
 pub struct FakeHal;
 
-/// Fake HAL implementation for use in unit tests.
 unsafe impl Hal for FakeHal {
     fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
         assert_ne!(pages, 0);
@@ -27,8 +37,9 @@ unsafe impl Hal for FakeHal {
         }
     }
 
-    unsafe fn dma_dealloc(_paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+    unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
         assert_ne!(pages, 0);
+        DMA_NODES.lock().unwrap().remove(&paddr);
         let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
         // Safe because the layout is the same as was used when the memory was allocated by
         // `dma_alloc` above.
@@ -38,6 +49,36 @@ unsafe impl Hal for FakeHal {
         0
     }
 
+    fn dma_alloc_with_locality(
+        pages: usize,
+        direction: BufferDirection,
+        locality: MemoryLocality,
+    ) -> (PhysAddr, NonNull<u8>) {
+        let (paddr, vaddr) = Self::dma_alloc(pages, direction);
+        // Pretend that every CPU is on node 0, and that the requested node is always available.
+        let node = match locality {
+            MemoryLocality::Any | MemoryLocality::Cpu(_) => 0,
+            MemoryLocality::Node(node) => node,
+        };
+        DMA_NODES.lock().unwrap().insert(paddr, node);
+        (paddr, vaddr)
+    }
+
+    fn dma_numa_node(paddr: PhysAddr) -> Option<NumaNode> {
+        DMA_NODES.lock().unwrap().get(&paddr).copied()
+    }
+
+    fn timestamp() -> Option<Duration> {
+        static START: Mutex<Option<Instant>> = Mutex::new(None);
+        Some(
+            START
+                .lock()
+                .unwrap()
+                .get_or_insert_with(Instant::now)
+                .elapsed(),
+        )
+    }
+
     unsafe fn mmio_phys_to_virt(paddr: PhysAddr, _size: usize) -> NonNull<u8> {
         NonNull::new(paddr as _).unwrap()
     }
@@ -81,6 +122,63 @@ unsafe impl Hal for FakeHal {
     }
 }
 
+/// Hooks by which tests can change the behaviour of a [`HookedHal`].
+pub trait FakeHalHooks: Debug {
+    /// The largest number of pages which a single DMA allocation may have before it fails.
+    const MAX_DMA_PAGES: usize = usize::MAX;
+
+    /// Called whenever a buffer is shared with the device.
+    fn shared() {}
+
+    /// Called whenever a buffer is unshared from the device.
+    fn unshared() {}
+
+    /// Called instead of [`Hal::notify_hook`], e.g. to record which queues were notified.
+    fn notify_hook(queues: &[u16], notify: &mut dyn FnMut()) {
+        let _ = queues;
+        notify();
+    }
+}
+
+/// A fake HAL which behaves like [`FakeHal`] except where the hooks `K` say otherwise.
+#[derive(Debug)]
+pub struct HookedHal<K: FakeHalHooks>(PhantomData<K>);
+
+unsafe impl<K: FakeHalHooks> Hal for HookedHal<K> {
+    fn dma_alloc(pages: usize, direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+        if pages > K::MAX_DMA_PAGES {
+            return (0, NonNull::dangling());
+        }
+        FakeHal::dma_alloc(pages, direction)
+    }
+
+    unsafe fn dma_dealloc(paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        unsafe { FakeHal::dma_dealloc(paddr, vaddr, pages) }
+    }
+
+    fn timestamp() -> Option<Duration> {
+        FakeHal::timestamp()
+    }
+
+    unsafe fn mmio_phys_to_virt(paddr: PhysAddr, size: usize) -> NonNull<u8> {
+        unsafe { FakeHal::mmio_phys_to_virt(paddr, size) }
+    }
+
+    unsafe fn share(buffer: NonNull<[u8]>, direction: BufferDirection) -> PhysAddr {
+        K::shared();
+        unsafe { FakeHal::share(buffer, direction) }
+    }
+
+    unsafe fn unshare(paddr: PhysAddr, buffer: NonNull<[u8]>, direction: BufferDirection) {
+        K::unshared();
+        unsafe { FakeHal::unshare(paddr, buffer, direction) }
+    }
+
+    fn notify_hook(queues: &[u16], notify: &mut dyn FnMut()) {
+        K::notify_hook(queues, notify);
+    }
+}
+
 fn virt_to_phys(vaddr: usize) -> PhysAddr {
     vaddr
 }
@@ -0,0 +1,98 @@
+//! Ready-made HAL implementations for simple memory maps.
+
+#![deny(unsafe_op_in_unsafe_fn)]
+
+use crate::{BufferDirection, Hal, PhysAddr, PAGE_SIZE};
+use alloc::alloc::{alloc_zeroed, dealloc, handle_alloc_error};
+use core::{alloc::Layout, ptr::NonNull};
+
+/// A [`Hal`] implementation for systems where physical addresses are a fixed offset below virtual
+/// addresses, i.e. `vaddr == paddr + OFFSET`.
+///
+/// DMA memory is allocated from the global allocator, so this is only suitable if the heap is
+/// within the offset mapping. Buffers are shared in place, as the device is assumed to have access to
+/// all of guest memory; this won't work with an IOMMU or confidential computing.
+#[derive(Debug)]
+pub struct OffsetHal<const OFFSET: usize>;
+
+/// A [`Hal`] implementation for systems where virtual addresses are identical to physical addresses,
+/// such as bare-metal code running on QEMU's `virt` machine with the MMU off or identity mapped.
+///
+/// See [`OffsetHal`] for the limitations.
+pub type IdentityHal = OffsetHal<0>;
+
+impl<const OFFSET: usize> OffsetHal<OFFSET> {
+    /// Converts a virtual address to the corresponding physical address.
+    pub fn virt_to_phys(vaddr: usize) -> PhysAddr {
+        vaddr.wrapping_sub(OFFSET)
+    }
+
+    /// Converts a physical address to the corresponding virtual address.
+    pub fn phys_to_virt(paddr: PhysAddr) -> usize {
+        paddr.wrapping_add(OFFSET)
+    }
+}
+
+unsafe impl<const OFFSET: usize> Hal for OffsetHal<OFFSET> {
+    fn dma_alloc(pages: usize, _direction: BufferDirection) -> (PhysAddr, NonNull<u8>) {
+        assert_ne!(pages, 0);
+        let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+        // Safe because the size and alignment of the layout are non-zero.
+        let vaddr = unsafe { alloc_zeroed(layout) };
+        if let Some(vaddr) = NonNull::new(vaddr) {
+            (Self::virt_to_phys(vaddr.as_ptr() as usize), vaddr)
+        } else {
+            handle_alloc_error(layout)
+        }
+    }
+
+    unsafe fn dma_dealloc(_paddr: PhysAddr, vaddr: NonNull<u8>, pages: usize) -> i32 {
+        assert_ne!(pages, 0);
+        let layout = Layout::from_size_align(pages * PAGE_SIZE, PAGE_SIZE).unwrap();
+        // Safe because the memory was allocated by `dma_alloc` above using the same allocator, and
+        // the layout is the same as was used then.
+        unsafe {
+            dealloc(vaddr.as_ptr(), layout);
+        }
+        0
+    }
+
+    unsafe fn mmio_phys_to_virt(paddr: PhysAddr, _size: usize) -> NonNull<u8> {
+        NonNull::new(Self::phys_to_virt(paddr) as _).unwrap()
+    }
+
+    unsafe fn share(buffer: NonNull<[u8]>, _direction: BufferDirection) -> PhysAddr {
+        // Nothing to do, as the host already has access to all memory.
+        Self::virt_to_phys(buffer.as_ptr() as *mut u8 as usize)
+    }
+
+    unsafe fn unshare(_paddr: PhysAddr, _buffer: NonNull<[u8]>, _direction: BufferDirection) {
+        // Nothing to do, as the host already has access to all memory and we didn't copy the buffer
+        // anywhere else.
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::Dma;
+    use core::ptr::{self, NonNull};
+
+    #[test]
+    fn identity_dma() {
+        let dma = Dma::<IdentityHal>::new(2, BufferDirection::Both).unwrap();
+        assert_eq!(dma.paddr(), dma.vaddr(0).as_ptr() as usize);
+        assert_eq!(dma.paddr() % PAGE_SIZE, 0);
+    }
+
+    #[test]
+    fn offset_share() {
+        type Hal = OffsetHal<0x1000>;
+        let mut buffer = [0u8; 16];
+        let ptr = NonNull::new(ptr::slice_from_raw_parts_mut(buffer.as_mut_ptr(), 16)).unwrap();
+        let paddr = unsafe { Hal::share(ptr, BufferDirection::DriverToDevice) };
+        assert_eq!(paddr, buffer.as_ptr() as usize - 0x1000);
+        assert_eq!(Hal::phys_to_virt(paddr), buffer.as_ptr() as usize);
+        unsafe { Hal::unshare(paddr, ptr, BufferDirection::DriverToDevice) };
+    }
+}
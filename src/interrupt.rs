@@ -0,0 +1,711 @@
+//! Interrupt handling helpers shared by the device drivers.
+
+use crate::{Error, Hal, Result};
+use core::time::Duration;
+use log::warn;
+
+/// Counts of interrupts acknowledged by a driver.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct InterruptStats {
+    /// The total number of times the driver was asked to acknowledge an interrupt.
+    pub total: u64,
+    /// The number of those which were spurious, i.e. the device had no interrupt pending or there
+    /// were no used buffers to process.
+    pub spurious: u64,
+    /// The number of interrupts which the device seems to have raised but which never arrived, as
+    /// detected by the [`LostInterruptWatchdog`].
+    pub lost: u64,
+}
+
+/// When to suspect that an interrupt was lost, e.g. because the hypervisor occasionally drops
+/// MMIO interrupts.
+///
+/// Drivers which support this, e.g. with [`VirtIOBlk::set_lost_interrupt_watchdog`], have a
+/// `check_lost_interrupt` method to be called periodically, such as from a timer tick. Once
+/// requests have been outstanding with no interrupt arriving for the given number of checks or
+/// the given time, whichever comes first, the driver looks at its used rings itself. If the device
+/// has finished some requests then it logs a warning, counts a lost interrupt in its
+/// [`InterruptStats`], and the caller should handle the completions as if the interrupt had
+/// arrived.
+///
+/// The watchdog is disabled by default, and while interrupts are disabled.
+///
+/// [`VirtIOBlk::set_lost_interrupt_watchdog`]: crate::device::blk::VirtIOBlk::set_lost_interrupt_watchdog
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct LostInterruptWatchdog {
+    /// The number of checks with requests outstanding and no interrupt after which to look at the
+    /// used rings, if any.
+    pub checks: Option<u32>,
+    /// How long requests may be outstanding with no interrupt before looking at the used rings, if
+    /// there is a limit.
+    ///
+    /// This is measured with [`Hal::timestamp`], and is ignored if the HAL has no clock.
+    pub timeout: Option<Duration>,
+}
+
+/// Keeps track of spurious interrupts for a device, and decides when to give up on interrupts and
+/// switch to polling.
+#[derive(Debug, Default)]
+pub(crate) struct InterruptAccounting {
+    stats: InterruptStats,
+    /// The number of spurious interrupts since the last genuine one.
+    consecutive_spurious: u32,
+    /// The number of consecutive spurious interrupts after which to switch to polling, if any.
+    threshold: Option<u32>,
+    /// Whether the threshold has been exceeded and the driver switched to polling.
+    polling: bool,
+    /// Whether the caller explicitly disabled interrupts.
+    disabled: bool,
+    /// The number of consecutive interrupts which arrived while interrupts were disabled.
+    consecutive_suppressed: u32,
+    /// The number of consecutive interrupts while disabled after which to warn, if any.
+    suppression_warning: Option<u32>,
+    /// When to suspect that an interrupt was lost.
+    watchdog: LostInterruptWatchdog,
+    /// The number of watchdog checks with requests outstanding since the last interrupt.
+    quiet_checks: u32,
+    /// When the first of those checks happened, if the HAL has a clock.
+    quiet_since: Option<Duration>,
+}
+
+impl InterruptAccounting {
+    /// Returns the counts of interrupts so far.
+    pub fn stats(&self) -> InterruptStats {
+        self.stats
+    }
+
+    /// Sets the number of consecutive spurious interrupts after which to switch to polling, or
+    /// `None` to never switch.
+    pub fn set_threshold(&mut self, threshold: Option<u32>) {
+        self.threshold = threshold;
+    }
+
+    /// Returns the number of consecutive spurious interrupts after which to switch to polling, if
+    /// any.
+    pub fn threshold(&self) -> Option<u32> {
+        self.threshold
+    }
+
+    /// Records whether the caller explicitly disabled interrupts.
+    pub fn set_disabled(&mut self, disabled: bool) {
+        self.disabled = disabled;
+    }
+
+    /// Sets the number of consecutive interrupts arriving while interrupts are disabled after
+    /// which to warn that the device is ignoring interrupt suppression, or `None` to never warn.
+    pub fn set_suppression_warning(&mut self, threshold: Option<u32>) {
+        self.suppression_warning = threshold;
+    }
+
+    /// Returns whether interrupts are enabled, i.e. neither disabled by the caller nor switched off
+    /// because of too many spurious interrupts.
+    pub fn enabled(&self) -> bool {
+        !self.disabled && !self.polling
+    }
+
+    /// Returns whether the threshold was exceeded so the driver is polling rather than using
+    /// interrupts.
+    pub fn polling(&self) -> bool {
+        self.polling
+    }
+
+    /// Leaves polling mode, e.g. because the caller explicitly re-enabled interrupts.
+    pub fn reset_polling(&mut self) {
+        self.polling = false;
+        self.consecutive_spurious = 0;
+        self.consecutive_suppressed = 0;
+    }
+
+    /// Sets when to suspect that an interrupt was lost.
+    pub fn set_watchdog(&mut self, watchdog: LostInterruptWatchdog) {
+        self.watchdog = watchdog;
+        self.reset_watchdog();
+    }
+
+    /// Records a periodic watchdog check, given whether the driver has requests outstanding and
+    /// the current time if known.
+    ///
+    /// Returns true if the watchdog expired, so the driver should look at its used rings in case
+    /// the device finished some requests but its interrupt was lost. The watchdog then starts
+    /// again.
+    pub fn watchdog_check(&mut self, outstanding: bool, now: Option<Duration>) -> bool {
+        if !outstanding || !self.enabled() {
+            self.reset_watchdog();
+            return false;
+        }
+        self.quiet_checks = self.quiet_checks.saturating_add(1);
+        // Only start timing from a check which knows the time, rather than guessing that a check
+        // without one was at time zero.
+        if let Some(now) = now {
+            self.quiet_since.get_or_insert(now);
+        }
+        let checks_expired = self
+            .watchdog
+            .checks
+            .is_some_and(|checks| self.quiet_checks >= checks);
+        let timeout_expired = match (self.watchdog.timeout, now, self.quiet_since) {
+            (Some(timeout), Some(now), Some(since)) => now.saturating_sub(since) >= timeout,
+            _ => false,
+        };
+        if checks_expired || timeout_expired {
+            self.reset_watchdog();
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Counts an interrupt which the watchdog found to be lost.
+    pub fn record_lost(&mut self) {
+        self.stats.lost += 1;
+    }
+
+    fn reset_watchdog(&mut self) {
+        self.quiet_checks = 0;
+        self.quiet_since = None;
+    }
+
+    /// Records an interrupt, and whether it was spurious.
+    ///
+    /// Returns true if this interrupt crossed the threshold, so the driver should now disable
+    /// interrupts and switch to polling.
+    pub fn record(&mut self, spurious: bool) -> bool {
+        self.stats.total += 1;
+        // Any interrupt shows that they are being delivered.
+        self.reset_watchdog();
+        self.record_suppression(spurious);
+        if !spurious {
+            self.consecutive_spurious = 0;
+            return false;
+        }
+        self.stats.spurious += 1;
+        self.consecutive_spurious += 1;
+        match self.threshold {
+            Some(threshold) if !self.polling && self.consecutive_spurious >= threshold => {
+                self.polling = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    /// Warns if too many genuine interrupts have arrived in a row while interrupts were disabled.
+    ///
+    /// Returns true if this interrupt crossed the threshold, and a warning was logged.
+    fn record_suppression(&mut self, spurious: bool) -> bool {
+        if self.enabled() || spurious {
+            self.consecutive_suppressed = 0;
+            return false;
+        }
+        self.consecutive_suppressed = self.consecutive_suppressed.saturating_add(1);
+        if Some(self.consecutive_suppressed) != self.suppression_warning {
+            return false;
+        }
+        warn!(
+            "{} interrupts in a row arrived while interrupts were disabled; interrupt suppression \
+             is ineffective",
+            self.consecutive_suppressed
+        );
+        true
+    }
+}
+
+/// A device which can be serviced by [`poll_all`].
+///
+/// Drivers which finish their requests internally, such as by waking the tasks waiting for them,
+/// implement this themselves: the console and network drivers do, and so does the block driver
+/// for requests submitted asynchronously with the `async` feature. Other drivers need their
+/// requests to be completed with the buffers which were submitted, so can be wrapped in a type
+/// which also owns those buffers and implements this to complete them.
+pub trait VirtioDevice {
+    /// Acknowledges any pending interrupt and dispatches whatever the device has finished since the
+    /// last call.
+    ///
+    /// This must check the used rings even if no interrupt was pending, so that it works while
+    /// interrupts are disabled or lost.
+    ///
+    /// Returns the number of completions dispatched.
+    fn poll(&mut self) -> Result<usize>;
+}
+
+/// The outcome of a call to [`poll_all`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct PollSummary {
+    /// The total number of completions dispatched by all the devices.
+    pub completions: usize,
+    /// The number of devices which returned an error.
+    pub errors: usize,
+}
+
+/// Polls each of the given devices once, dispatching anything they have finished.
+///
+/// This is intended to be called once per iteration of the main loop of a kernel with a cooperative
+/// scheduler, instead of each device having its own loop. An error from one device is logged and
+/// counted in the summary, but doesn't stop the rest from being polled.
+pub fn poll_all(devices: &mut [&mut dyn VirtioDevice]) -> PollSummary {
+    let mut summary = PollSummary::default();
+    for (index, device) in devices.iter_mut().enumerate() {
+        match device.poll() {
+            Ok(completions) => summary.completions += completions,
+            Err(e) => {
+                warn!("Error polling device {}: {}", index, e);
+                summary.errors += 1;
+            }
+        }
+    }
+    summary
+}
+
+/// A device sharing an interrupt line with other devices, which can be serviced by
+/// [`dispatch_shared_irq`].
+///
+/// The drivers which implement [`VirtioDevice`] implement this too, with
+/// [`handle_interrupt`](Self::handle_interrupt) doing the same as [`VirtioDevice::poll`] after the
+/// interrupt is acknowledged. Other drivers can be wrapped in the same way as for `VirtioDevice`.
+pub trait SharedIrqDevice {
+    /// Checks whether the device is asserting an interrupt, and acknowledges it if so.
+    ///
+    /// Returns true if the device had an interrupt pending.
+    fn ack_interrupt(&mut self) -> bool;
+
+    /// Handles an interrupt which the device asserted, e.g. by processing its used rings or
+    /// rereading its configuration space.
+    ///
+    /// Returns the number of completions dispatched.
+    fn handle_interrupt(&mut self) -> Result<usize>;
+}
+
+/// The outcome of a call to [`dispatch_shared_irq`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct SharedIrqSummary {
+    /// The number of devices which were asserting the interrupt.
+    pub asserted: usize,
+    /// The total number of completions dispatched by those devices.
+    pub completions: usize,
+    /// The number of devices whose handler returned an error.
+    pub errors: usize,
+}
+
+impl SharedIrqSummary {
+    /// Returns whether any of the devices was asserting the interrupt, i.e. whether it was handled
+    /// rather than belonging to some other device on the line or being spurious.
+    pub fn handled(&self) -> bool {
+        self.asserted != 0
+    }
+}
+
+/// Handles an interrupt on a line shared by the given devices, such as on an MMIO platform which
+/// wires several devices to one interrupt or with PCI INTx sharing.
+///
+/// This checks the interrupt status of every device, and only calls the handlers of those which
+/// were asserting the interrupt. All the devices are checked even once one is found, as several may
+/// have raised the interrupt at once and a level-triggered line stays asserted until all of them
+/// are acknowledged. An error from one handler is logged and counted in the summary, but doesn't
+/// stop the rest from being dispatched.
+///
+/// The caller should report the interrupt to its interrupt controller as handled only if
+/// [`SharedIrqSummary::handled`] returns true.
+pub fn dispatch_shared_irq(devices: &mut [&mut dyn SharedIrqDevice]) -> SharedIrqSummary {
+    let mut summary = SharedIrqSummary::default();
+    for (index, device) in devices.iter_mut().enumerate() {
+        if !device.ack_interrupt() {
+            continue;
+        }
+        summary.asserted += 1;
+        match device.handle_interrupt() {
+            Ok(completions) => summary.completions += completions,
+            Err(e) => {
+                warn!("Error handling interrupt for device {}: {}", index, e);
+                summary.errors += 1;
+            }
+        }
+    }
+    summary
+}
+
+/// Limits on how much work [`FairScheduler::run`] does in one invocation.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct WorkBudget {
+    /// The maximum number of completions to process from each queue. This must not be zero.
+    pub per_queue: usize,
+    /// How long the invocation may keep processing completions for, if there is a limit.
+    ///
+    /// This is measured with [`Hal::timestamp`], and is ignored if the HAL has no clock. At least
+    /// one completion is always processed if there is one.
+    pub deadline: Option<Duration>,
+}
+
+/// What happened in an invocation of [`FairScheduler::run`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct FairnessReport {
+    /// The total number of completions processed, from all queues.
+    pub completions: usize,
+    /// Whether processing stopped because the budget ran out rather than because every queue was
+    /// empty, so there may be more completions waiting.
+    pub more_pending: bool,
+}
+
+/// Shares the work of processing completions between the queues of a device, so that a flood on
+/// one queue can't starve the others.
+///
+/// For example a network driver's interrupt handler can use this to alternate between receiving
+/// packets and reclaiming transmitted ones, so that reclaiming still happens while packets arrive
+/// faster than they can be processed. Each invocation processes at most a fixed number of
+/// completions from each queue, and starts from a different queue to the last one so that no queue
+/// is always served first.
+#[derive(Clone, Debug, Default)]
+pub struct FairScheduler {
+    /// The queue to start from in the next invocation.
+    next: usize,
+}
+
+impl FairScheduler {
+    /// Processes completions from queues `0..queues` in turn, within the given budget.
+    ///
+    /// `process` is called with the index of a queue to process a single completion from it, and
+    /// returns whether there was one. An error from it stops the invocation and is returned, and the
+    /// next invocation starts from the following queue.
+    ///
+    /// Returns [`Error::InvalidParam`] if the per-queue budget is zero.
+    pub fn run<H: Hal>(
+        &mut self,
+        queues: usize,
+        budget: &WorkBudget,
+        mut process: impl FnMut(usize) -> Result<bool>,
+    ) -> Result<FairnessReport> {
+        if budget.per_queue == 0 {
+            return Err(Error::InvalidParam);
+        }
+        let mut report = FairnessReport::default();
+        if queues == 0 {
+            return Ok(report);
+        }
+        let start = budget.deadline.and(H::timestamp());
+        let first = self.next % queues;
+        self.next = (first + 1) % queues;
+        for offset in 0..queues {
+            let queue = (first + offset) % queues;
+            let mut processed = 0;
+            while processed < budget.per_queue {
+                let found = process(queue).inspect_err(|_| {
+                    self.next = (queue + 1) % queues;
+                })?;
+                if !found {
+                    break;
+                }
+                processed += 1;
+                report.completions += 1;
+                if let (Some(deadline), Some(start)) = (budget.deadline, start) {
+                    if H::timestamp().is_some_and(|now| now.saturating_sub(start) >= deadline) {
+                        self.next = (queue + 1) % queues;
+                        report.more_pending = true;
+                        return Ok(report);
+                    }
+                }
+            }
+            // The queue may still have more if it used all of its budget.
+            if processed == budget.per_queue {
+                report.more_pending = true;
+            }
+        }
+        Ok(report)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::hal::fake::FakeHal;
+    use alloc::vec::Vec;
+
+    /// A device which returns a fixed result each time it is polled.
+    struct FakeDevice {
+        result: Result<usize>,
+        polls: usize,
+    }
+
+    impl VirtioDevice for FakeDevice {
+        fn poll(&mut self) -> Result<usize> {
+            self.polls += 1;
+            self.result
+        }
+    }
+
+    #[test]
+    fn poll_all_devices() {
+        let mut first = FakeDevice {
+            result: Ok(2),
+            polls: 0,
+        };
+        let mut failing = FakeDevice {
+            result: Err(Error::IoError),
+            polls: 0,
+        };
+        let mut last = FakeDevice {
+            result: Ok(1),
+            polls: 0,
+        };
+
+        assert_eq!(
+            poll_all(&mut [&mut first, &mut failing, &mut last]),
+            PollSummary {
+                completions: 3,
+                errors: 1
+            }
+        );
+        // The error didn't stop the last device from being polled.
+        assert_eq!(first.polls, 1);
+        assert_eq!(failing.polls, 1);
+        assert_eq!(last.polls, 1);
+        assert_eq!(poll_all(&mut []), PollSummary::default());
+    }
+
+    /// A device on a shared interrupt line, which may or may not be asserting it.
+    struct FakeSharedDevice {
+        pending: bool,
+        result: Result<usize>,
+        handled: usize,
+    }
+
+    impl FakeSharedDevice {
+        fn new(pending: bool, result: Result<usize>) -> Self {
+            Self {
+                pending,
+                result,
+                handled: 0,
+            }
+        }
+    }
+
+    impl SharedIrqDevice for FakeSharedDevice {
+        fn ack_interrupt(&mut self) -> bool {
+            let pending = self.pending;
+            self.pending = false;
+            pending
+        }
+
+        fn handle_interrupt(&mut self) -> Result<usize> {
+            self.handled += 1;
+            self.result
+        }
+    }
+
+    #[test]
+    fn shared_irq() {
+        let mut idle = FakeSharedDevice::new(false, Ok(5));
+        let mut first = FakeSharedDevice::new(true, Ok(2));
+        let mut failing = FakeSharedDevice::new(true, Err(Error::IoError));
+        let mut last = FakeSharedDevice::new(true, Ok(1));
+
+        let summary = dispatch_shared_irq(&mut [&mut idle, &mut first, &mut failing, &mut last]);
+        assert_eq!(
+            summary,
+            SharedIrqSummary {
+                asserted: 3,
+                completions: 3,
+                errors: 1,
+            }
+        );
+        assert!(summary.handled());
+        // Only the devices which asserted the interrupt were dispatched.
+        assert_eq!(idle.handled, 0);
+        assert_eq!(first.handled, 1);
+        assert_eq!(failing.handled, 1);
+        assert_eq!(last.handled, 1);
+
+        // Now none of them are asserting it, so it must belong to some other device.
+        let summary = dispatch_shared_irq(&mut [&mut idle, &mut first, &mut failing, &mut last]);
+        assert!(!summary.handled());
+        assert_eq!(summary, SharedIrqSummary::default());
+        assert_eq!(first.handled, 1);
+    }
+
+    #[test]
+    fn fair_scheduling() {
+        const RX: usize = 0;
+        const TX: usize = 1;
+        let mut pending = [10, 3];
+        let mut order = Vec::new();
+        let mut process = |queue: usize| {
+            order.push(queue);
+            if pending[queue] == 0 {
+                return Ok(false);
+            }
+            pending[queue] -= 1;
+            Ok(true)
+        };
+        let budget = WorkBudget {
+            per_queue: 4,
+            deadline: None,
+        };
+        let mut scheduler = FairScheduler::default();
+
+        // The receive flood doesn't stop transmitted buffers from being reclaimed.
+        assert_eq!(
+            scheduler.run::<FakeHal>(2, &budget, &mut process),
+            Ok(FairnessReport {
+                completions: 7,
+                more_pending: true
+            })
+        );
+        // The next invocation starts from the other queue.
+        assert_eq!(
+            scheduler.run::<FakeHal>(2, &budget, &mut process),
+            Ok(FairnessReport {
+                completions: 4,
+                more_pending: true
+            })
+        );
+        assert_eq!(
+            scheduler.run::<FakeHal>(2, &budget, &mut process),
+            Ok(FairnessReport {
+                completions: 2,
+                more_pending: false
+            })
+        );
+        assert_eq!(
+            order,
+            [RX, RX, RX, RX, TX, TX, TX, TX, TX, RX, RX, RX, RX, RX, RX, RX, TX]
+        );
+    }
+
+    #[test]
+    fn fair_scheduling_deadline() {
+        let mut order = Vec::new();
+        let budget = WorkBudget {
+            per_queue: 4,
+            deadline: Some(Duration::ZERO),
+        };
+        let mut scheduler = FairScheduler::default();
+
+        // Only one completion fits in the deadline, and then the next queue gets a turn.
+        for _ in 0..3 {
+            assert_eq!(
+                scheduler.run::<FakeHal>(3, &budget, |queue| {
+                    order.push(queue);
+                    Ok(true)
+                }),
+                Ok(FairnessReport {
+                    completions: 1,
+                    more_pending: true
+                })
+            );
+        }
+        assert_eq!(order, [0, 1, 2]);
+
+        assert_eq!(
+            scheduler.run::<FakeHal>(
+                3,
+                &WorkBudget {
+                    per_queue: 0,
+                    deadline: None
+                },
+                |_| Ok(true)
+            ),
+            Err(Error::InvalidParam)
+        );
+    }
+
+    #[test]
+    fn counts() {
+        let mut accounting = InterruptAccounting::default();
+        assert!(!accounting.record(false));
+        assert!(!accounting.record(true));
+        assert!(!accounting.record(true));
+        assert_eq!(
+            accounting.stats(),
+            InterruptStats {
+                total: 3,
+                spurious: 2,
+                lost: 0,
+            }
+        );
+        assert!(!accounting.polling());
+    }
+
+    #[test]
+    fn switch_to_polling() {
+        let mut accounting = InterruptAccounting::default();
+        accounting.set_threshold(Some(2));
+        assert!(!accounting.record(true));
+        // A genuine interrupt resets the count.
+        assert!(!accounting.record(false));
+        assert!(!accounting.record(true));
+        assert!(accounting.record(true));
+        assert!(accounting.polling());
+        // Only report crossing the threshold once.
+        assert!(!accounting.record(true));
+
+        accounting.reset_polling();
+        assert!(!accounting.polling());
+        assert!(!accounting.record(true));
+        assert!(accounting.record(true));
+    }
+
+    #[test]
+    fn watchdog() {
+        let mut accounting = InterruptAccounting::default();
+        // The watchdog is disabled by default.
+        for _ in 0..10 {
+            assert!(!accounting.watchdog_check(true, None));
+        }
+
+        accounting.set_watchdog(LostInterruptWatchdog {
+            checks: Some(3),
+            timeout: Some(Duration::from_millis(10)),
+        });
+        assert!(!accounting.watchdog_check(true, None));
+        assert!(!accounting.watchdog_check(true, None));
+        // An interrupt starts the count again, as does having nothing outstanding.
+        accounting.record(false);
+        assert!(!accounting.watchdog_check(true, None));
+        assert!(!accounting.watchdog_check(false, None));
+        assert!(!accounting.watchdog_check(true, None));
+        assert!(!accounting.watchdog_check(true, None));
+        assert!(accounting.watchdog_check(true, None));
+        assert!(!accounting.watchdog_check(true, None));
+
+        // The timeout can expire before the count.
+        accounting.set_watchdog(LostInterruptWatchdog {
+            checks: Some(3),
+            timeout: Some(Duration::from_millis(10)),
+        });
+        assert!(!accounting.watchdog_check(true, Some(Duration::from_millis(100))));
+        assert!(accounting.watchdog_check(true, Some(Duration::from_millis(110))));
+
+        // A check without the time doesn't start the timer.
+        accounting.set_watchdog(LostInterruptWatchdog {
+            checks: None,
+            timeout: Some(Duration::from_millis(10)),
+        });
+        assert!(!accounting.watchdog_check(true, None));
+        assert!(!accounting.watchdog_check(true, Some(Duration::from_millis(200))));
+        assert!(!accounting.watchdog_check(true, Some(Duration::from_millis(205))));
+        assert!(accounting.watchdog_check(true, Some(Duration::from_millis(210))));
+
+        // Interrupts aren't expected while they are disabled.
+        accounting.set_disabled(true);
+        for _ in 0..10 {
+            assert!(!accounting.watchdog_check(true, None));
+        }
+    }
+
+    #[test]
+    fn ignored_suppression() {
+        let mut accounting = InterruptAccounting::default();
+        accounting.set_suppression_warning(Some(2));
+        assert!(!accounting.record_suppression(false));
+        assert!(!accounting.record_suppression(false));
+
+        accounting.set_disabled(true);
+        assert!(!accounting.record_suppression(false));
+        assert!(accounting.record_suppression(false));
+        // Only warn once while the device keeps ignoring suppression.
+        assert!(!accounting.record_suppression(false));
+
+        // Spurious interrupts don't count, as they don't indicate that the device raised them.
+        assert!(!accounting.record_suppression(true));
+        assert!(!accounting.record_suppression(false));
+        assert!(accounting.record_suppression(false));
+    }
+}
@@ -5,18 +5,22 @@
 //!
 //! # Usage
 //!
-//! You must first implement the [`Hal`] trait, to allocate DMA regions and translate between
-//! physical addresses (as seen by devices) and virtual addresses (as seen by your program). You can
-//! then construct the appropriate transport for the VirtIO device, e.g. for an MMIO device (perhaps
+//! You must first implement the [`Hal`] trait, to allocate DMA regions and share buffers with
+//! devices. The virtqueues pass every buffer which a driver gives the device through
+//! [`Hal::share`] before the device sees it, and through [`Hal::unshare`] once the device has used
+//! it, so the HAL needn't rely on the device seeing the same physical addresses as the driver: it
+//! can map each buffer into an IOMMU, make it accessible to the host of a protected VM (such as
+//! under pKVM or SEV), or copy it through a bounce buffer in a restricted DMA window (see
+//! [Device addresses](Hal#device-addresses)). You can then construct the appropriate transport for the VirtIO device, e.g. for an MMIO device (perhaps
 //! discovered from the device tree):
 //!
 //! ```
 //! use core::ptr::NonNull;
 //! use virtio_drivers::transport::mmio::{MmioTransport, VirtIOHeader};
 //!
-//! # fn example(mmio_device_address: usize) {
+//! # fn example(mmio_device_address: usize, mmio_size: usize) {
 //! let header = NonNull::new(mmio_device_address as *mut VirtIOHeader).unwrap();
-//! let transport = unsafe { MmioTransport::new(header) }.unwrap();
+//! let transport = unsafe { MmioTransport::new(header, mmio_size) }.unwrap();
 //! # }
 //! ```
 //!
@@ -24,14 +28,14 @@
 //!
 //! ```
 //! # use virtio_drivers::Hal;
-//! # #[cfg(feature = "alloc")]
+//! # #[cfg(feature = "console")]
 //! use virtio_drivers::{
 //!     device::console::VirtIOConsole,
 //!     transport::{mmio::MmioTransport, DeviceType, Transport},
 //! };
 
 //!
-//! # #[cfg(feature = "alloc")]
+//! # #[cfg(feature = "console")]
 //! # fn example<HalImpl: Hal>(transport: MmioTransport) {
 //! if transport.device_type() == DeviceType::Console {
 //!     let mut console = VirtIOConsole::<HalImpl, _>::new(transport).unwrap();
@@ -40,6 +44,8 @@
 //! }
 //! # }
 //! ```
+//!
+//! The most commonly used traits and driver types are also available from [`prelude`].
 
 #![cfg_attr(not(test), no_std)]
 #![deny(unused_must_use, missing_docs)]
@@ -49,10 +55,25 @@
 #[cfg(any(feature = "alloc", test))]
 extern crate alloc;
 
+pub mod checksum;
+pub mod completion;
+pub mod config;
 pub mod device;
+pub mod diagnostics;
+mod display;
+pub mod failover;
+pub mod fixed;
 mod hal;
+pub mod interrupt;
+mod poison;
+pub mod prelude;
 mod queue;
+pub mod registry;
+pub mod retry;
+#[cfg(test)]
+mod sim;
 pub mod transport;
+pub mod tunable;
 mod volatile;
 
 use core::{
@@ -60,7 +81,16 @@ use core::{
     ptr::{self, NonNull},
 };
 
-pub use self::hal::{BufferDirection, Hal, PhysAddr};
+#[cfg(feature = "hal-impls")]
+pub use self::hal::bounce::{BounceHal, BouncePool, BouncePoolSource, SizeClass};
+#[cfg(feature = "hal-impls")]
+pub use self::hal::offset::{IdentityHal, OffsetHal};
+pub use self::hal::{AllocFailurePolicy, BufferDirection, Hal, MemoryLocality, NumaNode, PhysAddr};
+pub use self::poison::POISON;
+pub use self::queue::{
+    InFlightLimit, QueueLayoutFlags, QueuePlacement, Reservation, ThrottleMode, VirtQueueLayout,
+    VirtQueueState,
+};
 
 /// The page size in bytes supported by the library (4 KiB).
 pub const PAGE_SIZE: usize = 0x1000;
@@ -83,16 +113,38 @@ pub enum Error {
     InvalidParam,
     /// Failed to alloc DMA memory.
     DmaError,
+    /// The HAL couldn't allocate DMA memory.
+    OutOfDmaMemory {
+        /// The size of the allocation which failed, in bytes.
+        requested: usize,
+        /// The alignment required for the allocation, in bytes.
+        align: usize,
+    },
+    /// There wasn't enough heap memory for a driver's buffers.
+    OutOfMemory {
+        /// The size of the allocation which failed, in bytes.
+        requested: usize,
+    },
     /// I/O Error
     IoError,
     /// The request was not supported by the device.
     Unsupported,
+    /// The request was not aligned to the device's block size.
+    Misaligned,
     /// The config space advertised by the device is smaller than the driver expected.
     ConfigSpaceTooSmall,
     /// The device doesn't have any config space, but the driver expects some.
     ConfigSpaceMissing,
     /// Error from the socket device.
     SocketDeviceError(device::socket::SocketError),
+    /// A SCSI command failed.
+    #[cfg(feature = "scsi")]
+    ScsiError(device::scsi::ScsiError),
+    /// The driver already has as many requests in flight as its [`InFlightLimit`] allows, try
+    /// again after completing some.
+    Throttled,
+    /// A [`Registry`](registry::Registry) has no free entries.
+    RegistryFull,
 }
 
 impl Display for Error {
@@ -107,8 +159,16 @@ impl Display for Error {
             Self::AlreadyUsed => write!(f, "Virtqueue is already in use"),
             Self::InvalidParam => write!(f, "Invalid parameter"),
             Self::DmaError => write!(f, "Failed to allocate DMA memory"),
+            Self::OutOfDmaMemory { requested, align } => write!(
+                f,
+                "Out of DMA memory allocating {requested} bytes with alignment {align}"
+            ),
+            Self::OutOfMemory { requested } => {
+                write!(f, "Out of memory allocating {requested} bytes")
+            }
             Self::IoError => write!(f, "I/O Error"),
             Self::Unsupported => write!(f, "Request not supported by device"),
+            Self::Misaligned => write!(f, "Request not aligned to the device's block size"),
             Self::ConfigSpaceTooSmall => write!(
                 f,
                 "Config space advertised by the device is smaller than expected"
@@ -120,6 +180,10 @@ impl Display for Error {
                 )
             }
             Self::SocketDeviceError(e) => write!(f, "Error from the socket device: {e:?}"),
+            #[cfg(feature = "scsi")]
+            Self::ScsiError(e) => write!(f, "SCSI command failed: {e}"),
+            Self::Throttled => write!(f, "Too many requests in flight"),
+            Self::RegistryFull => write!(f, "Registry is full"),
         }
     }
 }
@@ -130,6 +194,13 @@ impl From<device::socket::SocketError> for Error {
     }
 }
 
+#[cfg(feature = "scsi")]
+impl From<device::scsi::ScsiError> for Error {
+    fn from(e: device::scsi::ScsiError) -> Self {
+        Self::ScsiError(e)
+    }
+}
+
 /// Align `size` up to a page.
 fn align_up(size: usize) -> usize {
     (size + PAGE_SIZE) & !(PAGE_SIZE - 1)
@@ -137,7 +208,7 @@ fn align_up(size: usize) -> usize {
 
 /// The number of pages required to store `size` bytes, rounded up to a whole number of pages.
 fn pages(size: usize) -> usize {
-    (size + PAGE_SIZE - 1) / PAGE_SIZE
+    size.div_ceil(PAGE_SIZE)
 }
 
 // TODO: Use NonNull::slice_from_raw_parts once it is stable.
@@ -0,0 +1,101 @@
+//! Debug checks for drivers reading data which the device never wrote.
+//!
+//! With the `poison` feature, every device-writable buffer is filled with [`POISON`] before it is
+//! made available to the device, and again beyond the length the device reports having written
+//! once it is used. Drivers check the length which the device reports having
+//! written against the amount they are about to read. A device backend which writes short
+//! responses, or a driver which reads past what was written, then shows up as a warning and a
+//! recognisable pattern in the data rather than as stale buffer contents which happen to look
+//! plausible. Without the feature these are no-ops.
+
+use log::warn;
+
+/// The byte which device-writable buffers are filled with before being given to the device.
+pub const POISON: u8 = 0xa5;
+
+/// Fills the given device-writable buffers with [`POISON`], if the `poison` feature is enabled.
+pub(crate) fn fill(outputs: &mut [&mut [u8]]) {
+    if cfg!(feature = "poison") {
+        for output in outputs {
+            output.fill(POISON);
+        }
+    }
+}
+
+/// Fills the part of the given device-writable buffers beyond the first `written` bytes with
+/// [`POISON`] after the device has used them, if the `poison` feature is enabled.
+///
+/// This catches short writes however the HAL shares buffers, even if it bounces them through
+/// separate memory and copies the whole of each buffer back.
+pub(crate) fn fill_unwritten(outputs: &mut [&mut [u8]], written: u32) {
+    if cfg!(feature = "poison") {
+        let mut written = written as usize;
+        for output in outputs {
+            let len = output.len().min(written);
+            output[len..].fill(POISON);
+            written -= len;
+        }
+    }
+}
+
+/// Checks that the device wrote at least `read` bytes to a descriptor chain on the given queue,
+/// where `written` is the length it reported in the used ring, if the `poison` feature is enabled.
+///
+/// Logs a warning and returns false if the driver is about to read bytes that the device didn't
+/// write.
+pub(crate) fn check_written(queue_idx: u16, written: u32, read: usize) -> bool {
+    if cfg!(feature = "poison") && (written as usize) < read {
+        warn!(
+            "Device only wrote {} bytes on queue {}, but the driver reads {}",
+            written, queue_idx, read
+        );
+        false
+    } else {
+        true
+    }
+}
+
+#[cfg(all(test, feature = "poison"))]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        queue::VirtQueue,
+        transport::{
+            fake::{FakeTransport, QueueStatus, State},
+            DeviceType,
+        },
+    };
+    use core::ptr::NonNull;
+    use std::sync::{Arc, Mutex};
+
+    #[test]
+    fn poisons_outputs_and_checks_length() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+        let mut response = [0; 4];
+        let token = unsafe { queue.add(&[], &mut [&mut response]) }.unwrap();
+        // The device only writes the first two bytes.
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue::<4>(0, |_| vec![1, 2]);
+        let written = unsafe { queue.pop_used(token, &[], &mut [&mut response]) }.unwrap();
+
+        assert_eq!(response, [1, 2, POISON, POISON]);
+        assert!(check_written(0, written, 2));
+        assert!(!check_written(0, written, response.len()));
+    }
+}
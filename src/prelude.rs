@@ -0,0 +1,62 @@
+//! Convenience re-exports of the most commonly used items.
+//!
+//! The paths in this module are kept stable across internal reorganisations, so downstream code can
+//! depend on them with a single glob import:
+//!
+//! ```
+//! use virtio_drivers::prelude::*;
+//!
+//! # #[cfg(feature = "blk")]
+//! # fn example<H: Hal, T: Transport>(transport: T) -> Result<(), Error> {
+//! if transport.device_type() == DeviceType::Block {
+//!     let mut disk = VirtIOBlk::<H, _>::new(transport)?;
+//!     let mut buf = [0; SECTOR_SIZE];
+//!     disk.read_blocks(0, &mut buf)?;
+//! }
+//! # Ok(())
+//! # }
+//! ```
+//!
+//! [`crate::Result`] is deliberately not included, so that a glob import doesn't shadow the standard
+//! library's `Result`.
+
+#[cfg(feature = "balloon")]
+pub use crate::device::balloon::VirtIOBalloon;
+#[cfg(feature = "blk")]
+pub use crate::device::blk::{VirtIOBlk, SECTOR_SIZE};
+#[cfg(feature = "console")]
+pub use crate::device::console::VirtIOConsole;
+#[cfg(feature = "crypto")]
+pub use crate::device::crypto::VirtIOCrypto;
+#[cfg(feature = "fs")]
+pub use crate::device::fs::VirtIOFs;
+#[cfg(feature = "gpu")]
+pub use crate::device::gpu::VirtIOGpu;
+#[cfg(feature = "input")]
+pub use crate::device::input::VirtIOInput;
+#[cfg(all(feature = "net", feature = "alloc"))]
+pub use crate::device::net::VirtIONet;
+#[cfg(feature = "net")]
+pub use crate::device::net::VirtIONetRaw;
+#[cfg(feature = "p9")]
+pub use crate::device::p9::VirtIO9p;
+#[cfg(feature = "scsi")]
+pub use crate::device::scsi::VirtIOScsi;
+pub use crate::device::socket::SocketError;
+#[cfg(all(feature = "socket", feature = "alloc"))]
+pub use crate::device::socket::{VirtIOSocket, VsockConnectionManager};
+#[cfg(feature = "sound")]
+pub use crate::device::sound::VirtIOSound;
+pub use crate::interrupt::{
+    dispatch_shared_irq, poll_all, InterruptStats, PollSummary, SharedIrqDevice, SharedIrqSummary,
+    VirtioDevice,
+};
+pub use crate::transport::{
+    mmio::{MmioError, MmioTransport, VirtIOHeader},
+    pci::{PciTransport, VirtioPciError},
+    DeviceStatus, DeviceType, Transport,
+};
+pub use crate::tunable::{TunableValue, Tunables};
+pub use crate::{BufferDirection, Error, Hal, PhysAddr, PAGE_SIZE};
+#[cfg(feature = "hal-impls")]
+pub use crate::{IdentityHal, OffsetHal};
@@ -1,31 +1,59 @@
 #![deny(unsafe_op_in_unsafe_fn)]
-
-use crate::hal::{BufferDirection, Dma, Hal, PhysAddr};
-use crate::transport::Transport;
-use crate::{align_up, nonnull_slice_from_raw_parts, pages, Error, Result, PAGE_SIZE};
+// The queue is used on every I/O path, so it must report errors rather than panicking.
+#![cfg_attr(
+    not(test),
+    deny(clippy::panic, clippy::unwrap_used, clippy::expect_used)
+)]
+
+mod any;
+mod dump;
+mod layout;
+mod packed;
+
+#[cfg(any(feature = "console", feature = "gpu", feature = "input"))]
+pub use self::any::AnyQueue;
+pub use self::dump::QueueDump;
+use self::layout::{split_part_sizes, AnyLayout, RingFormat};
+use crate::diagnostics::{QueueMonitor, SlowPathThresholds};
+use crate::display::impl_flags_display;
+use crate::hal::{
+    self, AllocFailurePolicy, BufferDirection, Hal, MemoryLocality, NumaNode, PhysAddr,
+};
+use crate::poison;
+use crate::transport::{QueueNotifyAddress, Transport};
+use crate::{nonnull_slice_from_raw_parts, Error, Result};
 #[cfg(feature = "alloc")]
 use alloc::boxed::Box;
 use bitflags::bitflags;
 #[cfg(test)]
 use core::cmp::min;
+use core::convert::TryFrom;
 use core::hint::spin_loop;
-use core::mem::{size_of, take};
+use core::mem::{replace, take};
 #[cfg(test)]
 use core::ptr;
-use core::ptr::NonNull;
+use core::ptr::{addr_of, NonNull};
 use core::sync::atomic::{fence, AtomicU16, Ordering};
+use log::warn;
 use zerocopy::{AsBytes, FromBytes, FromZeroes};
 
+/// Converts a saved virtual address back to a pointer.
+fn vaddr(saved: u64) -> Result<NonNull<u8>> {
+    let address = usize::try_from(saved).map_err(|_| Error::InvalidParam)?;
+    NonNull::new(address as *mut u8).ok_or(Error::InvalidParam)
+}
+
 /// The mechanism for bulk data transport on virtio devices.
 ///
 /// Each device can have zero or more virtqueues.
 ///
 /// * `SIZE`: The size of the queue. This is both the number of descriptors, and the number of slots
-///   in the available and used rings.
+///   in the available and used rings. A queue created with [`AllocFailurePolicy::Degrade`] may
+///   end up smaller, as reported by [`size`](VirtQueue::size).
 #[derive(Debug)]
 pub struct VirtQueue<H: Hal, const SIZE: usize> {
     /// DMA guard
-    layout: VirtQueueLayout<H>,
+    layout: AnyLayout<H>,
     /// Descriptor table
     ///
     /// The device may be able to modify this, even though it's not supposed to, so we shouldn't
@@ -43,21 +71,37 @@ pub struct VirtQueue<H: Hal, const SIZE: usize> {
 
     /// The index of queue
     queue_idx: u16,
+    /// The number of entries in the queue, which is at most `SIZE`.
+    size: u16,
     /// The number of descriptors currently in use.
     num_used: u16,
+    /// The number of free descriptors which are set aside for outstanding [`Reservation`]s.
+    num_reserved: u16,
     /// The head desc index of the free list.
     free_head: u16,
     /// Our trusted copy of `desc` that the device can't access.
     desc_shadow: [Descriptor; SIZE],
     /// Our trusted copy of `avail.idx`.
     avail_idx: u16,
+    /// The value of `avail_idx` when `should_notify` was last called, so that a notification
+    /// covers every buffer added since then.
+    notified_avail_idx: u16,
     last_used_idx: u16,
     /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
     event_idx: bool,
+    /// Whether the driver wants used buffer notifications, as last set by
+    /// [`set_dev_notify`](Self::set_dev_notify).
+    dev_notify: bool,
     #[cfg(feature = "alloc")]
     indirect: bool,
     #[cfg(feature = "alloc")]
     indirect_lists: [Option<NonNull<[Descriptor]>>; SIZE],
+    /// Slow path diagnostics, which are disabled by default.
+    monitor: QueueMonitor,
+    /// The limit on descriptor chains which the device may have in flight at once, if any.
+    in_flight_limit: Option<InFlightLimit>,
+    /// The queue's doorbell, if the transport has one, looked up once when the queue is set up.
+    doorbell: Option<QueueNotifyAddress>,
 }
 
 impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
@@ -74,22 +118,85 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         indirect: bool,
         event_idx: bool,
     ) -> Result<Self> {
+        Self::new_with_locality(transport, idx, indirect, event_idx, MemoryLocality::Any)
+    }
+
+    /// Creates a new VirtQueue, asking the HAL to place its memory according to the given locality
+    /// hint.
+    ///
+    /// This is useful on multi-socket systems to keep the rings close to the CPU which will drive
+    /// the queue. See [`VirtQueue::new`] for the other parameters.
+    pub fn new_with_locality<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+        locality: MemoryLocality,
+    ) -> Result<Self> {
+        Self::new_with_policy(
+            transport,
+            idx,
+            indirect,
+            event_idx,
+            locality,
+            AllocFailurePolicy::Fail,
+        )
+    }
+
+    /// Creates a new VirtQueue, handling failure to allocate its memory according to the given
+    /// policy.
+    ///
+    /// With [`AllocFailurePolicy::Degrade`], if there isn't enough DMA memory for a queue of `SIZE`
+    /// entries then the queue is set up with half as many, and so on down to 2 entries. The size
+    /// it ended up with can be checked with [`size`](Self::size). See
+    /// [`VirtQueue::new_with_locality`] for the other parameters.
+    pub fn new_with_policy<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+        locality: MemoryLocality,
+        policy: AllocFailurePolicy,
+    ) -> Result<Self> {
+        if !SIZE.is_power_of_two() || SIZE > u16::MAX.into() {
+            return Err(Error::InvalidParam);
+        }
+        let mut size = SIZE as u16;
+        loop {
+            match Self::new_sized(transport, idx, indirect, event_idx, locality, size) {
+                Err(Error::OutOfDmaMemory { .. })
+                    if policy == AllocFailurePolicy::Degrade && size > 2 =>
+                {
+                    size /= 2;
+                    warn!("Out of memory for queue {}, trying {} entries", idx, size);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Creates a new VirtQueue with `size` entries, which must be a power of two no bigger than
+    /// `SIZE`.
+    fn new_sized<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        indirect: bool,
+        event_idx: bool,
+        locality: MemoryLocality,
+        size: u16,
+    ) -> Result<Self> {
+        #[cfg(not(feature = "alloc"))]
+        let _ = indirect;
         if transport.queue_used(idx) {
             return Err(Error::AlreadyUsed);
         }
-        if !SIZE.is_power_of_two()
-            || SIZE > u16::MAX.into()
-            || transport.max_queue_size(idx) < SIZE as u32
-        {
+        if transport.max_queue_size(idx) < size.into() {
             return Err(Error::InvalidParam);
         }
-        let size = SIZE as u16;
 
-        let layout = if transport.requires_legacy_layout() {
-            VirtQueueLayout::allocate_legacy(size)?
-        } else {
-            VirtQueueLayout::allocate_flexible(size)?
-        };
+        // This queue implementation only supports split rings.
+        let format = RingFormat::choose(transport, false);
+        let layout = AnyLayout::allocate_format(format, size, locality)?;
 
         transport.queue_set(
             idx,
@@ -97,12 +204,15 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             layout.descriptors_paddr(),
             layout.driver_area_paddr(),
             layout.device_area_paddr(),
-        );
+        )?;
+        let doorbell = transport.queue_notify_address(idx);
 
-        let desc =
-            nonnull_slice_from_raw_parts(layout.descriptors_vaddr().cast::<Descriptor>(), SIZE);
-        let avail = layout.avail_vaddr().cast();
-        let used = layout.used_vaddr().cast();
+        let desc = nonnull_slice_from_raw_parts(
+            layout.descriptors_vaddr().cast::<Descriptor>(),
+            size.into(),
+        );
+        let avail = layout.driver_area_vaddr().cast();
+        let used = layout.device_area_vaddr().cast();
 
         let mut desc_shadow: [Descriptor; SIZE] = FromZeroes::new_zeroed();
         // Link descriptors together.
@@ -123,16 +233,215 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             avail,
             used,
             queue_idx: idx,
+            size,
             num_used: 0,
+            num_reserved: 0,
             free_head: 0,
             desc_shadow,
             avail_idx: 0,
+            notified_avail_idx: 0,
             last_used_idx: 0,
             event_idx,
+            dev_notify: true,
             #[cfg(feature = "alloc")]
             indirect,
             #[cfg(feature = "alloc")]
             indirect_lists: [NONE; SIZE],
+            monitor: QueueMonitor::default(),
+            in_flight_limit: None,
+            doorbell,
+        })
+    }
+
+    /// Returns the number of entries in the queue.
+    ///
+    /// This is `SIZE` unless the queue was created with [`AllocFailurePolicy::Degrade`] and ran
+    /// out of memory.
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    /// Returns the NUMA nodes on which the parts of the queue were allocated, as reported by the
+    /// HAL.
+    pub fn placement(&self) -> QueuePlacement {
+        self.layout.placement()
+    }
+
+    /// Returns the physical layout of the queue and the current positions in its rings, e.g. for
+    /// debugging tools or to describe the queue to a hypervisor.
+    pub fn export_layout(&self) -> VirtQueueLayout {
+        let (descriptors_len, driver_area_len, device_area_len) = split_part_sizes(self.size);
+        let mut flags = QueueLayoutFlags::empty();
+        flags.set(
+            QueueLayoutFlags::LEGACY,
+            self.layout.format() == RingFormat::SplitLegacy,
+        );
+        flags.set(QueueLayoutFlags::EVENT_IDX, self.event_idx);
+        #[cfg(feature = "alloc")]
+        flags.set(QueueLayoutFlags::INDIRECT, self.indirect);
+        VirtQueueLayout {
+            descriptors_paddr: self.layout.descriptors_paddr() as u64,
+            driver_area_paddr: self.layout.driver_area_paddr() as u64,
+            device_area_paddr: self.layout.device_area_paddr() as u64,
+            descriptors_len: descriptors_len as u64,
+            driver_area_len: driver_area_len as u64,
+            device_area_len: device_area_len as u64,
+            queue_index: self.queue_idx,
+            size: self.size,
+            avail_idx: self.avail_idx,
+            last_used_idx: self.last_used_idx,
+            // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
+            // readable instance of UsedRing.
+            device_used_idx: unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) },
+            descriptors_in_use: self.num_used,
+            flags,
+        }
+    }
+
+    /// Returns a dump of the descriptor chains currently posted to the device, with their
+    /// descriptors, and of the ring indices and notification suppression flags, e.g. to log when
+    /// the device seems to have stopped completing requests.
+    pub fn debug_dump(&self) -> QueueDump<'_, H, SIZE> {
+        QueueDump::new(self)
+    }
+
+    /// Saves the state of the queue, so that it can be restored by [`VirtQueue::restore`] without
+    /// resetting the device.
+    ///
+    /// Requests may still be in flight, and can be completed as usual once the queue is restored.
+    /// Returns [`Error::NotReady`] if any descriptors are reserved or any request using indirect
+    /// descriptors is in flight, as they can't be described by the saved state.
+    ///
+    /// On success the queue must be leaked rather than dropped, as its memory now belongs to the
+    /// saved state. Slow path thresholds and the in-flight limit aren't saved, so must be set again
+    /// on the restored queue if needed.
+    pub fn save(&mut self) -> Result<VirtQueueState> {
+        if self.num_reserved != 0 {
+            return Err(Error::NotReady);
+        }
+        #[cfg(feature = "alloc")]
+        if self.indirect_lists.iter().any(Option::is_some) {
+            return Err(Error::NotReady);
+        }
+        // `restore` rebuilds the shadow descriptors from the descriptor table, so make sure it is
+        // up to date. Some fields of free descriptors are only updated in the shadow.
+        for index in 0..self.size {
+            self.write_desc(index);
+        }
+        Ok(VirtQueueState {
+            layout: self.export_layout(),
+            descriptors_vaddr: self.layout.descriptors_vaddr().as_ptr() as u64,
+            driver_area_vaddr: self.layout.driver_area_vaddr().as_ptr() as u64,
+            device_area_vaddr: self.layout.device_area_vaddr().as_ptr() as u64,
+            free_head: self.free_head,
+            reserved: [0; 3],
+        })
+    }
+
+    /// Restores a queue from the state saved by [`VirtQueue::save`], without setting it up on the
+    /// device again.
+    ///
+    /// Returns [`Error::NotReady`] if the queue isn't set up on the device, or
+    /// [`Error::InvalidParam`] if the state doesn't match this queue type, the transport or the
+    /// contents of the rings.
+    ///
+    /// # Safety
+    ///
+    /// The state must have been saved from a queue of the same size using the same HAL, which is
+    /// still set up on the same device, and whose memory was leaked rather than freed. Each saved
+    /// state must be restored at most once.
+    pub unsafe fn restore<T: Transport>(transport: &mut T, state: &VirtQueueState) -> Result<Self> {
+        let layout = &state.layout;
+        let idx = layout.queue_index;
+        let size = layout.size;
+        if !size.is_power_of_two() || usize::from(size) > SIZE || layout.descriptors_in_use > size {
+            return Err(Error::InvalidParam);
+        }
+        let format = if layout.flags.contains(QueueLayoutFlags::LEGACY) {
+            RingFormat::SplitLegacy
+        } else {
+            RingFormat::SplitModern
+        };
+        if format != RingFormat::choose(transport, false) {
+            return Err(Error::InvalidParam);
+        }
+        if !transport.queue_used(idx) {
+            return Err(Error::NotReady);
+        }
+        let doorbell = transport.queue_notify_address(idx);
+        let descriptors_vaddr = vaddr(state.descriptors_vaddr)?;
+        let driver_area_vaddr = vaddr(state.driver_area_vaddr)?;
+        let device_area_vaddr = vaddr(state.device_area_vaddr)?;
+
+        let desc =
+            nonnull_slice_from_raw_parts(descriptors_vaddr.cast::<Descriptor>(), size.into());
+        let avail: NonNull<AvailRing<SIZE>> = driver_area_vaddr.cast();
+        let used: NonNull<UsedRing<SIZE>> = device_area_vaddr.cast();
+
+        // SAFETY: Our caller promises that the state describes the memory of a live queue of this
+        // size, so `desc` and `avail` are properly aligned, dereferenceable and initialised.
+        let mut desc_shadow: [Descriptor; SIZE] = FromZeroes::new_zeroed();
+        let avail_idx = unsafe {
+            desc_shadow[..usize::from(size)].clone_from_slice(&*desc.as_ptr());
+            (*avail.as_ptr()).idx.load(Ordering::Acquire)
+        };
+        if avail_idx != layout.avail_idx {
+            return Err(Error::InvalidParam);
+        }
+        // Check that the free list has the expected length and doesn't loop, so later allocations
+        // from it can't go out of bounds.
+        let mut free = [false; SIZE];
+        let mut index = state.free_head;
+        for _ in 0..size - layout.descriptors_in_use {
+            let seen = free[..usize::from(size)]
+                .get_mut(usize::from(index))
+                .ok_or(Error::InvalidParam)?;
+            if *seen {
+                return Err(Error::InvalidParam);
+            }
+            *seen = true;
+            index = desc_shadow[usize::from(index)].next;
+        }
+
+        // SAFETY: Our caller promises that the queue's memory was allocated with these addresses
+        // and leaked, and that the state is only restored once.
+        let ring_layout = unsafe {
+            AnyLayout::from_saved(
+                format,
+                layout.size,
+                (layout.descriptors_paddr as PhysAddr, descriptors_vaddr),
+                (layout.driver_area_paddr as PhysAddr, driver_area_vaddr),
+                (layout.device_area_paddr as PhysAddr, device_area_vaddr),
+            )
+        };
+
+        #[cfg(feature = "alloc")]
+        const NONE: Option<NonNull<[Descriptor]>> = None;
+        Ok(VirtQueue {
+            layout: ring_layout,
+            desc,
+            avail,
+            used,
+            queue_idx: idx,
+            size,
+            num_used: layout.descriptors_in_use,
+            num_reserved: 0,
+            free_head: state.free_head,
+            desc_shadow,
+            avail_idx,
+            // The device may not have been notified about chains which are still in flight, so
+            // err on the side of notifying it again.
+            notified_avail_idx: avail_idx.wrapping_sub(layout.descriptors_in_use),
+            last_used_idx: layout.last_used_idx,
+            event_idx: layout.flags.contains(QueueLayoutFlags::EVENT_IDX),
+            dev_notify: true,
+            #[cfg(feature = "alloc")]
+            indirect: layout.flags.contains(QueueLayoutFlags::INDIRECT),
+            #[cfg(feature = "alloc")]
+            indirect_lists: [NONE; SIZE],
+            monitor: QueueMonitor::default(),
+            in_flight_limit: None,
+            doorbell,
         })
     }
 
@@ -155,19 +464,22 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             return Err(Error::InvalidParam);
         }
         let descriptors_needed = inputs.len() + outputs.len();
+        let size = usize::from(self.size);
+        let unavailable = usize::from(self.num_used + self.num_reserved);
         // Only consider indirect descriptors if the alloc feature is enabled, as they require
         // allocation.
         #[cfg(feature = "alloc")]
-        if self.num_used as usize + 1 > SIZE
-            || descriptors_needed > SIZE
-            || (!self.indirect && self.num_used as usize + descriptors_needed > SIZE)
-        {
-            return Err(Error::QueueFull);
-        }
+        let full = unavailable + 1 > size
+            || descriptors_needed > size
+            || (!self.indirect && unavailable + descriptors_needed > size);
         #[cfg(not(feature = "alloc"))]
-        if self.num_used as usize + descriptors_needed > SIZE {
+        let full = unavailable + descriptors_needed > size;
+        self.monitor.record_add(self.queue_idx, full);
+        if full {
             return Err(Error::QueueFull);
         }
+        check_buffers(inputs, outputs)?;
+        poison::fill(outputs);
 
         #[cfg(feature = "alloc")]
         let head = if self.indirect && descriptors_needed > 1 {
@@ -178,7 +490,96 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         #[cfg(not(feature = "alloc"))]
         let head = self.add_direct(inputs, outputs);
 
-        let avail_slot = self.avail_idx & (SIZE as u16 - 1);
+        self.push_avail(head);
+        Ok(head)
+    }
+
+    /// Sets the thresholds beyond which to warn about slow paths in this queue.
+    ///
+    /// See [`SlowPathThresholds`] for details. The `ignored_suppression` threshold is handled by
+    /// the drivers rather than the queue, so is ignored here.
+    pub fn set_slow_path_thresholds(&mut self, thresholds: SlowPathThresholds) {
+        self.monitor.set_thresholds(thresholds);
+    }
+
+    /// Sets aside the given number of descriptors, so that buffers using up to that many
+    /// descriptors in total can later be added with [`add_reserved`](Self::add_reserved) without
+    /// any risk of failing or allocating.
+    ///
+    /// This lets a caller do anything which may fail or block up front, and then submit requests
+    /// in a context where it can't, such as with a spinlock held. Returns [`Error::QueueFull`] if
+    /// there aren't enough free descriptors which aren't already reserved.
+    ///
+    /// Descriptors which aren't used must be given back with [`release`](Self::release), or they
+    /// will stay unavailable.
+    pub fn reserve(&mut self, descriptors: usize) -> Result<Reservation> {
+        if descriptors > usize::from(self.size - self.num_used - self.num_reserved) {
+            return Err(Error::QueueFull);
+        }
+        let descriptors = descriptors as u16;
+        self.num_reserved += descriptors;
+        Ok(Reservation {
+            queue: self.layout.descriptors_paddr(),
+            remaining: descriptors,
+        })
+    }
+
+    /// Adds buffers to the virtqueue using descriptors from the given reservation, and returns a
+    /// token.
+    ///
+    /// This never uses indirect descriptors, so doesn't allocate. It only fails if the reservation
+    /// is for a different queue, or doesn't have enough descriptors left for the buffers, in
+    /// which case it returns [`Error::InvalidParam`].
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add_reserved<'a, 'b>(
+        &mut self,
+        reservation: &mut Reservation,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        check_buffers(inputs, outputs)?;
+        let descriptors_needed = inputs.len() + outputs.len();
+        if !self.owns(reservation) || descriptors_needed > usize::from(reservation.remaining) {
+            return Err(Error::InvalidParam);
+        }
+        let descriptors_needed = descriptors_needed as u16;
+        reservation.remaining -= descriptors_needed;
+        self.num_reserved -= descriptors_needed;
+
+        let head = self.add_direct(inputs, outputs);
+        self.push_avail(head);
+        Ok(head)
+    }
+
+    /// Gives back any descriptors left in the given reservation, so they can be used by other
+    /// requests.
+    ///
+    /// Returns [`Error::InvalidParam`] if the reservation is for a different queue.
+    pub fn release(&mut self, reservation: Reservation) -> Result {
+        if !self.owns(&reservation) {
+            return Err(Error::InvalidParam);
+        }
+        self.num_reserved -= reservation.remaining;
+        Ok(())
+    }
+
+    /// Returns whether the given reservation was made in this queue and is still outstanding.
+    ///
+    /// Reservations are identified by the queue's descriptor table rather than its index, so that
+    /// one from another device's queue with the same index isn't accepted.
+    fn owns(&self, reservation: &Reservation) -> bool {
+        reservation.queue == self.layout.descriptors_paddr()
+            && reservation.remaining <= self.num_reserved
+    }
+
+    /// Makes the descriptor chain starting at `head` available to the device.
+    fn push_avail(&mut self, head: u16) {
+        self.monitor.record_available(head, H::timestamp);
+        let avail_slot = self.avail_idx & (self.size - 1);
         // Safe because self.avail is properly aligned, dereferenceable and initialised.
         unsafe {
             (*self.avail.as_ptr()).ring[avail_slot as usize] = head;
@@ -196,8 +597,6 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 .idx
                 .store(self.avail_idx, Ordering::Release);
         }
-
-        Ok(head)
     }
 
     fn add_direct<'a, 'b>(
@@ -210,7 +609,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         let mut last = self.free_head;
 
         for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
-            assert_ne!(buffer.len(), 0);
+            debug_assert_ne!(buffer.len(), 0);
 
             // Write to desc_shadow then copy.
             let desc = &mut self.desc_shadow[usize::from(self.free_head)];
@@ -255,15 +654,13 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             }
             desc.next = (i + 1) as u16;
         }
-        indirect_list
-            .last_mut()
-            .unwrap()
-            .flags
-            .remove(DescFlags::NEXT);
+        if let Some(last) = indirect_list.last_mut() {
+            last.flags.remove(DescFlags::NEXT);
+        }
 
         // Need to store pointer to indirect_list too, because direct_desc.set_buf will only store
         // the physical DMA address which might be different.
-        assert!(self.indirect_lists[usize::from(head)].is_none());
+        debug_assert!(self.indirect_lists[usize::from(head)].is_none());
         self.indirect_lists[usize::from(head)] = Some(indirect_list.as_mut().into());
 
         // Write a descriptor pointing to indirect descriptor list. We use Box::leak to prevent the
@@ -290,10 +687,10 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     /// This assumes that the device isn't processing any other buffers at the same time.
     ///
     /// The buffers must not be empty.
-    pub fn add_notify_wait_pop<'a>(
+    pub fn add_notify_wait_pop<'a, 'b>(
         &mut self,
-        inputs: &'a [&'a [u8]],
-        outputs: &'a mut [&'a mut [u8]],
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
         transport: &mut impl Transport,
     ) -> Result<u32> {
         // Safe because we don't return until the same token has been popped, so the buffers remain
@@ -302,7 +699,7 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
         // Notify the queue.
         if self.should_notify() {
-            transport.notify(self.queue_idx);
+            self.notify(transport);
         }
 
         // Wait until there is at least one element in the used ring.
@@ -317,10 +714,33 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
     /// Advise the device whether used buffer notifications are needed.
     ///
+    /// With `VIRTIO_F_EVENT_IDX`, [`pop_used`](Self::pop_used) stops moving the `used_event` index
+    /// while notifications are disabled. The device may have used more buffers before it sees
+    /// that they are enabled again, so the driver should check [`can_pop`](Self::can_pop)
+    /// afterwards rather than wait for a notification.
+    ///
     /// See Virtio v1.1 2.6.7 Used Buffer Notification Suppression
     pub fn set_dev_notify(&mut self, enable: bool) {
-        let avail_ring_flags = if enable { 0x0000 } else { 0x0001 };
-        if !self.event_idx {
+        self.dev_notify = enable;
+        if self.event_idx {
+            // Ask to be notified about the next buffer the device uses, or to disable, about the
+            // buffer before the next one to pop, which the device has already used and so won't
+            // pass again until the index wraps around.
+            let used_event = if enable {
+                self.last_used_idx
+            } else {
+                self.last_used_idx.wrapping_sub(1)
+            };
+            // Safe because `used_event` points into the driver area, which is only read by the
+            // device.
+            unsafe {
+                (*self.used_event()).store(used_event, Ordering::Release);
+            }
+            // Make sure the device sees the new `used_event` before the caller checks for buffers
+            // which it used in the meantime.
+            fence(Ordering::SeqCst);
+        } else {
+            let avail_ring_flags = if enable { 0x0000 } else { 0x0001 };
             // Safe because self.avail points to a valid, aligned, initialised, dereferenceable, readable
             // instance of AvailRing.
             unsafe {
@@ -334,13 +754,20 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     /// Returns whether the driver should notify the device after adding a new buffer to the
     /// virtqueue.
     ///
-    /// This will be false if the device has supressed notifications.
-    pub fn should_notify(&self) -> bool {
+    /// This will be false if the device has supressed notifications. With `VIRTIO_F_EVENT_IDX`, a
+    /// single notification covers every buffer added since the previous call, so the driver should
+    /// notify the device whenever this returns true, and may add several buffers before calling it
+    /// to coalesce their notifications.
+    pub fn should_notify(&mut self) -> bool {
         if self.event_idx {
+            // Make sure the device sees the new `avail.idx` before we check whether it wants to be
+            // notified about it.
+            fence(Ordering::SeqCst);
             // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
             // instance of UsedRing.
-            let avail_event = unsafe { (*self.used.as_ptr()).avail_event.load(Ordering::Acquire) };
-            self.avail_idx >= avail_event.wrapping_add(1)
+            let avail_event = unsafe { (*self.avail_event()).load(Ordering::Acquire) };
+            let old = replace(&mut self.notified_avail_idx, self.avail_idx);
+            need_event(avail_event, self.avail_idx, old)
         } else {
             // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
             // instance of UsedRing.
@@ -348,6 +775,40 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
     }
 
+    /// Notifies the device about buffers added to the queue, through the HAL's
+    /// [`notify_hook`](Hal::notify_hook).
+    ///
+    /// Drivers should call this when [`should_notify`](Self::should_notify) returns true.
+    pub fn notify(&self, transport: &mut impl Transport) {
+        hal::notify_queue::<H>(transport, self.queue_idx, self.doorbell);
+    }
+
+    /// Returns a pointer to the `used_event` field of the available ring, which comes after the
+    /// ring's `size` entries.
+    fn used_event(&self) -> *const AtomicU16 {
+        // SAFETY: The driver area was allocated for a ring of `self.size` entries followed by the
+        // field, so the offset is in bounds.
+        unsafe {
+            addr_of!((*self.avail.as_ptr()).ring)
+                .cast::<u16>()
+                .add(self.size.into())
+                .cast()
+        }
+    }
+
+    /// Returns a pointer to the `avail_event` field of the used ring, which comes after the
+    /// ring's `size` entries.
+    fn avail_event(&self) -> *const AtomicU16 {
+        // SAFETY: The device area was allocated for a ring of `self.size` entries followed by the
+        // field, so the offset is in bounds.
+        unsafe {
+            addr_of!((*self.used.as_ptr()).ring)
+                .cast::<UsedElem>()
+                .add(self.size.into())
+                .cast()
+        }
+    }
+
     /// Copies the descriptor at the given index from `desc_shadow` to `desc`, so it can be seen by
     /// the device.
     fn write_desc(&mut self, index: u16) {
@@ -359,6 +820,67 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
     }
 
+    /// Checks that the device wrote at least `read` bytes to a chain which it used, given the
+    /// `written` length from [`pop_used`](Self::pop_used), before the driver reads that many.
+    ///
+    /// This only does anything with the `poison` feature, which logs a warning and returns false
+    /// if the driver would read bytes that the device didn't write.
+    pub fn check_written(&self, written: u32, read: usize) -> bool {
+        poison::check_written(self.queue_idx, written, read)
+    }
+
+    /// Returns the number of descriptor chains which have been made available to the device but
+    /// not yet popped, including those which the device has used but the driver hasn't completed.
+    pub fn in_flight(&self) -> usize {
+        usize::from(self.avail_idx.wrapping_sub(self.last_used_idx))
+    }
+
+    /// Sets the limit on the number of descriptor chains which the device may have in flight at
+    /// once, as enforced by [`throttle`](Self::throttle), or `None` (the default) for no limit
+    /// other than the size of the queue.
+    ///
+    /// Returns [`Error::InvalidParam`] if the limit is zero.
+    pub fn set_in_flight_limit(&mut self, limit: Option<InFlightLimit>) -> Result {
+        if limit.is_some_and(|limit| limit.max == 0) {
+            return Err(Error::InvalidParam);
+        }
+        self.in_flight_limit = limit;
+        Ok(())
+    }
+
+    /// Returns the limit on the number of descriptor chains in flight, if any.
+    pub fn in_flight_limit(&self) -> Option<InFlightLimit> {
+        self.in_flight_limit
+    }
+
+    /// Checks whether another descriptor chain may be added without exceeding the in-flight limit.
+    ///
+    /// If the limit has been reached then this returns [`Error::Throttled`]. With
+    /// [`ThrottleMode::Block`] it first notifies the device and waits until it has used a chain,
+    /// unless one is already waiting to be popped. Drivers should call this before adding each
+    /// request which counts towards the limit.
+    pub fn throttle(&mut self, transport: &mut impl Transport) -> Result {
+        let Some(limit) = self.in_flight_limit else {
+            return Ok(());
+        };
+        if self.in_flight() < usize::from(limit.max) {
+            return Ok(());
+        }
+        match limit.mode {
+            ThrottleMode::Error => Err(Error::Throttled),
+            ThrottleMode::Block => {
+                // The driver may have deferred notifying the device, which would never make
+                // progress then.
+                self.notify(transport);
+                while !self.can_pop() {
+                    spin_loop();
+                }
+                // Used chains still count until they are popped, which only the caller can do.
+                Err(Error::Throttled)
+            }
+        }
+    }
+
     /// Returns whether there is a used element that can be popped.
     pub fn can_pop(&self) -> bool {
         // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
@@ -366,11 +888,19 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         self.last_used_idx != unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) }
     }
 
+    /// Returns the number of elements in the used ring which haven't been popped yet.
+    pub fn pending_used(&self) -> usize {
+        // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
+        // instance of UsedRing.
+        let used_idx = unsafe { (*self.used.as_ptr()).idx.load(Ordering::Acquire) };
+        usize::from(used_idx.wrapping_sub(self.last_used_idx))
+    }
+
     /// Returns the descriptor index (a.k.a. token) of the next used element without popping it, or
     /// `None` if the used ring is empty.
     pub fn peek_used(&self) -> Option<u16> {
         if self.can_pop() {
-            let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
+            let last_used_slot = self.last_used_idx & (self.size - 1);
             // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
             // readable instance of UsedRing.
             Some(unsafe { (*self.used.as_ptr()).ring[last_used_slot as usize].id as u16 })
@@ -379,18 +909,16 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
         }
     }
 
-    /// Returns the number of free descriptors.
+    /// Returns the number of free descriptors, not counting any which are reserved.
     pub fn available_desc(&self) -> usize {
+        let size = usize::from(self.size);
+        let unavailable = usize::from(self.num_used + self.num_reserved);
         #[cfg(feature = "alloc")]
         if self.indirect {
-            return if usize::from(self.num_used) == SIZE {
-                0
-            } else {
-                SIZE
-            };
+            return if unavailable == size { 0 } else { size };
         }
 
-        SIZE - usize::from(self.num_used)
+        size - unavailable
     }
 
     /// Unshares buffers in the list starting at descriptor index `head` and adds them to the free
@@ -402,13 +930,34 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     /// # Safety
     ///
     /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
-    /// queue by `add`.
-    unsafe fn recycle_descriptors<'a>(
+    /// queue by `add`. If there are a different number of them, or any has a different length or
+    /// direction to the descriptor it was added with, then [`Error::InvalidParam`] is returned and
+    /// nothing is recycled.
+    unsafe fn recycle_descriptors<'a, 'b>(
         &mut self,
         head: u16,
-        inputs: &'a [&'a [u8]],
-        outputs: &'a mut [&'a mut [u8]],
-    ) {
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result {
+        let buffers = inputs.len() + outputs.len();
+        if self.desc_shadow[usize::from(head)]
+            .flags
+            .contains(DescFlags::INDIRECT)
+        {
+            #[cfg(feature = "alloc")]
+            match self.indirect_lists[usize::from(head)] {
+                // SAFETY: We allocated the indirect list in `add_indirect`, and only we write to it.
+                Some(list) if list.len() == buffers => {
+                    check_descriptors(unsafe { list.as_ref() }.iter(), inputs, outputs)?
+                }
+                _ => return Err(Error::InvalidParam),
+            }
+        } else if self.chain_len(head) != buffers {
+            return Err(Error::InvalidParam);
+        } else {
+            check_descriptors(self.chain(head), inputs, outputs)?;
+        }
+
         let original_free_head = self.free_head;
         self.free_head = head;
 
@@ -418,7 +967,9 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             {
                 // Find the indirect descriptor list, unshare it and move its descriptor to the free
                 // list.
-                let indirect_list = self.indirect_lists[usize::from(head)].take().unwrap();
+                let Some(indirect_list) = self.indirect_lists[usize::from(head)].take() else {
+                    return Err(Error::InvalidParam);
+                };
                 // SAFETY: We allocated the indirect list in `add_indirect`, and the device has
                 // finished accessing it by this point.
                 let mut indirect_list = unsafe { Box::from_raw(indirect_list.as_ptr()) };
@@ -436,9 +987,8 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 }
 
                 // Unshare the buffers in the indirect descriptor list, and free it.
-                assert_eq!(indirect_list.len(), inputs.len() + outputs.len());
                 for (i, (buffer, direction)) in InputOutputIter::new(inputs, outputs).enumerate() {
-                    assert_ne!(buffer.len(), 0);
+                    debug_assert_ne!(buffer.len(), 0);
 
                     // SAFETY: The caller ensures that the buffer is valid and matches the
                     // descriptor from which we got `paddr`.
@@ -454,9 +1004,12 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
             let mut next = Some(head);
 
             for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
-                assert_ne!(buffer.len(), 0);
+                debug_assert_ne!(buffer.len(), 0);
 
-                let desc_index = next.expect("Descriptor chain was shorter than expected.");
+                // The length of the chain was checked above.
+                let Some(desc_index) = next else {
+                    break;
+                };
                 let desc = &mut self.desc_shadow[usize::from(desc_index)];
 
                 let paddr = desc.addr;
@@ -477,10 +1030,34 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
                 }
             }
 
-            if next.is_some() {
-                panic!("Descriptor chain was longer than expected.");
+            debug_assert!(next.is_none(), "Descriptor chain was longer than expected.");
+        }
+        Ok(())
+    }
+
+    /// Returns an iterator over the descriptors in the direct chain starting at `head`.
+    fn chain(&self, head: u16) -> impl Iterator<Item = &Descriptor> {
+        let mut next = Some(head);
+        core::iter::from_fn(move || {
+            let desc = &self.desc_shadow[usize::from(next?)];
+            next = desc.next();
+            Some(desc)
+        })
+    }
+
+    /// Returns the number of descriptors in the direct chain starting at `head`.
+    fn chain_len(&self, head: u16) -> usize {
+        let mut len = 1;
+        let mut next = self.desc_shadow[usize::from(head)].next();
+        // Stop at the queue size in case the chain is somehow circular.
+        while let Some(index) = next {
+            if len >= usize::from(self.size) {
+                break;
             }
+            len += 1;
+            next = self.desc_shadow[usize::from(index)].next();
         }
+        len
     }
 
     /// If the given token is next on the device used queue, pops it and returns the total buffer
@@ -492,18 +1069,18 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     ///
     /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
     /// queue by `add` when it returned the token being passed in here.
-    pub unsafe fn pop_used<'a>(
+    pub unsafe fn pop_used<'a, 'b>(
         &mut self,
         token: u16,
-        inputs: &'a [&'a [u8]],
-        outputs: &'a mut [&'a mut [u8]],
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
     ) -> Result<u32> {
         if !self.can_pop() {
             return Err(Error::NotReady);
         }
 
         // Get the index of the start of the descriptor chain for the next element in the used ring.
-        let last_used_slot = self.last_used_idx & (SIZE as u16 - 1);
+        let last_used_slot = self.last_used_idx & (self.size - 1);
         let index;
         let len;
         // Safe because self.used points to a valid, aligned, initialised, dereferenceable, readable
@@ -520,15 +1097,18 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
 
         // Safe because the caller ensures the buffers are valid and match the descriptor.
         unsafe {
-            self.recycle_descriptors(index, inputs, outputs);
+            self.recycle_descriptors(index, inputs, &mut *outputs)?;
         }
+        poison::fill_unwritten(outputs, len);
         self.last_used_idx = self.last_used_idx.wrapping_add(1);
+        self.monitor
+            .record_used(self.queue_idx, index, H::timestamp);
 
-        if self.event_idx {
+        if self.event_idx && self.dev_notify {
+            // Safe because `used_event` points into the driver area, which is only read by the
+            // device.
             unsafe {
-                (*self.avail.as_ptr())
-                    .used_event
-                    .store(self.last_used_idx, Ordering::Release);
+                (*self.used_event()).store(self.last_used_idx, Ordering::Release);
             }
         }
 
@@ -536,151 +1116,144 @@ impl<H: Hal, const SIZE: usize> VirtQueue<H, SIZE> {
     }
 }
 
-/// The inner layout of a VirtQueue.
-///
-/// Ref: 2.6 Split Virtqueues
-#[derive(Debug)]
-enum VirtQueueLayout<H: Hal> {
-    Legacy {
-        dma: Dma<H>,
-        avail_offset: usize,
-        used_offset: usize,
-    },
-    Modern {
-        /// The region used for the descriptor area and driver area.
-        driver_to_device_dma: Dma<H>,
-        /// The region used for the device area.
-        device_to_driver_dma: Dma<H>,
-        /// The offset from the start of the `driver_to_device_dma` region to the driver area
-        /// (available ring).
-        avail_offset: usize,
-    },
+/// What a driver does when a request would exceed its [`InFlightLimit`].
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum ThrottleMode {
+    /// Fail the request with [`Error::Throttled`], so the caller can try again after completing
+    /// some of the requests in flight.
+    #[default]
+    Error,
+    /// Wait until the device has finished with at least one request, then fail with
+    /// [`Error::Throttled`] so the caller can complete it and try again.
+    Block,
 }
 
-impl<H: Hal> VirtQueueLayout<H> {
-    /// Allocates a single DMA region containing all parts of the virtqueue, following the layout
-    /// required by legacy interfaces.
-    ///
-    /// Ref: 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
-    fn allocate_legacy(queue_size: u16) -> Result<Self> {
-        let (desc, avail, used) = queue_part_sizes(queue_size);
-        let size = align_up(desc + avail) + align_up(used);
-        // Allocate contiguous pages.
-        let dma = Dma::new(size / PAGE_SIZE, BufferDirection::Both)?;
-        Ok(Self::Legacy {
-            dma,
-            avail_offset: desc,
-            used_offset: align_up(desc + avail),
-        })
-    }
-
-    /// Allocates separate DMA regions for the the different parts of the virtqueue, as supported by
-    /// non-legacy interfaces.
-    ///
-    /// This is preferred over `allocate_legacy` where possible as it reduces memory fragmentation
-    /// and allows the HAL to know which DMA regions are used in which direction.
-    fn allocate_flexible(queue_size: u16) -> Result<Self> {
-        let (desc, avail, used) = queue_part_sizes(queue_size);
-        let driver_to_device_dma = Dma::new(pages(desc + avail), BufferDirection::DriverToDevice)?;
-        let device_to_driver_dma = Dma::new(pages(used), BufferDirection::DeviceToDriver)?;
-        Ok(Self::Modern {
-            driver_to_device_dma,
-            device_to_driver_dma,
-            avail_offset: desc,
-        })
-    }
+/// A limit on the number of requests which a driver has in flight at once, independent of the size
+/// of its queues.
+///
+/// This can be used for fairness across several devices, or to bound the number of completions
+/// which must be processed in one batch. A request counts as in flight from when it is submitted
+/// until the caller has completed it, even if the device has already finished with it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InFlightLimit {
+    /// The maximum number of requests in flight at once. This must not be zero.
+    pub max: u16,
+    /// What to do with a request which would exceed the limit.
+    pub mode: ThrottleMode,
+}
 
-    /// Returns the physical address of the descriptor area.
-    fn descriptors_paddr(&self) -> PhysAddr {
-        match self {
-            Self::Legacy { dma, .. } => dma.paddr(),
-            Self::Modern {
-                driver_to_device_dma,
-                ..
-            } => driver_to_device_dma.paddr(),
-        }
-    }
+/// Descriptors set aside in a virtqueue for requests which must be submitted without failing, as
+/// returned by `reserve` methods such as `VirtIOBlk::reserve`.
+///
+/// Any descriptors which aren't used must be given back to the queue they came from, or they will
+/// stay unavailable.
+#[must_use = "unused descriptors must be released back to the queue"]
+#[derive(Debug, Eq, PartialEq)]
+pub struct Reservation {
+    /// The physical address of the descriptor table of the queue which the descriptors are
+    /// reserved in, which identifies the queue.
+    queue: PhysAddr,
+    /// The number of descriptors left in the reservation.
+    remaining: u16,
+}
 
-    /// Returns a pointer to the descriptor table (in the descriptor area).
-    fn descriptors_vaddr(&self) -> NonNull<u8> {
-        match self {
-            Self::Legacy { dma, .. } => dma.vaddr(0),
-            Self::Modern {
-                driver_to_device_dma,
-                ..
-            } => driver_to_device_dma.vaddr(0),
-        }
+impl Reservation {
+    /// Returns the number of descriptors left in the reservation.
+    pub fn remaining(&self) -> usize {
+        self.remaining.into()
     }
+}
 
-    /// Returns the physical address of the driver area.
-    fn driver_area_paddr(&self) -> PhysAddr {
-        match self {
-            Self::Legacy {
-                dma, avail_offset, ..
-            } => dma.paddr() + avail_offset,
-            Self::Modern {
-                driver_to_device_dma,
-                avail_offset,
-                ..
-            } => driver_to_device_dma.paddr() + avail_offset,
-        }
-    }
+/// The NUMA nodes on which the parts of a virtqueue were allocated.
+///
+/// Each field is `None` if the HAL doesn't know where the memory landed.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct QueuePlacement {
+    /// The node of the descriptor table.
+    pub descriptors: Option<NumaNode>,
+    /// The node of the driver area (available ring).
+    pub driver_area: Option<NumaNode>,
+    /// The node of the device area (used ring).
+    pub device_area: Option<NumaNode>,
+}
 
-    /// Returns a pointer to the available ring (in the driver area).
-    fn avail_vaddr(&self) -> NonNull<u8> {
-        match self {
-            Self::Legacy {
-                dma, avail_offset, ..
-            } => dma.vaddr(*avail_offset),
-            Self::Modern {
-                driver_to_device_dma,
-                avail_offset,
-                ..
-            } => driver_to_device_dma.vaddr(*avail_offset),
-        }
-    }
+/// The physical layout of a virtqueue and the current positions in its rings, as returned by the
+/// drivers' `queue_layout` methods.
+///
+/// The struct has a fixed layout with no padding, so it can be serialised with
+/// [`AsBytes::as_bytes`] and parsed with [`FromBytes::read_from`]. Fields are in the guest's byte
+/// order.
+#[repr(C)]
+#[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct VirtQueueLayout {
+    /// The physical address of the descriptor table.
+    pub descriptors_paddr: u64,
+    /// The physical address of the driver area (available ring).
+    pub driver_area_paddr: u64,
+    /// The physical address of the device area (used ring).
+    pub device_area_paddr: u64,
+    /// The size of the descriptor table in bytes.
+    pub descriptors_len: u64,
+    /// The size of the driver area in bytes.
+    pub driver_area_len: u64,
+    /// The size of the device area in bytes.
+    pub device_area_len: u64,
+    /// The index of the queue on its device.
+    pub queue_index: u16,
+    /// The number of descriptors, and of slots in each ring.
+    pub size: u16,
+    /// The index which the driver will next write to in the available ring.
+    pub avail_idx: u16,
+    /// The index in the used ring of the next element which the driver will pop.
+    pub last_used_idx: u16,
+    /// The index which the device will next write to in the used ring.
+    pub device_used_idx: u16,
+    /// The number of descriptors which the driver has added and not yet reclaimed.
+    pub descriptors_in_use: u16,
+    /// How the queue was set up.
+    pub flags: QueueLayoutFlags,
+}
 
-    /// Returns the physical address of the device area.
-    fn device_area_paddr(&self) -> PhysAddr {
-        match self {
-            Self::Legacy {
-                used_offset, dma, ..
-            } => dma.paddr() + used_offset,
-            Self::Modern {
-                device_to_driver_dma,
-                ..
-            } => device_to_driver_dma.paddr(),
-        }
-    }
+/// Flags describing how a virtqueue was set up, in a [`VirtQueueLayout`].
+#[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+#[repr(transparent)]
+pub struct QueueLayoutFlags(u32);
 
-    /// Returns a pointer to the used ring (in the driver area).
-    fn used_vaddr(&self) -> NonNull<u8> {
-        match self {
-            Self::Legacy {
-                dma, used_offset, ..
-            } => dma.vaddr(*used_offset),
-            Self::Modern {
-                device_to_driver_dma,
-                ..
-            } => device_to_driver_dma.vaddr(0),
-        }
+bitflags! {
+    impl QueueLayoutFlags: u32 {
+        /// The rings are in a single region, in the layout required by legacy interfaces.
+        const LEGACY = 1 << 0;
+        /// `VIRTIO_F_EVENT_IDX` was negotiated.
+        const EVENT_IDX = 1 << 1;
+        /// Indirect descriptors are used for chains of more than one buffer.
+        const INDIRECT = 1 << 2;
     }
 }
 
-/// Returns the size in bytes of the descriptor table, available ring and used ring for a given
-/// queue size.
+/// The state of a virtqueue saved by a driver so that it can be restored later without resetting
+/// the device, e.g. across an in-place update of the guest kernel.
+///
+/// The memory of the rings isn't part of the state: it stays allocated and in use by the device,
+/// and the restored queue takes over ownership of it. Like [`VirtQueueLayout`] the struct has a
+/// fixed layout with no padding, so it can be copied to wherever it needs to survive the update.
 ///
-/// Ref: 2.6 Split Virtqueues
-fn queue_part_sizes(queue_size: u16) -> (usize, usize, usize) {
-    assert!(
-        queue_size.is_power_of_two(),
-        "queue size should be a power of 2"
-    );
-    let queue_size = queue_size as usize;
-    let desc = size_of::<Descriptor>() * queue_size;
-    let avail = size_of::<u16>() * (3 + queue_size);
-    let used = size_of::<u16>() * 3 + size_of::<UsedElem>() * queue_size;
-    (desc, avail, used)
+/// Only the block and entropy device drivers currently save and restore their queues this way;
+/// devices driven by any other driver are still reset when it is replaced.
+#[repr(C)]
+#[derive(AsBytes, Copy, Clone, Debug, Default, Eq, FromBytes, FromZeroes, PartialEq)]
+pub struct VirtQueueState {
+    /// The physical layout of the queue and the positions in its rings.
+    pub layout: VirtQueueLayout,
+    /// The virtual address at which the driver accesses the descriptor table.
+    pub descriptors_vaddr: u64,
+    /// The virtual address at which the driver accesses the driver area.
+    pub driver_area_vaddr: u64,
+    /// The virtual address at which the driver accesses the device area.
+    pub device_area_vaddr: u64,
+    /// The index of the first descriptor in the free list.
+    pub free_head: u16,
+    /// Reserved, always zero.
+    pub reserved: [u16; 3],
 }
 
 #[repr(C, align(16))]
@@ -709,13 +1282,16 @@ impl Descriptor {
             self.addr = H::share(buf, direction) as u64;
         }
         self.len = buf.len() as u32;
+        debug_assert_ne!(
+            direction,
+            BufferDirection::Both,
+            "Buffer passed to device should never use BufferDirection::Both."
+        );
         self.flags = extra_flags
             | match direction {
-                BufferDirection::DeviceToDriver => DescFlags::WRITE,
+                // If the device might write to the buffer then it must be marked as writable.
+                BufferDirection::DeviceToDriver | BufferDirection::Both => DescFlags::WRITE,
                 BufferDirection::DriverToDevice => DescFlags::empty(),
-                BufferDirection::Both => {
-                    panic!("Buffer passed to device should never use BufferDirection::Both.")
-                }
             };
     }
 
@@ -751,6 +1327,8 @@ bitflags! {
     }
 }
 
+impl_flags_display!(DescFlags);
+
 /// The driver uses the available ring to offer buffers to the device:
 /// each ring entry refers to the head of a descriptor chain.
 /// It is only written by the driver and read by the device.
@@ -784,6 +1362,50 @@ struct UsedElem {
     len: u32,
 }
 
+/// Returns whether the index `event` which the other side asked to be notified about is among the
+/// entries between `old` and `new`, taking wrap-around into account.
+///
+/// Ref: 2.7.10 Driver Notifications, `vring_need_event`
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+/// Returns [`Error::InvalidParam`] unless the given buffers have the same lengths and directions as
+/// the descriptors they were added with.
+///
+/// This stops a buffer which the device may have written to from being unshared, and perhaps
+/// copied back, as a device-readable one, or a read-only buffer from being written to as a
+/// device-writable one.
+fn check_descriptors<'d>(
+    descriptors: impl Iterator<Item = &'d Descriptor>,
+    inputs: &[&[u8]],
+    outputs: &[&mut [u8]],
+) -> Result {
+    let buffers = inputs
+        .iter()
+        .map(|input| (input.len(), false))
+        .chain(outputs.iter().map(|output| (output.len(), true)));
+    for (desc, (len, writable)) in descriptors.zip(buffers) {
+        if desc.len as usize != len || desc.flags.contains(DescFlags::WRITE) != writable {
+            return Err(Error::InvalidParam);
+        }
+    }
+    Ok(())
+}
+
+/// Returns [`Error::InvalidParam`] if there are no buffers or any of them is empty, as the device
+/// can't use empty descriptors.
+fn check_buffers(inputs: &[&[u8]], outputs: &[&mut [u8]]) -> Result {
+    if (inputs.is_empty() && outputs.is_empty())
+        || inputs.iter().any(|buffer| buffer.is_empty())
+        || outputs.iter().any(|buffer| buffer.is_empty())
+    {
+        Err(Error::InvalidParam)
+    } else {
+        Ok(())
+    }
+}
+
 struct InputOutputIter<'a, 'b> {
     inputs: &'a [&'b [u8]],
     outputs: &'a mut [&'b mut [u8]],
@@ -824,6 +1446,21 @@ fn take_first_mut<'a, T>(slice: &mut &'a mut [T]) -> Option<&'a mut T> {
     Some(first)
 }
 
+/// Returns whether the driver has made any buffers available which the fake device hasn't used yet.
+#[cfg(test)]
+pub(crate) fn fake_queue_has_available<const QUEUE_SIZE: usize>(
+    queue_driver_area: *const u8,
+    queue_device_area: *const u8,
+) -> bool {
+    let available_ring = queue_driver_area as *const AvailRing<QUEUE_SIZE>;
+    let used_ring = queue_device_area as *const UsedRing<QUEUE_SIZE>;
+    // Safe because the pointers are properly aligned, dereferenceable and initialised, and the
+    // fields we read are atomic.
+    unsafe {
+        (*available_ring).idx.load(Ordering::Acquire) != (*used_ring).idx.load(Ordering::Acquire)
+    }
+}
+
 /// Simulates the device reading from a VirtIO queue and writing a response back, for use in tests.
 ///
 /// The fake device always uses descriptors in order.
@@ -853,7 +1490,6 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
         let head_descriptor_index = (*available_ring).ring[next_slot as usize];
         let mut descriptor = &(*descriptors)[head_descriptor_index as usize];
 
-        let input_length;
         let output;
         if descriptor.flags.contains(DescFlags::INDIRECT) {
             // The descriptor shouldn't have any other flags if it is indirect.
@@ -881,7 +1517,6 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
 
                 indirect_descriptor_index += 1;
             }
-            input_length = input.len();
 
             // Let the test handle the request.
             output = handler(input);
@@ -918,7 +1553,6 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
                     break;
                 }
             }
-            input_length = input.len();
 
             // Let the test handle the request.
             output = handler(input);
@@ -949,7 +1583,7 @@ pub(crate) fn fake_read_write_queue<const QUEUE_SIZE: usize>(
 
         // Mark the buffer as used.
         (*used_ring).ring[next_slot as usize].id = head_descriptor_index as u32;
-        (*used_ring).ring[next_slot as usize].len = (input_length + output.len()) as u32;
+        (*used_ring).ring[next_slot as usize].len = output.len() as u32;
         (*used_ring).idx.fetch_add(1, Ordering::AcqRel);
     }
 }
@@ -959,20 +1593,26 @@ mod tests {
     use super::*;
     use crate::{
         device::common::Feature,
-        hal::fake::FakeHal,
+        hal::fake::{FakeHal, FakeHalHooks, HookedHal},
         transport::{
             fake::{FakeTransport, QueueStatus, State},
             mmio::{MmioTransport, VirtIOHeader, MODERN_VERSION},
             DeviceType,
         },
+        PAGE_SIZE,
+    };
+    use core::{ptr::NonNull, sync::atomic::AtomicUsize};
+    use std::{
+        sync::{Arc, Mutex},
+        thread,
     };
-    use core::ptr::NonNull;
-    use std::sync::{Arc, Mutex};
 
     #[test]
     fn invalid_queue_size() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
         // Size not a power of 2.
         assert_eq!(
             VirtQueue::<FakeHal, 3>::new(&mut transport, 0, false, false).unwrap_err(),
@@ -983,7 +1623,9 @@ mod tests {
     #[test]
     fn queue_too_big() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
         assert_eq!(
             VirtQueue::<FakeHal, 8>::new(&mut transport, 0, false, false).unwrap_err(),
             Error::InvalidParam
@@ -993,7 +1635,9 @@ mod tests {
     #[test]
     fn queue_already_used() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
         VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
         assert_eq!(
             VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap_err(),
@@ -1001,10 +1645,109 @@ mod tests {
         );
     }
 
+    /// Allows DMA allocations of at most one page.
+    #[derive(Debug)]
+    struct OnePage;
+
+    impl FakeHalHooks for OnePage {
+        const MAX_DMA_PAGES: usize = 1;
+    }
+
+    #[test]
+    fn degrade_queue_size() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 256);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        // The descriptors and available ring of a 256 entry queue need two pages.
+        assert_eq!(
+            VirtQueue::<HookedHal<OnePage>, 256>::new(&mut transport, 0, false, true).unwrap_err(),
+            Error::OutOfDmaMemory {
+                requested: 2 * PAGE_SIZE,
+                align: PAGE_SIZE,
+            }
+        );
+
+        let queue = VirtQueue::<HookedHal<OnePage>, 256>::new_with_policy(
+            &mut transport,
+            0,
+            false,
+            true,
+            MemoryLocality::Any,
+            AllocFailurePolicy::Degrade,
+        )
+        .unwrap();
+        assert_eq!(queue.size(), 128);
+        assert_eq!(queue.available_desc(), 128);
+        let layout = queue.export_layout();
+        assert_eq!(layout.size, 128);
+        assert_eq!(layout.driver_area_len, 262);
+        assert_eq!(layout.device_area_len, 1030);
+    }
+
+    #[test]
+    fn placement_unknown_without_hint() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(queue.placement(), QueuePlacement::default());
+    }
+
+    #[test]
+    fn export_layout() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+        unsafe { queue.add(&[&[1, 2]], &mut []) }.unwrap();
+
+        let layout = queue.export_layout();
+        assert_eq!(layout.size, 4);
+        assert_eq!(layout.descriptors_len, 64);
+        assert_eq!(layout.driver_area_len, 14);
+        assert_eq!(layout.device_area_len, 38);
+        assert_eq!(layout.avail_idx, 1);
+        assert_eq!(layout.last_used_idx, 0);
+        assert_eq!(layout.device_used_idx, 0);
+        assert_eq!(layout.descriptors_in_use, 1);
+        assert_eq!(layout.flags, QueueLayoutFlags::EVENT_IDX);
+        assert_ne!(layout.descriptors_paddr, 0);
+        assert_eq!(VirtQueueLayout::read_from(layout.as_bytes()), Some(layout));
+    }
+
+    #[test]
+    fn placement_with_node_hint() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let queue = VirtQueue::<FakeHal, 4>::new_with_locality(
+            &mut transport,
+            0,
+            false,
+            false,
+            MemoryLocality::Node(3),
+        )
+        .unwrap();
+        assert_eq!(
+            queue.placement(),
+            QueuePlacement {
+                descriptors: Some(3),
+                driver_area: Some(3),
+                device_area: Some(3),
+            }
+        );
+    }
+
     #[test]
     fn add_empty() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
         let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
         assert_eq!(
             unsafe { queue.add(&[], &mut []) }.unwrap_err(),
@@ -1012,10 +1755,68 @@ mod tests {
         );
     }
 
+    #[test]
+    fn add_zero_length() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(
+            unsafe { queue.add(&[&[1], &[]], &mut []) }.unwrap_err(),
+            Error::InvalidParam
+        );
+        assert_eq!(queue.available_desc(), 4);
+    }
+
+    #[test]
+    fn pop_used_wrong_buffers() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+        let request = [1, 2];
+        let mut response = [0];
+        let token = unsafe { queue.add(&[&request], &mut [&mut response]) }.unwrap();
+        fake_read_write_queue(
+            queue.desc.cast::<[Descriptor; 4]>().as_ptr(),
+            queue.avail.cast().as_ptr(),
+            queue.used.cast().as_ptr(),
+            |input| {
+                assert_eq!(input, request);
+                vec![3]
+            },
+        );
+
+        // Passing a different number of buffers is an error rather than a panic, and leaves the
+        // chain in place.
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&request], &mut []) },
+            Err(Error::InvalidParam)
+        );
+        // So is passing buffers of the wrong length, or passing the device-readable buffer as a
+        // device-writable one.
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&request[..1]], &mut [&mut response]) },
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[], &mut [&mut [0, 0], &mut response]) },
+            Err(Error::InvalidParam)
+        );
+        unsafe { queue.pop_used(token, &[&request], &mut [&mut response]) }.unwrap();
+        assert_eq!(response, [3]);
+        assert_eq!(queue.available_desc(), 4);
+    }
+
     #[test]
     fn add_too_many() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
         let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
         assert_eq!(queue.available_desc(), 4);
         assert_eq!(
@@ -1024,10 +1825,76 @@ mod tests {
         );
     }
 
+    #[test]
+    fn reserve() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+
+        let mut reservation = queue.reserve(3).unwrap();
+        assert_eq!(queue.available_desc(), 1);
+        assert_eq!(queue.reserve(2), Err(Error::QueueFull));
+        // Unreserved adds can't use the reserved descriptors.
+        assert_eq!(
+            unsafe { queue.add(&[&[1], &[2]], &mut []) },
+            Err(Error::QueueFull)
+        );
+
+        let token =
+            unsafe { queue.add_reserved(&mut reservation, &[&[1], &[2]], &mut []) }.unwrap();
+        assert_eq!(reservation.remaining(), 1);
+        assert_eq!(queue.available_desc(), 1);
+        assert_eq!(
+            unsafe { queue.add_reserved(&mut reservation, &[&[1], &[2]], &mut []) },
+            Err(Error::InvalidParam)
+        );
+
+        queue.release(reservation).unwrap();
+        assert_eq!(queue.available_desc(), 2);
+        assert_eq!(token, 0);
+    }
+
+    #[test]
+    fn release_foreign_reservation() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut other_header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut other_transport = unsafe {
+            MmioTransport::new(NonNull::from(&mut other_header), size_of::<VirtIOHeader>())
+        }
+        .unwrap();
+        let mut other_queue =
+            VirtQueue::<FakeHal, 4>::new(&mut other_transport, 0, false, false).unwrap();
+
+        // A reservation from a queue with the same index on another device isn't accepted.
+        let mut reservation = other_queue.reserve(3).unwrap();
+        assert_eq!(
+            unsafe { queue.add_reserved(&mut reservation, &[&[1]], &mut []) },
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            queue.release(Reservation {
+                queue: other_queue.layout.descriptors_paddr(),
+                remaining: 3,
+            }),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(queue.available_desc(), 4);
+        other_queue.release(reservation).unwrap();
+        assert_eq!(other_queue.available_desc(), 4);
+    }
+
     #[test]
     fn add_buffers() {
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
         let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
         assert_eq!(queue.available_desc(), 4);
 
@@ -1090,7 +1957,9 @@ mod tests {
         use core::ptr::slice_from_raw_parts;
 
         let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
-        let mut transport = unsafe { MmioTransport::new(NonNull::from(&mut header)) }.unwrap();
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
         let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, true, false).unwrap();
         assert_eq!(queue.available_desc(), 4);
 
@@ -1199,7 +2068,7 @@ mod tests {
         unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
 
         // Check that the transport would be notified.
-        assert_eq!(queue.should_notify(), true);
+        assert!(queue.should_notify());
 
         // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
         // initialised, and nothing else is accessing them at the same time.
@@ -1209,7 +2078,217 @@ mod tests {
         }
 
         // Check that the transport would not be notified.
-        assert_eq!(queue.should_notify(), false);
+        assert!(!queue.should_notify());
+    }
+
+    #[test]
+    fn debug_dump() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        let mut response = [0; 4];
+        unsafe { queue.add(&[&[1, 2]], &mut [&mut response]) }.unwrap();
+        unsafe { queue.add(&[&[3]], &mut []) }.unwrap();
+        state
+            .lock()
+            .unwrap()
+            .read_write_queue::<4>(0, |_| vec![9; 4]);
+
+        let dump = queue.debug_dump().to_string();
+        let lines = dump.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines[0],
+            "virtqueue 0: size 4, 3 descriptors in use, 0 reserved, free head 3"
+        );
+        assert_eq!(lines[1], "  avail: idx 2, flags 0x0");
+        assert_eq!(lines[2], "  used: idx 1, popped up to 0, flags 0x0");
+        assert_eq!(lines[3], "  chain 0: used by device, len 4");
+        assert!(lines[4].starts_with("    desc 0: addr "));
+        assert!(lines[4].ends_with(", len 2, flags NEXT, next 1"));
+        assert!(lines[5].ends_with(", len 4, flags WRITE"));
+        assert_eq!(lines[6], "  chain 2: available to device");
+        assert!(lines[7].ends_with(", len 1, flags (none)"));
+        assert_eq!(lines.len(), 8);
+    }
+
+    /// The number of buffers which [`CountSharing`] has seen shared and not yet unshared.
+    static SHARED_BUFFERS: AtomicUsize = AtomicUsize::new(0);
+
+    /// Counts the buffers shared with the device.
+    #[derive(Debug)]
+    struct CountSharing;
+
+    impl FakeHalHooks for CountSharing {
+        fn shared() {
+            SHARED_BUFFERS.fetch_add(1, Ordering::SeqCst);
+        }
+
+        fn unshared() {
+            SHARED_BUFFERS.fetch_sub(1, Ordering::SeqCst);
+        }
+    }
+
+    /// Tests that every buffer is shared with the device while it is in the queue, including the
+    /// indirect descriptor table, and unshared once the device has used it.
+    #[test]
+    fn share_unshare_balanced() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        for (index, indirect) in [(0, false), (1, true)] {
+            let mut queue = VirtQueue::<HookedHal<CountSharing>, 4>::new(
+                &mut transport,
+                index,
+                indirect,
+                false,
+            )
+            .unwrap();
+            let request = [1, 2];
+            let data = [3; 8];
+            let mut response = [0; 4];
+            let token = unsafe { queue.add(&[&request, &data], &mut [&mut response]) }.unwrap();
+            // Indirect descriptor tables are only used with the `alloc` feature.
+            let indirect_table = usize::from(indirect && cfg!(feature = "alloc"));
+            assert_eq!(SHARED_BUFFERS.load(Ordering::SeqCst), 3 + indirect_table);
+
+            state.lock().unwrap().read_write_queue::<4>(index, |input| {
+                assert_eq!(input, [1, 2, 3, 3, 3, 3, 3, 3, 3, 3]);
+                vec![4, 5, 6, 7]
+            });
+            unsafe { queue.pop_used(token, &[&request, &data], &mut [&mut response]) }.unwrap();
+            assert_eq!(SHARED_BUFFERS.load(Ordering::SeqCst), 0);
+            assert_eq!(response, [4, 5, 6, 7]);
+        }
+    }
+
+    /// The queues for which [`Doorbell`] was asked to notify the device.
+    static HOOKED_QUEUES: Mutex<Vec<u16>> = Mutex::new(Vec::new());
+
+    /// Signals the device itself instead of through the transport.
+    #[derive(Debug)]
+    struct Doorbell;
+
+    impl FakeHalHooks for Doorbell {
+        fn notify_hook(queues: &[u16], _notify: &mut dyn FnMut()) {
+            HOOKED_QUEUES.lock().unwrap().extend_from_slice(queues);
+        }
+    }
+
+    /// Tests that notifications go through the HAL's hook, which may replace the transport's.
+    #[test]
+    fn notify_hook() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default(), QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut hooked =
+            VirtQueue::<HookedHal<Doorbell>, 4>::new(&mut transport, 1, false, false).unwrap();
+        unsafe { hooked.add(&[&[42]], &mut []) }.unwrap();
+        assert!(hooked.should_notify());
+        hooked.notify(&mut transport);
+        assert_eq!(*HOOKED_QUEUES.lock().unwrap(), vec![1]);
+        assert!(!state.lock().unwrap().queues[1]
+            .notified
+            .load(Ordering::SeqCst));
+
+        // The default hook rings the transport's doorbell.
+        let queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        queue.notify(&mut transport);
+        assert!(state.lock().unwrap().queues[0]
+            .notified
+            .load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn throttle() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: 0,
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, false).unwrap();
+        assert_eq!(
+            queue.set_in_flight_limit(Some(InFlightLimit {
+                max: 0,
+                mode: ThrottleMode::Error,
+            })),
+            Err(Error::InvalidParam)
+        );
+        queue
+            .set_in_flight_limit(Some(InFlightLimit {
+                max: 2,
+                mode: ThrottleMode::Error,
+            }))
+            .unwrap();
+
+        let buffers = [[1], [2]];
+        let mut tokens = [0; 2];
+        for (buffer, token) in buffers.iter().zip(&mut tokens) {
+            queue.throttle(&mut transport).unwrap();
+            *token = unsafe { queue.add(&[buffer], &mut []) }.unwrap();
+        }
+        assert_eq!(queue.in_flight(), 2);
+        assert_eq!(queue.throttle(&mut transport), Err(Error::Throttled));
+
+        // In blocking mode, the device is notified and the driver waits for it to use a chain. It
+        // still counts as in flight until it is popped.
+        queue
+            .set_in_flight_limit(Some(InFlightLimit {
+                max: 2,
+                mode: ThrottleMode::Block,
+            }))
+            .unwrap();
+        let handle = thread::spawn(move || {
+            State::wait_until_queue_notified(&state, 0);
+            state.lock().unwrap().read_write_queue::<4>(0, |request| {
+                assert_eq!(request, [1]);
+                vec![]
+            });
+        });
+        assert_eq!(queue.throttle(&mut transport), Err(Error::Throttled));
+        assert_eq!(queue.in_flight(), 2);
+        assert_eq!(queue.pending_used(), 1);
+        handle.join().unwrap();
+
+        // Waiting can't make room while a used chain is waiting to be popped.
+        assert_eq!(queue.throttle(&mut transport), Err(Error::Throttled));
+        unsafe { queue.pop_used(tokens[0], &[&buffers[0]], &mut []) }.unwrap();
+        assert_eq!(queue.in_flight(), 1);
+        queue.throttle(&mut transport).unwrap();
     }
 
     /// Tests that the queue notifies the device about added buffers, if it hasn't suppressed
@@ -1234,7 +2313,7 @@ mod tests {
         assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 0);
 
         // Check that the transport would be notified.
-        assert_eq!(queue.should_notify(), true);
+        assert!(queue.should_notify());
 
         // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
         // initialised, and nothing else is accessing them at the same time.
@@ -1246,12 +2325,100 @@ mod tests {
         }
 
         // Check that the transport would not be notified.
-        assert_eq!(queue.should_notify(), false);
+        assert!(!queue.should_notify());
 
         // Add another buffer chain.
         assert_eq!(unsafe { queue.add(&[&[42]], &mut []) }.unwrap(), 1);
 
         // Check that the transport should be notified again now.
-        assert_eq!(queue.should_notify(), true);
+        assert!(queue.should_notify());
+    }
+
+    /// Tests that one notification covers a batch of buffers, even if the `avail_event` index the
+    /// device asked about is in the middle of it.
+    #[test]
+    fn event_idx_coalesces_batch() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::RING_EVENT_IDX.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+        // Start just before `avail.idx` wraps around.
+        queue.avail_idx = 0xfffe;
+        queue.notified_avail_idx = 0xfffe;
+
+        // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+        // initialised, and nothing else is accessing them at the same time.
+        let avail_event = unsafe { &(*queue.used.as_ptr()).avail_event };
+        avail_event.store(0xffff, Ordering::Release);
+        for _ in 0..3 {
+            unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        }
+        assert_eq!(queue.avail_idx, 1);
+        assert!(queue.should_notify());
+        // Nothing was added since the last check.
+        assert!(!queue.should_notify());
+
+        // The device asks about an entry which hasn't been added yet.
+        avail_event.store(2, Ordering::Release);
+        unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        assert!(!queue.should_notify());
+    }
+
+    /// Tests that with `VIRTIO_F_EVENT_IDX` the device isn't asked for used buffer notifications
+    /// while they are disabled, even as the driver pops buffers.
+    #[test]
+    fn set_dev_notify_event_idx() {
+        let mut config_space = ();
+        let state = Arc::new(Mutex::new(State {
+            queues: vec![QueueStatus::default()],
+            ..Default::default()
+        }));
+        let mut transport = FakeTransport {
+            device_type: DeviceType::Block,
+            max_queue_size: 4,
+            device_features: Feature::RING_EVENT_IDX.bits(),
+            config_space: NonNull::from(&mut config_space),
+            state: state.clone(),
+        };
+        let mut queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 0, false, true).unwrap();
+        // Returns whether the device would notify the driver after using one more buffer.
+        let used_buffer = |queue: &VirtQueue<FakeHal, 4>| {
+            // SAFETY: the various parts of the queue are properly aligned, dereferenceable and
+            // initialised, and the device isn't accessing them at the same time.
+            let old = unsafe { (*queue.used.as_ptr()).idx.load(Ordering::Acquire) };
+            state.lock().unwrap().read_from_queue::<4>(0);
+            let used_event = unsafe { (*queue.used_event()).load(Ordering::Acquire) };
+            need_event(used_event, old.wrapping_add(1), old)
+        };
+
+        for _ in 0..3 {
+            unsafe { queue.add(&[&[42]], &mut []) }.unwrap();
+        }
+        queue.set_dev_notify(false);
+        for token in 0..2 {
+            assert!(!used_buffer(&queue));
+            unsafe { queue.pop_used(token, &[&[42]], &mut []) }.unwrap();
+        }
+
+        queue.set_dev_notify(true);
+        assert!(used_buffer(&queue));
+    }
+
+    #[test]
+    fn need_event_wraps() {
+        assert!(need_event(0, 1, 0));
+        assert!(!need_event(1, 1, 0));
+        assert!(need_event(0xffff, 2, 0xfffe));
+        assert!(!need_event(0xfffd, 2, 0xfffe));
+        assert!(!need_event(5, 5, 5));
     }
 }
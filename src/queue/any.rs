@@ -0,0 +1,172 @@
+//! A virtqueue in whichever format was negotiated with the device.
+
+use super::packed::PackedQueue;
+use super::VirtQueue;
+use crate::hal::Hal;
+use crate::transport::Transport;
+use crate::Result;
+
+/// A virtqueue which is either split or packed, for drivers which support both formats.
+///
+/// The format is chosen when the queue is created, according to whether `VIRTIO_F_RING_PACKED`
+/// was negotiated, and the queue then forwards each operation to the implementation for that
+/// format.
+#[derive(Debug)]
+pub enum AnyQueue<H: Hal, const SIZE: usize> {
+    /// A split virtqueue.
+    Split(VirtQueue<H, SIZE>),
+    /// A packed virtqueue.
+    Packed(PackedQueue<H, SIZE>),
+}
+
+/// Calls the given method on whichever queue is in use.
+macro_rules! dispatch {
+    ($self:ident.$method:ident($($arg:expr),*)) => {
+        match $self {
+            Self::Split(queue) => queue.$method($($arg),*),
+            Self::Packed(queue) => queue.$method($($arg),*),
+        }
+    };
+}
+
+impl<H: Hal, const SIZE: usize> AnyQueue<H, SIZE> {
+    /// Creates a new queue, in the packed format if `packed` is set or the split format otherwise.
+    ///
+    /// * `packed`: Whether to use the packed format. This should be set if the
+    ///   `VIRTIO_F_RING_PACKED` feature has been negotiated with the device.
+    /// * `indirect`: Whether to use indirect descriptors, which are only supported by split
+    ///   queues.
+    /// * `event_idx`: Whether to use event index based notification suppression.
+    pub fn new<T: Transport>(
+        transport: &mut T,
+        idx: u16,
+        packed: bool,
+        indirect: bool,
+        event_idx: bool,
+    ) -> Result<Self> {
+        Ok(if packed {
+            Self::Packed(PackedQueue::new(transport, idx, event_idx)?)
+        } else {
+            Self::Split(VirtQueue::new(transport, idx, indirect, event_idx)?)
+        })
+    }
+
+    /// Returns whether the queue is in the packed format.
+    pub fn is_packed(&self) -> bool {
+        matches!(self, Self::Packed(_))
+    }
+
+    /// Adds buffers to the virtqueue, returning a token.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        // Safe because our caller promises the same as the queue requires.
+        unsafe { dispatch!(self.add(inputs, outputs)) }
+    }
+
+    /// Adds the given buffers to the virtqueue, notifies the device, blocks until the device uses
+    /// them, then pops them.
+    pub fn add_notify_wait_pop<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+        transport: &mut impl Transport,
+    ) -> Result<u32> {
+        dispatch!(self.add_notify_wait_pop(inputs, outputs, transport))
+    }
+
+    /// Advises the device whether used buffer notifications are needed.
+    pub fn set_dev_notify(&mut self, enable: bool) {
+        dispatch!(self.set_dev_notify(enable))
+    }
+
+    /// Returns whether the driver should notify the device after adding new buffers.
+    pub fn should_notify(&mut self) -> bool {
+        dispatch!(self.should_notify())
+    }
+
+    /// Notifies the device about buffers added to the queue, through the HAL's
+    /// [`notify_hook`](Hal::notify_hook).
+    pub fn notify(&self, transport: &mut impl Transport) {
+        dispatch!(self.notify(transport))
+    }
+
+    /// Checks that the device wrote at least `read` bytes to a chain which it used.
+    pub fn check_written(&self, written: u32, read: usize) -> bool {
+        dispatch!(self.check_written(written, read))
+    }
+
+    /// Returns whether there is a used element that can be popped.
+    pub fn can_pop(&self) -> bool {
+        dispatch!(self.can_pop())
+    }
+
+    /// Returns the number of used elements which haven't been popped yet.
+    pub fn pending_used(&self) -> usize {
+        dispatch!(self.pending_used())
+    }
+
+    /// Returns the token of the next used element without popping it, or `None` if there isn't
+    /// one.
+    pub fn peek_used(&self) -> Option<u16> {
+        dispatch!(self.peek_used())
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        dispatch!(self.available_desc())
+    }
+
+    /// If the given token is next to be used, pops it and returns the total buffer length which
+    /// was used (written) by the device.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_used<'a, 'b>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u32> {
+        // Safe because our caller promises the same as the queue requires.
+        unsafe { dispatch!(self.pop_used(token, inputs, outputs)) }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::mmio::{MmioTransport, VirtIOHeader, MODERN_VERSION},
+    };
+    use core::{mem::size_of, ptr::NonNull};
+
+    #[test]
+    fn choose_format() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let split = AnyQueue::<FakeHal, 4>::new(&mut transport, 0, false, false, false).unwrap();
+        assert!(!split.is_packed());
+
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let packed = AnyQueue::<FakeHal, 4>::new(&mut transport, 0, true, false, false).unwrap();
+        assert!(packed.is_packed());
+        assert_eq!(packed.available_desc(), 4);
+        assert_eq!(packed.peek_used(), None);
+    }
+}
@@ -0,0 +1,150 @@
+//! A human-readable dump of the state of a split virtqueue, for debugging hangs.
+
+use super::{DescFlags, Descriptor, VirtQueue};
+use crate::hal::Hal;
+use core::fmt::{self, Display, Formatter};
+use core::sync::atomic::Ordering;
+
+/// A snapshot of the descriptor chains posted to a [`VirtQueue`] and its ring indices, from
+/// [`VirtQueue::debug_dump`].
+///
+/// This is formatted with [`Display`], one chain per line followed by its descriptors, so that it
+/// can be logged when a device seems to have stopped completing requests. The state is read from
+/// the queue when it is formatted.
+pub struct QueueDump<'a, H: Hal, const SIZE: usize> {
+    queue: &'a VirtQueue<H, SIZE>,
+}
+
+impl<'a, H: Hal, const SIZE: usize> QueueDump<'a, H, SIZE> {
+    pub(super) fn new(queue: &'a VirtQueue<H, SIZE>) -> Self {
+        Self { queue }
+    }
+
+    /// Returns which descriptors are in use, by following the free list.
+    fn in_use(&self) -> [bool; SIZE] {
+        let queue = self.queue;
+        let mut in_use = [false; SIZE];
+        in_use[..usize::from(queue.size)].fill(true);
+        let mut next = queue.free_head;
+        for _ in 0..queue.size - queue.num_used {
+            let Some(free) = in_use.get_mut(usize::from(next)) else {
+                break;
+            };
+            *free = false;
+            next = queue.desc_shadow[usize::from(next)].next;
+        }
+        in_use
+    }
+
+    /// Returns the length which the device reported for the given chain, if it has used it but the
+    /// driver hasn't popped it yet.
+    fn used_len(&self, head: u16, device_used_idx: u16) -> Option<u32> {
+        let queue = self.queue;
+        let pending = device_used_idx.wrapping_sub(queue.last_used_idx);
+        (0..pending.min(queue.size)).find_map(|i| {
+            let index = usize::from(queue.last_used_idx.wrapping_add(i) & (queue.size - 1));
+            // Safe because self.used points to a valid, aligned, initialised, dereferenceable,
+            // readable instance of UsedRing.
+            let elem = unsafe { &(*queue.used.as_ptr()).ring[index] };
+            // Read the fields volatilely, as the device may be writing them.
+            // Safe because the pointers come from a valid reference.
+            let (id, len) = unsafe {
+                (
+                    (&elem.id as *const u32).read_volatile(),
+                    (&elem.len as *const u32).read_volatile(),
+                )
+            };
+            (id == u32::from(head)).then_some(len)
+        })
+    }
+}
+
+impl<H: Hal, const SIZE: usize> Display for QueueDump<'_, H, SIZE> {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        let queue = self.queue;
+        // Safe because self.avail and self.used point to valid, aligned, initialised,
+        // dereferenceable, readable rings, and the event fields come after `size` entries.
+        let (avail_flags, used_flags, device_used_idx, used_event, avail_event) = unsafe {
+            (
+                (*queue.avail.as_ptr()).flags.load(Ordering::Acquire),
+                (*queue.used.as_ptr()).flags.load(Ordering::Acquire),
+                (*queue.used.as_ptr()).idx.load(Ordering::Acquire),
+                (*queue.used_event()).load(Ordering::Acquire),
+                (*queue.avail_event()).load(Ordering::Acquire),
+            )
+        };
+        writeln!(
+            f,
+            "virtqueue {}: size {}, {} descriptors in use, {} reserved, free head {}",
+            queue.queue_idx, queue.size, queue.num_used, queue.num_reserved, queue.free_head
+        )?;
+        write!(
+            f,
+            "  avail: idx {}, flags {:#x}",
+            queue.avail_idx, avail_flags
+        )?;
+        if queue.event_idx {
+            write!(f, ", used_event {}", used_event)?;
+        }
+        write!(
+            f,
+            "\n  used: idx {}, popped up to {}, flags {:#x}",
+            device_used_idx, queue.last_used_idx, used_flags
+        )?;
+        if queue.event_idx {
+            write!(f, ", avail_event {}", avail_event)?;
+        }
+        writeln!(f)?;
+
+        // A chain's head is a descriptor in use which no other descriptor in use points to.
+        let in_use = self.in_use();
+        let mut is_head = in_use;
+        for (index, desc) in queue.desc_shadow.iter().enumerate() {
+            if in_use[index] {
+                if let Some(next) = desc.next() {
+                    if let Some(head) = is_head.get_mut(usize::from(next)) {
+                        *head = false;
+                    }
+                }
+            }
+        }
+        for head in (0..queue.size).filter(|&head| is_head[usize::from(head)]) {
+            match self.used_len(head, device_used_idx) {
+                Some(len) => writeln!(f, "  chain {}: used by device, len {}", head, len)?,
+                None => writeln!(f, "  chain {}: available to device", head)?,
+            }
+            let mut next = Some(head);
+            // Stop at the queue size in case the chain is somehow circular.
+            for _ in 0..queue.size {
+                let Some(index) = next else {
+                    break;
+                };
+                let desc = &queue.desc_shadow[usize::from(index)];
+                write_desc(f, "    desc", index, desc)?;
+                #[cfg(feature = "alloc")]
+                if let Some(list) = queue.indirect_lists[usize::from(index)] {
+                    // Safe because we allocated the indirect list in `add_indirect`, and only we
+                    // write to it.
+                    for (i, indirect) in unsafe { list.as_ref() }.iter().enumerate() {
+                        write_desc(f, "      indirect", i as u16, indirect)?;
+                    }
+                }
+                next = desc.next();
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Writes a line describing the given descriptor.
+fn write_desc(f: &mut Formatter, prefix: &str, index: u16, desc: &Descriptor) -> fmt::Result {
+    write!(
+        f,
+        "{} {}: addr {:#x}, len {}, flags {}",
+        prefix, index, desc.addr, desc.len, desc.flags
+    )?;
+    if desc.flags.contains(DescFlags::NEXT) {
+        write!(f, ", next {}", desc.next)?;
+    }
+    writeln!(f)
+}
@@ -0,0 +1,460 @@
+//! Strategies for laying out the memory of a virtqueue's rings.
+//!
+//! The queue implementation only deals in the addresses of the three areas of a queue (descriptor
+//! area, driver area and device area), so all knowledge of how they are allocated and aligned for
+//! different ring formats and transports is contained here.
+
+use super::{Descriptor, QueuePlacement, UsedElem};
+use crate::hal::{BufferDirection, Dma, Hal, MemoryLocality, PhysAddr};
+use crate::transport::Transport;
+use crate::{align_up, pages, Result, PAGE_SIZE};
+use core::mem::size_of;
+use core::ptr::NonNull;
+
+/// A strategy for allocating and laying out the memory of a virtqueue.
+pub(crate) trait RingLayout<H: Hal>: Sized {
+    /// Allocates the memory for a queue of the given size.
+    fn allocate(queue_size: u16, locality: MemoryLocality) -> Result<Self>;
+
+    /// Returns the NUMA nodes on which the DMA regions of the queue were allocated.
+    fn placement(&self) -> QueuePlacement;
+
+    /// Returns the physical address of the descriptor area.
+    fn descriptors_paddr(&self) -> PhysAddr;
+
+    /// Returns a pointer to the descriptor area.
+    fn descriptors_vaddr(&self) -> NonNull<u8>;
+
+    /// Returns the physical address of the driver area.
+    fn driver_area_paddr(&self) -> PhysAddr;
+
+    /// Returns a pointer to the driver area.
+    fn driver_area_vaddr(&self) -> NonNull<u8>;
+
+    /// Returns the physical address of the device area.
+    fn device_area_paddr(&self) -> PhysAddr;
+
+    /// Returns a pointer to the device area.
+    fn device_area_vaddr(&self) -> NonNull<u8>;
+}
+
+/// A split virtqueue in a single DMA region, as required by legacy interfaces.
+///
+/// Ref: 2.6.2 Legacy Interfaces: A Note on Virtqueue Layout
+#[derive(Debug)]
+pub(crate) struct SplitLegacy<H: Hal> {
+    dma: Dma<H>,
+    avail_offset: usize,
+    used_offset: usize,
+}
+
+impl<H: Hal> RingLayout<H> for SplitLegacy<H> {
+    fn allocate(queue_size: u16, locality: MemoryLocality) -> Result<Self> {
+        let (desc, avail, used) = split_part_sizes(queue_size);
+        let size = align_up(desc + avail) + align_up(used);
+        // Allocate contiguous pages.
+        let dma = Dma::new_with_locality(size / PAGE_SIZE, BufferDirection::Both, locality)?;
+        Ok(Self {
+            dma,
+            avail_offset: desc,
+            used_offset: align_up(desc + avail),
+        })
+    }
+
+    fn placement(&self) -> QueuePlacement {
+        let node = self.dma.numa_node();
+        QueuePlacement {
+            descriptors: node,
+            driver_area: node,
+            device_area: node,
+        }
+    }
+
+    fn descriptors_paddr(&self) -> PhysAddr {
+        self.dma.paddr()
+    }
+
+    fn descriptors_vaddr(&self) -> NonNull<u8> {
+        self.dma.vaddr(0)
+    }
+
+    fn driver_area_paddr(&self) -> PhysAddr {
+        self.dma.paddr() + self.avail_offset
+    }
+
+    fn driver_area_vaddr(&self) -> NonNull<u8> {
+        self.dma.vaddr(self.avail_offset)
+    }
+
+    fn device_area_paddr(&self) -> PhysAddr {
+        self.dma.paddr() + self.used_offset
+    }
+
+    fn device_area_vaddr(&self) -> NonNull<u8> {
+        self.dma.vaddr(self.used_offset)
+    }
+}
+
+/// A split virtqueue with separate DMA regions for the parts written by the driver and by the
+/// device, as supported by non-legacy interfaces.
+///
+/// This is preferred over [`SplitLegacy`] where possible as it reduces memory fragmentation and
+/// allows the HAL to know which DMA regions are used in which direction.
+#[derive(Debug)]
+pub(crate) struct SplitModern<H: Hal> {
+    /// The region used for the descriptor area and driver area.
+    driver_to_device_dma: Dma<H>,
+    /// The region used for the device area.
+    device_to_driver_dma: Dma<H>,
+    /// The offset from the start of the `driver_to_device_dma` region to the driver area
+    /// (available ring).
+    avail_offset: usize,
+}
+
+impl<H: Hal> RingLayout<H> for SplitModern<H> {
+    fn allocate(queue_size: u16, locality: MemoryLocality) -> Result<Self> {
+        let (desc, avail, used) = split_part_sizes(queue_size);
+        let driver_to_device_dma = Dma::new_with_locality(
+            pages(desc + avail),
+            BufferDirection::DriverToDevice,
+            locality,
+        )?;
+        let device_to_driver_dma =
+            Dma::new_with_locality(pages(used), BufferDirection::DeviceToDriver, locality)?;
+        Ok(Self {
+            driver_to_device_dma,
+            device_to_driver_dma,
+            avail_offset: desc,
+        })
+    }
+
+    fn placement(&self) -> QueuePlacement {
+        QueuePlacement {
+            descriptors: self.driver_to_device_dma.numa_node(),
+            driver_area: self.driver_to_device_dma.numa_node(),
+            device_area: self.device_to_driver_dma.numa_node(),
+        }
+    }
+
+    fn descriptors_paddr(&self) -> PhysAddr {
+        self.driver_to_device_dma.paddr()
+    }
+
+    fn descriptors_vaddr(&self) -> NonNull<u8> {
+        self.driver_to_device_dma.vaddr(0)
+    }
+
+    fn driver_area_paddr(&self) -> PhysAddr {
+        self.driver_to_device_dma.paddr() + self.avail_offset
+    }
+
+    fn driver_area_vaddr(&self) -> NonNull<u8> {
+        self.driver_to_device_dma.vaddr(self.avail_offset)
+    }
+
+    fn device_area_paddr(&self) -> PhysAddr {
+        self.device_to_driver_dma.paddr()
+    }
+
+    fn device_area_vaddr(&self) -> NonNull<u8> {
+        self.device_to_driver_dma.vaddr(0)
+    }
+}
+
+/// A packed virtqueue, with the descriptor ring (written by both sides) in one DMA region and the
+/// driver and device event suppression structures in another.
+///
+/// Ref: 2.7 Packed Virtqueues
+#[derive(Debug)]
+pub(crate) struct Packed<H: Hal> {
+    /// The region used for the descriptor ring.
+    ring_dma: Dma<H>,
+    /// The region used for the driver and device event suppression structures.
+    event_dma: Dma<H>,
+}
+
+/// The size of a packed virtqueue event suppression structure.
+const PACKED_EVENT_SUPPRESSION_SIZE: usize = 4;
+
+impl<H: Hal> RingLayout<H> for Packed<H> {
+    fn allocate(queue_size: u16, locality: MemoryLocality) -> Result<Self> {
+        let ring_dma = Dma::new_with_locality(
+            pages(size_of::<Descriptor>() * usize::from(queue_size)),
+            BufferDirection::Both,
+            locality,
+        )?;
+        let event_dma = Dma::new_with_locality(
+            pages(2 * PACKED_EVENT_SUPPRESSION_SIZE),
+            BufferDirection::Both,
+            locality,
+        )?;
+        Ok(Self {
+            ring_dma,
+            event_dma,
+        })
+    }
+
+    fn placement(&self) -> QueuePlacement {
+        QueuePlacement {
+            descriptors: self.ring_dma.numa_node(),
+            driver_area: self.event_dma.numa_node(),
+            device_area: self.event_dma.numa_node(),
+        }
+    }
+
+    fn descriptors_paddr(&self) -> PhysAddr {
+        self.ring_dma.paddr()
+    }
+
+    fn descriptors_vaddr(&self) -> NonNull<u8> {
+        self.ring_dma.vaddr(0)
+    }
+
+    fn driver_area_paddr(&self) -> PhysAddr {
+        self.event_dma.paddr()
+    }
+
+    fn driver_area_vaddr(&self) -> NonNull<u8> {
+        self.event_dma.vaddr(0)
+    }
+
+    fn device_area_paddr(&self) -> PhysAddr {
+        self.event_dma.paddr() + PACKED_EVENT_SUPPRESSION_SIZE
+    }
+
+    fn device_area_vaddr(&self) -> NonNull<u8> {
+        self.event_dma.vaddr(PACKED_EVENT_SUPPRESSION_SIZE)
+    }
+}
+
+/// The format of a virtqueue's rings, which decides its [`RingLayout`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub(crate) enum RingFormat {
+    /// Split rings, in the single region required by legacy interfaces.
+    SplitLegacy,
+    /// Split rings, in separate regions for each direction.
+    SplitModern,
+    /// A packed ring, if `VIRTIO_F_RING_PACKED` was negotiated.
+    Packed,
+}
+
+impl RingFormat {
+    /// Chooses the ring format for a queue from whether the packed ring format was negotiated and
+    /// what the transport requires.
+    pub fn choose<T: Transport + ?Sized>(transport: &T, packed: bool) -> Self {
+        if packed {
+            Self::Packed
+        } else if transport.requires_legacy_layout() {
+            Self::SplitLegacy
+        } else {
+            Self::SplitModern
+        }
+    }
+}
+
+/// A layout chosen at runtime according to a [`RingFormat`].
+#[derive(Debug)]
+pub(crate) enum AnyLayout<H: Hal> {
+    SplitLegacy(SplitLegacy<H>),
+    SplitModern(SplitModern<H>),
+    Packed(Packed<H>),
+}
+
+/// Calls the given method on whichever layout is in use.
+macro_rules! dispatch {
+    ($self:ident.$method:ident()) => {
+        match $self {
+            Self::SplitLegacy(layout) => layout.$method(),
+            Self::SplitModern(layout) => layout.$method(),
+            Self::Packed(layout) => layout.$method(),
+        }
+    };
+}
+
+impl<H: Hal> AnyLayout<H> {
+    /// Allocates the memory for a queue of the given size and format.
+    pub fn allocate_format(
+        format: RingFormat,
+        queue_size: u16,
+        locality: MemoryLocality,
+    ) -> Result<Self> {
+        Ok(match format {
+            RingFormat::SplitLegacy => {
+                Self::SplitLegacy(SplitLegacy::allocate(queue_size, locality)?)
+            }
+            RingFormat::SplitModern => {
+                Self::SplitModern(SplitModern::allocate(queue_size, locality)?)
+            }
+            RingFormat::Packed => Self::Packed(Packed::allocate(queue_size, locality)?),
+        })
+    }
+
+    /// Takes back ownership of the memory of a queue of the given size and format which was leaked
+    /// after its state was saved, given the physical and virtual addresses of its descriptor area,
+    /// driver area and device area.
+    ///
+    /// # Safety
+    ///
+    /// The addresses must be those of a queue previously allocated by [`Self::allocate_format`] with
+    /// the same size and format, whose memory has not been freed and is not owned by anything else.
+    pub unsafe fn from_saved(
+        format: RingFormat,
+        queue_size: u16,
+        descriptors: (PhysAddr, NonNull<u8>),
+        driver_area: (PhysAddr, NonNull<u8>),
+        device_area: (PhysAddr, NonNull<u8>),
+    ) -> Self {
+        let (desc, avail, used) = split_part_sizes(queue_size);
+        // SAFETY: Our caller promises that each region was allocated with these addresses, and the
+        // sizes are calculated in the same way as in `allocate`.
+        unsafe {
+            match format {
+                RingFormat::SplitLegacy => Self::SplitLegacy(SplitLegacy {
+                    dma: Dma::from_raw_parts(
+                        descriptors.0,
+                        descriptors.1,
+                        (align_up(desc + avail) + align_up(used)) / PAGE_SIZE,
+                    ),
+                    avail_offset: desc,
+                    used_offset: align_up(desc + avail),
+                }),
+                RingFormat::SplitModern => Self::SplitModern(SplitModern {
+                    driver_to_device_dma: Dma::from_raw_parts(
+                        descriptors.0,
+                        descriptors.1,
+                        pages(desc + avail),
+                    ),
+                    device_to_driver_dma: Dma::from_raw_parts(
+                        device_area.0,
+                        device_area.1,
+                        pages(used),
+                    ),
+                    avail_offset: desc,
+                }),
+                RingFormat::Packed => Self::Packed(Packed {
+                    ring_dma: Dma::from_raw_parts(
+                        descriptors.0,
+                        descriptors.1,
+                        pages(size_of::<Descriptor>() * usize::from(queue_size)),
+                    ),
+                    event_dma: Dma::from_raw_parts(
+                        driver_area.0,
+                        driver_area.1,
+                        pages(2 * PACKED_EVENT_SUPPRESSION_SIZE),
+                    ),
+                }),
+            }
+        }
+    }
+
+    /// Returns the format of the rings.
+    pub fn format(&self) -> RingFormat {
+        match self {
+            Self::SplitLegacy(_) => RingFormat::SplitLegacy,
+            Self::SplitModern(_) => RingFormat::SplitModern,
+            Self::Packed(_) => RingFormat::Packed,
+        }
+    }
+
+    pub fn placement(&self) -> QueuePlacement {
+        dispatch!(self.placement())
+    }
+
+    pub fn descriptors_paddr(&self) -> PhysAddr {
+        dispatch!(self.descriptors_paddr())
+    }
+
+    pub fn descriptors_vaddr(&self) -> NonNull<u8> {
+        dispatch!(self.descriptors_vaddr())
+    }
+
+    pub fn driver_area_paddr(&self) -> PhysAddr {
+        dispatch!(self.driver_area_paddr())
+    }
+
+    pub fn driver_area_vaddr(&self) -> NonNull<u8> {
+        dispatch!(self.driver_area_vaddr())
+    }
+
+    pub fn device_area_paddr(&self) -> PhysAddr {
+        dispatch!(self.device_area_paddr())
+    }
+
+    pub fn device_area_vaddr(&self) -> NonNull<u8> {
+        dispatch!(self.device_area_vaddr())
+    }
+}
+
+/// Returns the size in bytes of the descriptor table, available ring and used ring of a split
+/// virtqueue of the given size.
+///
+/// Ref: 2.6 Split Virtqueues
+pub(crate) fn split_part_sizes(queue_size: u16) -> (usize, usize, usize) {
+    debug_assert!(
+        queue_size.is_power_of_two(),
+        "queue size should be a power of 2"
+    );
+    let queue_size = queue_size as usize;
+    let desc = size_of::<Descriptor>() * queue_size;
+    let avail = size_of::<u16>() * (3 + queue_size);
+    let used = size_of::<u16>() * 3 + size_of::<UsedElem>() * queue_size;
+    (desc, avail, used)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::mmio::{MmioTransport, VirtIOHeader, LEGACY_VERSION, MODERN_VERSION},
+    };
+
+    #[test]
+    fn choose_format() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4);
+        let transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(
+            RingFormat::choose(&transport, false),
+            RingFormat::SplitLegacy
+        );
+        assert_eq!(RingFormat::choose(&transport, true), RingFormat::Packed);
+
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(
+            RingFormat::choose(&transport, false),
+            RingFormat::SplitModern
+        );
+    }
+
+    #[test]
+    fn split_legacy_alignment() {
+        let layout =
+            AnyLayout::<FakeHal>::allocate_format(RingFormat::SplitLegacy, 4, MemoryLocality::Any)
+                .unwrap();
+        assert_eq!(layout.format(), RingFormat::SplitLegacy);
+        let base = layout.descriptors_paddr();
+        // The available ring follows the descriptor table directly, but the used ring must be on
+        // the next page.
+        assert_eq!(
+            layout.driver_area_paddr(),
+            base + 4 * size_of::<Descriptor>()
+        );
+        assert_eq!(layout.device_area_paddr(), base + PAGE_SIZE);
+    }
+
+    #[test]
+    fn packed_event_suppression() {
+        let layout =
+            AnyLayout::<FakeHal>::allocate_format(RingFormat::Packed, 4, MemoryLocality::Any)
+                .unwrap();
+        assert_eq!(
+            layout.device_area_paddr(),
+            layout.driver_area_paddr() + PACKED_EVENT_SUPPRESSION_SIZE
+        );
+    }
+}
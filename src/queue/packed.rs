@@ -0,0 +1,705 @@
+//! Packed virtqueues, for devices which negotiated `VIRTIO_F_RING_PACKED`.
+//!
+//! Ref: 2.8 Packed Virtqueues
+
+use super::layout::{AnyLayout, RingFormat};
+use super::{check_buffers, check_descriptors, DescFlags, Descriptor, InputOutputIter};
+use crate::hal::{self, Hal, MemoryLocality};
+use crate::poison;
+use crate::transport::{QueueNotifyAddress, Transport};
+use crate::{nonnull_slice_from_raw_parts, Error, Result};
+use core::hint::spin_loop;
+use core::mem::take;
+use core::ptr::{self, addr_of, addr_of_mut, NonNull};
+use core::sync::atomic::{fence, AtomicU16, Ordering};
+use zerocopy::{AsBytes, FromBytes, FromZeroes};
+
+/// The flag set in a packed descriptor's `flags` when it is made available, if the driver's wrap
+/// counter is set.
+const AVAIL: u16 = 1 << 7;
+/// The flag set in a packed descriptor's `flags` when it is made available, if the driver's wrap
+/// counter is clear, or when it is used, if the device's wrap counter is set.
+const USED: u16 = 1 << 15;
+
+/// Values of the `flags` field of an event suppression structure.
+const EVENT_FLAGS_ENABLE: u16 = 0;
+const EVENT_FLAGS_DISABLE: u16 = 1;
+const EVENT_FLAGS_DESC: u16 = 2;
+
+/// A virtqueue in the packed format.
+///
+/// This supports the same basic operations as a split `VirtQueue`, and tokens are used in the same
+/// way: they are the IDs of the buffers, which are in `0..SIZE`, and a chain which is popped frees
+/// its ID to be returned by the next `add`. Indirect descriptors, reservations and in-flight limits
+/// aren't supported.
+///
+/// * `SIZE`: The size of the queue. This is both the number of descriptors in the ring, and the
+///   number of buffer IDs.
+#[derive(Debug)]
+pub struct PackedQueue<H: Hal, const SIZE: usize> {
+    /// DMA guard
+    layout: AnyLayout<H>,
+    /// Descriptor ring
+    ///
+    /// The device writes used descriptors over the ones we made available, so the only values we
+    /// read back from it are the flags, buffer ID and length of used descriptors.
+    ring: NonNull<[PackedDescriptor]>,
+    /// Driver event suppression structure, which we write to tell the device when to notify us.
+    driver_event: NonNull<EventSuppression>,
+    /// Device event suppression structure, which the device writes to tell us when to notify it.
+    device_event: NonNull<EventSuppression>,
+
+    /// The index of queue
+    queue_idx: u16,
+    /// The number of descriptors in the ring currently in use.
+    num_used: u16,
+    /// The head of the free list of buffer IDs.
+    free_head: u16,
+    /// The buffers of each chain in flight, linked from the entry for the chain's buffer ID. Free
+    /// entries are linked into the free list, and the device can't access any of them.
+    buffers: [Descriptor; SIZE],
+    /// The number of descriptors in the ring used by the chain with each buffer ID.
+    chain_len: [u16; SIZE],
+    /// The position in the ring at which the next descriptor will be made available.
+    next_avail: u16,
+    /// The driver's wrap counter, which is flipped each time `next_avail` wraps around.
+    avail_wrap: bool,
+    /// The number of descriptors made available since `should_notify` was last called.
+    unchecked_avail: usize,
+    /// The position in the ring of the next used descriptor to pop.
+    last_used: u16,
+    /// The wrap counter for `last_used`.
+    used_wrap: bool,
+    /// Whether the `VIRTIO_F_EVENT_IDX` feature has been negotiated.
+    event_idx: bool,
+    /// The queue's doorbell, if the transport has one, looked up once when the queue is set up.
+    doorbell: Option<QueueNotifyAddress>,
+}
+
+impl<H: Hal, const SIZE: usize> PackedQueue<H, SIZE> {
+    /// Creates a new packed virtqueue.
+    ///
+    /// * `event_idx`: Whether to use descriptor event suppression. This should be set if the
+    ///   `VIRTIO_F_EVENT_IDX` feature has been negotiated with the device.
+    pub fn new<T: Transport>(transport: &mut T, idx: u16, event_idx: bool) -> Result<Self> {
+        if transport.queue_used(idx) {
+            return Err(Error::AlreadyUsed);
+        }
+        // Legacy interfaces can't describe the separate areas of a packed queue.
+        if transport.requires_legacy_layout() {
+            return Err(Error::Unsupported);
+        }
+        // Positions in the ring must fit in the 15 bits of an event offset.
+        if SIZE == 0 || SIZE > 1 << 15 || transport.max_queue_size(idx) < SIZE as u32 {
+            return Err(Error::InvalidParam);
+        }
+        let size = SIZE as u16;
+
+        let layout = AnyLayout::allocate_format(RingFormat::Packed, size, MemoryLocality::Any)?;
+        transport.queue_set(
+            idx,
+            size.into(),
+            layout.descriptors_paddr(),
+            layout.driver_area_paddr(),
+            layout.device_area_paddr(),
+        )?;
+        let doorbell = transport.queue_notify_address(idx);
+
+        let ring = nonnull_slice_from_raw_parts(
+            layout.descriptors_vaddr().cast::<PackedDescriptor>(),
+            SIZE,
+        );
+        let driver_event = layout.driver_area_vaddr().cast();
+        let device_event = layout.device_area_vaddr().cast();
+
+        let mut buffers: [Descriptor; SIZE] = FromZeroes::new_zeroed();
+        for (i, buffer) in buffers.iter_mut().enumerate() {
+            buffer.next = i as u16 + 1;
+        }
+
+        Ok(PackedQueue {
+            layout,
+            ring,
+            driver_event,
+            device_event,
+            queue_idx: idx,
+            num_used: 0,
+            free_head: 0,
+            buffers,
+            chain_len: [0; SIZE],
+            next_avail: 0,
+            // Both wrap counters start at 1.
+            avail_wrap: true,
+            unchecked_avail: 0,
+            last_used: 0,
+            used_wrap: true,
+            event_idx,
+            doorbell,
+        })
+    }
+
+    /// Adds buffers to the virtqueue, returning a token.
+    ///
+    /// The buffers must not be empty.
+    ///
+    /// # Safety
+    ///
+    /// The input and output buffers must remain valid and not be accessed until a call to
+    /// `pop_used` with the returned token succeeds.
+    pub unsafe fn add<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u16> {
+        check_buffers(inputs, outputs)?;
+        let descriptors_needed = inputs.len() + outputs.len();
+        if usize::from(self.num_used) + descriptors_needed > SIZE {
+            return Err(Error::QueueFull);
+        }
+        poison::fill(outputs);
+
+        // Take entries from the free list for the buffers. The first is the buffer ID.
+        let id = self.free_head;
+        let mut last = self.free_head;
+        for (buffer, direction) in InputOutputIter::new(inputs, outputs) {
+            let entry = &mut self.buffers[usize::from(self.free_head)];
+            // Safe because our caller promises that the buffers live at least until `pop_used`
+            // returns them.
+            unsafe {
+                entry.set_buf::<H>(buffer, direction, DescFlags::NEXT);
+            }
+            last = self.free_head;
+            self.free_head = entry.next;
+        }
+        self.buffers[usize::from(last)]
+            .flags
+            .remove(DescFlags::NEXT);
+
+        // Write the descriptors to the ring, leaving the flags of the first until last so that
+        // the device doesn't see the chain until it is complete.
+        let head = self.next_avail;
+        let head_flags = self.buffers[usize::from(id)].flags.bits() | self.avail_flags();
+        let mut entry = id;
+        for i in 0..descriptors_needed {
+            let Descriptor {
+                addr,
+                len,
+                flags,
+                next,
+            } = self.buffers[usize::from(entry)].clone();
+            self.write_desc(addr, len, id);
+            if i != 0 {
+                self.write_flags(self.next_avail, flags.bits() | self.avail_flags());
+            }
+            entry = next;
+            self.advance_avail();
+        }
+        self.chain_len[usize::from(id)] = descriptors_needed as u16;
+        // Write barrier so that the device sees the rest of the chain before the head is made
+        // available.
+        fence(Ordering::SeqCst);
+        self.write_flags(head, head_flags);
+
+        self.num_used += descriptors_needed as u16;
+        Ok(id)
+    }
+
+    /// Adds the given buffers to the virtqueue, notifies the device, blocks until the device uses
+    /// them, then pops them.
+    ///
+    /// This assumes that the device isn't processing any other buffers at the same time.
+    ///
+    /// The buffers must not be empty.
+    pub fn add_notify_wait_pop<'a, 'b>(
+        &mut self,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+        transport: &mut impl Transport,
+    ) -> Result<u32> {
+        // Safe because we don't return until the same token has been popped, so the buffers remain
+        // valid and are not otherwise accessed until then.
+        let token = unsafe { self.add(inputs, outputs) }?;
+
+        if self.should_notify() {
+            self.notify(transport);
+        }
+
+        while !self.can_pop() {
+            spin_loop();
+        }
+
+        // Safe because these are the same buffers as we passed to `add` above and they are still
+        // valid.
+        unsafe { self.pop_used(token, inputs, outputs) }
+    }
+
+    /// Advises the device whether used buffer notifications are needed.
+    ///
+    /// Ref: 2.8.10 Event Suppression Structure Format
+    pub fn set_dev_notify(&mut self, enable: bool) {
+        let flags = if enable {
+            EVENT_FLAGS_ENABLE
+        } else {
+            EVENT_FLAGS_DISABLE
+        };
+        // Safe because self.driver_event points to a valid, aligned, initialised, dereferenceable
+        // instance of EventSuppression.
+        unsafe {
+            (*self.driver_event.as_ptr())
+                .flags
+                .store(flags, Ordering::Release);
+        }
+    }
+
+    /// Returns whether the driver should notify the device after adding a new buffer to the
+    /// virtqueue.
+    ///
+    /// This will be false if the device has suppressed notifications. As for split queues, a single
+    /// notification covers every descriptor made available since the previous call.
+    pub fn should_notify(&mut self) -> bool {
+        let added = take(&mut self.unchecked_avail);
+        // Make sure the device sees the descriptors we added before we check whether it wants to
+        // be notified about them.
+        fence(Ordering::SeqCst);
+        // Safe because self.device_event points to a valid, aligned, initialised, dereferenceable,
+        // readable instance of EventSuppression.
+        let (flags, off_wrap) = unsafe {
+            let event = &*self.device_event.as_ptr();
+            (
+                event.flags.load(Ordering::Acquire),
+                event.off_wrap.load(Ordering::Acquire),
+            )
+        };
+        match flags & 0x3 {
+            EVENT_FLAGS_DISABLE => false,
+            EVENT_FLAGS_DESC if self.event_idx => {
+                // Notify if the descriptor the device asked about is among those made available
+                // since the last check, by comparing positions in a sequence which counts through
+                // the ring twice, once for each value of the wrap counter. If the whole ring was
+                // filled since then, the device must be told regardless.
+                if added >= SIZE {
+                    return true;
+                }
+                let event = ring_sequence::<SIZE>(off_wrap & 0x7fff, off_wrap & (1 << 15) != 0);
+                let old = (ring_sequence::<SIZE>(self.next_avail, self.avail_wrap) + 2 * SIZE
+                    - added)
+                    % (2 * SIZE);
+                (event + 2 * SIZE - old) % (2 * SIZE) < added
+            }
+            _ => true,
+        }
+    }
+
+    /// Notifies the device about descriptors made available, through the HAL's
+    /// [`notify_hook`](Hal::notify_hook).
+    pub fn notify(&self, transport: &mut impl Transport) {
+        hal::notify_queue::<H>(transport, self.queue_idx, self.doorbell);
+    }
+
+    /// Checks that the device wrote at least `read` bytes to a chain which it used, given the
+    /// `written` length from [`pop_used`](Self::pop_used), before the driver reads that many.
+    pub fn check_written(&self, written: u32, read: usize) -> bool {
+        poison::check_written(self.queue_idx, written, read)
+    }
+
+    /// Returns whether there is a used descriptor that can be popped.
+    pub fn can_pop(&self) -> bool {
+        self.is_used(self.last_used, self.used_wrap)
+    }
+
+    /// Returns the number of used descriptor chains which haven't been popped yet.
+    pub fn pending_used(&self) -> usize {
+        let mut position = self.last_used;
+        let mut wrap = self.used_wrap;
+        let mut pending = 0;
+        let mut descriptors = 0;
+        while descriptors < usize::from(self.num_used) && self.is_used(position, wrap) {
+            let Some(&len) = self.chain_len.get(usize::from(self.read_id(position))) else {
+                break;
+            };
+            if len == 0 {
+                break;
+            }
+            pending += 1;
+            descriptors += usize::from(len);
+            (position, wrap) = advance::<SIZE>(position, wrap, len);
+        }
+        pending
+    }
+
+    /// Returns the buffer ID (a.k.a. token) of the next used descriptor without popping it, or
+    /// `None` if there isn't one.
+    pub fn peek_used(&self) -> Option<u16> {
+        self.can_pop().then(|| self.read_id(self.last_used))
+    }
+
+    /// Returns the number of free descriptors.
+    pub fn available_desc(&self) -> usize {
+        SIZE - usize::from(self.num_used)
+    }
+
+    /// If the given token is the next used descriptor, pops it and returns the total buffer length
+    /// which was used (written) by the device.
+    ///
+    /// # Safety
+    ///
+    /// The buffers in `inputs` and `outputs` must match the set of buffers originally added to the
+    /// queue by `add` when it returned the token being passed in here.
+    pub unsafe fn pop_used<'a, 'b>(
+        &mut self,
+        token: u16,
+        inputs: &'a [&'b [u8]],
+        outputs: &'a mut [&'b mut [u8]],
+    ) -> Result<u32> {
+        if !self.can_pop() {
+            return Err(Error::NotReady);
+        }
+        let id = self.read_id(self.last_used);
+        // Safe because self.ring is properly aligned, dereferenceable and initialised, and the
+        // device has finished writing this descriptor.
+        let len = unsafe {
+            ptr::read_volatile(addr_of!(
+                (*self.ring.as_ptr())[usize::from(self.last_used)].len
+            ))
+        };
+        if id != token {
+            // The device used a different descriptor chain to the one we were expecting.
+            return Err(Error::WrongToken);
+        }
+        let chain_len = self.chain_len[usize::from(id)];
+        if usize::from(chain_len) != inputs.len() + outputs.len() {
+            return Err(Error::InvalidParam);
+        }
+        let mut next = Some(id);
+        let chain = core::iter::from_fn(|| {
+            let desc = &self.buffers[usize::from(next?)];
+            next = desc.next();
+            Some(desc)
+        });
+        check_descriptors(chain, inputs, outputs)?;
+
+        // Unshare the buffers and move their entries to the free list.
+        let mut entry = id;
+        for (buffer, direction) in InputOutputIter::new(inputs, &mut *outputs) {
+            let desc = &mut self.buffers[usize::from(entry)];
+            let paddr = desc.addr;
+            desc.unset_buf();
+            // SAFETY: The caller ensures that the buffer is valid and matches the descriptor from
+            // which we got `paddr`.
+            unsafe {
+                H::unshare(paddr as usize, buffer, direction);
+            }
+            match desc.next() {
+                Some(next) => entry = next,
+                None => desc.next = self.free_head,
+            }
+        }
+        self.free_head = id;
+        self.chain_len[usize::from(id)] = 0;
+        self.num_used -= chain_len;
+        poison::fill_unwritten(outputs, len);
+
+        (self.last_used, self.used_wrap) =
+            advance::<SIZE>(self.last_used, self.used_wrap, chain_len);
+        Ok(len)
+    }
+
+    /// Returns the flags marking a descriptor at `next_avail` as available.
+    fn avail_flags(&self) -> u16 {
+        if self.avail_wrap {
+            AVAIL
+        } else {
+            USED
+        }
+    }
+
+    /// Moves `next_avail` on by one, flipping the wrap counter if it wraps around.
+    fn advance_avail(&mut self) {
+        (self.next_avail, self.avail_wrap) = advance::<SIZE>(self.next_avail, self.avail_wrap, 1);
+        self.unchecked_avail = self.unchecked_avail.saturating_add(1);
+    }
+
+    /// Writes the address, length and buffer ID of the descriptor at `next_avail`.
+    fn write_desc(&mut self, addr: u64, len: u32, id: u16) {
+        let position = usize::from(self.next_avail);
+        // Safe because self.ring is properly aligned, dereferenceable and initialised, and the
+        // device doesn't read the descriptor until its flags mark it as available.
+        unsafe {
+            let slot = addr_of_mut!((*self.ring.as_ptr())[position]);
+            ptr::write_volatile(addr_of_mut!((*slot).addr), addr);
+            ptr::write_volatile(addr_of_mut!((*slot).len), len);
+            ptr::write_volatile(addr_of_mut!((*slot).id), id);
+        }
+    }
+
+    /// Writes the flags of the descriptor at the given position in the ring.
+    fn write_flags(&mut self, position: u16, flags: u16) {
+        // Safe because self.ring is properly aligned, dereferenceable and initialised.
+        unsafe {
+            ptr::write_volatile(
+                addr_of_mut!((*self.ring.as_ptr())[usize::from(position)].flags),
+                flags,
+            );
+        }
+    }
+
+    /// Returns whether the descriptor at the given position has been used by the device, given
+    /// the wrap counter for that position.
+    fn is_used(&self, position: u16, wrap: bool) -> bool {
+        // Safe because self.ring is properly aligned, dereferenceable and initialised.
+        let flags = unsafe {
+            ptr::read_volatile(addr_of!((*self.ring.as_ptr())[usize::from(position)].flags))
+        };
+        // Read barrier so that the rest of the used descriptor isn't read before its flags.
+        fence(Ordering::Acquire);
+        let avail = flags & AVAIL != 0;
+        let used = flags & USED != 0;
+        avail == used && used == wrap
+    }
+
+    /// Reads the buffer ID of the used descriptor at the given position.
+    fn read_id(&self, position: u16) -> u16 {
+        // Safe because self.ring is properly aligned, dereferenceable and initialised.
+        unsafe { ptr::read_volatile(addr_of!((*self.ring.as_ptr())[usize::from(position)].id)) }
+    }
+}
+
+/// Returns the position and wrap counter `count` descriptors after the given ones in a ring of
+/// `SIZE` descriptors.
+fn advance<const SIZE: usize>(position: u16, wrap: bool, count: u16) -> (u16, bool) {
+    let next = usize::from(position) + usize::from(count);
+    if next >= SIZE {
+        ((next - SIZE) as u16, !wrap)
+    } else {
+        (next as u16, wrap)
+    }
+}
+
+/// Returns the index of the given position in a sequence of `2 * SIZE` positions, in which the
+/// second half has the opposite wrap counter to the first.
+fn ring_sequence<const SIZE: usize>(position: u16, wrap: bool) -> usize {
+    usize::from(position) + if wrap { 0 } else { SIZE }
+}
+
+/// A descriptor in a packed virtqueue.
+///
+/// Ref: 2.8.13 Packed Virtqueue Descriptor Format
+#[repr(C, align(16))]
+#[derive(AsBytes, Clone, Debug, FromBytes, FromZeroes)]
+struct PackedDescriptor {
+    addr: u64,
+    len: u32,
+    id: u16,
+    flags: u16,
+}
+
+/// An event suppression structure, used to tell the other side when to send notifications.
+///
+/// Ref: 2.8.10 Event Suppression Structure Format
+#[repr(C)]
+#[derive(Debug)]
+struct EventSuppression {
+    /// The position and wrap counter of the descriptor to be notified about, if `flags` is
+    /// `EVENT_FLAGS_DESC`.
+    off_wrap: AtomicU16,
+    flags: AtomicU16,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        hal::fake::FakeHal,
+        transport::mmio::{MmioTransport, VirtIOHeader, LEGACY_VERSION, MODERN_VERSION},
+    };
+    use core::mem::size_of;
+    use std::vec::Vec;
+
+    /// The address, length and flags of a descriptor, as seen by the device.
+    type DeviceDescriptor = (u64, u32, u16);
+
+    /// The device's side of a packed queue.
+    struct FakeDevice {
+        /// The position and wrap counter of the next descriptor to read.
+        avail: (u16, bool),
+        /// The position and wrap counter at which to write the next used descriptor.
+        used: (u16, bool),
+    }
+
+    impl FakeDevice {
+        fn new() -> Self {
+            Self {
+                avail: (0, true),
+                used: (0, true),
+            }
+        }
+
+        /// Reads the next available chain, returning its buffer ID and the address, length and
+        /// flags of each of its descriptors.
+        fn take<const SIZE: usize>(
+            &mut self,
+            queue: &PackedQueue<FakeHal, SIZE>,
+        ) -> Option<(u16, Vec<DeviceDescriptor>)> {
+            let mut descriptors = Vec::new();
+            loop {
+                let (position, wrap) = self.avail;
+                let desc = unsafe { &(*queue.ring.as_ptr())[usize::from(position)] };
+                let avail = desc.flags & AVAIL != 0;
+                let used = desc.flags & USED != 0;
+                if avail != wrap || used == wrap {
+                    assert!(
+                        descriptors.is_empty(),
+                        "Chain ended before the last descriptor"
+                    );
+                    return None;
+                }
+                descriptors.push((desc.addr, desc.len, desc.flags & !(AVAIL | USED)));
+                self.avail = advance::<SIZE>(position, wrap, 1);
+                if desc.flags & DescFlags::NEXT.bits() == 0 {
+                    return Some((desc.id, descriptors));
+                }
+            }
+        }
+
+        /// Writes a used descriptor for the chain with the given buffer ID and number of
+        /// descriptors.
+        fn use_chain<const SIZE: usize>(
+            &mut self,
+            queue: &mut PackedQueue<FakeHal, SIZE>,
+            id: u16,
+            chain_len: u16,
+            written: u32,
+        ) {
+            let (position, wrap) = self.used;
+            let desc = unsafe { &mut (*queue.ring.as_ptr())[usize::from(position)] };
+            desc.id = id;
+            desc.len = written;
+            desc.flags = if wrap { AVAIL | USED } else { 0 };
+            self.used = advance::<SIZE>(position, wrap, chain_len);
+        }
+    }
+
+    fn transport(header: &mut VirtIOHeader) -> MmioTransport {
+        unsafe { MmioTransport::new(NonNull::from(header), size_of::<VirtIOHeader>()) }.unwrap()
+    }
+
+    #[test]
+    fn add_and_pop() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = transport(&mut header);
+        let mut queue = PackedQueue::<FakeHal, 4>::new(&mut transport, 0, false).unwrap();
+        let mut device = FakeDevice::new();
+        assert!(!queue.can_pop());
+        assert_eq!(device.take(&queue), None);
+
+        let request = [1, 2, 3];
+        let mut response = [0; 4];
+        let token = unsafe { queue.add(&[&request], &mut [&mut response]) }.unwrap();
+        assert_eq!(queue.available_desc(), 2);
+
+        let (id, descriptors) = device.take(&queue).unwrap();
+        assert_eq!(id, token);
+        let lengths_and_flags: Vec<_> = descriptors
+            .iter()
+            .map(|&(_, len, flags)| (len, flags))
+            .collect();
+        assert_eq!(
+            lengths_and_flags,
+            [(3, DescFlags::NEXT.bits()), (4, DescFlags::WRITE.bits())]
+        );
+        assert!(!queue.can_pop());
+
+        device.use_chain(&mut queue, id, 2, 4);
+        assert_eq!(queue.pending_used(), 1);
+        assert_eq!(queue.peek_used(), Some(token));
+        assert_eq!(
+            unsafe { queue.pop_used(token + 1, &[&request], &mut [&mut response]) },
+            Err(Error::WrongToken)
+        );
+        assert_eq!(
+            unsafe { queue.pop_used(token, &[&request], &mut [&mut response]) },
+            Ok(4)
+        );
+        assert_eq!(queue.available_desc(), 4);
+        assert!(!queue.can_pop());
+    }
+
+    #[test]
+    fn wrap_around_out_of_order() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = transport(&mut header);
+        let mut queue = PackedQueue::<FakeHal, 4>::new(&mut transport, 0, false).unwrap();
+        let mut device = FakeDevice::new();
+        let first = [1];
+        let mut second = [0; 2];
+
+        // Go around the ring several times, so the wrap counters flip in the middle of chains.
+        for _ in 0..5 {
+            let a = unsafe { queue.add(&[&first], &mut []) }.unwrap();
+            let b = unsafe { queue.add(&[&first], &mut [&mut second]) }.unwrap();
+            assert_eq!(queue.available_desc(), 1);
+            assert_eq!(
+                unsafe { queue.add(&[&first], &mut [&mut second]) },
+                Err(Error::QueueFull)
+            );
+
+            let (id_a, _) = device.take(&queue).unwrap();
+            let (id_b, descriptors) = device.take(&queue).unwrap();
+            assert_eq!((id_a, id_b), (a, b));
+            assert_eq!(descriptors.len(), 2);
+
+            // The device finishes with the second chain first.
+            device.use_chain(&mut queue, b, 2, 2);
+            device.use_chain(&mut queue, a, 1, 0);
+            assert_eq!(queue.pending_used(), 2);
+            assert_eq!(queue.peek_used(), Some(b));
+            assert_eq!(
+                unsafe { queue.pop_used(b, &[&first], &mut [&mut second]) },
+                Ok(2)
+            );
+            assert_eq!(queue.peek_used(), Some(a));
+            assert_eq!(unsafe { queue.pop_used(a, &[&first], &mut []) }, Ok(0));
+            assert_eq!(queue.available_desc(), 4);
+        }
+    }
+
+    #[test]
+    fn notification_suppression() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 1, 0, 0, 4);
+        let mut transport = transport(&mut header);
+        let mut queue = PackedQueue::<FakeHal, 4>::new(&mut transport, 0, true).unwrap();
+        let device_event = unsafe { &*queue.device_event.as_ptr() };
+        assert!(queue.should_notify());
+
+        device_event
+            .flags
+            .store(EVENT_FLAGS_DISABLE, Ordering::SeqCst);
+        assert!(!queue.should_notify());
+
+        // Ask to be notified once the descriptor at position 1 is available.
+        device_event.flags.store(EVENT_FLAGS_DESC, Ordering::SeqCst);
+        device_event.off_wrap.store(1 | 1 << 15, Ordering::SeqCst);
+        unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        assert!(!queue.should_notify());
+        unsafe { queue.add(&[&[1]], &mut []) }.unwrap();
+        assert!(queue.should_notify());
+
+        queue.set_dev_notify(false);
+        assert_eq!(
+            unsafe { &*queue.driver_event.as_ptr() }
+                .flags
+                .load(Ordering::SeqCst),
+            EVENT_FLAGS_DISABLE
+        );
+    }
+
+    #[test]
+    fn legacy_unsupported() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4);
+        let mut transport = transport(&mut header);
+        assert_eq!(
+            PackedQueue::<FakeHal, 4>::new(&mut transport, 0, false).unwrap_err(),
+            Error::Unsupported
+        );
+    }
+}
@@ -0,0 +1,257 @@
+//! Stable IDs for drivers and their queues, and a registry to resolve them.
+//!
+//! Every driver is assigned a [`DriverId`] when it is constructed, which is never reused for
+//! another driver while the program runs. There are `u32::MAX` IDs, after which constructing a
+//! driver panics rather than reusing one. Interrupt handlers, logs and diagnostics can refer to a
+//! driver or one of its queues by ID rather than by a raw pointer, and resolve the ID through a
+//! [`Registry`] owned by the embedder:
+//!
+//! ```
+//! use virtio_drivers::registry::{DriverId, QueueId, Registry};
+//!
+//! struct Device {
+//!     irq: u32,
+//! }
+//!
+//! // Usually a static protected by a lock, shared with the interrupt handlers.
+//! let mut registry = Registry::<Device, 4>::new();
+//! // Usually the ID of a driver, from its `id` method.
+//! let id = DriverId::allocate();
+//! registry.register(id, Device { irq: 33 }).unwrap();
+//!
+//! let queue = QueueId::new(id, 0);
+//! assert_eq!(registry.resolve_queue(queue).unwrap().irq, 33);
+//!
+//! // Once the driver is unregistered, e.g. because it was dropped, its ID no longer resolves.
+//! registry.unregister(id);
+//! assert!(registry.get(id).is_none());
+//! ```
+//!
+//! As IDs aren't reused, an ID which outlives its driver, e.g. one captured by an interrupt which
+//! arrives while the driver is being torn down, resolves to nothing rather than to some other
+//! driver.
+
+use crate::{Error, Result};
+use core::fmt::{self, Display, Formatter};
+use core::sync::atomic::{AtomicU32, Ordering};
+
+/// The next ID to assign to a driver.
+static NEXT_DRIVER_ID: AtomicU32 = AtomicU32::new(1);
+
+/// The ID of a driver, assigned when it is constructed and unique for the life of the program.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct DriverId(u32);
+
+impl DriverId {
+    /// Assigns a new ID, different from every ID assigned before.
+    ///
+    /// Drivers call this when they are constructed, but embedders may also use it for IDs of their
+    /// own, e.g. for a wrapper which owns several drivers.
+    ///
+    /// # Panics
+    ///
+    /// Panics if all `u32::MAX` IDs have already been assigned, rather than reusing one.
+    pub fn allocate() -> Self {
+        let value = NEXT_DRIVER_ID
+            .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |next| {
+                next.checked_add(1)
+            })
+            .expect("Ran out of driver IDs");
+        Self(value)
+    }
+
+    /// Returns the ID with the given numeric value, as previously returned by
+    /// [`value`](Self::value), e.g. after it was stored as an integer in an interrupt vector table.
+    ///
+    /// Returns `None` if no ID with the value has been assigned, so that a value which didn't come
+    /// from an ID can't be mistaken for one assigned later.
+    pub fn from_value(value: u32) -> Option<Self> {
+        if value != 0 && value < NEXT_DRIVER_ID.load(Ordering::Relaxed) {
+            Some(Self(value))
+        } else {
+            None
+        }
+    }
+
+    /// Returns the numeric value of the ID.
+    pub const fn value(self) -> u32 {
+        self.0
+    }
+}
+
+impl Display for DriverId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "driver {}", self.0)
+    }
+}
+
+/// The ID of one of a driver's virtqueues: the driver's ID and the index of the queue on the
+/// device.
+#[derive(Copy, Clone, Debug, Eq, Hash, Ord, PartialEq, PartialOrd)]
+pub struct QueueId {
+    /// The ID of the driver which owns the queue.
+    pub driver: DriverId,
+    /// The index of the queue on the device.
+    pub index: u16,
+}
+
+impl QueueId {
+    /// Returns the ID of the queue with the given index of the given driver.
+    pub fn new(driver: DriverId, index: u16) -> Self {
+        Self { driver, index }
+    }
+}
+
+impl Display for QueueId {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} queue {}", self.driver, self.index)
+    }
+}
+
+/// A fixed-capacity map from driver IDs to whatever the embedder needs to reach the drivers, such
+/// as a reference to a lock around each driver or an index into its own table.
+///
+/// The registry does no locking of its own, so that the embedder can protect it with whatever lock
+/// is safe to take from its interrupt handlers. It needs no allocator, and [`new`](Self::new) is a
+/// `const fn` so that it can initialise a static.
+pub struct Registry<T, const N: usize> {
+    entries: [Option<(DriverId, T)>; N],
+}
+
+impl<T, const N: usize> Registry<T, N> {
+    const EMPTY: Option<(DriverId, T)> = None;
+
+    /// Creates an empty registry.
+    pub const fn new() -> Self {
+        Self {
+            entries: [Self::EMPTY; N],
+        }
+    }
+
+    /// Registers the given value for the driver with the given ID.
+    ///
+    /// Returns [`Error::AlreadyUsed`] if the ID is already registered, or [`Error::RegistryFull`]
+    /// if the registry has no free entries.
+    pub fn register(&mut self, id: DriverId, value: T) -> Result {
+        if self.get(id).is_some() {
+            return Err(Error::AlreadyUsed);
+        }
+        let entry = self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.is_none())
+            .ok_or(Error::RegistryFull)?;
+        *entry = Some((id, value));
+        Ok(())
+    }
+
+    /// Removes the driver with the given ID from the registry, returning the value which was
+    /// registered for it, if any.
+    pub fn unregister(&mut self, id: DriverId) -> Option<T> {
+        self.entries
+            .iter_mut()
+            .find(|entry| matches!(entry, Some((entry_id, _)) if *entry_id == id))?
+            .take()
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the value registered for the driver with the given ID, if any.
+    pub fn get(&self, id: DriverId) -> Option<&T> {
+        self.iter()
+            .find(|(entry_id, _)| *entry_id == id)
+            .map(|(_, value)| value)
+    }
+
+    /// Returns the value registered for the driver with the given ID mutably, if any.
+    pub fn get_mut(&mut self, id: DriverId) -> Option<&mut T> {
+        self.entries.iter_mut().find_map(|entry| match entry {
+            Some((entry_id, value)) if *entry_id == id => Some(value),
+            _ => None,
+        })
+    }
+
+    /// Returns the value registered for the driver which owns the queue with the given ID, if any.
+    pub fn resolve_queue(&self, queue: QueueId) -> Option<&T> {
+        self.get(queue.driver)
+    }
+
+    /// Returns the number of drivers registered.
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    /// Returns whether no drivers are registered.
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Returns an iterator over the IDs of the registered drivers and their values.
+    pub fn iter(&self) -> impl Iterator<Item = (DriverId, &T)> {
+        self.entries
+            .iter()
+            .filter_map(|entry| entry.as_ref().map(|(id, value)| (*id, value)))
+    }
+}
+
+impl<T, const N: usize> Default for Registry<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ids_are_unique() {
+        let first = DriverId::allocate();
+        let second = DriverId::allocate();
+        assert_ne!(first, second);
+        assert_eq!(
+            QueueId::new(first, 2).to_string(),
+            format!("{} queue 2", first)
+        );
+    }
+
+    #[test]
+    fn from_value() {
+        let id = DriverId::allocate();
+        assert_eq!(DriverId::from_value(id.value()), Some(id));
+        // Values which haven't been assigned yet can't be turned into IDs.
+        assert_eq!(DriverId::from_value(0), None);
+        assert_eq!(DriverId::from_value(u32::MAX), None);
+    }
+
+    #[test]
+    fn register_and_resolve() {
+        let mut registry = Registry::<&str, 2>::new();
+        let blk = DriverId::allocate();
+        let net = DriverId::allocate();
+        let console = DriverId::allocate();
+
+        registry.register(blk, "blk").unwrap();
+        assert_eq!(registry.register(blk, "blk"), Err(Error::AlreadyUsed));
+        registry.register(net, "net").unwrap();
+        assert_eq!(
+            registry.register(console, "console"),
+            Err(Error::RegistryFull)
+        );
+        assert_eq!(registry.len(), 2);
+
+        assert_eq!(registry.get(blk), Some(&"blk"));
+        assert_eq!(registry.resolve_queue(QueueId::new(net, 1)), Some(&"net"));
+        *registry.get_mut(net).unwrap() = "net0";
+        assert_eq!(registry.get(net), Some(&"net0"));
+
+        // A stale ID doesn't resolve to the driver registered in its place.
+        assert_eq!(registry.unregister(blk), Some("blk"));
+        assert_eq!(registry.unregister(blk), None);
+        registry.register(console, "console").unwrap();
+        assert_eq!(registry.get(blk), None);
+        assert_eq!(
+            registry.iter().collect::<Vec<_>>(),
+            vec![(console, &"console"), (net, &"net0")]
+        );
+    }
+}
@@ -0,0 +1,166 @@
+//! Retrying blocking driver operations after transient failures.
+//!
+//! By default a blocking driver method returns any error from the device or the virtqueue straight
+//! to the caller. Drivers which support it, e.g. with [`VirtIOBlk::set_retry_policy`], can instead
+//! be given a [`RetryPolicy`] which retries operations that failed for a reason which may go away
+//! by itself, such as the queue being full of requests submitted by other code:
+//!
+//! ```
+//! use virtio_drivers::retry::{Backoff, RetryPolicy};
+//!
+//! fn yield_now(_error: virtio_drivers::Error, _attempt: u32) {
+//!     // Let the tasks whose requests are filling the queue run.
+//! }
+//!
+//! let policy = RetryPolicy {
+//!     max_retries: 8,
+//!     backoff: Backoff::Hook(yield_now),
+//! };
+//! ```
+//!
+//! [`VirtIOBlk::set_retry_policy`]: crate::device::blk::VirtIOBlk::set_retry_policy
+
+use crate::{Error, Result};
+use core::hint::spin_loop;
+
+/// What to do between a transient failure and the next attempt.
+#[derive(Copy, Clone, Debug, Default)]
+pub enum Backoff {
+    /// Retry immediately.
+    #[default]
+    Immediate,
+    /// Spin for `initial` iterations before the first retry, doubling for each further retry up
+    /// to `max` iterations.
+    Spin {
+        /// The number of iterations to spin for before the first retry.
+        initial: u32,
+        /// The maximum number of iterations to spin for before any retry.
+        max: u32,
+    },
+    /// Call the given function before each retry, with the error and the number of the retry
+    /// starting from 1, e.g. to sleep or to yield to other tasks.
+    Hook(fn(Error, u32)),
+}
+
+/// How many times, and how, a blocking driver method retries after a transient failure.
+///
+/// The default never retries.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RetryPolicy {
+    /// The maximum number of retries after the first attempt.
+    pub max_retries: u32,
+    /// What to do before each retry.
+    pub backoff: Backoff,
+}
+
+impl RetryPolicy {
+    /// A policy which never retries.
+    pub const NEVER: Self = Self {
+        max_retries: 0,
+        backoff: Backoff::Immediate,
+    };
+
+    /// Calls `operation` until it succeeds, fails with an error which isn't
+    /// [transient](is_transient), or has been retried `max_retries` times, and returns its last
+    /// result.
+    pub fn run<T>(self, mut operation: impl FnMut() -> Result<T>) -> Result<T> {
+        let mut retry = 0;
+        loop {
+            match operation() {
+                Err(e) if is_transient(&e) && retry < self.max_retries => {
+                    retry += 1;
+                    self.back_off(e, retry);
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// Waits before the given retry, according to the backoff.
+    fn back_off(&self, error: Error, retry: u32) {
+        match self.backoff {
+            Backoff::Immediate => {}
+            Backoff::Spin { initial, max } => {
+                let factor = 1u32.checked_shl(retry - 1).unwrap_or(u32::MAX);
+                let spins = initial.saturating_mul(factor).min(max);
+                for _ in 0..spins {
+                    spin_loop();
+                }
+            }
+            Backoff::Hook(hook) => hook(error, retry),
+        }
+    }
+}
+
+/// Returns whether the given error may go away by itself, so the operation which failed with it
+/// is worth retrying.
+///
+/// These are [`Error::QueueFull`] and [`Error::Throttled`], which clear once other requests
+/// complete, and [`Error::NotReady`], which clears once the device is ready, e.g. after it has
+/// been reset or a medium has been inserted.
+pub fn is_transient(error: &Error) -> bool {
+    matches!(error, Error::QueueFull | Error::Throttled | Error::NotReady)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::sync::atomic::{AtomicU32, Ordering};
+
+    static HOOK_CALLS: AtomicU32 = AtomicU32::new(0);
+
+    fn count_hook(error: Error, retry: u32) {
+        assert_eq!(error, Error::QueueFull);
+        assert_eq!(retry, HOOK_CALLS.fetch_add(1, Ordering::SeqCst) + 1);
+    }
+
+    #[test]
+    fn retries_transient_errors() {
+        let policy = RetryPolicy {
+            max_retries: 3,
+            backoff: Backoff::Hook(count_hook),
+        };
+
+        // Succeeds on the third attempt.
+        let mut attempts = 0;
+        let result = policy.run(|| {
+            attempts += 1;
+            if attempts < 3 {
+                Err(Error::QueueFull)
+            } else {
+                Ok(attempts)
+            }
+        });
+        assert_eq!(result, Ok(3));
+        assert_eq!(HOOK_CALLS.load(Ordering::SeqCst), 2);
+
+        // Gives up after the maximum number of retries.
+        attempts = 0;
+        let result = RetryPolicy {
+            backoff: Backoff::Spin { initial: 1, max: 4 },
+            ..policy
+        }
+        .run(|| -> Result {
+            attempts += 1;
+            Err(Error::Throttled)
+        });
+        assert_eq!(result, Err(Error::Throttled));
+        assert_eq!(attempts, 4);
+
+        // Doesn't retry other errors, or at all by default.
+        attempts = 0;
+        let result = policy.run(|| -> Result {
+            attempts += 1;
+            Err(Error::IoError)
+        });
+        assert_eq!(result, Err(Error::IoError));
+        assert_eq!(attempts, 1);
+        attempts = 0;
+        let result = RetryPolicy::default().run(|| -> Result {
+            attempts += 1;
+            Err(Error::QueueFull)
+        });
+        assert_eq!(result, Err(Error::QueueFull));
+        assert_eq!(attempts, 1);
+    }
+}
@@ -0,0 +1,268 @@
+//! A simulated block device backed by memory.
+
+use super::{Chain, Model, VIRTIO_F_INDIRECT_DESC};
+use crate::transport::DeviceType;
+use core::convert::TryInto;
+
+const SECTOR_SIZE: usize = 512;
+
+const VIRTIO_BLK_F_FLUSH: u64 = 1 << 9;
+
+const VIRTIO_BLK_T_IN: u32 = 0;
+const VIRTIO_BLK_T_OUT: u32 = 1;
+const VIRTIO_BLK_T_FLUSH: u32 = 4;
+const VIRTIO_BLK_T_GET_ID: u32 = 8;
+
+const VIRTIO_BLK_S_OK: u8 = 0;
+const VIRTIO_BLK_S_IOERR: u8 = 1;
+const VIRTIO_BLK_S_UNSUPP: u8 = 2;
+
+/// The length of the request header: type, reserved and sector.
+const HEADER_LEN: usize = 16;
+
+/// A block device whose disk is a buffer in memory.
+#[derive(Debug)]
+pub struct BlkModel {
+    /// The contents of the disk.
+    pub disk: Vec<u8>,
+    /// Whether to offer indirect descriptors.
+    indirect: bool,
+}
+
+impl BlkModel {
+    /// Creates a new zeroed disk with the given number of sectors.
+    pub fn new(sectors: usize, indirect: bool) -> Self {
+        Self {
+            disk: vec![0; sectors * SECTOR_SIZE],
+            indirect,
+        }
+    }
+
+    /// Handles a request, writing its data to the chain and returning the status.
+    fn request(&mut self, chain: &Chain) -> (Vec<u8>, u8) {
+        let request = chain.read();
+        let type_ = u32::from_le_bytes(request[0..4].try_into().unwrap());
+        let sector = u64::from_le_bytes(request[8..16].try_into().unwrap()) as usize;
+        // The last writable byte is always the status.
+        let data_len = chain.writable_len() - 1;
+        let start = sector * SECTOR_SIZE;
+        match type_ {
+            VIRTIO_BLK_T_IN => {
+                if !data_len.is_multiple_of(SECTOR_SIZE) || start + data_len > self.disk.len() {
+                    return (vec![], VIRTIO_BLK_S_IOERR);
+                }
+                (self.disk[start..start + data_len].to_vec(), VIRTIO_BLK_S_OK)
+            }
+            VIRTIO_BLK_T_OUT => {
+                let data = &request[HEADER_LEN..];
+                if !data.len().is_multiple_of(SECTOR_SIZE) || start + data.len() > self.disk.len() {
+                    return (vec![], VIRTIO_BLK_S_IOERR);
+                }
+                self.disk[start..start + data.len()].copy_from_slice(data);
+                (vec![], VIRTIO_BLK_S_OK)
+            }
+            VIRTIO_BLK_T_FLUSH => (vec![], VIRTIO_BLK_S_OK),
+            VIRTIO_BLK_T_GET_ID => (b"sim".to_vec(), VIRTIO_BLK_S_OK),
+            _ => (vec![], VIRTIO_BLK_S_UNSUPP),
+        }
+    }
+}
+
+impl Model for BlkModel {
+    const DEVICE_TYPE: DeviceType = DeviceType::Block;
+
+    fn features(&self) -> u64 {
+        if self.indirect {
+            VIRTIO_BLK_F_FLUSH | VIRTIO_F_INDIRECT_DESC
+        } else {
+            VIRTIO_BLK_F_FLUSH
+        }
+    }
+
+    fn queue_sizes(&self) -> Vec<u32> {
+        vec![16]
+    }
+
+    fn config(&self) -> Vec<u32> {
+        let sectors = (self.disk.len() / SECTOR_SIZE) as u64;
+        let mut config = vec![0; 16];
+        config[0] = sectors as u32;
+        config[1] = (sectors >> 32) as u32;
+        config
+    }
+
+    fn handle(&mut self, _queue: u16, chain: &Chain) -> Option<u32> {
+        let (mut response, status) = self.request(chain);
+        // Data which was read is followed by the status, which always goes in the last byte.
+        response.resize(chain.writable_len() - 1, 0);
+        response.push(status);
+        Some(chain.write(&response))
+    }
+
+    fn may_reorder(&self, _queue: u16) -> bool {
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::blk::{BlkReq, BlkResp, RespStatus, VirtIOBlk},
+        hal::fake::FakeHal,
+        sim::{Profile, Sim, CASES},
+    };
+    use proptest::{collection::vec, prelude::*};
+    use std::thread;
+
+    const SECTORS: usize = 64;
+
+    /// An operation for a test to perform on the block device.
+    #[derive(Clone, Debug)]
+    enum Op {
+        /// Writes the data starting at the sector.
+        Write {
+            sector: usize,
+            data: Vec<u8>,
+        },
+        /// Reads the given number of sectors starting at the sector.
+        Read {
+            sector: usize,
+            sectors: usize,
+        },
+        Flush,
+        /// Reads one sector from each of the given sectors, with all the reads in flight at once,
+        /// and completes them in whatever order the device returns them.
+        ReadMany(Vec<usize>),
+    }
+
+    /// A read which has been submitted but not yet completed.
+    struct PendingRead {
+        token: u16,
+        sector: usize,
+        req: Box<BlkReq>,
+        buf: Vec<u8>,
+        resp: Box<BlkResp>,
+    }
+
+    fn op() -> impl Strategy<Value = Op> {
+        // Requests of one to four sectors which fit on the disk.
+        let range = (1..5usize).prop_flat_map(|sectors| (0..=SECTORS - sectors, Just(sectors)));
+        prop_oneof![
+            range
+                .clone()
+                .prop_flat_map(|(sector, sectors)| (
+                    Just(sector),
+                    vec(any::<u8>(), sectors * SECTOR_SIZE)
+                ))
+                .prop_map(|(sector, data)| Op::Write { sector, data }),
+            range.prop_map(|(sector, sectors)| Op::Read { sector, sectors }),
+            Just(Op::Flush),
+            vec(0..SECTORS, 1..6).prop_map(Op::ReadMany),
+        ]
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(CASES))]
+
+        #[test]
+        fn random_requests(seed: u64, indirect: bool, ops in vec(op(), 1..32)) {
+            check_requests(Profile::Polling, seed, indirect, &ops)?;
+        }
+
+        #[test]
+        fn random_requests_event_driven(seed: u64, indirect: bool, ops in vec(op(), 1..32)) {
+            check_requests(Profile::EventDriven, seed, indirect, &ops)?;
+        }
+
+        #[test]
+        fn random_requests_firecracker(seed: u64, indirect: bool, ops in vec(op(), 1..32)) {
+            check_requests(Profile::Firecracker, seed, indirect, &ops)?;
+        }
+
+        #[test]
+        fn random_requests_cloud_hypervisor(seed: u64, indirect: bool, ops in vec(op(), 1..32)) {
+            check_requests(Profile::CloudHypervisor, seed, indirect, &ops)?;
+        }
+    }
+
+    /// Performs the given operations on a simulated device with the given profile, and checks that
+    /// every read returns what was last written.
+    fn check_requests(
+        profile: Profile,
+        seed: u64,
+        indirect: bool,
+        ops: &[Op],
+    ) -> Result<(), TestCaseError> {
+        let sim = Sim::start_with_profile(BlkModel::new(SECTORS, indirect), seed, profile);
+        let mut blk = VirtIOBlk::<FakeHal, _>::new(sim.transport()).unwrap();
+        // Declared after the driver so that, if a check fails, the device is stopped before the
+        // driver frees the queue.
+        let sim = sim;
+        let mut expected = vec![0; SECTORS * SECTOR_SIZE];
+
+        for op in ops {
+            match op {
+                Op::Write { sector, data } => {
+                    blk.write_blocks(*sector, data).unwrap();
+                    let start = sector * SECTOR_SIZE;
+                    expected[start..start + data.len()].copy_from_slice(data);
+                }
+                Op::Read { sector, sectors } => {
+                    let mut data = vec![0; sectors * SECTOR_SIZE];
+                    blk.read_blocks(*sector, &mut data).unwrap();
+                    let start = sector * SECTOR_SIZE;
+                    prop_assert_eq!(&data[..], &expected[start..start + data.len()]);
+                }
+                Op::Flush => blk.flush().unwrap(),
+                Op::ReadMany(sectors) => {
+                    let mut pending = Vec::new();
+                    for &sector in sectors {
+                        let mut read = PendingRead {
+                            token: 0,
+                            sector,
+                            req: Box::default(),
+                            buf: vec![0; SECTOR_SIZE],
+                            resp: Box::default(),
+                        };
+                        read.token = unsafe {
+                            blk.read_blocks_nb(sector, &mut read.req, &mut read.buf, &mut read.resp)
+                        }
+                        .unwrap();
+                        pending.push(read);
+                    }
+                    let mut completed = Vec::new();
+                    while !pending.is_empty() {
+                        let Some(token) = blk.peek_used() else {
+                            thread::yield_now();
+                            continue;
+                        };
+                        let index = pending.iter().position(|read| read.token == token).unwrap();
+                        let mut read = pending.swap_remove(index);
+                        unsafe {
+                            blk.complete_read_blocks(
+                                token,
+                                &read.req,
+                                &mut read.buf,
+                                &mut read.resp,
+                            )
+                        }
+                        .unwrap();
+                        completed.push(read);
+                    }
+                    // Only check the reads once the device has finished with all of them.
+                    for read in completed {
+                        prop_assert_eq!(read.resp.status(), RespStatus::OK);
+                        let start = read.sector * SECTOR_SIZE;
+                        prop_assert_eq!(&read.buf[..], &expected[start..start + SECTOR_SIZE]);
+                    }
+                }
+            }
+        }
+
+        let model = sim.stop();
+        drop(blk);
+        prop_assert_eq!(model.disk, expected);
+        Ok(())
+    }
+}
@@ -0,0 +1,637 @@
+//! A simulated VirtIO device which runs on another thread, for testing drivers and the virtqueue
+//! implementation against a device with unpredictable timing.
+//!
+//! The device side is implemented independently of the drivers, from the specification, and talks
+//! to them through the [`software`](crate::transport::software) transport. Each [`Model`] decides
+//! what to do with the descriptor chains the driver makes available, while the simulator takes care
+//! of the rings: it takes chains from the available ring as soon as they appear, handles them after
+//! a random delay and, where the model allows it, completes them in a random order.
+//!
+//! Tests are written with `proptest`: it generates sequences of operations for the driver, whose
+//! results are checked against a simple model, along with the seed of the [`Rng`] which decides
+//! the device's timing. A failing case is shrunk and saved under `proptest-regressions`, so it is
+//! run again first next time, with the same device timing as far as thread scheduling allows.
+//!
+//! VMMs differ in the features their devices offer and in how the devices find out about new
+//! buffers and signal used ones, so the simulated device can behave like different VMMs according
+//! to its [`Profile`].
+
+#[cfg(feature = "blk")]
+mod blk;
+#[cfg(all(feature = "net", feature = "alloc"))]
+mod net;
+
+use crate::transport::{
+    software::{
+        QueueConfig, SharedRegisters, SoftwareHooks, SoftwareTransport, INTERRUPT_USED_BUFFER,
+    },
+    DeviceStatus, DeviceType,
+};
+use core::{
+    mem::size_of,
+    ptr::{self, NonNull},
+    sync::atomic::{fence, AtomicBool, AtomicU16, AtomicU32, AtomicU64, Ordering},
+    time::Duration,
+};
+use std::{
+    sync::Arc,
+    thread::{self, JoinHandle},
+};
+
+/// The number of cases each property test runs, which is kept low as each case starts a device
+/// thread.
+pub const CASES: u32 = 16;
+
+const VIRTQ_DESC_F_NEXT: u16 = 1;
+const VIRTQ_DESC_F_WRITE: u16 = 2;
+const VIRTQ_DESC_F_INDIRECT: u16 = 4;
+
+const VIRTIO_F_INDIRECT_DESC: u64 = 1 << 28;
+const VIRTIO_F_EVENT_IDX: u64 = 1 << 29;
+const VIRTIO_F_VERSION_1: u64 = 1 << 32;
+
+/// A small deterministic pseudo-random number generator (xorshift64*), so that failures can be
+/// reproduced from the seed.
+#[derive(Clone, Debug)]
+pub struct Rng(u64);
+
+impl Rng {
+    /// Creates a new generator from the given seed.
+    pub fn new(seed: u64) -> Self {
+        // Mix the seed so that nearby seeds give unrelated sequences, and avoid the zero state.
+        Self((seed ^ 0x9e37_79b9_7f4a_7c15).wrapping_mul(0xbf58_476d_1ce4_e5b9) | 1)
+    }
+
+    /// Returns the next 64 random bits.
+    pub fn next_u64(&mut self) -> u64 {
+        self.0 ^= self.0 >> 12;
+        self.0 ^= self.0 << 25;
+        self.0 ^= self.0 >> 27;
+        self.0.wrapping_mul(0x2545_f491_4f6c_dd1d)
+    }
+
+    /// Returns a random number in the given range.
+    pub fn range(&mut self, start: usize, end: usize) -> usize {
+        assert!(start < end);
+        start + (self.next_u64() % (end - start) as u64) as usize
+    }
+
+    /// Returns true with a probability of one in `n`.
+    pub fn one_in(&mut self, n: u64) -> bool {
+        self.next_u64().is_multiple_of(n)
+    }
+}
+
+/// How a simulated device learns about new buffers and signals used ones, and which transport
+/// features it offers on top of those of its [`Model`].
+///
+/// The specification allows any of these behaviours, so a driver must work with all of them. One
+/// which only works with a polling device has a bug which may not show up under QEMU.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum Profile {
+    /// The device polls the available rings continuously, and raises an interrupt for each used
+    /// buffer. This is the most forgiving, as it finds new buffers even if the driver doesn't
+    /// notify it.
+    #[default]
+    Polling,
+    /// The device only looks at a queue's available ring after the driver notifies it, and raises
+    /// a single interrupt for each batch of used buffers.
+    ///
+    /// This is the event loop which Firecracker and Cloud Hypervisor devices are built around: they
+    /// wake up when the queue's notification eventfd fires, process everything available, and then
+    /// signal the used queue once. The other features of those VMMs are left out; see
+    /// [`Firecracker`](Self::Firecracker) and [`CloudHypervisor`](Self::CloudHypervisor).
+    EventDriven,
+    /// Behaves like a Firecracker virtio-mmio device.
+    ///
+    /// The device is event-driven, and offers `VIRTIO_F_VERSION_1` and `VIRTIO_F_EVENT_IDX` but
+    /// never `VIRTIO_F_INDIRECT_DESC`, which Firecracker doesn't implement. With `EVENT_IDX`
+    /// negotiated the device publishes `avail_event` each time it has emptied the available ring
+    /// and then looks at the ring once more, so a driver which skips a notification it was asked
+    /// for stalls. Interrupts are only raised for a batch of used buffers if it crosses the
+    /// driver's `used_event`.
+    Firecracker,
+    /// Behaves like a Cloud Hypervisor virtio device.
+    ///
+    /// This notifies and interrupts like [`Firecracker`](Self::Firecracker), but also offers
+    /// `VIRTIO_F_INDIRECT_DESC`, whatever the model offers, as Cloud Hypervisor's queue
+    /// implementation supports indirect descriptors for every device.
+    CloudHypervisor,
+}
+
+impl Profile {
+    /// Returns the features which a device offers under this profile, given those of its model.
+    fn device_features(self, model_features: u64) -> u64 {
+        match self {
+            Self::Polling | Self::EventDriven => model_features,
+            Self::Firecracker => {
+                (model_features & !VIRTIO_F_INDIRECT_DESC) | VIRTIO_F_VERSION_1 | VIRTIO_F_EVENT_IDX
+            }
+            Self::CloudHypervisor => {
+                model_features | VIRTIO_F_INDIRECT_DESC | VIRTIO_F_VERSION_1 | VIRTIO_F_EVENT_IDX
+            }
+        }
+    }
+
+    /// Returns whether the device only looks at a queue's available ring after being notified,
+    /// and raises a single interrupt for each batch of used buffers.
+    fn event_driven(self) -> bool {
+        self != Self::Polling
+    }
+}
+
+/// Returns whether the other side asked to be told about an index moving from `old` to `new`,
+/// given its event index (`used_event` or `avail_event`).
+///
+/// Ref: 2.7.10 Used Buffer Notification Suppression
+fn need_event(event: u16, new: u16, old: u16) -> bool {
+    new.wrapping_sub(event).wrapping_sub(1) < new.wrapping_sub(old)
+}
+
+/// A descriptor as laid out in memory by the driver.
+#[repr(C)]
+#[derive(Copy, Clone, Debug)]
+struct RawDescriptor {
+    addr: u64,
+    len: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// A chain of buffers which the driver made available to the device.
+#[derive(Debug)]
+pub struct Chain {
+    head: u16,
+    /// The address and length of each device-readable buffer.
+    readable: Vec<(u64, u32)>,
+    /// The address and length of each device-writable buffer.
+    writable: Vec<(u64, u32)>,
+}
+
+impl Chain {
+    /// Returns the contents of the device-readable buffers, concatenated.
+    pub fn read(&self) -> Vec<u8> {
+        let mut data = Vec::new();
+        for &(addr, len) in &self.readable {
+            // Safe because the driver gave us the buffer, and won't touch it until it is used.
+            data.extend_from_slice(unsafe {
+                core::slice::from_raw_parts(addr as *const u8, len as usize)
+            });
+        }
+        data
+    }
+
+    /// Returns the total length of the device-writable buffers.
+    pub fn writable_len(&self) -> usize {
+        self.writable.iter().map(|&(_, len)| len as usize).sum()
+    }
+
+    /// Writes the given data to the device-writable buffers in order, and returns its length.
+    ///
+    /// Panics if it doesn't fit, as that means the driver gave too small a buffer.
+    pub fn write(&self, mut data: &[u8]) -> u32 {
+        assert!(
+            data.len() <= self.writable_len(),
+            "Driver gave {} bytes of writable buffers for {} bytes",
+            self.writable_len(),
+            data.len()
+        );
+        let written = data.len() as u32;
+        for &(addr, len) in &self.writable {
+            let chunk = data.len().min(len as usize);
+            // Safe because the driver gave us the buffer, and won't touch it until it is used.
+            unsafe {
+                ptr::copy_nonoverlapping(data.as_ptr(), addr as *mut u8, chunk);
+            }
+            data = &data[chunk..];
+        }
+        written
+    }
+}
+
+/// What a simulated device does with the buffers it is given.
+pub trait Model: Send + 'static {
+    /// The type of device being simulated.
+    const DEVICE_TYPE: DeviceType;
+
+    /// Returns the feature bits which the device offers.
+    fn features(&self) -> u64;
+
+    /// Returns the maximum size of each of the device's queues.
+    fn queue_sizes(&self) -> Vec<u32>;
+
+    /// Returns the initial contents of the device-specific config space.
+    fn config(&self) -> Vec<u32>;
+
+    /// Tells the model which features the driver accepted, once the driver has set `DRIVER_OK`.
+    fn negotiated(&mut self, _features: u64) {}
+
+    /// Handles a chain from the given queue, and returns the number of bytes written to it, or
+    /// `None` if it can't be handled yet (e.g. a receive buffer with no packet to put in it).
+    ///
+    /// Chains in each queue are offered in the order the driver made them available, and no later
+    /// chain is offered until an earlier one has been handled.
+    fn handle(&mut self, queue: u16, chain: &Chain) -> Option<u32>;
+
+    /// Returns whether chains in the given queue may be completed out of order once handled.
+    fn may_reorder(&self, queue: u16) -> bool;
+}
+
+/// The device's view of one virtqueue.
+#[derive(Debug, Default)]
+struct DeviceQueue {
+    config: QueueConfig,
+    last_avail_idx: u16,
+    used_idx: u16,
+    /// Chains taken from the available ring which the model hasn't handled yet.
+    waiting: Vec<Chain>,
+    /// The head and used length of chains which have been handled but not yet returned.
+    handled: Vec<(u16, u32)>,
+}
+
+impl DeviceQueue {
+    /// Takes all new chains from the available ring.
+    ///
+    /// With `event_idx` the device then asks to be notified about the next chain through
+    /// `avail_event`, and looks at the ring again in case the driver added one in the meantime
+    /// without notifying.
+    ///
+    /// # Safety
+    ///
+    /// The queue configuration must be valid and the driver must not free the queue.
+    unsafe fn take_available(&mut self, event_idx: bool) {
+        let size = self.config.size as u16;
+        let avail = self.config.driver_area as usize;
+        loop {
+            // Safe because the caller promises the available ring is valid.
+            let avail_idx = unsafe { &*((avail + 2) as *const AtomicU16) }.load(Ordering::Acquire);
+            if self.last_avail_idx == avail_idx {
+                return;
+            }
+            while self.last_avail_idx != avail_idx {
+                let slot = usize::from(self.last_avail_idx % size);
+                let head = unsafe { ptr::read_volatile((avail + 4 + 2 * slot) as *const u16) };
+                self.last_avail_idx = self.last_avail_idx.wrapping_add(1);
+                let chain = unsafe { self.read_chain(head) };
+                self.waiting.push(chain);
+            }
+            if !event_idx {
+                return;
+            }
+            let avail_event = self.config.device_area as usize + 4 + 8 * usize::from(size);
+            // Safe because the caller promises the used ring is valid.
+            unsafe { ptr::write_volatile(avail_event as *mut u16, self.last_avail_idx) };
+            fence(Ordering::SeqCst);
+        }
+    }
+
+    /// Returns the `used_event` which the driver last published.
+    ///
+    /// # Safety
+    ///
+    /// The queue configuration must be valid and the driver must not free the queue.
+    unsafe fn used_event(&self) -> u16 {
+        let used_event = self.config.driver_area as usize + 4 + 2 * self.config.size as usize;
+        // Safe because the caller promises the available ring is valid.
+        unsafe { ptr::read_volatile(used_event as *const u16) }
+    }
+
+    /// Reads the descriptor chain starting at the given head, following an indirect table if
+    /// there is one.
+    unsafe fn read_chain(&self, head: u16) -> Chain {
+        let mut chain = Chain {
+            head,
+            readable: Vec::new(),
+            writable: Vec::new(),
+        };
+        let mut table = self.config.descriptors as *const RawDescriptor;
+        let mut table_len = self.config.size as usize;
+        let mut index = head;
+        for _ in 0..=self.config.size {
+            assert!(
+                usize::from(index) < table_len,
+                "Descriptor index out of range"
+            );
+            // Safe because the caller promises the descriptor table is valid.
+            let descriptor = unsafe { ptr::read_volatile(table.add(usize::from(index))) };
+            if descriptor.flags & VIRTQ_DESC_F_INDIRECT != 0 {
+                assert_eq!(descriptor.flags, VIRTQ_DESC_F_INDIRECT);
+                assert_eq!(table, self.config.descriptors as *const RawDescriptor);
+                table = descriptor.addr as *const RawDescriptor;
+                table_len = descriptor.len as usize / size_of::<RawDescriptor>();
+                index = 0;
+                continue;
+            }
+            assert_ne!(descriptor.len, 0, "Empty descriptor");
+            if descriptor.flags & VIRTQ_DESC_F_WRITE != 0 {
+                chain.writable.push((descriptor.addr, descriptor.len));
+            } else {
+                assert!(
+                    chain.writable.is_empty(),
+                    "Readable descriptor after writable one"
+                );
+                chain.readable.push((descriptor.addr, descriptor.len));
+            }
+            if descriptor.flags & VIRTQ_DESC_F_NEXT == 0 {
+                return chain;
+            }
+            index = descriptor.next;
+        }
+        panic!("Descriptor chain starting at {} is a loop", head);
+    }
+
+    /// Returns a chain to the driver through the used ring.
+    ///
+    /// # Safety
+    ///
+    /// The queue configuration must be valid and the driver must not free the queue.
+    unsafe fn push_used(&mut self, head: u16, len: u32) {
+        let used = self.config.device_area as usize;
+        let slot = usize::from(self.used_idx % self.config.size as u16);
+        // Safe because the caller promises the used ring is valid.
+        unsafe {
+            let element = (used + 4 + 8 * slot) as *mut u32;
+            ptr::write_volatile(element, head.into());
+            ptr::write_volatile(element.add(1), len);
+        }
+        self.used_idx = self.used_idx.wrapping_add(1);
+        fence(Ordering::SeqCst);
+        unsafe { &*((used + 2) as *const AtomicU16) }.store(self.used_idx, Ordering::Release);
+    }
+}
+
+/// The driver-side hooks for a simulated device, which keep the shared memory alive as long as
+/// the transport.
+#[derive(Debug)]
+pub struct SimHooks {
+    registers: Arc<SharedRegisters>,
+    config: Arc<[AtomicU32]>,
+    notified: Arc<AtomicU64>,
+}
+
+impl SoftwareHooks for SimHooks {
+    fn notify(&mut self, queue: u16) {
+        // Only an event-driven device looks at this. Notifications for queues which the device
+        // doesn't have are ignored, as a real device would.
+        if let Some(bit) = notified_bit(queue) {
+            self.notified.fetch_or(bit, Ordering::AcqRel);
+        }
+    }
+}
+
+/// Returns the bit for the given queue in a mask of notified queues, or `None` if it is out of
+/// range.
+fn notified_bit(queue: u16) -> Option<u64> {
+    1u64.checked_shl(queue.into())
+}
+
+/// A simulated device running on its own thread.
+pub struct Sim<M: Model> {
+    registers: Arc<SharedRegisters>,
+    config: Arc<[AtomicU32]>,
+    /// A bit for each queue which the driver has notified since the device last looked at it.
+    notified: Arc<AtomicU64>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<M>>,
+}
+
+impl<M: Model> Sim<M> {
+    /// Starts simulating a polling device with the given model, with timing and completion order
+    /// chosen randomly from the given seed.
+    pub fn start(model: M, seed: u64) -> Self {
+        Self::start_with_profile(model, seed, Profile::Polling)
+    }
+
+    /// Starts simulating a device with the given model and profile, with timing and completion
+    /// order chosen randomly from the given seed.
+    pub fn start_with_profile(model: M, seed: u64, profile: Profile) -> Self {
+        let registers = Arc::new(SharedRegisters::new(
+            M::DEVICE_TYPE,
+            profile.device_features(model.features()),
+        ));
+        let queue_sizes = model.queue_sizes();
+        assert!(
+            queue_sizes.len() <= u64::BITS as usize,
+            "Too many queues to track notifications for"
+        );
+        for (queue, &size) in queue_sizes.iter().enumerate() {
+            registers.set_queue_max(queue as u16, size).unwrap();
+        }
+        let config: Arc<[AtomicU32]> = model.config().into_iter().map(AtomicU32::new).collect();
+        let notified = Arc::new(AtomicU64::new(0));
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread = {
+            let device = Device {
+                registers: registers.clone(),
+                notified: notified.clone(),
+                profile,
+            };
+            let stop = stop.clone();
+            thread::spawn(move || device.run(model, queue_sizes.len(), &stop, Rng::new(seed)))
+        };
+        Self {
+            registers,
+            config,
+            notified,
+            stop,
+            thread: Some(thread),
+        }
+    }
+
+    /// Returns a transport for a driver to talk to the device.
+    ///
+    /// The device must be stopped with [`stop`](Self::stop) before the driver is dropped, as the
+    /// device may otherwise access the queues after they are freed.
+    pub fn transport(&self) -> SoftwareTransport<SimHooks> {
+        let config_space = NonNull::new(ptr::slice_from_raw_parts_mut(
+            self.config.as_ptr() as *mut u8,
+            self.config.len() * size_of::<u32>(),
+        ))
+        .unwrap();
+        let hooks = SimHooks {
+            registers: self.registers.clone(),
+            config: self.config.clone(),
+            notified: self.notified.clone(),
+        };
+        // Safe because the hooks keep the registers and config space alive as long as the
+        // transport, and they are only accessed through atomics or volatile accesses.
+        unsafe {
+            SoftwareTransport::new(NonNull::from(&*hooks.registers), Some(config_space), hooks)
+        }
+    }
+
+    /// Stops the device once it has returned everything it was given, and returns the model for
+    /// inspection.
+    pub fn stop(mut self) -> M {
+        self.stop.store(true, Ordering::Release);
+        self.thread.take().unwrap().join().unwrap()
+    }
+}
+
+impl<M: Model> Drop for Sim<M> {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Release);
+        if let Some(thread) = self.thread.take() {
+            // Don't panic again if the device thread already did.
+            let _ = thread.join();
+        }
+    }
+}
+
+/// The device side of a simulated device, as seen from the device thread.
+struct Device {
+    registers: Arc<SharedRegisters>,
+    notified: Arc<AtomicU64>,
+    profile: Profile,
+}
+
+impl Device {
+    /// Returns whether the device should look for new chains in the given queue now.
+    fn should_take_available(&self, queue: u16) -> bool {
+        if !self.profile.event_driven() {
+            return true;
+        }
+        // The number of queues was checked when the device started.
+        let bit = notified_bit(queue).unwrap();
+        self.notified.fetch_and(!bit, Ordering::AcqRel) & bit != 0
+    }
+
+    /// Raises a used buffer interrupt for the given queue, whose used index moved from `old` to
+    /// the current one, unless the driver suppressed it.
+    ///
+    /// # Safety
+    ///
+    /// The queue configuration must be valid and the driver must not free the queue.
+    unsafe fn signal_used(&self, queue: &DeviceQueue, old: u16, event_idx: bool) {
+        // Safe because the caller promises the queue is valid.
+        if !event_idx || need_event(unsafe { queue.used_event() }, queue.used_idx, old) {
+            self.registers.raise_interrupt(INTERRUPT_USED_BUFFER);
+        }
+    }
+
+    /// The main loop of the device thread.
+    fn run<M: Model>(
+        &self,
+        mut model: M,
+        queue_count: usize,
+        stop: &AtomicBool,
+        mut rng: Rng,
+    ) -> M {
+        let registers = &self.registers;
+        let mut queues: Vec<DeviceQueue> =
+            (0..queue_count).map(|_| DeviceQueue::default()).collect();
+        // The features the driver accepted, once it has set `DRIVER_OK`.
+        let mut driver_features = None;
+        loop {
+            let stopping = stop.load(Ordering::Acquire);
+            let mut busy = false;
+            if !registers.status().contains(DeviceStatus::DRIVER_OK) {
+                driver_features = None;
+            } else {
+                let features = *driver_features.get_or_insert_with(|| {
+                    let features = registers.driver_features();
+                    model.negotiated(features);
+                    features
+                });
+                let event_idx = features & VIRTIO_F_EVENT_IDX != 0;
+                for (index, queue) in queues.iter_mut().enumerate() {
+                    let index = index as u16;
+                    let config = registers.queue_config(index).unwrap();
+                    if config != queue.config {
+                        // The queue was set up or reset.
+                        *queue = DeviceQueue {
+                            config,
+                            ..Default::default()
+                        };
+                    }
+                    if config.size == 0 {
+                        continue;
+                    }
+                    if self.should_take_available(index) {
+                        // Safe because the driver keeps the queue valid while it is set up, and
+                        // the test stops the device before dropping the driver.
+                        unsafe { queue.take_available(event_idx) };
+                    }
+
+                    // Handle waiting chains in order, until the model can't handle one.
+                    while !queue.waiting.is_empty() && !rng.one_in(4) {
+                        let Some(len) = model.handle(index, &queue.waiting[0]) else {
+                            break;
+                        };
+                        let chain = queue.waiting.remove(0);
+                        queue.handled.push((chain.head, len));
+                    }
+
+                    // Return some of the handled chains, in a random order if allowed.
+                    let batch_start = queue.used_idx;
+                    while !queue.handled.is_empty() && (stopping || !rng.one_in(3)) {
+                        let position = if model.may_reorder(index) {
+                            rng.range(0, queue.handled.len())
+                        } else {
+                            0
+                        };
+                        let (head, len) = queue.handled.remove(position);
+                        let old = queue.used_idx;
+                        unsafe { queue.push_used(head, len) };
+                        if !self.profile.event_driven() {
+                            unsafe { self.signal_used(queue, old, event_idx) };
+                        }
+                    }
+                    if queue.used_idx != batch_start && self.profile.event_driven() {
+                        unsafe { self.signal_used(queue, batch_start, event_idx) };
+                    }
+                    busy |= !queue.handled.is_empty();
+                }
+            }
+            if stopping && !busy {
+                return model;
+            }
+            if rng.one_in(2) {
+                thread::sleep(Duration::from_micros(rng.range(0, 50) as u64));
+            } else {
+                thread::yield_now();
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn notified_bits() {
+        assert_eq!(notified_bit(0), Some(1));
+        assert_eq!(notified_bit(8), Some(1 << 8));
+        assert_eq!(notified_bit(63), Some(1 << 63));
+        assert_eq!(notified_bit(64), None);
+        assert_eq!(notified_bit(u16::MAX), None);
+    }
+
+    #[test]
+    fn need_event_crossing() {
+        // The driver asked to hear about index 5 being passed.
+        assert!(!need_event(5, 5, 3));
+        assert!(need_event(5, 6, 3));
+        assert!(need_event(5, 6, 5));
+        assert!(!need_event(5, 7, 6));
+        // The same across the wrap.
+        assert!(need_event(u16::MAX, 1, u16::MAX - 2));
+        assert!(!need_event(u16::MAX, u16::MAX, u16::MAX - 2));
+    }
+
+    #[test]
+    fn profile_features() {
+        let model = 1 | VIRTIO_F_INDIRECT_DESC;
+        assert_eq!(Profile::Polling.device_features(model), model);
+        assert_eq!(Profile::EventDriven.device_features(model), model);
+        assert_eq!(
+            Profile::Firecracker.device_features(model),
+            1 | VIRTIO_F_VERSION_1 | VIRTIO_F_EVENT_IDX
+        );
+        assert_eq!(
+            Profile::CloudHypervisor.device_features(1),
+            1 | VIRTIO_F_INDIRECT_DESC | VIRTIO_F_VERSION_1 | VIRTIO_F_EVENT_IDX
+        );
+    }
+}
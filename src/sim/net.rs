@@ -0,0 +1,215 @@
+//! A simulated network device which loops transmitted packets back to the receive queue.
+
+use super::{Chain, Model, VIRTIO_F_VERSION_1};
+use crate::transport::DeviceType;
+use std::collections::VecDeque;
+
+const VIRTIO_NET_F_MAC: u64 = 1 << 5;
+
+const RECEIVE_QUEUE: u16 = 0;
+const TRANSMIT_QUEUE: u16 = 1;
+
+/// The length of `struct virtio_net_hdr` without `num_buffers`, which is only present with
+/// `VIRTIO_F_VERSION_1` or `VIRTIO_NET_F_MRG_RXBUF`. The model doesn't offer the latter.
+const NET_HDR_LEN: usize = 10;
+
+/// A network device which receives every packet it transmits.
+#[derive(Debug)]
+pub struct LoopbackModel {
+    /// Packets which have been transmitted but not yet received.
+    in_flight: VecDeque<Vec<u8>>,
+    /// The number of packets transmitted so far.
+    pub transmitted: usize,
+    /// The length of the header before each packet, which depends on the negotiated features.
+    hdr_len: usize,
+}
+
+impl Default for LoopbackModel {
+    fn default() -> Self {
+        Self {
+            in_flight: VecDeque::new(),
+            transmitted: 0,
+            hdr_len: NET_HDR_LEN,
+        }
+    }
+}
+
+impl Model for LoopbackModel {
+    const DEVICE_TYPE: DeviceType = DeviceType::Network;
+
+    fn features(&self) -> u64 {
+        VIRTIO_NET_F_MAC
+    }
+
+    fn queue_sizes(&self) -> Vec<u32> {
+        vec![16, 16]
+    }
+
+    fn config(&self) -> Vec<u32> {
+        // MAC address 02:00:00:00:00:01, link up.
+        vec![0x0000_0002, 0x0001_0100, 0]
+    }
+
+    fn negotiated(&mut self, features: u64) {
+        self.hdr_len = if features & VIRTIO_F_VERSION_1 != 0 {
+            NET_HDR_LEN + 2
+        } else {
+            NET_HDR_LEN
+        };
+    }
+
+    fn handle(&mut self, queue: u16, chain: &Chain) -> Option<u32> {
+        match queue {
+            RECEIVE_QUEUE => {
+                let packet = self.in_flight.pop_front()?;
+                let mut data = vec![0; self.hdr_len];
+                if self.hdr_len > NET_HDR_LEN {
+                    // Without mergeable receive buffers every packet fits in one buffer.
+                    data[NET_HDR_LEN..].copy_from_slice(&1u16.to_le_bytes());
+                }
+                data.extend_from_slice(&packet);
+                Some(chain.write(&data))
+            }
+            TRANSMIT_QUEUE => {
+                let data = chain.read();
+                self.in_flight.push_back(data[self.hdr_len..].to_vec());
+                self.transmitted += 1;
+                Some(0)
+            }
+            _ => panic!("Unexpected queue {}", queue),
+        }
+    }
+
+    fn may_reorder(&self, queue: u16) -> bool {
+        // Transmissions may complete in any order, but packets are received in order.
+        queue == TRANSMIT_QUEUE
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::net::{RxHookStats, RxVerdict, VirtIONet},
+        hal::fake::FakeHal,
+        sim::{Profile, Sim, CASES},
+        Error,
+    };
+    use proptest::{collection::vec, prelude::*};
+    use std::thread;
+
+    /// Batches of packets to send, each of which is received before the next batch is sent.
+    fn batches() -> impl Strategy<Value = Vec<Vec<Vec<u8>>>> {
+        vec(vec(vec(any::<u8>(), 14..1515), 1..5), 1..16)
+    }
+
+    proptest! {
+        #![proptest_config(ProptestConfig::with_cases(CASES))]
+
+        #[test]
+        fn loopback(seed: u64, batches in batches()) {
+            check_loopback(Profile::Polling, seed, &batches)?;
+        }
+
+        #[test]
+        fn loopback_event_driven(seed: u64, batches in batches()) {
+            check_loopback(Profile::EventDriven, seed, &batches)?;
+        }
+
+        #[test]
+        fn loopback_firecracker(seed: u64, batches in batches()) {
+            check_loopback(Profile::Firecracker, seed, &batches)?;
+        }
+
+        #[test]
+        fn loopback_cloud_hypervisor(seed: u64, batches in batches()) {
+            check_loopback(Profile::CloudHypervisor, seed, &batches)?;
+        }
+    }
+
+    /// Sends the given batches of packets through a simulated loopback device with the given
+    /// profile, and checks that they are received unchanged and in order.
+    fn check_loopback(
+        profile: Profile,
+        seed: u64,
+        batches: &[Vec<Vec<u8>>],
+    ) -> Result<(), TestCaseError> {
+        let sim = Sim::start_with_profile(LoopbackModel::default(), seed, profile);
+        let mut net = VirtIONet::<FakeHal, _, 16>::new(sim.transport(), 2048).unwrap();
+        // Declared after the driver so that, if a check fails, the device is stopped before the
+        // driver frees the queues.
+        let sim = sim;
+        prop_assert_eq!(net.mac_address(), [0x02, 0, 0, 0, 0, 0x01]);
+
+        let mut sent = 0;
+        for packets in batches {
+            for packet in packets {
+                let mut tx_buf = net.new_tx_buffer(packet.len());
+                tx_buf.packet_mut().copy_from_slice(packet);
+                net.send(tx_buf).unwrap();
+                sent += 1;
+            }
+            for packet in packets {
+                let rx_buf = loop {
+                    match net.receive() {
+                        Ok(rx_buf) => break rx_buf,
+                        Err(Error::NotReady) => thread::yield_now(),
+                        Err(e) => panic!("{:?}", e),
+                    }
+                };
+                prop_assert_eq!(rx_buf.packet(), &packet[..]);
+                net.recycle_rx_buffer(rx_buf).unwrap();
+            }
+        }
+
+        let model = sim.stop();
+        drop(net);
+        prop_assert_eq!(model.transmitted, sent);
+        Ok(())
+    }
+
+    #[test]
+    fn rx_hook() {
+        let sim = Sim::start(LoopbackModel::default(), 0);
+        let mut net = VirtIONet::<FakeHal, _, 16>::new(sim.transport(), 2048).unwrap();
+        net.set_rx_hook(Some(Box::new(|rx_buf| match rx_buf.packet()[0] {
+            0 => RxVerdict::Pass,
+            1 => RxVerdict::Drop,
+            _ => RxVerdict::Recycle,
+        })));
+
+        // Send more packets than there are receive buffers, to check that filtered buffers are
+        // put back in the queue.
+        for i in 0..40u8 {
+            let mut tx_buf = net.new_tx_buffer(60);
+            tx_buf.packet_mut()[0] = i % 3;
+            tx_buf.packet_mut()[1] = i;
+            net.send(tx_buf).unwrap();
+            if i % 3 == 0 {
+                let rx_buf = loop {
+                    match net.receive() {
+                        Ok(rx_buf) => break rx_buf,
+                        Err(Error::NotReady) => thread::yield_now(),
+                        Err(e) => panic!("{:?}", e),
+                    }
+                };
+                assert_eq!(rx_buf.packet()[..2], [0, i]);
+                net.recycle_rx_buffer(rx_buf).unwrap();
+            }
+        }
+        // Wait for the last filtered packets to be handled.
+        while net.rx_hook_stats().dropped + net.rx_hook_stats().recycled < 26 {
+            assert!(matches!(net.receive(), Err(Error::NotReady)));
+            thread::yield_now();
+        }
+        assert_eq!(
+            net.rx_hook_stats(),
+            RxHookStats {
+                dropped: 13,
+                recycled: 13,
+            }
+        );
+
+        sim.stop();
+    }
+}
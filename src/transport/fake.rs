@@ -1,11 +1,13 @@
-use super::{DeviceStatus, DeviceType, Transport};
+//! Fake transport implementation for tests.
+
+use super::{DeviceStatus, DeviceType, SharedMemoryRegion, Transport};
 use crate::{
-    queue::{fake_read_write_queue, Descriptor},
-    PhysAddr, Result,
+    queue::{fake_queue_has_available, fake_read_write_queue, Descriptor},
+    Error, PhysAddr, Result,
 };
 use alloc::{sync::Arc, vec::Vec};
 use core::{
-    any::TypeId,
+    mem::size_of,
     ptr::NonNull,
     sync::atomic::{AtomicBool, Ordering},
     time::Duration,
@@ -15,10 +17,15 @@ use std::{sync::Mutex, thread};
 /// A fake implementation of [`Transport`] for unit tests.
 #[derive(Debug)]
 pub struct FakeTransport<C: 'static> {
+    /// The type of device which the transport claims to be.
     pub device_type: DeviceType,
+    /// The maximum queue size reported for every queue.
     pub max_queue_size: u32,
+    /// The features offered by the fake device.
     pub device_features: u64,
+    /// The device-specific configuration space.
     pub config_space: NonNull<C>,
+    /// State shared between the transport and the test acting as the device.
     pub state: Arc<Mutex<State>>,
 }
 
@@ -68,12 +75,13 @@ impl<C> Transport for FakeTransport<C> {
         descriptors: PhysAddr,
         driver_area: PhysAddr,
         device_area: PhysAddr,
-    ) {
+    ) -> Result {
         let mut state = self.state.lock().unwrap();
         state.queues[queue as usize].size = size;
         state.queues[queue as usize].descriptors = descriptors;
         state.queues[queue as usize].driver_area = driver_area;
         state.queues[queue as usize].device_area = device_area;
+        Ok(())
     }
 
     fn queue_unset(&mut self, queue: u16) {
@@ -97,22 +105,52 @@ impl<C> Transport for FakeTransport<C> {
         pending
     }
 
+    fn config_generation(&self) -> u32 {
+        self.state.lock().unwrap().config_generation
+    }
+
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        self.state
+            .lock()
+            .unwrap()
+            .shared_memory_regions
+            .iter()
+            .find(|(region_id, _)| *region_id == id)
+            .map(|(_, region)| *region)
+    }
+
     fn config_space<T: 'static>(&self) -> Result<NonNull<T>> {
-        if TypeId::of::<T>() == TypeId::of::<C>() {
+        // Drivers may view a prefix of the config space through a smaller type, as for fields
+        // which only exist with some features, but never more than the test provided.
+        if size_of::<T>() <= size_of::<C>() {
             Ok(self.config_space.cast())
         } else {
-            panic!("Unexpected config space type.");
+            Err(Error::ConfigSpaceTooSmall)
         }
     }
+
+    fn config_space_size(&self) -> usize {
+        size_of::<C>()
+    }
 }
 
+/// The state of a fake device, shared between the driver's transport and the test.
 #[derive(Debug, Default)]
 pub struct State {
+    /// The device status most recently set by the driver.
     pub status: DeviceStatus,
+    /// The features which the driver has accepted.
     pub driver_features: u64,
+    /// The guest page size set by the driver.
     pub guest_page_size: u32,
+    /// Whether the device has an interrupt pending for the driver to acknowledge.
     pub interrupt_pending: bool,
+    /// The configuration generation counter.
+    pub config_generation: u32,
+    /// The state of each of the device's queues.
     pub queues: Vec<QueueStatus>,
+    /// The device's shared memory regions, with their IDs.
+    pub shared_memory_regions: Vec<(u8, SharedMemoryRegion)>,
 }
 
 impl State {
@@ -176,6 +214,17 @@ impl State {
         )
     }
 
+    /// Returns whether the driver has added any buffers to the given queue which the device hasn't
+    /// used yet.
+    pub fn queue_has_available<const QUEUE_SIZE: usize>(&self, queue_index: u16) -> bool {
+        let queue = &self.queues[queue_index as usize];
+        assert_ne!(queue.descriptors, 0);
+        fake_queue_has_available::<QUEUE_SIZE>(
+            queue.driver_area as *const u8,
+            queue.device_area as *const u8,
+        )
+    }
+
     /// Waits until the given queue is notified.
     pub fn wait_until_queue_notified(state: &Mutex<Self>, queue_index: u16) {
         while !state.lock().unwrap().queues[usize::from(queue_index)]
@@ -187,11 +236,17 @@ impl State {
     }
 }
 
+/// The state of a single queue of a fake device.
 #[derive(Debug, Default)]
 pub struct QueueStatus {
+    /// The size of the queue set by the driver.
     pub size: u32,
+    /// The physical address of the descriptor table.
     pub descriptors: PhysAddr,
+    /// The physical address of the driver area (available ring).
     pub driver_area: PhysAddr,
+    /// The physical address of the device area (used ring).
     pub device_area: PhysAddr,
+    /// Whether the driver has notified the device about the queue since this was last cleared.
     pub notified: AtomicBool,
 }
@@ -1,6 +1,14 @@
 //! MMIO transport for VirtIO.
 
-use super::{DeviceStatus, DeviceType, Transport};
+#[cfg(feature = "acpi")]
+pub mod acpi;
+#[cfg(feature = "fdt")]
+pub mod fdt;
+
+use super::{
+    DeviceStatus, DeviceType, QueueNotifyAddress, SharedMemoryRegion, Transport,
+    TransportCapabilities,
+};
 use crate::{
     align_up,
     queue::Descriptor,
@@ -11,8 +19,9 @@ use core::{
     convert::{TryFrom, TryInto},
     fmt::{self, Display, Formatter},
     mem::{align_of, size_of},
-    ptr::NonNull,
+    ptr::{addr_of_mut, NonNull},
 };
+use log::warn;
 
 const MAGIC_VALUE: u32 = 0x7472_6976;
 pub(crate) const LEGACY_VERSION: u32 = 1;
@@ -41,6 +50,15 @@ impl TryFrom<u32> for MmioVersion {
     }
 }
 
+impl Display for MmioVersion {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Legacy => f.write_str("legacy"),
+            Self::Modern => f.write_str("modern"),
+        }
+    }
+}
+
 impl From<MmioVersion> for u32 {
     fn from(version: MmioVersion) -> Self {
         match version {
@@ -59,6 +77,8 @@ pub enum MmioError {
     UnsupportedVersion(u32),
     /// The header reports a device ID of 0.
     ZeroDeviceId,
+    /// The MMIO region is smaller than the register block, so can't be a VirtIO MMIO device.
+    RegionTooSmall(usize),
 }
 
 impl Display for MmioError {
@@ -73,6 +93,11 @@ impl Display for MmioError {
                 write!(f, "Unsupported Virtio MMIO version {}.", version)
             }
             Self::ZeroDeviceId => write!(f, "Device ID was zero."),
+            Self::RegionTooSmall(size) => write!(
+                f,
+                "MMIO region of {:#x} bytes is too small for the {:#x} byte register block.",
+                size, CONFIG_SPACE_OFFSET
+            ),
         }
     }
 }
@@ -215,7 +240,19 @@ pub struct VirtIOHeader {
     queue_device_high: WriteOnly<u32>,
 
     /// Reserved
-    __r9: [ReadOnly<u32>; 21],
+    __r9: ReadOnly<u32>,
+
+    /// Shared memory region ID selection
+    shm_sel: WriteOnly<u32>,
+    /// Length of the selected shared memory region, or all ones if there is no such region
+    shm_len_low: ReadOnly<u32>,
+    shm_len_high: ReadOnly<u32>,
+    /// Guest physical base address of the selected shared memory region
+    shm_base_low: ReadOnly<u32>,
+    shm_base_high: ReadOnly<u32>,
+
+    /// Reserved
+    __r10: [ReadOnly<u32>; 15],
 
     config_generation: ReadOnly<u32>,
 }
@@ -265,11 +302,78 @@ impl VirtIOHeader {
             queue_device_low: Default::default(),
             queue_device_high: Default::default(),
             __r9: Default::default(),
+            shm_sel: Default::default(),
+            shm_len_low: Default::default(),
+            shm_len_high: Default::default(),
+            shm_base_low: Default::default(),
+            shm_base_high: Default::default(),
+            __r10: Default::default(),
             config_generation: Default::default(),
         }
     }
 }
 
+/// When an [`MmioTransport`] writes `InterruptACK`, relative to reading `InterruptStatus` and
+/// returning to the driver.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub enum AckOrder {
+    /// Read `InterruptStatus`, and write the bits which were set back to `InterruptACK` if there
+    /// were any. This is what the specification describes.
+    #[default]
+    StatusThenAck,
+    /// As for `StatusThenAck`, but then read `InterruptStatus` again so that the acknowledgement
+    /// has reached the device before the driver goes on to read the used rings.
+    ///
+    /// This is for platforms which post MMIO writes, where the device may otherwise still see the
+    /// interrupt as pending once the driver has consumed the buffers, and raise it again.
+    FlushAck,
+    /// Read `InterruptStatus`, and always write the bits which were set back to `InterruptACK`,
+    /// even if there were none.
+    ///
+    /// This is for platforms whose interrupt line is not deasserted unless `InterruptACK` is
+    /// written on every interrupt, even if `InterruptStatus` reads as zero. Only bits which were
+    /// seen set are acknowledged, so an interrupt raised after the status was read isn't lost.
+    AlwaysAck,
+}
+
+/// How an [`MmioTransport`] acknowledges interrupts.
+///
+/// The default is what the specification describes: every bit set in `InterruptStatus` is
+/// acknowledged after reading it. Some hypervisors get this wrong, so it can be changed per
+/// transport instance with [`MmioTransport::set_interrupt_ack`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InterruptAck {
+    /// The bits of `InterruptStatus` to acknowledge. Other bits are never written to
+    /// `InterruptACK`, and are ignored when deciding whether there was an interrupt.
+    pub mask: u32,
+    /// When to write `InterruptACK`.
+    pub order: AckOrder,
+}
+
+impl InterruptAck {
+    /// Acknowledges every bit of `InterruptStatus`, as the specification describes.
+    pub const DEFAULT: Self = Self {
+        mask: u32::MAX,
+        order: AckOrder::StatusThenAck,
+    };
+
+    /// Only acknowledges the given bits of `InterruptStatus`.
+    pub const fn with_mask(self, mask: u32) -> Self {
+        Self { mask, ..self }
+    }
+
+    /// Acknowledges in the given order.
+    pub const fn with_order(self, order: AckOrder) -> Self {
+        Self { order, ..self }
+    }
+}
+
+impl Default for InterruptAck {
+    fn default() -> Self {
+        Self::DEFAULT
+    }
+}
+
 /// MMIO Device Register Interface.
 ///
 /// Ref: 4.2.2 MMIO Device Register Layout and 4.2.4 Legacy interface
@@ -277,16 +381,26 @@ impl VirtIOHeader {
 pub struct MmioTransport {
     header: NonNull<VirtIOHeader>,
     version: MmioVersion,
+    /// The size in bytes of the MMIO region, including the registers and config space.
+    mmio_size: usize,
+    interrupt_ack: InterruptAck,
 }
 
 impl MmioTransport {
     /// Constructs a new VirtIO MMIO transport, or returns an error if the header reports an
     /// unsupported version.
     ///
+    /// `mmio_size` is the size of the MMIO region in bytes, e.g. from the device tree. It must
+    /// cover at least the register block; config space accesses beyond it fail with
+    /// [`Error::ConfigSpaceTooSmall`] rather than reading past the end of the mapping.
+    ///
     /// # Safety
-    /// `header` must point to a properly aligned valid VirtIO MMIO region, which must remain valid
-    /// for the lifetime of the transport that is returned.
-    pub unsafe fn new(header: NonNull<VirtIOHeader>) -> Result<Self, MmioError> {
+    /// `header` must point to a properly aligned valid VirtIO MMIO region of at least `mmio_size`
+    /// bytes, which must remain valid for the lifetime of the transport that is returned.
+    pub unsafe fn new(header: NonNull<VirtIOHeader>, mmio_size: usize) -> Result<Self, MmioError> {
+        if mmio_size < CONFIG_SPACE_OFFSET {
+            return Err(MmioError::RegionTooSmall(mmio_size));
+        }
         let magic = volread!(header, magic);
         if magic != MAGIC_VALUE {
             return Err(MmioError::BadMagic(magic));
@@ -295,7 +409,23 @@ impl MmioTransport {
             return Err(MmioError::ZeroDeviceId);
         }
         let version = volread!(header, version).try_into()?;
-        Ok(Self { header, version })
+        Ok(Self {
+            header,
+            version,
+            mmio_size,
+            interrupt_ack: InterruptAck::DEFAULT,
+        })
+    }
+
+    /// Returns how the transport acknowledges interrupts.
+    pub fn interrupt_ack(&self) -> InterruptAck {
+        self.interrupt_ack
+    }
+
+    /// Changes how the transport acknowledges interrupts, to work around platforms which don't
+    /// follow the specification.
+    pub fn set_interrupt_ack(&mut self, interrupt_ack: InterruptAck) {
+        self.interrupt_ack = interrupt_ack;
     }
 
     /// Gets the version of the VirtIO MMIO transport.
@@ -317,6 +447,10 @@ impl Transport for MmioTransport {
         device_id.into()
     }
 
+    fn vendor_id(&self) -> u32 {
+        MmioTransport::vendor_id(self)
+    }
+
     fn read_device_features(&mut self) -> u64 {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
@@ -353,6 +487,17 @@ impl Transport for MmioTransport {
         }
     }
 
+    fn queue_notify_address(&mut self, queue: u16) -> Option<QueueNotifyAddress> {
+        // Safe because self.header points to a valid VirtIO MMIO region, which stays mapped while
+        // the transport is alive, and QueueNotify is a 32-bit register.
+        unsafe {
+            Some(QueueNotifyAddress::new_u32(
+                NonNull::new(addr_of_mut!((*self.header.as_ptr()).queue_notify))?.cast(),
+                queue.into(),
+            ))
+        }
+    }
+
     fn get_status(&self) -> DeviceStatus {
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe { volread!(self.header, status) }
@@ -393,23 +538,25 @@ impl Transport for MmioTransport {
         descriptors: PhysAddr,
         driver_area: PhysAddr,
         device_area: PhysAddr,
-    ) {
+    ) -> Result<(), Error> {
         match self.version {
             MmioVersion::Legacy => {
-                assert_eq!(
-                    driver_area - descriptors,
-                    size_of::<Descriptor>() * size as usize
-                );
-                assert_eq!(
-                    device_area - descriptors,
-                    align_up(
-                        size_of::<Descriptor>() * size as usize
-                            + size_of::<u16>() * (size as usize + 3)
-                    )
-                );
+                // Legacy devices only take the address of the descriptor table, and assume the
+                // rest of the queue follows it in the legacy layout.
+                if driver_area.wrapping_sub(descriptors) != size_of::<Descriptor>() * size as usize
+                    || device_area.wrapping_sub(descriptors)
+                        != align_up(
+                            size_of::<Descriptor>() * size as usize
+                                + size_of::<u16>() * (size as usize + 3),
+                        )
+                {
+                    return Err(Error::InvalidParam);
+                }
                 let align = PAGE_SIZE as u32;
                 let pfn = (descriptors / PAGE_SIZE) as u32;
-                assert_eq!(pfn as usize * PAGE_SIZE, descriptors);
+                if pfn as usize * PAGE_SIZE != descriptors {
+                    return Err(Error::Misaligned);
+                }
                 // Safe because self.header points to a valid VirtIO MMIO region.
                 unsafe {
                     volwrite!(self.header, queue_sel, queue.into());
@@ -433,6 +580,7 @@ impl Transport for MmioTransport {
                 }
             }
         }
+        Ok(())
     }
 
     fn queue_unset(&mut self, queue: u16) {
@@ -479,27 +627,85 @@ impl Transport for MmioTransport {
     }
 
     fn ack_interrupt(&mut self) -> bool {
+        let InterruptAck { mask, order } = self.interrupt_ack;
         // Safe because self.header points to a valid VirtIO MMIO region.
         unsafe {
-            let interrupt = volread!(self.header, interrupt_status);
-            if interrupt != 0 {
-                volwrite!(self.header, interrupt_ack, interrupt);
-                true
-            } else {
-                false
+            let interrupt = volread!(self.header, interrupt_status) & mask;
+            if interrupt == 0 && order != AckOrder::AlwaysAck {
+                return false;
             }
+            volwrite!(self.header, interrupt_ack, interrupt);
+            if order == AckOrder::FlushAck {
+                volread!(self.header, interrupt_status);
+            }
+            interrupt != 0
+        }
+    }
+
+    fn capabilities(&self) -> TransportCapabilities {
+        match self.version {
+            MmioVersion::Legacy => TransportCapabilities::empty(),
+            MmioVersion::Modern => TransportCapabilities::SHARED_MEMORY,
+        }
+    }
+
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        if self.version == MmioVersion::Legacy {
+            // Shared memory regions were only added in the modern interface.
+            return None;
+        }
+        // Safe because self.header points to a valid VirtIO MMIO region.
+        let (len, base) = unsafe {
+            volwrite!(self.header, shm_sel, id.into());
+            (
+                u64::from(volread!(self.header, shm_len_high)) << 32
+                    | u64::from(volread!(self.header, shm_len_low)),
+                u64::from(volread!(self.header, shm_base_high)) << 32
+                    | u64::from(volread!(self.header, shm_base_low)),
+            )
+        };
+        if len == u64::MAX {
+            return None;
+        }
+        Some(SharedMemoryRegion {
+            paddr: base as PhysAddr,
+            len,
+        })
+    }
+
+    fn config_generation(&self) -> u32 {
+        match self.version {
+            // Legacy devices don't have a generation counter.
+            MmioVersion::Legacy => 0,
+            // Safe because self.header points to a valid VirtIO MMIO region.
+            MmioVersion::Modern => unsafe { volread!(self.header, config_generation) },
         }
     }
 
     fn config_space<T>(&self) -> Result<NonNull<T>, Error> {
         if align_of::<T>() > 4 {
-            // Panic as this should only happen if the driver is written incorrectly.
-            panic!(
+            // This should only happen if the driver is written incorrectly.
+            warn!(
                 "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
                 align_of::<T>()
             );
+            return Err(Error::Misaligned);
+        }
+        if size_of::<T>() > self.mmio_size - CONFIG_SPACE_OFFSET {
+            return Err(Error::ConfigSpaceTooSmall);
         }
-        Ok(NonNull::new((self.header.as_ptr() as usize + CONFIG_SPACE_OFFSET) as _).unwrap())
+        NonNull::new((self.header.as_ptr() as usize + CONFIG_SPACE_OFFSET) as _)
+            .ok_or(Error::ConfigSpaceMissing)
+    }
+
+    fn config_space_size(&self) -> usize {
+        self.mmio_size.saturating_sub(CONFIG_SPACE_OFFSET)
+    }
+}
+
+impl Display for MmioTransport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(f, "{} ({} MMIO)", self.device_type(), self.version)
     }
 }
 
@@ -509,3 +715,210 @@ impl Drop for MmioTransport {
         self.set_status(DeviceStatus::empty())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use core::ptr::addr_of_mut;
+
+    #[test]
+    fn region_too_small() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        assert_eq!(
+            unsafe { MmioTransport::new(NonNull::from(&mut header), 0x80) }.unwrap_err(),
+            MmioError::RegionTooSmall(0x80)
+        );
+
+        // A region with only the registers has no room for config space.
+        let transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(
+            transport.config_space::<u32>(),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+    }
+
+    #[test]
+    fn queue_doorbell() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let doorbell = transport.queue_notify_address(3).unwrap();
+        let queue_notify = unsafe { addr_of_mut!((*transport.header.as_ptr()).queue_notify) };
+        assert_eq!(doorbell.address(), queue_notify as usize);
+
+        unsafe { doorbell.notify() };
+        assert_eq!(unsafe { queue_notify.cast::<u32>().read() }, 3);
+    }
+
+    #[test]
+    fn display() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 1, 0, 0, 4);
+        let transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(transport.to_string(), "virtio-net (legacy MMIO)");
+        assert_eq!(
+            (DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER).to_string(),
+            "ACKNOWLEDGE|DRIVER"
+        );
+    }
+
+    #[test]
+    fn shared_memory_region() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 26, 0, 0, 4);
+        header.shm_len_low = ReadOnly::new(0x4000_0000);
+        header.shm_len_high = ReadOnly::new(0x1);
+        header.shm_base_low = ReadOnly::new(0x8000_0000);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(
+            transport.shared_memory_region(0),
+            Some(SharedMemoryRegion {
+                paddr: 0x8000_0000,
+                len: 0x1_4000_0000,
+            })
+        );
+        drop(transport);
+
+        header.shm_len_low = ReadOnly::new(u32::MAX);
+        header.shm_len_high = ReadOnly::new(u32::MAX);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(transport.shared_memory_region(1), None);
+        assert_eq!(
+            transport.capabilities(),
+            TransportCapabilities::SHARED_MEMORY
+        );
+        drop(transport);
+
+        header.version = ReadOnly::new(LEGACY_VERSION);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(transport.capabilities(), TransportCapabilities::empty());
+        assert_eq!(transport.shared_memory_region(0), None);
+    }
+
+    #[test]
+    fn config_space_misaligned() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        let transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        assert_eq!(transport.config_space::<u64>(), Err(Error::Misaligned));
+    }
+
+    #[test]
+    fn legacy_config_fields() {
+        #[repr(C)]
+        struct LegacyDevice {
+            header: VirtIOHeader,
+            config: [u32; 3],
+        }
+
+        let mut device = LegacyDevice {
+            header: VirtIOHeader::make_fake_header(LEGACY_VERSION, 2, 0, 0, 4),
+            config: [0; 3],
+        };
+        assert_eq!(size_of::<VirtIOHeader>(), CONFIG_SPACE_OFFSET);
+        let mut transport = unsafe {
+            MmioTransport::new(NonNull::from(&mut device).cast(), size_of::<LegacyDevice>())
+        }
+        .unwrap();
+        assert_eq!(transport.config_space_size(), 12);
+
+        // Legacy devices use the guest's byte order.
+        transport
+            .write_config::<u64>(4, 0x1122_3344_5566_7788)
+            .unwrap();
+        assert_eq!(transport.read_config::<u64>(4), Ok(0x1122_3344_5566_7788));
+        assert_eq!(
+            transport.read_config::<u64>(8),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+        drop(transport);
+        let mut bytes = [0; 8];
+        bytes[..4].copy_from_slice(&device.config[1].to_ne_bytes());
+        bytes[4..].copy_from_slice(&device.config[2].to_ne_bytes());
+        assert_eq!(u64::from_ne_bytes(bytes), 0x1122_3344_5566_7788);
+    }
+
+    #[test]
+    fn legacy_queue_misaligned() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 2, 0, 0, 4);
+        let mut transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let descriptors = PAGE_SIZE + 16;
+        let driver_area = descriptors + size_of::<Descriptor>() * 4;
+        let device_area =
+            descriptors + align_up(size_of::<Descriptor>() * 4 + size_of::<u16>() * 7);
+        assert_eq!(
+            transport.queue_set(0, 4, descriptors, driver_area, device_area),
+            Err(Error::Misaligned)
+        );
+        assert_eq!(
+            transport.queue_set(0, 4, PAGE_SIZE, driver_area, device_area),
+            Err(Error::InvalidParam)
+        );
+        assert!(!transport.queue_used(0));
+    }
+
+    #[test]
+    fn interrupt_ack_mask() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        let header = NonNull::from(&mut header);
+        let mut transport =
+            unsafe { MmioTransport::new(header, size_of::<VirtIOHeader>()) }.unwrap();
+        let status = unsafe { addr_of_mut!((*header.as_ptr()).interrupt_status) } as *mut u32;
+        let ack = unsafe { addr_of_mut!((*header.as_ptr()).interrupt_ack) } as *mut u32;
+
+        // By default every bit is acknowledged.
+        unsafe { status.write_volatile(0b11) };
+        assert!(transport.ack_interrupt());
+        assert_eq!(unsafe { ack.read_volatile() }, 0b11);
+
+        // A configuration change alone is ignored once it is masked out.
+        transport.set_interrupt_ack(InterruptAck::DEFAULT.with_mask(0b01));
+        unsafe {
+            ack.write_volatile(0);
+            status.write_volatile(0b10);
+        }
+        assert!(!transport.ack_interrupt());
+        assert_eq!(unsafe { ack.read_volatile() }, 0);
+
+        unsafe { status.write_volatile(0b11) };
+        assert!(transport.ack_interrupt());
+        assert_eq!(unsafe { ack.read_volatile() }, 0b01);
+    }
+
+    #[test]
+    fn interrupt_always_ack() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0, 0, 4);
+        let header = NonNull::from(&mut header);
+        let mut transport =
+            unsafe { MmioTransport::new(header, size_of::<VirtIOHeader>()) }.unwrap();
+        transport.set_interrupt_ack(
+            InterruptAck::DEFAULT
+                .with_mask(0b01)
+                .with_order(AckOrder::AlwaysAck),
+        );
+        let status = unsafe { addr_of_mut!((*header.as_ptr()).interrupt_status) } as *mut u32;
+        let ack = unsafe { addr_of_mut!((*header.as_ptr()).interrupt_ack) } as *mut u32;
+
+        // InterruptACK is written even though no interrupt is pending, but nothing is acknowledged.
+        unsafe { ack.write_volatile(0xff) };
+        assert!(!transport.ack_interrupt());
+        assert_eq!(unsafe { ack.read_volatile() }, 0);
+
+        // Only the bits which were set and not masked out are acknowledged.
+        unsafe { status.write_volatile(0b11) };
+        assert!(transport.ack_interrupt());
+        assert_eq!(unsafe { ack.read_volatile() }, 0b01);
+    }
+}
@@ -0,0 +1,271 @@
+//! Discovery of VirtIO MMIO devices described by ACPI.
+//!
+//! On ACPI-based arm64 VMs, such as those run by Cloud Hypervisor, VirtIO MMIO devices appear in
+//! the DSDT as devices with hardware ID [`VIRTIO_MMIO_HID`]. Evaluating their `_CRS` method with
+//! an AML interpreter gives a resource template buffer, which [`AcpiMmioDevice::from_resources`]
+//! turns into the device's register block and interrupt:
+//!
+//! ```
+//! use virtio_drivers::{transport::mmio::acpi::AcpiMmioDevice, Hal};
+//!
+//! # fn example<HalImpl: Hal>(crs: &[u8]) {
+//! let device = AcpiMmioDevice::from_resources(crs).unwrap();
+//! // Safe because the DSDT describes a real device which nothing else is using.
+//! let transport = unsafe { device.transport::<HalImpl>() }.unwrap();
+//! // Set up a driver for the transport, and route `device.gsi` to it.
+//! # }
+//! ```
+
+use super::{MmioError, MmioTransport};
+use crate::{Hal, PhysAddr};
+use core::{
+    convert::{TryFrom, TryInto},
+    fmt::{self, Display, Formatter},
+};
+
+/// The ACPI hardware ID (`_HID`) of VirtIO MMIO devices.
+pub const VIRTIO_MMIO_HID: &str = "LNRO0005";
+
+/// Small resource descriptor: IRQ.
+const SMALL_IRQ: u8 = 0x04;
+/// Small resource descriptor: end tag.
+const SMALL_END_TAG: u8 = 0x0f;
+/// Large resource descriptor: 32-bit memory range.
+const LARGE_MEMORY32: u8 = 0x05;
+/// Large resource descriptor: 32-bit fixed memory range.
+const LARGE_FIXED_MEMORY32: u8 = 0x06;
+/// Large resource descriptor: DWord address space.
+const LARGE_DWORD_ADDRESS: u8 = 0x07;
+/// Large resource descriptor: extended interrupt.
+const LARGE_EXTENDED_IRQ: u8 = 0x09;
+/// Large resource descriptor: QWord address space.
+const LARGE_QWORD_ADDRESS: u8 = 0x0a;
+
+/// The resource type of an address space descriptor for memory.
+const ADDRESS_SPACE_MEMORY: u8 = 0;
+
+/// An error parsing the resources of a VirtIO MMIO device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum AcpiError {
+    /// A resource descriptor runs past the end of the buffer.
+    Truncated,
+    /// There is no memory resource for the device's register block.
+    MissingMemory,
+    /// The register block doesn't fit in the address space.
+    AddressOutOfRange(u64),
+}
+
+impl Display for AcpiError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Truncated => write!(f, "ACPI resource descriptor is truncated."),
+            Self::MissingMemory => write!(f, "No memory resource for the MMIO register block."),
+            Self::AddressOutOfRange(address) => {
+                write!(f, "MMIO register block at {:#x} is out of range.", address)
+            }
+        }
+    }
+}
+
+/// A VirtIO MMIO device described by ACPI.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct AcpiMmioDevice {
+    /// The physical address of the device's register block.
+    pub paddr: PhysAddr,
+    /// The size of the device's register block in bytes.
+    pub size: usize,
+    /// The global system interrupt of the device, if it has one.
+    pub gsi: Option<u32>,
+}
+
+impl AcpiMmioDevice {
+    /// Finds the register block and interrupt of a device from the resource template returned by
+    /// its `_CRS` method.
+    ///
+    /// The first memory resource is used for the register block, and the first interrupt for the
+    /// GSI. Memory ranges may be given as fixed or ranged 32-bit memory descriptors, or DWord or
+    /// QWord memory address space descriptors; interrupts as extended or legacy IRQ descriptors.
+    pub fn from_resources(resources: &[u8]) -> Result<Self, AcpiError> {
+        let mut memory = None;
+        let mut gsi = None;
+        let mut offset = 0;
+        while let Some(&tag) = resources.get(offset) {
+            let (kind, body, len) = if tag & 0x80 == 0 {
+                // Small descriptor, with the length in the tag byte.
+                let len = usize::from(tag & 0x07);
+                let body = resources
+                    .get(offset + 1..offset + 1 + len)
+                    .ok_or(AcpiError::Truncated)?;
+                (Descriptor::Small((tag >> 3) & 0x0f), body, 1 + len)
+            } else {
+                let len = resources
+                    .get(offset + 1..offset + 3)
+                    .ok_or(AcpiError::Truncated)?;
+                let len = usize::from(u16::from_le_bytes([len[0], len[1]]));
+                let body = resources
+                    .get(offset + 3..offset + 3 + len)
+                    .ok_or(AcpiError::Truncated)?;
+                (Descriptor::Large(tag & 0x7f), body, 3 + len)
+            };
+            offset += len;
+
+            match kind {
+                Descriptor::Small(SMALL_END_TAG) => break,
+                Descriptor::Small(SMALL_IRQ) if gsi.is_none() => {
+                    let mask = u16::from_le_bytes(field(body, 0)?);
+                    if mask != 0 {
+                        gsi = Some(mask.trailing_zeros());
+                    }
+                }
+                Descriptor::Large(LARGE_EXTENDED_IRQ) if gsi.is_none() => {
+                    let count: [u8; 1] = field(body, 1)?;
+                    if count[0] > 0 {
+                        gsi = Some(u32::from_le_bytes(field(body, 2)?));
+                    }
+                }
+                Descriptor::Large(LARGE_FIXED_MEMORY32) if memory.is_none() => {
+                    memory = Some((
+                        u32::from_le_bytes(field(body, 1)?).into(),
+                        u32::from_le_bytes(field(body, 5)?).into(),
+                    ));
+                }
+                Descriptor::Large(LARGE_MEMORY32) if memory.is_none() => {
+                    memory = Some((
+                        u32::from_le_bytes(field(body, 1)?).into(),
+                        u32::from_le_bytes(field(body, 13)?).into(),
+                    ));
+                }
+                Descriptor::Large(LARGE_DWORD_ADDRESS)
+                    if memory.is_none() && body.first() == Some(&ADDRESS_SPACE_MEMORY) =>
+                {
+                    memory = Some((
+                        u32::from_le_bytes(field(body, 7)?).into(),
+                        u32::from_le_bytes(field(body, 19)?).into(),
+                    ));
+                }
+                Descriptor::Large(LARGE_QWORD_ADDRESS)
+                    if memory.is_none() && body.first() == Some(&ADDRESS_SPACE_MEMORY) =>
+                {
+                    memory = Some((
+                        u64::from_le_bytes(field(body, 11)?),
+                        u64::from_le_bytes(field(body, 35)?),
+                    ));
+                }
+                _ => {}
+            }
+        }
+
+        let (paddr, size): (u64, u64) = memory.ok_or(AcpiError::MissingMemory)?;
+        Ok(Self {
+            paddr: PhysAddr::try_from(paddr).map_err(|_| AcpiError::AddressOutOfRange(paddr))?,
+            size: usize::try_from(size).map_err(|_| AcpiError::AddressOutOfRange(paddr))?,
+            gsi,
+        })
+    }
+
+    /// Maps the device's register block with [`Hal::mmio_phys_to_virt`] and creates an MMIO
+    /// transport for it.
+    ///
+    /// # Safety
+    ///
+    /// The resources must describe a real VirtIO MMIO device, whose registers aren't accessed
+    /// through any other means for the lifetime of the transport.
+    pub unsafe fn transport<H: Hal>(&self) -> Result<MmioTransport, MmioError> {
+        let header = H::mmio_phys_to_virt(self.paddr, self.size).cast();
+        MmioTransport::new(header, self.size)
+    }
+}
+
+/// The type of a resource descriptor.
+enum Descriptor {
+    /// A small descriptor, with its tag.
+    Small(u8),
+    /// A large descriptor, with its type.
+    Large(u8),
+}
+
+/// Reads the `N` byte field at the given offset in a descriptor body.
+fn field<const N: usize>(body: &[u8], offset: usize) -> Result<[u8; N], AcpiError> {
+    body.get(offset..offset + N)
+        .and_then(|bytes| bytes.try_into().ok())
+        .ok_or(AcpiError::Truncated)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const END_TAG: [u8; 2] = [0x79, 0x00];
+
+    /// Returns a large descriptor with the given type and body.
+    fn large(kind: u8, body: &[u8]) -> Vec<u8> {
+        let mut descriptor = vec![0x80 | kind];
+        descriptor.extend_from_slice(&(body.len() as u16).to_le_bytes());
+        descriptor.extend_from_slice(body);
+        descriptor
+    }
+
+    #[test]
+    fn fixed_memory_and_extended_irq() {
+        // Memory32Fixed (ReadWrite, 0x0a000000, 0x200) and Interrupt (..., 0x20), as Cloud
+        // Hypervisor generates.
+        let mut memory = vec![1];
+        memory.extend_from_slice(&0x0a00_0000u32.to_le_bytes());
+        memory.extend_from_slice(&0x200u32.to_le_bytes());
+        let mut irq = vec![0x0b, 1];
+        irq.extend_from_slice(&0x20u32.to_le_bytes());
+        let mut resources = large(LARGE_FIXED_MEMORY32, &memory);
+        resources.extend(large(LARGE_EXTENDED_IRQ, &irq));
+        resources.extend_from_slice(&END_TAG);
+
+        assert_eq!(
+            AcpiMmioDevice::from_resources(&resources),
+            Ok(AcpiMmioDevice {
+                paddr: 0x0a00_0000,
+                size: 0x200,
+                gsi: Some(0x20),
+            })
+        );
+    }
+
+    #[test]
+    fn qword_memory_and_legacy_irq() {
+        // QWordMemory with minimum 0x1_0000_0000 and length 0x1000.
+        let mut memory = vec![ADDRESS_SPACE_MEMORY, 0, 0];
+        for value in [0u64, 0x1_0000_0000, 0x1_0000_0fff, 0, 0x1000] {
+            memory.extend_from_slice(&value.to_le_bytes());
+        }
+        let mut resources = vec![0x22, 0x20, 0x00];
+        resources.extend(large(LARGE_QWORD_ADDRESS, &memory));
+        resources.extend_from_slice(&END_TAG);
+
+        let device = AcpiMmioDevice::from_resources(&resources);
+        if cfg!(target_pointer_width = "64") {
+            assert_eq!(
+                device,
+                Ok(AcpiMmioDevice {
+                    paddr: 0x1_0000_0000,
+                    size: 0x1000,
+                    gsi: Some(5),
+                })
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_resources() {
+        assert_eq!(
+            AcpiMmioDevice::from_resources(&END_TAG),
+            Err(AcpiError::MissingMemory)
+        );
+        let resources = large(LARGE_FIXED_MEMORY32, &[1, 0, 0]);
+        assert_eq!(
+            AcpiMmioDevice::from_resources(&resources),
+            Err(AcpiError::Truncated)
+        );
+        assert_eq!(
+            AcpiMmioDevice::from_resources(&resources[..4]),
+            Err(AcpiError::Truncated)
+        );
+    }
+}
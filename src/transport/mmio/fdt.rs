@@ -0,0 +1,516 @@
+//! A minimal flattened device tree walker, to find VirtIO MMIO devices.
+//!
+//! Kernels on RISC-V and Arm usually discover VirtIO MMIO devices from the device tree passed by
+//! the bootloader or VMM. This module finds all the `virtio,mmio` nodes in a device tree blob
+//! without any further dependencies, so that each kernel doesn't need its own parsing:
+//!
+//! ```
+//! use virtio_drivers::{transport::mmio::fdt::Fdt, Hal};
+//!
+//! # fn example<HalImpl: Hal>(dtb: &[u8]) {
+//! let fdt = Fdt::new(dtb).unwrap();
+//! for node in fdt.virtio_mmio_nodes() {
+//!     // Safe because the device tree describes real devices which nothing else is using.
+//!     match unsafe { node.transport::<HalImpl>() } {
+//!         Ok(transport) => {
+//!             let irq = node.interrupt_cells().next();
+//!             // Set up a driver for the transport, and route `irq` to it.
+//!         }
+//!         // There may be placeholder nodes with no device behind them.
+//!         Err(e) => {}
+//!     }
+//! }
+//! # }
+//! ```
+
+use super::{MmioError, MmioTransport};
+use crate::{Hal, PhysAddr};
+use core::{
+    convert::{TryFrom, TryInto},
+    fmt::{self, Display, Formatter},
+    slice, str,
+};
+use log::warn;
+
+const FDT_MAGIC: u32 = 0xd00d_feed;
+const HEADER_SIZE: usize = 40;
+
+const FDT_BEGIN_NODE: u32 = 1;
+const FDT_END_NODE: u32 = 2;
+const FDT_PROP: u32 = 3;
+const FDT_NOP: u32 = 4;
+const FDT_END: u32 = 9;
+
+/// The maximum nesting depth of nodes which the walker supports.
+const MAX_DEPTH: usize = 16;
+
+/// The default `#address-cells` and `#size-cells`, if a node doesn't specify them.
+const DEFAULT_CELLS: (u32, u32) = (2, 1);
+
+/// An error parsing a device tree blob.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum FdtError {
+    /// The blob doesn't start with the expected magic value 0xd00dfeed.
+    BadMagic(u32),
+    /// The blob is shorter than its header says, or a block lies outside it.
+    Truncated,
+}
+
+impl Display for FdtError {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::BadMagic(magic) => write!(
+                f,
+                "Invalid device tree magic value {:#010x} (expected 0xd00dfeed).",
+                magic
+            ),
+            Self::Truncated => write!(f, "Device tree blob is truncated."),
+        }
+    }
+}
+
+/// A flattened device tree blob.
+#[derive(Copy, Clone, Debug)]
+pub struct Fdt<'a> {
+    /// The structure block.
+    structure: &'a [u8],
+    /// The strings block, holding property names.
+    strings: &'a [u8],
+}
+
+impl<'a> Fdt<'a> {
+    /// Parses the header of the given device tree blob.
+    pub fn new(data: &'a [u8]) -> Result<Self, FdtError> {
+        let magic = be32(data, 0).ok_or(FdtError::Truncated)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let header = |offset| be32(data, offset).ok_or(FdtError::Truncated);
+        let data = data.get(..header(4)? as usize).ok_or(FdtError::Truncated)?;
+        let block = |offset: u32, size: u32| {
+            let start = offset as usize;
+            data.get(start..start.saturating_add(size as usize))
+                .ok_or(FdtError::Truncated)
+        };
+        Ok(Self {
+            structure: block(header(8)?, header(36)?)?,
+            strings: block(header(12)?, header(32)?)?,
+        })
+    }
+
+    /// Parses the device tree blob at the given address, e.g. as passed by the bootloader.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to a valid device tree blob, which must stay valid and unmodified for the
+    /// lifetime `'a`.
+    pub unsafe fn from_ptr(ptr: *const u8) -> Result<Self, FdtError> {
+        let header = slice::from_raw_parts(ptr, HEADER_SIZE);
+        let magic = be32(header, 0).ok_or(FdtError::Truncated)?;
+        if magic != FDT_MAGIC {
+            return Err(FdtError::BadMagic(magic));
+        }
+        let total_size = be32(header, 4).ok_or(FdtError::Truncated)?;
+        Self::new(slice::from_raw_parts(ptr, total_size as usize))
+    }
+
+    /// Returns an iterator over all the enabled nodes compatible with `virtio,mmio`.
+    ///
+    /// If the structure block turns out to be malformed then a warning is logged, and the
+    /// iterator stops at that point.
+    pub fn virtio_mmio_nodes(&self) -> VirtioMmioNodes<'a> {
+        VirtioMmioNodes {
+            fdt: *self,
+            offset: 0,
+            cells: [DEFAULT_CELLS; MAX_DEPTH],
+            depth: 0,
+            current: None,
+            done: false,
+        }
+    }
+
+    /// Returns the NUL-terminated string at the given offset in the strings block.
+    fn string(&self, offset: u32) -> Option<&'a [u8]> {
+        let rest = self.strings.get(offset as usize..)?;
+        let len = rest.iter().position(|&b| b == 0)?;
+        Some(&rest[..len])
+    }
+}
+
+/// A VirtIO MMIO device found in a device tree.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct VirtioMmioNode<'a> {
+    /// The name of the node, e.g. `virtio_mmio@10001000`.
+    pub name: &'a str,
+    /// The physical address of the device's register block, from the first entry of `reg`.
+    pub paddr: PhysAddr,
+    /// The size of the device's register block in bytes, from the first entry of `reg`.
+    pub size: usize,
+    /// The raw value of the `interrupts` property.
+    interrupts: &'a [u8],
+}
+
+impl<'a> VirtioMmioNode<'a> {
+    /// Returns the cells of the node's `interrupts` property.
+    ///
+    /// How many cells make up an interrupt specifier depends on the interrupt controller. For
+    /// example a RISC-V PLIC uses a single cell with the IRQ number, while an Arm GIC uses three,
+    /// with the interrupt number in the second.
+    pub fn interrupt_cells(&self) -> impl Iterator<Item = u32> + 'a {
+        self.interrupts
+            .chunks_exact(4)
+            .map(|cell| u32::from_be_bytes([cell[0], cell[1], cell[2], cell[3]]))
+    }
+
+    /// Maps the device's register block with [`Hal::mmio_phys_to_virt`] and creates an MMIO
+    /// transport for it.
+    ///
+    /// Returns [`MmioError::ZeroDeviceId`] for placeholder nodes which have no device behind them,
+    /// as VMMs such as QEMU often create many of these.
+    ///
+    /// # Safety
+    ///
+    /// The node must describe a real VirtIO MMIO device, whose registers aren't accessed through
+    /// any other means for the lifetime of the transport.
+    pub unsafe fn transport<H: Hal>(&self) -> Result<MmioTransport, MmioError> {
+        let header = H::mmio_phys_to_virt(self.paddr, self.size).cast();
+        MmioTransport::new(header, self.size)
+    }
+}
+
+/// An iterator over the `virtio,mmio` nodes of a device tree, returned by
+/// [`Fdt::virtio_mmio_nodes`].
+#[derive(Clone, Debug)]
+pub struct VirtioMmioNodes<'a> {
+    fdt: Fdt<'a>,
+    /// The offset of the next token in the structure block.
+    offset: usize,
+    /// The `#address-cells` and `#size-cells` of each open node, which apply to its children.
+    cells: [(u32, u32); MAX_DEPTH],
+    /// The number of open nodes.
+    depth: usize,
+    /// The properties seen so far of the most recently opened node, until its first child or
+    /// its end.
+    current: Option<NodeProperties<'a>>,
+    done: bool,
+}
+
+/// The properties of a node which matter for finding VirtIO MMIO devices.
+#[derive(Clone, Debug)]
+struct NodeProperties<'a> {
+    name: &'a str,
+    /// The `#address-cells` and `#size-cells` of the parent node.
+    parent_cells: (u32, u32),
+    compatible: bool,
+    enabled: bool,
+    reg: Option<&'a [u8]>,
+    interrupts: &'a [u8],
+}
+
+impl<'a> NodeProperties<'a> {
+    /// Returns the node as a VirtIO MMIO device, if it is an enabled one with a usable `reg`.
+    fn into_virtio_mmio_node(self) -> Option<VirtioMmioNode<'a>> {
+        if !self.compatible || !self.enabled {
+            return None;
+        }
+        let (address_cells, size_cells) = self.parent_cells;
+        let reg = self.reg?;
+        let address_len = address_cells as usize * 4;
+        let paddr = read_cells(reg.get(..address_len)?)?;
+        let size = read_cells(reg.get(address_len..address_len + size_cells as usize * 4)?)?;
+        Some(VirtioMmioNode {
+            name: self.name,
+            paddr: PhysAddr::try_from(paddr).ok()?,
+            size: usize::try_from(size).ok()?,
+            interrupts: self.interrupts,
+        })
+    }
+}
+
+impl<'a> VirtioMmioNodes<'a> {
+    /// Reads the next token and handles it, returning a node if one has finished.
+    ///
+    /// Returns `Err` if the structure block is malformed.
+    fn step(&mut self) -> Result<Option<VirtioMmioNode<'a>>, &'static str> {
+        let structure = self.fdt.structure;
+        let token = be32(structure, self.offset).ok_or("missing FDT_END")?;
+        self.offset += 4;
+        match token {
+            FDT_BEGIN_NODE => {
+                let rest = structure.get(self.offset..).ok_or("truncated node")?;
+                let name_len = rest
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or("unterminated node name")?;
+                let name = str::from_utf8(&rest[..name_len]).unwrap_or_default();
+                self.offset = align4(self.offset + name_len + 1);
+
+                let finished = self.finish_current();
+                if self.depth == MAX_DEPTH {
+                    return Err("nodes nested too deeply");
+                }
+                let parent_cells = match self.depth {
+                    0 => DEFAULT_CELLS,
+                    depth => self.cells[depth - 1],
+                };
+                self.cells[self.depth] = DEFAULT_CELLS;
+                self.depth += 1;
+                self.current = Some(NodeProperties {
+                    name,
+                    parent_cells,
+                    compatible: false,
+                    enabled: true,
+                    reg: None,
+                    interrupts: &[],
+                });
+                Ok(finished)
+            }
+            FDT_END_NODE => {
+                let finished = self.finish_current();
+                self.depth = self.depth.checked_sub(1).ok_or("unbalanced FDT_END_NODE")?;
+                Ok(finished)
+            }
+            FDT_PROP => {
+                let len = be32(structure, self.offset).ok_or("truncated property")? as usize;
+                let name_offset = be32(structure, self.offset + 4).ok_or("truncated property")?;
+                let start = self.offset + 8;
+                let value = structure
+                    .get(start..start + len)
+                    .ok_or("truncated property")?;
+                self.offset = align4(start + len);
+                let name = self
+                    .fdt
+                    .string(name_offset)
+                    .ok_or("bad property name offset")?;
+                self.property(name, value);
+                Ok(None)
+            }
+            FDT_NOP => Ok(None),
+            FDT_END => {
+                self.done = true;
+                Ok(None)
+            }
+            _ => Err("unknown token"),
+        }
+    }
+
+    /// Records a property of the current node.
+    fn property(&mut self, name: &[u8], value: &'a [u8]) {
+        // Properties after a node's first child are invalid, so ignore them.
+        let Some(current) = &mut self.current else {
+            return;
+        };
+        let cell = || Some(u32::from_be_bytes(value.try_into().ok()?));
+        match name {
+            b"compatible" => {
+                current.compatible = value.split(|&b| b == 0).any(|s| s == b"virtio,mmio");
+            }
+            b"status" => {
+                current.enabled = matches!(value, b"okay\0" | b"ok\0");
+            }
+            b"reg" => current.reg = Some(value),
+            b"interrupts" => current.interrupts = value,
+            b"#address-cells" => {
+                if let Some(cells) = cell() {
+                    self.cells[self.depth - 1].0 = cells;
+                }
+            }
+            b"#size-cells" => {
+                if let Some(cells) = cell() {
+                    self.cells[self.depth - 1].1 = cells;
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// Finishes reading the properties of the current node, and returns it if it is a VirtIO MMIO
+    /// device.
+    fn finish_current(&mut self) -> Option<VirtioMmioNode<'a>> {
+        self.current.take()?.into_virtio_mmio_node()
+    }
+}
+
+impl<'a> Iterator for VirtioMmioNodes<'a> {
+    type Item = VirtioMmioNode<'a>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.done {
+            match self.step() {
+                Ok(Some(node)) => return Some(node),
+                Ok(None) => {}
+                Err(e) => {
+                    warn!(
+                        "Malformed device tree at structure offset {:#x}: {}",
+                        self.offset, e
+                    );
+                    self.done = true;
+                }
+            }
+        }
+        None
+    }
+}
+
+/// Reads the big-endian `u32` at the given offset, if it is in bounds.
+fn be32(data: &[u8], offset: usize) -> Option<u32> {
+    let bytes = data.get(offset..offset.checked_add(4)?)?;
+    Some(u32::from_be_bytes(bytes.try_into().ok()?))
+}
+
+/// Reads a number made of one or two big-endian cells.
+fn read_cells(cells: &[u8]) -> Option<u64> {
+    match cells.len() {
+        0 => Some(0),
+        4 => be32(cells, 0).map(u64::from),
+        8 => Some(u64::from(be32(cells, 0)?) << 32 | u64::from(be32(cells, 4)?)),
+        _ => None,
+    }
+}
+
+/// Rounds the given offset up to the next multiple of 4.
+fn align4(offset: usize) -> usize {
+    (offset + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Builds device tree blobs for tests.
+    #[derive(Default)]
+    struct FdtBuilder {
+        structure: Vec<u8>,
+        strings: Vec<u8>,
+    }
+
+    impl FdtBuilder {
+        fn token(&mut self, token: u32) -> &mut Self {
+            self.structure.extend_from_slice(&token.to_be_bytes());
+            self
+        }
+
+        fn pad(&mut self) {
+            while !self.structure.len().is_multiple_of(4) {
+                self.structure.push(0);
+            }
+        }
+
+        fn begin(&mut self, name: &str) -> &mut Self {
+            self.token(FDT_BEGIN_NODE);
+            self.structure.extend_from_slice(name.as_bytes());
+            self.structure.push(0);
+            self.pad();
+            self
+        }
+
+        fn end(&mut self) -> &mut Self {
+            self.token(FDT_END_NODE)
+        }
+
+        fn prop(&mut self, name: &str, value: &[u8]) -> &mut Self {
+            let name_offset = self.strings.len() as u32;
+            self.strings.extend_from_slice(name.as_bytes());
+            self.strings.push(0);
+            self.token(FDT_PROP)
+                .token(value.len() as u32)
+                .token(name_offset);
+            self.structure.extend_from_slice(value);
+            self.pad();
+            self
+        }
+
+        fn cells(&mut self, name: &str, cells: &[u32]) -> &mut Self {
+            let value: Vec<u8> = cells.iter().flat_map(|cell| cell.to_be_bytes()).collect();
+            self.prop(name, &value)
+        }
+
+        fn build(&mut self) -> Vec<u8> {
+            self.token(FDT_END);
+            let struct_offset = HEADER_SIZE as u32;
+            let strings_offset = struct_offset + self.structure.len() as u32;
+            let total_size = strings_offset + self.strings.len() as u32;
+            let header = [
+                FDT_MAGIC,
+                total_size,
+                struct_offset,
+                strings_offset,
+                0,
+                17,
+                16,
+                0,
+                self.strings.len() as u32,
+                self.structure.len() as u32,
+            ];
+            let mut blob: Vec<u8> = header.iter().flat_map(|x| x.to_be_bytes()).collect();
+            blob.extend_from_slice(&self.structure);
+            blob.extend_from_slice(&self.strings);
+            blob
+        }
+    }
+
+    #[test]
+    fn finds_virtio_mmio_nodes() {
+        let blob = FdtBuilder::default()
+            .begin("")
+            .cells("#address-cells", &[2])
+            .cells("#size-cells", &[2])
+            .begin("soc")
+            .cells("#address-cells", &[1])
+            .cells("#size-cells", &[1])
+            .begin("virtio_mmio@10001000")
+            .cells("interrupts", &[1])
+            .cells("reg", &[0x1000_1000, 0x1000])
+            .prop("compatible", b"virtio,mmio\0")
+            .end()
+            .begin("virtio_mmio@10002000")
+            .prop("status", b"disabled\0")
+            .cells("reg", &[0x1000_2000, 0x1000])
+            .prop("compatible", b"virtio,mmio\0")
+            .end()
+            .begin("uart@10000000")
+            .cells("reg", &[0x1000_0000, 0x100])
+            .prop("compatible", b"ns16550a\0")
+            .end()
+            .end()
+            .begin("virtio_mmio@a000000")
+            .prop("compatible", b"vendor,thing\0virtio,mmio\0")
+            .cells("reg", &[0, 0xa00_0000, 0, 0x200])
+            .cells("interrupts", &[0, 16, 1])
+            .prop("status", b"okay\0")
+            .end()
+            .end()
+            .build();
+
+        let fdt = Fdt::new(&blob).unwrap();
+        let nodes: Vec<_> = fdt.virtio_mmio_nodes().collect();
+        assert_eq!(nodes.len(), 2);
+        assert_eq!(nodes[0].name, "virtio_mmio@10001000");
+        assert_eq!(nodes[0].paddr, 0x1000_1000);
+        assert_eq!(nodes[0].size, 0x1000);
+        assert_eq!(nodes[0].interrupt_cells().collect::<Vec<_>>(), [1]);
+        assert_eq!(nodes[1].name, "virtio_mmio@a000000");
+        assert_eq!(nodes[1].paddr, 0xa00_0000);
+        assert_eq!(nodes[1].size, 0x200);
+        assert_eq!(nodes[1].interrupt_cells().collect::<Vec<_>>(), [0, 16, 1]);
+    }
+
+    #[test]
+    fn invalid_blobs() {
+        assert_eq!(Fdt::new(&[0; 8]).unwrap_err(), FdtError::BadMagic(0));
+        let mut blob = FdtBuilder::default().begin("").end().build();
+        assert_eq!(
+            Fdt::new(&blob[..blob.len() - 1]).unwrap_err(),
+            FdtError::Truncated
+        );
+
+        // A structure block without an end stops the iterator rather than panicking.
+        blob.truncate(blob.len() - 4);
+        let len = blob.len() as u32;
+        blob[4..8].copy_from_slice(&len.to_be_bytes());
+        blob[12..16].copy_from_slice(&len.to_be_bytes());
+        blob[36..40].copy_from_slice(&(len - HEADER_SIZE as u32).to_be_bytes());
+        let fdt = Fdt::new(&blob).unwrap();
+        assert_eq!(fdt.virtio_mmio_nodes().count(), 0);
+    }
+}
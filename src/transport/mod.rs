@@ -1,13 +1,36 @@
 //! VirtIO transports.
+//!
+//! Besides the standard MMIO and PCI transports, the drivers can be used over any transport which
+//! implements the [`Transport`] trait. The [`software`] module provides a reference implementation
+//! for devices implemented in software on another core, communicating through shared memory.
+
+// Transports are used on every I/O path, so they must report errors rather than panicking.
+#![cfg_attr(
+    not(test),
+    deny(clippy::panic, clippy::unwrap_used, clippy::expect_used)
+)]
 
 #[cfg(test)]
 pub mod fake;
 pub mod mmio;
 pub mod pci;
-
-use crate::{PhysAddr, Result, PAGE_SIZE};
+pub mod quirks;
+#[cfg(feature = "alloc")]
+pub mod report;
+pub mod software;
+
+use crate::{
+    config::{self, ConfigField},
+    device::common::Feature,
+    display::{impl_flags_display, FlagNames},
+    PhysAddr, Result, PAGE_SIZE,
+};
 use bitflags::{bitflags, Flags};
-use core::{fmt::Debug, ops::BitAnd, ptr::NonNull};
+use core::{
+    fmt::{self, Debug, Display, Formatter},
+    ops::BitAnd,
+    ptr::NonNull,
+};
 use log::debug;
 
 /// A VirtIO transport layer.
@@ -15,6 +38,13 @@ pub trait Transport {
     /// Gets the device type.
     fn device_type(&self) -> DeviceType;
 
+    /// Gets the vendor ID of the device, or 0 if the transport doesn't provide one.
+    ///
+    /// For PCI this is the subsystem vendor ID, as the PCI vendor ID is always that of VirtIO.
+    fn vendor_id(&self) -> u32 {
+        0
+    }
+
     /// Reads device features.
     fn read_device_features(&mut self) -> u64;
 
@@ -27,6 +57,31 @@ pub trait Transport {
     /// Notifies the given queue on the device.
     fn notify(&mut self, queue: u16);
 
+    /// Returns the doorbell for the given queue, if the transport notifies it with a single MMIO
+    /// write, so that drivers can look it up once after [`queue_set`](Self::queue_set) rather than
+    /// on every notification.
+    ///
+    /// For example, a modern PCI device places each queue's doorbell at its own offset in the
+    /// notification region, scaled by `notify_off_multiplier`, which the transport would otherwise
+    /// have to read back from the device every time. Writing to the doorbell must have the same
+    /// effect as [`notify`](Self::notify). The default implementation returns `None`, so the queue
+    /// is always notified through `notify`.
+    fn queue_notify_address(&mut self, queue: u16) -> Option<QueueNotifyAddress> {
+        let _ = queue;
+        None
+    }
+
+    /// Notifies several queues on the device in one go, e.g. after a driver has refilled its
+    /// receive queue and added packets to its transmit queue in the same pass.
+    ///
+    /// The default implementation notifies each queue in turn; transports which can combine
+    /// doorbell writes may override it.
+    fn notify_multi(&mut self, queues: &[u16]) {
+        for &queue in queues {
+            self.notify(queue);
+        }
+    }
+
     /// Gets the device status.
     fn get_status(&self) -> DeviceStatus;
 
@@ -42,6 +97,10 @@ pub trait Transport {
     fn requires_legacy_layout(&self) -> bool;
 
     /// Sets up the given queue.
+    ///
+    /// Returns [`Error::InvalidParam`](crate::Error::InvalidParam) or
+    /// [`Error::Misaligned`](crate::Error::Misaligned) if the transport can't use the given
+    /// layout, in which case the queue is left unset.
     fn queue_set(
         &mut self,
         queue: u16,
@@ -49,7 +108,7 @@ pub trait Transport {
         descriptors: PhysAddr,
         driver_area: PhysAddr,
         device_area: PhysAddr,
-    );
+    ) -> Result;
 
     /// Disables and resets the given queue.
     fn queue_unset(&mut self, queue: u16);
@@ -59,9 +118,37 @@ pub trait Transport {
 
     /// Acknowledges an interrupt.
     ///
-    /// Returns true on success.
+    /// Returns true if the device had raised an interrupt which this acknowledged. Interrupts
+    /// delivered through an MSI-X vector need no acknowledgement, so this returns false for them.
     fn ack_interrupt(&mut self) -> bool;
 
+    /// Returns the location of the device's shared memory region with the given ID, or `None` if
+    /// the device has no such region or the transport doesn't support shared memory.
+    ///
+    /// The region is typically mapped by the driver with
+    /// [`Hal::mmio_phys_to_virt`](crate::Hal::mmio_phys_to_virt). Ref: 2.10 Shared Memory Regions
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        let _ = id;
+        None
+    }
+
+    /// Returns which optional operations the transport supports for this device.
+    ///
+    /// Drivers can use this to adapt at runtime to the transport they are given, rather than
+    /// trying operations and handling the failures.
+    fn capabilities(&self) -> TransportCapabilities {
+        TransportCapabilities::empty()
+    }
+
+    /// Returns the device's configuration generation counter, which changes whenever the device
+    /// changes its configuration space.
+    ///
+    /// Transports without a generation counter, such as legacy MMIO, always return 0. See
+    /// [`read_config_consistent`](crate::config::read_config_consistent).
+    fn config_generation(&self) -> u32 {
+        0
+    }
+
     /// Begins initializing the device.
     ///
     /// Ref: virtio 3.1.1 Device Initialization
@@ -74,10 +161,16 @@ pub trait Transport {
         self.set_status(DeviceStatus::empty());
         self.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
 
-        let device_features = F::from_bits_truncate(self.read_device_features());
-        debug!("Device features: {:?}", device_features);
-        let negotiated_features = device_features & supported_features;
-        self.write_driver_features(negotiated_features.bits());
+        let device_features_bits = self.read_device_features();
+        let device_features = F::from_bits_truncate(device_features_bits);
+        debug!("Device features: {}", FlagNames(&device_features));
+        let driver_features = driver_features(
+            device_features_bits,
+            (device_features & supported_features).bits(),
+            self.requires_legacy_layout(),
+        );
+        self.write_driver_features(driver_features);
+        let negotiated_features = F::from_bits_truncate(driver_features);
 
         self.set_status(
             DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER | DeviceStatus::FEATURES_OK,
@@ -99,9 +192,175 @@ pub trait Transport {
     }
 
     /// Gets the pointer to the config space.
+    ///
+    /// Returns [`Error::Misaligned`](crate::Error::Misaligned) if `T` needs more than the 4 byte
+    /// alignment which VirtIO guarantees.
     fn config_space<T: 'static>(&self) -> Result<NonNull<T>>;
+
+    /// Returns the size of the device's configuration space in bytes, or 0 if it has none.
+    ///
+    /// [`read_config`](Self::read_config) and [`write_config`](Self::write_config) only access
+    /// fields within this size. The default implementation returns 0, so transports which don't
+    /// override it can't use them.
+    fn config_space_size(&self) -> usize {
+        0
+    }
+
+    /// Reads the field of type `V` at the given byte offset in the device's configuration space.
+    ///
+    /// The field is converted from the device's byte order, which is little-endian for modern
+    /// devices but the guest's native byte order for legacy ones. Each access is aligned and no
+    /// wider than 32 bits, and the read is retried if the device changed its configuration part way
+    /// through: according to the [configuration generation](Self::config_generation), or for legacy
+    /// interfaces, which don't have one, until two reads of a field wider than 32 bits agree.
+    ///
+    /// Returns [`Error::ConfigSpaceTooSmall`](crate::Error::ConfigSpaceTooSmall) if the field
+    /// isn't within the configuration space, or [`Error::Misaligned`](crate::Error::Misaligned) if
+    /// the offset isn't a multiple of the field's alignment, up to 4 bytes.
+    fn read_config<V: ConfigField>(&self, offset: usize) -> Result<V> {
+        config::read_field(self, offset)
+    }
+
+    /// Writes the given value to the field of type `V` at the given byte offset in the device's
+    /// configuration space, converting it to the device's byte order.
+    ///
+    /// Returns the same errors as [`read_config`](Self::read_config).
+    fn write_config<V: ConfigField>(&mut self, offset: usize, value: V) -> Result {
+        config::write_field(self, offset, value)
+    }
+}
+
+/// The doorbell for one virtqueue: the MMIO register to write to notify the device about new
+/// buffers in the queue, and the value to write.
+///
+/// Returned by [`Transport::queue_notify_address`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct QueueNotifyAddress {
+    register: NotifyRegister,
+    value: u32,
+}
+
+/// The width of a doorbell register.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+enum NotifyRegister {
+    U16(NonNull<u16>),
+    U32(NonNull<u32>),
+}
+
+impl QueueNotifyAddress {
+    /// Returns a doorbell which is notified by writing the given 16-bit value to the given
+    /// register.
+    ///
+    /// # Safety
+    ///
+    /// `register` must be a valid, aligned MMIO register which may be written for as long as the
+    /// transport returning this is alive.
+    pub unsafe fn new_u16(register: NonNull<u16>, value: u16) -> Self {
+        Self {
+            register: NotifyRegister::U16(register),
+            value: value.into(),
+        }
+    }
+
+    /// Returns a doorbell which is notified by writing the given 32-bit value to the given
+    /// register.
+    ///
+    /// # Safety
+    ///
+    /// `register` must be a valid, aligned MMIO register which may be written for as long as the
+    /// transport returning this is alive.
+    pub unsafe fn new_u32(register: NonNull<u32>, value: u32) -> Self {
+        Self {
+            register: NotifyRegister::U32(register),
+            value,
+        }
+    }
+
+    /// Returns the virtual address of the doorbell register.
+    pub fn address(&self) -> usize {
+        match self.register {
+            NotifyRegister::U16(register) => register.as_ptr() as usize,
+            NotifyRegister::U32(register) => register.as_ptr() as usize,
+        }
+    }
+
+    /// Notifies the queue by writing to the doorbell.
+    ///
+    /// # Safety
+    ///
+    /// The transport which returned the doorbell must still be alive.
+    pub unsafe fn notify(&self) {
+        // Safe because the transport promised that the register is valid while it is alive, and
+        // our caller promises that it is.
+        unsafe {
+            match self.register {
+                // The value came from a `u16` in `new_u16`.
+                NotifyRegister::U16(register) => {
+                    register.as_ptr().write_volatile(self.value as u16)
+                }
+                NotifyRegister::U32(register) => register.as_ptr().write_volatile(self.value),
+            }
+        }
+    }
+}
+
+/// Returns the features for the driver to accept, given those offered by the device and those
+/// which the driver supports of them.
+///
+/// A device offering `VIRTIO_F_VERSION_1` through a modern interface may refuse drivers which don't
+/// accept it, so it is accepted even if the driver's feature type doesn't mention it.
+///
+/// `VIRTIO_F_ACCESS_PLATFORM` is always accepted too, for the reasons given in
+/// [Device addresses](crate::Hal#device-addresses).
+///
+/// A legacy interface can't negotiate either feature, which leaves the device using the legacy
+/// semantics.
+fn driver_features(device_features: u64, supported: u64, legacy: bool) -> u64 {
+    let mandatory = (Feature::VERSION_1 | Feature::ACCESS_PLATFORM).bits();
+    if legacy {
+        supported & !mandatory
+    } else {
+        supported | (device_features & mandatory)
+    }
+}
+
+/// The location of a shared memory region, which the device and driver can both access without
+/// going through a virtqueue.
+///
+/// For example, a virtio-fs device uses one as the DAX window for mapping file contents directly
+/// into the guest.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct SharedMemoryRegion {
+    /// The guest physical address of the start of the region.
+    pub paddr: PhysAddr,
+    /// The length of the region in bytes.
+    pub len: u64,
 }
 
+bitflags! {
+    /// Optional operations which a transport may support, as returned by
+    /// [`Transport::capabilities`].
+    #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+    pub struct TransportCapabilities: u32 {
+        /// The device may have shared memory regions, which
+        /// [`Transport::shared_memory_region`] can find.
+        const SHARED_MEMORY = 1 << 0;
+        /// Individual queues can be reset without resetting the whole device.
+        ///
+        /// None of the transports in this crate support this yet.
+        const QUEUE_RESET = 1 << 1;
+        /// The device can signal interrupts with MSI-X, so the driver can give each queue its own
+        /// vector.
+        const MSIX = 1 << 2;
+        /// Queue notifications can carry extra data about the available buffers.
+        ///
+        /// None of the transports in this crate support this yet.
+        const NOTIFICATION_DATA = 1 << 3;
+    }
+}
+
+impl_flags_display!(TransportCapabilities);
+
 bitflags! {
     /// The device status field. Writing 0 into this field resets the device.
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -132,6 +391,8 @@ bitflags! {
     }
 }
 
+impl_flags_display!(DeviceStatus);
+
 /// Types of virtio devices.
 #[repr(u8)]
 #[derive(Clone, Copy, Debug, Eq, PartialEq)]
@@ -160,6 +421,8 @@ pub enum DeviceType {
     Pstore = 22,
     IOMMU = 23,
     Memory = 24,
+    Sound = 25,
+    FileSystem = 26,
 }
 
 impl From<u32> for DeviceType {
@@ -187,11 +450,52 @@ impl From<u32> for DeviceType {
             22 => DeviceType::Pstore,
             23 => DeviceType::IOMMU,
             24 => DeviceType::Memory,
+            25 => DeviceType::Sound,
+            26 => DeviceType::FileSystem,
             _ => DeviceType::Invalid,
         }
     }
 }
 
+impl DeviceType {
+    /// Returns the conventional short name of the device type, as used by Linux and QEMU, e.g.
+    /// `virtio-net`.
+    pub fn name(self) -> &'static str {
+        match self {
+            DeviceType::Invalid => "invalid",
+            DeviceType::Network => "virtio-net",
+            DeviceType::Block => "virtio-blk",
+            DeviceType::Console => "virtio-console",
+            DeviceType::EntropySource => "virtio-rng",
+            DeviceType::MemoryBallooning | DeviceType::MemoryBalloon => "virtio-balloon",
+            DeviceType::IoMemory => "virtio-iomem",
+            DeviceType::Rpmsg => "virtio-rpmsg",
+            DeviceType::ScsiHost => "virtio-scsi",
+            DeviceType::_9P => "virtio-9p",
+            DeviceType::Mac80211 => "virtio-wlan",
+            DeviceType::RprocSerial => "virtio-rproc-serial",
+            DeviceType::VirtioCAIF => "virtio-caif",
+            DeviceType::GPU => "virtio-gpu",
+            DeviceType::Timer => "virtio-clock",
+            DeviceType::Input => "virtio-input",
+            DeviceType::Socket => "virtio-vsock",
+            DeviceType::Crypto => "virtio-crypto",
+            DeviceType::SignalDistributionModule => "virtio-sdm",
+            DeviceType::Pstore => "virtio-pstore",
+            DeviceType::IOMMU => "virtio-iommu",
+            DeviceType::Memory => "virtio-mem",
+            DeviceType::Sound => "virtio-snd",
+            DeviceType::FileSystem => "virtio-fs",
+        }
+    }
+}
+
+impl Display for DeviceType {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        f.write_str(self.name())
+    }
+}
+
 impl From<u16> for DeviceType {
     fn from(virtio_device_id: u16) -> Self {
         u32::from(virtio_device_id).into()
@@ -203,3 +507,29 @@ impl From<u8> for DeviceType {
         u32::from(virtio_device_id).into()
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn mandatory_feature_negotiation() {
+        let version_1 = Feature::VERSION_1.bits();
+        let access_platform = Feature::ACCESS_PLATFORM.bits();
+        // A modern interface accepts VERSION_1 and ACCESS_PLATFORM whenever the device offers them.
+        assert_eq!(
+            driver_features(version_1 | 0b11, 0b01, false),
+            version_1 | 0b01
+        );
+        assert_eq!(
+            driver_features(version_1 | access_platform | 0b11, 0b01, false),
+            version_1 | access_platform | 0b01
+        );
+        assert_eq!(driver_features(0b11, 0b01, false), 0b01);
+        // A legacy interface never does.
+        assert_eq!(
+            driver_features(version_1 | access_platform | 0b11, version_1 | 0b01, true),
+            0b01
+        );
+    }
+}
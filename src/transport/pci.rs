@@ -2,21 +2,27 @@
 
 pub mod bus;
 
-use self::bus::{DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_VNDR};
-use super::{DeviceStatus, DeviceType, Transport};
+use self::bus::{
+    BarInfo, DeviceFunction, DeviceFunctionInfo, PciError, PciRoot, PCI_CAP_ID_MSIX,
+    PCI_CAP_ID_VNDR,
+};
+use super::{
+    DeviceStatus, DeviceType, QueueNotifyAddress, SharedMemoryRegion, Transport,
+    TransportCapabilities,
+};
 use crate::{
     hal::{Hal, PhysAddr},
     nonnull_slice_from_raw_parts,
-    volatile::{
-        volread, volwrite, ReadOnly, Volatile, VolatileReadable, VolatileWritable, WriteOnly,
-    },
-    Error,
+    volatile::{volread, volwrite, ReadOnly, Volatile, VolatileReadable, WriteOnly},
+    Error, PAGE_SIZE,
 };
 use core::{
+    convert::TryFrom,
     fmt::{self, Display, Formatter},
     mem::{align_of, size_of},
     ptr::{addr_of_mut, NonNull},
 };
+use log::warn;
 
 /// The PCI vendor ID for VirtIO devices.
 const VIRTIO_VENDOR_ID: u16 = 0x1af4;
@@ -40,6 +46,10 @@ const CAP_BAR_OFFSET_OFFSET: u8 = 8;
 const CAP_LENGTH_OFFSET: u8 = 12;
 /// The offset of the`notify_off_multiplier` field within `virtio_pci_notify_cap`.
 const CAP_NOTIFY_OFF_MULTIPLIER_OFFSET: u8 = 16;
+/// The offset of the `offset_hi` field within `virtio_pci_cap64`.
+const CAP_OFFSET_HI_OFFSET: u8 = 16;
+/// The offset of the `length_hi` field within `virtio_pci_cap64`.
+const CAP_LENGTH_HI_OFFSET: u8 = 20;
 
 /// Common configuration.
 const VIRTIO_PCI_CAP_COMMON_CFG: u8 = 1;
@@ -49,6 +59,17 @@ const VIRTIO_PCI_CAP_NOTIFY_CFG: u8 = 2;
 const VIRTIO_PCI_CAP_ISR_CFG: u8 = 3;
 /// Device specific configuration.
 const VIRTIO_PCI_CAP_DEVICE_CFG: u8 = 4;
+/// Shared memory region.
+const VIRTIO_PCI_CAP_SHARED_MEMORY_CFG: u8 = 8;
+
+/// The maximum number of shared memory regions which the transport keeps track of.
+const MAX_SHARED_MEMORY_REGIONS: usize = 8;
+
+/// The MSI-X vector value meaning that no vector is assigned.
+pub const NO_VECTOR: u16 = 0xffff;
+
+/// The offset of the subsystem vendor ID and subsystem ID in PCI configuration space.
+const SUBSYSTEM_VENDOR_ID_OFFSET: u8 = 0x2c;
 
 fn device_type(pci_device_id: u16) -> DeviceType {
     match pci_device_id {
@@ -76,33 +97,119 @@ pub fn virtio_device_type(device_function_info: &DeviceFunctionInfo) -> Option<D
     None
 }
 
+/// Which interface of a device to drive it through.
+///
+/// Transitional devices, i.e. those with one of the PCI device IDs from `0x1000` to `0x103f`,
+/// offer both the modern interface described by the VirtIO PCI capabilities and the legacy
+/// interface in I/O BAR 0. Legacy operation never negotiates `VIRTIO_F_VERSION_1`, so the device
+/// uses the legacy semantics for its config space and queues.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum PciInterface {
+    /// The modern interface, as used by VirtIO 1.0 and later.
+    Modern,
+    /// The legacy interface, as used before VirtIO 1.0.
+    Legacy,
+}
+
+impl Display for PciInterface {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Modern => f.write_str("modern"),
+            Self::Legacy => f.write_str("legacy"),
+        }
+    }
+}
+
+/// The registers through which the transport drives the device.
+#[derive(Debug)]
+enum Registers {
+    /// The structures of the modern interface, as described by the VirtIO PCI capabilities.
+    Modern {
+        /// The common configuration structure within some BAR.
+        common_cfg: NonNull<CommonCfg>,
+        /// The start of the queue notification region within some BAR.
+        notify_region: NonNull<[WriteOnly<u16>]>,
+        notify_off_multiplier: u32,
+        /// The ISR status register within some BAR.
+        isr_status: NonNull<Volatile<u8>>,
+    },
+    /// The header of the legacy interface, at the start of I/O BAR 0.
+    Legacy {
+        header: NonNull<LegacyHeader>,
+        /// Whether MSI-X was enabled when the transport was created, in which case the header
+        /// includes the MSI-X vector registers.
+        msix_enabled: bool,
+    },
+}
+
+/// The device-specific configuration space, as 32-bit words.
+type ConfigSpace = NonNull<[u32]>;
+
 /// PCI transport for VirtIO.
 ///
 /// Ref: 4.1 Virtio Over PCI Bus
 #[derive(Debug)]
 pub struct PciTransport {
     device_type: DeviceType,
+    /// The PCI subsystem vendor ID of the device.
+    subsystem_vendor_id: u16,
     /// The bus, device and function identifier for the VirtIO device.
     device_function: DeviceFunction,
-    /// The common configuration structure within some BAR.
-    common_cfg: NonNull<CommonCfg>,
-    /// The start of the queue notification region within some BAR.
-    notify_region: NonNull<[WriteOnly<u16>]>,
-    notify_off_multiplier: u32,
-    /// The ISR status register within some BAR.
-    isr_status: NonNull<Volatile<u8>>,
+    registers: Registers,
     /// The VirtIO device-specific configuration within some BAR.
-    config_space: Option<NonNull<[u32]>>,
+    config_space: Option<ConfigSpace>,
+    /// The MSI-X vector to assign for configuration change notifications.
+    config_msix_vector: u16,
+    /// The MSI-X vector to assign to queues as they are set up.
+    queue_msix_vector: u16,
+    /// The shared memory regions of the device, with their IDs.
+    shared_memory_regions: [Option<(u8, SharedMemoryRegion)>; MAX_SHARED_MEMORY_REGIONS],
+    /// Whether the device function has an MSI-X capability.
+    msix: bool,
 }
 
 impl PciTransport {
     /// Construct a new PCI VirtIO device driver for the given device function on the given PCI
     /// root controller.
     ///
-    /// The PCI device must already have had its BARs allocated.
+    /// The PCI device must already have had its BARs allocated. This always uses the modern
+    /// interface; see [`new_transitional`](Self::new_transitional) to use the legacy interface of
+    /// a transitional device.
     pub fn new<H: Hal>(
         root: &mut PciRoot,
         device_function: DeviceFunction,
+    ) -> Result<Self, VirtioPciError> {
+        Self::init::<H>(root, device_function, None, Some(PciInterface::Modern))
+    }
+
+    /// Constructs a new PCI VirtIO transport for the given device function, using the given
+    /// interface, or if `interface` is `None` the modern interface if the device has one and
+    /// otherwise the legacy interface.
+    ///
+    /// `io_window` is the physical address at which the platform maps PCI I/O space, through which
+    /// the legacy registers in I/O BAR 0 are accessed with [`Hal::mmio_phys_to_virt`]. Platforms
+    /// which can only access I/O space through port I/O instructions can't use the legacy
+    /// interface.
+    ///
+    /// With the legacy interface, the driver's queue sizes must match the maximum sizes reported
+    /// by the device, queues must be page aligned, and MSI-X must be enabled or disabled for the
+    /// device function before the transport is created, as that moves the device-specific
+    /// configuration. Returns [`VirtioPciError::NotTransitional`] if the device has no legacy
+    /// interface.
+    pub fn new_transitional<H: Hal>(
+        root: &mut PciRoot,
+        device_function: DeviceFunction,
+        io_window: PhysAddr,
+        interface: Option<PciInterface>,
+    ) -> Result<Self, VirtioPciError> {
+        Self::init::<H>(root, device_function, Some(io_window), interface)
+    }
+
+    fn init<H: Hal>(
+        root: &mut PciRoot,
+        device_function: DeviceFunction,
+        io_window: Option<PhysAddr>,
+        interface: Option<PciInterface>,
     ) -> Result<Self, VirtioPciError> {
         let device_vendor = root.config_read_word(device_function, 0);
         let device_id = (device_vendor >> 16) as u16;
@@ -111,6 +218,8 @@ impl PciTransport {
             return Err(VirtioPciError::InvalidVendorId(vendor_id));
         }
         let device_type = device_type(device_id);
+        let subsystem_vendor_id =
+            root.config_read_word(device_function, SUBSYSTEM_VENDOR_ID_OFFSET) as u16;
 
         // Find the PCI capabilities we need.
         let mut common_cfg = None;
@@ -118,7 +227,12 @@ impl PciTransport {
         let mut notify_off_multiplier = 0;
         let mut isr_cfg = None;
         let mut device_cfg = None;
+        let mut shared_memory_cfgs = [None; MAX_SHARED_MEMORY_REGIONS];
+        let mut msix = false;
         for capability in root.capabilities(device_function) {
+            if capability.id == PCI_CAP_ID_MSIX {
+                msix = true;
+            }
             if capability.id != PCI_CAP_ID_VNDR {
                 continue;
             }
@@ -153,10 +267,62 @@ impl PciTransport {
                 VIRTIO_PCI_CAP_DEVICE_CFG if device_cfg.is_none() => {
                     device_cfg = Some(struct_info);
                 }
+                VIRTIO_PCI_CAP_SHARED_MEMORY_CFG if cap_len >= 24 => {
+                    let id = (root
+                        .config_read_word(device_function, capability.offset + CAP_BAR_OFFSET)
+                        >> 8) as u8;
+                    let offset_hi = root.config_read_word(
+                        device_function,
+                        capability.offset + CAP_OFFSET_HI_OFFSET,
+                    );
+                    let length_hi = root.config_read_word(
+                        device_function,
+                        capability.offset + CAP_LENGTH_HI_OFFSET,
+                    );
+                    let info = SharedMemoryCapabilityInfo {
+                        id,
+                        bar: struct_info.bar,
+                        offset: u64::from(offset_hi) << 32 | u64::from(struct_info.offset),
+                        length: u64::from(length_hi) << 32 | u64::from(struct_info.length),
+                    };
+                    if let Some(slot) = shared_memory_cfgs.iter_mut().find(|slot| slot.is_none()) {
+                        *slot = Some(info);
+                    } else {
+                        warn!("Ignoring shared memory region {} as there are too many", id);
+                    }
+                }
                 _ => {}
             }
         }
 
+        let interface = interface.unwrap_or(if common_cfg.is_some() {
+            PciInterface::Modern
+        } else {
+            PciInterface::Legacy
+        });
+        if interface == PciInterface::Legacy {
+            let io_window = match io_window {
+                Some(io_window) if device_id < PCI_DEVICE_ID_OFFSET => io_window,
+                _ => return Err(VirtioPciError::NotTransitional(device_id)),
+            };
+            let msix_enabled = root
+                .msix_info(device_function)
+                .is_some_and(|msix_info| root.msix_enabled(device_function, &msix_info));
+            let (registers, config_space) =
+                get_legacy_registers::<H>(root, device_function, io_window, msix_enabled)?;
+            return Ok(Self {
+                device_type,
+                subsystem_vendor_id,
+                device_function,
+                registers,
+                config_space,
+                config_msix_vector: NO_VECTOR,
+                queue_msix_vector: NO_VECTOR,
+                shared_memory_regions: [None; MAX_SHARED_MEMORY_REGIONS],
+                msix,
+            });
+        }
+
         let common_cfg = get_bar_region::<H, _>(
             root,
             device_function,
@@ -164,7 +330,7 @@ impl PciTransport {
         )?;
 
         let notify_cfg = notify_cfg.ok_or(VirtioPciError::MissingNotifyConfig)?;
-        if notify_off_multiplier % 2 != 0 {
+        if !notify_off_multiplier.is_multiple_of(2) {
             return Err(VirtioPciError::InvalidNotifyOffMultiplier(
                 notify_off_multiplier,
             ));
@@ -187,16 +353,108 @@ impl PciTransport {
             None
         };
 
+        let mut shared_memory_regions = [None; MAX_SHARED_MEMORY_REGIONS];
+        for (slot, info) in shared_memory_regions
+            .iter_mut()
+            .zip(shared_memory_cfgs.iter().flatten())
+        {
+            // Shared memory regions are optional, so don't fail if one is unusable.
+            match get_shared_memory_region(root, device_function, info) {
+                Ok(region) => *slot = Some((info.id, region)),
+                Err(e) => warn!("Ignoring shared memory region {}: {}", info.id, e),
+            }
+        }
+
         Ok(Self {
             device_type,
+            subsystem_vendor_id,
             device_function,
-            common_cfg,
-            notify_region,
-            notify_off_multiplier,
-            isr_status,
+            registers: Registers::Modern {
+                common_cfg,
+                notify_region,
+                notify_off_multiplier,
+                isr_status,
+            },
             config_space,
+            config_msix_vector: NO_VECTOR,
+            queue_msix_vector: NO_VECTOR,
+            shared_memory_regions,
+            msix,
         })
     }
+
+    /// Returns which interface the transport drives the device through.
+    pub fn interface(&self) -> PciInterface {
+        match self.registers {
+            Registers::Modern { .. } => PciInterface::Modern,
+            Registers::Legacy { .. } => PciInterface::Legacy,
+        }
+    }
+
+    /// Sets the MSI-X vector used by the device to signal configuration changes, or [`NO_VECTOR`]
+    /// (the default) to disable them.
+    ///
+    /// MSI-X must already have been enabled for the device function, e.g. with
+    /// [`PciRoot::set_msix_enabled`](bus::PciRoot::set_msix_enabled). Returns
+    /// [`VirtioPciError::MsixVectorUnavailable`] if the device couldn't allocate the vector.
+    ///
+    /// Resetting the device clears the vector, so the transport assigns it again whenever a driver
+    /// starts initialising the device.
+    pub fn set_config_msix_vector(&mut self, vector: u16) -> Result<(), VirtioPciError> {
+        self.config_msix_vector = vector;
+        if self.write_config_msix_vector() {
+            Ok(())
+        } else {
+            Err(VirtioPciError::MsixVectorUnavailable(vector))
+        }
+    }
+
+    /// Writes the configuration change MSI-X vector to the device, and returns whether the device
+    /// accepted it.
+    fn write_config_msix_vector(&mut self) -> bool {
+        // Safe because the register pointers are valid and we checked when mapping them that they
+        // were aligned.
+        unsafe {
+            match self.registers {
+                Registers::Modern { common_cfg, .. } => {
+                    volwrite!(common_cfg, msix_config, self.config_msix_vector);
+                    volread!(common_cfg, msix_config) == self.config_msix_vector
+                }
+                Registers::Legacy {
+                    header,
+                    msix_enabled: true,
+                } => {
+                    volwrite!(header, config_msix_vector, self.config_msix_vector);
+                    volread!(header, config_msix_vector) == self.config_msix_vector
+                }
+                // The legacy header only has the vector registers while MSI-X is enabled.
+                Registers::Legacy { .. } => false,
+            }
+        }
+    }
+
+    /// Sets the MSI-X vector which will be assigned to each queue when the driver sets it up, or
+    /// [`NO_VECTOR`] (the default) for no vector.
+    ///
+    /// While queues have a vector, [`Transport::ack_interrupt`] always returns false, as the
+    /// device doesn't use the ISR status. The handler for the vector should instead process the
+    /// queues directly.
+    ///
+    /// This must be called before creating the device driver. If the device can't allocate the
+    /// vector for some queue then a warning is logged and the queue is left without one.
+    pub fn set_queue_msix_vector(&mut self, vector: u16) {
+        self.queue_msix_vector = vector;
+    }
+
+    /// Returns whether the device signals queue interrupts through an MSI-X vector rather than the
+    /// ISR status.
+    fn msix_vectors_in_use(&self) -> bool {
+        let msix_enabled = match self.registers {
+            Registers::Modern { .. } => true,
+            Registers::Legacy { msix_enabled, .. } => msix_enabled,
+        };
+        msix_enabled && self.queue_msix_vector != NO_VECTOR
+    }
 }
 
 impl Transport for PciTransport {
@@ -204,68 +462,143 @@ impl Transport for PciTransport {
         self.device_type
     }
 
+    fn vendor_id(&self) -> u32 {
+        self.subsystem_vendor_id.into()
+    }
+
     fn read_device_features(&mut self) -> u64 {
-        // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
+        // Safe because the register pointers are valid and we checked when mapping them that they
+        // were aligned.
         unsafe {
-            volwrite!(self.common_cfg, device_feature_select, 0);
-            let mut device_features_bits = volread!(self.common_cfg, device_feature) as u64;
-            volwrite!(self.common_cfg, device_feature_select, 1);
-            device_features_bits |= (volread!(self.common_cfg, device_feature) as u64) << 32;
-            device_features_bits
+            match self.registers {
+                Registers::Modern { common_cfg, .. } => {
+                    volwrite!(common_cfg, device_feature_select, 0);
+                    let mut device_features_bits = volread!(common_cfg, device_feature) as u64;
+                    volwrite!(common_cfg, device_feature_select, 1);
+                    device_features_bits |= (volread!(common_cfg, device_feature) as u64) << 32;
+                    device_features_bits
+                }
+                // The legacy interface only has the low 32 feature bits, so the device never
+                // offers VIRTIO_F_VERSION_1 through it.
+                Registers::Legacy { header, .. } => volread!(header, host_features).into(),
+            }
         }
     }
 
     fn write_driver_features(&mut self, driver_features: u64) {
-        // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
+        // Safe because the register pointers are valid and we checked when mapping them that they
+        // were aligned.
         unsafe {
-            volwrite!(self.common_cfg, driver_feature_select, 0);
-            volwrite!(self.common_cfg, driver_feature, driver_features as u32);
-            volwrite!(self.common_cfg, driver_feature_select, 1);
-            volwrite!(
-                self.common_cfg,
-                driver_feature,
-                (driver_features >> 32) as u32
-            );
+            match self.registers {
+                Registers::Modern { common_cfg, .. } => {
+                    volwrite!(common_cfg, driver_feature_select, 0);
+                    volwrite!(common_cfg, driver_feature, driver_features as u32);
+                    volwrite!(common_cfg, driver_feature_select, 1);
+                    volwrite!(common_cfg, driver_feature, (driver_features >> 32) as u32);
+                }
+                Registers::Legacy { header, .. } => {
+                    volwrite!(header, guest_features, driver_features as u32);
+                }
+            }
         }
     }
 
     fn max_queue_size(&mut self, queue: u16) -> u32 {
-        // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
+        // Safe because the register pointers are valid and we checked when mapping them that they
+        // were aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            volread!(self.common_cfg, queue_size).into()
+            match self.registers {
+                Registers::Modern { common_cfg, .. } => {
+                    volwrite!(common_cfg, queue_select, queue);
+                    volread!(common_cfg, queue_size).into()
+                }
+                Registers::Legacy { header, .. } => {
+                    volwrite!(header, queue_select, queue);
+                    volread!(header, queue_size).into()
+                }
+            }
         }
     }
 
     fn notify(&mut self, queue: u16) {
-        // Safe because the common config and notify region pointers are valid and we checked in
-        // get_bar_region that they were aligned.
+        if let Some(doorbell) = self.queue_notify_address(queue) {
+            // Safe because the doorbell came from this transport, which is alive.
+            unsafe { doorbell.notify() }
+        }
+    }
+
+    fn queue_notify_address(&mut self, queue: u16) -> Option<QueueNotifyAddress> {
+        // Safe because the register pointers are valid and we checked when mapping them that they
+        // were aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            // TODO: Consider caching this somewhere (per queue).
-            let queue_notify_off = volread!(self.common_cfg, queue_notify_off);
+            match self.registers {
+                Registers::Modern {
+                    common_cfg,
+                    notify_region,
+                    notify_off_multiplier,
+                    ..
+                } => {
+                    volwrite!(common_cfg, queue_select, queue);
+                    let queue_notify_off = volread!(common_cfg, queue_notify_off);
 
-            let offset_bytes = usize::from(queue_notify_off) * self.notify_off_multiplier as usize;
-            let index = offset_bytes / size_of::<u16>();
-            addr_of_mut!((*self.notify_region.as_ptr())[index]).vwrite(queue);
+                    let offset_bytes =
+                        usize::from(queue_notify_off) * notify_off_multiplier as usize;
+                    let index = offset_bytes / size_of::<u16>();
+                    if index >= notify_region.len() {
+                        warn!(
+                            "Notification offset {:#x} for queue {} is outside the notification region",
+                            offset_bytes, queue
+                        );
+                        return None;
+                    }
+                    let register = addr_of_mut!((*notify_region.as_ptr())[index]);
+                    Some(QueueNotifyAddress::new_u16(
+                        NonNull::new(register)?.cast(),
+                        queue,
+                    ))
+                }
+                Registers::Legacy { header, .. } => Some(QueueNotifyAddress::new_u16(
+                    NonNull::new(addr_of_mut!((*header.as_ptr()).queue_notify))?.cast(),
+                    queue,
+                )),
+            }
         }
     }
 
     fn get_status(&self) -> DeviceStatus {
-        // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
-        let status = unsafe { volread!(self.common_cfg, device_status) };
+        // Safe because the register pointers are valid and we checked when mapping them that they
+        // were aligned.
+        let status = unsafe {
+            match self.registers {
+                Registers::Modern { common_cfg, .. } => volread!(common_cfg, device_status),
+                Registers::Legacy { header, .. } => volread!(header, device_status),
+            }
+        };
         DeviceStatus::from_bits_truncate(status.into())
     }
 
     fn set_status(&mut self, status: DeviceStatus) {
-        // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
+        // Safe because the register pointers are valid and we checked when mapping them that they
+        // were aligned.
         unsafe {
-            volwrite!(self.common_cfg, device_status, status.bits() as u8);
+            match self.registers {
+                Registers::Modern { common_cfg, .. } => {
+                    volwrite!(common_cfg, device_status, status.bits() as u8);
+                }
+                Registers::Legacy { header, .. } => {
+                    volwrite!(header, device_status, status.bits() as u8);
+                }
+            }
+        }
+        // The driver has just reset the device and started initialising it.
+        if status == DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER
+            && self.config_msix_vector != NO_VECTOR
+            && !self.write_config_msix_vector()
+        {
+            warn!(
+                "Device couldn't allocate MSI-X vector {} for configuration changes",
+                self.config_msix_vector
+            );
         }
     }
 
@@ -274,7 +607,7 @@ impl Transport for PciTransport {
     }
 
     fn requires_legacy_layout(&self) -> bool {
-        false
+        self.interface() == PciInterface::Legacy
     }
 
     fn queue_set(
@@ -284,61 +617,180 @@ impl Transport for PciTransport {
         descriptors: PhysAddr,
         driver_area: PhysAddr,
         device_area: PhysAddr,
-    ) {
-        // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
+    ) -> Result<(), Error> {
+        // Safe because the register pointers are valid and we checked when mapping them that they
+        // were aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            volwrite!(self.common_cfg, queue_size, size as u16);
-            volwrite!(self.common_cfg, queue_desc, descriptors as u64);
-            volwrite!(self.common_cfg, queue_driver, driver_area as u64);
-            volwrite!(self.common_cfg, queue_device, device_area as u64);
-            volwrite!(self.common_cfg, queue_enable, 1);
+            match self.registers {
+                Registers::Modern { common_cfg, .. } => {
+                    volwrite!(common_cfg, queue_select, queue);
+                    volwrite!(common_cfg, queue_size, size as u16);
+                    volwrite!(common_cfg, queue_desc, descriptors as u64);
+                    volwrite!(common_cfg, queue_driver, driver_area as u64);
+                    volwrite!(common_cfg, queue_device, device_area as u64);
+                    if self.queue_msix_vector != NO_VECTOR {
+                        volwrite!(common_cfg, queue_msix_vector, self.queue_msix_vector);
+                        if volread!(common_cfg, queue_msix_vector) != self.queue_msix_vector {
+                            warn!(
+                                "Device couldn't allocate MSI-X vector {} for queue {}",
+                                self.queue_msix_vector, queue
+                            );
+                        }
+                    }
+                    volwrite!(common_cfg, queue_enable, 1);
+                }
+                Registers::Legacy {
+                    header,
+                    msix_enabled,
+                } => {
+                    // The legacy interface has a fixed queue size, and finds the driver and device
+                    // areas from the page number of the descriptor table.
+                    volwrite!(header, queue_select, queue);
+                    if u32::from(volread!(header, queue_size)) != size {
+                        warn!(
+                            "Legacy PCI queue {} must have size {}, not {}",
+                            queue,
+                            volread!(header, queue_size),
+                            size
+                        );
+                        return Err(Error::InvalidParam);
+                    }
+                    if !descriptors.is_multiple_of(PAGE_SIZE) {
+                        return Err(Error::Misaligned);
+                    }
+                    let pfn =
+                        u32::try_from(descriptors / PAGE_SIZE).map_err(|_| Error::InvalidParam)?;
+                    if msix_enabled && self.queue_msix_vector != NO_VECTOR {
+                        volwrite!(header, queue_msix_vector, self.queue_msix_vector);
+                        if volread!(header, queue_msix_vector) != self.queue_msix_vector {
+                            warn!(
+                                "Device couldn't allocate MSI-X vector {} for queue {}",
+                                self.queue_msix_vector, queue
+                            );
+                        }
+                    }
+                    volwrite!(header, queue_address, pfn);
+                }
+            }
         }
+        Ok(())
     }
 
-    fn queue_unset(&mut self, _queue: u16) {
-        // The VirtIO spec doesn't allow queues to be unset once they have been set up for the PCI
-        // transport, so this is a no-op.
+    fn queue_unset(&mut self, queue: u16) {
+        // The VirtIO spec doesn't allow queues to be unset once they have been set up for the
+        // modern PCI interface, so this only does anything for the legacy interface.
+        if let Registers::Legacy { header, .. } = self.registers {
+            // Safe because the header pointer is valid and we checked when mapping it that it was
+            // aligned.
+            unsafe {
+                volwrite!(header, queue_select, queue);
+                volwrite!(header, queue_address, 0);
+            }
+        }
     }
 
     fn queue_used(&mut self, queue: u16) -> bool {
-        // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
+        // Safe because the register pointers are valid and we checked when mapping them that they
+        // were aligned.
         unsafe {
-            volwrite!(self.common_cfg, queue_select, queue);
-            volread!(self.common_cfg, queue_enable) == 1
+            match self.registers {
+                Registers::Modern { common_cfg, .. } => {
+                    volwrite!(common_cfg, queue_select, queue);
+                    volread!(common_cfg, queue_enable) == 1
+                }
+                Registers::Legacy { header, .. } => {
+                    volwrite!(header, queue_select, queue);
+                    volread!(header, queue_address) != 0
+                }
+            }
         }
     }
 
     fn ack_interrupt(&mut self) -> bool {
-        // Safe because the common config pointer is valid and we checked in get_bar_region that it
-        // was aligned.
+        if self.msix_vectors_in_use() {
+            // The device doesn't set the ISR status while MSI-X is in use, and MSI-X interrupts
+            // don't need to be de-asserted, so there is nothing to acknowledge.
+            return false;
+        }
+        // Safe because the register pointers are valid and we checked when mapping them that they
+        // were aligned.
         // Reading the ISR status resets it to 0 and causes the device to de-assert the interrupt.
-        let isr_status = unsafe { self.isr_status.as_ptr().vread() };
+        let isr_status = unsafe {
+            match self.registers {
+                Registers::Modern { isr_status, .. } => isr_status.as_ptr().vread(),
+                Registers::Legacy { header, .. } => volread!(header, isr_status),
+            }
+        };
         // TODO: Distinguish between queue interrupt and device configuration interrupt.
         isr_status & 0x3 != 0
     }
 
+    fn capabilities(&self) -> TransportCapabilities {
+        let mut capabilities = TransportCapabilities::empty();
+        if self.shared_memory_regions.iter().any(Option::is_some) {
+            capabilities |= TransportCapabilities::SHARED_MEMORY;
+        }
+        if self.msix {
+            capabilities |= TransportCapabilities::MSIX;
+        }
+        capabilities
+    }
+
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        self.shared_memory_regions
+            .iter()
+            .flatten()
+            .find(|(region_id, _)| *region_id == id)
+            .map(|(_, region)| *region)
+    }
+
+    fn config_generation(&self) -> u32 {
+        match self.registers {
+            // Safe because the common config pointer is valid and we checked in get_bar_region
+            // that it was aligned.
+            Registers::Modern { common_cfg, .. } => unsafe {
+                volread!(common_cfg, config_generation).into()
+            },
+            // The legacy interface has no generation counter.
+            Registers::Legacy { .. } => 0,
+        }
+    }
+
     fn config_space<T>(&self) -> Result<NonNull<T>, Error> {
         if let Some(config_space) = self.config_space {
             if size_of::<T>() > config_space.len() * size_of::<u32>() {
                 Err(Error::ConfigSpaceTooSmall)
             } else if align_of::<T>() > 4 {
-                // Panic as this should only happen if the driver is written incorrectly.
-                panic!(
+                // This should only happen if the driver is written incorrectly.
+                warn!(
                     "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
                     align_of::<T>()
                 );
+                Err(Error::Misaligned)
             } else {
-                // TODO: Use NonNull::as_non_null_ptr once it is stable.
-                let config_space_ptr = NonNull::new(config_space.as_ptr() as *mut u32).unwrap();
-                Ok(config_space_ptr.cast())
+                Ok(config_space.cast())
             }
         } else {
             Err(Error::ConfigSpaceMissing)
         }
     }
+
+    fn config_space_size(&self) -> usize {
+        self.config_space
+            .map_or(0, |config_space| config_space.len() * size_of::<u32>())
+    }
+}
+
+impl Display for PciTransport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{} ({} PCI at {})",
+            self.device_type,
+            self.interface(),
+            self.device_function
+        )
+    }
 }
 
 impl Drop for PciTransport {
@@ -370,6 +822,28 @@ struct CommonCfg {
     queue_device: Volatile<u64>,
 }
 
+/// The header of the legacy interface, see 4.1.4.8 "Legacy Interfaces: A Note on PCI Device
+/// Layout".
+///
+/// The MSI-X vector registers are only present while MSI-X is enabled, otherwise the
+/// device-specific configuration starts in their place.
+#[repr(C)]
+struct LegacyHeader {
+    host_features: ReadOnly<u32>,
+    guest_features: Volatile<u32>,
+    queue_address: Volatile<u32>,
+    queue_size: ReadOnly<u16>,
+    queue_select: Volatile<u16>,
+    queue_notify: WriteOnly<u16>,
+    device_status: Volatile<u8>,
+    isr_status: ReadOnly<u8>,
+    config_msix_vector: Volatile<u16>,
+    queue_msix_vector: Volatile<u16>,
+}
+
+/// The length of the legacy header without the MSI-X vector registers.
+const LEGACY_HEADER_LEN: usize = 20;
+
 /// Information about a VirtIO structure within some BAR, as provided by a `virtio_pci_cap`.
 #[derive(Clone, Debug, Eq, PartialEq)]
 struct VirtioCapabilityInfo {
@@ -381,6 +855,48 @@ struct VirtioCapabilityInfo {
     length: u32,
 }
 
+/// Information about a shared memory region within some BAR, as provided by a
+/// `virtio_pci_cap64`.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+struct SharedMemoryCapabilityInfo {
+    /// The ID of the shared memory region.
+    id: u8,
+    /// The bar in which the region can be found.
+    bar: u8,
+    /// The offset within the bar.
+    offset: u64,
+    /// The length in bytes of the region.
+    length: u64,
+}
+
+/// Finds the physical address of the given shared memory region.
+///
+/// The region isn't mapped, as it may be much larger than the parts the driver needs.
+fn get_shared_memory_region(
+    root: &mut PciRoot,
+    device_function: DeviceFunction,
+    info: &SharedMemoryCapabilityInfo,
+) -> Result<SharedMemoryRegion, VirtioPciError> {
+    let bar_info = root.bar_info(device_function, info.bar)?;
+    let (bar_address, bar_size) = bar_info
+        .memory_address_size()
+        .ok_or(VirtioPciError::UnexpectedIoBar)?;
+    if bar_address == 0 {
+        return Err(VirtioPciError::BarNotAllocated(info.bar));
+    }
+    if info
+        .offset
+        .checked_add(info.length)
+        .is_none_or(|end| end > bar_size)
+    {
+        return Err(VirtioPciError::BarOffsetOutOfRange);
+    }
+    Ok(SharedMemoryRegion {
+        paddr: (bar_address + info.offset) as PhysAddr,
+        len: info.length,
+    })
+}
+
 fn get_bar_region<H: Hal, T>(
     root: &mut PciRoot,
     device_function: DeviceFunction,
@@ -393,7 +909,7 @@ fn get_bar_region<H: Hal, T>(
     if bar_address == 0 {
         return Err(VirtioPciError::BarNotAllocated(struct_info.bar));
     }
-    if struct_info.offset + struct_info.length > bar_size
+    if u64::from(struct_info.offset) + u64::from(struct_info.length) > bar_size
         || size_of::<T>() > struct_info.length as usize
     {
         return Err(VirtioPciError::BarOffsetOutOfRange);
@@ -402,7 +918,7 @@ fn get_bar_region<H: Hal, T>(
     // Safe because the paddr and size describe a valid MMIO region, at least according to the PCI
     // bus.
     let vaddr = unsafe { H::mmio_phys_to_virt(paddr, struct_info.length as usize) };
-    if vaddr.as_ptr() as usize % align_of::<T>() != 0 {
+    if !(vaddr.as_ptr() as usize).is_multiple_of(align_of::<T>()) {
         return Err(VirtioPciError::Misaligned {
             vaddr,
             alignment: align_of::<T>(),
@@ -411,6 +927,50 @@ fn get_bar_region<H: Hal, T>(
     Ok(vaddr.cast())
 }
 
+/// Maps the legacy registers in I/O BAR 0 through the given window onto PCI I/O space, and returns
+/// the registers and the device-specific configuration after them.
+fn get_legacy_registers<H: Hal>(
+    root: &mut PciRoot,
+    device_function: DeviceFunction,
+    io_window: PhysAddr,
+    msix_enabled: bool,
+) -> Result<(Registers, Option<ConfigSpace>), VirtioPciError> {
+    let BarInfo::IO { address, size } = root.bar_info(device_function, 0)? else {
+        return Err(VirtioPciError::UnexpectedMemoryBar);
+    };
+    if address == 0 {
+        return Err(VirtioPciError::BarNotAllocated(0));
+    }
+    let header_len = if msix_enabled {
+        size_of::<LegacyHeader>()
+    } else {
+        LEGACY_HEADER_LEN
+    };
+    let size = size as usize;
+    if size < header_len {
+        return Err(VirtioPciError::BarOffsetOutOfRange);
+    }
+    // Safe because the paddr and size describe a valid I/O region mapped into memory, at least
+    // according to the PCI bus and the caller.
+    let vaddr = unsafe { H::mmio_phys_to_virt(io_window + address as PhysAddr, size) };
+    if !(vaddr.as_ptr() as usize).is_multiple_of(align_of::<LegacyHeader>()) {
+        return Err(VirtioPciError::Misaligned {
+            vaddr,
+            alignment: align_of::<LegacyHeader>(),
+        });
+    }
+    let config_space = (size > header_len).then(|| {
+        // Safe because the configuration follows the header within the BAR.
+        let start = unsafe { NonNull::new_unchecked(vaddr.as_ptr().add(header_len)) };
+        nonnull_slice_from_raw_parts(start.cast(), (size - header_len) / size_of::<u32>())
+    });
+    let registers = Registers::Legacy {
+        header: vaddr.cast(),
+        msix_enabled,
+    };
+    Ok((registers, config_space))
+}
+
 fn get_bar_region_slice<H: Hal, T>(
     root: &mut PciRoot,
     device_function: DeviceFunction,
@@ -439,6 +999,11 @@ pub enum VirtioPciError {
     MissingIsrConfig,
     /// An IO BAR was provided rather than a memory BAR.
     UnexpectedIoBar,
+    /// A memory BAR was provided rather than the I/O BAR of the legacy interface.
+    UnexpectedMemoryBar,
+    /// The legacy interface was asked for, but the device with the given PCI device ID isn't a
+    /// transitional device, or no window onto PCI I/O space was given.
+    NotTransitional(u16),
     /// A BAR which we need was not allocated an address.
     BarNotAllocated(u8),
     /// The offset for some capability was greater than the length of the BAR.
@@ -450,6 +1015,8 @@ pub enum VirtioPciError {
         /// The expected alignment in bytes.
         alignment: usize,
     },
+    /// The device couldn't allocate the given MSI-X vector.
+    MsixVectorUnavailable(u16),
     /// A generic PCI error,
     Pci(PciError),
 }
@@ -481,6 +1048,14 @@ impl Display for VirtioPciError {
                 write!(f, "No valid `VIRTIO_PCI_CAP_ISR_CFG` capability was found.")
             }
             Self::UnexpectedIoBar => write!(f, "Unexpected IO BAR (expected memory BAR)."),
+            Self::UnexpectedMemoryBar => {
+                write!(f, "Unexpected memory BAR (expected legacy IO BAR).")
+            }
+            Self::NotTransitional(device_id) => write!(
+                f,
+                "PCI device ID {:#06x} has no usable legacy interface.",
+                device_id
+            ),
             Self::BarNotAllocated(bar_index) => write!(f, "Bar {} not allocated.", bar_index),
             Self::BarOffsetOutOfRange => write!(f, "Capability offset greater than BAR length."),
             Self::Misaligned { vaddr, alignment } => write!(
@@ -488,6 +1063,9 @@ impl Display for VirtioPciError {
                 "Virtual address {:#018?} was not aligned to a {} byte boundary as expected.",
                 vaddr, alignment
             ),
+            Self::MsixVectorUnavailable(vector) => {
+                write!(f, "The device couldn't allocate MSI-X vector {}.", vector)
+            }
             Self::Pci(pci_error) => pci_error.fmt(f),
         }
     }
@@ -503,6 +1081,267 @@ impl From<PciError> for VirtioPciError {
 mod tests {
     use super::*;
 
+    /// Memory standing in for the structures which a modern device has in its BARs.
+    #[derive(Default)]
+    #[repr(C, align(8))]
+    struct FakeModernRegions {
+        common_cfg: [u64; 7],
+        notify: [u16; 4],
+        isr_status: u8,
+    }
+
+    /// Returns a transport for a modern device whose structures are in the given memory.
+    fn fake_modern(regions: &mut FakeModernRegions, notify_off_multiplier: u32) -> PciTransport {
+        PciTransport {
+            device_type: DeviceType::Block,
+            subsystem_vendor_id: VIRTIO_VENDOR_ID,
+            device_function: DeviceFunction {
+                bus: 0,
+                device: 0,
+                function: 0,
+            },
+            registers: Registers::Modern {
+                common_cfg: NonNull::from(&mut regions.common_cfg).cast(),
+                notify_region: nonnull_slice_from_raw_parts(
+                    NonNull::from(&mut regions.notify).cast(),
+                    regions.notify.len(),
+                ),
+                notify_off_multiplier,
+                isr_status: NonNull::from(&mut regions.isr_status).cast(),
+            },
+            config_space: None,
+            config_msix_vector: NO_VECTOR,
+            queue_msix_vector: NO_VECTOR,
+            shared_memory_regions: [None; MAX_SHARED_MEMORY_REGIONS],
+            msix: true,
+        }
+    }
+
+    /// Returns the common configuration structure of a transport created by [`fake_modern`].
+    fn common_cfg(transport: &PciTransport) -> NonNull<CommonCfg> {
+        let Registers::Modern { common_cfg, .. } = transport.registers else {
+            panic!("Not a modern transport");
+        };
+        common_cfg
+    }
+
+    /// Returns a transport for a legacy device whose header is in the given memory.
+    ///
+    /// The memory covers the whole header, including the MSI-X vector registers which are only
+    /// used if `msix_enabled` is set.
+    fn fake_legacy(header: &mut [u32; 6], msix_enabled: bool) -> PciTransport {
+        PciTransport {
+            device_type: DeviceType::Block,
+            subsystem_vendor_id: VIRTIO_VENDOR_ID,
+            device_function: DeviceFunction {
+                bus: 0,
+                device: 0,
+                function: 0,
+            },
+            registers: Registers::Legacy {
+                header: NonNull::from(header).cast(),
+                msix_enabled,
+            },
+            config_space: None,
+            config_msix_vector: NO_VECTOR,
+            queue_msix_vector: NO_VECTOR,
+            shared_memory_regions: [None; MAX_SHARED_MEMORY_REGIONS],
+            msix: msix_enabled,
+        }
+    }
+
+    /// Returns the header of a transport created by [`fake_legacy`].
+    fn legacy_header(transport: &PciTransport) -> NonNull<LegacyHeader> {
+        let Registers::Legacy { header, .. } = transport.registers else {
+            panic!("Not a legacy transport");
+        };
+        header
+    }
+
+    /// Returns the word of a fake legacy header holding the given bytes, in memory order.
+    fn legacy_word(bytes: [u8; 4]) -> u32 {
+        u32::from_ne_bytes(bytes)
+    }
+
+    /// Returns the word of a fake legacy header holding the queue size and queue select registers.
+    fn legacy_queue_size_word(queue_size: u16) -> u32 {
+        let [low, high] = queue_size.to_ne_bytes();
+        legacy_word([low, high, 0, 0])
+    }
+
+    #[test]
+    fn legacy_features_and_status() {
+        let mut header = [0x1234_5678, 0, 0, 0, 0, 0];
+        let mut transport = fake_legacy(&mut header, false);
+        assert_eq!(transport.interface(), PciInterface::Legacy);
+        assert!(transport.requires_legacy_layout());
+        assert_eq!(transport.config_generation(), 0);
+
+        // Only the low 32 feature bits exist in the legacy header.
+        assert_eq!(transport.read_device_features(), 0x1234_5678);
+        transport.write_driver_features(0x0000_0001_0000_0003);
+        let header = legacy_header(&transport);
+        // Safe because the header is valid and aligned.
+        assert_eq!(unsafe { volread!(header, guest_features) }, 3);
+
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        assert_eq!(
+            transport.get_status(),
+            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER
+        );
+        // Safe because the header is valid and aligned.
+        assert_eq!(
+            unsafe { volread!(header, device_status) },
+            (DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER).bits() as u8
+        );
+    }
+
+    #[test]
+    fn legacy_queue_set() {
+        let mut header = [0, 0, 0, legacy_queue_size_word(16), 0, 0];
+        let mut transport = fake_legacy(&mut header, false);
+        assert_eq!(transport.max_queue_size(0), 16);
+        assert!(!transport.queue_used(0));
+
+        // The legacy interface has a fixed queue size, and needs page-aligned descriptors.
+        assert_eq!(
+            transport.queue_set(0, 8, 0x3000, 0x3100, 0x4000),
+            Err(Error::InvalidParam)
+        );
+        assert_eq!(
+            transport.queue_set(0, 16, 0x3008, 0x3108, 0x4000),
+            Err(Error::Misaligned)
+        );
+        assert!(!transport.queue_used(0));
+
+        transport.queue_set(0, 16, 0x3000, 0x3100, 0x4000).unwrap();
+        let header = legacy_header(&transport);
+        // Safe because the header is valid and aligned.
+        assert_eq!(unsafe { volread!(header, queue_address) }, 3);
+        assert!(transport.queue_used(0));
+
+        transport.queue_unset(0);
+        assert!(!transport.queue_used(0));
+    }
+
+    #[test]
+    fn legacy_queue_notify() {
+        let mut header = [0; 6];
+        let mut transport = fake_legacy(&mut header, false);
+        let header = legacy_header(&transport);
+        let doorbell = transport.queue_notify_address(2).unwrap();
+        assert_eq!(doorbell.address(), header.as_ptr() as usize + 16);
+
+        transport.notify(2);
+        // Safe because the queue notify register is valid and aligned. It is write-only for a real
+        // device, but the fake memory behind it can be read back.
+        let queue_notify = unsafe { header.cast::<u16>().as_ptr().add(8).read_volatile() };
+        assert_eq!(queue_notify, 2);
+    }
+
+    #[test]
+    fn legacy_msix_disabled() {
+        let mut header = [0, 0, 0, 0, legacy_word([0, 0, 0, 1]), 0];
+        let mut transport = fake_legacy(&mut header, false);
+        assert!(!transport
+            .capabilities()
+            .contains(TransportCapabilities::MSIX));
+
+        // Without MSI-X the header has no vector registers, so there is nothing to assign.
+        assert_eq!(
+            transport.set_config_msix_vector(1),
+            Err(VirtioPciError::MsixVectorUnavailable(1))
+        );
+        transport.set_queue_msix_vector(2);
+        assert!(transport.ack_interrupt());
+        // The word where the vector registers would be is left alone.
+        assert_eq!(header[5], 0);
+    }
+
+    #[test]
+    fn legacy_msix_enabled() {
+        let mut header = [
+            0,
+            0,
+            0,
+            legacy_queue_size_word(16),
+            legacy_word([0, 0, 0, 1]),
+            0,
+        ];
+        let mut transport = fake_legacy(&mut header, true);
+        assert!(transport
+            .capabilities()
+            .contains(TransportCapabilities::MSIX));
+
+        transport.set_config_msix_vector(1).unwrap();
+        transport.set_queue_msix_vector(2);
+        transport.queue_set(0, 16, 0x3000, 0x3100, 0x4000).unwrap();
+        let header = legacy_header(&transport);
+        // Safe because the header is valid and aligned, and includes the vector registers.
+        unsafe {
+            assert_eq!(volread!(header, config_msix_vector), 1);
+            assert_eq!(volread!(header, queue_msix_vector), 2);
+        }
+
+        // With MSI-X there is no ISR status to acknowledge, even if it happens to be set.
+        assert!(!transport.ack_interrupt());
+    }
+
+    #[test]
+    fn ack_interrupt_isr() {
+        let mut regions = FakeModernRegions {
+            isr_status: 1,
+            ..Default::default()
+        };
+        let mut transport = fake_modern(&mut regions, 2);
+        assert!(transport.ack_interrupt());
+
+        let mut regions = FakeModernRegions::default();
+        let mut transport = fake_modern(&mut regions, 2);
+        assert!(!transport.ack_interrupt());
+    }
+
+    #[test]
+    fn modern_queue_notify() {
+        let mut regions = FakeModernRegions::default();
+        let notify_region = regions.notify.as_ptr() as usize;
+        let mut transport = fake_modern(&mut regions, 2);
+        let common_cfg = common_cfg(&transport);
+
+        // Safe because the common configuration structure is valid and aligned.
+        unsafe { volwrite!(common_cfg, queue_notify_off, 3) };
+        let doorbell = transport.queue_notify_address(1).unwrap();
+        assert_eq!(doorbell.address(), notify_region + 6);
+        // Safe because the transport is still alive.
+        unsafe { doorbell.notify() };
+        assert_eq!(regions.notify, [0, 0, 0, 1]);
+
+        // The offset of the next register is past the end of the notification region.
+        // Safe because the common configuration structure is valid and aligned.
+        unsafe { volwrite!(common_cfg, queue_notify_off, 4) };
+        assert_eq!(transport.queue_notify_address(1), None);
+    }
+
+    #[test]
+    fn ack_interrupt_msix() {
+        let mut regions = FakeModernRegions {
+            isr_status: 1,
+            ..Default::default()
+        };
+        let mut transport = fake_modern(&mut regions, 2);
+        assert!(transport
+            .capabilities()
+            .contains(TransportCapabilities::MSIX));
+        transport.set_queue_msix_vector(3);
+        transport.queue_set(0, 16, 0x1000, 0x2000, 0x3000).unwrap();
+        let common_cfg = common_cfg(&transport);
+        // Safe because the common configuration structure is valid and aligned.
+        assert_eq!(unsafe { volread!(common_cfg, queue_msix_vector) }, 3);
+
+        // With MSI-X there is no ISR status to acknowledge, even if it happens to be set.
+        assert!(!transport.ack_interrupt());
+    }
+
     #[test]
     fn transitional_device_ids() {
         assert_eq!(device_type(0x1000), DeviceType::Network);
@@ -515,8 +1354,10 @@ mod tests {
         assert_eq!(device_type(0x1045), DeviceType::MemoryBalloon);
         assert_eq!(device_type(0x1049), DeviceType::_9P);
         assert_eq!(device_type(0x1058), DeviceType::Memory);
+        assert_eq!(device_type(0x1059), DeviceType::Sound);
+        assert_eq!(device_type(0x105a), DeviceType::FileSystem);
         assert_eq!(device_type(0x1040), DeviceType::Invalid);
-        assert_eq!(device_type(0x1059), DeviceType::Invalid);
+        assert_eq!(device_type(0x105b), DeviceType::Invalid);
     }
 
     #[test]
@@ -1,5 +1,6 @@
 //! Module for dealing with a PCI bus in general, without anything specific to VirtIO.
 
+use crate::display::impl_flags_display;
 use bitflags::bitflags;
 use core::{
     convert::TryFrom,
@@ -21,6 +22,13 @@ const BAR0_OFFSET: u8 = 0x10;
 
 /// ID for vendor-specific PCI capabilities.
 pub const PCI_CAP_ID_VNDR: u8 = 0x09;
+/// ID for MSI-X capability.
+pub const PCI_CAP_ID_MSIX: u8 = 0x11;
+
+/// The MSI-X Enable bit in the MSI-X message control register.
+const MSIX_ENABLE: u16 = 1 << 15;
+/// The Function Mask bit in the MSI-X message control register.
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
 
 bitflags! {
     /// The status register in PCI configuration space.
@@ -52,6 +60,8 @@ bitflags! {
     }
 }
 
+impl_flags_display!(Status);
+
 bitflags! {
     /// The command register in PCI configuration space.
     #[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
@@ -80,6 +90,8 @@ bitflags! {
     }
 }
 
+impl_flags_display!(Command);
+
 /// Errors accessing a PCI device.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum PciError {
@@ -238,7 +250,7 @@ impl PciRoot {
     }
 
     /// Gets an iterator over the capabilities of the given device function.
-    pub fn capabilities(&self, device_function: DeviceFunction) -> CapabilityIterator {
+    pub fn capabilities(&self, device_function: DeviceFunction) -> CapabilityIterator<'_> {
         CapabilityIterator {
             root: self,
             device_function,
@@ -246,6 +258,55 @@ impl PciRoot {
         }
     }
 
+    /// Returns information about the MSI-X capability of the given device function, if it has one.
+    pub fn msix_info(&self, device_function: DeviceFunction) -> Option<MsixInfo> {
+        let capability = self
+            .capabilities(device_function)
+            .find(|capability| capability.id == PCI_CAP_ID_MSIX)?;
+        let table = self.config_read_word(device_function, capability.offset + 4);
+        let pba = self.config_read_word(device_function, capability.offset + 8);
+        Some(MsixInfo {
+            offset: capability.offset,
+            table_size: (capability.private_header & 0x7ff) + 1,
+            table_bar: (table & 0x7) as u8,
+            table_offset: table & !0x7,
+            pba_bar: (pba & 0x7) as u8,
+            pba_offset: pba & !0x7,
+        })
+    }
+
+    /// Enables or disables MSI-X for the given device function, which must have an MSI-X
+    /// capability as returned by [`msix_info`](Self::msix_info).
+    ///
+    /// Enabling MSI-X also clears the function mask, so the caller should set up the MSI-X table
+    /// first.
+    pub fn set_msix_enabled(
+        &mut self,
+        device_function: DeviceFunction,
+        msix: &MsixInfo,
+        enabled: bool,
+    ) {
+        let header = self.config_read_word(device_function, msix.offset);
+        let mut message_control = (header >> 16) as u16 & !MSIX_FUNCTION_MASK;
+        if enabled {
+            message_control |= MSIX_ENABLE;
+        } else {
+            message_control &= !MSIX_ENABLE;
+        }
+        self.config_write_word(
+            device_function,
+            msix.offset,
+            (header & 0xffff) | u32::from(message_control) << 16,
+        );
+    }
+
+    /// Returns whether MSI-X is enabled for the given device function, which must have an MSI-X
+    /// capability as returned by [`msix_info`](Self::msix_info).
+    pub fn msix_enabled(&self, device_function: DeviceFunction, msix: &MsixInfo) -> bool {
+        let header = self.config_read_word(device_function, msix.offset);
+        (header >> 16) as u16 & MSIX_ENABLE != 0
+    }
+
     /// Gets information about the given BAR of the given device function.
     pub fn bar_info(
         &mut self,
@@ -254,33 +315,31 @@ impl PciRoot {
     ) -> Result<BarInfo, PciError> {
         let bar_orig = self.config_read_word(device_function, BAR0_OFFSET + 4 * bar_index);
 
-        // Get the size of the BAR.
-        self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, 0xffffffff);
-        let size_mask = self.config_read_word(device_function, BAR0_OFFSET + 4 * bar_index);
-        // A wrapping add is necessary to correctly handle the case of unused BARs, which read back
-        // as 0, and should be treated as size 0.
-        let size = (!(size_mask & 0xfffffff0)).wrapping_add(1);
-
-        // Restore the original value.
-        self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, bar_orig);
-
         if bar_orig & 0x00000001 == 0x00000001 {
             // I/O space
             let address = bar_orig & 0xfffffffc;
+            let size_mask = self.read_size_mask(device_function, bar_index, bar_orig);
+            // I/O BARs are at most 256 bytes, so this can't truncate.
+            let size = bar_size(size_mask, None, 0x00000003) as u32;
             Ok(BarInfo::IO { address, size })
         } else {
             // Memory space
             let mut address = u64::from(bar_orig & 0xfffffff0);
             let prefetchable = bar_orig & 0x00000008 != 0;
             let address_type = MemoryBarType::try_from(((bar_orig & 0x00000006) >> 1) as u8)?;
-            if address_type == MemoryBarType::Width64 {
+            let size_mask = self.read_size_mask(device_function, bar_index, bar_orig);
+            let size_mask_top = if address_type == MemoryBarType::Width64 {
                 if bar_index >= 5 {
                     return Err(PciError::InvalidBarType);
                 }
                 let address_top =
                     self.config_read_word(device_function, BAR0_OFFSET + 4 * (bar_index + 1));
                 address |= u64::from(address_top) << 32;
-            }
+                Some(self.read_size_mask(device_function, bar_index + 1, address_top))
+            } else {
+                None
+            };
+            let size = bar_size(size_mask, size_mask_top, 0x0000000f);
             Ok(BarInfo::Memory {
                 address_type,
                 prefetchable,
@@ -290,6 +349,16 @@ impl PciRoot {
         }
     }
 
+    /// Writes all ones to the given BAR register to find which address bits the device implements,
+    /// then restores its original value.
+    fn read_size_mask(&mut self, device_function: DeviceFunction, bar_index: u8, orig: u32) -> u32 {
+        let offset = BAR0_OFFSET + 4 * bar_index;
+        self.config_write_word(device_function, offset, 0xffffffff);
+        let size_mask = self.config_read_word(device_function, offset);
+        self.config_write_word(device_function, offset, orig);
+        size_mask
+    }
+
     /// Sets the address of the given 32-bit memory or I/O BAR of the given device function.
     pub fn set_bar_32(&mut self, device_function: DeviceFunction, bar_index: u8, address: u32) {
         self.config_write_word(device_function, BAR0_OFFSET + 4 * bar_index, address);
@@ -329,7 +398,7 @@ pub enum BarInfo {
         /// The memory address, always 16-byte aligned.
         address: u64,
         /// The size of the BAR in bytes.
-        size: u32,
+        size: u64,
     },
     /// The BAR is for an I/O region.
     IO {
@@ -355,7 +424,7 @@ impl BarInfo {
 
     /// Returns the address and size of this BAR if it is a memory bar, or `None` if it is an IO
     /// BAR.
-    pub fn memory_address_size(&self) -> Option<(u64, u32)> {
+    pub fn memory_address_size(&self) -> Option<(u64, u64)> {
         if let Self::Memory { address, size, .. } = self {
             Some((*address, *size))
         } else {
@@ -384,6 +453,20 @@ impl Display for BarInfo {
     }
 }
 
+/// Computes the size of a BAR from the values read back after writing all ones to its register, and
+/// to the register holding its upper half if it is a 64-bit BAR.
+///
+/// `flag_bits` are the low bits of the register which don't form part of the address.
+fn bar_size(size_mask: u32, size_mask_top: Option<u32>, flag_bits: u32) -> u64 {
+    let size_mask = size_mask & !flag_bits;
+    // A wrapping add is necessary to correctly handle the case of unused BARs, which read back
+    // as 0, and should be treated as size 0.
+    match size_mask_top {
+        Some(top) => (!(u64::from(top) << 32 | u64::from(size_mask))).wrapping_add(1),
+        None => u64::from((!size_mask).wrapping_add(1)),
+    }
+}
+
 /// The location allowed for a memory BAR.
 #[derive(Copy, Clone, Debug, Eq, PartialEq)]
 pub enum MemoryBarType {
@@ -455,6 +538,23 @@ impl<'a> Iterator for CapabilityIterator<'a> {
     }
 }
 
+/// Information about the MSI-X capability of a PCI device function.
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub struct MsixInfo {
+    /// The offset of the capability in the PCI configuration space of the device function.
+    pub offset: u8,
+    /// The number of entries in the MSI-X table.
+    pub table_size: u16,
+    /// The index of the BAR containing the MSI-X table.
+    pub table_bar: u8,
+    /// The offset of the MSI-X table within its BAR.
+    pub table_offset: u32,
+    /// The index of the BAR containing the pending bit array.
+    pub pba_bar: u8,
+    /// The offset of the pending bit array within its BAR.
+    pub pba_offset: u32,
+}
+
 /// Information about a PCI device capability.
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub struct CapabilityInfo {
@@ -601,3 +701,31 @@ impl From<u8> for HeaderType {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bar_size_32() {
+        assert_eq!(bar_size(0xfffff000, None, 0x0000000f), 0x1000);
+        assert_eq!(bar_size(0xfffff008, None, 0x0000000f), 0x1000);
+        assert_eq!(bar_size(0xffffffe1, None, 0x00000003), 0x20);
+        // Unused BARs read back as 0.
+        assert_eq!(bar_size(0, None, 0x0000000f), 0);
+    }
+
+    #[test]
+    fn bar_size_64() {
+        assert_eq!(
+            bar_size(0xfff0000c, Some(0xffffffff), 0x0000000f),
+            0x10_0000
+        );
+        // Larger than 4 GiB, so only the upper half has bits set.
+        assert_eq!(
+            bar_size(0x0000000c, Some(0xfffffffe), 0x0000000f),
+            0x2_0000_0000
+        );
+        assert_eq!(bar_size(0, Some(0), 0x0000000f), 0);
+    }
+}
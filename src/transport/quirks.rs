@@ -0,0 +1,318 @@
+//! Workarounds for device implementations which don't conform to the VirtIO specification.
+//!
+//! Rather than special-casing broken devices in each driver, the embedder describes them in a table
+//! of [`Quirk`]s and wraps the transport in a [`QuirksTransport`], which applies the fixes for any
+//! quirks matching the device before the driver sees it:
+//!
+//! ```
+//! use virtio_drivers::transport::{
+//!     quirks::{Quirk, QuirkMatch, QuirksTransport},
+//!     DeviceType, Transport,
+//! };
+//!
+//! const QUIRKS: &[Quirk] = &[
+//!     // This vendor's legacy block devices claim to support indirect descriptors, but don't.
+//!     Quirk::new(
+//!         QuirkMatch::ANY
+//!             .with_legacy(true)
+//!             .with_vendor_id(0x1234)
+//!             .with_device_type(DeviceType::Block),
+//!     )
+//!     .with_masked_features(1 << 28),
+//!     // This vendor's devices report a larger maximum queue size than they can actually handle.
+//!     Quirk::new(QuirkMatch::ANY.with_vendor_id(0x5678)).with_max_queue_size(64),
+//! ];
+//!
+//! # fn example<T: Transport>(transport: T) {
+//! let transport = QuirksTransport::new(transport, QUIRKS);
+//! // Create the driver with `transport` as usual.
+//! # }
+//! ```
+
+use super::{
+    DeviceStatus, DeviceType, QueueNotifyAddress, SharedMemoryRegion, Transport,
+    TransportCapabilities,
+};
+use crate::{PhysAddr, Result};
+use core::ptr::NonNull;
+use log::info;
+
+/// Which devices a [`Quirk`] applies to.
+///
+/// Each field is `None` to match any device.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct QuirkMatch {
+    /// Whether the device uses a legacy transport interface (e.g. MMIO version 1), as reported by
+    /// [`Transport::requires_legacy_layout`].
+    pub legacy: Option<bool>,
+    /// The vendor ID reported by [`Transport::vendor_id`].
+    pub vendor_id: Option<u32>,
+    /// The device type.
+    pub device_type: Option<DeviceType>,
+}
+
+impl QuirkMatch {
+    /// Matches any device.
+    pub const ANY: Self = Self {
+        legacy: None,
+        vendor_id: None,
+        device_type: None,
+    };
+
+    /// Only matches devices with (if `true`) or without (if `false`) a legacy transport interface.
+    pub const fn with_legacy(self, legacy: bool) -> Self {
+        Self {
+            legacy: Some(legacy),
+            ..self
+        }
+    }
+
+    /// Only matches devices with the given vendor ID.
+    pub const fn with_vendor_id(self, vendor_id: u32) -> Self {
+        Self {
+            vendor_id: Some(vendor_id),
+            ..self
+        }
+    }
+
+    /// Only matches devices of the given type.
+    pub const fn with_device_type(self, device_type: DeviceType) -> Self {
+        Self {
+            device_type: Some(device_type),
+            ..self
+        }
+    }
+
+    /// Returns whether the device behind the given transport matches.
+    pub fn matches<T: Transport + ?Sized>(&self, transport: &T) -> bool {
+        self.legacy
+            .is_none_or(|legacy| transport.requires_legacy_layout() == legacy)
+            && self
+                .vendor_id
+                .is_none_or(|vendor_id| transport.vendor_id() == vendor_id)
+            && self
+                .device_type
+                .is_none_or(|device_type| transport.device_type() == device_type)
+    }
+}
+
+/// A known deviation from the specification by some devices, and how to work around it.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Quirk {
+    /// The devices which this quirk applies to.
+    pub matches: QuirkMatch,
+    /// Feature bits to hide from the driver, because the device doesn't implement them correctly.
+    pub masked_features: u64,
+    /// A limit on the queue size, for devices which report a larger maximum than they support.
+    pub max_queue_size: Option<u32>,
+}
+
+impl Quirk {
+    /// Creates a quirk for the given devices, which doesn't change anything until some fixes are
+    /// added with the `with_` methods.
+    pub const fn new(matches: QuirkMatch) -> Self {
+        Self {
+            matches,
+            masked_features: 0,
+            max_queue_size: None,
+        }
+    }
+
+    /// Hides the given feature bits from the driver.
+    pub const fn with_masked_features(self, features: u64) -> Self {
+        Self {
+            masked_features: self.masked_features | features,
+            ..self
+        }
+    }
+
+    /// Limits the size of all queues to the given maximum.
+    pub const fn with_max_queue_size(self, max_queue_size: u32) -> Self {
+        Self {
+            max_queue_size: Some(max_queue_size),
+            ..self
+        }
+    }
+}
+
+/// A transport wrapper which applies the fixes for any matching [`Quirk`]s.
+#[derive(Debug)]
+pub struct QuirksTransport<T: Transport> {
+    inner: T,
+    masked_features: u64,
+    max_queue_size: Option<u32>,
+}
+
+impl<T: Transport> QuirksTransport<T> {
+    /// Wraps the given transport, applying the fixes of all quirks in the table which match the
+    /// device.
+    pub fn new(inner: T, quirks: &[Quirk]) -> Self {
+        let mut masked_features = 0;
+        let mut max_queue_size: Option<u32> = None;
+        for quirk in quirks.iter().filter(|quirk| quirk.matches.matches(&inner)) {
+            info!("Applying quirk {:?}", quirk);
+            masked_features |= quirk.masked_features;
+            if let Some(limit) = quirk.max_queue_size {
+                max_queue_size = Some(max_queue_size.map_or(limit, |max| max.min(limit)));
+            }
+        }
+        Self {
+            inner,
+            masked_features,
+            max_queue_size,
+        }
+    }
+
+    /// Returns a reference to the wrapped transport.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwraps the inner transport.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+}
+
+impl<T: Transport> Transport for QuirksTransport<T> {
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+
+    fn vendor_id(&self) -> u32 {
+        self.inner.vendor_id()
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.inner.read_device_features() & !self.masked_features
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.inner.write_driver_features(driver_features)
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        let max = self.inner.max_queue_size(queue);
+        self.max_queue_size.map_or(max, |limit| max.min(limit))
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.inner.notify(queue)
+    }
+
+    fn queue_notify_address(&mut self, queue: u16) -> Option<QueueNotifyAddress> {
+        self.inner.queue_notify_address(queue)
+    }
+
+    fn notify_multi(&mut self, queues: &[u16]) {
+        self.inner.notify_multi(queues)
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        self.inner.get_status()
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.inner.set_status(status)
+    }
+
+    fn set_guest_page_size(&mut self, guest_page_size: u32) {
+        self.inner.set_guest_page_size(guest_page_size)
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        self.inner.requires_legacy_layout()
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) -> Result {
+        self.inner
+            .queue_set(queue, size, descriptors, driver_area, device_area)
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        self.inner.queue_unset(queue)
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.inner.queue_used(queue)
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        self.inner.ack_interrupt()
+    }
+
+    fn capabilities(&self) -> TransportCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        self.inner.shared_memory_region(id)
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.inner.config_generation()
+    }
+
+    fn config_space<C: 'static>(&self) -> Result<NonNull<C>> {
+        self.inner.config_space()
+    }
+
+    fn config_space_size(&self) -> usize {
+        self.inner.config_space_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::transport::mmio::{MmioTransport, VirtIOHeader, LEGACY_VERSION, MODERN_VERSION};
+
+    const QUIRKS: &[Quirk] = &[
+        Quirk::new(
+            QuirkMatch::ANY
+                .with_legacy(true)
+                .with_device_type(DeviceType::Block),
+        )
+        .with_masked_features(0b10),
+        Quirk::new(QuirkMatch::ANY.with_vendor_id(0x1234)).with_max_queue_size(8),
+        Quirk::new(QuirkMatch::ANY.with_vendor_id(0x1234)).with_max_queue_size(16),
+    ];
+
+    #[test]
+    fn matching_quirks_applied() {
+        let mut header = VirtIOHeader::make_fake_header(LEGACY_VERSION, 2, 0x1234, 0b11, 32);
+        let transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut transport = QuirksTransport::new(transport, QUIRKS);
+        let features = transport.inner_mut().read_device_features();
+        assert_eq!(features & 0b11, 0b11);
+        assert_eq!(transport.read_device_features(), features & !0b10);
+        // The smallest limit wins.
+        assert_eq!(transport.max_queue_size(0), 8);
+    }
+
+    #[test]
+    fn non_matching_quirks_ignored() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0x4321, 0b11, 32);
+        let transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut transport = QuirksTransport::new(transport, QUIRKS);
+        let features = transport.inner_mut().read_device_features();
+        assert_eq!(transport.read_device_features(), features);
+        assert_eq!(transport.max_queue_size(0), 32);
+    }
+}
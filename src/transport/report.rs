@@ -0,0 +1,429 @@
+//! Structured reports of how far device initialization got, for diagnosing failures.
+//!
+//! Drivers take ownership of their transport, so if initialization fails the transport is gone
+//! along with anything it knew about the device. Wrapping it in a [`ReportingTransport`] records
+//! each step of the bring-up in an [`InitReport`] which outlives the driver:
+//!
+//! ```
+//! use virtio_drivers::{
+//!     device::blk::VirtIOBlk,
+//!     transport::{report::ReportingTransport, Transport},
+//!     Hal,
+//! };
+//!
+//! # fn example<HalImpl: Hal, T: Transport>(transport: T) {
+//! let transport = ReportingTransport::new(transport);
+//! let report = transport.report_handle();
+//! match VirtIOBlk::<HalImpl, _>::new(transport) {
+//!     Ok(blk) => { /* Use the device as usual. */ }
+//!     Err(e) => log::error!("Block device failed to initialize: {}\n{}", e, report.report()),
+//! }
+//! # }
+//! ```
+
+use super::{
+    DeviceStatus, DeviceType, QueueNotifyAddress, SharedMemoryRegion, Transport,
+    TransportCapabilities,
+};
+use crate::{Error, PhysAddr, Result};
+use alloc::sync::Arc;
+use core::{
+    cell::UnsafeCell,
+    fmt::{self, Display, Formatter},
+    hint::spin_loop,
+    ptr::NonNull,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+/// The maximum number of queues whose setup is recorded in an [`InitReport`].
+pub const MAX_REPORTED_QUEUES: usize = 8;
+
+/// A step of device initialization, in the order they happen.
+#[derive(Copy, Clone, Debug, Default, Eq, Ord, PartialEq, PartialOrd)]
+pub enum InitStep {
+    /// Initialization hasn't started.
+    #[default]
+    NotStarted,
+    /// The device has been reset.
+    Reset,
+    /// The driver has acknowledged the device.
+    Acknowledged,
+    /// The driver has written the features it wants to use.
+    FeaturesWritten,
+    /// The driver has set `FEATURES_OK`.
+    FeaturesOk,
+    /// The driver has started setting up queues.
+    QueueSetup,
+    /// The driver has set `DRIVER_OK`, so initialization is complete.
+    DriverOk,
+}
+
+/// What happened when setting up one queue during initialization.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct QueueAttempt {
+    /// The index of the queue.
+    pub queue: u16,
+    /// The maximum size reported by the device, if the driver asked for it.
+    pub max_size: Option<u32>,
+    /// The size the driver tried to set, if it got that far.
+    pub size: Option<u32>,
+    /// The error returned by the transport when setting the queue, if any.
+    pub error: Option<Error>,
+}
+
+/// A record of device initialization, from a [`ReportingTransport`].
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct InitReport {
+    /// The type of the device.
+    pub device_type: DeviceType,
+    /// The vendor ID of the device, or 0 if the transport doesn't provide one.
+    pub vendor_id: u32,
+    /// Whether the transport uses the legacy interface.
+    pub legacy: bool,
+    /// The last step which initialization reached.
+    pub step: InitStep,
+    /// The features offered by the device.
+    pub offered_features: u64,
+    /// The features requested by the driver.
+    pub requested_features: u64,
+    /// The device status read back after the driver last set it. If `FEATURES_OK` is missing
+    /// after the driver set it, the device rejected the requested features.
+    pub device_status: DeviceStatus,
+    /// The queues which the driver tried to set up, in order. Queues after the first
+    /// [`MAX_REPORTED_QUEUES`] aren't recorded.
+    pub queues: [Option<QueueAttempt>; MAX_REPORTED_QUEUES],
+}
+
+impl InitReport {
+    fn queue_mut(&mut self, queue: u16) -> Option<&mut QueueAttempt> {
+        let index = self
+            .queues
+            .iter()
+            .position(|attempt| attempt.is_none_or(|attempt| attempt.queue == queue))?;
+        Some(self.queues[index].get_or_insert(QueueAttempt {
+            queue,
+            ..Default::default()
+        }))
+    }
+}
+
+impl Display for InitReport {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        writeln!(
+            f,
+            "{:?} device (vendor {:#x}, {}) reached {:?}",
+            self.device_type,
+            self.vendor_id,
+            if self.legacy { "legacy" } else { "modern" },
+            self.step
+        )?;
+        writeln!(
+            f,
+            "features offered {:#x}, requested {:#x}, status {:?}",
+            self.offered_features, self.requested_features, self.device_status
+        )?;
+        for attempt in self.queues.iter().flatten() {
+            write!(
+                f,
+                "queue {}: max size {:?}",
+                attempt.queue, attempt.max_size
+            )?;
+            if let Some(size) = attempt.size {
+                write!(f, ", set size {}", size)?;
+            }
+            if let Some(error) = attempt.error {
+                write!(f, ", failed: {}", error)?;
+            }
+            writeln!(f)?;
+        }
+        Ok(())
+    }
+}
+
+/// An [`InitReport`] shared between a [`ReportingTransport`] and its [`InitReportHandle`]s.
+#[derive(Debug)]
+struct SharedReport {
+    locked: AtomicBool,
+    report: UnsafeCell<InitReport>,
+}
+
+// Safe because the report is only accessed with the lock held.
+unsafe impl Sync for SharedReport {}
+
+impl SharedReport {
+    fn update<R>(&self, f: impl FnOnce(&mut InitReport) -> R) -> R {
+        while self
+            .locked
+            .compare_exchange_weak(false, true, Ordering::Acquire, Ordering::Relaxed)
+            .is_err()
+        {
+            spin_loop();
+        }
+        // Safe because we hold the lock, so nothing else is accessing the report.
+        let result = f(unsafe { &mut *self.report.get() });
+        self.locked.store(false, Ordering::Release);
+        result
+    }
+}
+
+/// A handle to read the [`InitReport`] of a [`ReportingTransport`], even after the transport has
+/// been dropped.
+#[derive(Clone, Debug)]
+pub struct InitReportHandle {
+    shared: Arc<SharedReport>,
+}
+
+impl InitReportHandle {
+    /// Returns a copy of the report so far.
+    pub fn report(&self) -> InitReport {
+        self.shared.update(|report| *report)
+    }
+}
+
+/// A transport wrapper which records the progress of device initialization in an [`InitReport`].
+#[derive(Debug)]
+pub struct ReportingTransport<T: Transport> {
+    inner: T,
+    shared: Arc<SharedReport>,
+}
+
+impl<T: Transport> ReportingTransport<T> {
+    /// Wraps the given transport, starting a new report.
+    pub fn new(inner: T) -> Self {
+        let report = InitReport {
+            device_type: inner.device_type(),
+            vendor_id: inner.vendor_id(),
+            legacy: inner.requires_legacy_layout(),
+            step: InitStep::NotStarted,
+            offered_features: 0,
+            requested_features: 0,
+            device_status: DeviceStatus::empty(),
+            queues: [None; MAX_REPORTED_QUEUES],
+        };
+        Self {
+            inner,
+            shared: Arc::new(SharedReport {
+                locked: AtomicBool::new(false),
+                report: UnsafeCell::new(report),
+            }),
+        }
+    }
+
+    /// Returns a handle from which the report can be read, even after the transport is dropped.
+    pub fn report_handle(&self) -> InitReportHandle {
+        InitReportHandle {
+            shared: self.shared.clone(),
+        }
+    }
+
+    /// Returns a copy of the report so far.
+    pub fn report(&self) -> InitReport {
+        self.shared.update(|report| *report)
+    }
+
+    /// Returns a reference to the wrapped transport.
+    pub fn inner(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the wrapped transport.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Unwraps the inner transport.
+    pub fn into_inner(self) -> T {
+        self.inner
+    }
+
+    /// Records that initialization reached the given step, unless it had already got further.
+    fn reach(&self, step: InitStep) {
+        self.shared
+            .update(|report| report.step = report.step.max(step));
+    }
+}
+
+impl<T: Transport> Transport for ReportingTransport<T> {
+    fn device_type(&self) -> DeviceType {
+        self.inner.device_type()
+    }
+
+    fn vendor_id(&self) -> u32 {
+        self.inner.vendor_id()
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        let features = self.inner.read_device_features();
+        self.shared
+            .update(|report| report.offered_features = features);
+        features
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.inner.write_driver_features(driver_features);
+        self.shared
+            .update(|report| report.requested_features = driver_features);
+        self.reach(InitStep::FeaturesWritten);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        let max = self.inner.max_queue_size(queue);
+        self.shared.update(|report| {
+            if let Some(attempt) = report.queue_mut(queue) {
+                attempt.max_size = Some(max);
+            }
+        });
+        max
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.inner.notify(queue)
+    }
+
+    fn queue_notify_address(&mut self, queue: u16) -> Option<QueueNotifyAddress> {
+        self.inner.queue_notify_address(queue)
+    }
+
+    fn notify_multi(&mut self, queues: &[u16]) {
+        self.inner.notify_multi(queues)
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        self.inner.get_status()
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.inner.set_status(status);
+        let device_status = self.inner.get_status();
+        self.shared
+            .update(|report| report.device_status = device_status);
+        self.reach(if status.contains(DeviceStatus::DRIVER_OK) {
+            InitStep::DriverOk
+        } else if status.contains(DeviceStatus::FEATURES_OK) {
+            InitStep::FeaturesOk
+        } else if status.contains(DeviceStatus::ACKNOWLEDGE) {
+            InitStep::Acknowledged
+        } else {
+            InitStep::Reset
+        });
+    }
+
+    fn set_guest_page_size(&mut self, guest_page_size: u32) {
+        self.inner.set_guest_page_size(guest_page_size)
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        self.inner.requires_legacy_layout()
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) -> Result {
+        let result = self
+            .inner
+            .queue_set(queue, size, descriptors, driver_area, device_area);
+        self.shared.update(|report| {
+            if let Some(attempt) = report.queue_mut(queue) {
+                attempt.size = Some(size);
+                attempt.error = result.err();
+            }
+        });
+        self.reach(InitStep::QueueSetup);
+        result
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        self.inner.queue_unset(queue)
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.inner.queue_used(queue)
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        self.inner.ack_interrupt()
+    }
+
+    fn capabilities(&self) -> TransportCapabilities {
+        self.inner.capabilities()
+    }
+
+    fn shared_memory_region(&mut self, id: u8) -> Option<SharedMemoryRegion> {
+        self.inner.shared_memory_region(id)
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.inner.config_generation()
+    }
+
+    fn config_space<C: 'static>(&self) -> Result<NonNull<C>> {
+        self.inner.config_space()
+    }
+
+    fn config_space_size(&self) -> usize {
+        self.inner.config_space_size()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        device::common::Feature,
+        hal::fake::FakeHal,
+        queue::VirtQueue,
+        transport::mmio::{MmioTransport, VirtIOHeader, MODERN_VERSION},
+    };
+    use alloc::string::ToString;
+    use core::mem::size_of;
+
+    #[test]
+    fn queue_too_big() {
+        let mut header = VirtIOHeader::make_fake_header(MODERN_VERSION, 2, 0x1234, 0b11 << 28, 4);
+        let transport =
+            unsafe { MmioTransport::new(NonNull::from(&mut header), size_of::<VirtIOHeader>()) }
+                .unwrap();
+        let mut transport = ReportingTransport::new(transport);
+        let handle = transport.report_handle();
+        assert_eq!(handle.report().step, InitStep::NotStarted);
+
+        // Simulate a driver which needs a bigger queue than the device supports.
+        transport.begin_init(Feature::RING_INDIRECT_DESC);
+        assert!(VirtQueue::<FakeHal, 8>::new(&mut transport, 0, false, false).is_err());
+        let queue = VirtQueue::<FakeHal, 4>::new(&mut transport, 1, false, false).unwrap();
+        drop(queue);
+        drop(transport);
+
+        let report = handle.report();
+        assert_eq!(report.device_type, DeviceType::Block);
+        assert_eq!(report.vendor_id, 0x1234);
+        assert_eq!(report.step, InitStep::QueueSetup);
+        assert_eq!(report.offered_features & 0xffff_ffff, 0b11 << 28);
+        assert_eq!(report.requested_features & 0xffff_ffff, 1 << 28);
+        assert!(report.device_status.contains(DeviceStatus::FEATURES_OK));
+        assert_eq!(
+            report.queues[..3],
+            [
+                Some(QueueAttempt {
+                    queue: 0,
+                    max_size: Some(4),
+                    size: None,
+                    error: None
+                }),
+                Some(QueueAttempt {
+                    queue: 1,
+                    max_size: Some(4),
+                    size: Some(4),
+                    error: None
+                }),
+                None
+            ]
+        );
+        assert!(report.to_string().contains("queue 0: max size Some(4)\n"));
+    }
+}
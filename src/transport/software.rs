@@ -0,0 +1,419 @@
+//! A reference transport for devices implemented in software on another core or in another VM,
+//! communicating through shared memory.
+//!
+//! This is useful for asymmetric multiprocessing (AMP) systems, where a device is provided by
+//! firmware or an RTOS running on a different core rather than by a hypervisor. The transport
+//! registers are kept in a [`SharedRegisters`] structure in memory which both sides can access,
+//! and the two sides signal each other with whatever mechanism the platform provides (such as
+//! inter-processor interrupts or a mailbox), via the [`SoftwareHooks`] trait on the driver side and
+//! [`SharedRegisters::raise_interrupt`] on the device side.
+//!
+//! The register layout is specific to this crate and not defined by the VirtIO specification, so
+//! the device side must also use [`SharedRegisters`].
+
+use super::{DeviceStatus, DeviceType, Transport};
+use crate::{Error, PhysAddr, Result};
+use core::{
+    mem::{align_of, size_of},
+    ptr::NonNull,
+    sync::atomic::{AtomicU32, Ordering},
+};
+use log::warn;
+
+/// The maximum number of queues supported by [`SharedRegisters`].
+pub const MAX_QUEUES: usize = 8;
+
+/// Bit set in the interrupt status when the device has used buffers in a queue.
+pub const INTERRUPT_USED_BUFFER: u32 = 1 << 0;
+/// Bit set in the interrupt status when the device configuration has changed.
+pub const INTERRUPT_CONFIG_CHANGE: u32 = 1 << 1;
+
+/// Platform hooks which the driver side of a [`SoftwareTransport`] uses to signal the device.
+pub trait SoftwareHooks {
+    /// Tells the device that the driver has made new buffers available in the given queue, e.g.
+    /// by sending an inter-processor interrupt to the core running the device.
+    fn notify(&mut self, queue: u16);
+}
+
+/// The registers for a single queue within [`SharedRegisters`].
+#[derive(Debug, Default)]
+#[repr(C)]
+struct SharedQueue {
+    /// The maximum queue size supported by the device, or 0 if the queue doesn't exist. Written by
+    /// the device.
+    num_max: AtomicU32,
+    /// The queue size chosen by the driver, or 0 if the queue isn't set up.
+    num: AtomicU32,
+    descriptors: SharedAddress,
+    driver_area: SharedAddress,
+    device_area: SharedAddress,
+}
+
+/// The queue configuration chosen by the driver, as seen by the device.
+#[derive(Copy, Clone, Debug, Default, Eq, PartialEq)]
+pub struct QueueConfig {
+    /// The number of entries in the queue, or 0 if it isn't set up.
+    pub size: u32,
+    /// The physical address of the descriptor table.
+    pub descriptors: u64,
+    /// The physical address of the driver area (available ring).
+    pub driver_area: u64,
+    /// The physical address of the device area (used ring).
+    pub device_area: u64,
+}
+
+/// A 64-bit address split into two 32-bit halves, so that it can be accessed atomically on targets
+/// without 64-bit atomics.
+#[derive(Debug, Default)]
+#[repr(C)]
+struct SharedAddress {
+    low: AtomicU32,
+    high: AtomicU32,
+}
+
+impl SharedAddress {
+    fn load(&self) -> u64 {
+        u64::from(self.low.load(Ordering::Acquire))
+            | u64::from(self.high.load(Ordering::Acquire)) << 32
+    }
+
+    fn store(&self, value: u64) {
+        self.low.store(value as u32, Ordering::Release);
+        self.high.store((value >> 32) as u32, Ordering::Release);
+    }
+}
+
+/// The transport registers shared between the driver and a software device.
+///
+/// This must be placed in memory which both sides can access coherently. The device side should
+/// initialise it with [`new`](Self::new) and [`set_queue_max`](Self::set_queue_max) before the
+/// driver creates its [`SoftwareTransport`].
+#[derive(Debug, Default)]
+#[repr(C)]
+pub struct SharedRegisters {
+    device_id: AtomicU32,
+    device_features: SharedAddress,
+    driver_features: SharedAddress,
+    status: AtomicU32,
+    interrupt_status: AtomicU32,
+    config_generation: AtomicU32,
+    queues: [SharedQueue; MAX_QUEUES],
+}
+
+impl SharedRegisters {
+    /// Creates a new set of registers for a device of the given type offering the given features.
+    pub fn new(device_type: DeviceType, device_features: u64) -> Self {
+        let registers = Self::default();
+        registers
+            .device_id
+            .store(device_type as u32, Ordering::Release);
+        registers.device_features.store(device_features);
+        registers
+    }
+
+    /// Sets the maximum size which the device supports for the given queue, or 0 if the queue
+    /// doesn't exist.
+    ///
+    /// Returns [`Error::InvalidParam`] if `queue` is not less than [`MAX_QUEUES`].
+    pub fn set_queue_max(&self, queue: u16, num_max: u32) -> Result {
+        self.queue(queue)?.num_max.store(num_max, Ordering::Release);
+        Ok(())
+    }
+
+    /// Returns the features which the driver has accepted.
+    pub fn driver_features(&self) -> u64 {
+        self.driver_features.load()
+    }
+
+    /// Returns the status most recently set by the driver.
+    pub fn status(&self) -> DeviceStatus {
+        DeviceStatus::from_bits_truncate(self.status.load(Ordering::Acquire))
+    }
+
+    /// Returns the configuration of the given queue as set up by the driver.
+    ///
+    /// Returns [`Error::InvalidParam`] if `queue` is not less than [`MAX_QUEUES`].
+    pub fn queue_config(&self, queue: u16) -> Result<QueueConfig> {
+        let queue = self.queue(queue)?;
+        Ok(QueueConfig {
+            size: queue.num.load(Ordering::Acquire),
+            descriptors: queue.descriptors.load(),
+            driver_area: queue.driver_area.load(),
+            device_area: queue.device_area.load(),
+        })
+    }
+
+    /// Increments the configuration generation counter.
+    ///
+    /// The device side should call this after changing the device-specific config space, before
+    /// raising [`INTERRUPT_CONFIG_CHANGE`].
+    pub fn bump_config_generation(&self) {
+        self.config_generation.fetch_add(1, Ordering::AcqRel);
+    }
+
+    /// Sets the given bits (e.g. [`INTERRUPT_USED_BUFFER`]) in the interrupt status, for the driver
+    /// to acknowledge.
+    ///
+    /// The device side should call this before signalling the driver's core with whatever
+    /// mechanism the platform provides.
+    pub fn raise_interrupt(&self, status: u32) {
+        self.interrupt_status.fetch_or(status, Ordering::AcqRel);
+    }
+
+    /// Returns the registers for the given queue, or [`Error::InvalidParam`] if there is no such
+    /// queue.
+    fn queue(&self, queue: u16) -> Result<&SharedQueue> {
+        self.queues
+            .get(usize::from(queue))
+            .ok_or(Error::InvalidParam)
+    }
+}
+
+/// The driver side of a transport to a device implemented in software, through registers in shared
+/// memory.
+///
+/// See the [module documentation](self) for details.
+#[derive(Debug)]
+pub struct SoftwareTransport<K: SoftwareHooks> {
+    registers: NonNull<SharedRegisters>,
+    config_space: Option<NonNull<[u8]>>,
+    hooks: K,
+}
+
+impl<K: SoftwareHooks> SoftwareTransport<K> {
+    /// Creates a new transport using the given shared registers and device-specific config space,
+    /// which signals the device through the given hooks.
+    ///
+    /// # Safety
+    ///
+    /// `registers` must point to a valid, initialised `SharedRegisters` and `config_space` (if any)
+    /// to a valid region of memory, both of which the device side may access concurrently but
+    /// nothing else on the driver side may access for the lifetime of the transport.
+    pub unsafe fn new(
+        registers: NonNull<SharedRegisters>,
+        config_space: Option<NonNull<[u8]>>,
+        hooks: K,
+    ) -> Self {
+        Self {
+            registers,
+            config_space,
+            hooks,
+        }
+    }
+
+    /// Returns a mutable reference to the hooks used to signal the device.
+    pub fn hooks(&mut self) -> &mut K {
+        &mut self.hooks
+    }
+
+    fn registers(&self) -> &SharedRegisters {
+        // Safe because the caller of `new` promised that the pointer is valid, and all fields are
+        // atomics so may be accessed concurrently by the device side.
+        unsafe { self.registers.as_ref() }
+    }
+}
+
+impl<K: SoftwareHooks> Transport for SoftwareTransport<K> {
+    fn device_type(&self) -> DeviceType {
+        self.registers().device_id.load(Ordering::Acquire).into()
+    }
+
+    fn read_device_features(&mut self) -> u64 {
+        self.registers().device_features.load()
+    }
+
+    fn write_driver_features(&mut self, driver_features: u64) {
+        self.registers().driver_features.store(driver_features);
+    }
+
+    fn max_queue_size(&mut self, queue: u16) -> u32 {
+        self.registers()
+            .queue(queue)
+            .map_or(0, |queue| queue.num_max.load(Ordering::Acquire))
+    }
+
+    fn notify(&mut self, queue: u16) {
+        self.hooks.notify(queue);
+    }
+
+    fn get_status(&self) -> DeviceStatus {
+        self.registers().status()
+    }
+
+    fn set_status(&mut self, status: DeviceStatus) {
+        self.registers()
+            .status
+            .store(status.bits(), Ordering::Release);
+    }
+
+    fn set_guest_page_size(&mut self, _guest_page_size: u32) {
+        // No-op, the software transport doesn't use the legacy layout.
+    }
+
+    fn requires_legacy_layout(&self) -> bool {
+        false
+    }
+
+    fn queue_set(
+        &mut self,
+        queue: u16,
+        size: u32,
+        descriptors: PhysAddr,
+        driver_area: PhysAddr,
+        device_area: PhysAddr,
+    ) -> Result {
+        let queue = self.registers().queue(queue)?;
+        queue.descriptors.store(descriptors as u64);
+        queue.driver_area.store(driver_area as u64);
+        queue.device_area.store(device_area as u64);
+        // Set the size last, as it marks the queue as ready.
+        queue.num.store(size, Ordering::Release);
+        Ok(())
+    }
+
+    fn queue_unset(&mut self, queue: u16) {
+        let Ok(queue) = self.registers().queue(queue) else {
+            return;
+        };
+        queue.num.store(0, Ordering::Release);
+        queue.descriptors.store(0);
+        queue.driver_area.store(0);
+        queue.device_area.store(0);
+    }
+
+    fn queue_used(&mut self, queue: u16) -> bool {
+        self.registers()
+            .queue(queue)
+            .is_ok_and(|queue| queue.num.load(Ordering::Acquire) != 0)
+    }
+
+    fn ack_interrupt(&mut self) -> bool {
+        self.registers().interrupt_status.swap(0, Ordering::AcqRel) != 0
+    }
+
+    fn config_generation(&self) -> u32 {
+        self.registers().config_generation.load(Ordering::Acquire)
+    }
+
+    fn config_space<T: 'static>(&self) -> Result<NonNull<T>> {
+        if align_of::<T>() > 4 {
+            // This should only happen if the driver is written incorrectly.
+            warn!(
+                "Driver expected config space alignment of {} bytes, but VirtIO only guarantees 4 byte alignment.",
+                align_of::<T>()
+            );
+            return Err(Error::Misaligned);
+        }
+        let config_space = self.config_space.ok_or(Error::ConfigSpaceMissing)?;
+        if config_space.len() < size_of::<T>() {
+            Err(Error::ConfigSpaceTooSmall)
+        } else {
+            Ok(config_space.cast())
+        }
+    }
+
+    fn config_space_size(&self) -> usize {
+        self.config_space
+            .map_or(0, |config_space| config_space.len())
+    }
+}
+
+impl<K: SoftwareHooks> Drop for SoftwareTransport<K> {
+    fn drop(&mut self) {
+        // Reset the device when the transport is dropped.
+        self.set_status(DeviceStatus::empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use alloc::vec::Vec;
+
+    #[derive(Debug, Default)]
+    struct RecordingHooks {
+        notified: Vec<u16>,
+    }
+
+    impl SoftwareHooks for RecordingHooks {
+        fn notify(&mut self, queue: u16) {
+            self.notified.push(queue);
+        }
+    }
+
+    #[test]
+    fn driver_and_device_sides() {
+        let registers = SharedRegisters::new(DeviceType::Block, 0x1234_0000_0001);
+        registers.set_queue_max(0, 16).unwrap();
+        assert_eq!(
+            registers.set_queue_max(MAX_QUEUES as u16, 16),
+            Err(Error::InvalidParam)
+        );
+        let mut config = [0u8; 8];
+        let mut transport = unsafe {
+            SoftwareTransport::new(
+                NonNull::from(&registers),
+                Some(NonNull::from(&mut config[..])),
+                RecordingHooks::default(),
+            )
+        };
+
+        assert_eq!(transport.device_type(), DeviceType::Block);
+        assert_eq!(transport.read_device_features(), 0x1234_0000_0001);
+        transport.write_driver_features(0x1234_0000_0000);
+        assert_eq!(registers.driver_features(), 0x1234_0000_0000);
+        assert_eq!(transport.max_queue_size(0), 16);
+        assert_eq!(transport.max_queue_size(1), 0);
+        assert_eq!(transport.max_queue_size(MAX_QUEUES as u16), 0);
+
+        transport.set_status(DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER);
+        assert_eq!(
+            registers.status(),
+            DeviceStatus::ACKNOWLEDGE | DeviceStatus::DRIVER
+        );
+
+        transport
+            .queue_set(0, 16, 0x1000, 0x2000, 0x1_0000_3000)
+            .unwrap();
+        assert!(transport.queue_used(0));
+        assert_eq!(
+            registers.queue_config(0),
+            Ok(QueueConfig {
+                size: 16,
+                descriptors: 0x1000,
+                driver_area: 0x2000,
+                device_area: 0x1_0000_3000,
+            })
+        );
+        transport.queue_unset(0);
+        assert!(!transport.queue_used(0));
+
+        // Queues beyond the end of the registers don't exist.
+        let queue = MAX_QUEUES as u16;
+        assert_eq!(
+            transport.queue_set(queue, 16, 0x1000, 0x2000, 0x3000),
+            Err(Error::InvalidParam)
+        );
+        assert!(!transport.queue_used(queue));
+        transport.queue_unset(queue);
+        assert_eq!(registers.queue_config(queue), Err(Error::InvalidParam));
+
+        transport.notify(0);
+        assert_eq!(transport.hooks().notified, [0]);
+
+        assert!(!transport.ack_interrupt());
+        registers.raise_interrupt(INTERRUPT_USED_BUFFER);
+        assert!(transport.ack_interrupt());
+        assert!(!transport.ack_interrupt());
+
+        assert_eq!(transport.config_generation(), 0);
+        registers.bump_config_generation();
+        assert_eq!(transport.config_generation(), 1);
+
+        assert!(transport.config_space::<[u32; 2]>().is_ok());
+        assert_eq!(
+            transport.config_space::<[u32; 3]>(),
+            Err(Error::ConfigSpaceTooSmall)
+        );
+    }
+}
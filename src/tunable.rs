@@ -0,0 +1,120 @@
+//! Runtime tunables which an OS can expose to users, e.g. through a sysctl or sysfs-style
+//! interface.
+//!
+//! Drivers which implement [`Tunables`] list their knobs with [`Tunables::tunables`], and allow
+//! them to be read and written by name without the OS needing to know about each driver's methods:
+//!
+//! ```
+//! use virtio_drivers::tunable::Tunables;
+//!
+//! fn dump(driver: &impl Tunables) {
+//!     for tunable in driver.tunables() {
+//!         let value = driver.tunable(tunable.name).unwrap();
+//!         println!("{} = {} ({})", tunable.name, value, tunable.description);
+//!     }
+//! }
+//! ```
+//!
+//! Only knobs which the drivers actually support are listed; for example there are no interrupt
+//! coalescing tunables because none of the drivers negotiate notification coalescing yet.
+
+use crate::{Error, Result};
+use core::fmt::{self, Display, Formatter};
+
+/// The value of a tunable.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub enum TunableValue {
+    /// A flag.
+    Bool(bool),
+    /// A number.
+    U32(u32),
+    /// A number which may be unset.
+    OptionalU32(Option<u32>),
+}
+
+impl Display for TunableValue {
+    fn fmt(&self, f: &mut Formatter) -> fmt::Result {
+        match self {
+            Self::Bool(value) => write!(f, "{}", value),
+            Self::U32(value) | Self::OptionalU32(Some(value)) => write!(f, "{}", value),
+            Self::OptionalU32(None) => write!(f, "none"),
+        }
+    }
+}
+
+/// A description of a tunable.
+#[derive(Copy, Clone, Debug, Eq, PartialEq)]
+pub struct Tunable {
+    /// The name used to read and write the tunable.
+    pub name: &'static str,
+    /// A short human-readable description.
+    pub description: &'static str,
+    /// Whether the tunable can be changed at runtime, or is only reported for information.
+    pub writable: bool,
+}
+
+/// A driver with runtime tunables.
+pub trait Tunables {
+    /// Lists the driver's tunables.
+    fn tunables(&self) -> &'static [Tunable];
+
+    /// Returns the current value of the tunable with the given name.
+    ///
+    /// Returns [`Error::InvalidParam`] if there is no such tunable.
+    fn tunable(&self, name: &str) -> Result<TunableValue>;
+
+    /// Changes the value of the tunable with the given name.
+    ///
+    /// Returns [`Error::InvalidParam`] if there is no such tunable or the value is of the wrong
+    /// type, or [`Error::Unsupported`] if the tunable isn't writable.
+    fn set_tunable(&mut self, name: &str, value: TunableValue) -> Result;
+}
+
+/// The name of the tunable for whether interrupts are enabled.
+pub(crate) const INTERRUPTS_ENABLED: &str = "interrupts_enabled";
+/// The name of the tunable for the spurious interrupt threshold.
+pub(crate) const SPURIOUS_INTERRUPT_THRESHOLD: &str = "spurious_interrupt_threshold";
+/// The name of the tunable for the size of the driver's queues.
+pub(crate) const QUEUE_SIZE: &str = "queue_size";
+
+/// The tunables shared by drivers which use interrupt accounting.
+pub(crate) const INTERRUPTS_ENABLED_TUNABLE: Tunable = Tunable {
+    name: INTERRUPTS_ENABLED,
+    description: "Whether the device raises interrupts; if false the driver must be polled",
+    writable: true,
+};
+pub(crate) const SPURIOUS_INTERRUPT_THRESHOLD_TUNABLE: Tunable = Tunable {
+    name: SPURIOUS_INTERRUPT_THRESHOLD,
+    description: "Consecutive spurious interrupts after which to switch to polling",
+    writable: true,
+};
+pub(crate) const QUEUE_SIZE_TUNABLE: Tunable = Tunable {
+    name: QUEUE_SIZE,
+    description: "Number of descriptors in each virtqueue",
+    writable: false,
+};
+
+/// Returns the error for an attempt to set a tunable, after the writable ones have been handled.
+pub(crate) fn set_error(tunables: &[Tunable], name: &str) -> Error {
+    if tunables.iter().any(|tunable| tunable.name == name) {
+        Error::Unsupported
+    } else {
+        Error::InvalidParam
+    }
+}
+
+/// Extracts a flag from a tunable value.
+pub(crate) fn expect_bool(value: TunableValue) -> Result<bool> {
+    match value {
+        TunableValue::Bool(value) => Ok(value),
+        _ => Err(Error::InvalidParam),
+    }
+}
+
+/// Extracts an optional number from a tunable value.
+pub(crate) fn expect_optional_u32(value: TunableValue) -> Result<Option<u32>> {
+    match value {
+        TunableValue::OptionalU32(value) => Ok(value),
+        _ => Err(Error::InvalidParam),
+    }
+}